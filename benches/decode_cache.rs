@@ -0,0 +1,68 @@
+//! A hot loop with and without the decode cache:
+//! `cargo bench --bench decode_cache`
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use riscv_emulator::decode_cache::DecodeCache;
+use riscv_emulator::{Rv32iInstruction, Vm};
+
+/// counts a0 up 2000 times:
+/// 0x1000 addi t0, zero, 2000
+/// 0x1004 addi a0, a0, 1
+/// 0x1008 addi t0, t0, -1
+/// 0x100c bne t0, zero, 0x1004
+/// 0x1010 ebreak
+const LOOP: [u32; 5] = [
+    0x7d00_0293,
+    0x0015_0513,
+    0xfff2_8293,
+    0xfe02_9ce3,
+    0x0010_0073,
+];
+
+fn run(decode_cache: bool) -> i32 {
+    let program: Vec<u8> = LOOP.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let mut vm = Vm::new(0x1000, 0x100);
+    if decode_cache {
+        vm = vm.with_decode_cache();
+    }
+    vm.load_program(0x1000, &program).unwrap();
+    vm.run().unwrap();
+    vm.vm_state.registers[10]
+}
+
+fn decode_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot loop");
+    group.bench_function("decoding every fetch", |b| b.iter(|| run(black_box(false))));
+    group.bench_function("decode cache", |b| b.iter(|| run(black_box(true))));
+    group.finish();
+
+    // the fetch alone, the run loop also pays for the stats and the execution
+    let mut cache = DecodeCache::new(0x1000, 0x100);
+    for (index, word) in LOOP.iter().enumerate() {
+        let instruction = Rv32iInstruction::from_core_instruction_format(word.to_le_bytes());
+        cache.insert(0x1000 + index as u32 * 4, *word, instruction.unwrap());
+    }
+    let mut group = c.benchmark_group("fetch");
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            for word in LOOP {
+                let instruction =
+                    Rv32iInstruction::from_core_instruction_format(black_box(word).to_le_bytes());
+                black_box(instruction.unwrap());
+            }
+        })
+    });
+    group.bench_function("decode cache", |b| {
+        b.iter(|| {
+            for address in (0x1000..0x1014).step_by(4) {
+                black_box(cache.get(black_box(address)).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode_cache);
+criterion_main!(benches);
@@ -0,0 +1,296 @@
+//! Representative guest workloads through the whole run loop, to catch
+//! regressions in the dispatch and the memory system:
+//! `cargo bench --bench interpreter`
+//!
+//! The programs are assembled by the small encoders below, one instruction
+//! per line with the word index in front so the branch offsets can be
+//! checked by hand.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use riscv_emulator::{StopReason, Vm};
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const SP: u32 = 2;
+const T0: u32 = 5;
+const T1: u32 = 6;
+const T2: u32 = 7;
+const S0: u32 = 8;
+const S1: u32 = 9;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+const S2: u32 = 18;
+const S3: u32 = 19;
+const T3: u32 = 28;
+
+const EBREAK: u32 = 0x0010_0073;
+
+fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x33
+}
+
+fn i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | 0x23
+}
+
+fn b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12 & 1) << 31)
+        | ((imm >> 5 & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm >> 1 & 0xf) << 8)
+        | ((imm >> 11 & 1) << 7)
+        | 0x63
+}
+
+fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0, rs2, rs1, 0b000, rd)
+}
+
+fn xor(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0, rs2, rs1, 0b100, rd)
+}
+
+fn sltu(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0, rs2, rs1, 0b011, rd)
+}
+
+fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i(imm, rs1, 0b000, rd, 0x13)
+}
+
+fn andi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i(imm, rs1, 0b111, rd, 0x13)
+}
+
+fn slli(rd: u32, rs1: u32, shamt: i32) -> u32 {
+    i(shamt, rs1, 0b001, rd, 0x13)
+}
+
+fn srli(rd: u32, rs1: u32, shamt: i32) -> u32 {
+    i(shamt, rs1, 0b101, rd, 0x13)
+}
+
+fn lw(rd: u32, imm: i32, rs1: u32) -> u32 {
+    i(imm, rs1, 0b010, rd, 0x03)
+}
+
+fn sw(rs2: u32, imm: i32, rs1: u32) -> u32 {
+    s(imm, rs2, rs1, 0b010)
+}
+
+fn beq(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b(offset, rs2, rs1, 0b000)
+}
+
+fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b(offset, rs2, rs1, 0b001)
+}
+
+fn blt(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b(offset, rs2, rs1, 0b100)
+}
+
+fn jal(rd: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 20 & 1) << 31)
+        | ((imm >> 1 & 0x3ff) << 21)
+        | ((imm >> 11 & 1) << 20)
+        | ((imm >> 12 & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+fn ret() -> u32 {
+    i(0, RA, 0b000, ZERO, 0x67)
+}
+
+fn lui(rd: u32, imm: u32) -> u32 {
+    (imm << 12) | (rd << 7) | 0x37
+}
+
+/// fib(15) = 610 the recursive way, with the stack at the top of memory
+fn fibonacci() -> Vec<u32> {
+    vec![
+        /* 0 */ lui(SP, 0x11),
+        /* 1 */ addi(A0, ZERO, 15),
+        /* 2 */ jal(RA, 2 * 4),
+        /* 3 */ EBREAK,
+        // fib(a0)
+        /* 4 */ addi(T0, ZERO, 2),
+        /* 5 */ blt(A0, T0, 16 * 4),
+        /* 6 */ addi(SP, SP, -12),
+        /* 7 */ sw(RA, 8, SP),
+        /* 8 */ sw(S0, 4, SP),
+        /* 9 */ sw(S1, 0, SP),
+        /* 10 */ addi(S0, A0, 0),
+        /* 11 */ addi(A0, S0, -1),
+        /* 12 */ jal(RA, -8 * 4),
+        /* 13 */ addi(S1, A0, 0),
+        /* 14 */ addi(A0, S0, -2),
+        /* 15 */ jal(RA, -11 * 4),
+        /* 16 */ add(A0, A0, S1),
+        /* 17 */ lw(RA, 8, SP),
+        /* 18 */ lw(S0, 4, SP),
+        /* 19 */ lw(S1, 0, SP),
+        /* 20 */ addi(SP, SP, 12),
+        /* 21 */ ret(),
+    ]
+}
+
+/// copies 1 KiB from 0x1000 (the program itself, then zeros) to 0x3000 a
+/// word at a time
+fn memcpy() -> Vec<u32> {
+    vec![
+        /* 0 */ lui(A0, 0x3),
+        /* 1 */ lui(A1, 0x1),
+        /* 2 */ addi(A2, ZERO, 1024),
+        /* 3 */ add(A2, A2, A1),
+        /* 4 */ lw(T0, 0, A1),
+        /* 5 */ sw(T0, 0, A0),
+        /* 6 */ addi(A1, A1, 4),
+        /* 7 */ addi(A0, A0, 4),
+        /* 8 */ bne(A1, A2, -4 * 4),
+        /* 9 */ EBREAK,
+    ]
+}
+
+/// a Dhrystone-like mix of arithmetic, a record in memory, a data dependent
+/// branch and a call, 1000 times
+fn dhrystone_like() -> Vec<u32> {
+    vec![
+        /* 0 */ lui(S0, 0x2),
+        /* 1 */ addi(S1, ZERO, 1000),
+        /* 2 */ addi(S2, ZERO, 0),
+        // loop
+        /* 3 */ add(S2, S2, S1),
+        /* 4 */ andi(T0, S1, 7),
+        /* 5 */ slli(T0, T0, 2),
+        /* 6 */ add(T0, T0, S0),
+        /* 7 */ xor(T1, S2, S1),
+        /* 8 */ sw(T1, 0, T0),
+        /* 9 */ lw(T2, 0, T0),
+        /* 10 */ andi(T3, T2, 1),
+        /* 11 */ beq(T3, ZERO, 2 * 4),
+        /* 12 */ addi(S3, S3, 1),
+        /* 13 */ addi(A0, S2, 0),
+        /* 14 */ jal(RA, 4 * 4),
+        /* 15 */ addi(S1, S1, -1),
+        /* 16 */ bne(S1, ZERO, -13 * 4),
+        /* 17 */ EBREAK,
+        // add3(a0)
+        /* 18 */ addi(A0, A0, 3),
+        /* 19 */ sltu(A1, A0, S2),
+        /* 20 */ ret(),
+    ]
+}
+
+/// 10000 rounds of the xorshift32 generator, only register arithmetic and
+/// the loop branch
+fn xorshift() -> Vec<u32> {
+    vec![
+        /* 0 */ lui(A0, 0x12345),
+        /* 1 */ lui(S1, 0x2),
+        /* 2 */ addi(S1, S1, 1808),
+        // loop
+        /* 3 */ slli(T0, A0, 13),
+        /* 4 */ xor(A0, A0, T0),
+        /* 5 */ srli(T0, A0, 17),
+        /* 6 */ xor(A0, A0, T0),
+        /* 7 */ slli(T0, A0, 5),
+        /* 8 */ xor(A0, A0, T0),
+        /* 9 */ addi(S1, S1, -1),
+        /* 10 */ bne(S1, ZERO, -7 * 4),
+        /* 11 */ EBREAK,
+    ]
+}
+
+/// a fresh vm with 64 KiB of memory from 0x1000 and `program` at its start
+fn vm_with(program: &[u32]) -> Vm {
+    let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let mut vm = Vm::new(0x1000, 0x10000);
+    vm.load_program(0x1000, &bytes).unwrap();
+    vm
+}
+
+fn run(program: &[u32]) -> Vm {
+    let mut vm = vm_with(program);
+    assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+    vm
+}
+
+#[cfg(feature = "jit")]
+fn run_jitted(program: &[u32]) -> Vm {
+    let mut vm = vm_with(program).with_jit();
+    assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+    vm
+}
+
+fn interpreter(c: &mut Criterion) {
+    // the workloads have to compute the right thing to be worth timing
+    assert_eq!(run(&fibonacci()).vm_state.registers[A0 as usize], 610);
+    let copied = run(&memcpy());
+    assert_eq!(copied.memory.read_u32(0x3000).unwrap(), memcpy()[0]);
+    assert_eq!(
+        run(&dhrystone_like()).vm_state.registers[A0 as usize],
+        500_503
+    );
+    assert_eq!(
+        run(&xorshift()).vm_state.registers[A0 as usize],
+        0x0973_7866
+    );
+    #[cfg(feature = "jit")]
+    assert_eq!(
+        run_jitted(&xorshift()).vm_state.registers[A0 as usize],
+        0x0973_7866
+    );
+
+    let mut group = c.benchmark_group("interpreter");
+    for (name, program) in [
+        ("fibonacci", fibonacci()),
+        ("memcpy", memcpy()),
+        ("dhrystone-like", dhrystone_like()),
+        ("xorshift", xorshift()),
+    ] {
+        group.bench_function(name, |bencher| {
+            bencher.iter(|| run(black_box(&program)).stats.instructions_retired)
+        });
+        group.bench_function(format!("{name} (blocks)"), |bencher| {
+            bencher.iter(|| {
+                let mut vm = vm_with(black_box(&program)).with_block_cache();
+                vm.run()
+            })
+        });
+        // `cargo bench --features jit --bench interpreter`. The vm is
+        // reused, so the blocks are translated once and the numbers are the
+        // steady state
+        #[cfg(feature = "jit")]
+        group.bench_function(format!("{name} (jit)"), |bencher| {
+            let mut vm = vm_with(&program).with_jit();
+            let start = vm.vm_state.clone();
+            bencher.iter(|| {
+                vm.vm_state = start.clone();
+                vm.run()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
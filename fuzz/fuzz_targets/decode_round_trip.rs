@@ -0,0 +1,40 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use riscv_emulator::encode::canonical;
+use riscv_emulator::{Rv32iInstruction, Vm, VmError};
+
+const BASE: u32 = 0x1000;
+
+// the input is one instruction word. Either it decodes and encodes back to
+// itself (up to the bits the decoder ignores), or the decoder and the vm
+// both reject it as that very illegal instruction. A field that is cut
+// short or taken from the wrong bits while decoding shows up as a
+// different word
+fuzz_target!(|data: [u8; 4]| {
+    let word = u32::from_le_bytes(data);
+    match Rv32iInstruction::from_core_instruction_format(data) {
+        Ok(instruction) => assert_eq!(
+            instruction.encode(),
+            canonical(word),
+            "{word:#010x} decoded to {instruction:?}"
+        ),
+        Err(error) => {
+            assert_eq!(
+                error,
+                VmError::IllegalInstruction {
+                    pc: 0,
+                    instruction: word,
+                }
+            );
+            let mut vm = Vm::new(BASE, 0x100);
+            vm.load_program(BASE, &data).expect("the word fits");
+            assert_eq!(
+                vm.step(),
+                Err(VmError::IllegalInstruction {
+                    pc: BASE,
+                    instruction: word,
+                })
+            );
+        }
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use riscv_emulator::fs::VirtFs;
+use riscv_emulator::Vm;
+
+/// where the program goes and how big the memory is
+const BASE: u32 = 0x1000;
+const MEMORY_SIZE: usize = 0x1000;
+/// enough to get through loops, small enough to keep the fuzzer fast
+const EXECUTION_LIMIT: u64 = 10_000;
+
+// the input is the program itself. It runs in a sandbox: an empty in-memory
+// file system for its syscalls and an instruction limit. Whatever it does,
+// the vm must not panic, and a failing run must end in a VmError that is
+// recorded in the run stats
+fuzz_target!(|data: &[u8]| {
+    let program = &data[..data.len().min(MEMORY_SIZE)];
+    let mut vm = Vm::new(BASE, MEMORY_SIZE).with_file_system(VirtFs::new());
+    vm.execution_limit = Some(EXECUTION_LIMIT);
+    vm.load_program(BASE, program)
+        .expect("the program fits into the memory");
+
+    if let Err(error) = vm.run() {
+        assert!(!error.to_string().is_empty());
+        assert!(vm.stats.exit.is_some());
+        assert!(vm.stats.instructions_retired <= EXECUTION_LIMIT);
+        let _ = error.pc();
+    }
+});
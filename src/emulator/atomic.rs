@@ -0,0 +1,191 @@
+//! The A extension: `lr.w`/`sc.w` and the word AMOs. Each hart can hold a
+//! reservation on one word. Any store to that word, by any hart, drops the
+//! reservation, so `sc.w` fails if another hart got in between. The
+//! reservations live in the vm and not in a hart's state, so they survive
+//! the switches between harts in `smp.rs`.
+
+use super::emulator::{Instruction, Vm};
+use super::error::{AccessKind, VmError};
+use super::memory::MemoryAccess;
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// the reserved address per hart id
+#[derive(Debug, Clone, Default)]
+pub struct Reservations {
+    addresses: Vec<Option<u32>>,
+    /// the hart that runs now
+    pub(super) hart: usize,
+}
+
+impl Reservations {
+    fn reserve(&mut self, address: u32) {
+        if self.addresses.len() <= self.hart {
+            self.addresses.resize(self.hart + 1, None);
+        }
+        self.addresses[self.hart] = Some(address);
+    }
+
+    /// the reservation of the running hart, it is gone afterwards
+    fn take(&mut self) -> Option<u32> {
+        self.addresses.get_mut(self.hart).and_then(Option::take)
+    }
+
+    /// drops the reservations on the word a store to `size` bytes from
+    /// `address` touches
+    pub(super) fn store(&mut self, address: u32, size: u32) {
+        let end = address.wrapping_add(size);
+        for reservation in &mut self.addresses {
+            if reservation.is_some_and(|word| word < end && address < word + 4) {
+                *reservation = None;
+            }
+        }
+    }
+}
+
+impl Vm {
+    /// executes an A extension instruction and moves the pc past it. Returns
+    /// the store it did, or the load for `lr.w`. A failed `sc.w` does not
+    /// access the memory
+    pub(super) fn execute_atomic(
+        &mut self,
+        instruction: &Instruction,
+    ) -> Result<Option<MemoryAccess>, VmError> {
+        let Instruction::Rv32iInstruction(_, instruction) = instruction else {
+            return Ok(None);
+        };
+        let (Rv32iInstruction::LrW(r)
+        | Rv32iInstruction::ScW(r)
+        | Rv32iInstruction::AmoswapW(r)
+        | Rv32iInstruction::AmoaddW(r)
+        | Rv32iInstruction::AmoxorW(r)
+        | Rv32iInstruction::AmoandW(r)
+        | Rv32iInstruction::AmoorW(r)
+        | Rv32iInstruction::AmominW(r)
+        | Rv32iInstruction::AmomaxW(r)
+        | Rv32iInstruction::AmominuW(r)
+        | Rv32iInstruction::AmomaxuW(r)) = instruction
+        else {
+            return Ok(None);
+        };
+
+        let address = self.vm_state.registers[r.rs1] as u32;
+        let source = self.vm_state.registers[r.rs2] as u32;
+        // unlike the plain loads and stores, atomics have to be aligned
+        if !address.is_multiple_of(4) {
+            return Err(VmError::MisalignedAccess {
+                pc: 0,
+                instruction: 0,
+                address,
+            });
+        }
+        let kind = match instruction {
+            Rv32iInstruction::LrW(_) => AccessKind::Load,
+            _ => AccessKind::Store,
+        };
+        let address = self.translate(address, kind)?;
+
+        let (result, access) = match instruction {
+            Rv32iInstruction::LrW(_) => {
+                let value = self.memory.read_u32(address)?;
+                self.reservations.reserve(address);
+                (
+                    value,
+                    MemoryAccess::Read {
+                        address,
+                        size: 4,
+                        value,
+                    },
+                )
+            }
+            Rv32iInstruction::ScW(_) => {
+                if self.reservations.take() != Some(address) {
+                    self.set_atomic_result(r.rd, 1);
+                    return Ok(None);
+                }
+                self.memory.write_u32(address, source)?;
+                (
+                    0,
+                    MemoryAccess::Write {
+                        address,
+                        size: 4,
+                        value: source,
+                    },
+                )
+            }
+            amo => {
+                let old = self.memory.read_u32(address)?;
+                let new = match amo {
+                    Rv32iInstruction::AmoswapW(_) => source,
+                    Rv32iInstruction::AmoaddW(_) => old.wrapping_add(source),
+                    Rv32iInstruction::AmoxorW(_) => old ^ source,
+                    Rv32iInstruction::AmoandW(_) => old & source,
+                    Rv32iInstruction::AmoorW(_) => old | source,
+                    Rv32iInstruction::AmominW(_) => (old as i32).min(source as i32) as u32,
+                    Rv32iInstruction::AmomaxW(_) => (old as i32).max(source as i32) as u32,
+                    Rv32iInstruction::AmominuW(_) => old.min(source),
+                    _ => old.max(source),
+                };
+                self.memory.write_u32(address, new)?;
+                (
+                    old,
+                    MemoryAccess::Write {
+                        address,
+                        size: 4,
+                        value: new,
+                    },
+                )
+            }
+        };
+
+        self.set_atomic_result(r.rd, result);
+        Ok(Some(access))
+    }
+
+    fn set_atomic_result(&mut self, rd: Register, value: u32) {
+        self.vm_state.registers.write(rd, value as i32);
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_fail_a_store_conditional_after_a_store_to_the_word() {
+        // 0x1000 lui a1, 0x1
+        // 0x1004 addi a1, a1, 0x80
+        // 0x1008 addi a2, zero, 7
+        // 0x100c lr.w a0, (a1)
+        // 0x1010 sc.w a3, a2, (a1)    <- succeeds, a3 = 0
+        // 0x1014 lr.w a0, (a1)
+        // 0x1018 sw zero, 0(a1)
+        // 0x101c sc.w a4, a2, (a1)    <- fails, a4 = 1
+        // 0x1020 amoadd.w a5, a2, (a1)
+        // 0x1024 ebreak
+        let program: Vec<u8> = [
+            0x0000_15b7u32,
+            0x0805_8593,
+            0x0070_0613,
+            0x1005_a52f,
+            0x18c5_a6af,
+            0x1005_a52f,
+            0x0005_a023,
+            0x18c5_a72f,
+            0x00c5_a7af,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        let registers = vm.vm_state.registers;
+        assert_eq!((registers[13], registers[14], registers[15]), (0, 1, 0));
+        assert_eq!(vm.memory.read_u32(0x1080).unwrap(), 7);
+    }
+}
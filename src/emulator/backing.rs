@@ -0,0 +1,221 @@
+//! What holds the bytes of the guest RAM. Small memories are a `Vec`, big
+//! ones on native hosts an anonymous mmap: the kernel hands out zeroed pages
+//! only when they are first touched, so a vm with 1 GiB of RAM starts
+//! right away and only uses the memory the guest does. On wasm (and without
+//! `std`) everything is a `Vec`, the web frontend keeps a view into it.
+//!
+//! `fork` freezes the bytes into an unlinked temporary file that the parent
+//! and the child then both map privately: a page is only copied once one of
+//! them writes to it. Hosts without files just clone.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+#[cfg(all(feature = "std", unix))]
+use std::fs::File;
+#[cfg(all(feature = "std", unix))]
+use std::sync::Arc;
+
+use super::prelude::*;
+
+/// memories from this size up are mapped lazily
+pub const MMAP_THRESHOLD: usize = 16 << 20;
+/// memories from this size up are forked copy-on-write
+pub const FORK_THRESHOLD: usize = 1 << 20;
+/// the clone of a mapping copies the pages that are not all zeros
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+const PAGE_SIZE: usize = 4096;
+
+pub(super) enum Backing {
+    Heap(Vec<u8>),
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    Mmap(memmap2::MmapMut),
+    /// a private mapping of a frozen image, `image` is `None` once the
+    /// mapping was written to since
+    #[cfg(all(feature = "std", unix))]
+    Shared {
+        map: memmap2::MmapMut,
+        image: Option<Arc<File>>,
+    },
+}
+
+impl Backing {
+    /// `size` zeroed bytes, mapped when there are many and the host can
+    pub(super) fn zeroed(size: usize) -> Self {
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if size >= MMAP_THRESHOLD {
+            // without a mapping the heap still works, eagerly
+            if let Ok(map) = memmap2::MmapMut::map_anon(size) {
+                return Self::Mmap(map);
+            }
+        }
+        Self::Heap(vec![0; size])
+    }
+
+    /// whether the pages are only allocated once they are touched
+    pub(super) fn is_lazy(&self) -> bool {
+        !matches!(self, Self::Heap(_))
+    }
+
+    /// a copy that shares the pages with `self` until either writes to them
+    #[cfg(all(feature = "std", unix))]
+    pub(super) fn fork(&mut self) -> Self {
+        if self.len() >= FORK_THRESHOLD {
+            let image = match self {
+                Self::Shared {
+                    image: Some(image), ..
+                } => Some(image.clone()),
+                _ => None,
+            };
+            // the parent moves onto the image as well, so its later writes
+            // stay its own
+            let image = image.or_else(|| {
+                let image = Arc::new(self.freeze().ok()?);
+                *self = Self::map_image(&image)?;
+                Some(image)
+            });
+            if let Some(child) = image.and_then(|image| Self::map_image(&image)) {
+                return child;
+            }
+        }
+        self.clone()
+    }
+
+    #[cfg(not(all(feature = "std", unix)))]
+    pub(super) fn fork(&mut self) -> Self {
+        self.clone()
+    }
+
+    /// the bytes in an unlinked temporary file, only the pages that are
+    /// not all zeros are written
+    #[cfg(all(feature = "std", unix))]
+    fn freeze(&self) -> std::io::Result<File> {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use std::os::unix::fs::FileExt;
+
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "riscv-vm-fork-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        file.set_len(self.len() as u64)?;
+        for (index, page) in self.chunks(PAGE_SIZE).enumerate() {
+            if page.iter().any(|byte| *byte != 0) {
+                file.write_all_at(page, (index * PAGE_SIZE) as u64)?;
+            }
+        }
+        Ok(file)
+    }
+
+    #[cfg(all(feature = "std", unix))]
+    fn map_image(image: &Arc<File>) -> Option<Self> {
+        // SAFETY: the file is unlinked and nobody writes to it after
+        // `freeze`, so it never changes under the mapping
+        let map = unsafe { memmap2::MmapOptions::new().map_copy(&**image) }.ok()?;
+        Some(Self::Shared {
+            map,
+            image: Some(image.clone()),
+        })
+    }
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Heap(bytes) => bytes,
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            Self::Mmap(map) => map,
+            #[cfg(all(feature = "std", unix))]
+            Self::Shared { map, .. } => map,
+        }
+    }
+}
+
+impl DerefMut for Backing {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(bytes) => bytes,
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            Self::Mmap(map) => map,
+            #[cfg(all(feature = "std", unix))]
+            Self::Shared { map, image } => {
+                // the next fork has to freeze the new bytes
+                *image = None;
+                map
+            }
+        }
+    }
+}
+
+impl Clone for Backing {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Heap(bytes) => Self::Heap(bytes.clone()),
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            map => {
+                // the untouched pages stay untouched in the copy
+                let mut copy = Self::zeroed(map.len());
+                for (to, from) in copy.chunks_mut(PAGE_SIZE).zip(map.chunks(PAGE_SIZE)) {
+                    if from.iter().any(|byte| *byte != 0) {
+                        to.copy_from_slice(from);
+                    }
+                }
+                copy
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.len())?;
+        if self.is_lazy() {
+            write!(f, " (mapped)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backing, FORK_THRESHOLD, MMAP_THRESHOLD};
+
+    #[test]
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    fn should_map_big_memories_lazily() {
+        assert!(!Backing::zeroed(0x1000).is_lazy());
+
+        let mut backing = Backing::zeroed(MMAP_THRESHOLD);
+        assert!(backing.is_lazy());
+        assert_eq!(backing.len(), MMAP_THRESHOLD);
+        backing[MMAP_THRESHOLD - 1] = 7;
+
+        let copy = backing.clone();
+        assert!(copy.is_lazy());
+        assert_eq!((copy[0], copy[MMAP_THRESHOLD - 1]), (0, 7));
+    }
+
+    #[test]
+    fn should_fork_without_sharing_writes() {
+        for size in [0x1000, FORK_THRESHOLD, MMAP_THRESHOLD] {
+            let mut parent = Backing::zeroed(size);
+            parent[0] = 1;
+            let mut child = parent.fork();
+            child[0] = 2;
+            parent[size - 1] = 3;
+            // forking again after a write sees the write
+            let grandchild = parent.fork();
+            assert_eq!((parent[0], parent[size - 1]), (1, 3));
+            assert_eq!((child[0], child[size - 1]), (2, 0));
+            assert_eq!((grandchild[0], grandchild[size - 1]), (1, 3));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Many independent programs at once, spread over threads with rayon: the
+//! submissions of a class against the same tests, or a fuzz corpus. Every
+//! job gets a vm of its own, set up like `riscv-vm run` does (its segments,
+//! a stack with its arguments, the Linux syscalls) with the batch's
+//! `VmLimits`, and runs to the end. The results come back in the order of
+//! the jobs, with what the program wrote:
+//!
+//! ```text
+//! let limits = VmLimits::new().with_max_instructions(10_000_000);
+//! let results = Batch::new().with_limits(limits).run(&jobs);
+//! for result in &results {
+//!     println!("{}: {:?}", result.name, result.exit_code());
+//! }
+//! ```
+//!
+//! A vm never leaves the thread it was made on, so the jobs only hold
+//! bytes and the hooks and devices of a vm can not be part of one.
+
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use thiserror::Error;
+
+use super::elf::{Elf, ElfError};
+use super::emulator::{StopReason, Vm};
+use super::error::VmError;
+use super::fs::VirtFs;
+use super::limits::VmLimits;
+use super::process::{user_memory_map, DEFAULT_STACK_SIZE, DEFAULT_STACK_TOP, STACK_GUARD_SIZE};
+use super::stack_limit::StackLimit;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum BatchError {
+    #[error(transparent)]
+    Elf(#[from] ElfError),
+
+    #[error(transparent)]
+    Vm(#[from] VmError),
+}
+
+/// one program of a batch
+#[derive(Debug, Clone)]
+pub struct BatchJob {
+    /// names the result, e.g. the path of the file
+    pub name: String,
+    /// a static RV32 ELF file
+    pub elf: Vec<u8>,
+    /// after argv[0], which is `name`
+    pub args: Vec<String>,
+    /// what the guest can open, its input
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+impl BatchJob {
+    pub fn new(name: impl Into<String>, elf: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: name.into(),
+            elf: elf.into(),
+            args: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn with_args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// a file at `path` in the guest's file system
+    pub fn with_file(mut self, path: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.files.push((path.to_string(), contents.into()));
+        self
+    }
+}
+
+/// how one job went
+#[derive(Debug, Clone)]
+pub struct BatchResult {
+    pub name: String,
+    /// why the program stopped, or why it did not load or run to the end
+    pub outcome: Result<StopReason, BatchError>,
+    /// what the guest wrote to fd 1 and `tohost`
+    pub stdout: Vec<u8>,
+    /// what the guest wrote to fd 2
+    pub stderr: Vec<u8>,
+    pub instructions: u64,
+    pub wall_time: Duration,
+}
+
+impl BatchResult {
+    /// the exit status of the guest, `None` when it did not exit
+    pub fn exit_code(&self) -> Option<i32> {
+        let stop_reason = self.outcome.as_ref().ok()?;
+        Some(stop_reason.exit_reason()?.code())
+    }
+}
+
+/// runs jobs in parallel
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    limits: VmLimits,
+    /// `None` uses rayon's global pool, one thread per core
+    threads: Option<usize>,
+}
+
+impl Batch {
+    /// no limits and a thread per core
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the limits of every job, on its own
+    pub fn with_limits(mut self, limits: VmLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// runs every job, the results are in the same order
+    pub fn run(&self, jobs: &[BatchJob]) -> Vec<BatchResult> {
+        let run_all = || jobs.par_iter().map(|job| self.run_job(job)).collect();
+        let pool = self.threads.and_then(|threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .ok()
+        });
+        match pool {
+            Some(pool) => pool.install(run_all),
+            None => run_all(),
+        }
+    }
+
+    fn run_job(&self, job: &BatchJob) -> BatchResult {
+        let start = Instant::now();
+        let mut result = BatchResult {
+            name: job.name.clone(),
+            outcome: Ok(StopReason::Ebreak),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            instructions: 0,
+            wall_time: Duration::ZERO,
+        };
+        result.outcome = self.load(job).and_then(|mut vm| {
+            let outcome = vm.run().map_err(BatchError::from);
+            if let Some(syscalls) = &mut vm.syscalls {
+                result.stdout = core::mem::take(&mut syscalls.stdout);
+                result.stderr = core::mem::take(&mut syscalls.stderr);
+            }
+            if let Some(htif) = &mut vm.htif {
+                result.stdout.append(&mut htif.output);
+            }
+            result.instructions = vm.stats.instructions_retired;
+            outcome
+        });
+        result.wall_time = start.elapsed();
+        result
+    }
+
+    /// the vm of `job`, ready to run
+    fn load(&self, job: &BatchJob) -> Result<Vm, BatchError> {
+        let elf = Elf::parse(&job.elf)?;
+        let map = user_memory_map(&elf, DEFAULT_STACK_TOP, DEFAULT_STACK_SIZE);
+        let mut fs = VirtFs::new();
+        for (path, contents) in &job.files {
+            fs.insert(path, contents.clone());
+        }
+        let stack_limit = StackLimit::new(DEFAULT_STACK_TOP - DEFAULT_STACK_SIZE, STACK_GUARD_SIZE);
+        let mut vm = Vm::from_memory_map_with_limits(&map, self.limits)?
+            .with_file_system(fs)
+            .with_stack_limit(stack_limit);
+        let entry = vm.load_elf(&job.elf)?;
+        let args: Vec<&str> = core::iter::once(job.name.as_str())
+            .chain(job.args.iter().map(String::as_str))
+            .collect();
+        vm.push_arguments(DEFAULT_STACK_TOP, &args, &[], entry)?;
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, BatchError, BatchJob};
+    use crate::elf::{test_elf, ElfError};
+    use crate::limits::VmLimits;
+    use crate::VmError;
+
+    #[test]
+    fn should_run_every_job_with_its_own_limits() {
+        // 0x1000 addi a0, zero, 42
+        // 0x1004 addi a7, zero, 93
+        // 0x1008 ecall                 <- exit(42)
+        let exits = test_elf::build(0x1000, &[0x02a0_0513, 0x05d0_0893, 0x0000_0073], 0, &[]);
+        // 0x1000 jal zero, 0
+        let spins = test_elf::build(0x1000, &[0x0000_006f], 0, &[]);
+        let jobs = [
+            BatchJob::new("exits", exits.clone()),
+            BatchJob::new("spins", spins),
+            BatchJob::new("garbage", *b"nope"),
+            BatchJob::new("exits again", exits).with_args(&["a", "b"]),
+        ];
+
+        let limits = VmLimits::new().with_max_instructions(1000);
+        let results = Batch::new().with_limits(limits).with_threads(2).run(&jobs);
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names, ["exits", "spins", "garbage", "exits again"]);
+        assert_eq!(results[0].exit_code(), Some(42));
+        assert_eq!(results[0].instructions, 3);
+        assert!(matches!(
+            results[1].outcome,
+            Err(BatchError::Vm(VmError::ExecutionLimitExceeded {
+                limit: 1000,
+                ..
+            }))
+        ));
+        assert_eq!(results[2].outcome, Err(BatchError::Elf(ElfError::NotElf32)));
+        assert_eq!(results[3].exit_code(), Some(42));
+    }
+}
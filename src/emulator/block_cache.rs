@@ -0,0 +1,279 @@
+//! A basic block interpreter: the register-only instructions from a pc up
+//! to and including the first branch are decoded once into a block of
+//! pre-resolved handlers (see `dispatch.rs`). A block remembers the blocks
+//! it went on to, so a hot loop goes from block to block without looking
+//! anything up. Plain Rust, so it works under wasm too, unlike the jit.
+//!
+//! Loads, stores, jumps, ecalls and CSR instructions end a block before
+//! them and go through the normal interpreter. A guest store to a page with
+//! blocks on it drops all blocks, as does loading code.
+
+use super::dispatch::{Dispatch, Opcode};
+use super::emulator::Vm;
+use super::memory::Memory;
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+
+const PAGE_SHIFT: u32 = 12;
+
+/// how many instructions a block has at most, for the jit too
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// how many instructions run before the blocks go back to the run loop
+const MAX_CHAIN_LENGTH: u64 = 4096;
+
+#[derive(Debug)]
+struct Block {
+    instructions: Vec<Dispatch>,
+    /// the blocks this one went on to, as (pc, index in `blocks`). A branch
+    /// has two
+    successors: [Option<(u32, usize)>; 2],
+}
+
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    /// the index in `blocks` per start pc, `None` when no block starts there
+    starts: HashMap<u32, Option<usize>>,
+    /// the pages with code of a block
+    pages: HashSet<u32>,
+    /// how many instructions ran from blocks
+    pub instructions: u64,
+}
+
+impl BlockCache {
+    /// the index of the block at `pc`, decoding it if needed. `None` if the
+    /// instruction at `pc` can not start a block
+    fn block(&mut self, pc: u32, memory: &Memory) -> Option<usize> {
+        if let Some(index) = self.starts.get(&pc) {
+            return *index;
+        }
+
+        let instructions = decode_block(pc, memory);
+        let index = (!instructions.is_empty()).then(|| {
+            let end = pc.wrapping_add(4 * (instructions.len() as u32 - 1));
+            self.pages.insert(pc >> PAGE_SHIFT);
+            self.pages.insert(end >> PAGE_SHIFT);
+            self.blocks.push(Block {
+                instructions,
+                successors: [None; 2],
+            });
+            self.blocks.len() - 1
+        });
+        self.starts.insert(pc, index);
+        index
+    }
+
+    /// the block after `block` at `pc`, through its successors when it went
+    /// there before
+    fn next(&mut self, block: usize, pc: u32, memory: &Memory) -> Option<usize> {
+        let successors = self.blocks[block].successors;
+        if let Some((_, next)) = successors.iter().flatten().find(|(start, _)| *start == pc) {
+            return Some(*next);
+        }
+
+        let next = self.block(pc, memory)?;
+        if let Some(slot) = self.blocks[block]
+            .successors
+            .iter_mut()
+            .find(|slot| slot.is_none())
+        {
+            *slot = Some((pc, next));
+        }
+        Some(next)
+    }
+
+    /// drops every block if the `size` bytes from `address` touch a page
+    /// with code of one
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        let last = address.wrapping_add(size.max(1) - 1);
+        if self.pages.contains(&(address >> PAGE_SHIFT))
+            || self.pages.contains(&(last >> PAGE_SHIFT))
+        {
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.starts.clear();
+        self.pages.clear();
+    }
+
+    /// how many blocks are decoded
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// the register-only instructions from `pc`, up to and including the first
+/// branch
+pub(super) fn decode_block(pc: u32, memory: &Memory) -> Vec<Dispatch> {
+    let mut instructions = Vec::new();
+    let mut address = pc;
+    while instructions.len() < MAX_BLOCK_LENGTH {
+        let Some(dispatch) = memory
+            .fetch_u32(address)
+            .ok()
+            .and_then(|word| {
+                Rv32iInstruction::from_core_instruction_format(word.to_le_bytes()).ok()
+            })
+            .and_then(|instruction| Dispatch::of(&instruction))
+            .filter(|dispatch| !matches!(dispatch.opcode, Opcode::Jal | Opcode::Jalr))
+        else {
+            break;
+        };
+        instructions.push(dispatch);
+        if dispatch.is_branch() {
+            break;
+        }
+        address = address.wrapping_add(4);
+    }
+    instructions
+}
+
+impl Vm {
+    /// runs register-only code as basic blocks, see `block_cache.rs`. The
+    /// run statistics count the instructions of blocks, but not per pc or
+    /// for the stack
+    pub fn with_block_cache(mut self) -> Self {
+        self.block_cache = Some(BlockCache::default());
+        self
+    }
+
+    /// whether nothing wants to see each instruction: no hooks, timing, gas,
+    /// performance counters, breakpoints, CLINT or SBI timer, and no paging.
+    /// Only then can the blocks and the jit run
+    pub(super) fn is_plain(&self) -> bool {
+        self.hooks.is_empty()
+            && self.timing.is_none()
+            && self.caches.is_none()
+            && self.gas.is_none()
+            && self.performance_counters.is_none()
+            && self.breakpoints.breakpoints().next().is_none()
+            && self.clint.is_none()
+            && self.stack_limit.is_none()
+            && self.folded_stacks.is_none()
+            && self.sbi.as_ref().is_none_or(|sbi| sbi.timer.is_none())
+            && !self.is_paging()
+    }
+
+    /// runs blocks from `pc` on for as long as they chain, returns whether
+    /// it ran any
+    pub(super) fn run_blocks(&mut self, pc: u32) -> bool {
+        if !self.is_plain() {
+            return false;
+        }
+        // the interpreter reports the instruction that crosses the limit
+        let budget = self.budget(MAX_CHAIN_LENGTH);
+        let Some(cache) = &mut self.block_cache else {
+            return false;
+        };
+        let Some(mut block) = cache.block(pc, &self.memory) else {
+            return false;
+        };
+        let mut retired = 0;
+        loop {
+            let instructions = &cache.blocks[block].instructions;
+            if retired + instructions.len() as u64 > budget {
+                break;
+            }
+            for instruction in instructions {
+                instruction.execute(&mut self.vm_state);
+            }
+            retired += instructions.len() as u64;
+
+            match cache.next(block, self.vm_state.pc as u32, &self.memory) {
+                Some(next) => block = next,
+                None => break,
+            }
+        }
+
+        cache.instructions += retired;
+        self.stats.instructions_retired += retired;
+        retired > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hpm::{HpmEvent, PerformanceCounters, CSR_MHPMEVENT3};
+    use crate::{StopReason, Vm, VmError};
+
+    /// 0x1000 addi t0, zero, 100
+    /// 0x1004 addi a0, a0, 3       <- loop
+    /// 0x1008 slli a1, a0, 2
+    /// 0x100c blt a1, a0, 0x1014
+    /// 0x1010 xor a3, a3, a1
+    /// 0x1014 addi t0, t0, -1
+    /// 0x1018 bne t0, zero, 0x1004
+    /// 0x101c ebreak
+    const LOOP: [u32; 8] = [
+        0x0640_0293,
+        0x0035_0513,
+        0x0025_1593,
+        0x00a5_c463,
+        0x00b6_c6b3,
+        0xfff2_8293,
+        0xfe02_96e3,
+        0x0010_0073,
+    ];
+
+    #[test]
+    fn should_run_chained_blocks_like_the_interpreter() {
+        let mut interpreted = Vm::new(0x1000, 0x100);
+        interpreted
+            .load_program(
+                0x1000,
+                &LOOP
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        assert_eq!(interpreted.run(), Ok(StopReason::Ebreak));
+
+        let mut vm = Vm::with_program_words(0x1000, &LOOP).with_block_cache();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers, interpreted.vm_state.registers);
+        assert_eq!(
+            vm.stats.instructions_retired,
+            interpreted.stats.instructions_retired
+        );
+        let cache = vm.block_cache.as_ref().unwrap();
+        // 0x1000, 0x1004 and 0x1010
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.instructions, interpreted.stats.instructions_retired);
+    }
+
+    #[test]
+    fn performance_counters_should_see_the_branches_of_every_iteration() {
+        let mut vm = Vm::with_program_words(0x1000, &LOOP)
+            .with_block_cache()
+            .with_performance_counters(PerformanceCounters::new());
+        vm.write_csr(CSR_MHPMEVENT3, HpmEvent::Branches as u32);
+        vm.write_csr(CSR_MHPMEVENT3 + 1, HpmEvent::TakenBranches as u32);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+
+        // the blt and the bne of 100 iterations, all but the last bne taken
+        let counters = vm.performance_counters.as_ref().unwrap();
+        assert_eq!(counters.counter(3), 200);
+        assert_eq!(counters.counter(4), 99);
+        assert_eq!(vm.block_cache.as_ref().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn should_stop_at_the_execution_limit() {
+        let mut vm = Vm::with_program_words(0x1000, &LOOP).with_block_cache();
+        vm.execution_limit = Some(50);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::ExecutionLimitExceeded { limit: 50, .. })
+        ));
+        assert_eq!(vm.stats.instructions_retired, 50);
+    }
+}
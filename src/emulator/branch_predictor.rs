@@ -0,0 +1,158 @@
+//! Branch predictor models to show how well a branch predicts, for teaching
+//! microarchitecture. The profiler feeds every retired conditional branch
+//! to the predictor: it predicts first, then learns the outcome, and the
+//! report has the mispredictions per branch. Nothing here changes how the
+//! vm runs.
+
+use core::fmt;
+
+use super::prelude::*;
+
+/// a branch predictor. Implement it for a model of your own and give it to
+/// `Profiler::set_branch_predictor`
+pub trait BranchPredictor: fmt::Debug {
+    /// whether the branch at `pc` to `target` will be taken
+    fn predict(&mut self, pc: u32, target: u32) -> bool;
+
+    /// the branch at `pc` was `taken` or not
+    fn update(&mut self, pc: u32, taken: bool);
+}
+
+/// backward taken, forward not taken: loops predict right except for their
+/// last iteration
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StaticPredictor;
+
+impl BranchPredictor for StaticPredictor {
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        target < pc
+    }
+
+    fn update(&mut self, _pc: u32, _taken: bool) {}
+}
+
+/// the table of 2 bit saturating counters the two predictors below share,
+/// 0 and 1 predict not taken, 2 and 3 taken
+#[derive(Debug, Clone)]
+struct Counters(Vec<u8>);
+
+impl Counters {
+    fn new(index_bits: u32) -> Self {
+        // weakly not taken
+        Self(vec![1; 1 << index_bits])
+    }
+
+    fn index(&self, value: u32) -> usize {
+        value as usize & (self.0.len() - 1)
+    }
+
+    fn predict(&self, index: usize) -> bool {
+        self.0[index] >= 2
+    }
+
+    fn update(&mut self, index: usize, taken: bool) {
+        let counter = &mut self.0[index];
+        *counter = match taken {
+            true => (*counter + 1).min(3),
+            false => counter.saturating_sub(1),
+        };
+    }
+}
+
+/// a 2 bit saturating counter per branch, indexed by the low bits of the pc
+#[derive(Debug, Clone)]
+pub struct TwoBitPredictor {
+    counters: Counters,
+}
+
+impl TwoBitPredictor {
+    /// `2^index_bits` counters, branches that share the index share one
+    pub fn new(index_bits: u32) -> Self {
+        Self {
+            counters: Counters::new(index_bits),
+        }
+    }
+}
+
+impl BranchPredictor for TwoBitPredictor {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(self.counters.index(pc >> 1))
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        let index = self.counters.index(pc >> 1);
+        self.counters.update(index, taken);
+    }
+}
+
+/// gshare: the 2 bit counters are indexed by the pc xor the outcomes of the
+/// last branches, so a branch can learn patterns that depend on the branches
+/// before it
+#[derive(Debug, Clone)]
+pub struct GsharePredictor {
+    counters: Counters,
+    /// the last outcomes, the latest in bit 0
+    history: u32,
+}
+
+impl GsharePredictor {
+    /// `2^history_bits` counters and as many outcomes in the history
+    pub fn new(history_bits: u32) -> Self {
+        Self {
+            counters: Counters::new(history_bits),
+            history: 0,
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        self.counters.index((pc >> 1) ^ self.history)
+    }
+}
+
+impl BranchPredictor for GsharePredictor {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(self.index(pc))
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        let index = self.index(pc);
+        self.counters.update(index, taken);
+        self.history = self.history << 1 | u32::from(taken);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BranchPredictor, GsharePredictor, StaticPredictor, TwoBitPredictor};
+
+    /// how often `predictor` is wrong about the branch at 0x1010 going back
+    /// to 0x1000 with `outcomes`
+    fn mispredictions(predictor: &mut dyn BranchPredictor, outcomes: &[bool]) -> usize {
+        outcomes
+            .iter()
+            .filter(|taken| {
+                let predicted = predictor.predict(0x1010, 0x1000);
+                predictor.update(0x1010, **taken);
+                predicted != **taken
+            })
+            .count()
+    }
+
+    #[test]
+    fn should_learn_what_the_models_can_learn() {
+        // a loop of 4 iterations, run 4 times
+        let loops: Vec<bool> = [true, true, true, false].repeat(4);
+        assert_eq!(mispredictions(&mut StaticPredictor, &loops), 4);
+        assert_eq!(mispredictions(&mut TwoBitPredictor::new(4), &loops), 5);
+
+        // a counter is wrong about alternating outcomes every time, the
+        // history learns them
+        let alternating: Vec<bool> = [true, false].repeat(16);
+        assert_eq!(
+            mispredictions(&mut TwoBitPredictor::new(4), &alternating),
+            32
+        );
+        let gshare = mispredictions(&mut GsharePredictor::new(4), &alternating);
+        assert!(gshare <= 4, "gshare mispredicted {gshare} times");
+    }
+}
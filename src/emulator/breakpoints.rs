@@ -0,0 +1,171 @@
+use alloc::collections::BTreeSet;
+
+use super::memory::MemoryAccess;
+use super::prelude::*;
+
+/// which accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// reads and writes
+    Access,
+}
+
+/// stops `run()` after an access that touches `start..start + size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    pub size: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// true if the access overlaps the watched range and is of the watched
+    /// kind
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        let (address, size, kind_matches) = match *access {
+            MemoryAccess::Read { address, size, .. } => {
+                (address, size, self.kind != WatchKind::Write)
+            }
+            MemoryAccess::Write { address, size, .. } => {
+                (address, size, self.kind != WatchKind::Read)
+            }
+        };
+
+        let start = self.start as u64;
+        let end = start + self.size as u64;
+        let access_start = address as u64;
+        let access_end = access_start + size as u64;
+
+        kind_matches && access_start < end && start < access_end
+    }
+}
+
+/// The breakpoints and watchpoints of a vm. `Vm::run()` checks them and
+/// returns `StopReason::Breakpoint`/`StopReason::Watchpoint` when one is hit,
+/// so every frontend (web debugger, GDB stub, ...) shares this one
+/// implementation.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    pcs: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Breakpoints {
+    /// stops `run()` before the instruction at `pc` executes
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.pcs.insert(pc);
+    }
+
+    /// returns false if there was no breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        self.pcs.remove(&pc)
+    }
+
+    pub fn is_breakpoint(&self, pc: u32) -> bool {
+        self.pcs.contains(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pcs.iter().copied()
+    }
+
+    /// stops `run()` right after an instruction accessed `start..start + size`
+    pub fn add_watchpoint(&mut self, start: u32, size: u32, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { start, size, kind });
+    }
+
+    /// removes the watchpoints on exactly this range, returns how many there
+    /// were
+    pub fn remove_watchpoint(&mut self, start: u32, size: u32) -> usize {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.start != start || watchpoint.size != size);
+        before - self.watchpoints.len()
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// the first watchpoint the access hits
+    pub fn watchpoint_hit(&self, access: &MemoryAccess) -> Option<&Watchpoint> {
+        self.watchpoints
+            .iter()
+            .find(|watchpoint| watchpoint.matches(access))
+    }
+
+    pub fn clear(&mut self) {
+        self.pcs.clear();
+        self.watchpoints.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WatchKind, Watchpoint};
+    use crate::memory::MemoryAccess;
+    use crate::{StopReason, Vm};
+
+    // 0x1000 addi a0, zero, 42
+    // 0x1004 lui t0, 0x1
+    // 0x1008 sw a0, 0x80(t0)
+    // 0x100c lw a1, 0x80(t0)
+    // 0x1010 ebreak
+    const PROGRAM: [u32; 5] = [
+        0x02a0_0513,
+        0x0000_12b7,
+        0x08a2_a023,
+        0x0802_a583,
+        0x0010_0073,
+    ];
+
+    #[test]
+    fn run_should_stop_at_a_breakpoint_and_continue_from_it() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.breakpoints.add_breakpoint(0x1004);
+
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint { pc: 0x1004 }));
+        assert_eq!(vm.vm_state.pc, 0x1004);
+        assert_eq!(vm.vm_state.registers[10], 42);
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.pc, 0x1010);
+    }
+
+    #[test]
+    fn run_should_stop_after_a_watched_access() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.breakpoints.add_watchpoint(0x1080, 4, WatchKind::Write);
+        // only overlaps the last byte of the word lw reads
+        vm.breakpoints.add_watchpoint(0x1083, 1, WatchKind::Read);
+
+        assert_eq!(
+            vm.run(),
+            Ok(StopReason::Watchpoint {
+                pc: 0x1008,
+                watchpoint: Watchpoint {
+                    start: 0x1080,
+                    size: 4,
+                    kind: WatchKind::Write
+                },
+                access: MemoryAccess::Write {
+                    address: 0x1080,
+                    size: 4,
+                    value: 42
+                }
+            })
+        );
+        assert_eq!(vm.vm_state.pc, 0x100c);
+
+        let Ok(StopReason::Watchpoint { pc, .. }) = vm.run() else {
+            panic!("the read watchpoint should hit");
+        };
+        assert_eq!(pc, 0x100c);
+        assert_eq!(vm.vm_state.registers[11], 42);
+
+        assert_eq!(vm.breakpoints.remove_watchpoint(0x1083, 1), 1);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+    }
+}
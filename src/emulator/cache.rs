@@ -0,0 +1,350 @@
+//! A model of the L1 caches, to see the locality of guest code: an
+//! instruction cache that every fetch goes through and a data cache for the
+//! loads and stores, each with its size, associativity and line size and
+//! LRU replacement. It only counts hits and misses, the data always comes
+//! from memory; add a `TimingModel` for cycles.
+//!
+//! Every access is charged to the instruction that did it, so the report
+//! can say which functions miss. An access is one line, the one its address
+//! is in, even when a misaligned one spans two.
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+
+use super::emulator::Vm;
+use super::memory::MemoryAccess;
+use super::prelude::*;
+use super::profile::FunctionRange;
+
+/// the geometry of a cache, every number has to be a power of two
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// in bytes
+    pub size: u32,
+    /// lines per set, 1 is direct mapped
+    pub associativity: u32,
+    /// in bytes
+    pub line_size: u32,
+}
+
+impl CacheConfig {
+    pub fn new(size: u32, associativity: u32, line_size: u32) -> Self {
+        Self {
+            size,
+            associativity,
+            line_size,
+        }
+    }
+
+    pub fn sets(&self) -> u32 {
+        self.size / (self.associativity * self.line_size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// misses per access, 0 without accesses
+    pub fn miss_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            accesses => self.misses as f64 / accesses as f64,
+        }
+    }
+
+    fn add(&mut self, other: CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+    }
+}
+
+/// one set associative cache with LRU replacement
+#[derive(Debug, Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    /// the line numbers in each set, the most recently used first
+    sets: Vec<Vec<u32>>,
+    pub stats: CacheStats,
+    /// per pc of the instruction that did the access
+    per_pc: BTreeMap<u32, CacheStats>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        let CacheConfig {
+            size,
+            associativity,
+            line_size,
+        } = config;
+        assert!(
+            [size, associativity, line_size]
+                .iter()
+                .all(|number| number.is_power_of_two())
+                && associativity * line_size <= size,
+            "the cache has to be a power of two sets of {associativity} lines of {line_size} bytes"
+        );
+        Self {
+            config,
+            sets: vec![Vec::new(); config.sets() as usize],
+            stats: CacheStats::default(),
+            per_pc: BTreeMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    /// looks up the line of `address` for the instruction at `pc` and
+    /// returns whether it hit. A miss evicts the least recently used line of
+    /// the set when it is full
+    pub fn access(&mut self, pc: u32, address: u32) -> bool {
+        let line = address / self.config.line_size;
+        let set = &mut self.sets[(line % self.config.sets()) as usize];
+        let hit = match set.iter().position(|cached| *cached == line) {
+            Some(index) => {
+                set.remove(index);
+                true
+            }
+            None => {
+                set.truncate(self.config.associativity as usize - 1);
+                false
+            }
+        };
+        set.insert(0, line);
+
+        let stats = self.per_pc.entry(pc).or_default();
+        for stats in [stats, &mut self.stats] {
+            match hit {
+                true => stats.hits += 1,
+                false => stats.misses += 1,
+            }
+        }
+        hit
+    }
+
+    /// the hits and misses per pc of the instructions that did the accesses
+    pub fn per_pc(&self) -> &BTreeMap<u32, CacheStats> {
+        &self.per_pc
+    }
+
+    /// empties the cache and forgets the counts
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+}
+
+/// the hits and misses of the code of one function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCacheStats {
+    /// "other" for the code outside of every function
+    pub name: String,
+    pub instruction: CacheStats,
+    pub data: CacheStats,
+}
+
+/// The split L1: either cache is optional
+#[derive(Debug, Clone, Default)]
+pub struct Caches {
+    pub instruction: Option<Cache>,
+    pub data: Option<Cache>,
+}
+
+impl Caches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_instruction_cache(mut self, config: CacheConfig) -> Self {
+        self.instruction = Some(Cache::new(config));
+        self
+    }
+
+    pub fn with_data_cache(mut self, config: CacheConfig) -> Self {
+        self.data = Some(Cache::new(config));
+        self
+    }
+
+    /// the fetch of the instruction at `pc` and the load or store it did
+    pub fn access(&mut self, pc: u32, access: Option<MemoryAccess>) {
+        if let Some(cache) = &mut self.instruction {
+            cache.access(pc, pc);
+        }
+        if let (
+            Some(cache),
+            Some(MemoryAccess::Read { address, .. } | MemoryAccess::Write { address, .. }),
+        ) = (&mut self.data, access)
+        {
+            cache.access(pc, address);
+        }
+    }
+
+    /// the hits and misses per function of `functions`, the most misses
+    /// first. Functions without accesses are left out
+    pub fn per_function(&self, functions: &[FunctionRange]) -> Vec<FunctionCacheStats> {
+        let mut per_function: BTreeMap<&str, (CacheStats, CacheStats)> = BTreeMap::new();
+        let name_of = |pc: &u32| {
+            functions
+                .iter()
+                .find(|function| (function.start..function.end).contains(pc))
+                .map_or("other", |function| function.name.as_str())
+        };
+        for (cache, instruction) in [(&self.instruction, true), (&self.data, false)] {
+            let Some(cache) = cache else {
+                continue;
+            };
+            for (pc, stats) in cache.per_pc() {
+                let entry = per_function.entry(name_of(pc)).or_default();
+                match instruction {
+                    true => entry.0.add(*stats),
+                    false => entry.1.add(*stats),
+                }
+            }
+        }
+        let mut per_function: Vec<FunctionCacheStats> = per_function
+            .into_iter()
+            .map(|(name, (instruction, data))| FunctionCacheStats {
+                name: name.to_string(),
+                instruction,
+                data,
+            })
+            .collect();
+        per_function
+            .sort_by_key(|stats| core::cmp::Reverse(stats.instruction.misses + stats.data.misses));
+        per_function
+    }
+}
+
+/// ```text
+/// caches:
+///   I$ 4096 bytes, 2-way, 32 byte lines: 950 hits, 50 misses (5.0%)
+/// ```
+impl fmt::Display for Caches {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "caches:")?;
+        for (name, cache) in [("I$", &self.instruction), ("D$", &self.data)] {
+            let Some(cache) = cache else {
+                continue;
+            };
+            let CacheConfig {
+                size,
+                associativity,
+                line_size,
+            } = cache.config;
+            writeln!(
+                f,
+                "  {name} {size} bytes, {associativity}-way, {line_size} byte lines: {} hits, {} misses ({:.1}%)",
+                cache.stats.hits,
+                cache.stats.misses,
+                cache.stats.miss_rate() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// runs the fetches, loads and stores through `caches` from now on
+    pub fn with_caches(mut self, caches: Caches) -> Self {
+        self.caches = Some(caches);
+        self
+    }
+
+    /// the hits and misses per function of the symbol table
+    pub fn cache_stats_per_function(&self) -> Vec<FunctionCacheStats> {
+        self.caches.as_ref().map_or_else(Vec::new, |caches| {
+            caches.per_function(&FunctionRange::from_symbols(&self.symbols))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheConfig, CacheStats, Caches};
+    use crate::elf::{Symbol, SymbolKind};
+    use crate::Vm;
+
+    #[test]
+    fn should_evict_the_least_recently_used_line() {
+        // 2 sets of 2 lines of 16 bytes, 0x00, 0x20 and 0x40 share set 0
+        let mut cache = Cache::new(CacheConfig::new(64, 2, 16));
+        assert!(!cache.access(0, 0x00));
+        assert!(!cache.access(0, 0x24));
+        assert!(cache.access(0, 0x0c));
+        assert!(!cache.access(0, 0x40));
+        // 0x20 was the least recently used
+        assert!(cache.access(0, 0x04));
+        assert!(!cache.access(0, 0x20));
+        assert!(!cache.access(0, 0x10));
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 5 });
+    }
+
+    #[test]
+    fn should_count_the_misses_of_each_function() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 addi t1, zero, 8
+        // 0x1008 lw a0, 256(t0)        <- sum, 8 times, 4 lines apart
+        // 0x100c addi t0, t0, 64
+        // 0x1010 addi t1, t1, -1
+        // 0x1014 bne t1, zero, 0x1008
+        // 0x1018 ebreak
+        let program: Vec<u8> = [
+            0x0000_12b7u32,
+            0x0080_0313,
+            0x1002_a503,
+            0x0402_8293,
+            0xfff3_0313,
+            0xfe03_1ae3,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let caches = Caches::new()
+            .with_instruction_cache(CacheConfig::new(256, 2, 16))
+            .with_data_cache(CacheConfig::new(256, 2, 16));
+        let mut vm = Vm::new(0x1000, 0x800).with_caches(caches);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.symbols.push(Symbol {
+            name: "sum".to_string(),
+            address: 0x1008,
+            size: 0x10,
+            kind: SymbolKind::Function,
+        });
+        vm.run().unwrap();
+
+        let caches = vm.caches.as_ref().unwrap();
+        // the code is two lines
+        let instruction = caches.instruction.as_ref().unwrap();
+        assert_eq!(
+            instruction.stats,
+            CacheStats {
+                hits: 32,
+                misses: 2
+            }
+        );
+        // every load is a line of its own
+        let data = caches.data.as_ref().unwrap();
+        assert_eq!(data.stats, CacheStats { hits: 0, misses: 8 });
+
+        let per_function = vm.cache_stats_per_function();
+        assert_eq!(per_function.len(), 2);
+        assert_eq!(per_function[0].name, "sum");
+        assert_eq!(
+            per_function[0].instruction,
+            CacheStats {
+                hits: 31,
+                misses: 1
+            }
+        );
+        assert_eq!(per_function[0].data, CacheStats { hits: 0, misses: 8 });
+        assert_eq!(per_function[1].name, "other");
+        assert!(vm
+            .summary()
+            .contains("D$ 256 bytes, 2-way, 16 byte lines: 0 hits, 8 misses"));
+    }
+}
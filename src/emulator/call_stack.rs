@@ -0,0 +1,189 @@
+//! A shadow call stack: the vm pushes a frame for every `jal`/`jalr` that
+//! links and pops it on `ret`, so a program that trapped or got stuck can be
+//! shown as a backtrace.
+//!
+//! The guest stack is not unwound, the frames only come from the calls the
+//! vm saw. Code that switches stacks or jumps out of functions (longjmp)
+//! confuses it until the next `ret` to a known return address.
+
+use core::fmt;
+
+use super::emulator::{Instruction, PseudoInstruction, Vm};
+use super::prelude::*;
+use super::profile::FunctionRange;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// deeper call stacks forget their oldest frames, so runaway recursion does
+/// not eat the host memory
+const MAX_DEPTH: usize = 4096;
+
+/// one call the vm saw
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// the pc of the jal/jalr
+    pub call_site: u32,
+    /// where the call went
+    pub target: u32,
+    /// where the callee returns to
+    pub return_address: u32,
+}
+
+/// one line of a backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceEntry {
+    pub pc: u32,
+    /// the function the pc is in and how far into it, if it is known
+    pub function: Option<(String, u32)>,
+}
+
+/// the current pc first, then the call sites up to the first call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace(pub Vec<BacktraceEntry>);
+
+/// ```text
+/// #0 0x00001018 in increment+0x4
+/// #1 0x00001004 in main+0x4
+/// ```
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (depth, entry) in self.0.iter().enumerate() {
+            write!(f, "#{depth} {:#010x}", entry.pc)?;
+            match &entry.function {
+                Some((name, 0)) => writeln!(f, " in {name}")?,
+                Some((name, offset)) => writeln!(f, " in {name}+{offset:#x}")?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// the calls that did not return yet, the oldest first
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    /// where the guest is and how it got there. `functions` gives the names,
+    /// the entries outside of them have no name
+    pub fn backtrace(&self, functions: &[FunctionRange]) -> Backtrace {
+        self.backtrace_at(self.vm_state.pc as u32, functions)
+    }
+
+    /// `backtrace` with `pc` as the current pc
+    pub(super) fn backtrace_at(&self, pc: u32, functions: &[FunctionRange]) -> Backtrace {
+        let pcs =
+            core::iter::once(pc).chain(self.call_stack.iter().rev().map(|frame| frame.call_site));
+
+        Backtrace(
+            pcs.map(|pc| BacktraceEntry {
+                pc,
+                function: functions
+                    .iter()
+                    .find(|function| (function.start..function.end).contains(&pc))
+                    .map(|function| (function.name.clone(), pc - function.start)),
+            })
+            .collect(),
+        )
+    }
+
+    /// called after every instruction, the pc already points to the next one
+    pub(super) fn track_call(&mut self, pc: u32, instruction: &Instruction) {
+        let link = match instruction {
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jal(jal)) => jal.rd,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jalr(jalr))
+                if jalr.rd.is_zero() && jalr.rs1 == Register::RA =>
+            {
+                return self.track_return();
+            }
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jalr(jalr)) => jalr.rd,
+            Instruction::PseudoInstruction(_, PseudoInstruction::Ret) => {
+                return self.track_return();
+            }
+            _ => return,
+        };
+        if link.is_zero() {
+            return;
+        }
+
+        if self.call_stack.len() == MAX_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(Frame {
+            call_site: pc,
+            target: self.vm_state.pc as u32,
+            return_address: pc.wrapping_add(4),
+        });
+    }
+
+    /// pops the frame the ret returned to, and the frames above it that
+    /// never returned. A ret to an address no frame knows leaves the stack
+    /// alone
+    fn track_return(&mut self) {
+        let pc = self.vm_state.pc as u32;
+        if let Some(depth) = self
+            .call_stack
+            .iter()
+            .rposition(|frame| frame.return_address == pc)
+        {
+            self.call_stack.truncate(depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Frame;
+    use crate::profile::FunctionRange;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn backtrace_should_show_the_calls_that_did_not_return() {
+        // 0x1000 addi s0, zero, 3     <- main
+        // 0x1004 jal ra, 0x1014
+        // 0x1008 addi s0, s0, -1
+        // 0x100c bne s0, zero, 0x1004
+        // 0x1010 ebreak
+        // 0x1014 addi a0, a0, 1       <- increment
+        // 0x1018 ret
+        let program: Vec<u8> = [
+            0x0030_0413u32,
+            0x0100_00ef,
+            0xfff4_0413,
+            0xfe04_1ce3,
+            0x0010_0073,
+            0x0015_0513,
+            0x0000_8067,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let functions = [
+            FunctionRange::new("main", 0x1000, 0x1014),
+            FunctionRange::new("increment", 0x1014, 0x101c),
+        ];
+
+        vm.breakpoints.add_breakpoint(0x1018);
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint { pc: 0x1018 }));
+        assert_eq!(
+            vm.call_stack(),
+            [Frame {
+                call_site: 0x1004,
+                target: 0x1014,
+                return_address: 0x1008
+            }]
+        );
+        assert_eq!(
+            vm.backtrace(&functions).to_string(),
+            "#0 0x00001018 in increment+0x4\n#1 0x00001004 in main+0x4\n"
+        );
+
+        vm.breakpoints.clear();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert!(vm.call_stack().is_empty());
+        assert_eq!(vm.backtrace(&[]).to_string(), "#0 0x00001010\n");
+    }
+}
@@ -0,0 +1,325 @@
+//! The time of the vm and the CLINT, the timer of QEMU's `virt` machine.
+//! `rdtime`, the CLINT's `mtime` and the SBI timer all read the same clock,
+//! which ticks with a `ClockSource`: the cycles of the guest (the default,
+//! the same in every run), the host's monotonic clock, or only by hand for
+//! deterministic tests. `advance_time` and `fast_forward_to_timer` move it
+//! forward, so a guest that sleeps does not have to spin through the wait.
+//!
+//! The CLINT has the registers of every hart: `msip` at `4 * hart`,
+//! `mtimecmp` at `0x4000 + 8 * hart` and `mtime` at `0xbff8`, the 64 bit
+//! ones as two words. `mip` gets MTIP while `mtime >= mtimecmp` and MSIP
+//! while `msip` is set.
+
+use super::cooperative::Stopwatch;
+use super::csr::{MIP_MSIP, MIP_MTIP};
+use super::dtb::{CLINT_BASE, CLINT_SIZE};
+use super::emulator::Vm;
+use super::prelude::*;
+use super::replay::ReplayEvent;
+
+/// ticks per second of the host clock, the `timebase-frequency` of the
+/// device tree
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+const MSIP: u32 = 0x0000;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+/// what makes the time pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockSource {
+    /// one tick per cycle (see `Vm::cycles`), the guest sees time pass at
+    /// its own speed
+    #[default]
+    Cycles,
+    /// the host's monotonic clock at `TIMEBASE_FREQUENCY`. Without std it
+    /// stands still
+    Host,
+    /// only `advance_time` moves it
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    pub source: ClockSource,
+    started: Stopwatch,
+    /// what `advance_time` and writes to `mtime` added to the source
+    offset: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(ClockSource::default())
+    }
+}
+
+impl Clock {
+    pub fn new(source: ClockSource) -> Self {
+        Self {
+            source,
+            started: Stopwatch::start(),
+            offset: 0,
+        }
+    }
+}
+
+/// the CLINT registers of the harts
+#[derive(Debug, Clone)]
+pub struct Clint {
+    pub base: u32,
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self {
+            base: CLINT_BASE,
+            msip: Vec::new(),
+            mtimecmp: Vec::new(),
+        }
+    }
+}
+
+impl Clint {
+    /// when the timer interrupt of `hart` becomes pending, never until the
+    /// guest sets it
+    pub fn mtimecmp(&self, hart: usize) -> u64 {
+        self.mtimecmp.get(hart).copied().unwrap_or(u64::MAX)
+    }
+
+    pub fn msip(&self, hart: usize) -> bool {
+        self.msip.get(hart).copied().unwrap_or(false)
+    }
+
+    fn contains(&self, address: u32, size: u32) -> bool {
+        address >= self.base && address - self.base + size <= CLINT_SIZE
+    }
+}
+
+/// `size` bytes of the 64 bit `register` at byte `shift` of it
+fn read_part(register: u64, shift: u32, size: u32) -> u32 {
+    let mask = u64::MAX >> (64 - 8 * size);
+    (register >> (8 * shift) & mask) as u32
+}
+
+/// `register` with the `size` bytes at byte `shift` replaced by `value`
+fn write_part(register: u64, shift: u32, size: u32, value: u32) -> u64 {
+    let mask = (u64::MAX >> (64 - 8 * size)) << (8 * shift);
+    register & !mask | (u64::from(value) << (8 * shift) & mask)
+}
+
+impl Vm {
+    /// lets the time pass with `source` instead of the cycles
+    pub fn with_clock(mut self, source: ClockSource) -> Self {
+        self.clock = Clock::new(source);
+        self
+    }
+
+    /// puts a CLINT at `CLINT_BASE`, where the device tree says it is
+    pub fn with_clint(mut self) -> Self {
+        self.clint = Some(Clint::default());
+        self
+    }
+
+    /// the ticks of `mtime` and `rdtime`
+    pub fn time(&self) -> u64 {
+        let source = match self.clock.source {
+            ClockSource::Cycles => self.cycles(),
+            ClockSource::Host => {
+                let elapsed = self.clock.started.elapsed();
+                (elapsed.as_nanos() * u128::from(TIMEBASE_FREQUENCY) / 1_000_000_000) as u64
+            }
+            ClockSource::Manual => 0,
+        };
+        source.wrapping_add(self.clock.offset)
+    }
+
+    /// `time()` for the guest, the host clock goes through the replay log
+    pub(super) fn observed_time(&mut self) -> u64 {
+        let time = self.time();
+        if self.clock.source != ClockSource::Host {
+            return time;
+        }
+        let at = self.stats.instructions_retired;
+        if self.is_replaying() {
+            let replayed = self.replayed(|event| match *event {
+                ReplayEvent::Time { at: read_at, value } if read_at == at => Some(value),
+                _ => None,
+            });
+            return replayed.unwrap_or(time);
+        }
+        self.record(ReplayEvent::Time { at, value: time });
+        time
+    }
+
+    /// moves the time `ticks` forward
+    pub fn advance_time(&mut self, ticks: u64) {
+        self.clock.offset = self.clock.offset.wrapping_add(ticks);
+        self.tick_timers();
+    }
+
+    /// moves the time forward to the next CLINT or SBI timer of the current
+    /// hart, for guests that wait for it. Returns false if no timer is set or
+    /// it fired already
+    pub fn fast_forward_to_timer(&mut self) -> bool {
+        let hart = self.csrs.mhartid as usize;
+        let clint = self.clint.as_ref().map(|clint| clint.mtimecmp(hart));
+        let sbi = self.sbi.as_ref().and_then(|sbi| sbi.timer);
+        let now = self.time();
+        match clint
+            .into_iter()
+            .chain(sbi)
+            .filter(|&at| at != u64::MAX)
+            .min()
+        {
+            Some(at) if at > now => {
+                self.advance_time(at - now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// updates the timer and software interrupts in `mip`
+    pub(super) fn tick_timers(&mut self) {
+        self.tick_sbi_timer();
+        let Some(clint) = &self.clint else {
+            return;
+        };
+        let hart = self.csrs.mhartid as usize;
+        let (msip, mtimecmp) = (clint.msip(hart), clint.mtimecmp(hart));
+        let timer = self.time() >= mtimecmp;
+        for (bit, pending) in [(MIP_MSIP, msip), (MIP_MTIP, timer)] {
+            if pending {
+                self.csrs.mip |= bit;
+            } else {
+                self.csrs.mip &= !bit;
+            }
+        }
+    }
+
+    /// a load of the CLINT register at `address`, `None` if it is not in the
+    /// CLINT
+    pub(super) fn read_clint(&mut self, address: u32, size: u32) -> Option<u32> {
+        let clint = self
+            .clint
+            .as_ref()
+            .filter(|clint| clint.contains(address, size))?;
+        let offset = address - clint.base;
+        let shift = offset & 0b111;
+        Some(if offset >= MTIME {
+            read_part(self.observed_time(), shift, size)
+        } else if offset >= MTIMECMP {
+            let hart = (offset - MTIMECMP) as usize / 8;
+            read_part(clint.mtimecmp(hart), shift, size)
+        } else {
+            let hart = (offset - MSIP) as usize / 4;
+            u32::from(offset.is_multiple_of(4) && clint.msip(hart))
+        })
+    }
+
+    /// a store to the CLINT register at `address`, returns false if it is
+    /// not in the CLINT
+    pub(super) fn write_clint(&mut self, address: u32, size: u32, value: u32) -> bool {
+        let now = self.time();
+        let Some(clint) = self
+            .clint
+            .as_mut()
+            .filter(|clint| clint.contains(address, size))
+        else {
+            return false;
+        };
+        let offset = address - clint.base;
+        let shift = offset & 0b111;
+        if offset >= MTIME {
+            let time = write_part(now, shift, size, value);
+            self.clock.offset = self.clock.offset.wrapping_add(time.wrapping_sub(now));
+        } else if offset >= MTIMECMP {
+            let hart = (offset - MTIMECMP) as usize / 8;
+            if clint.mtimecmp.len() <= hart {
+                clint.mtimecmp.resize(hart + 1, u64::MAX);
+            }
+            clint.mtimecmp[hart] = write_part(clint.mtimecmp[hart], shift, size, value);
+        } else if offset.is_multiple_of(4) {
+            let hart = (offset - MSIP) as usize / 4;
+            if clint.msip.len() <= hart {
+                clint.msip.resize(hart + 1, false);
+            }
+            clint.msip[hart] = value & 1 != 0;
+        }
+        self.tick_timers();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClockSource;
+    use crate::csr::MIP_MTIP;
+    use crate::{Register, Vm};
+
+    #[test]
+    fn rdtime_should_follow_the_clock_source() {
+        // 0x1000 rdtime a0
+        // 0x1004 rdtime a1
+        let program: Vec<u8> = [0xc010_2573u32, 0xc010_25f3]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        // the cycles before each instruction
+        assert_eq!(vm.vm_state.registers[Register::A0], 0);
+        assert_eq!(vm.vm_state.registers[Register::A1], 1);
+
+        let mut vm = Vm::new(0x1000, 0x100).with_clock(ClockSource::Manual);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.advance_time(1000);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], 1000);
+        assert_eq!(vm.vm_state.registers[Register::A1], 1000);
+    }
+
+    #[test]
+    fn the_clint_timer_should_fire_at_mtimecmp() {
+        // 0x1000 lui t0, 0x2004         mtimecmp of hart 0
+        // 0x1004 addi t1, zero, 500
+        // 0x1008 sw t1, 0(t0)
+        // 0x100c sw zero, 4(t0)
+        // 0x1010 lui t0, 0x200c
+        // 0x1014 lw a0, -8(t0)          mtime
+        let program: Vec<u8> = [
+            0x0200_42b7u32,
+            0x1f40_0313,
+            0x0062_a023,
+            0x0002_a223,
+            0x0200_c2b7,
+            0xff82_a503,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100)
+            .with_clock(ClockSource::Manual)
+            .with_clint();
+        vm.load_program(0x1000, &program).unwrap();
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.clint.as_ref().unwrap().mtimecmp(0), 500);
+        assert_eq!(vm.csrs.mip & MIP_MTIP, 0);
+
+        assert!(vm.fast_forward_to_timer());
+        assert_eq!(vm.time(), 500);
+        assert_ne!(vm.csrs.mip & MIP_MTIP, 0);
+        assert!(!vm.fast_forward_to_timer());
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], 500);
+    }
+}
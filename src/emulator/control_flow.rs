@@ -0,0 +1,336 @@
+//! Control-flow graph of the code loaded into a vm. The static part follows
+//! every branch, jump and call from the entry points. The targets of `jalr`
+//! (function pointers, jump tables, returns) can not be known statically,
+//! so the graph adds the ones the vm saw while running (see
+//! `RunStats::indirect_jumps`). Building the graph again after a run refines
+//! it.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt::Write;
+
+use super::decompile::decompile;
+use super::emulator::{Instruction, PseudoInstruction, Vm};
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+
+/// why control goes from one block to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// the block just continues with the next instruction, also the return
+    /// site after a call or an ecall
+    FallThrough,
+    /// a taken conditional branch
+    Branch,
+    /// `jal zero`
+    Jump,
+    /// `jal ra` or any other jal that links
+    Call,
+    /// a `jalr` target that was observed at runtime
+    Indirect,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::FallThrough => "fallthrough",
+            Self::Branch => "branch",
+            Self::Jump => "jump",
+            Self::Call => "call",
+            Self::Indirect => "indirect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    /// the start of the block the edge leaves
+    pub from: u32,
+    /// the start of the block the edge goes to
+    pub to: u32,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub start: u32,
+    pub instructions: Vec<Instruction>,
+}
+
+impl BasicBlock {
+    /// the address right after the last instruction
+    pub fn end(&self) -> u32 {
+        self.start + 4 * self.instructions.len() as u32
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ControlFlowGraph {
+    /// the blocks by their start address
+    pub blocks: BTreeMap<u32, BasicBlock>,
+    pub edges: BTreeSet<Edge>,
+}
+
+impl ControlFlowGraph {
+    /// builds the graph of everything reachable from `entries`. Code that can
+    /// not be fetched or decoded ends the path it is on.
+    pub fn build(vm: &Vm, entries: &[u32]) -> Self {
+        let indirect_jumps = &vm.stats.indirect_jumps;
+
+        // first find every reachable instruction, and the addresses where a
+        // block has to start
+        let mut leaders: BTreeSet<u32> = entries.iter().copied().collect();
+        leaders.extend(indirect_jumps.values().flatten());
+        let mut reachable = BTreeSet::new();
+        let mut worklist: Vec<u32> = leaders.iter().copied().collect();
+
+        while let Some(mut address) = worklist.pop() {
+            while !reachable.contains(&address) {
+                let Ok(instruction) = vm.fetch_at(address) else {
+                    break;
+                };
+                reachable.insert(address);
+
+                if instruction.ends_basic_block() {
+                    for (target, _) in successors(&instruction, indirect_jumps) {
+                        leaders.insert(target);
+                        worklist.push(target);
+                    }
+                    break;
+                }
+                address = address.wrapping_add(4);
+            }
+        }
+
+        // then cut the reachable instructions into blocks
+        let mut graph = Self::default();
+        for &start in leaders.iter().filter(|start| reachable.contains(start)) {
+            let mut block = BasicBlock {
+                start,
+                instructions: Vec::new(),
+            };
+            let mut address = start;
+
+            while let Ok(instruction) = vm.fetch_at(address) {
+                if instruction.ends_basic_block() {
+                    for (to, kind) in successors(&instruction, indirect_jumps) {
+                        if reachable.contains(&to) {
+                            graph.edges.insert(Edge {
+                                from: start,
+                                to,
+                                kind,
+                            });
+                        }
+                    }
+                    block.instructions.push(instruction);
+                    break;
+                }
+
+                block.instructions.push(instruction);
+                address = address.wrapping_add(4);
+                if leaders.contains(&address) || !reachable.contains(&address) {
+                    if reachable.contains(&address) {
+                        graph.edges.insert(Edge {
+                            from: start,
+                            to: address,
+                            kind: EdgeKind::FallThrough,
+                        });
+                    }
+                    break;
+                }
+            }
+
+            graph.blocks.insert(start, block);
+        }
+
+        graph
+    }
+
+    /// the graph in Graphviz DOT, every block is labelled with its
+    /// pseudocode
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph cfg {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for block in self.blocks.values() {
+            let label: String = decompile(&block.instructions)
+                .lines()
+                .map(|line| format!("{}\\l", escape(line)))
+                .collect();
+            let _ = writeln!(dot, "    \"{:#010x}\" [label=\"{label}\"];", block.start);
+        }
+        for edge in &self.edges {
+            let _ = writeln!(
+                dot,
+                "    \"{:#010x}\" -> \"{:#010x}\" [label=\"{}\"];",
+                edge.from,
+                edge.to,
+                edge.kind.name()
+            );
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// the graph as JSON: `{"blocks": [{"start", "end", "code"}], "edges":
+    /// [{"from", "to", "kind"}]}`, addresses are numbers
+    pub fn to_json(&self) -> String {
+        let blocks: Vec<String> = self
+            .blocks
+            .values()
+            .map(|block| {
+                let code: Vec<String> = decompile(&block.instructions)
+                    .lines()
+                    .map(|line| format!("\"{}\"", escape(line)))
+                    .collect();
+                format!(
+                    "{{\"start\":{},\"end\":{},\"code\":[{}]}}",
+                    block.start,
+                    block.end(),
+                    code.join(",")
+                )
+            })
+            .collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|edge| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"kind\":\"{}\"}}",
+                    edge.from,
+                    edge.to,
+                    edge.kind.name()
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"blocks\":[{}],\"edges\":[{}]}}",
+            blocks.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+/// where control can go after an instruction that ends a block
+fn successors(
+    instruction: &Instruction,
+    indirect_jumps: &BTreeMap<u32, BTreeSet<u32>>,
+) -> Vec<(u32, EdgeKind)> {
+    let pc = instruction.address() as u32;
+    let next = pc.wrapping_add(4);
+    let observed = || {
+        indirect_jumps
+            .get(&pc)
+            .into_iter()
+            .flatten()
+            .map(|target| (*target, EdgeKind::Indirect))
+    };
+
+    match instruction {
+        Instruction::PseudoInstruction(_, PseudoInstruction::Ret) => observed().collect(),
+        Instruction::PseudoInstruction(_, _) => vec![(next, EdgeKind::FallThrough)],
+        Instruction::Rv32iInstruction(_, instruction) => match instruction {
+            Rv32iInstruction::Beq(b)
+            | Rv32iInstruction::Bne(b)
+            | Rv32iInstruction::Blt(b)
+            | Rv32iInstruction::Bge(b)
+            | Rv32iInstruction::Bltu(b)
+            | Rv32iInstruction::Bgeu(b) => vec![
+                (pc.wrapping_add(b.imm as i32 as u32), EdgeKind::Branch),
+                (next, EdgeKind::FallThrough),
+            ],
+            Rv32iInstruction::Jal(j) if j.rd.is_zero() => {
+                vec![(pc.wrapping_add(j.imm as u32), EdgeKind::Jump)]
+            }
+            Rv32iInstruction::Jal(j) => vec![
+                (pc.wrapping_add(j.imm as u32), EdgeKind::Call),
+                (next, EdgeKind::FallThrough),
+            ],
+            Rv32iInstruction::Jalr(i) if i.rd.is_zero() => observed().collect(),
+            Rv32iInstruction::Jalr(_) => observed()
+                .chain(core::iter::once((next, EdgeKind::FallThrough)))
+                .collect(),
+            Rv32iInstruction::Ebreak | Rv32iInstruction::Mret | Rv32iInstruction::Sret => {
+                Vec::new()
+            }
+            _ => vec![(next, EdgeKind::FallThrough)],
+        },
+    }
+}
+
+/// escapes a string for a DOT or JSON string literal
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ControlFlowGraph, Edge, EdgeKind};
+    use crate::Vm;
+
+    // 0x1000 addi a0, zero, 3
+    // 0x1004 jal ra, 0x1014       call f
+    // 0x1008 beq a0, zero, 0x1010
+    // 0x100c addi a0, a0, -1
+    // 0x1010 ebreak
+    // 0x1014 addi a0, a0, 1       f
+    // 0x1018 ret
+    const PROGRAM: [u32; 7] = [
+        0x0030_0513,
+        0x0100_00ef,
+        0x0005_0463,
+        0xfff5_0513,
+        0x0010_0073,
+        0x0015_0513,
+        0x0000_8067,
+    ];
+
+    fn edge(from: u32, to: u32, kind: EdgeKind) -> Edge {
+        Edge { from, to, kind }
+    }
+
+    #[test]
+    fn static_graph_should_follow_branches_and_calls() {
+        let vm = Vm::with_program_words(0x1000, &PROGRAM);
+        let graph = ControlFlowGraph::build(&vm, &[0x1000]);
+
+        let starts: Vec<u32> = graph.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0x1000, 0x1008, 0x100c, 0x1010, 0x1014]);
+        assert_eq!(graph.blocks[&0x1000].end(), 0x1008);
+
+        // nothing ran yet so the ret has no known target
+        assert_eq!(
+            graph.edges.iter().copied().collect::<Vec<_>>(),
+            vec![
+                edge(0x1000, 0x1008, EdgeKind::FallThrough),
+                edge(0x1000, 0x1014, EdgeKind::Call),
+                edge(0x1008, 0x100c, EdgeKind::FallThrough),
+                edge(0x1008, 0x1010, EdgeKind::Branch),
+                edge(0x100c, 0x1010, EdgeKind::FallThrough),
+            ]
+        );
+    }
+
+    #[test]
+    fn observed_returns_should_refine_the_graph() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.run().unwrap();
+
+        let graph = ControlFlowGraph::build(&vm, &[0x1000]);
+
+        assert!(graph
+            .edges
+            .contains(&edge(0x1014, 0x1008, EdgeKind::Indirect)));
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("\"0x00001014\" -> \"0x00001008\" [label=\"indirect\"];"));
+        assert!(dot.contains("0x00001014:  a0 = a0 + 1;\\l0x00001018:  return a0;\\l"));
+
+        let json = graph.to_json();
+        assert!(json.contains("{\"start\":4116,\"end\":4124,\"code\":[\"0x00001014:  a0 = a0 + 1;\",\"0x00001018:  return a0;\"]}"));
+        assert!(json.contains("{\"from\":4116,\"to\":4104,\"kind\":\"indirect\"}"));
+    }
+}
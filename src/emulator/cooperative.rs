@@ -0,0 +1,174 @@
+//! Running long guests without freezing the host, for the browser event
+//! loop. `run_for_time` runs slices of `run_for` until a wall-clock budget is
+//! used up, `run_async` does that again and again and awaits the host's
+//! `yield_now` between the slices (a `setTimeout` promise on the web), until
+//! the guest stops or someone calls `AbortHandle::abort`.
+//!
+//! The time is only looked at every `CHECK_INTERVAL` instructions, so a
+//! slice is a bit longer than asked for. `Stopwatch` is the clock: under wasm
+//! `std::time::Instant` panics, so there it asks JavaScript's `Date`. Without
+//! std there is no clock at all, every slice is `CHECK_INTERVAL`
+//! instructions then.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::emulator::{StopReason, Vm};
+use super::error::VmError;
+
+/// instructions between two looks at the clock and the abort handle
+const CHECK_INTERVAL: u64 = 10_000;
+
+/// stops the current (or the next) `run_for_time`/`run_async` of a vm with
+/// `Aborted`, from a UI callback or another thread
+#[derive(Debug, Clone, Default)]
+pub struct AbortHandle(Arc<AtomicBool>);
+
+impl AbortHandle {
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// whether it was aborted, and resets it
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// measures wall-clock time, also in the browser. Without std no time ever
+/// passes
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Stopwatch {
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    started: std::time::Instant,
+    /// milliseconds since the epoch
+    #[cfg(all(feature = "std", target_arch = "wasm32"))]
+    started: f64,
+}
+
+impl Stopwatch {
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub(super) fn start() -> Self {
+        Self {
+            started: std::time::Instant::now(),
+        }
+    }
+
+    #[cfg(all(feature = "std", target_arch = "wasm32"))]
+    pub(super) fn start() -> Self {
+        Self {
+            started: js_sys::Date::now(),
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(super) fn start() -> Self {
+        Self {}
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub(super) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    #[cfg(all(feature = "std", target_arch = "wasm32"))]
+    pub(super) fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64((js_sys::Date::now() - self.started).max(0.0) / 1000.0)
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(super) fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl Vm {
+    /// a handle that stops `run_for_time` and `run_async` with `Aborted`
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// like `run()`, but stops with `Preempted` once about `time` has
+    /// passed (after `CHECK_INTERVAL` instructions without std), or with
+    /// `Aborted`
+    pub fn run_for_time(&mut self, time: Duration) -> Result<StopReason, VmError> {
+        let stopwatch = Stopwatch::start();
+        loop {
+            if self.abort.take() {
+                let pc = self.vm_state.pc as u32;
+                return Ok(StopReason::Aborted { pc });
+            }
+            match self.run_for(CHECK_INTERVAL)? {
+                StopReason::Preempted { pc }
+                    if stopwatch.elapsed() >= time || cfg!(not(feature = "std")) =>
+                {
+                    return Ok(StopReason::Preempted { pc });
+                }
+                StopReason::Preempted { .. } => {}
+                stop_reason => return Ok(stop_reason),
+            }
+        }
+    }
+
+    /// runs in slices of `slice` wall-clock time and awaits `yield_now()`
+    /// between them and while the guest waits in `wfi`, until the guest stops, something goes wrong or the
+    /// abort handle stops it
+    pub async fn run_async<F: Future<Output = ()>>(
+        &mut self,
+        slice: Duration,
+        mut yield_now: impl FnMut() -> F,
+    ) -> Result<StopReason, VmError> {
+        loop {
+            match self.run_for_time(slice)? {
+                StopReason::Preempted { .. } | StopReason::Waiting { .. } => yield_now().await,
+                stop_reason => return Ok(stop_reason),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{self, Future};
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    use crate::{StopReason, Vm};
+
+    /// polls `future` once, the futures here never wait
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("the future waited"),
+        }
+    }
+
+    #[test]
+    fn should_run_in_slices_until_aborted() {
+        // 0x1000 jal zero, 0
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &0x0000_006fu32.to_le_bytes())
+            .unwrap();
+
+        assert_eq!(
+            vm.run_for_time(Duration::ZERO),
+            Ok(StopReason::Preempted { pc: 0x1000 })
+        );
+
+        let abort = vm.abort_handle();
+        let mut yields = 0;
+        let result = block_on(vm.run_async(Duration::ZERO, || {
+            yields += 1;
+            if yields == 3 {
+                abort.abort();
+            }
+            future::ready(())
+        }));
+        assert_eq!(result, Ok(StopReason::Aborted { pc: 0x1000 }));
+        assert_eq!(yields, 3);
+        assert_eq!(vm.stats.instructions_retired, 4 * 10_000);
+    }
+}
@@ -0,0 +1,172 @@
+//! Which guest code ran, for fuzzing guests and for teaching. `Coverage` is
+//! a `VmHooks` that counts every executed pc; the report is the set of
+//! addresses (one per line, what `addr2line -e program.elf` reads), or an
+//! lcov tracefile for `genhtml` made with the line numbers of the ELF file.
+//! Coverage of several runs (fuzz inputs) adds up with `merge`.
+
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use super::debug_line::LineTable;
+use super::emulator::{Instruction, Vm, VmState};
+use super::hooks::VmHooks;
+use super::prelude::*;
+
+/// The handle to read the coverage from while the vm owns the hooks.
+/// Create it with `Vm::add_coverage`.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage(Rc<RefCell<BTreeMap<u32, u64>>>);
+
+impl Coverage {
+    /// the executed pcs and how often they ran, by address
+    pub fn counts(&self) -> BTreeMap<u32, u64> {
+        self.0.borrow().clone()
+    }
+
+    pub fn is_covered(&self, pc: u32) -> bool {
+        self.0.borrow().contains_key(&pc)
+    }
+
+    /// adds the counts of `other`, e.g. of the vm of another fuzz input
+    pub fn merge(&self, other: &Coverage) {
+        if Rc::ptr_eq(&self.0, &other.0) {
+            return;
+        }
+        let mut counts = self.0.borrow_mut();
+        for (pc, count) in other.0.borrow().iter() {
+            *counts.entry(*pc).or_insert(0) += count;
+        }
+    }
+
+    /// forgets everything recorded so far
+    pub fn reset(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// the executed pcs in hex, one per line
+    pub fn to_addresses(&self) -> String {
+        let mut addresses = String::new();
+        for pc in self.0.borrow().keys() {
+            let _ = writeln!(addresses, "{pc:#010x}");
+        }
+        addresses
+    }
+
+    /// an lcov tracefile: every source line with code, with how often its
+    /// most executed instruction ran
+    pub fn to_lcov(&self, line_table: &LineTable) -> String {
+        let counts = self.0.borrow();
+        // file → line → hits
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+        for (index, row) in line_table.rows.iter().enumerate() {
+            let Some(file) = line_table.files.get(row.file) else {
+                continue;
+            };
+            if row.end_sequence {
+                continue;
+            }
+            let end = line_table
+                .rows
+                .get(index + 1)
+                .map_or(row.address.saturating_add(4), |next| next.address);
+            let hits = counts
+                .range(row.address..end.max(row.address))
+                .map(|(_, count)| *count)
+                .max()
+                .unwrap_or(0);
+            let line = files.entry(file).or_default().entry(row.line).or_insert(0);
+            *line = (*line).max(hits);
+        }
+
+        let mut lcov = String::new();
+        for (file, lines) in files {
+            let _ = writeln!(lcov, "TN:\nSF:{file}");
+            for (line, hits) in &lines {
+                let _ = writeln!(lcov, "DA:{line},{hits}");
+            }
+            let hit = lines.values().filter(|hits| **hits > 0).count();
+            let _ = writeln!(lcov, "LF:{}\nLH:{hit}\nend_of_record", lines.len());
+        }
+        lcov
+    }
+}
+
+impl VmHooks for Coverage {
+    fn before_instruction(&mut self, vm_state: &VmState, _instruction: &Instruction) {
+        *self.0.borrow_mut().entry(vm_state.pc as u32).or_insert(0) += 1;
+    }
+}
+
+impl Vm {
+    /// starts recording coverage, the returned handle gives the report
+    pub fn add_coverage(&mut self) -> Coverage {
+        let coverage = Coverage::default();
+        self.add_hooks(Box::new(coverage.clone()));
+        coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_line::{LineRow, LineTable};
+    use crate::Vm;
+
+    #[test]
+    fn should_record_the_executed_pcs_and_lines() {
+        // 0x1000 addi a0, zero, 1     line 1
+        // 0x1004 jal zero, 0x100c
+        // 0x1008 addi a0, a0, 1       line 2
+        // 0x100c addi a0, a0, 2
+        // 0x1010 ebreak               line 3
+        let program: Vec<u8> = [
+            0x0010_0513u32,
+            0x0080_006f,
+            0x0015_0513,
+            0x0025_0513,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        let coverage = vm.add_coverage();
+        vm.run().unwrap();
+        assert!(coverage.is_covered(0x100c) && !coverage.is_covered(0x1008));
+        assert_eq!(
+            coverage.to_addresses(),
+            "0x00001000\n0x00001004\n0x0000100c\n"
+        );
+
+        let row = |address, line, end_sequence| LineRow {
+            address,
+            file: 0,
+            line,
+            is_stmt: true,
+            end_sequence,
+        };
+        let line_table = LineTable {
+            files: vec!["main.c".to_string()],
+            rows: vec![
+                row(0x1000, 1, false),
+                row(0x1008, 2, false),
+                row(0x1010, 3, false),
+                row(0x1014, 3, true),
+            ],
+        };
+        assert_eq!(
+            coverage.to_lcov(&line_table),
+            "TN:\nSF:main.c\nDA:1,1\nDA:2,1\nDA:3,0\nLF:3\nLH:2\nend_of_record\n"
+        );
+
+        // a second run of the same code
+        let other = vm.add_coverage();
+        vm.vm_state.pc = 0x1000;
+        vm.run().unwrap();
+        coverage.merge(&other);
+        assert_eq!(coverage.counts()[&0x1000], 3);
+    }
+}
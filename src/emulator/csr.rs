@@ -0,0 +1,1046 @@
+//! The CSRs and traps of machine and supervisor mode: enough of the
+//! privileged spec for a guest to install its own trap handlers (`mtvec`,
+//! `stvec`, direct or vectored), take traps into them and `mret` or `sret`
+//! back. Traps go to machine mode unless they come from a lower mode and
+//! `medeleg`/`mideleg` hand them to supervisor mode. Entering a trap pushes
+//! the interrupt enable and the mode onto `mstatus` (`MIE` into `MPIE`, the
+//! mode into `MPP`, or `SIE`/`SPIE`/`SPP`), the return pops them.
+//!
+//! A mode below machine mode can only use the CSRs of its own mode and the
+//! ones below (bits 9:8 of the number), `mret` is for machine mode, `sret`
+//! for supervisor mode and up, `wfi` is not for user mode. Anything else is
+//! an illegal instruction, and so is an access to a CSR the vm does not have
+//! or a write to a read-only one. Those always trap into the guest, so a
+//! kernel gets to handle what its user programs do wrong.
+//!
+//! `cycle`, `time` and `instret` are read only, the `mhpmcounter`s of
+//! `hpm.rs` can be written. Interrupts pending in `mip` and enabled in `mie`
+//! are taken between two instructions when the mode lets them in, the
+//! highest priority one first, with `xepc` at the instruction that did not
+//! run yet. A handler that turns interrupts on again nests, it has to save
+//! `xepc` and `mstatus` (for `xPP`) before. The instructions the vm does not
+//! know fail `run()` unless `with_illegal_instruction_traps` sends them to
+//! the guest too, page faults (see `mmu.rs`) always go to the guest.
+
+use super::emulator::{Instruction, Vm};
+use super::error::VmError;
+use super::rv32i::Rv32iInstruction;
+use super::timing::{CSR_CYCLE, CSR_HIGH_HALF, CSR_INSTRET, CSR_TIME};
+use super::vector::{CSR_VL, CSR_VLENB, CSR_VTYPE};
+
+pub const CSR_SSTATUS: u16 = 0x100;
+pub const CSR_SIE: u16 = 0x104;
+pub const CSR_STVEC: u16 = 0x105;
+pub const CSR_SSCRATCH: u16 = 0x140;
+pub const CSR_SEPC: u16 = 0x141;
+pub const CSR_SCAUSE: u16 = 0x142;
+pub const CSR_STVAL: u16 = 0x143;
+pub const CSR_SIP: u16 = 0x144;
+pub const CSR_SATP: u16 = 0x180;
+pub const CSR_MSTATUS: u16 = 0x300;
+pub const CSR_MISA: u16 = 0x301;
+pub const CSR_MEDELEG: u16 = 0x302;
+pub const CSR_MIDELEG: u16 = 0x303;
+pub const CSR_MIE: u16 = 0x304;
+pub const CSR_MTVEC: u16 = 0x305;
+pub const CSR_MSCRATCH: u16 = 0x340;
+pub const CSR_MEPC: u16 = 0x341;
+pub const CSR_MCAUSE: u16 = 0x342;
+pub const CSR_MTVAL: u16 = 0x343;
+pub const CSR_MIP: u16 = 0x344;
+pub const CSR_MHARTID: u16 = 0xf14;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+/// the mode before the last trap into supervisor mode, set for supervisor
+pub const MSTATUS_SPP: u32 = 1 << 8;
+/// the mode before the last trap into machine mode
+pub const MSTATUS_MPP: u32 = 0b11 << MSTATUS_MPP_SHIFT;
+const MSTATUS_MPP_SHIFT: u32 = 11;
+/// supervisor mode may load and store to user pages, see `mmu.rs`
+pub const MSTATUS_SUM: u32 = 1 << 18;
+/// loads may read execute-only pages
+pub const MSTATUS_MXR: u32 = 1 << 19;
+/// what the guest can change in `mstatus`
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_SUM
+    | MSTATUS_MXR;
+/// the part of `mstatus` that `sstatus` is
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+/// the S and U bits of `misa`, the modes are not extensions to turn off
+const MISA_SUPERVISOR: u32 = 1 << 18;
+const MISA_USER: u32 = 1 << 20;
+
+/// the supervisor software and timer interrupts are pending, the timer
+/// one from `sbi.rs`
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_STIP: u32 = 1 << 5;
+/// the machine software and timer interrupts are pending, see `clint.rs`
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_MTIP: u32 = 1 << 7;
+/// the supervisor and machine external interrupts are pending, see `plic.rs`
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+/// the low two bits of `mtvec`: traps go to the base, or interrupts to
+/// `base + 4 * code` while exceptions still go to the base
+pub const TVEC_MODE_DIRECT: u32 = 0;
+pub const TVEC_MODE_VECTORED: u32 = 1;
+
+/// the interrupts `mideleg` can hand to supervisor mode, the supervisor ones
+const DELEGABLE_INTERRUPTS: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// the exceptions `medeleg` can, all but the ecall from machine mode
+const DELEGABLE_EXCEPTIONS: u32 = 0xffff & !(1 << CAUSE_ECALL_FROM_M);
+
+/// the top bit of `mcause` for interrupts, the rest is the bit in `mip`
+pub const CAUSE_INTERRUPT: u32 = 1 << 31;
+/// the interrupts in the order the spec takes them when several are
+/// pending: MEI, MSI, MTI, then SEI, SSI, STI
+const INTERRUPT_PRIORITY: [u32; 6] = [11, 3, 7, 9, 1, 5];
+
+/// `mcause` of the exceptions the vm raises
+pub const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const CAUSE_BREAKPOINT: u32 = 3;
+/// the ecall causes are 8 plus the mode, see `Privilege`
+pub const CAUSE_ECALL_FROM_U: u32 = 8;
+pub const CAUSE_ECALL_FROM_S: u32 = 9;
+pub const CAUSE_ECALL_FROM_M: u32 = 11;
+
+/// the mode a hart runs in, the numbers are the ones in `MPP`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl Privilege {
+    /// the mode of the two bits of `MPP`, `None` for the reserved 2
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits & 0b11 {
+            0 => Some(Self::User),
+            1 => Some(Self::Supervisor),
+            3 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// the CSRs of machine and supervisor mode that are not counters, and the
+/// mode the hart runs in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Csrs {
+    /// not a CSR, but it changes with the traps like the CSRs do
+    pub privilege: Privilege,
+    pub mstatus: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    /// the id of the hart, see `smp.rs`
+    pub mhartid: u32,
+    pub medeleg: u32,
+    pub mideleg: u32,
+    pub stvec: u32,
+    pub sscratch: u32,
+    pub sepc: u32,
+    pub scause: u32,
+    pub stval: u32,
+    /// the page tables, see `mmu.rs`
+    pub satp: u32,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Self {
+            privilege: Privilege::Machine,
+            mstatus: MSTATUS_MPP,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mhartid: 0,
+            medeleg: 0,
+            mideleg: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+        }
+    }
+}
+
+/// `value` written to `mtvec` or `stvec`: the modes 2 and 3 are reserved,
+/// those are direct
+fn legal_tvec(value: u32) -> u32 {
+    if value & 0b11 > TVEC_MODE_VECTORED {
+        value & !0b11
+    } else {
+        value
+    }
+}
+
+/// where a trap with `cause` goes with `tvec` in `mtvec` or `stvec`
+fn trap_vector(tvec: u32, cause: u32) -> u32 {
+    let base = tvec & !0b11;
+    if tvec & 0b11 == TVEC_MODE_VECTORED && cause & CAUSE_INTERRUPT != 0 {
+        base.wrapping_add(4 * (cause & !CAUSE_INTERRUPT))
+    } else {
+        base
+    }
+}
+
+/// what a CSR instruction does with its operand
+enum CsrUpdate {
+    Write,
+    Set,
+    Clear,
+}
+
+impl Vm {
+    /// the value of a CSR, `None` if the vm does not have it. There is no real
+    /// clock, time is the cycle counter
+    pub fn read_csr(&self, csr: u16) -> Option<u32> {
+        let counter = |value: u64| {
+            if csr & CSR_HIGH_HALF != 0 {
+                (value >> 32) as u32
+            } else {
+                value as u32
+            }
+        };
+
+        Some(match csr {
+            CSR_SSTATUS => self.csrs.mstatus & SSTATUS_MASK,
+            CSR_SIE => self.csrs.mie & self.csrs.mideleg,
+            CSR_STVEC => self.csrs.stvec,
+            CSR_SSCRATCH => self.csrs.sscratch,
+            CSR_SEPC => self.csrs.sepc,
+            CSR_SCAUSE => self.csrs.scause,
+            CSR_STVAL => self.csrs.stval,
+            CSR_SIP => self.csrs.mip & self.csrs.mideleg,
+            CSR_SATP => self.csrs.satp,
+            CSR_MSTATUS => self.csrs.mstatus,
+            CSR_MISA => self.extensions.misa() | MISA_SUPERVISOR | MISA_USER,
+            CSR_MEDELEG => self.csrs.medeleg,
+            CSR_MIDELEG => self.csrs.mideleg,
+            CSR_MIE => self.csrs.mie,
+            CSR_MIP => self.csrs.mip,
+            CSR_MTVEC => self.csrs.mtvec,
+            CSR_MSCRATCH => self.csrs.mscratch,
+            CSR_MEPC => self.csrs.mepc,
+            CSR_MCAUSE => self.csrs.mcause,
+            CSR_MTVAL => self.csrs.mtval,
+            CSR_MHARTID => self.csrs.mhartid,
+            CSR_VL => self.vector.as_ref()?.vl,
+            CSR_VTYPE => self.vector.as_ref()?.vtype,
+            CSR_VLENB => self.vector.as_ref()?.vlenb() as u32,
+            _ => match csr & !CSR_HIGH_HALF {
+                CSR_CYCLE => counter(self.cycles()),
+                CSR_TIME => counter(self.time()),
+                CSR_INSTRET => counter(self.stats.instructions_retired),
+                _ => return self.performance_counters.as_ref()?.read(csr),
+            },
+        })
+    }
+
+    /// writes a CSR, returns false if the vm does not have it or it is read
+    /// only. Bits the vm does not implement are dropped
+    pub fn write_csr(&mut self, csr: u16, value: u32) -> bool {
+        let mideleg = self.csrs.mideleg;
+        match csr {
+            CSR_SSTATUS => self.write_mstatus(value, SSTATUS_MASK),
+            CSR_SIE => self.csrs.mie = self.csrs.mie & !mideleg | value & mideleg,
+            CSR_STVEC => self.csrs.stvec = legal_tvec(value),
+            CSR_SSCRATCH => self.csrs.sscratch = value,
+            CSR_SEPC => self.csrs.sepc = value & !0b11,
+            CSR_SCAUSE => self.csrs.scause = value,
+            CSR_STVAL => self.csrs.stval = value,
+            // only the software interrupt is the guest's to raise
+            CSR_SIP => {
+                let mask = mideleg & MIP_SSIP;
+                self.csrs.mip = self.csrs.mip & !mask | value & mask;
+            }
+            // the TLB keeps the translations of the other ASIDs, a guest
+            // that reuses one runs `sfence.vma` for it
+            CSR_SATP => self.csrs.satp = value,
+            CSR_MSTATUS => self.write_mstatus(value, MSTATUS_WRITABLE),
+            // the extensions can not be changed at run time
+            CSR_MISA => {}
+            CSR_MEDELEG => self.csrs.medeleg = value & DELEGABLE_EXCEPTIONS,
+            CSR_MIDELEG => self.csrs.mideleg = value & DELEGABLE_INTERRUPTS,
+            CSR_MIE => self.csrs.mie = value,
+            CSR_MIP => self.csrs.mip = value,
+            CSR_MTVEC => self.csrs.mtvec = legal_tvec(value),
+            CSR_MSCRATCH => self.csrs.mscratch = value,
+            // instructions are 4 byte aligned
+            CSR_MEPC => self.csrs.mepc = value & !0b11,
+            CSR_MCAUSE => self.csrs.mcause = value,
+            CSR_MTVAL => self.csrs.mtval = value,
+            _ => {
+                return self
+                    .performance_counters
+                    .as_mut()
+                    .is_some_and(|counters| counters.write(csr, value))
+            }
+        }
+        true
+    }
+
+    /// `value` into `mstatus`, only the bits in `mask`. `MPP` keeps its
+    /// mode when `value` has the reserved one
+    fn write_mstatus(&mut self, value: u32, mask: u32) {
+        let old = self.csrs.mstatus;
+        let mut mstatus = old & !mask | value & mask & MSTATUS_WRITABLE;
+        if Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT).is_none() {
+            mstatus = mstatus & !MSTATUS_MPP | old & MSTATUS_MPP;
+        }
+        self.csrs.mstatus = mstatus;
+    }
+
+    /// takes a trap into the guest's handler, at `stvec` if a lower mode
+    /// delegated it to supervisor mode, at `mtvec` otherwise: the trapping
+    /// pc goes to `xepc`, the mode to `xPP`, and the handler runs with
+    /// interrupts off
+    pub fn trap(&mut self, cause: u32, value: u32) {
+        for hooks in &mut self.hooks {
+            hooks.on_trap(self.vm_state.pc as u32, cause, value);
+        }
+        let pc = self.vm_state.pc as u32;
+        let csrs = &mut self.csrs;
+        let privilege = csrs.privilege;
+        let delegation = if cause & CAUSE_INTERRUPT != 0 {
+            csrs.mideleg
+        } else {
+            csrs.medeleg
+        };
+        let code = cause & !CAUSE_INTERRUPT;
+        let delegated = privilege < Privilege::Machine && code < 32 && delegation & 1 << code != 0;
+        let mstatus = csrs.mstatus;
+
+        let handler = if delegated {
+            let spie = if mstatus & MSTATUS_SIE != 0 {
+                MSTATUS_SPIE
+            } else {
+                0
+            };
+            let spp = if privilege == Privilege::Supervisor {
+                MSTATUS_SPP
+            } else {
+                0
+            };
+            csrs.sepc = pc;
+            csrs.scause = cause;
+            csrs.stval = value;
+            csrs.mstatus = mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP) | spie | spp;
+            csrs.privilege = Privilege::Supervisor;
+            trap_vector(csrs.stvec, cause)
+        } else {
+            let mpie = if mstatus & MSTATUS_MIE != 0 {
+                MSTATUS_MPIE
+            } else {
+                0
+            };
+            let mpp = (privilege as u32) << MSTATUS_MPP_SHIFT;
+            csrs.mepc = pc;
+            csrs.mcause = cause;
+            csrs.mtval = value;
+            csrs.mstatus = mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP) | mpie | mpp;
+            csrs.privilege = Privilege::Machine;
+            trap_vector(csrs.mtvec, cause)
+        };
+        self.vm_state.pc = handler as i32;
+    }
+
+    /// the interrupts that are pending in `mip` and enabled in `mie`, whether
+    /// `mstatus.MIE` lets them in or not. `wfi` waits for one of these
+    pub fn pending_interrupts(&self) -> u32 {
+        self.csrs.mip & self.csrs.mie
+    }
+
+    /// the code of the interrupt taken before the next instruction: the
+    /// highest priority one of `pending_interrupts` that the mode lets in.
+    /// The ones for machine mode go first, below machine mode always and in
+    /// it while `mstatus.MIE` is set. The ones `mideleg` delegates go to
+    /// supervisor mode: from user mode always, in supervisor mode while
+    /// `mstatus.SIE` is set, in machine mode never
+    pub fn next_interrupt(&self) -> Option<u32> {
+        let Csrs {
+            privilege,
+            mstatus,
+            mideleg,
+            ..
+        } = self.csrs;
+        let pending = self.pending_interrupts();
+        let machine = privilege < Privilege::Machine || mstatus & MSTATUS_MIE != 0;
+        let supervisor = privilege < Privilege::Supervisor
+            || privilege == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0;
+        let enabled = [
+            if machine { pending & !mideleg } else { 0 },
+            if supervisor { pending & mideleg } else { 0 },
+        ];
+        enabled.into_iter().find_map(|enabled| {
+            INTERRUPT_PRIORITY
+                .into_iter()
+                .find(|code| enabled & 1 << code != 0)
+        })
+    }
+
+    /// takes `next_interrupt`, if there is one. Returns whether it took one
+    pub(super) fn take_interrupt(&mut self) -> bool {
+        let Some(code) = self.next_interrupt() else {
+            return false;
+        };
+        self.trap(CAUSE_INTERRUPT | code, 0);
+        self.stats.record_trap("interrupt");
+        true
+    }
+
+    /// whether the mode the hart runs in may execute `instruction`
+    pub(super) fn is_allowed(&self, instruction: &Instruction) -> bool {
+        let Instruction::Rv32iInstruction(_, instruction) = instruction else {
+            return true;
+        };
+        let required = match instruction {
+            Rv32iInstruction::Csrrw(i)
+            | Rv32iInstruction::Csrrs(i)
+            | Rv32iInstruction::Csrrc(i)
+            | Rv32iInstruction::Csrrwi(i)
+            | Rv32iInstruction::Csrrsi(i)
+            | Rv32iInstruction::Csrrci(i) => u32::from(i.imm as u16 >> 8 & 0b11),
+            Rv32iInstruction::Mret => Privilege::Machine as u32,
+            Rv32iInstruction::Sret | Rv32iInstruction::Wfi | Rv32iInstruction::SfenceVma(_) => {
+                Privilege::Supervisor as u32
+            }
+            _ => return true,
+        };
+        self.csrs.privilege as u32 >= required
+    }
+
+    /// the instructions the vm does not know trap into the guest from now on
+    /// like the privileged ones already do, the handler finds the
+    /// instruction word in `mtval`
+    pub fn with_illegal_instruction_traps(mut self) -> Self {
+        self.trap_illegal_instructions = true;
+        self
+    }
+
+    /// takes the trap for `error` if it is a page fault, or an illegal
+    /// instruction and those trap, gives the error back otherwise
+    pub(super) fn trap_fault(&mut self, error: VmError) -> Result<(), VmError> {
+        match error {
+            VmError::PageFault { address, kind, .. } => self.trap(kind.page_fault_cause(), address),
+            VmError::IllegalInstruction { instruction, .. } if self.trap_illegal_instructions => {
+                self.trap(CAUSE_ILLEGAL_INSTRUCTION, instruction)
+            }
+            _ => return Err(error),
+        }
+        self.stats.record_trap(error.trap_name());
+        Ok(())
+    }
+
+    /// the illegal instruction trap for `word`, whatever
+    /// `trap_illegal_instructions` says: for what the mode may not do and
+    /// the CSRs the vm does not have
+    pub(super) fn trap_illegal_instruction(&mut self, word: u32) {
+        self.trap(CAUSE_ILLEGAL_INSTRUCTION, word);
+        self.stats.record_trap("illegal instruction");
+    }
+
+    /// executes the CSR instructions, `mret`, `sret` and `wfi`, returns false
+    /// for any other instruction and for CSRs the vm does not have (or can not
+    /// write), those are illegal instructions
+    pub(super) fn execute_csr(&mut self, instruction: &Instruction) -> bool {
+        let Instruction::Rv32iInstruction(_, instruction) = instruction else {
+            return false;
+        };
+        let register = |rs1| self.vm_state.registers[rs1] as u32;
+        let (csr, operand, update) = match instruction {
+            Rv32iInstruction::Mret => {
+                self.mret();
+                return true;
+            }
+            Rv32iInstruction::Sret => {
+                self.sret();
+                return true;
+            }
+            Rv32iInstruction::Wfi => {
+                self.wfi();
+                return true;
+            }
+            Rv32iInstruction::Csrrw(i) => (i, register(i.rs1), Some(CsrUpdate::Write)),
+            // reading with x0 (or 0) as the operand does not write, so read
+            // only CSRs can be read that way
+            Rv32iInstruction::Csrrs(i) => (
+                i,
+                register(i.rs1),
+                (!i.rs1.is_zero()).then_some(CsrUpdate::Set),
+            ),
+            Rv32iInstruction::Csrrc(i) => (
+                i,
+                register(i.rs1),
+                (!i.rs1.is_zero()).then_some(CsrUpdate::Clear),
+            ),
+            Rv32iInstruction::Csrrwi(i) => (i, i.rs1.index() as u32, Some(CsrUpdate::Write)),
+            Rv32iInstruction::Csrrsi(i) => (
+                i,
+                i.rs1.index() as u32,
+                (!i.rs1.is_zero()).then_some(CsrUpdate::Set),
+            ),
+            Rv32iInstruction::Csrrci(i) => (
+                i,
+                i.rs1.index() as u32,
+                (!i.rs1.is_zero()).then_some(CsrUpdate::Clear),
+            ),
+            _ => return false,
+        };
+
+        let number = csr.imm as u16;
+        let old = if number & !CSR_HIGH_HALF == CSR_TIME {
+            let time = self.observed_time();
+            Some(if number & CSR_HIGH_HALF != 0 {
+                (time >> 32) as u32
+            } else {
+                time as u32
+            })
+        } else {
+            self.read_csr(number)
+        };
+        let Some(old) = old else {
+            return false;
+        };
+        let new = match update {
+            Some(CsrUpdate::Write) => Some(operand),
+            Some(CsrUpdate::Set) => Some(old | operand),
+            Some(CsrUpdate::Clear) => Some(old & !operand),
+            None => None,
+        };
+        if let Some(new) = new {
+            if !self.write_csr(number, new) {
+                return false;
+            }
+        }
+
+        self.vm_state.registers.write(csr.rd, old as i32);
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        true
+    }
+
+    /// returns from a trap into machine mode to `mepc` and the mode in
+    /// `MPP`, turning the interrupts back on if they were on before it. `MPP`
+    /// is user mode after, the least privileged one
+    fn mret(&mut self) {
+        let mstatus = self.csrs.mstatus;
+        let mie = if mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+
+        // `write_mstatus` never lets the reserved mode into `MPP`
+        self.csrs.privilege =
+            Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT).unwrap_or(Privilege::Machine);
+        self.csrs.mstatus = mstatus & !(MSTATUS_MIE | MSTATUS_MPP) | mie | MSTATUS_MPIE;
+        self.vm_state.pc = self.csrs.mepc as i32;
+    }
+
+    /// returns from a trap into supervisor mode to `sepc` and the mode in
+    /// `SPP`, like `mret`
+    fn sret(&mut self) {
+        let mstatus = self.csrs.mstatus;
+        let sie = if mstatus & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+
+        self.csrs.privilege = if mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        self.csrs.mstatus = mstatus & !(MSTATUS_SIE | MSTATUS_SPP) | sie | MSTATUS_SPIE;
+        self.vm_state.pc = self.csrs.sepc as i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Privilege, CAUSE_ECALL_FROM_M, CAUSE_ECALL_FROM_U, CAUSE_ILLEGAL_INSTRUCTION,
+        CAUSE_INTERRUPT, CSR_MSTATUS, CSR_MTVEC, CSR_SSTATUS, MIP_MTIP, MSTATUS_MIE, MSTATUS_MPIE,
+        MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP,
+    };
+    use crate::instruction_formats::InstructionFormat;
+    use crate::{StopReason, Vm, VmError};
+
+    #[test]
+    fn should_trap_into_the_handler_and_mret_back() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x20
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c csrrsi zero, mstatus, 8
+        // 0x1010 ecall                    <- traps
+        // 0x1014 addi a1, zero, 1
+        // 0x1018 ebreak
+        // 0x101c nop
+        // 0x1020 csrrs a0, mcause, zero   <- handler
+        // 0x1024 csrrs t1, mepc, zero
+        // 0x1028 addi t1, t1, 4
+        // 0x102c csrrw zero, mepc, t1
+        // 0x1030 mret
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x0202_8293,
+            0x3052_9073,
+            0x3004_6073,
+            0x0000_0073,
+            0x0010_0593,
+            0x0010_0073,
+            0x0000_0013,
+            0x3420_2573,
+            0x3410_2373,
+            0x0043_0313,
+            0x3413_1073,
+            0x3020_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.ecall_policy = crate::ecall::EcallPolicy::bare_metal();
+
+        vm.breakpoints.add_breakpoint(0x1020);
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint { pc: 0x1020 }));
+        assert_eq!(vm.csrs.mepc, 0x1010);
+        // interrupts are off in the handler, and back on after the mret
+        assert_eq!(vm.csrs.mstatus & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+        vm.breakpoints.clear();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], CAUSE_ECALL_FROM_M as i32);
+        assert_eq!(vm.vm_state.registers[11], 1);
+        assert_ne!(vm.csrs.mstatus & MSTATUS_MIE, 0);
+    }
+
+    #[test]
+    fn interrupts_should_be_taken_by_priority_and_nest() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x2c
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c lui t0, 0x1
+        // 0x1010 addi t0, t0, -0x778     MEI, MTI and MSI
+        // 0x1014 csrrw zero, mie, t0
+        // 0x1018 csrrw zero, mip, t0
+        // 0x101c lui s0, 0x1
+        // 0x1020 addi s0, s0, 0x100      the log of mcause and mepc
+        // 0x1024 csrrsi zero, mstatus, 8 <- all three are taken after this
+        // 0x1028 ebreak
+        // 0x102c csrrs t1, mcause, zero  <- handler
+        // 0x1030 sw t1, 0(s0)
+        // 0x1034 csrrs t3, mepc, zero
+        // 0x1038 sw t3, 4(s0)
+        // 0x103c addi s0, s0, 8
+        // 0x1040 addi t2, zero, 1
+        // 0x1044 sll t2, t2, t1
+        // 0x1048 csrrc zero, mip, t2     clears the one it handles
+        // 0x104c addi sp, sp, -8
+        // 0x1050 sw t3, 0(sp)
+        // 0x1054 csrrs t4, mstatus, zero
+        // 0x1058 sw t4, 4(sp)            mepc and MPP are pushed
+        // 0x105c csrrsi zero, mstatus, 8 <- the next one nests here
+        // 0x1060 csrrci zero, mstatus, 8
+        // 0x1064 lw t3, 0(sp)
+        // 0x1068 lw t4, 4(sp)
+        // 0x106c addi sp, sp, 8
+        // 0x1070 csrrw zero, mepc, t3
+        // 0x1074 csrrw zero, mstatus, t4
+        // 0x1078 mret
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x02c2_8293,
+            0x3052_9073,
+            0x0000_12b7,
+            0x8882_8293,
+            0x3042_9073,
+            0x3442_9073,
+            0x0000_1437,
+            0x1004_0413,
+            0x3004_6073,
+            0x0010_0073,
+            0x3420_2373,
+            0x0064_2023,
+            0x3410_2e73,
+            0x01c4_2223,
+            0x0084_0413,
+            0x0010_0393,
+            0x0063_93b3,
+            0x3443_b073,
+            0xff81_0113,
+            0x01c1_2023,
+            0x3000_2ef3,
+            0x01d1_2223,
+            0x3004_6073,
+            0x3004_7073,
+            0x0001_2e03,
+            0x0041_2e83,
+            0x0081_0113,
+            0x341e_1073,
+            0x300e_9073,
+            0x3020_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x200);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.set_sp(0x1200);
+
+        // pending and enabled, but mstatus.MIE is off
+        vm.breakpoints.add_breakpoint(0x1024);
+        assert_eq!(vm.run(), Ok(StopReason::Breakpoint { pc: 0x1024 }));
+        assert_eq!(vm.pending_interrupts(), 0x888);
+        assert_eq!(vm.next_interrupt(), None);
+
+        // the interrupt is a step of its own, right after the csrrsi
+        vm.step().unwrap();
+        assert_eq!(vm.next_interrupt(), Some(11));
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.pc, 0x102c);
+        assert_eq!(vm.csrs.mepc, 0x1028);
+        assert_eq!(vm.next_interrupt(), None);
+
+        vm.breakpoints.clear();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.pending_interrupts(), 0);
+        assert_ne!(vm.csrs.mstatus & MSTATUS_MIE, 0);
+        let log: Vec<u32> = (0..6)
+            .map(|index| vm.memory.read_u32(0x1100 + 4 * index).unwrap())
+            .collect();
+        assert_eq!(
+            log,
+            [
+                CAUSE_INTERRUPT | 11,
+                0x1028,
+                CAUSE_INTERRUPT | 3,
+                0x1060,
+                CAUSE_INTERRUPT | 7,
+                0x1060,
+            ]
+        );
+        assert_eq!(vm.vm_state.sp(), 0x1200);
+    }
+
+    #[test]
+    fn vectored_mtvec_should_send_interrupts_to_their_own_entry() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x25       base 0x1024, vectored
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c addi t0, zero, 8        MSI
+        // 0x1010 csrrw zero, mie, t0
+        // 0x1014 csrrw zero, mip, t0
+        // 0x1018 csrrsi zero, mstatus, 8 <- the interrupt goes to 0x1030
+        // 0x101c ecall                   <- the exception to 0x1024
+        // 0x1020 ebreak
+        // 0x1024 jal zero, 0x103c        exceptions
+        // 0x1028 nop
+        // 0x102c nop
+        // 0x1030 csrrs a0, mcause, zero  <- machine software interrupt
+        // 0x1034 csrrc zero, mip, t0
+        // 0x1038 mret
+        // 0x103c csrrs a1, mcause, zero
+        // 0x1040 csrrs t1, mepc, zero
+        // 0x1044 addi t1, t1, 4
+        // 0x1048 csrrw zero, mepc, t1
+        // 0x104c mret
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x0252_8293,
+            0x3052_9073,
+            0x0080_0293,
+            0x3042_9073,
+            0x3442_9073,
+            0x3004_6073,
+            0x0000_0073,
+            0x0010_0073,
+            0x0180_006f,
+            0x0000_0013,
+            0x0000_0013,
+            0x3420_2573,
+            0x3442_b073,
+            0x3020_0073,
+            0x3420_25f3,
+            0x3410_2373,
+            0x0043_0313,
+            0x3413_1073,
+            0x3020_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.ecall_policy = crate::ecall::EcallPolicy::bare_metal();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10] as u32, CAUSE_INTERRUPT | 3);
+        assert_eq!(vm.vm_state.registers[11], CAUSE_ECALL_FROM_M as i32);
+
+        // the reserved modes are direct
+        assert!(vm.write_csr(CSR_MTVEC, 0x2002));
+        assert_eq!(vm.read_csr(CSR_MTVEC), Some(0x2000));
+    }
+
+    #[test]
+    fn illegal_instructions_should_trap_with_the_word_in_mtval() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x10
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c .word 0xfe000033         <- add with funct7 0x7f
+        // 0x1010 csrrs a0, mtval, zero    <- handler
+        // 0x1014 csrrs a1, mcause, zero
+        // 0x1018 ebreak
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x0102_8293,
+            0x3052_9073,
+            0xfe00_0033,
+            0x3430_2573,
+            0x3420_25f3,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let error = vm.run().unwrap_err();
+        assert_eq!(
+            error,
+            VmError::IllegalInstruction {
+                pc: 0x100c,
+                instruction: 0xfe00_0033,
+            }
+        );
+        assert_eq!(error.closest_format(), Some(InstructionFormat::R));
+        assert_eq!(
+            error.to_string(),
+            "illegal instruction 0xfe000033 at pc 0x0000100c (R-type)"
+        );
+
+        let mut vm = Vm::new(0x1000, 0x100).with_illegal_instruction_traps();
+        vm.load_program(0x1000, &program).unwrap();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.csrs.mepc, 0x100c);
+        assert_eq!(vm.vm_state.registers[10] as u32, 0xfe00_0033);
+        assert_eq!(vm.vm_state.registers[11], CAUSE_ILLEGAL_INSTRUCTION as i32);
+    }
+
+    #[test]
+    fn the_status_stack_should_follow_traps_across_modes() {
+        let new_vm = || {
+            let mut vm = Vm::new(0x1000, 0x100);
+            vm.memory.write_u32(0x1000, 0x0000_0013).unwrap(); // nop
+            vm.memory.write_u32(0x1040, 0x3020_0073).unwrap(); // mret
+            vm.memory.write_u32(0x1080, 0x1020_0073).unwrap(); // sret
+            vm.csrs.mtvec = 0x1040;
+            vm.csrs.stvec = 0x1080;
+            vm
+        };
+        let bit = |mstatus: u32, bit: u32| mstatus & bit != 0;
+        let modes = [Privilege::User, Privilege::Supervisor, Privilege::Machine];
+
+        for (from, delegated, enabled) in modes.into_iter().flat_map(|from| {
+            [(false, false), (false, true), (true, false), (true, true)]
+                .map(|(delegated, enabled)| (from, delegated, enabled))
+        }) {
+            let case = format!("from {from:?}, delegated {delegated}, enabled {enabled}");
+            let mut vm = new_vm();
+            vm.csrs.privilege = from;
+            vm.csrs.mstatus = if enabled {
+                MSTATUS_MIE | MSTATUS_SIE
+            } else {
+                0
+            };
+            vm.csrs.medeleg = if delegated {
+                1 << CAUSE_ILLEGAL_INSTRUCTION
+            } else {
+                0
+            };
+
+            vm.trap(CAUSE_ILLEGAL_INSTRUCTION, 0);
+            let mstatus = vm.csrs.mstatus;
+            if delegated && from != Privilege::Machine {
+                assert_eq!(vm.csrs.privilege, Privilege::Supervisor, "{case}");
+                assert_eq!((vm.vm_state.pc, vm.csrs.sepc), (0x1080, 0x1000), "{case}");
+                assert_eq!(bit(mstatus, MSTATUS_SPP), from == Privilege::Supervisor);
+                assert_eq!(bit(mstatus, MSTATUS_SPIE), enabled, "{case}");
+                assert!(!bit(mstatus, MSTATUS_SIE), "{case}");
+                assert_eq!(bit(mstatus, MSTATUS_MIE), enabled, "{case}");
+            } else {
+                assert_eq!(vm.csrs.privilege, Privilege::Machine, "{case}");
+                assert_eq!((vm.vm_state.pc, vm.csrs.mepc), (0x1040, 0x1000), "{case}");
+                assert_eq!(mstatus & MSTATUS_MPP, (from as u32) << 11, "{case}");
+                assert_eq!(bit(mstatus, MSTATUS_MPIE), enabled, "{case}");
+                assert!(!bit(mstatus, MSTATUS_MIE), "{case}");
+                assert_eq!(bit(mstatus, MSTATUS_SIE), enabled, "{case}");
+            }
+
+            // the return pops what the trap pushed, the previous mode is
+            // user mode after
+            vm.step().unwrap();
+            let mstatus = vm.csrs.mstatus;
+            assert_eq!(vm.csrs.privilege, from, "{case}");
+            assert_eq!(vm.vm_state.pc, 0x1000, "{case}");
+            assert_eq!(mstatus & (MSTATUS_MPP | MSTATUS_SPP), 0, "{case}");
+            assert_eq!(bit(mstatus, MSTATUS_MIE), enabled, "{case}");
+            assert_eq!(bit(mstatus, MSTATUS_SIE), enabled, "{case}");
+        }
+
+        // a machine timer interrupt in the supervisor handler of a trap from
+        // user mode, with the supervisor interrupts off
+        let mut vm = new_vm();
+        vm.csrs.privilege = Privilege::User;
+        vm.csrs.medeleg = 1 << CAUSE_ILLEGAL_INSTRUCTION;
+        vm.csrs.mie = MIP_MTIP;
+        vm.trap(CAUSE_ILLEGAL_INSTRUCTION, 0);
+        vm.csrs.mip = MIP_MTIP;
+        assert_eq!(vm.next_interrupt(), Some(7));
+        vm.step().unwrap();
+        assert_eq!(vm.csrs.privilege, Privilege::Machine);
+        assert_eq!((vm.vm_state.pc, vm.csrs.mepc), (0x1040, 0x1080));
+        assert_eq!(
+            vm.csrs.mstatus & MSTATUS_MPP,
+            (Privilege::Supervisor as u32) << 11
+        );
+
+        vm.csrs.mip = 0;
+        vm.step().unwrap();
+        assert_eq!(vm.csrs.privilege, Privilege::Supervisor);
+        vm.step().unwrap();
+        assert_eq!(vm.csrs.privilege, Privilege::User);
+        assert_eq!(vm.vm_state.pc, 0x1000);
+
+        // the reserved mode does not get into MPP, sstatus is a view
+        assert!(vm.write_csr(CSR_MSTATUS, 0b10 << 11 | MSTATUS_MIE));
+        assert_eq!(vm.read_csr(CSR_MSTATUS), Some(MSTATUS_MIE));
+        assert!(vm.write_csr(CSR_SSTATUS, MSTATUS_SIE | MSTATUS_MIE));
+        assert_eq!(vm.read_csr(CSR_SSTATUS), Some(MSTATUS_SIE));
+        assert_eq!(vm.csrs.mstatus, MSTATUS_MIE | MSTATUS_SIE);
+    }
+
+    #[test]
+    fn user_mode_should_trap_for_ecalls_and_machine_instructions() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x44
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c lui s0, 0x1
+        // 0x1010 addi s0, s0, 0x100      the log of mcause
+        // 0x1014 lui t1, 0x2
+        // 0x1018 addi t1, t1, -0x800
+        // 0x101c csrrc zero, mstatus, t1 MPP is user mode
+        // 0x1020 auipc t0, 0
+        // 0x1024 addi t0, t0, 0x10
+        // 0x1028 csrrw zero, mepc, t0
+        // 0x102c mret
+        // 0x1030 ecall                   <- user mode
+        // 0x1034 csrrs a0, mstatus, zero
+        // 0x1038 mret
+        // 0x103c wfi
+        // 0x1040 ebreak
+        // 0x1044 csrrs t2, mcause, zero  <- handler
+        // 0x1048 sw t2, 0(s0)
+        // 0x104c addi s0, s0, 4
+        // 0x1050 csrrs t2, mepc, zero
+        // 0x1054 addi t2, t2, 4
+        // 0x1058 csrrw zero, mepc, t2
+        // 0x105c mret
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x0442_8293,
+            0x3052_9073,
+            0x0000_1437,
+            0x1004_0413,
+            0x0000_2337,
+            0x8003_0313,
+            0x3003_3073,
+            0x0000_0297,
+            0x0102_8293,
+            0x3412_9073,
+            0x3020_0073,
+            0x0000_0073,
+            0x3000_2573,
+            0x3020_0073,
+            0x1050_0073,
+            0x0010_0073,
+            0x3420_23f3,
+            0x0074_2023,
+            0x0044_0413,
+            0x3410_23f3,
+            0x0043_8393,
+            0x3413_9073,
+            0x3020_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        // no `with_illegal_instruction_traps`, what user mode may not do
+        // goes to the kernel anyway
+        let mut vm = Vm::new(0x1000, 0x200);
+        vm.load_program(0x1000, &program).unwrap();
+
+        // the ecall goes to the guest's kernel even with the host's policy
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.csrs.privilege, Privilege::User);
+        let log: Vec<u32> = (0..4)
+            .map(|index| vm.memory.read_u32(0x1100 + 4 * index).unwrap())
+            .collect();
+        assert_eq!(
+            log,
+            [
+                CAUSE_ECALL_FROM_U,
+                CAUSE_ILLEGAL_INSTRUCTION,
+                CAUSE_ILLEGAL_INSTRUCTION,
+                CAUSE_ILLEGAL_INSTRUCTION,
+            ]
+        );
+        assert_eq!(vm.csrs.mtval, 0x1050_0073);
+        assert_eq!(vm.vm_state.registers[10], 0);
+    }
+
+    #[test]
+    fn should_not_write_read_only_or_unknown_csrs() {
+        // csrrw zero, cycle, t0
+        let program = 0xc002_9073u32.to_le_bytes();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.csrs.mtvec = 0x1080;
+        // a trap, also without `with_illegal_instruction_traps`
+        assert_eq!(vm.step(), Ok(()));
+        assert_eq!(vm.csrs.mcause, CAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(vm.csrs.mtval, 0xc002_9073);
+        assert_eq!(vm.csrs.mepc, 0x1000);
+        assert_eq!(vm.vm_state.pc, 0x1080);
+
+        assert_eq!(vm.read_csr(0x7c0), None);
+        assert!(!vm.write_csr(0x7c0, 1));
+    }
+}
@@ -0,0 +1,608 @@
+//! Source line numbers from the DWARF `.debug_line` section (versions 2 to
+//! 5, 32-bit DWARF only): the pc → (file, line) mapping and stepping over a
+//! whole source line.
+
+use super::elf::{Elf, ElfError};
+use super::emulator::{Instruction, Vm};
+use super::error::VmError;
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+
+// standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// DWARF 5 directory and file entry formats
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+/// one row of the line number matrix, the columns are left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u32,
+    /// index into `LineTable::files`
+    pub file: usize,
+    pub line: u32,
+    /// a good place for a breakpoint, the start of a statement
+    pub is_stmt: bool,
+    /// the first address after a sequence, it maps to no line
+    pub end_sequence: bool,
+}
+
+/// the line number rows of all compilation units, sorted by address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    pub rows: Vec<LineRow>,
+}
+
+/// reads the sections front to back, failing with `Truncated` instead of
+/// panicking
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ElfError> {
+        let bytes = self
+            .offset
+            .checked_add(length)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or(ElfError::Truncated)?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfError> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ElfError> {
+        let rest = self.bytes.get(self.offset..).ok_or(ElfError::Truncated)?;
+        let length = rest
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(ElfError::Truncated)?;
+        self.offset += length + 1;
+        Ok(String::from_utf8_lossy(&rest[..length]).into_owned())
+    }
+}
+
+/// the NUL terminated string at `offset` of a string section
+fn string_at(section: &[u8], offset: usize) -> Result<String, ElfError> {
+    Cursor {
+        bytes: section,
+        offset,
+    }
+    .string()
+}
+
+fn unsupported(reason: impl Into<String>) -> ElfError {
+    ElfError::UnsupportedDwarf {
+        reason: reason.into(),
+    }
+}
+
+/// the string sections DWARF 5 entries can point into
+struct StringSections<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+/// a path or directory index in a DWARF 5 entry
+enum EntryValue {
+    String(String),
+    Number(u64),
+    Other,
+}
+
+impl LineTable {
+    /// the line table of an ELF file, `None` if it has no `.debug_line`
+    pub fn from_elf(elf: &Elf) -> Result<Option<Self>, ElfError> {
+        let Some(debug_line) = elf.section(".debug_line") else {
+            return Ok(None);
+        };
+        let section = |name| elf.section(name).map_or(&[][..], |s| s.data.as_slice());
+        let strings = StringSections {
+            debug_str: section(".debug_str"),
+            debug_line_str: section(".debug_line_str"),
+        };
+        let mut table = Self::parse(&debug_line.data, &strings)?;
+        for row in &mut table.rows {
+            row.address = row.address.wrapping_add(elf.load_bias);
+        }
+        Ok(Some(table))
+    }
+
+    fn parse(debug_line: &[u8], strings: &StringSections) -> Result<Self, ElfError> {
+        let mut table = Self::default();
+        let mut cursor = Cursor {
+            bytes: debug_line,
+            offset: 0,
+        };
+        while cursor.offset < debug_line.len() {
+            table.parse_unit(&mut cursor, strings)?;
+        }
+        // stable, so an end_sequence stays before a sequence starting at
+        // the same address
+        table.rows.sort_by_key(|row| row.address);
+        Ok(table)
+    }
+
+    /// parses one line number program, the cursor ends after it
+    fn parse_unit(
+        &mut self,
+        cursor: &mut Cursor,
+        strings: &StringSections,
+    ) -> Result<(), ElfError> {
+        let unit_length = cursor.u32()?;
+        if unit_length >= 0xffff_fff0 {
+            return Err(unsupported("64-bit DWARF"));
+        }
+        let unit_end = cursor.offset + unit_length as usize;
+        let version = cursor.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(unsupported(format!("line table version {version}")));
+        }
+        if version >= 5 {
+            let _address_size = cursor.u8()?;
+            let _segment_selector_size = cursor.u8()?;
+        }
+        let header_length = cursor.u32()? as usize;
+        let program_start = cursor.offset + header_length;
+
+        let minimum_instruction_length = u32::from(cursor.u8()?);
+        if version >= 4 {
+            let _maximum_operations_per_instruction = cursor.u8()?;
+        }
+        let default_is_stmt = cursor.u8()? != 0;
+        let line_base = cursor.u8()? as i8;
+        let line_range = cursor.u8()?;
+        let opcode_base = cursor.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(unsupported("line_range or opcode_base is 0"));
+        }
+        let standard_opcode_lengths = cursor.take(opcode_base as usize - 1)?.to_vec();
+
+        // the files of this unit as indices into `self.files`, DWARF 5
+        // counts them from 0 and earlier versions from 1
+        let mut files = Vec::new();
+        let first_file = if version >= 5 { 0 } else { 1 };
+        if version >= 5 {
+            let directories: Vec<String> = Self::parse_entries(cursor, strings)?
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            for (path, directory) in Self::parse_entries(cursor, strings)? {
+                files.push(self.add_file(&directories, path, directory));
+            }
+        } else {
+            let mut directories = vec![String::new()];
+            loop {
+                let directory = cursor.string()?;
+                if directory.is_empty() {
+                    break;
+                }
+                directories.push(directory);
+            }
+            loop {
+                let path = cursor.string()?;
+                if path.is_empty() {
+                    break;
+                }
+                let directory = cursor.uleb()? as usize;
+                let _modification_time = cursor.uleb()?;
+                let _length = cursor.uleb()?;
+                files.push(self.add_file(&directories, path, directory));
+            }
+        }
+
+        cursor.offset = program_start;
+        let mut address = 0u32;
+        let mut file = 1u64;
+        let mut line = 1i64;
+        let mut is_stmt = default_is_stmt;
+        while cursor.offset < unit_end {
+            let mut emit = |end_sequence: bool, address: u32, file: u64, line: i64| {
+                let file = (file as usize)
+                    .checked_sub(first_file)
+                    .and_then(|index| files.get(index))
+                    .copied()
+                    .unwrap_or(usize::MAX);
+                self.rows.push(LineRow {
+                    address,
+                    file,
+                    line: line as u32,
+                    is_stmt,
+                    end_sequence,
+                });
+            };
+
+            let opcode = cursor.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                address = address
+                    .wrapping_add(u32::from(adjusted / line_range) * minimum_instruction_length);
+                line += i64::from(line_base) + i64::from(adjusted % line_range);
+                emit(false, address, file, line);
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let length = cursor.uleb()? as usize;
+                    let end = cursor.offset + length;
+                    match cursor.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            emit(true, address, file, line);
+                            address = 0;
+                            file = 1;
+                            line = 1;
+                            is_stmt = default_is_stmt;
+                        }
+                        DW_LNE_SET_ADDRESS => {
+                            address = cursor.u32()?;
+                        }
+                        DW_LNE_DEFINE_FILE => {
+                            let path = cursor.string()?;
+                            let directory = cursor.uleb()? as usize;
+                            let index = self.add_file(&[], path, directory);
+                            files.push(index);
+                        }
+                        _ => {}
+                    }
+                    cursor.offset = end;
+                }
+                DW_LNS_COPY => emit(false, address, file, line),
+                DW_LNS_ADVANCE_PC => {
+                    address =
+                        address.wrapping_add(cursor.uleb()? as u32 * minimum_instruction_length);
+                }
+                DW_LNS_ADVANCE_LINE => line += cursor.sleb()?,
+                DW_LNS_SET_FILE => file = cursor.uleb()?,
+                DW_LNS_NEGATE_STMT => is_stmt = !is_stmt,
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    address = address.wrapping_add(
+                        u32::from(adjusted / line_range) * minimum_instruction_length,
+                    );
+                }
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    address = address.wrapping_add(u32::from(cursor.u16()?));
+                }
+                // the rest only have ULEB128 operands the rows do not need
+                _ => {
+                    for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                        cursor.uleb()?;
+                    }
+                }
+            }
+        }
+        cursor.offset = unit_end;
+        Ok(())
+    }
+
+    /// the DWARF 5 directory or file name table: (path, directory index)
+    /// per entry
+    fn parse_entries(
+        cursor: &mut Cursor,
+        strings: &StringSections,
+    ) -> Result<Vec<(String, usize)>, ElfError> {
+        let format_count = cursor.u8()?;
+        let mut format = Vec::new();
+        for _ in 0..format_count {
+            format.push((cursor.uleb()?, cursor.uleb()?));
+        }
+
+        let count = cursor.uleb()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let mut path = String::new();
+            let mut directory = 0;
+            for (content, form) in &format {
+                let value = match *form {
+                    DW_FORM_STRING => EntryValue::String(cursor.string()?),
+                    DW_FORM_LINE_STRP => EntryValue::String(string_at(
+                        strings.debug_line_str,
+                        cursor.u32()? as usize,
+                    )?),
+                    DW_FORM_STRP => {
+                        EntryValue::String(string_at(strings.debug_str, cursor.u32()? as usize)?)
+                    }
+                    DW_FORM_UDATA => EntryValue::Number(cursor.uleb()?),
+                    DW_FORM_DATA1 => EntryValue::Number(cursor.u8()?.into()),
+                    DW_FORM_DATA2 => EntryValue::Number(cursor.u16()?.into()),
+                    DW_FORM_DATA4 => EntryValue::Number(cursor.u32()?.into()),
+                    DW_FORM_DATA8 => {
+                        cursor.take(8)?;
+                        EntryValue::Other
+                    }
+                    DW_FORM_DATA16 => {
+                        cursor.take(16)?;
+                        EntryValue::Other
+                    }
+                    DW_FORM_BLOCK => {
+                        let length = cursor.uleb()? as usize;
+                        cursor.take(length)?;
+                        EntryValue::Other
+                    }
+                    form => return Err(unsupported(format!("entry form {form:#x}"))),
+                };
+                match (*content, value) {
+                    (DW_LNCT_PATH, EntryValue::String(value)) => path = value,
+                    (DW_LNCT_DIRECTORY_INDEX, EntryValue::Number(value)) => {
+                        directory = value as usize;
+                    }
+                    _ => {}
+                }
+            }
+            entries.push((path, directory));
+        }
+        Ok(entries)
+    }
+
+    /// adds `directory/path` (or `path` if it is absolute or the directory
+    /// is unknown) to the files, returns its index
+    fn add_file(&mut self, directories: &[String], path: String, directory: usize) -> usize {
+        let path = match directories.get(directory) {
+            Some(directory) if !directory.is_empty() && !path.starts_with('/') => {
+                format!("{directory}/{path}")
+            }
+            _ => path,
+        };
+        match self.files.iter().position(|file| *file == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// the row `pc` belongs to: the last one at or before it, unless that one
+    /// ends its sequence
+    fn row(&self, pc: u32) -> Option<&LineRow> {
+        let index = self.rows.partition_point(|row| row.address <= pc);
+        let row = self.rows[..index].last()?;
+        (!row.end_sequence).then_some(row)
+    }
+
+    /// the source line the instruction at `pc` was compiled from
+    pub fn location(&self, pc: u32) -> Option<SourceLocation> {
+        let row = self.row(pc)?;
+        Some(SourceLocation {
+            file: self.files.get(row.file)?.clone(),
+            line: row.line,
+        })
+    }
+
+    /// whether a statement starts at `pc`
+    pub fn is_statement(&self, pc: u32) -> bool {
+        let start = self.rows.partition_point(|row| row.address < pc);
+        self.rows[start..]
+            .iter()
+            .take_while(|row| row.address == pc)
+            .any(|row| row.is_stmt && !row.end_sequence)
+    }
+}
+
+impl Vm {
+    /// the source line the pc is on, if the loaded ELF file had line numbers
+    pub fn source_location(&self) -> Option<SourceLocation> {
+        self.line_table.as_ref()?.location(self.vm_state.pc as u32)
+    }
+
+    /// steps to the start of the next source line, stepping over calls (like
+    /// `next` in gdb). Code without line numbers is stepped through. Returns
+    /// the new line, or `None` when the guest reached an `ebreak` first. Like
+    /// `step()` it does not check breakpoints or gas
+    pub fn step_over_line(&mut self) -> Result<Option<SourceLocation>, VmError> {
+        let Some(line_table) = self.line_table.take() else {
+            return Ok(None);
+        };
+        let result = self.step_over_line_with(&line_table);
+        self.line_table = Some(line_table);
+        result
+    }
+
+    fn step_over_line_with(
+        &mut self,
+        line_table: &LineTable,
+    ) -> Result<Option<SourceLocation>, VmError> {
+        let start = line_table.location(self.vm_state.pc as u32);
+        let depth = self.call_stack.len();
+        loop {
+            if let Instruction::Rv32iInstruction(_, Rv32iInstruction::Ebreak) = self.fetch()? {
+                return Ok(None);
+            }
+            self.step()?;
+
+            let pc = self.vm_state.pc as u32;
+            if self.call_stack.len() > depth || !line_table.is_statement(pc) {
+                continue;
+            }
+            let location = line_table.location(pc);
+            if location.is_some() && (location != start || self.call_stack.len() < depth) {
+                return Ok(location);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineTable, SourceLocation, StringSections};
+    use crate::elf::test_elf;
+    use crate::Vm;
+
+    /// a DWARF 4 line program for `main.rs` in `src`:
+    /// 0x1000 line 3, 0x1004 line 4, 0x100c line 3 again, 0x1010 line 5,
+    /// sequence ends at 0x1014. The function at 0x1014 (line 10) has its own
+    /// sequence
+    fn debug_line() -> Vec<u8> {
+        let mut header = vec![
+            1,    // minimum_instruction_length
+            1,    // maximum_operations_per_instruction
+            1,    // default_is_stmt
+            0xfb, // line_base -5
+            14,   // line_range
+            13,   // opcode_base
+        ];
+        header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        header.extend_from_slice(b"src\0\0main.rs\0\x01\x00\x00\0");
+
+        let special = |address: u8, line: i8| 13 + (line + 5) as u8 + 14 * address;
+        let mut program = vec![0, 5, 2];
+        program.extend_from_slice(&0x1000u32.to_le_bytes());
+        program.extend_from_slice(&[
+            3,
+            2, // line 3
+            1, // copy
+            special(4, 1),
+            special(8, -1),
+            special(4, 2),
+            2,
+            4, // advance_pc 4
+            0,
+            1,
+            1, // end_sequence
+            0,
+            5,
+            2,
+        ]);
+        program.extend_from_slice(&0x1014u32.to_le_bytes());
+        program.extend_from_slice(&[3, 9, 1, 2, 4, 0, 1, 1]);
+
+        let mut unit = 4u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+
+        let mut section = (unit.len() as u32).to_le_bytes().to_vec();
+        section.extend_from_slice(&unit);
+        section
+    }
+
+    fn location(line: u32) -> Option<SourceLocation> {
+        Some(SourceLocation {
+            file: "src/main.rs".to_string(),
+            line,
+        })
+    }
+
+    #[test]
+    fn should_map_pcs_to_source_lines() {
+        let strings = StringSections {
+            debug_str: &[],
+            debug_line_str: &[],
+        };
+        let table = LineTable::parse(&debug_line(), &strings).unwrap();
+
+        assert_eq!(table.location(0x1000), location(3));
+        assert_eq!(table.location(0x1008), location(4));
+        assert_eq!(table.location(0x100c), location(3));
+        assert_eq!(table.location(0x1010), location(5));
+        assert_eq!(table.location(0x1014), location(10));
+        assert_eq!(table.location(0x1018), None);
+        assert_eq!(table.location(0x0ffc), None);
+    }
+
+    #[test]
+    fn should_step_over_calls_line_by_line() {
+        // 0x1000 addi a0, zero, 1   line 3
+        // 0x1004 jal ra, 0x1014     line 4
+        // 0x1008 addi a0, a0, 1     line 4
+        // 0x100c addi a0, a0, 1     line 3
+        // 0x1010 ebreak             line 5
+        // 0x1014 addi a0, a0, 10    line 10
+        // 0x1018 ret
+        let code = [
+            0x0010_0513,
+            0x0100_00ef,
+            0x0015_0513,
+            0x0015_0513,
+            0x0010_0073,
+            0x00a5_0513,
+            0x0000_8067,
+        ];
+        let debug_line = debug_line();
+        let elf =
+            test_elf::build_with_sections(0x1000, &code, 0, &[], &[(".debug_line", &debug_line)]);
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_elf(&elf).unwrap();
+
+        assert_eq!(vm.source_location(), location(3));
+        assert_eq!(vm.step_over_line(), Ok(location(4)));
+        assert_eq!(vm.step_over_line(), Ok(location(3)));
+        assert_eq!(vm.vm_state.registers[10], 12);
+        assert_eq!(vm.step_over_line(), Ok(location(5)));
+        assert_eq!(vm.step_over_line(), Ok(None));
+    }
+}
@@ -0,0 +1,227 @@
+//! A cache of decoded instructions per 4 KiB page, so hot loops skip decoding
+//! the same words again. Guest stores drop the pages they write to. Loading
+//! a program, an ELF file or a snapshot drops everything.
+//!
+//! The host can also write to `vm.memory` directly. It must call
+//! `Vm::invalidate_decode_cache` after writing code that way, `fence.i` does
+//! the same for the guest.
+
+use super::emulator::{Instruction, Vm};
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+
+const PAGE_SHIFT: u32 = 12;
+const INSTRUCTIONS_PER_PAGE: usize = 1 << (PAGE_SHIFT - 2);
+
+/// the decoded instructions of one page with their raw words, `None` where
+/// nothing was decoded yet
+type Page = Box<[Option<(u32, Rv32iInstruction)>; INSTRUCTIONS_PER_PAGE]>;
+
+/// one entry per page of the guest memory, so a lookup is an index instead
+/// of a hash
+#[derive(Debug)]
+pub struct DecodeCache {
+    base: u32,
+    pages: Vec<Option<Page>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    /// a cache for the memory `base..base + size`
+    pub fn new(base: u32, size: usize) -> Self {
+        let page_count = size.div_ceil(1 << PAGE_SHIFT);
+        Self {
+            base,
+            pages: (0..page_count).map(|_| None).collect(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// the page index and the slot in it of the 4 byte aligned `address`
+    fn locate(&self, address: u32) -> (usize, usize) {
+        let offset = address.wrapping_sub(self.base) as usize;
+        (
+            offset >> PAGE_SHIFT,
+            (offset >> 2) & (INSTRUCTIONS_PER_PAGE - 1),
+        )
+    }
+
+    /// the cached instruction at the 4 byte aligned `address`
+    pub fn get(&mut self, address: u32) -> Option<(u32, Rv32iInstruction)> {
+        let (page, slot) = self.locate(address);
+        let cached = self
+            .pages
+            .get(page)
+            .and_then(|page| page.as_ref())
+            .and_then(|page| page[slot]);
+        match cached {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        cached
+    }
+
+    pub fn insert(&mut self, address: u32, word: u32, instruction: Rv32iInstruction) {
+        let (page, slot) = self.locate(address);
+        if let Some(page) = self.pages.get_mut(page) {
+            page.get_or_insert_with(|| Box::new([None; INSTRUCTIONS_PER_PAGE]))[slot] =
+                Some((word, instruction));
+        }
+    }
+
+    /// drops the pages `size` bytes from `address` touch
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        let (first, _) = self.locate(address);
+        let (last, _) = self.locate(address.wrapping_add(size.max(1) - 1));
+        for page in [first, last] {
+            if let Some(page) = self.pages.get_mut(page) {
+                *page = None;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.fill_with(|| None);
+    }
+
+    /// how many pages have decoded instructions
+    pub fn pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+}
+
+impl Vm {
+    /// turns on the decode cache
+    pub fn with_decode_cache(mut self) -> Self {
+        self.decode_cache = Some(DecodeCache::new(self.memory.base(), self.memory.size()));
+        self
+    }
+
+    /// drops every decoded instruction and block, and every jitted block
+    /// with the `jit` feature. Call it after writing code through
+    /// `vm.memory` directly
+    pub fn invalidate_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        if let Some(blocks) = &mut self.block_cache {
+            blocks.clear();
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.clear();
+        }
+    }
+
+    /// executes `fence` and `fence.i`, returns false for any other
+    /// instruction. Every access is in order here, so `fence` does nothing
+    pub(super) fn execute_fence(&mut self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Fence(_)) => {}
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::FenceI) => {
+                self.invalidate_decode_cache()
+            }
+            _ => return false,
+        }
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_decode_a_loop_body_once() {
+        // 0x1000 addi t0, zero, 3
+        // 0x1004 addi a0, a0, 1       <- loop
+        // 0x1008 addi t0, t0, -1
+        // 0x100c bne t0, zero, 0x1004
+        // 0x1010 ebreak
+        let mut vm = Vm::with_program_words(
+            0x1000,
+            &[
+                0x0030_0293,
+                0x0015_0513,
+                0xfff2_8293,
+                0xfe02_9ce3,
+                0x0010_0073,
+            ],
+        )
+        .with_decode_cache();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], 3);
+        let cache = vm.decode_cache.as_ref().unwrap();
+        assert_eq!((cache.misses, cache.hits), (5, 6));
+    }
+
+    #[test]
+    fn should_see_code_the_guest_overwrites() {
+        // 0x1000 lui t1, 0x1
+        // 0x1004 lw t2, 0x40(t1)
+        // 0x1008 addi a0, a0, 1       <- becomes addi a0, a0, 100
+        // 0x100c bne a1, zero, 0x101c
+        // 0x1010 addi a1, zero, 1
+        // 0x1014 sw t2, 8(t1)
+        // 0x1018 jal zero, 0x1008
+        // 0x101c ebreak
+        // 0x1040 .word addi a0, a0, 100
+        let mut program = vec![
+            0x0000_1337,
+            0x0403_2383,
+            0x0015_0513,
+            0x0005_9863,
+            0x0010_0593,
+            0x0073_2423,
+            0xff1f_f06f,
+            0x0010_0073,
+        ];
+        program.resize(16, 0);
+        program.push(0x0645_0513);
+        let mut vm = Vm::with_program_words(0x1000, &program).with_decode_cache();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], 101);
+    }
+
+    #[test]
+    fn should_run_code_the_guest_writes_after_fence_i() {
+        // 0x1000 lui t1, 0x1
+        // 0x1004 jal ra, 0x1080
+        // 0x1008 lw t2, 0x40(t1)
+        // 0x100c sw t2, 0x80(t1)
+        // 0x1010 fence
+        // 0x1014 fence.i
+        // 0x1018 jal ra, 0x1080
+        // 0x101c ebreak
+        // 0x1040 .word addi a0, zero, 42
+        // 0x1080 addi a0, zero, 1     <- becomes addi a0, zero, 42
+        // 0x1084 ret
+        let mut program = vec![
+            0x0000_1337,
+            0x07c0_00ef,
+            0x0403_2383,
+            0x0873_2023,
+            0x0ff0_000f,
+            0x0000_100f,
+            0x0680_00ef,
+            0x0010_0073,
+        ];
+        program.resize(16, 0);
+        program.push(0x02a0_0513);
+        program.resize(32, 0);
+        program.extend([0x0010_0513, 0x0000_8067]);
+        let mut vm = Vm::with_program_words(0x1000, &program)
+            .with_decode_cache()
+            .with_block_cache();
+
+        assert_eq!(vm.fetch_at(0x1010).unwrap().to_string(), "fence");
+        assert_eq!(vm.fetch_at(0x1014).unwrap().to_string(), "fence.i");
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], 42);
+    }
+}
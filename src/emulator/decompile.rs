@@ -0,0 +1,385 @@
+//! Best-effort lifting of a basic block into C-like pseudocode, for people
+//! who read C better than assembly. There is no type or data-flow analysis,
+//! every instruction becomes one statement and registers keep their ABI
+//! names.
+
+use super::emulator::{Instruction, PseudoInstruction, Vm};
+use super::instruction_signatures::{
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+    Source1Source2Immediate,
+};
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+use super::VmError;
+
+/// a basic block is cut after this many instructions even if it does not end
+/// in a jump, so decompiling data or a very long block stays cheap
+pub const MAX_BLOCK_LENGTH: usize = 64;
+
+impl Instruction {
+    /// true for the instructions after which execution does not simply go on
+    /// with the next one (branches, jumps, ecall and ebreak)
+    pub fn ends_basic_block(&self) -> bool {
+        match self {
+            Self::PseudoInstruction(_, PseudoInstruction::Ret) => true,
+            Self::PseudoInstruction(_, _) => false,
+            Self::Rv32iInstruction(_, instruction) => matches!(
+                instruction,
+                Rv32iInstruction::Beq(_)
+                    | Rv32iInstruction::Bne(_)
+                    | Rv32iInstruction::Blt(_)
+                    | Rv32iInstruction::Bge(_)
+                    | Rv32iInstruction::Bltu(_)
+                    | Rv32iInstruction::Bgeu(_)
+                    | Rv32iInstruction::Jal(_)
+                    | Rv32iInstruction::Jalr(_)
+                    | Rv32iInstruction::Ecall
+                    | Rv32iInstruction::Ebreak
+                    | Rv32iInstruction::Mret
+                    | Rv32iInstruction::Sret
+            ),
+        }
+    }
+}
+
+impl Vm {
+    /// decodes the basic block starting at `address`, up to and including the
+    /// instruction that ends it
+    pub fn basic_block(&self, address: u32) -> Result<Vec<Instruction>, VmError> {
+        let mut block = Vec::new();
+        let mut address = address;
+
+        while block.len() < MAX_BLOCK_LENGTH {
+            let instruction = self.fetch_at(address)?;
+            let ends_block = instruction.ends_basic_block();
+            block.push(instruction);
+            if ends_block {
+                break;
+            }
+            address = address.wrapping_add(4);
+        }
+
+        Ok(block)
+    }
+
+    /// the pseudocode of the basic block starting at `address`, e.g. the one
+    /// the vm is stopped in
+    pub fn decompile_at(&self, address: u32) -> Result<String, VmError> {
+        Ok(decompile(&self.basic_block(address)?))
+    }
+}
+
+/// turns the instructions into pseudocode, one line per instruction with its
+/// address in front
+pub fn decompile(instructions: &[Instruction]) -> String {
+    let mut decompiler = Decompiler::default();
+    let mut pseudocode = String::new();
+
+    for instruction in instructions {
+        let statement = decompiler.statement(instruction);
+        pseudocode.push_str(&format!(
+            "{:#010x}:  {statement}\n",
+            instruction.address() as u32
+        ));
+    }
+
+    pseudocode
+}
+
+/// what is remembered from the instructions before, to guess the arguments of
+/// a call
+#[derive(Default)]
+struct Decompiler {
+    /// the argument registers a0-a7 written so far, bit n is a<n>
+    arguments_written: u8,
+}
+
+impl Decompiler {
+    fn statement(&mut self, instruction: &Instruction) -> String {
+        let pc = instruction.address() as u32;
+
+        let statement = match instruction {
+            Instruction::PseudoInstruction(_, PseudoInstruction::Ret) => "return a0;".to_string(),
+            Instruction::PseudoInstruction(
+                _,
+                PseudoInstruction::Li(DestinationImmediate { rd, imm }),
+            ) => assign(*rd, &immediate(*imm)),
+            Instruction::Rv32iInstruction(_, instruction) => self.rv32i_statement(pc, instruction),
+        };
+
+        if let Some(argument) = instruction.destination().and_then(Register::argument_index) {
+            self.arguments_written |= 1 << argument;
+        }
+
+        statement
+    }
+
+    fn rv32i_statement(&self, pc: u32, instruction: &Rv32iInstruction) -> String {
+        match instruction {
+            Rv32iInstruction::Add(r) => binary(r, "+"),
+            Rv32iInstruction::Sub(r) => binary(r, "-"),
+            Rv32iInstruction::Xor(r) => binary(r, "^"),
+            Rv32iInstruction::Or(r) => binary(r, "|"),
+            Rv32iInstruction::And(r) => binary(r, "&"),
+            Rv32iInstruction::Sll(r) => binary(r, "<<"),
+            Rv32iInstruction::Srl(r) => assign(r.rd, &format!("(u32){} >> {}", r.rs1, r.rs2)),
+            Rv32iInstruction::Sra(r) => binary(r, ">>"),
+            Rv32iInstruction::Slt(r) => binary(r, "<"),
+            Rv32iInstruction::Sltu(r) => assign(r.rd, &format!("(u32){} < (u32){}", r.rs1, r.rs2)),
+
+            Rv32iInstruction::Addi(i) => match (i.rs1, i.imm) {
+                (Register::ZERO, imm) => assign(i.rd, &immediate(imm as i32)),
+                (rs1, 0) => assign(i.rd, rs1.abi_name()),
+                (rs1, imm) if imm < 0 => assign(i.rd, &format!("{rs1} - {}", -(imm as i32))),
+                (rs1, imm) => assign(i.rd, &format!("{rs1} + {imm}")),
+            },
+            Rv32iInstruction::Xori(i) if i.imm == -1 => assign(i.rd, &format!("~{}", i.rs1)),
+            Rv32iInstruction::Xori(i) => binary_immediate(i, "^"),
+            Rv32iInstruction::Ori(i) => binary_immediate(i, "|"),
+            Rv32iInstruction::Andi(i) => binary_immediate(i, "&"),
+            Rv32iInstruction::Slli(i) => binary_immediate(i, "<<"),
+            Rv32iInstruction::Srli(i) => {
+                assign(i.rd, &format!("(u32){} >> {}", i.rs1, i.imm & 0x1f))
+            }
+            Rv32iInstruction::Srai(i) => assign(i.rd, &format!("{} >> {}", i.rs1, i.imm & 0x1f)),
+            Rv32iInstruction::Slti(i) => binary_immediate(i, "<"),
+            Rv32iInstruction::Sltiu(i) if i.imm == 1 => assign(i.rd, &format!("{} == 0", i.rs1)),
+            Rv32iInstruction::Sltiu(i) => {
+                assign(i.rd, &format!("(u32){} < {}u", i.rs1, i.imm as i32 as u32))
+            }
+
+            Rv32iInstruction::Lb(i) => assign(i.rd, &load("i8", i)),
+            Rv32iInstruction::Lh(i) => assign(i.rd, &load("i16", i)),
+            Rv32iInstruction::Lw(i) => assign(i.rd, &load("i32", i)),
+            Rv32iInstruction::Lbu(i) => assign(i.rd, &load("u8", i)),
+            Rv32iInstruction::Lhu(i) => assign(i.rd, &load("u16", i)),
+
+            Rv32iInstruction::Sb(s) => store("i8", s),
+            Rv32iInstruction::Sh(s) => store("i16", s),
+            Rv32iInstruction::Sw(s) => store("i32", s),
+
+            Rv32iInstruction::Beq(b) => branch(pc, b, "=="),
+            Rv32iInstruction::Bne(b) => branch(pc, b, "!="),
+            Rv32iInstruction::Blt(b) => branch(pc, b, "<"),
+            Rv32iInstruction::Bge(b) => branch(pc, b, ">="),
+            Rv32iInstruction::Bltu(b) => unsigned_branch(pc, b, "<"),
+            Rv32iInstruction::Bgeu(b) => unsigned_branch(pc, b, ">="),
+
+            Rv32iInstruction::Jal(j) => {
+                let target = pc.wrapping_add(j.imm as u32);
+                match j.rd {
+                    Register::ZERO => format!("goto {target:#x};"),
+                    _ => self.call(j.rd, &format!("func_{target:x}")),
+                }
+            }
+            Rv32iInstruction::Jalr(i) => {
+                let target = offset(i.rs1, i.imm);
+                match (i.rd, i.rs1, i.imm) {
+                    (Register::ZERO, Register::RA, 0) => "return a0;".to_string(),
+                    (Register::ZERO, _, _) => format!("goto *{target};"),
+                    (rd, _, _) => self.call(rd, &format!("(*{target})")),
+                }
+            }
+
+            Rv32iInstruction::Lui(u) => assign(u.rd, &format!("{:#x}", (u.imm as u32) << 12)),
+            Rv32iInstruction::Auipc(u) => assign(
+                u.rd,
+                &format!("{:#x}", pc.wrapping_add((u.imm as u32) << 12)),
+            ),
+
+            Rv32iInstruction::Ecall => "a0 = ecall(a7, a0, a1, a2);".to_string(),
+            Rv32iInstruction::Ebreak => "breakpoint();".to_string(),
+            Rv32iInstruction::Csrrs(i) if i.rs1.is_zero() => {
+                assign(i.rd, &format!("read_csr({:#x})", i.imm))
+            }
+            Rv32iInstruction::Csrrs(i) => {
+                csr_call(i.rd, &format!("read_and_set_csr({:#x}, {})", i.imm, i.rs1))
+            }
+            Rv32iInstruction::Mret => "return_from_trap();".to_string(),
+            Rv32iInstruction::Sret => "return_from_supervisor_trap();".to_string(),
+            Rv32iInstruction::Wfi => "wait_for_interrupt();".to_string(),
+            Rv32iInstruction::Fence(_) => "fence();".to_string(),
+            Rv32iInstruction::FenceI => "flush_instruction_cache();".to_string(),
+            Rv32iInstruction::SfenceVma(r) => {
+                format!("flush_translations({}, {});", r.rs1, r.rs2)
+            }
+            Rv32iInstruction::Vector(vector) => format!("asm(\"{vector}\");"),
+            Rv32iInstruction::Custom(word) => format!("asm(\".insn 4, {word:#010x}\");"),
+            Rv32iInstruction::Csrrw(i) => {
+                csr_call(i.rd, &format!("swap_csr({:#x}, {})", i.imm, i.rs1))
+            }
+            Rv32iInstruction::Csrrc(i) => csr_call(
+                i.rd,
+                &format!("read_and_clear_csr({:#x}, {})", i.imm, i.rs1),
+            ),
+            Rv32iInstruction::Csrrwi(i) => {
+                csr_call(i.rd, &format!("swap_csr({:#x}, {})", i.imm, i.rs1.index()))
+            }
+            Rv32iInstruction::Csrrsi(i) => csr_call(
+                i.rd,
+                &format!("read_and_set_csr({:#x}, {})", i.imm, i.rs1.index()),
+            ),
+            Rv32iInstruction::Csrrci(i) => csr_call(
+                i.rd,
+                &format!("read_and_clear_csr({:#x}, {})", i.imm, i.rs1.index()),
+            ),
+
+            Rv32iInstruction::LrW(r) => assign(r.rd, &format!("load_reserved((i32*){})", r.rs1)),
+            Rv32iInstruction::ScW(r) => csr_call(
+                r.rd,
+                &format!("store_conditional((i32*){}, {})", r.rs1, r.rs2),
+            ),
+            Rv32iInstruction::AmoswapW(r)
+            | Rv32iInstruction::AmoaddW(r)
+            | Rv32iInstruction::AmoxorW(r)
+            | Rv32iInstruction::AmoandW(r)
+            | Rv32iInstruction::AmoorW(r)
+            | Rv32iInstruction::AmominW(r)
+            | Rv32iInstruction::AmomaxW(r)
+            | Rv32iInstruction::AmominuW(r)
+            | Rv32iInstruction::AmomaxuW(r) => {
+                let operation = instruction.mnemonic().trim_start_matches("amo");
+                let operation = operation.trim_end_matches(".w");
+                csr_call(
+                    r.rd,
+                    &format!("atomic_{operation}((i32*){}, {})", r.rs1, r.rs2),
+                )
+            }
+        }
+    }
+
+    /// a call is written with the argument registers set up before it in the
+    /// same block, e.g. `a0 = func_1000(a0, a1);`
+    fn call(&self, link: Register, function: &str) -> String {
+        let argument_count = 8 - self.arguments_written.leading_zeros() as u8;
+        let arguments: Vec<&str> = (0..argument_count)
+            .filter_map(|n| Register::new(Register::A0.index() as u8 + n))
+            .map(Register::abi_name)
+            .collect();
+        let call = format!("a0 = {function}({});", arguments.join(", "));
+
+        // anything but ra as link register is unusual enough to show it
+        if link == Register::RA {
+            call
+        } else {
+            format!("{call} /* link in {link} */")
+        }
+    }
+}
+
+fn assign(rd: Register, value: &str) -> String {
+    // writes to x0 are thrown away, `addi zero, zero, 0` is the canonical nop
+    if rd.is_zero() {
+        return "/* nop */".to_string();
+    }
+    format!("{} = {value};", rd)
+}
+
+/// a CSR write still happens when the old value is thrown away
+fn csr_call(rd: Register, call: &str) -> String {
+    if rd.is_zero() {
+        format!("{call};")
+    } else {
+        assign(rd, call)
+    }
+}
+
+fn immediate(imm: i32) -> String {
+    if (-256..256).contains(&imm) {
+        imm.to_string()
+    } else {
+        format!("{imm:#x}")
+    }
+}
+
+fn binary(r: &DestinationSource1Source2, operator: &str) -> String {
+    assign(r.rd, &format!("{} {operator} {}", r.rs1, r.rs2))
+}
+
+fn binary_immediate(i: &DestinationSource1Immediate, operator: &str) -> String {
+    assign(
+        i.rd,
+        &format!("{} {operator} {}", i.rs1, immediate(i.imm as i32)),
+    )
+}
+
+/// `sp + 8`, `sp - 4` or just `sp`
+fn offset(base: Register, imm: i16) -> String {
+    match imm {
+        0 => base.to_string(),
+        imm if imm < 0 => format!("({base} - {})", -(imm as i32)),
+        imm => format!("({base} + {imm})"),
+    }
+}
+
+fn load(kind: &str, i: &DestinationSource1Immediate) -> String {
+    format!("*({kind} *){}", offset(i.rs1, i.imm))
+}
+
+fn store(kind: &str, s: &Source1Source2Immediate) -> String {
+    format!("*({kind} *){} = {};", offset(s.rs1, s.imm), s.rs2)
+}
+
+fn branch(pc: u32, b: &Source1Source2Immediate, operator: &str) -> String {
+    let target = pc.wrapping_add(b.imm as i32 as u32);
+    format!("if ({} {operator} {}) goto {target:#x};", b.rs1, b.rs2)
+}
+
+fn unsigned_branch(pc: u32, b: &Source1Source2Immediate, operator: &str) -> String {
+    let target = pc.wrapping_add(b.imm as i32 as u32);
+    format!(
+        "if ((u32){} {operator} (u32){}) goto {target:#x};",
+        b.rs1, b.rs2
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Vm;
+
+    #[test]
+    fn should_decompile_a_call_with_its_arguments() {
+        // 0x1000 addi a0, zero, 42
+        // 0x1004 addi a1, a0, -1
+        // 0x1008 sw a1, 8(sp)
+        // 0x100c jal ra, 0x1020
+        let program: Vec<u8> = [0x02a0_0513u32, 0xfff5_0593, 0x00b1_2423, 0x014000ef]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(
+            vm.decompile_at(0x1000).unwrap(),
+            "0x00001000:  a0 = 42;\n\
+             0x00001004:  a1 = a0 - 1;\n\
+             0x00001008:  *(i32 *)(sp + 8) = a1;\n\
+             0x0000100c:  a0 = func_1020(a0, a1);\n"
+        );
+    }
+
+    #[test]
+    fn block_should_end_at_the_first_branch() {
+        // 0x1000 lw a5, 0(a0)
+        // 0x1004 bltu a5, a1, 0x0ff8
+        // 0x1008 ret
+        let program: Vec<u8> = [0x0005_2783u32, 0xfeb7_eae3, 0x0000_8067]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(
+            vm.decompile_at(0x1000).unwrap(),
+            "0x00001000:  a5 = *(i32 *)a0;\n\
+             0x00001004:  if ((u32)a5 < (u32)a1) goto 0xff8;\n"
+        );
+        assert_eq!(
+            vm.decompile_at(0x1008).unwrap(),
+            "0x00001008:  return a0;\n"
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use thiserror::Error;
+
+use super::emulator::Vm;
+use super::register::Register;
+
+#[derive(Error, Debug)]
+pub enum DifferentialError {
+    #[error("could not parse line {line} of the reference trace: `{content}`")]
+    Parse { line: usize, content: String },
+    #[error("could not run the reference simulator: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// what the reference simulator tells us about the state after an instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpectedState {
+    /// spike's commit log only lists the registers that were written
+    RegisterWrites(Vec<(Register, u32)>),
+    /// QEMU dumps the full register file
+    Registers([u32; 32]),
+}
+
+/// one instruction retired by the reference simulator
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceStep {
+    /// the address of the instruction
+    pub pc: u32,
+    /// the raw instruction, if the trace contains it
+    pub instruction: Option<u32>,
+    /// the state after the instruction
+    pub expected: ExpectedState,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReferenceTrace {
+    pub steps: Vec<ReferenceStep>,
+}
+
+impl ReferenceTrace {
+    /// parses the output of `spike -l --log-commits`, the lines look like
+    ///
+    /// ```text
+    /// core   0: 3 0x80000000 (0x00000297) x5  0x80000000
+    /// ```
+    ///
+    /// where `3` is the privilege level, then the pc, the raw instruction and
+    /// the register writes. Lines that are not commit lines (the disassembly
+    /// of `-l` without `--log-commits`, warnings, ...) are skipped.
+    pub fn from_spike_commit_log(log: &str) -> Result<Self, DifferentialError> {
+        let mut steps = Vec::new();
+
+        for (line_index, line) in log.lines().enumerate() {
+            let parse_error = || DifferentialError::Parse {
+                line: line_index + 1,
+                content: line.to_string(),
+            };
+
+            let Some(rest) = line.trim_start().strip_prefix("core") else {
+                continue;
+            };
+            let Some((_, commit)) = rest.split_once(':') else {
+                continue;
+            };
+
+            let tokens: Vec<&str> = commit.split_whitespace().collect();
+
+            // the first token must be the privilege level, otherwise this is
+            // a disassembly line
+            match tokens.first() {
+                Some(privilege)
+                    if privilege.len() == 1 && privilege.chars().all(|c| c.is_ascii_digit()) => {}
+                _ => continue,
+            }
+
+            let pc = tokens
+                .get(1)
+                .and_then(|token| parse_hex(token))
+                .ok_or_else(parse_error)?;
+            let instruction = tokens
+                .get(2)
+                .and_then(|token| token.strip_prefix('(')?.strip_suffix(')'))
+                .and_then(parse_hex)
+                .ok_or_else(parse_error)?;
+
+            let mut register_writes = Vec::new();
+            let mut index = 3;
+            while index < tokens.len() {
+                // x registers only, memory writes (`mem`) and CSRs are skipped
+                // together with their value
+                if let Some(register) =
+                    Register::from_name(tokens[index]).filter(|_| tokens[index].starts_with('x'))
+                {
+                    let value = tokens
+                        .get(index + 1)
+                        .and_then(|token| parse_hex(token))
+                        .ok_or_else(parse_error)?;
+                    register_writes.push((register, value));
+                    index += 2;
+                } else if tokens[index] == "mem" {
+                    // `mem address` for loads, `mem address value` for stores
+                    index += 2;
+                    if tokens
+                        .get(index)
+                        .is_some_and(|token| token.starts_with("0x"))
+                    {
+                        index += 1;
+                    }
+                } else {
+                    index += 2;
+                }
+            }
+
+            steps.push(ReferenceStep {
+                pc,
+                instruction: Some(instruction),
+                expected: ExpectedState::RegisterWrites(register_writes),
+            });
+        }
+
+        Ok(Self { steps })
+    }
+
+    /// parses the output of `qemu-riscv32 -one-insn-per-tb -d cpu,nochain`.
+    /// QEMU prints the whole cpu state *before* every instruction
+    ///
+    /// ```text
+    ///  pc       80000000
+    ///  x0/zero  00000000 x1/ra    00000000 x2/sp    00000000 x3/gp    00000000
+    ///  ...
+    /// ```
+    ///
+    /// so the state after instruction `n` is the dump in front of
+    /// instruction `n + 1`, which means the last dump only closes the
+    /// previous step.
+    pub fn from_qemu_cpu_log(log: &str) -> Result<Self, DifferentialError> {
+        let mut dumps: Vec<(u32, [u32; 32])> = Vec::new();
+
+        for (line_index, line) in log.lines().enumerate() {
+            let parse_error = || DifferentialError::Parse {
+                line: line_index + 1,
+                content: line.to_string(),
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            if tokens.first() == Some(&"pc") {
+                let pc = tokens
+                    .get(1)
+                    .and_then(|token| parse_hex(token))
+                    .ok_or_else(parse_error)?;
+                dumps.push((pc, [0; 32]));
+                continue;
+            }
+
+            for pair in tokens.chunks(2) {
+                let [name, value] = pair else {
+                    continue;
+                };
+                let Some(register) = name
+                    .split_once('/')
+                    .and_then(|(register, _)| Register::from_name(register))
+                else {
+                    continue;
+                };
+
+                let value = parse_hex(value).ok_or_else(parse_error)?;
+                if let Some((_, registers)) = dumps.last_mut() {
+                    registers[register] = value;
+                }
+            }
+        }
+
+        let steps = dumps
+            .windows(2)
+            .map(|window| ReferenceStep {
+                pc: window[0].0,
+                instruction: None,
+                expected: ExpectedState::Registers(window[1].1),
+            })
+            .collect();
+
+        Ok(Self { steps })
+    }
+
+    /// drops everything before the first instruction at `pc`. Handy to skip
+    /// the boot rom spike runs before jumping to the program.
+    pub fn starting_at(mut self, pc: u32) -> Self {
+        let first = self
+            .steps
+            .iter()
+            .position(|step| step.pc == pc)
+            .unwrap_or(self.steps.len());
+        self.steps.drain(..first);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+/// runs `spike` on the elf and returns its commit log (spike writes it to stderr)
+pub fn run_spike(elf: &Path, isa: &str) -> Result<ReferenceTrace, DifferentialError> {
+    let output = Command::new("spike")
+        .arg(format!("--isa={isa}"))
+        .arg("-l")
+        .arg("--log-commits")
+        .arg(elf)
+        .output()?;
+
+    ReferenceTrace::from_spike_commit_log(&String::from_utf8_lossy(&output.stderr))
+}
+
+/// runs QEMU user mode on the elf and returns its per instruction cpu dumps
+/// (QEMU writes them to stderr)
+pub fn run_qemu(elf: &Path) -> Result<ReferenceTrace, DifferentialError> {
+    let output = Command::new("qemu-riscv32")
+        .arg("-one-insn-per-tb")
+        .arg("-d")
+        .arg("cpu,nochain")
+        .arg(elf)
+        .output()?;
+
+    ReferenceTrace::from_qemu_cpu_log(&String::from_utf8_lossy(&output.stderr))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DivergenceKind {
+    /// we are not at the instruction the reference executed
+    Pc { expected: u32, actual: u32 },
+    /// same pc, but a different instruction in memory
+    Instruction { expected: u32, actual: u32 },
+    /// our vm could not execute the instruction at all
+    Vm(String),
+    /// a register has a different value after the instruction
+    Register {
+        register: Register,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// the first place where our vm and the reference disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// index of the instruction in the trace, starting at 0
+    pub step: usize,
+    pub pc: u32,
+    /// the instruction at `pc` in our memory
+    pub instruction: Option<u32>,
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "divergence at step {}, pc {:#010x}", self.step, self.pc)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, " ({instruction:#010x})")?;
+        }
+        write!(f, ": ")?;
+
+        match &self.kind {
+            DivergenceKind::Pc { expected, actual } => {
+                write!(f, "pc expected {expected:#010x}, got {actual:#010x}")
+            }
+            DivergenceKind::Instruction { expected, actual } => {
+                write!(
+                    f,
+                    "instruction expected {expected:#010x}, got {actual:#010x}"
+                )
+            }
+            DivergenceKind::Vm(error) => write!(f, "the vm failed with `{error}`"),
+            DivergenceKind::Register {
+                register,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{register:#} expected {expected:#010x}, got {actual:#010x}"
+            ),
+        }
+    }
+}
+
+/// Differential testing against a reference simulator (spike or QEMU).
+///
+/// The same program runs in the reference simulator with its trace output
+/// turned on, that trace is parsed into a `ReferenceTrace` and this steps
+/// `vm` through it one instruction at a time, comparing the whole register
+/// file after every instruction. The vm must already be set up the same way
+/// the reference starts (program loaded, pc and registers set).
+///
+/// Returns the number of instructions that matched, or the first divergence.
+pub fn compare(vm: &mut Vm, trace: &ReferenceTrace) -> Result<usize, Divergence> {
+    // what the registers should be, only needed for spike since it only tells
+    // us about the writes
+    let mut expected_registers = vm
+        .vm_state
+        .registers
+        .as_array()
+        .map(|register| register as u32);
+
+    for (step_index, step) in trace.steps.iter().enumerate() {
+        let pc = vm.vm_state.pc as u32;
+        let instruction = vm.memory.read_u32(pc).ok();
+        let divergence = |kind| Divergence {
+            step: step_index,
+            pc,
+            instruction,
+            kind,
+        };
+
+        if pc != step.pc {
+            return Err(divergence(DivergenceKind::Pc {
+                expected: step.pc,
+                actual: pc,
+            }));
+        }
+
+        if let (Some(expected), Some(actual)) = (step.instruction, instruction) {
+            if expected != actual {
+                return Err(divergence(DivergenceKind::Instruction { expected, actual }));
+            }
+        }
+
+        if let Err(error) = vm.step() {
+            return Err(divergence(DivergenceKind::Vm(error.to_string())));
+        }
+
+        match &step.expected {
+            ExpectedState::RegisterWrites(register_writes) => {
+                for (register, value) in register_writes {
+                    expected_registers[*register] = *value;
+                }
+            }
+            ExpectedState::Registers(registers) => expected_registers = *registers,
+        }
+        // x0 is zero no matter what the trace says
+        expected_registers[Register::ZERO] = 0;
+
+        for register in Register::all() {
+            let expected = expected_registers[register];
+            let actual = vm.vm_state.registers[register] as u32;
+            if actual != expected {
+                return Err(divergence(DivergenceKind::Register {
+                    register,
+                    expected,
+                    actual,
+                }));
+            }
+        }
+    }
+
+    Ok(trace.steps.len())
+}
+
+fn parse_hex(token: &str) -> Option<u32> {
+    u32::from_str_radix(token.trim_start_matches("0x"), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, DivergenceKind, ExpectedState, ReferenceTrace};
+    use crate::{Register, Vm};
+
+    // addi a3, zero, 10
+    // addi a2, zero, 15
+    // add a0, a0, a1
+    const PROGRAM: [u32; 3] = [0x00a0_0693, 0x00f0_0613, 0x00b5_0533];
+
+    #[test]
+    fn should_parse_spike_commit_log() {
+        let log = "\
+core   0: 0x00001000 (0x00a00693) li      a3, 10
+core   0: 3 0x00001000 (0x00a00693) x13 0x0000000a
+core   0: 3 0x00001004 (0x00012403) x8  0x00000000 mem 0x00002000
+core   0: 3 0x00001008 (0x00812023) mem 0x00002000 0x00000000
+";
+        let trace = ReferenceTrace::from_spike_commit_log(log).unwrap();
+
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace.steps[0].pc, 0x1000);
+        assert_eq!(trace.steps[0].instruction, Some(0x00a0_0693));
+        assert_eq!(
+            trace.steps[0].expected,
+            ExpectedState::RegisterWrites(vec![(Register::A3, 10)])
+        );
+        assert_eq!(
+            trace.steps[1].expected,
+            ExpectedState::RegisterWrites(vec![(Register::S0, 0)])
+        );
+        assert_eq!(
+            trace.steps[2].expected,
+            ExpectedState::RegisterWrites(vec![])
+        );
+    }
+
+    #[test]
+    fn should_parse_qemu_cpu_log() {
+        let log = "\
+ pc       00001000
+ x0/zero  00000000 x1/ra    00000000 x2/sp    00000000 x3/gp    00000000
+ pc       00001004
+ x0/zero  00000000 x1/ra    00000000 x2/sp    00000010 x3/gp    00000000
+";
+        let trace = ReferenceTrace::from_qemu_cpu_log(log).unwrap();
+
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace.steps[0].pc, 0x1000);
+        let ExpectedState::Registers(registers) = trace.steps[0].expected else {
+            panic!("qemu dumps the full register file");
+        };
+        assert_eq!(registers[2], 0x10);
+    }
+
+    #[test]
+    fn should_match_a_correct_reference_trace() {
+        let log = "\
+core   0: 3 0x00001000 (0x00a00693) x13 0x0000000a
+core   0: 3 0x00001004 (0x00f00613) x12 0x0000000f
+core   0: 3 0x00001008 (0x00b50533) x10 0x00000069
+";
+        let trace = ReferenceTrace::from_spike_commit_log(log).unwrap();
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.vm_state.registers.write(Register::A0, 50);
+        vm.vm_state.registers.write(Register::A1, 55);
+
+        assert_eq!(compare(&mut vm, &trace), Ok(3));
+    }
+
+    #[test]
+    fn should_report_the_first_divergence() {
+        // the reference says a2 is 16 after the second instruction
+        let log = "\
+core   0: 3 0x00001000 (0x00a00693) x13 0x0000000a
+core   0: 3 0x00001004 (0x00f00613) x12 0x00000010
+core   0: 3 0x00001008 (0x00b50533) x10 0x00000069
+";
+        let trace = ReferenceTrace::from_spike_commit_log(log).unwrap();
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.vm_state.registers.write(Register::A0, 50);
+        vm.vm_state.registers.write(Register::A1, 55);
+
+        let divergence = compare(&mut vm, &trace).unwrap_err();
+
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.pc, 0x1004);
+        assert_eq!(
+            divergence.kind,
+            DivergenceKind::Register {
+                register: Register::A2,
+                expected: 0x10,
+                actual: 0xf
+            }
+        );
+        assert_eq!(
+            divergence.to_string(),
+            "divergence at step 1, pc 0x00001004 (0x00f00613): x12 expected 0x00000010, got 0x0000000f"
+        );
+    }
+}
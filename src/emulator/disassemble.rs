@@ -0,0 +1,287 @@
+//! Disassembly in the GNU assembler syntax, with ABI register names. Jump and
+//! branch targets are printed as `<symbol>` (or `<symbol+0x8>`) when the
+//! symbols of the loaded ELF file know them.
+
+use core::fmt;
+
+use super::elf::Symbol;
+use super::emulator::{Instruction, PseudoInstruction, Vm};
+use super::error::VmError;
+use super::instruction_signatures::{
+    DestinationSource1Immediate, DestinationSource1Source2, Source1Source2Immediate,
+};
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// the symbol `address` is in and how far into it. Symbols without a size
+/// (assembly labels) only match their own address
+pub fn symbol_at(symbols: &[Symbol], address: u32) -> Option<(&Symbol, u32)> {
+    symbols
+        .iter()
+        .filter(|symbol| {
+            let offset = address.wrapping_sub(symbol.address);
+            address >= symbol.address && (offset < symbol.size || offset == 0)
+        })
+        .max_by_key(|symbol| symbol.address)
+        .map(|symbol| (symbol, address - symbol.address))
+}
+
+/// `<name>`/`<name+0x8>` if a symbol knows `address`, the address otherwise
+fn target(symbols: &[Symbol], address: u32) -> String {
+    match symbol_at(symbols, address) {
+        Some((symbol, 0)) => format!("<{}>", symbol.name),
+        Some((symbol, offset)) => format!("<{}+{offset:#x}>", symbol.name),
+        None => format!("{address:#x}"),
+    }
+}
+
+fn register(mnemonic: &str, r: &DestinationSource1Source2) -> String {
+    format!("{mnemonic} {}, {}, {}", r.rd, r.rs1, r.rs2)
+}
+
+fn immediate(mnemonic: &str, i: &DestinationSource1Immediate) -> String {
+    format!("{mnemonic} {}, {}, {}", i.rd, i.rs1, i.imm)
+}
+
+fn shift(mnemonic: &str, i: &DestinationSource1Immediate) -> String {
+    format!("{mnemonic} {}, {}, {}", i.rd, i.rs1, i.imm & 0x1f)
+}
+
+fn load(mnemonic: &str, i: &DestinationSource1Immediate) -> String {
+    format!("{mnemonic} {}, {}({})", i.rd, i.imm, i.rs1)
+}
+
+fn store(mnemonic: &str, s: &Source1Source2Immediate) -> String {
+    format!("{mnemonic} {}, {}({})", s.rs2, s.imm, s.rs1)
+}
+
+fn branch(mnemonic: &str, pc: u32, b: &Source1Source2Immediate, symbols: &[Symbol]) -> String {
+    let address = pc.wrapping_add(b.imm as i32 as u32);
+    format!(
+        "{mnemonic} {}, {}, {}",
+        b.rs1,
+        b.rs2,
+        target(symbols, address)
+    )
+}
+
+/// the CSR instructions, the immediate forms keep their operand in `rs1`
+fn csr(mnemonic: &str, i: &DestinationSource1Immediate, immediate: bool) -> String {
+    let csr = i.imm as u16;
+    if immediate {
+        format!("{mnemonic} {}, {csr:#x}, {}", i.rd, i.rs1.index())
+    } else {
+        format!("{mnemonic} {}, {csr:#x}, {}", i.rd, i.rs1)
+    }
+}
+
+/// `fence pred, succ` like objdump, the plain `fence` orders everything
+fn fence(imm: u32) -> String {
+    let set = |bits: u32| -> String {
+        "iorw"
+            .chars()
+            .enumerate()
+            .filter(|(index, _)| bits & (0b1000 >> index) != 0)
+            .map(|(_, access)| access)
+            .collect()
+    };
+    let (pred, succ) = (imm >> 4 & 0xf, imm & 0xf);
+    match imm >> 8 {
+        0b1000 if pred == 0b0011 && succ == 0b0011 => "fence.tso".to_string(),
+        _ if pred == 0xf && succ == 0xf => "fence".to_string(),
+        _ => format!("fence {}, {}", set(pred), set(succ)),
+    }
+}
+
+impl Instruction {
+    /// the instruction in assembly, with the jump and branch targets named
+    /// after `symbols` where they can be
+    pub fn disassemble(&self, symbols: &[Symbol]) -> String {
+        let pc = self.address() as u32;
+        let instruction = match self {
+            Self::PseudoInstruction(_, PseudoInstruction::Ret) => return "ret".to_string(),
+            Self::PseudoInstruction(_, PseudoInstruction::Li(li)) => {
+                return format!("li {}, {}", li.rd, li.imm)
+            }
+            Self::Rv32iInstruction(_, instruction) => instruction,
+        };
+
+        let mnemonic = instruction.mnemonic();
+        match instruction {
+            Rv32iInstruction::Add(r)
+            | Rv32iInstruction::Sub(r)
+            | Rv32iInstruction::Xor(r)
+            | Rv32iInstruction::Or(r)
+            | Rv32iInstruction::And(r)
+            | Rv32iInstruction::Sll(r)
+            | Rv32iInstruction::Srl(r)
+            | Rv32iInstruction::Sra(r)
+            | Rv32iInstruction::Slt(r)
+            | Rv32iInstruction::Sltu(r) => register(mnemonic, r),
+
+            Rv32iInstruction::Addi(i)
+            | Rv32iInstruction::Xori(i)
+            | Rv32iInstruction::Ori(i)
+            | Rv32iInstruction::Andi(i)
+            | Rv32iInstruction::Slti(i)
+            | Rv32iInstruction::Sltiu(i) => immediate(mnemonic, i),
+            Rv32iInstruction::Slli(i) | Rv32iInstruction::Srli(i) | Rv32iInstruction::Srai(i) => {
+                shift(mnemonic, i)
+            }
+
+            // objdump prints the plain return as the pseudo-instruction too
+            Rv32iInstruction::Jalr(i) if i.rd.is_zero() && i.rs1 == Register::RA && i.imm == 0 => {
+                "ret".to_string()
+            }
+            Rv32iInstruction::Lb(i)
+            | Rv32iInstruction::Lh(i)
+            | Rv32iInstruction::Lw(i)
+            | Rv32iInstruction::Lbu(i)
+            | Rv32iInstruction::Lhu(i)
+            | Rv32iInstruction::Jalr(i) => load(mnemonic, i),
+
+            Rv32iInstruction::Sb(s) | Rv32iInstruction::Sh(s) | Rv32iInstruction::Sw(s) => {
+                store(mnemonic, s)
+            }
+
+            Rv32iInstruction::Beq(b)
+            | Rv32iInstruction::Bne(b)
+            | Rv32iInstruction::Blt(b)
+            | Rv32iInstruction::Bge(b)
+            | Rv32iInstruction::Bltu(b)
+            | Rv32iInstruction::Bgeu(b) => branch(mnemonic, pc, b, symbols),
+
+            Rv32iInstruction::Jal(j) => {
+                let address = pc.wrapping_add(j.imm as u32);
+                format!("jal {}, {}", j.rd, target(symbols, address))
+            }
+            Rv32iInstruction::Lui(u) | Rv32iInstruction::Auipc(u) => {
+                format!("{mnemonic} {}, {:#x}", u.rd, u.imm as u32 & 0xf_ffff)
+            }
+
+            Rv32iInstruction::Ecall
+            | Rv32iInstruction::Ebreak
+            | Rv32iInstruction::Mret
+            | Rv32iInstruction::Sret
+            | Rv32iInstruction::Wfi => mnemonic.to_string(),
+            Rv32iInstruction::Csrrw(i)
+            | Rv32iInstruction::Csrrs(i)
+            | Rv32iInstruction::Csrrc(i) => csr(mnemonic, i, false),
+            Rv32iInstruction::Csrrwi(i)
+            | Rv32iInstruction::Csrrsi(i)
+            | Rv32iInstruction::Csrrci(i) => csr(mnemonic, i, true),
+
+            Rv32iInstruction::Fence(i) => fence(i.imm as u32 & 0xfff),
+            Rv32iInstruction::FenceI => mnemonic.to_string(),
+            Rv32iInstruction::SfenceVma(r) => format!("{mnemonic} {}, {}", r.rs1, r.rs2),
+            Rv32iInstruction::Vector(vector) => vector.to_string(),
+            Rv32iInstruction::Custom(word) => format!("{mnemonic} 4, {word:#010x}"),
+
+            Rv32iInstruction::LrW(r) => format!("{mnemonic} {}, ({})", r.rd, r.rs1),
+            Rv32iInstruction::ScW(r)
+            | Rv32iInstruction::AmoswapW(r)
+            | Rv32iInstruction::AmoaddW(r)
+            | Rv32iInstruction::AmoxorW(r)
+            | Rv32iInstruction::AmoandW(r)
+            | Rv32iInstruction::AmoorW(r)
+            | Rv32iInstruction::AmominW(r)
+            | Rv32iInstruction::AmomaxW(r)
+            | Rv32iInstruction::AmominuW(r)
+            | Rv32iInstruction::AmomaxuW(r) => {
+                format!("{mnemonic} {}, {}, ({})", r.rd, r.rs2, r.rs1)
+            }
+        }
+    }
+}
+
+/// the assembly without symbols
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.disassemble(&[]))
+    }
+}
+
+impl Vm {
+    /// the symbol of the loaded ELF file `address` is in and how far into it
+    pub fn symbol_at(&self, address: u32) -> Option<(&Symbol, u32)> {
+        symbol_at(&self.symbols, address)
+    }
+
+    /// the address of the symbol called `name`
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.symbol(name).map(|symbol| symbol.address)
+    }
+
+    /// disassembles `count` instructions from `address`, one line each like
+    /// `0x00001004: jal ra, <sum_2_number>`. A label line like
+    /// `<sum_2_number>:` goes before the first instruction of a symbol
+    pub fn disassemble(&self, address: u32, count: usize) -> Result<String, VmError> {
+        let mut lines = String::new();
+        for index in 0..count as u32 {
+            let pc = address.wrapping_add(index * 4);
+            if let Some((symbol, 0)) = self.symbol_at(pc) {
+                lines.push_str(&format!("<{}>:\n", symbol.name));
+            }
+            let text = match self.fetch_at(pc)? {
+                Instruction::Rv32iInstruction(_, Rv32iInstruction::Custom(word)) => self
+                    .disassemble_custom(word)
+                    .unwrap_or_else(|| format!(".insn 4, {word:#010x}")),
+                instruction => instruction.disassemble(&self.symbols),
+            };
+            lines.push_str(&format!("{pc:#010x}: {text}\n"));
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::test_elf;
+    use crate::Vm;
+
+    #[test]
+    fn should_name_jump_targets_after_symbols() {
+        // 0x1000 addi a0, zero, 1     <- _start
+        // 0x1004 jal ra, 0x1010
+        // 0x1008 bne a0, zero, 0x1014
+        // 0x100c ebreak
+        // 0x1010 add a0, a0, a1       <- sum_2_number
+        // 0x1014 ret
+        let code = [
+            0x0010_0513,
+            0x00c0_00ef,
+            0x0005_1663,
+            0x0010_0073,
+            0x00b5_0533,
+            0x0000_8067,
+        ];
+        let elf = test_elf::build(
+            0x1000,
+            &code,
+            0,
+            &[("_start", 0x1000), ("sum_2_number", 0x1010)],
+        );
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_elf(&elf).unwrap();
+
+        assert_eq!(vm.address_of("sum_2_number"), Some(0x1010));
+        assert_eq!(vm.address_of("missing"), None);
+        assert_eq!(vm.symbol_at(0x1010).unwrap().0.name, "sum_2_number");
+        // labels have no size, they only name their own address
+        assert!(vm.symbol_at(0x1014).is_none());
+
+        assert_eq!(
+            vm.disassemble(0x1000, 6).unwrap(),
+            "<_start>:\n\
+             0x00001000: addi a0, zero, 1\n\
+             0x00001004: jal ra, <sum_2_number>\n\
+             0x00001008: bne a0, zero, 0x1014\n\
+             0x0000100c: ebreak\n\
+             <sum_2_number>:\n\
+             0x00001010: add a0, a0, a1\n\
+             0x00001014: ret\n"
+        );
+        assert_eq!(vm.fetch_at(0x1004).unwrap().to_string(), "jal ra, 0x1010");
+    }
+}
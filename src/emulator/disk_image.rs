@@ -0,0 +1,779 @@
+//! Disk images for the guest, prepared on the host: create a raw image,
+//! resize it, format it as FAT or ext2 and put files into it or take them
+//! out, so no external tools (mkfs, mtools, debugfs) are needed. The CLI
+//! has them as `riscv-vm disk`, and `riscv-vm run --disk` hands the image
+//! to the guest as its virtio disk.
+//!
+//! FAT12/FAT16 with files in the root directory (8.3 names) is supported
+//! here, ext2 with files in the root directory in `ext2.rs`. That is enough
+//! to hand input files to a guest and read its results back.
+
+use core::fmt;
+
+use thiserror::Error;
+
+use super::ext2;
+use super::prelude::*;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const RESERVED_SECTORS: usize = 1;
+const FAT_COUNT: usize = 2;
+const ROOT_ENTRIES: usize = 512;
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const MEDIA_DESCRIPTOR: u8 = 0xf8;
+
+const ATTRIBUTE_VOLUME_LABEL: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0f;
+
+/// directory entries whose name starts with this byte are deleted
+const DELETED_ENTRY: u8 = 0xe5;
+
+/// FAT16 can not have more clusters than this
+const MAX_FAT16_CLUSTERS: usize = 65524;
+/// with fewer clusters than this the filesystem is FAT12
+const MIN_FAT16_CLUSTERS: usize = 4085;
+
+/// the filesystems an image can be formatted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// FAT12 or FAT16, whichever fits the size
+    Fat,
+    Ext2,
+}
+
+impl Filesystem {
+    /// `fat` or `ext2`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fat" => Some(Self::Fat),
+            "ext2" => Some(Self::Ext2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fat => "FAT12/FAT16",
+            Self::Ext2 => "ext2",
+        })
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DiskImageError {
+    #[error("the image size must be a multiple of {SECTOR_SIZE} bytes")]
+    NotSectorAligned,
+
+    #[error("{size} bytes is too small or too big for a {filesystem} filesystem")]
+    UnsupportedSize { size: usize, filesystem: Filesystem },
+
+    #[error("the image does not contain a FAT12/FAT16 or ext2 filesystem")]
+    UnknownFilesystem,
+
+    /// not 8.3 on FAT, empty, with a `/` or too long on ext2
+    #[error("{name:?} is not a valid file name")]
+    InvalidName { name: String },
+
+    #[error("file {name:?} not found")]
+    FileNotFound { name: String },
+
+    #[error("the root directory is full")]
+    RootDirectoryFull,
+
+    #[error("not enough free space for {size} bytes")]
+    DiskFull { size: usize },
+
+    #[error("there is no free inode for another file")]
+    NoFreeInode,
+}
+
+/// a file in the root directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// `NAME.EXT`
+    pub name: String,
+    pub size: u32,
+}
+
+/// A raw disk image, a whole number of 512 byte sectors. The bytes are what
+/// the guest sees on its block device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskImage {
+    data: Vec<u8>,
+}
+
+impl DiskImage {
+    /// an image of `size` bytes filled with zeros
+    pub fn new(size: usize) -> Result<Self, DiskImageError> {
+        if !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(DiskImageError::NotSectorAligned);
+        }
+        Ok(Self {
+            data: vec![0; size],
+        })
+    }
+
+    /// an image from a file the host read
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, DiskImageError> {
+        if !data.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(DiskImageError::NotSectorAligned);
+        }
+        Ok(Self { data })
+    }
+
+    /// a new image formatted as FAT, see `format_fat`
+    pub fn new_fat(size: usize, label: &str) -> Result<Self, DiskImageError> {
+        let mut image = Self::new(size)?;
+        image.format_fat(label)?;
+        Ok(image)
+    }
+
+    /// a new image formatted as ext2, see `format_ext2`
+    pub fn new_ext2(size: usize, label: &str) -> Result<Self, DiskImageError> {
+        let mut image = Self::new(size)?;
+        image.format_ext2(label)?;
+        Ok(image)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn sector_count(&self) -> usize {
+        self.data.len() / SECTOR_SIZE
+    }
+
+    /// grows the image with zeros or cuts it off. A filesystem in it keeps
+    /// its old size, growing only adds room for a new partition or format
+    pub fn resize(&mut self, size: usize) -> Result<(), DiskImageError> {
+        if !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(DiskImageError::NotSectorAligned);
+        }
+        self.data.resize(size, 0);
+        Ok(())
+    }
+
+    pub fn sector(&self, sector: usize) -> Option<&[u8]> {
+        self.data
+            .get(sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE)
+    }
+
+    pub fn sector_mut(&mut self, sector: usize) -> Option<&mut [u8]> {
+        self.data
+            .get_mut(sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE)
+    }
+
+    /// writes an empty `filesystem` over the whole image
+    pub fn format(&mut self, filesystem: Filesystem, label: &str) -> Result<(), DiskImageError> {
+        match filesystem {
+            Filesystem::Fat => self.format_fat(label),
+            Filesystem::Ext2 => self.format_ext2(label),
+        }
+    }
+
+    /// writes an empty ext2 filesystem with 4 KiB blocks over the whole
+    /// image, which has to be 64 KiB to 128 MiB. `label` is cut to 16 bytes
+    pub fn format_ext2(&mut self, label: &str) -> Result<(), DiskImageError> {
+        ext2::format(&mut self.data, label)
+    }
+
+    /// the filesystem in the image, `None` if it has none of the supported
+    /// ones
+    pub fn filesystem(&self) -> Option<Filesystem> {
+        if FatLayout::read(self).is_ok() {
+            Some(Filesystem::Fat)
+        } else if ext2::is_ext2(&self.data) {
+            Some(Filesystem::Ext2)
+        } else {
+            None
+        }
+    }
+
+    /// writes an empty FAT12 or FAT16 filesystem (whichever fits the size)
+    /// over the whole image, `label` is cut to 11 characters
+    pub fn format_fat(&mut self, label: &str) -> Result<(), DiskImageError> {
+        let layout = FatLayout::for_size(self.data.len())?;
+        self.data.fill(0);
+
+        let boot_sector = &mut self.data[..SECTOR_SIZE];
+        // jmp short + nop, then the OEM name
+        boot_sector[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        boot_sector[3..11].copy_from_slice(b"RVVM    ");
+        boot_sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot_sector[13] = layout.sectors_per_cluster as u8;
+        boot_sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot_sector[16] = FAT_COUNT as u8;
+        boot_sector[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+        match u16::try_from(layout.total_sectors) {
+            Ok(total_sectors) => boot_sector[19..21].copy_from_slice(&total_sectors.to_le_bytes()),
+            Err(_) => {
+                boot_sector[32..36].copy_from_slice(&(layout.total_sectors as u32).to_le_bytes())
+            }
+        }
+        boot_sector[21] = MEDIA_DESCRIPTOR;
+        boot_sector[22..24].copy_from_slice(&(layout.fat_sectors as u16).to_le_bytes());
+        // sectors per track and heads, only for old BIOSes
+        boot_sector[24..26].copy_from_slice(&32u16.to_le_bytes());
+        boot_sector[26..28].copy_from_slice(&64u16.to_le_bytes());
+        boot_sector[36] = 0x80;
+        boot_sector[38] = 0x29;
+        boot_sector[39..43].copy_from_slice(&0x5256_564du32.to_le_bytes());
+        boot_sector[43..54].copy_from_slice(&padded_label(label));
+        boot_sector[54..62].copy_from_slice(if layout.is_fat12 {
+            b"FAT12   "
+        } else {
+            b"FAT16   "
+        });
+        boot_sector[510] = 0x55;
+        boot_sector[511] = 0xaa;
+
+        let mut fat = Fat {
+            image: self,
+            layout,
+        };
+        fat.set_entry(0, 0xff00 | MEDIA_DESCRIPTOR as u16);
+        fat.set_entry(1, 0xffff);
+
+        if !label.is_empty() {
+            let offset = layout.root_directory_offset();
+            self.data[offset..offset + 11].copy_from_slice(&padded_label(label));
+            self.data[offset + 11] = ATTRIBUTE_VOLUME_LABEL;
+        }
+
+        Ok(())
+    }
+
+    /// the files in the root directory
+    pub fn list_files(&self) -> Result<Vec<FileEntry>, DiskImageError> {
+        if self.filesystem() == Some(Filesystem::Ext2) {
+            return ext2::list_files(&self.data);
+        }
+        let layout = FatLayout::read(self)?;
+        Ok((0..ROOT_ENTRIES)
+            .filter_map(|index| {
+                let entry = self.directory_entry(&layout, index);
+                is_file(entry).then(|| FileEntry {
+                    name: display_name(entry[..11].try_into().unwrap()),
+                    size: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+                })
+            })
+            .collect())
+    }
+
+    /// the contents of the file `name` in the root directory
+    pub fn extract_file(&self, name: &str) -> Result<Vec<u8>, DiskImageError> {
+        if self.filesystem() == Some(Filesystem::Ext2) {
+            return ext2::extract_file(&self.data, name);
+        }
+        let layout = FatLayout::read(self)?;
+        let short_name = short_name(name)?;
+        let index =
+            self.find_file(&layout, &short_name)
+                .ok_or_else(|| DiskImageError::FileNotFound {
+                    name: name.to_string(),
+                })?;
+
+        let entry = self.directory_entry(&layout, index);
+        let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+        let mut cluster = u16::from_le_bytes(entry[26..28].try_into().unwrap()) as usize;
+
+        let mut contents = Vec::with_capacity(size);
+        let cluster_size = layout.cluster_size();
+        // a broken chain can not loop forever, it can not be longer than
+        // the number of clusters
+        for _ in 0..layout.cluster_count {
+            if contents.len() >= size || !layout.is_data_cluster(cluster) {
+                break;
+            }
+            let offset = layout.cluster_offset(cluster);
+            contents.extend_from_slice(&self.data[offset..offset + cluster_size]);
+            cluster = layout.entry(&self.data, cluster);
+        }
+        contents.truncate(size);
+        Ok(contents)
+    }
+
+    /// writes `contents` as the file `name` into the root directory, a file
+    /// with the same name is replaced
+    pub fn inject_file(&mut self, name: &str, contents: &[u8]) -> Result<(), DiskImageError> {
+        if self.filesystem() == Some(Filesystem::Ext2) {
+            return ext2::inject_file(&mut self.data, name, contents);
+        }
+        let layout = FatLayout::read(self)?;
+        let short_name = short_name(name)?;
+
+        if self.find_file(&layout, &short_name).is_some() {
+            self.remove_file(name)?;
+        }
+
+        let index = (0..ROOT_ENTRIES)
+            .find(|index| {
+                let first = self.directory_entry(&layout, *index)[0];
+                first == 0 || first == DELETED_ENTRY
+            })
+            .ok_or(DiskImageError::RootDirectoryFull)?;
+
+        let cluster_size = layout.cluster_size();
+        let needed = contents.len().div_ceil(cluster_size);
+        let free: Vec<usize> = (2..layout.cluster_count + 2)
+            .filter(|cluster| layout.entry(&self.data, *cluster) == 0)
+            .take(needed)
+            .collect();
+        if free.len() < needed {
+            return Err(DiskImageError::DiskFull {
+                size: contents.len(),
+            });
+        }
+
+        for (position, (cluster, chunk)) in
+            free.iter().zip(contents.chunks(cluster_size)).enumerate()
+        {
+            let offset = layout.cluster_offset(*cluster);
+            self.data[offset..offset + chunk.len()].copy_from_slice(chunk);
+            self.data[offset + chunk.len()..offset + cluster_size].fill(0);
+
+            let next = free.get(position + 1).map_or(0xffff, |next| *next as u16);
+            Fat {
+                image: self,
+                layout,
+            }
+            .set_entry(*cluster, next);
+        }
+
+        let offset = layout.root_directory_offset() + index * DIRECTORY_ENTRY_SIZE;
+        let entry = &mut self.data[offset..offset + DIRECTORY_ENTRY_SIZE];
+        entry.fill(0);
+        entry[..11].copy_from_slice(&short_name);
+        entry[11] = ATTRIBUTE_ARCHIVE;
+        let first_cluster = free.first().map_or(0, |cluster| *cluster as u16);
+        entry[26..28].copy_from_slice(&first_cluster.to_le_bytes());
+        entry[28..32].copy_from_slice(&(contents.len() as u32).to_le_bytes());
+
+        Ok(())
+    }
+
+    /// deletes the file `name` from the root directory and frees its
+    /// clusters
+    pub fn remove_file(&mut self, name: &str) -> Result<(), DiskImageError> {
+        if self.filesystem() == Some(Filesystem::Ext2) {
+            return ext2::remove_file(&mut self.data, name);
+        }
+        let layout = FatLayout::read(self)?;
+        let index = self.find_file(&layout, &short_name(name)?).ok_or_else(|| {
+            DiskImageError::FileNotFound {
+                name: name.to_string(),
+            }
+        })?;
+
+        let entry = self.directory_entry(&layout, index);
+        let mut cluster = u16::from_le_bytes(entry[26..28].try_into().unwrap()) as usize;
+        for _ in 0..layout.cluster_count {
+            if !layout.is_data_cluster(cluster) {
+                break;
+            }
+            let next = layout.entry(&self.data, cluster);
+            Fat {
+                image: self,
+                layout,
+            }
+            .set_entry(cluster, 0);
+            cluster = next;
+        }
+
+        let offset = layout.root_directory_offset() + index * DIRECTORY_ENTRY_SIZE;
+        self.data[offset] = DELETED_ENTRY;
+        Ok(())
+    }
+
+    fn directory_entry(&self, layout: &FatLayout, index: usize) -> &[u8] {
+        let offset = layout.root_directory_offset() + index * DIRECTORY_ENTRY_SIZE;
+        &self.data[offset..offset + DIRECTORY_ENTRY_SIZE]
+    }
+
+    fn find_file(&self, layout: &FatLayout, short_name: &[u8; 11]) -> Option<usize> {
+        (0..ROOT_ENTRIES).find(|index| {
+            let entry = self.directory_entry(layout, *index);
+            is_file(entry) && entry[..11] == short_name[..]
+        })
+    }
+}
+
+/// where everything is in a FAT12/FAT16 filesystem
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FatLayout {
+    total_sectors: usize,
+    sectors_per_cluster: usize,
+    fat_sectors: usize,
+    root_entries: usize,
+    cluster_count: usize,
+    is_fat12: bool,
+}
+
+impl FatLayout {
+    /// the layout `format_fat` uses for an image of `size` bytes
+    fn for_size(size: usize) -> Result<Self, DiskImageError> {
+        let total_sectors = size / SECTOR_SIZE;
+        let root_sectors = ROOT_ENTRIES * DIRECTORY_ENTRY_SIZE / SECTOR_SIZE;
+        let unsupported = DiskImageError::UnsupportedSize {
+            size,
+            filesystem: Filesystem::Fat,
+        };
+
+        // the smallest clusters that keep the cluster count in FAT16 range
+        let mut sectors_per_cluster = 1;
+        while (total_sectors / sectors_per_cluster) > MAX_FAT16_CLUSTERS {
+            sectors_per_cluster *= 2;
+            if sectors_per_cluster > 64 {
+                return Err(unsupported);
+            }
+        }
+
+        // the FAT size depends on the cluster count and the other way round,
+        // a few rounds settle it
+        let mut fat_sectors = 1;
+        for _ in 0..4 {
+            let data_sectors = total_sectors
+                .checked_sub(RESERVED_SECTORS + root_sectors + FAT_COUNT * fat_sectors)
+                .ok_or(unsupported.clone())?;
+            let cluster_count = data_sectors / sectors_per_cluster;
+            let fat_bytes = if cluster_count < MIN_FAT16_CLUSTERS {
+                (cluster_count + 2) * 3 / 2 + 1
+            } else {
+                (cluster_count + 2) * 2
+            };
+            fat_sectors = fat_bytes.div_ceil(SECTOR_SIZE);
+        }
+
+        let layout = Self::new(
+            total_sectors,
+            sectors_per_cluster,
+            fat_sectors,
+            ROOT_ENTRIES,
+        )
+        .ok_or(unsupported.clone())?;
+        if layout.cluster_count < 16 {
+            return Err(unsupported);
+        }
+        Ok(layout)
+    }
+
+    /// the layout in the boot sector of `image`
+    fn read(image: &DiskImage) -> Result<Self, DiskImageError> {
+        let boot_sector = image.sector(0).ok_or(DiskImageError::UnknownFilesystem)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([boot_sector[offset], boot_sector[offset + 1]]);
+
+        if boot_sector[510..512] != [0x55, 0xaa]
+            || u16_at(11) as usize != SECTOR_SIZE
+            || u16_at(14) as usize != RESERVED_SECTORS
+            || boot_sector[16] as usize != FAT_COUNT
+        {
+            return Err(DiskImageError::UnknownFilesystem);
+        }
+
+        let total_sectors = match u16_at(19) {
+            0 => u32::from_le_bytes(boot_sector[32..36].try_into().unwrap()) as usize,
+            total_sectors => total_sectors as usize,
+        };
+        if total_sectors > image.sector_count() {
+            return Err(DiskImageError::UnknownFilesystem);
+        }
+
+        let layout = Self::new(
+            total_sectors,
+            boot_sector[13] as usize,
+            u16_at(22) as usize,
+            u16_at(17) as usize,
+        )
+        .ok_or(DiskImageError::UnknownFilesystem)?;
+        if layout.cluster_count > MAX_FAT16_CLUSTERS {
+            return Err(DiskImageError::UnknownFilesystem);
+        }
+        Ok(layout)
+    }
+
+    fn new(
+        total_sectors: usize,
+        sectors_per_cluster: usize,
+        fat_sectors: usize,
+        root_entries: usize,
+    ) -> Option<Self> {
+        if sectors_per_cluster == 0 || root_entries != ROOT_ENTRIES || fat_sectors == 0 {
+            return None;
+        }
+        let root_sectors = root_entries * DIRECTORY_ENTRY_SIZE / SECTOR_SIZE;
+        let data_sectors =
+            total_sectors.checked_sub(RESERVED_SECTORS + FAT_COUNT * fat_sectors + root_sectors)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        Some(Self {
+            total_sectors,
+            sectors_per_cluster,
+            fat_sectors,
+            root_entries,
+            cluster_count,
+            // the cluster count alone decides the FAT type
+            is_fat12: cluster_count < MIN_FAT16_CLUSTERS,
+        })
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn fat_offset(&self) -> usize {
+        RESERVED_SECTORS * SECTOR_SIZE
+    }
+
+    fn root_directory_offset(&self) -> usize {
+        self.fat_offset() + FAT_COUNT * self.fat_sectors * SECTOR_SIZE
+    }
+
+    fn cluster_offset(&self, cluster: usize) -> usize {
+        self.root_directory_offset()
+            + self.root_entries * DIRECTORY_ENTRY_SIZE
+            + (cluster - 2) * self.cluster_size()
+    }
+
+    /// clusters 0 and 1 are reserved, the end-of-chain markers are above the
+    /// last cluster
+    fn is_data_cluster(&self, cluster: usize) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// the FAT entry of `cluster` in the first FAT
+    fn entry(&self, data: &[u8], cluster: usize) -> usize {
+        let fat = &data[self.fat_offset()..];
+        if self.is_fat12 {
+            let offset = cluster + cluster / 2;
+            let pair = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+            let entry = if cluster.is_multiple_of(2) {
+                pair & 0x0fff
+            } else {
+                pair >> 4
+            };
+            // make the FAT12 end-of-chain markers look like the FAT16 ones
+            if entry >= 0x0ff8 {
+                0xffff
+            } else {
+                entry as usize
+            }
+        } else {
+            u16::from_le_bytes([fat[cluster * 2], fat[cluster * 2 + 1]]) as usize
+        }
+    }
+}
+
+/// writes FAT entries into every copy of the FAT
+struct Fat<'a> {
+    image: &'a mut DiskImage,
+    layout: FatLayout,
+}
+
+impl Fat<'_> {
+    /// `value` is a FAT16 value, FAT12 keeps its lower 12 bits
+    fn set_entry(&mut self, cluster: usize, value: u16) {
+        for copy in 0..FAT_COUNT {
+            let start = self.layout.fat_offset() + copy * self.layout.fat_sectors * SECTOR_SIZE;
+            let fat = &mut self.image.data[start..start + self.layout.fat_sectors * SECTOR_SIZE];
+
+            if self.layout.is_fat12 {
+                let offset = cluster + cluster / 2;
+                let value = value & 0x0fff;
+                if cluster.is_multiple_of(2) {
+                    fat[offset] = value as u8;
+                    fat[offset + 1] = (fat[offset + 1] & 0xf0) | (value >> 8) as u8;
+                } else {
+                    fat[offset] = (fat[offset] & 0x0f) | ((value << 4) as u8);
+                    fat[offset + 1] = (value >> 4) as u8;
+                }
+            } else {
+                fat[cluster * 2..cluster * 2 + 2].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// a directory entry of a file, not a label, directory, long name part,
+/// deleted or unused entry
+fn is_file(entry: &[u8]) -> bool {
+    let attributes = entry[11];
+    entry[0] != 0
+        && entry[0] != DELETED_ENTRY
+        && attributes != ATTRIBUTE_LONG_NAME
+        && attributes & (ATTRIBUTE_VOLUME_LABEL | ATTRIBUTE_DIRECTORY) == 0
+}
+
+/// `hello.txt` as `HELLO   TXT`
+fn short_name(name: &str) -> Result<[u8; 11], DiskImageError> {
+    let invalid = || DiskImageError::InvalidName {
+        name: name.to_string(),
+    };
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid_part = |part: &str, max: usize| {
+        part.len() <= max
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&byte))
+    };
+    if base.is_empty() || !valid_part(base, 8) || !valid_part(extension, 3) {
+        return Err(invalid());
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Ok(short_name)
+}
+
+/// `HELLO   TXT` as `HELLO.TXT`
+fn display_name(short_name: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&short_name[..8]);
+    let extension = String::from_utf8_lossy(&short_name[8..]);
+    match extension.trim_end() {
+        "" => base.trim_end().to_string(),
+        extension => format!("{}.{extension}", base.trim_end()),
+    }
+}
+
+fn padded_label(label: &str) -> [u8; 11] {
+    let mut padded = [b' '; 11];
+    for (byte, character) in padded.iter_mut().zip(label.bytes()) {
+        *byte = character.to_ascii_uppercase();
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskImage, DiskImageError, FileEntry, Filesystem};
+
+    #[test]
+    fn should_inject_and_extract_files() {
+        let image = DiskImage::new_fat(1024 * 1024, "guest").unwrap();
+        assert_eq!(&image.as_bytes()[54..62], b"FAT12   ");
+
+        // survives a round trip through the bytes the host saves
+        let mut image = DiskImage::from_bytes(image.into_bytes()).unwrap();
+        let big: Vec<u8> = (0..3000).map(|n| n as u8).collect();
+        image.inject_file("hello.txt", b"hello guest\n").unwrap();
+        image.inject_file("data.bin", &big).unwrap();
+        image.inject_file("hello.txt", b"hi\n").unwrap();
+
+        // the replaced file takes its old directory slot again
+        assert_eq!(
+            image.list_files().unwrap(),
+            vec![
+                FileEntry {
+                    name: "HELLO.TXT".to_string(),
+                    size: 3
+                },
+                FileEntry {
+                    name: "DATA.BIN".to_string(),
+                    size: 3000
+                },
+            ]
+        );
+        assert_eq!(image.extract_file("HELLO.TXT").unwrap(), b"hi\n");
+        assert_eq!(image.extract_file("data.bin").unwrap(), big);
+
+        image.remove_file("data.bin").unwrap();
+        assert_eq!(
+            image.extract_file("data.bin"),
+            Err(DiskImageError::FileNotFound {
+                name: "data.bin".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn should_pick_fat16_for_bigger_images_and_report_a_full_disk() {
+        let mut image = DiskImage::new_fat(4 * 1024 * 1024, "").unwrap();
+        assert_eq!(&image.as_bytes()[54..62], b"FAT16   ");
+
+        let file = vec![0xab; 1024 * 1024];
+        image.inject_file("a.bin", &file).unwrap();
+        image.inject_file("b.bin", &file).unwrap();
+        image.inject_file("c.bin", &file).unwrap();
+        assert_eq!(
+            image.inject_file("d.bin", &file),
+            Err(DiskImageError::DiskFull { size: 1024 * 1024 })
+        );
+        assert_eq!(image.extract_file("c.bin").unwrap(), file);
+
+        assert_eq!(
+            image.inject_file("too_long_name.txt", b""),
+            Err(DiskImageError::InvalidName {
+                name: "too_long_name.txt".to_string()
+            })
+        );
+        assert_eq!(DiskImage::new(1000), Err(DiskImageError::NotSectorAligned));
+    }
+
+    #[test]
+    fn should_inject_and_extract_files_on_ext2() {
+        let image = DiskImage::new_ext2(1024 * 1024, "guest").unwrap();
+        let mut image = DiskImage::from_bytes(image.into_bytes()).unwrap();
+        assert_eq!(image.filesystem(), Some(Filesystem::Ext2));
+        assert!(image.list_files().unwrap().is_empty());
+
+        // more than the 12 direct blocks, the rest go through an indirect one
+        let big: Vec<u8> = (0..100_000).map(|n| (n % 251) as u8).collect();
+        image.inject_file("hello.txt", b"hello guest\n").unwrap();
+        image.inject_file("Data.bin", &big).unwrap();
+        image.inject_file("hello.txt", b"hi\n").unwrap();
+        // the replaced file goes into the room its old entry left
+        assert_eq!(
+            image.list_files().unwrap(),
+            vec![
+                FileEntry {
+                    name: "hello.txt".to_string(),
+                    size: 3
+                },
+                FileEntry {
+                    name: "Data.bin".to_string(),
+                    size: 100_000
+                },
+            ]
+        );
+        assert_eq!(image.extract_file("hello.txt").unwrap(), b"hi\n");
+        assert_eq!(image.extract_file("Data.bin").unwrap(), big);
+        // names are case sensitive, lost+found is not a file
+        for name in ["data.bin", "lost+found"] {
+            assert_eq!(
+                image.extract_file(name),
+                Err(DiskImageError::FileNotFound {
+                    name: name.to_string()
+                })
+            );
+        }
+
+        // its blocks are free again for a file as big
+        image.remove_file("Data.bin").unwrap();
+        image.inject_file("again.bin", &big).unwrap();
+        assert_eq!(
+            image.inject_file("a/b", b""),
+            Err(DiskImageError::InvalidName {
+                name: "a/b".to_string()
+            })
+        );
+        let mut small = DiskImage::new_ext2(64 * 1024, "").unwrap();
+        assert_eq!(
+            small.inject_file("big.bin", &big),
+            Err(DiskImageError::DiskFull { size: 100_000 })
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Flat dispatch for the RV32I instructions that only need the vm state. A
+//! decoded instruction becomes a compact opcode id and its operands in one
+//! shape, and the id indexes a table of handlers. That is a single indirect
+//! call per instruction instead of the nested match over the extensions and
+//! their variants.
+//!
+//! Loads, stores, ecalls and the CSR instructions need more than the vm
+//! state, the vm executes those itself.
+
+use super::emulator::VmState;
+use super::instruction_signatures::{
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+    Source1Source2Immediate,
+};
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// the compact id of an instruction, its index in `HANDLERS`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Add,
+    Sub,
+    Xor,
+    Or,
+    And,
+    Sll,
+    Srl,
+    Sra,
+    Slt,
+    Sltu,
+    Addi,
+    Xori,
+    Ori,
+    Andi,
+    Slli,
+    Srli,
+    Srai,
+    Slti,
+    Sltiu,
+    Lui,
+    Auipc,
+    Beq,
+    Bne,
+    Blt,
+    Bge,
+    Bltu,
+    Bgeu,
+    Jal,
+    Jalr,
+}
+
+/// the operands of every instruction in one shape, the unused ones are zero
+#[derive(Debug, Clone, Copy)]
+pub struct Operands {
+    pub rd: Register,
+    pub rs1: Register,
+    pub rs2: Register,
+    pub imm: i32,
+}
+
+impl Operands {
+    fn r(&self) -> DestinationSource1Source2 {
+        DestinationSource1Source2 {
+            rd: self.rd,
+            rs1: self.rs1,
+            rs2: self.rs2,
+        }
+    }
+
+    fn i(&self) -> DestinationSource1Immediate {
+        DestinationSource1Immediate {
+            rd: self.rd,
+            rs1: self.rs1,
+            imm: self.imm as i16,
+        }
+    }
+
+    fn b(&self) -> Source1Source2Immediate {
+        Source1Source2Immediate {
+            rs1: self.rs1,
+            rs2: self.rs2,
+            imm: self.imm as i16,
+        }
+    }
+
+    fn u(&self) -> DestinationImmediate {
+        DestinationImmediate {
+            rd: self.rd,
+            imm: self.imm,
+        }
+    }
+}
+
+/// executes one instruction, returns whether it moved the pc itself
+type Handler = fn(&Operands, &mut VmState) -> bool;
+
+/// the handlers in `Opcode` order
+static HANDLERS: [Handler; 29] = [
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_add(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_sub(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_xor(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_or(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_and(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_sll(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_srl(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_sra(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_slt(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_sltu(&o.r(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_addi(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_xori(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_ori(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_andi(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_slli(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_srli(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_srai(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_slti(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_sltiu(&o.i(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_lui(&o.u(), s);
+        false
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_auipc(&o.u(), s);
+        false
+    },
+    |o, s| Rv32iInstruction::rv32i_instruction_beq(&o.b(), s),
+    |o, s| Rv32iInstruction::rv32i_instruction_bne(&o.b(), s),
+    |o, s| Rv32iInstruction::rv32i_instruction_blt(&o.b(), s),
+    |o, s| Rv32iInstruction::rv32i_instruction_bge(&o.b(), s),
+    |o, s| Rv32iInstruction::rv32i_instruction_bltu(&o.b(), s),
+    |o, s| Rv32iInstruction::rv32i_instruction_bgeu(&o.b(), s),
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_jal(&o.u(), s);
+        true
+    },
+    |o, s| {
+        Rv32iInstruction::rv32i_instruction_jalr(&o.i(), s);
+        true
+    },
+];
+
+/// an instruction ready for the handler table
+#[derive(Debug, Clone, Copy)]
+pub struct Dispatch {
+    pub opcode: Opcode,
+    pub operands: Operands,
+}
+
+impl Dispatch {
+    /// the opcode id and operands of `instruction`, `None` for the
+    /// instructions the vm executes itself
+    pub fn of(instruction: &Rv32iInstruction) -> Option<Self> {
+        let r = |opcode, r: &DestinationSource1Source2| Self {
+            opcode,
+            operands: Operands {
+                rd: r.rd,
+                rs1: r.rs1,
+                rs2: r.rs2,
+                imm: 0,
+            },
+        };
+        let i = |opcode, i: &DestinationSource1Immediate| Self {
+            opcode,
+            operands: Operands {
+                rd: i.rd,
+                rs1: i.rs1,
+                rs2: Register::ZERO,
+                imm: i.imm as i32,
+            },
+        };
+        let b = |opcode, b: &Source1Source2Immediate| Self {
+            opcode,
+            operands: Operands {
+                rd: Register::ZERO,
+                rs1: b.rs1,
+                rs2: b.rs2,
+                imm: b.imm as i32,
+            },
+        };
+        let u = |opcode, u: &DestinationImmediate| Self {
+            opcode,
+            operands: Operands {
+                rd: u.rd,
+                rs1: Register::ZERO,
+                rs2: Register::ZERO,
+                imm: u.imm,
+            },
+        };
+
+        Some(match instruction {
+            Rv32iInstruction::Add(x) => r(Opcode::Add, x),
+            Rv32iInstruction::Sub(x) => r(Opcode::Sub, x),
+            Rv32iInstruction::Xor(x) => r(Opcode::Xor, x),
+            Rv32iInstruction::Or(x) => r(Opcode::Or, x),
+            Rv32iInstruction::And(x) => r(Opcode::And, x),
+            Rv32iInstruction::Sll(x) => r(Opcode::Sll, x),
+            Rv32iInstruction::Srl(x) => r(Opcode::Srl, x),
+            Rv32iInstruction::Sra(x) => r(Opcode::Sra, x),
+            Rv32iInstruction::Slt(x) => r(Opcode::Slt, x),
+            Rv32iInstruction::Sltu(x) => r(Opcode::Sltu, x),
+            Rv32iInstruction::Addi(x) => i(Opcode::Addi, x),
+            Rv32iInstruction::Xori(x) => i(Opcode::Xori, x),
+            Rv32iInstruction::Ori(x) => i(Opcode::Ori, x),
+            Rv32iInstruction::Andi(x) => i(Opcode::Andi, x),
+            Rv32iInstruction::Slli(x) => i(Opcode::Slli, x),
+            Rv32iInstruction::Srli(x) => i(Opcode::Srli, x),
+            Rv32iInstruction::Srai(x) => i(Opcode::Srai, x),
+            Rv32iInstruction::Slti(x) => i(Opcode::Slti, x),
+            Rv32iInstruction::Sltiu(x) => i(Opcode::Sltiu, x),
+            Rv32iInstruction::Lui(x) => u(Opcode::Lui, x),
+            Rv32iInstruction::Auipc(x) => u(Opcode::Auipc, x),
+            Rv32iInstruction::Beq(x) => b(Opcode::Beq, x),
+            Rv32iInstruction::Bne(x) => b(Opcode::Bne, x),
+            Rv32iInstruction::Blt(x) => b(Opcode::Blt, x),
+            Rv32iInstruction::Bge(x) => b(Opcode::Bge, x),
+            Rv32iInstruction::Bltu(x) => b(Opcode::Bltu, x),
+            Rv32iInstruction::Bgeu(x) => b(Opcode::Bgeu, x),
+            Rv32iInstruction::Jal(x) => u(Opcode::Jal, x),
+            Rv32iInstruction::Jalr(x) => i(Opcode::Jalr, x),
+            _ => return None,
+        })
+    }
+
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self.opcode,
+            Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge | Opcode::Bltu | Opcode::Bgeu
+        )
+    }
+
+    /// executes the instruction and moves the pc past it unless it jumped
+    #[inline]
+    pub fn execute(&self, vm_state: &mut VmState) {
+        if !HANDLERS[self.opcode as usize](&self.operands, vm_state) {
+            vm_state.pc = vm_state.pc.wrapping_add(4);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dispatch, Opcode, HANDLERS};
+    use crate::{Instruction, Vm};
+
+    #[test]
+    fn should_have_a_handler_per_opcode() {
+        assert_eq!(HANDLERS.len(), Opcode::Jalr as usize + 1);
+    }
+
+    #[test]
+    fn should_execute_through_the_table() {
+        // 0x1000 addi a0, zero, 5
+        // 0x1004 beq a0, a0, 8
+        // 0x1008 jal ra, 0x1008
+        let program: Vec<u8> = [0x0050_0513u32, 0x00a5_0463, 0x0000_00ef]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        let dispatch = |address| {
+            let Ok(Instruction::Rv32iInstruction(_, instruction)) = vm.fetch_at(address) else {
+                panic!("not an rv32i instruction");
+            };
+            Dispatch::of(&instruction).unwrap()
+        };
+        let (addi, beq, jal) = (dispatch(0x1000), dispatch(0x1004), dispatch(0x1008));
+        assert_eq!(addi.opcode, Opcode::Addi);
+
+        addi.execute(&mut vm.vm_state);
+        assert_eq!((vm.vm_state.registers[10], vm.vm_state.pc), (5, 0x1004));
+        beq.execute(&mut vm.vm_state);
+        assert_eq!(vm.vm_state.pc, 0x100c);
+        vm.vm_state.pc = 0x1008;
+        jal.execute(&mut vm.vm_state);
+        assert_eq!((vm.vm_state.registers[1], vm.vm_state.pc), (0x100c, 0x1008));
+    }
+
+    #[test]
+    fn should_wrap_the_pc_past_0x7fff_fffc() {
+        // 0x7fff_fffc addi a0, a0, 1
+        let mut vm = Vm::new(0x7fff_f000, 0x1000);
+        vm.load_program(0x7fff_fffc, &0x0015_0513u32.to_le_bytes())
+            .unwrap();
+        vm.vm_state.pc = 0x7fff_fffc;
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.registers[10], 1);
+        assert_eq!(vm.vm_state.pc as u32, 0x8000_0000);
+    }
+}
@@ -0,0 +1,396 @@
+//! A flattened device tree (DTB) of the vm, so kernels find the memory, the
+//! harts and the devices like on a real board. The bootloader convention is
+//! that a0 holds the hart id and a1 the address of the tree when the kernel
+//! starts, `Vm::load_device_tree` sets both.
+//!
+//! The layout follows QEMU's `virt` machine: the CLINT at 0x0200_0000, the
+//! PLIC at 0x0c00_0000, the UART at 0x1000_0000 (see `uart.rs`) and the
+//! virtio devices from 0x1000_1000 (see `virtio.rs`).
+
+use super::emulator::Vm;
+use super::error::VmError;
+use super::prelude::*;
+use super::register::Register;
+use super::uart::{UART_BASE, UART_IRQ, UART_SIZE};
+use super::virtio::VIRTIO_IRQ;
+
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x0001_0000;
+pub const PLIC_BASE: u32 = 0x0c00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+
+/// the clock of the 16550 on `virt`
+const UART_CLOCK: u32 = 3_686_400;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+/// the header is 10 words
+const HEADER_SIZE: usize = 40;
+
+/// what the tree describes. The devices are (base, size), `None` leaves the
+/// device out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceTree {
+    pub memory_base: u32,
+    pub memory_size: u32,
+    pub harts: u32,
+    /// the `riscv,isa` string of every hart
+    pub isa: String,
+    /// how many ticks of `time` are a second
+    pub timebase_frequency: u32,
+    /// the kernel command line
+    pub bootargs: Option<String>,
+    /// where the initial ramdisk starts and ends
+    pub initrd: Option<(u32, u32)>,
+    pub uart: Option<(u32, u32)>,
+    pub clint: Option<(u32, u32)>,
+    pub plic: Option<(u32, u32)>,
+    /// the virtio MMIO transports, see `virtio.rs`
+    pub virtio: Vec<(u32, u32)>,
+}
+
+impl DeviceTree {
+    /// one hart with the UART, CLINT and PLIC where `virt` has them
+    pub fn virt(memory_base: u32, memory_size: u32) -> Self {
+        Self {
+            memory_base,
+            memory_size,
+            harts: 1,
+            isa: "rv32ia".to_string(),
+            timebase_frequency: 10_000_000,
+            bootargs: None,
+            initrd: None,
+            uart: Some((UART_BASE, UART_SIZE)),
+            clint: Some((CLINT_BASE, CLINT_SIZE)),
+            plic: Some((PLIC_BASE, PLIC_SIZE)),
+            virtio: Vec::new(),
+        }
+    }
+
+    /// the tree in the flattened format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // the phandles: the interrupt controller of each hart, then the PLIC
+        let intc = |hart: u32| hart + 1;
+        let plic_phandle = self.harts + 1;
+
+        let mut fdt = Fdt::default();
+        fdt.begin_node("");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[1]);
+        fdt.string("compatible", "riscv-virtio");
+        fdt.string("model", "web-riscv-vm");
+
+        fdt.begin_node("chosen");
+        if let Some(bootargs) = &self.bootargs {
+            fdt.string("bootargs", bootargs);
+        }
+        if let Some((start, end)) = self.initrd {
+            fdt.cells("linux,initrd-start", &[start]);
+            fdt.cells("linux,initrd-end", &[end]);
+        }
+        if let Some((base, _)) = self.uart {
+            fdt.string("stdout-path", &format!("/soc/serial@{base:x}"));
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", self.memory_base));
+        fdt.string("device_type", "memory");
+        fdt.cells("reg", &[self.memory_base, self.memory_size]);
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[0]);
+        fdt.cells("timebase-frequency", &[self.timebase_frequency]);
+        for hart in 0..self.harts {
+            fdt.begin_node(&format!("cpu@{hart}"));
+            fdt.string("device_type", "cpu");
+            fdt.cells("reg", &[hart]);
+            fdt.string("status", "okay");
+            fdt.string("compatible", "riscv");
+            fdt.string("riscv,isa", &self.isa);
+            fdt.begin_node("interrupt-controller");
+            fdt.cells("#interrupt-cells", &[1]);
+            fdt.empty("interrupt-controller");
+            fdt.string("compatible", "riscv,cpu-intc");
+            fdt.cells("phandle", &[intc(hart)]);
+            fdt.end_node();
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[1]);
+        fdt.string("compatible", "simple-bus");
+        fdt.empty("ranges");
+        if let Some((base, size)) = self.clint {
+            // the software and timer interrupts of every hart
+            let interrupts: Vec<u32> = (0..self.harts)
+                .flat_map(|hart| [intc(hart), 3, intc(hart), 7])
+                .collect();
+            fdt.begin_node(&format!("clint@{base:x}"));
+            fdt.string("compatible", "riscv,clint0");
+            fdt.cells("reg", &[base, size]);
+            fdt.cells("interrupts-extended", &interrupts);
+            fdt.end_node();
+        }
+        if let Some((base, size)) = self.plic {
+            // the machine and supervisor external interrupts of every hart
+            let interrupts: Vec<u32> = (0..self.harts)
+                .flat_map(|hart| [intc(hart), 11, intc(hart), 9])
+                .collect();
+            fdt.begin_node(&format!("plic@{base:x}"));
+            fdt.string("compatible", "riscv,plic0");
+            fdt.cells("reg", &[base, size]);
+            fdt.cells("#interrupt-cells", &[1]);
+            fdt.empty("interrupt-controller");
+            fdt.cells("riscv,ndev", &[31]);
+            fdt.cells("interrupts-extended", &interrupts);
+            fdt.cells("phandle", &[plic_phandle]);
+            fdt.end_node();
+        }
+        if let Some((base, size)) = self.uart {
+            fdt.begin_node(&format!("serial@{base:x}"));
+            fdt.string("compatible", "ns16550a");
+            fdt.cells("reg", &[base, size]);
+            fdt.cells("clock-frequency", &[UART_CLOCK]);
+            if self.plic.is_some() {
+                fdt.cells("interrupts", &[UART_IRQ]);
+                fdt.cells("interrupt-parent", &[plic_phandle]);
+            }
+            fdt.end_node();
+        }
+        for (index, (base, size)) in self.virtio.iter().enumerate() {
+            fdt.begin_node(&format!("virtio_mmio@{base:x}"));
+            fdt.string("compatible", "virtio,mmio");
+            fdt.cells("reg", &[*base, *size]);
+            if self.plic.is_some() {
+                fdt.cells("interrupts", &[VIRTIO_IRQ + index as u32]);
+                fdt.cells("interrupt-parent", &[plic_phandle]);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+}
+
+/// writes the structure and strings blocks
+#[derive(Default)]
+struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// the offset of each property name in `strings`
+    names: HashMap<String, u32>,
+}
+
+impl Fdt {
+    fn word(&mut self, word: u32) {
+        self.structure.extend_from_slice(&word.to_be_bytes());
+    }
+
+    /// pads the structure block to a word boundary
+    fn align(&mut self) {
+        let padded = self.structure.len().next_multiple_of(4);
+        self.structure.resize(padded, 0);
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.word(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.word(FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let offset = *self.names.entry(name.to_string()).or_insert_with(|| {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        });
+        self.word(FDT_PROP);
+        self.word(value.len() as u32);
+        self.word(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    fn cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    fn string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    fn empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    /// the header, an empty memory reservation map, the structure and the
+    /// strings
+    fn finish(mut self) -> Vec<u8> {
+        self.word(FDT_END);
+        // the reservation map is a single (0, 0) entry
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&self.structure);
+        bytes.extend_from_slice(&self.strings);
+        bytes
+    }
+}
+
+impl Vm {
+    /// puts `tree` at the end of the memory, 8 byte aligned, and points a1
+    /// at it with the hart id in a0. Returns where it is
+    pub fn load_device_tree(&mut self, tree: &DeviceTree) -> Result<u32, VmError> {
+        let bytes = tree.to_bytes();
+        let end = self.memory.base() as u64 + self.memory.size() as u64;
+        let address = end
+            .checked_sub(bytes.len() as u64)
+            .filter(|address| *address >= self.memory.base() as u64)
+            .ok_or(VmError::MemoryOutOfBounds {
+                pc: 0,
+                instruction: 0,
+                address: self.memory.base(),
+            })? as u32
+            & !7;
+        self.load_device_tree_at(address, tree)?;
+        Ok(address)
+    }
+
+    /// `load_device_tree` at `address`
+    pub fn load_device_tree_at(&mut self, address: u32, tree: &DeviceTree) -> Result<(), VmError> {
+        self.load_program(address, &tree.to_bytes())?;
+        self.vm_state
+            .registers
+            .write(Register::A0, self.csrs.mhartid as i32);
+        self.vm_state.registers.write(Register::A1, address as i32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceTree, FDT_MAGIC};
+    use crate::{Register, Vm};
+
+    fn word(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// the value of property `name` of the node at `path`, walking the
+    /// structure block
+    fn property<'a>(tree: &'a [u8], path: &str, name: &str) -> Option<&'a [u8]> {
+        let (structure, strings) = (word(tree, 8) as usize, word(tree, 12) as usize);
+        let mut nodes: Vec<String> = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = word(tree, offset);
+            offset += 4;
+            match token {
+                1 => {
+                    let length = tree[offset..].iter().position(|&byte| byte == 0).unwrap();
+                    nodes.push(String::from_utf8_lossy(&tree[offset..offset + length]).into());
+                    offset = (offset + length + 1).next_multiple_of(4);
+                }
+                2 => {
+                    nodes.pop();
+                }
+                3 => {
+                    let (length, name_offset) = (word(tree, offset), word(tree, offset + 4));
+                    let value = &tree[offset + 8..offset + 8 + length as usize];
+                    let name_start = &tree[strings + name_offset as usize..];
+                    let name_length = name_start.iter().position(|&byte| byte == 0).unwrap();
+                    if nodes.join("/") == path && &name_start[..name_length] == name.as_bytes() {
+                        return Some(value);
+                    }
+                    offset = (offset + 8 + length as usize).next_multiple_of(4);
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn should_describe_the_memory_and_the_devices() {
+        let mut tree = DeviceTree::virt(0x8000_0000, 0x0800_0000);
+        tree.harts = 2;
+        tree.bootargs = Some("console=ttyS0".to_string());
+        tree.initrd = Some((0x8400_0000, 0x8400_1000));
+        tree.virtio.push((0x1000_1000, 0x1000));
+        let bytes = tree.to_bytes();
+
+        assert_eq!(word(&bytes, 0), FDT_MAGIC);
+        assert_eq!(word(&bytes, 4) as usize, bytes.len());
+        assert_eq!(
+            property(&bytes, "/memory@80000000", "reg"),
+            Some(&[0x80, 0, 0, 0, 0x08, 0, 0, 0][..])
+        );
+        assert_eq!(
+            property(&bytes, "/soc/serial@10000000", "compatible"),
+            Some(&b"ns16550a\0"[..])
+        );
+        assert_eq!(
+            property(&bytes, "/chosen", "bootargs"),
+            Some(&b"console=ttyS0\0"[..])
+        );
+        assert_eq!(
+            property(&bytes, "/chosen", "linux,initrd-end"),
+            Some(&[0x84, 0, 0x10, 0][..])
+        );
+        assert!(property(&bytes, "/cpus/cpu@1/interrupt-controller", "phandle").is_some());
+        assert!(property(&bytes, "/soc/plic@c000000", "interrupt-controller").is_some());
+        assert_eq!(
+            property(&bytes, "/soc/virtio_mmio@10001000", "interrupts"),
+            Some(&[0, 0, 0, 1][..])
+        );
+    }
+
+    #[test]
+    fn should_point_a1_at_the_tree() {
+        let mut vm = Vm::new(0x1000, 0x1000);
+        let tree = DeviceTree::virt(0x1000, 0x1000);
+        let address = vm.load_device_tree(&tree).unwrap();
+
+        assert_eq!(address % 8, 0);
+        assert!(address as usize + tree.to_bytes().len() <= 0x2000);
+        assert_eq!(vm.vm_state.registers[Register::A1], address as i32);
+        assert_eq!(vm.vm_state.registers[Register::A0], 0);
+        assert_eq!(vm.memory.read_u32(address).unwrap(), FDT_MAGIC.swap_bytes());
+    }
+}
@@ -0,0 +1,223 @@
+//! Who handles an `ecall`: the host (hypercalls, host handlers registered
+//! per ecall number) or the guest's own trap handler at `mtvec`. Guests that
+//! implement part of their runtime themselves can take some ecall numbers
+//! and leave the rest to the host.
+//!
+//! The ecalls of user mode always go to the guest: the host plays the
+//! firmware below a kernel, and a program the kernel runs in user mode
+//! calls the kernel. The cause says the mode, 8 for user mode, 9 for
+//! supervisor mode and 11 for machine mode.
+
+use super::csr::{Privilege, CAUSE_ECALL_FROM_U};
+use super::emulator::{Vm, VmState};
+use super::error::VmError;
+use super::memory::Memory;
+use super::prelude::*;
+use super::register::Register;
+use super::snapshot::{HYPERCALL_CHECKPOINT, HYPERCALL_RESTORE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcallAction {
+    /// the vm handles it: a hypercall or a registered handler, any other
+    /// number is not implemented
+    Host,
+    /// trap into the guest's handler at `mtvec`
+    Guest,
+}
+
+/// a host handler for one ecall number. It gets the arguments in the
+/// registers and writes the results there, the vm moves past the ecall
+pub type EcallHandler = Box<dyn FnMut(&mut VmState, &mut Memory) -> Result<(), VmError>>;
+
+/// the action per ecall number (in a7), and the action for the numbers
+/// without one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcallPolicy {
+    pub default: EcallAction,
+    actions: HashMap<u32, EcallAction>,
+}
+
+impl Default for EcallPolicy {
+    fn default() -> Self {
+        Self::user()
+    }
+}
+
+impl EcallPolicy {
+    /// user programs: the host handles every ecall, like a proxy kernel
+    pub fn user() -> Self {
+        Self {
+            default: EcallAction::Host,
+            actions: HashMap::new(),
+        }
+    }
+
+    /// bare metal programs: every ecall traps into the guest, only the
+    /// snapshot hypercalls still go to the host
+    pub fn bare_metal() -> Self {
+        Self {
+            default: EcallAction::Guest,
+            actions: HashMap::new(),
+        }
+        .with(HYPERCALL_CHECKPOINT, EcallAction::Host)
+        .with(HYPERCALL_RESTORE, EcallAction::Host)
+    }
+
+    pub fn with(mut self, number: u32, action: EcallAction) -> Self {
+        self.actions.insert(number, action);
+        self
+    }
+
+    pub fn action(&self, number: u32) -> EcallAction {
+        self.actions.get(&number).copied().unwrap_or(self.default)
+    }
+}
+
+impl Vm {
+    pub fn with_ecall_policy(mut self, policy: EcallPolicy) -> Self {
+        self.ecall_policy = policy;
+        self
+    }
+
+    /// handles ecall `number` on the host from now on, the policy still
+    /// decides whether the host gets it
+    pub fn add_ecall_handler(&mut self, number: u32, handler: EcallHandler) {
+        self.ecall_handlers.insert(number, handler);
+    }
+
+    /// executes the ecall at `pc`, `word` is the raw instruction for the
+    /// errors. Only the ecalls the host handles are traced, the snapshot
+    /// hypercalls are not
+    pub(super) fn ecall(&mut self, pc: u32, word: u32) -> Result<(), VmError> {
+        let number = self.vm_state.registers[Register::A7] as u32;
+
+        let privilege = self.csrs.privilege;
+        if privilege == Privilege::User || self.ecall_policy.action(number) == EcallAction::Guest {
+            self.trap(CAUSE_ECALL_FROM_U + privilege as u32, 0);
+            self.stats.record_trap("ecall");
+            return Ok(());
+        }
+
+        if self.snapshot_hypercall(number) {
+            self.stats.record_trap("hypercall");
+            return Ok(());
+        }
+
+        if self.sbi_call(number) {
+            self.stats.record_trap("sbi");
+            return Ok(());
+        }
+
+        let registers = self.strace.is_some().then_some(self.vm_state.registers);
+        let syscall = self.syscall(number);
+        let result = match self.ecall_handlers.get_mut(&number) {
+            _ if syscall => {
+                self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+                Ok(())
+            }
+            Some(handler) => {
+                // the handler may have written code
+                if let Some(cache) = &mut self.decode_cache {
+                    cache.clear();
+                }
+                if let Some(blocks) = &mut self.block_cache {
+                    blocks.clear();
+                }
+                #[cfg(feature = "jit")]
+                if let Some(jit) = &mut self.jit {
+                    jit.clear();
+                }
+                handler(&mut self.vm_state, &mut self.memory)
+                    .map(|()| self.vm_state.pc = self.vm_state.pc.wrapping_add(4))
+                    .map_err(|error| error.at(pc, word))
+            }
+            None => Err(VmError::NotImplemented {
+                pc,
+                instruction: word,
+            }),
+        };
+        if let (Some(strace), Some(registers)) = (&mut self.strace, registers) {
+            let a0 = self.vm_state.registers[Register::A0];
+            strace.record(
+                registers.as_array(),
+                result.as_ref().ok().map(|_| a0),
+                &self.memory,
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EcallAction, EcallPolicy};
+    use crate::{Register, StopReason, Vm, VmError};
+
+    /// 0x1000 auipc t0, 0
+    /// 0x1004 addi t0, t0, 0x20
+    /// 0x1008 csrrw zero, mtvec, t0
+    /// 0x100c addi a7, zero, `number`
+    /// 0x1010 ecall
+    /// 0x1014 ebreak
+    /// 0x1018 nop
+    /// 0x101c nop
+    /// 0x1020 addi a0, zero, 7       <- guest trap handler
+    /// 0x1024 ebreak
+    fn program(number: u32) -> Vec<u8> {
+        [
+            0x0000_0297u32,
+            0x0202_8293,
+            0x3052_9073,
+            (number << 20) | 0x0000_0893,
+            0x0000_0073,
+            0x0010_0073,
+            0x0000_0013,
+            0x0000_0013,
+            0x0070_0513,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect()
+    }
+
+    #[test]
+    fn should_send_each_ecall_number_where_the_policy_says() {
+        let policy = EcallPolicy::bare_metal().with(64, EcallAction::Host);
+
+        // 93 is not in the table, the guest handles it
+        let mut vm = Vm::new(0x1000, 0x100).with_ecall_policy(policy.clone());
+        vm.load_program(0x1000, &program(93)).unwrap();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.pc, 0x1024);
+        assert_eq!(vm.vm_state.registers[Register::A0], 7);
+        assert_eq!(vm.csrs.mepc, 0x1010);
+
+        // 64 goes to the host handler
+        let mut vm = Vm::new(0x1000, 0x100).with_ecall_policy(policy);
+        vm.load_program(0x1000, &program(64)).unwrap();
+        vm.add_ecall_handler(
+            64,
+            Box::new(|vm_state, _| {
+                vm_state.registers.write(Register::A0, 42);
+                Ok(())
+            }),
+        );
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.pc, 0x1014);
+        assert_eq!(vm.vm_state.registers[Register::A0], 42);
+    }
+
+    #[test]
+    fn host_ecalls_without_a_handler_should_not_be_implemented() {
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program(93)).unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(VmError::NotImplemented {
+                pc: 0x1010,
+                instruction: 0x0000_0073
+            })
+        );
+    }
+}
@@ -0,0 +1,596 @@
+//! A small ELF32 loader for RISC-V guests: it copies the `PT_LOAD` segments
+//! into memory, starts at the entry point and keeps the symbols so the host
+//! can find labels like `begin_signature`.
+//!
+//! Position independent executables (`ET_DYN`) are moved by a load bias,
+//! `DEFAULT_LOAD_BIAS` unless the host picks one: the segments, the entry
+//! point, the symbols and the line numbers all move with it, and the
+//! `R_RISCV_RELATIVE` relocations are applied to the segment data while
+//! parsing, so the loaded program is just like one linked for its address.
+
+use thiserror::Error;
+
+use super::debug_line::LineTable;
+use super::emulator::Vm;
+use super::error::VmError;
+use super::prelude::*;
+
+const EM_RISCV: u16 = 0xf3;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+/// the executable bit of a segment's flags
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+/// the section index of symbols that do not move with the load bias
+const SHN_ABS: u16 = 0xfff1;
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_RELATIVE: u32 = 3;
+
+/// where position independent executables go unless the host picks
+/// another address, above the null page
+pub const DEFAULT_LOAD_BIAS: u32 = 0x1_0000;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ElfError {
+    #[error("not a little endian ELF32 file")]
+    NotElf32,
+
+    #[error("the ELF file is for machine {machine:#x}, not RISC-V")]
+    WrongMachine { machine: u16 },
+
+    #[error("the ELF file is cut off or a header points outside of it")]
+    Truncated,
+
+    #[error("unsupported DWARF: {reason}")]
+    UnsupportedDwarf { reason: String },
+
+    #[error("symbol {name:?} not found")]
+    SymbolNotFound { name: String },
+
+    #[error("relocation type {kind} is not supported, only R_RISCV_RELATIVE")]
+    UnsupportedRelocation { kind: u32 },
+
+    #[error("the relocation at {address:#x} is outside of the segment data")]
+    BadRelocation { address: u32 },
+
+    #[error(transparent)]
+    Memory(#[from] VmError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    /// labels without a type, like `begin_signature` in assembly
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+/// a `PT_LOAD` segment, `memory_size - data.len()` bytes after the data are
+/// zeroed (.bss)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub memory_size: u32,
+    /// `p_flags`: execute 1, write 2, read 4
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// a named section with its bytes in the file, `.bss` like sections have
+/// none
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub entry: u32,
+    /// what was added to every address, 0 unless it is position independent
+    pub load_bias: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+    pub sections: Vec<Section>,
+}
+
+/// little endian reads that fail with `Truncated` instead of panicking
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, length: usize) -> Result<&[u8], ElfError> {
+        offset
+            .checked_add(length)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or(ElfError::Truncated)
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, ElfError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    /// the NUL terminated string at `offset`
+    fn string(&self, offset: usize) -> Result<String, ElfError> {
+        let bytes = self.0.get(offset..).ok_or(ElfError::Truncated)?;
+        let length = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(ElfError::Truncated)?;
+        Ok(String::from_utf8_lossy(&bytes[..length]).into_owned())
+    }
+}
+
+impl Elf {
+    /// a position independent executable is moved to `DEFAULT_LOAD_BIAS`
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        Self::parse_with_load_bias(bytes, DEFAULT_LOAD_BIAS)
+    }
+
+    /// `parse`, a position independent executable is moved by `load_bias`.
+    /// Other executables stay where they were linked
+    pub fn parse_with_load_bias(bytes: &[u8], load_bias: u32) -> Result<Self, ElfError> {
+        let elf = Reader(bytes);
+        if elf.bytes(0, 4)? != b"\x7fELF" || elf.u8(4)? != 1 || elf.u8(5)? != 1 {
+            return Err(ElfError::NotElf32);
+        }
+        let machine = elf.u16(18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::WrongMachine { machine });
+        }
+
+        let load_bias = match elf.u16(16)? {
+            ET_DYN => load_bias,
+            _ => 0,
+        };
+        let entry = elf.u32(24)?.wrapping_add(load_bias);
+        let program_headers = elf.u32(28)? as usize;
+        let section_headers = elf.u32(32)? as usize;
+        let program_header_size = elf.u16(42)? as usize;
+        let program_header_count = elf.u16(44)? as usize;
+        let section_header_size = elf.u16(46)? as usize;
+        let section_header_count = elf.u16(48)? as usize;
+        let section_names = elf.u16(50)? as usize;
+
+        let mut segments = Vec::new();
+        for index in 0..program_header_count {
+            let header = program_headers + index * program_header_size;
+            if elf.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let offset = elf.u32(header + 4)? as usize;
+            // the physical address, bare metal programs are linked for it
+            let address = elf.u32(header + 12)?.wrapping_add(load_bias);
+            let file_size = elf.u32(header + 16)? as usize;
+            let memory_size = elf.u32(header + 20)?;
+            segments.push(Segment {
+                address,
+                data: elf.bytes(offset, file_size)?.to_vec(),
+                memory_size,
+                flags: elf.u32(header + 24)?,
+            });
+        }
+
+        // without a section name table every section is unnamed
+        let names = if section_names != 0 && section_names < section_header_count {
+            Some(elf.u32(section_headers + section_names * section_header_size + 16)? as usize)
+        } else {
+            None
+        };
+
+        let mut symbols = Vec::new();
+        let mut sections = Vec::new();
+        let mut relocations = Vec::new();
+        for index in 0..section_header_count {
+            let header = section_headers + index * section_header_size;
+            let kind = elf.u32(header + 4)?;
+            if let (Some(names), true) = (names, index > 0) {
+                let data = if kind == SHT_NOBITS {
+                    Vec::new()
+                } else {
+                    let offset = elf.u32(header + 16)? as usize;
+                    elf.bytes(offset, elf.u32(header + 20)? as usize)?.to_vec()
+                };
+                sections.push(Section {
+                    name: elf.string(names + elf.u32(header)? as usize)?,
+                    data,
+                });
+            }
+            if kind == SHT_RELA {
+                let offset = elf.u32(header + 16)? as usize;
+                let size = elf.u32(header + 20)? as usize;
+                let entry_size = (elf.u32(header + 36)? as usize).max(12);
+                for relocation in (offset..offset + size).step_by(entry_size) {
+                    relocations.push((
+                        elf.u32(relocation)?,
+                        elf.u32(relocation + 4)? & 0xff,
+                        elf.u32(relocation + 8)?,
+                    ));
+                }
+            }
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let offset = elf.u32(header + 16)? as usize;
+            let size = elf.u32(header + 20)? as usize;
+            let strings_index = elf.u32(header + 24)? as usize;
+            let entry_size = (elf.u32(header + 36)? as usize).max(16);
+            let strings =
+                elf.u32(section_headers + strings_index * section_header_size + 16)? as usize;
+
+            for symbol in (offset..offset + size).step_by(entry_size) {
+                let info = elf.u8(symbol + 12)?;
+                let kind = match info & 0xf {
+                    STT_SECTION | STT_FILE => continue,
+                    STT_FUNC => SymbolKind::Function,
+                    STT_OBJECT => SymbolKind::Object,
+                    _ => SymbolKind::Other,
+                };
+                let name = elf.string(strings + elf.u32(symbol)? as usize)?;
+                if name.is_empty() {
+                    continue;
+                }
+                let bias = match elf.u16(symbol + 14)? {
+                    SHN_ABS => 0,
+                    _ => load_bias,
+                };
+                symbols.push(Symbol {
+                    name,
+                    address: elf.u32(symbol + 4)?.wrapping_add(bias),
+                    size: elf.u32(symbol + 8)?,
+                    kind,
+                });
+            }
+        }
+
+        // an executable linked for its address has nothing to relocate
+        if load_bias != 0 {
+            for (offset, kind, addend) in relocations {
+                relocate(&mut segments, offset, kind, addend, load_bias)?;
+            }
+        }
+
+        Ok(Self {
+            entry,
+            load_bias,
+            segments,
+            symbols,
+            sections,
+        })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+}
+
+/// applies one relocation of `kind` at `offset` to the moved `segments`
+fn relocate(
+    segments: &mut [Segment],
+    offset: u32,
+    kind: u32,
+    addend: u32,
+    load_bias: u32,
+) -> Result<(), ElfError> {
+    match kind {
+        R_RISCV_NONE => return Ok(()),
+        R_RISCV_RELATIVE => {}
+        kind => return Err(ElfError::UnsupportedRelocation { kind }),
+    }
+    let address = offset.wrapping_add(load_bias);
+    let word = segments
+        .iter_mut()
+        .find_map(|segment| {
+            let start = address.checked_sub(segment.address)? as usize;
+            segment.data.get_mut(start..start.checked_add(4)?)
+        })
+        .ok_or(ElfError::BadRelocation { address })?;
+    word.copy_from_slice(&addend.wrapping_add(load_bias).to_le_bytes());
+    Ok(())
+}
+
+impl Vm {
+    /// loads the segments of an ELF file, moves the pc to its entry point and
+    /// keeps its symbols and line numbers. Broken debug info is left out, the
+    /// program still loads. Returns the entry point
+    pub fn load_elf(&mut self, bytes: &[u8]) -> Result<u32, ElfError> {
+        self.load_elf_with_load_bias(bytes, DEFAULT_LOAD_BIAS)
+    }
+
+    /// `load_elf`, a position independent executable is moved by
+    /// `load_bias`
+    pub fn load_elf_with_load_bias(
+        &mut self,
+        bytes: &[u8],
+        load_bias: u32,
+    ) -> Result<u32, ElfError> {
+        let elf = Elf::parse_with_load_bias(bytes, load_bias)?;
+        self.invalidate_decode_cache();
+        for segment in &elf.segments {
+            self.memory.load(segment.address, &segment.data)?;
+            let bss = (segment.memory_size as usize).saturating_sub(segment.data.len());
+            let bss_start = segment.address.wrapping_add(segment.data.len() as u32);
+            self.memory.load(bss_start, &vec![0; bss])?;
+        }
+
+        self.vm_state.pc = elf.entry as i32;
+        // the heap starts after the program, at `_end` if the linker said
+        let end = elf
+            .segments
+            .iter()
+            .map(|segment| segment.address.wrapping_add(segment.memory_size))
+            .max();
+        let symbol_end = elf
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name == "_end")
+            .map(|symbol| symbol.address);
+        self.stats.heap_start = symbol_end.or(end);
+        self.line_table = LineTable::from_elf(&elf).ok().flatten();
+        self.symbols = elf.symbols;
+        self.htif_from_symbols();
+        Ok(elf.entry)
+    }
+
+    /// the symbol called `name` of the loaded ELF file
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+/// builds small ELF files for the tests, without a toolchain
+#[cfg(test)]
+pub(crate) mod test_elf {
+    /// an ELF file with one segment holding `code` at `address` (plus
+    /// `bss` zeroed bytes) and the `symbols` as untyped labels
+    pub(crate) fn build(address: u32, code: &[u32], bss: u32, symbols: &[(&str, u32)]) -> Vec<u8> {
+        build_with_sections(address, code, bss, symbols, &[])
+    }
+
+    /// like `build`, plus extra sections that are not loaded, like
+    /// `.debug_line`
+    pub(crate) fn build_with_sections(
+        address: u32,
+        code: &[u32],
+        bss: u32,
+        symbols: &[(&str, u32)],
+        sections: &[(&str, &[u8])],
+    ) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        let mut strings = vec![0u8];
+        let mut symbol_table = vec![0u8; 16];
+        for (name, value) in symbols {
+            symbol_table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            symbol_table.extend_from_slice(&value.to_le_bytes());
+            symbol_table.extend_from_slice(&[0; 8]);
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+
+        let mut section_names = b"\0.symtab\0.strtab\0".to_vec();
+        let mut extra_headers = Vec::new();
+        let program_header = 52;
+        let code_offset = program_header + 32;
+        let symbols_offset = code_offset + code.len();
+        let strings_offset = symbols_offset + symbol_table.len();
+        let mut offset = strings_offset + strings.len();
+        for (name, data) in sections {
+            extra_headers.push((section_names.len() as u32, offset as u32, data.len() as u32));
+            section_names.extend_from_slice(name.as_bytes());
+            section_names.push(0);
+            offset += data.len();
+        }
+        let section_names_name = section_names.len() as u32;
+        section_names.extend_from_slice(b".shstrtab\0");
+        let section_names_offset = offset;
+        let section_headers = section_names_offset + section_names.len();
+        let section_count = 4 + sections.len() as u16;
+
+        let mut elf = Vec::new();
+        elf.extend_from_slice(b"\x7fELF\x01\x01\x01");
+        elf.resize(16, 0);
+        for half in [2u16, 0xf3] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1, address, program_header as u32, section_headers as u32, 0] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [52u16, 32, 1, 40, section_count, section_count - 1] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+
+        let memory_size = code.len() as u32 + bss;
+        for word in [
+            1,
+            code_offset as u32,
+            address,
+            address,
+            code.len() as u32,
+            memory_size,
+            7,
+            4,
+        ] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf.extend_from_slice(&code);
+        elf.extend_from_slice(&symbol_table);
+        elf.extend_from_slice(&strings);
+        for (_, data) in sections {
+            elf.extend_from_slice(data);
+        }
+        elf.extend_from_slice(&section_names);
+
+        // null section, .symtab (linked to section 2), .strtab, the extra
+        // sections, .shstrtab
+        elf.extend_from_slice(&[0; 40]);
+        for word in [
+            1,
+            2,
+            0,
+            0,
+            symbols_offset as u32,
+            symbol_table.len() as u32,
+            2,
+            0,
+            4,
+            16,
+        ] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for word in [
+            9,
+            3,
+            0,
+            0,
+            strings_offset as u32,
+            strings.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for (name, offset, size) in extra_headers {
+            for word in [name, 1, 0, 0, offset, size, 0, 0, 1, 0] {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        for word in [
+            section_names_name,
+            3,
+            0,
+            0,
+            section_names_offset as u32,
+            section_names.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_elf, Elf, ElfError, SymbolKind, DEFAULT_LOAD_BIAS};
+    use crate::Vm;
+
+    #[test]
+    fn should_load_segments_entry_and_symbols() {
+        // addi a0, zero, 42; ebreak
+        let elf = test_elf::build(
+            0x1000,
+            &[0x02a0_0513, 0x0010_0073],
+            8,
+            &[("_start", 0x1000)],
+        );
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.memory.write_u32(0x1008, 0xffff_ffff).unwrap();
+
+        assert_eq!(vm.load_elf(&elf), Ok(0x1000));
+        assert_eq!(vm.vm_state.pc, 0x1000);
+        // .bss is zeroed
+        assert_eq!(vm.memory.read_u32(0x1008).unwrap(), 0);
+
+        let start = vm.symbol("_start").unwrap();
+        assert_eq!((start.address, start.kind), (0x1000, SymbolKind::Other));
+
+        vm.run().unwrap();
+        assert_eq!(vm.vm_state.registers[10], 42);
+    }
+
+    #[test]
+    fn should_move_position_independent_executables_by_the_load_bias() {
+        // 0x0 auipc a0, 0x0
+        // 0x4 lw a0, 12(a0)
+        // 0x8 ebreak
+        // 0xc .word 0                  <- R_RISCV_RELATIVE 0x8
+        let relocation: Vec<u8> = [0xcu32, 3, 8]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let mut bytes = test_elf::build_with_sections(
+            0,
+            &[0x0000_0517, 0x00c5_2503, 0x0010_0073, 0],
+            0,
+            &[("_start", 0)],
+            &[(".rela.dyn", &relocation)],
+        );
+        // ET_DYN, and .rela.dyn is SHT_RELA
+        bytes[16] = 3;
+        let section_headers = u32::from_le_bytes(bytes[32..36].try_into().unwrap()) as usize;
+        let rela = section_headers + 3 * 40;
+        bytes[rela + 4] = 4;
+
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!((elf.entry, elf.load_bias), (0x1_0000, DEFAULT_LOAD_BIAS));
+        assert_eq!(elf.segments[0].address, 0x1_0000);
+        assert_eq!(elf.symbols[0].address, 0x1_0000);
+
+        let mut vm = Vm::new(0x4_0000, 0x100);
+        assert_eq!(vm.load_elf_with_load_bias(&bytes, 0x4_0000), Ok(0x4_0000));
+        vm.run().unwrap();
+        assert_eq!(vm.vm_state.registers[10], 0x4_0008);
+
+        // relocations against symbols need a dynamic linker
+        let offset = u32::from_le_bytes(bytes[rela + 16..rela + 20].try_into().unwrap()) as usize;
+        bytes[offset + 4] = 1;
+        assert_eq!(
+            Elf::parse(&bytes),
+            Err(ElfError::UnsupportedRelocation { kind: 1 })
+        );
+    }
+
+    #[test]
+    fn should_reject_files_that_are_not_riscv_elf32() {
+        let mut vm = Vm::new(0x1000, 0x100);
+        assert_eq!(vm.load_elf(b"#!/bin/sh"), Err(ElfError::NotElf32));
+
+        let mut elf = test_elf::build(0x1000, &[], 0, &[]);
+        elf[18] = 0x3e;
+        assert_eq!(
+            vm.load_elf(&elf),
+            Err(ElfError::WrongMachine { machine: 0x3e })
+        );
+        assert_eq!(
+            vm.load_elf(&test_elf::build(0x1000, &[], 0, &[])[..60]),
+            Err(ElfError::Truncated)
+        );
+    }
+}
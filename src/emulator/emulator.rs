@@ -1,28 +1,131 @@
-use super::instruction_signatures::DestinationImmediate;
+use core::time::Duration;
 
+use super::atomic::Reservations;
+use super::block_cache::BlockCache;
+use super::breakpoints::{Breakpoints, Watchpoint};
+use super::cache::Caches;
+use super::call_stack::Frame;
+use super::clint::{Clint, Clock, TIMEBASE_FREQUENCY};
+use super::cooperative::{AbortHandle, Stopwatch};
+use super::csr::Csrs;
+use super::debug_line::LineTable;
+use super::decode_cache::DecodeCache;
+use super::dispatch::Dispatch;
+use super::ecall::{EcallHandler, EcallPolicy};
+use super::elf::Symbol;
+use super::error::VmError;
+use super::extensions::Extensions;
+use super::flame_graph::FoldedStacks;
+use super::gas::{GasMeter, InstructionClass};
+use super::instruction_signatures::{
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+};
+#[cfg(feature = "jit")]
+use super::jit::Jit;
+use super::limits::{VmLimits, TIME_CHECK_INTERVAL};
+
+use super::hooks::VmHooks;
+use super::hpm::PerformanceCounters;
+use super::htif::Htif;
+use super::memory::{Memory, MemoryAccess, MemoryMap};
+use super::mmio::Bus;
+use super::mmu::Mmu;
+use super::plic::Plic;
+use super::plugin::{CustomOpcode, InstructionPlugin};
+use super::prelude::*;
+use super::register::{Register, RegisterFile};
+use super::replay::Replay;
 use super::rv32i::Rv32iInstruction;
-use std::usize;
-use thiserror::Error;
+use super::sbi::Sbi;
+use super::semihosting::Semihosting;
+use super::snapshot::{HypercallPolicy, Snapshot};
+use super::stack_limit::StackLimit;
+use super::strace::Strace;
+use super::summary::RunStats;
+use super::syscalls::Syscalls;
+use super::timing::TimingModel;
+use super::uninit::UninitializedRead;
+use super::vector::VectorUnit;
+use super::wfi::WfiPolicy;
+
+/// how many instructions `Vm::execute_instructions` runs without an
+/// `execution_limit`
+pub const INSTRUCTION_BUDGET: u64 = 10_000_000;
 
-#[derive(Error, Debug)]
-pub enum Rv32iInstructionError {
-    #[error("the instruction `{0}` is not implemented yet")]
-    InstructionNotImplemented(String),
+/// why `Vm::run()` stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// the guest executed `ebreak`
+    Ebreak,
+    /// the pc reached a breakpoint, the instruction there did not run yet
+    Breakpoint { pc: u32 },
+    /// the instruction at `pc` did an access a watchpoint was waiting for,
+    /// the access already happened
+    Watchpoint {
+        pc: u32,
+        watchpoint: Watchpoint,
+        access: MemoryAccess,
+    },
+    /// the gas left can not pay for the instruction at `pc`, it did not run.
+    /// Adding gas and calling `run()` again continues there
+    OutOfGas { pc: u32 },
+    /// the instructions `run_for()` allowed have run, the instruction at `pc`
+    /// did not run yet
+    Preempted { pc: u32 },
+    /// the guest asked the SBI to shut down or reset the system
+    Shutdown,
+    /// the hart stopped itself with the SBI call `hart_stop` at `pc`
+    HartStopped { pc: u32 },
+    /// the guest called the `exit` syscall with `code`
+    Exit { code: i32 },
+    /// the guest wrote the exit command with `code` to `tohost`, see
+    /// `htif.rs`
+    HtifExit { code: i32 },
+    /// the abort handle stopped `run_for_time` or `run_async` before the
+    /// instruction at `pc`
+    Aborted { pc: u32 },
+    /// a `wfi` before `pc` waits for an interrupt, see `wfi.rs`. `run()`
+    /// again continues at `pc`
+    Waiting { pc: u32 },
 }
 
-#[derive(Debug)]
-pub struct Registers {
-    registers: [i32; 32],
+impl StopReason {
+    /// how the guest ended, if it did: `ebreak`, the `tohost` write of the
+    /// riscv-tests or the `exit` syscall
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        match self {
+            Self::Ebreak => Some(ExitReason::Breakpoint),
+            Self::HtifExit { code } => Some(ExitReason::HtifExit(*code)),
+            Self::Exit { code } => Some(ExitReason::SyscallExit(*code)),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct VmState {
-    pub registers: [i32; 32],
+/// why the guest ended, see `StopReason::exit_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Breakpoint,
+    HtifExit(i32),
+    SyscallExit(i32),
+}
+
+impl ExitReason {
+    /// the exit status of the guest, `ebreak` is a success
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Breakpoint => 0,
+            Self::HtifExit(code) | Self::SyscallExit(code) => code,
+        }
+    }
+}
 
-    /// stack pointer
-    // This should be register x2 by the specs (page 41)
-    // https://drive.google.com/file/d/1uviu1nH-tScFfgrovvFCrj7Omv8tFtkp/view
-    pub sp: i32,
+/// the architectural state of a hart. sp, ra and gp are x2, x1 and x3 of
+/// `registers` (page 41 of the spec), the accessors below only read and
+/// write those, so there is one copy of them
+#[derive(Debug, Clone)]
+pub struct VmState {
+    pub registers: RegisterFile,
 
     /// program counter
     pub pc: i32,
@@ -30,28 +133,747 @@ pub struct VmState {
 
 impl Default for VmState {
     fn default() -> Self {
-        let initial_registers = [0; 32];
         Self {
             pc: 1000,
-            registers: initial_registers,
-            sp: 0,
+            registers: RegisterFile::default(),
         }
     }
 }
 
-struct Vm {
-    vm_state: VmState,
+impl VmState {
+    /// stack pointer, x2
+    pub fn sp(&self) -> i32 {
+        self.registers[Register::SP]
+    }
+
+    pub fn set_sp(&mut self, sp: i32) {
+        self.registers.write(Register::SP, sp);
+    }
+
+    /// return address, x1
+    pub fn ra(&self) -> i32 {
+        self.registers[Register::RA]
+    }
+
+    pub fn set_ra(&mut self, ra: i32) {
+        self.registers.write(Register::RA, ra);
+    }
+
+    /// global pointer, x3
+    pub fn gp(&self) -> i32 {
+        self.registers[Register::GP]
+    }
+
+    pub fn set_gp(&mut self, gp: i32) {
+        self.registers.write(Register::GP, gp);
+    }
+}
+
+pub struct Vm {
+    pub vm_state: VmState,
+    pub memory: Memory,
+    pub stats: RunStats,
+
+    /// the optional cycle-approximate timing model, `None` means only
+    /// instructions are counted
+    pub timing: Option<TimingModel>,
+
+    /// the optional model of the L1 caches, it only counts hits and misses
+    pub caches: Option<Caches>,
+
+    /// the optional Zihpm counters, without them `mhpmcounter3` and up do
+    /// not exist
+    pub performance_counters: Option<PerformanceCounters>,
+
+    /// what the guest is allowed to do with the snapshot hypercalls
+    pub hypercall_policy: HypercallPolicy,
+
+    /// the snapshots the guest asked for, the index is the checkpoint id
+    pub(super) checkpoints: Vec<Snapshot>,
+
+    /// see `call_stack()`
+    pub(super) call_stack: Vec<Frame>,
+
+    /// `run()` fails with `ExecutionLimitExceeded` once this many
+    /// instructions were retired, `None` means no limit
+    pub execution_limit: Option<u64>,
+
+    /// `run()` stops with `Preempted` once this many instructions were
+    /// retired, see `run_for()`
+    pub(super) slice_end: Option<u64>,
+    /// see `abort_handle()`
+    pub(super) abort: AbortHandle,
+
+    /// gas metering for sandboxed guests, `None` means instructions are free
+    pub gas: Option<GasMeter>,
+
+    /// logs every syscall the guest makes, `None` means off
+    pub strace: Option<Strace>,
+
+    /// see `add_hooks`
+    pub(super) hooks: Vec<Box<dyn VmHooks>>,
+
+    /// where `run()` stops
+    pub breakpoints: Breakpoints,
+
+    /// the symbols of the loaded ELF file, see `load_elf`
+    pub symbols: Vec<Symbol>,
+    /// the line numbers of the loaded ELF file, if it had them
+    pub line_table: Option<LineTable>,
+
+    /// decoded instructions per page, `None` means every fetch decodes
+    pub decode_cache: Option<DecodeCache>,
+
+    /// basic blocks of register-only code, `None` means one instruction
+    /// at a time
+    pub block_cache: Option<BlockCache>,
+
+    /// native code for hot blocks, `None` means everything is interpreted
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
+
+    /// the machine-mode CSRs, see `csr.rs`
+    pub csrs: Csrs,
+    /// what `rdtime`, `mtime` and the SBI timer read, see `clint.rs`
+    pub(super) clock: Clock,
+    /// the timer of the `virt` machine, `None` means there is none
+    pub clint: Option<Clint>,
+    /// the interrupt controller of the `virt` machine, see `raise_irq`
+    pub plic: Option<Plic>,
+    /// the instructions the vm does not know trap into the guest's handler
+    /// (with the word in `mtval`) instead of failing `run()`. What the mode
+    /// may not do and unknown CSRs always trap
+    pub trap_illegal_instructions: bool,
+    /// the log of the inputs while recording or replaying, see `replay.rs`
+    pub(super) replay: Option<Replay>,
+
+    /// the semihosting calls, `None` means every `ebreak` stops `run()`
+    pub semihosting: Option<Semihosting>,
+
+    /// the SBI firmware calls, `None` means they go to the ecall handlers
+    pub sbi: Option<Sbi>,
+
+    /// the Linux syscalls, `None` means they go to the ecall handlers
+    pub syscalls: Option<Syscalls>,
+
+    /// the vector registers, `None` makes the vector instructions illegal
+    pub vector: Option<VectorUnit>,
+
+    /// spike's tohost/fromhost interface, see `htif.rs`. `load_elf` turns
+    /// it on when the program has a `tohost` symbol
+    pub htif: Option<Htif>,
+
+    /// the instructions of other extensions are illegal, see `extensions.rs`
+    pub(super) extensions: Extensions,
+
+    /// where `run()` stops after the instruction that asked for it
+    pub(super) stop: Option<StopReason>,
+
+    /// where `run()` fails with `StackOverflow`, see `stack_limit.rs`
+    pub stack_limit: Option<StackLimit>,
+    /// the loads of uninitialized memory, `None` means nobody looks, see
+    /// `uninit.rs`
+    pub(super) uninitialized_reads: Option<Vec<UninitializedRead>>,
+    /// the instructions and cycles per call stack, see `flame_graph.rs`
+    pub(super) folded_stacks: Option<FoldedStacks>,
+    /// see `limits.rs`, the instruction limit is `execution_limit`
+    pub limits: VmLimits,
+
+    /// the memory-mapped devices, see `mmio.rs`
+    pub(super) bus: Bus,
+
+    /// the `lr.w` reservations of every hart, see `atomic.rs`
+    pub(super) reservations: Reservations,
+
+    /// which ecalls the host handles and which trap into the guest
+    pub ecall_policy: EcallPolicy,
+    /// what `wfi` does while no interrupt is pending
+    pub wfi_policy: WfiPolicy,
+    /// the TLB, see `mmu.rs`
+    pub(super) mmu: Mmu,
+    pub(super) ecall_handlers: HashMap<u32, EcallHandler>,
+    /// the custom instructions, see `plugin.rs`
+    pub(super) plugins: HashMap<CustomOpcode, Box<dyn InstructionPlugin>>,
 }
 
 impl Vm {
+    /// creates a vm with `memory_size` bytes of memory starting at `memory_base`.
+    /// The program counter starts at the beginning of the memory.
+    pub fn new(memory_base: u32, memory_size: usize) -> Self {
+        Self {
+            vm_state: VmState {
+                pc: memory_base as i32,
+                ..VmState::default()
+            },
+            memory: Memory::new(memory_base, memory_size),
+            stats: RunStats::default(),
+            timing: None,
+            caches: None,
+            performance_counters: None,
+            hypercall_policy: HypercallPolicy::default(),
+            checkpoints: Vec::new(),
+            call_stack: Vec::new(),
+            execution_limit: None,
+            slice_end: None,
+            abort: AbortHandle::default(),
+            gas: None,
+            strace: None,
+            hooks: Vec::new(),
+            breakpoints: Breakpoints::default(),
+            symbols: Vec::new(),
+            line_table: None,
+            decode_cache: None,
+            block_cache: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            csrs: Csrs::default(),
+            clock: Clock::default(),
+            clint: None,
+            plic: None,
+            trap_illegal_instructions: false,
+            replay: None,
+            semihosting: None,
+            sbi: None,
+            syscalls: None,
+            vector: None,
+            htif: None,
+            extensions: Extensions::default(),
+            stop: None,
+            stack_limit: None,
+            uninitialized_reads: None,
+            folded_stacks: None,
+            limits: VmLimits::default(),
+            bus: Bus::default(),
+            reservations: Reservations::default(),
+            ecall_policy: EcallPolicy::default(),
+            wfi_policy: WfiPolicy::default(),
+            mmu: Mmu::default(),
+            ecall_handlers: HashMap::new(),
+            plugins: HashMap::new(),
+        }
+    }
+
+    /// creates a vm with the address space of `map` instead of one flat
+    /// block of memory, see `MemoryMap`. The program counter starts at the
+    /// first region that is no guard
+    pub fn from_memory_map(map: &MemoryMap) -> Self {
+        let mut vm = Self::new(0, 0);
+        vm.memory = Memory::from_map(map);
+        vm.vm_state.pc = map.start() as i32;
+        vm
+    }
+
+    /// turns on the cycle-approximate timing model
+    pub fn with_timing(mut self, timing: TimingModel) -> Self {
+        self.timing = Some(timing);
+        self
+    }
+
+    /// turns on syscall logging
+    pub fn with_strace(mut self, strace: Strace) -> Self {
+        self.strace = Some(strace);
+        self
+    }
+
+    /// copies the raw program bytes (as they are in the .text section of an
+    /// elf file) into memory at `address`
+    pub fn load_program(&mut self, address: u32, program: &[u8]) -> Result<(), VmError> {
+        self.invalidate_decode_cache();
+        self.memory.load(address, program)
+    }
+
+    /// a vm with 0x100 bytes of memory at `base` and `program` loaded there
+    #[cfg(test)]
+    pub(crate) fn with_program_words(base: u32, program: &[u32]) -> Self {
+        let bytes: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Self::new(base, 0x100);
+        vm.load_program(base, &bytes).unwrap();
+        vm
+    }
+
+    /// fetches the 32 bits at the program counter and decodes them
+    pub fn fetch(&self) -> Result<Instruction, VmError> {
+        self.fetch_at(self.vm_state.pc as u32)
+    }
+
+    /// fetches and decodes the instruction at `address` without executing it
+    pub fn fetch_at(&self, address: u32) -> Result<Instruction, VmError> {
+        self.fetch_word_at(address)
+            .map(|(_, instruction)| instruction)
+    }
+
+    /// like `fetch_at`, but also returns the raw instruction word
+    pub(super) fn fetch_word_at(&self, address: u32) -> Result<(u32, Instruction), VmError> {
+        // without the C extension every instruction is 4 byte aligned
+        if !address.is_multiple_of(4) {
+            return Err(VmError::MisalignedAccess {
+                pc: address,
+                instruction: 0,
+                address,
+            });
+        }
+
+        let word = self
+            .memory
+            .fetch_u32(address)
+            .map_err(|error| error.at(address, 0))?;
+        let rv32i_instruction = Rv32iInstruction::from_core_instruction_format(word.to_le_bytes())
+            .map_err(|error| error.at(address, word))?;
+        if !self.extensions.contains(rv32i_instruction.extension()) {
+            return Err(VmError::IllegalInstruction {
+                pc: address,
+                instruction: word,
+            });
+        }
+
+        Ok((
+            word,
+            Instruction::Rv32iInstruction(address as i32, rv32i_instruction),
+        ))
+    }
+
+    /// `fetch_word_at` through the decode cache, if it is on
+    fn fetch_cached(&mut self, address: u32) -> Result<(u32, Instruction), VmError> {
+        // the cache has the code of the physical addresses
+        if self.is_paging() {
+            return self.fetch_paged(address);
+        }
+        let Some(cache) = &mut self.decode_cache else {
+            return self.fetch_word_at(address);
+        };
+        if let Some((word, instruction)) = cache.get(address) {
+            return Ok((
+                word,
+                Instruction::Rv32iInstruction(address as i32, instruction),
+            ));
+        }
+
+        let (word, instruction) = self.fetch_word_at(address)?;
+        if let (Some(cache), Instruction::Rv32iInstruction(_, rv32i_instruction)) =
+            (&mut self.decode_cache, &instruction)
+        {
+            cache.insert(address, word, *rv32i_instruction);
+        }
+        Ok((word, instruction))
+    }
+
+    /// executes the single instruction the program counter points to. Taking
+    /// an interrupt is a step of its own, the pc is at the handler then
+    pub fn step(&mut self) -> Result<(), VmError> {
+        self.update_device_interrupts();
+        self.replay_irqs();
+        if self.take_interrupt() {
+            return Ok(());
+        }
+        let result = self
+            .fetch_cached(self.vm_state.pc as u32)
+            .and_then(|(word, instruction)| self.execute(word, instruction));
+        result.map(|_| ()).or_else(|error| self.trap_fault(error))
+    }
+
+    /// like `run()`, but stops with `Preempted` after `instructions`
+    /// instructions. This is how `smp.rs` takes turns between harts
+    pub fn run_for(&mut self, instructions: u64) -> Result<StopReason, VmError> {
+        self.slice_end = Some(self.stats.instructions_retired + instructions);
+        let result = self.run();
+        self.slice_end = None;
+        result
+    }
+
+    /// how many instructions may run before the run loop has to check the
+    /// execution limit, the end of a `run_for()` or the next replayed
+    /// interrupt, at most `max`
+    pub(super) fn budget(&self, max: u64) -> u64 {
+        [
+            self.execution_limit,
+            self.slice_end,
+            self.next_replayed_irq(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(max, |budget, end| {
+            budget.min(end.saturating_sub(self.stats.instructions_retired))
+        })
+    }
+
+    /// runs until the guest executes `ebreak`, hits a breakpoint or
+    /// watchpoint, runs out of gas, or until something goes wrong. A breakpoint on the
+    /// instruction `run()` starts at does not stop it, so calling `run()`
+    /// again continues after a breakpoint.
+    pub fn run(&mut self) -> Result<StopReason, VmError> {
+        let stopwatch = Stopwatch::start();
+        let mut first = true;
+        let mut next_time_check = self.stats.instructions_retired;
+
+        let result = loop {
+            let pc = self.vm_state.pc as u32;
+            if self.limits.max_wall_time.is_some()
+                && self.stats.instructions_retired >= next_time_check
+            {
+                if let Err(error) = self.check_wall_time(&stopwatch) {
+                    break Err(error);
+                }
+                next_time_check = self.stats.instructions_retired + TIME_CHECK_INTERVAL;
+            }
+            if !first && self.breakpoints.is_breakpoint(pc) {
+                break Ok(StopReason::Breakpoint { pc });
+            }
+            first = false;
+
+            if self
+                .slice_end
+                .is_some_and(|end| self.stats.instructions_retired >= end)
+            {
+                break Ok(StopReason::Preempted { pc });
+            }
+
+            self.update_device_interrupts();
+            self.replay_irqs();
+            if self.take_interrupt() {
+                continue;
+            }
+            #[cfg(feature = "jit")]
+            if self.run_jitted(pc) {
+                continue;
+            }
+            if self.run_blocks(pc) {
+                continue;
+            }
+
+            let (word, instruction) = match self.fetch_cached(self.vm_state.pc as u32) {
+                Ok(fetched) => fetched,
+                Err(error) => match self.trap_fault(error) {
+                    Ok(()) => continue,
+                    Err(error) => break Err(error),
+                },
+            };
+
+            if let Instruction::Rv32iInstruction(_, Rv32iInstruction::Ebreak) = instruction {
+                if self.semihosting_call(pc) {
+                    let sp = self.vm_state.sp() as u32;
+                    self.stats.record_instruction(pc, sp, sp);
+                    match self.stop.take() {
+                        Some(stop_reason) => break Ok(stop_reason),
+                        None => continue,
+                    }
+                }
+                self.stats.record_trap("ebreak");
+                break Ok(StopReason::Ebreak);
+            }
+
+            if let Some(limit) = self.execution_limit {
+                if self.stats.instructions_retired >= limit {
+                    break Err(VmError::ExecutionLimitExceeded {
+                        pc: self.vm_state.pc as u32,
+                        instruction: word,
+                        limit,
+                    });
+                }
+            }
+
+            if let Some(gas) = &mut self.gas {
+                if !gas.charge(&instruction) {
+                    break Ok(StopReason::OutOfGas { pc });
+                }
+            }
+
+            match self.execute(word, instruction) {
+                Ok(Some(access)) => {
+                    if let Some(watchpoint) = self.breakpoints.watchpoint_hit(&access) {
+                        break Ok(StopReason::Watchpoint {
+                            pc,
+                            watchpoint: *watchpoint,
+                            access,
+                        });
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    if let Err(error) = self.trap_fault(error) {
+                        break Err(error);
+                    }
+                }
+            }
+
+            if let Some(stop_reason) = self.stop.take() {
+                break Ok(stop_reason);
+            }
+        };
+
+        self.stats.run_time += stopwatch.elapsed();
+        self.stats.exit = Some(match &result {
+            Ok(stop_reason) => format!("{stop_reason:?}"),
+            Err(error) => {
+                self.stats.record_trap(error.trap_name());
+                error.to_string()
+            }
+        });
+
+        result
+    }
+
+    /// a readable report of the last run: why it stopped, how many
+    /// instructions ran, the time of the guest's clock and how long the host
+    /// took, the stack and heap usage, traps, the hottest functions and the
+    /// accesses per device, plus the cycles per memory region when timing is on and the
+    /// cache hits and misses with caches, and the TLB's once the guest pages
+    pub fn summary(&self) -> String {
+        let nanoseconds = u128::from(self.time()) * 1_000_000_000 / u128::from(TIMEBASE_FREQUENCY);
+        let guest_time = Duration::from_nanos(nanoseconds as u64);
+        let mut summary = self
+            .stats
+            .report(&self.symbols, Some(guest_time))
+            .to_string();
+        if let Some(timing) = &self.timing {
+            summary.push_str(&timing.to_string());
+        }
+        if let Some(caches) = &self.caches {
+            summary.push_str(&caches.to_string());
+        }
+        let tlb = self.tlb();
+        if tlb.stats.hits + tlb.stats.misses != 0 {
+            summary.push_str(&tlb.to_string());
+        }
+        summary
+    }
+
+    /// `word` is the raw instruction, faults are reported with it. Returns
+    /// the load or store the instruction did, if any
+    fn execute(
+        &mut self,
+        word: u32,
+        instruction: Instruction,
+    ) -> Result<Option<MemoryAccess>, VmError> {
+        let pc = self.vm_state.pc as u32;
+        let sp = self.vm_state.sp() as u32;
+
+        // the vm handles the ecalls itself since they need more than the vm
+        // state, see `ecall.rs`
+        let is_ecall = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Ecall)
+        );
+        let is_atomic = matches!(
+            &instruction,
+            Instruction::Rv32iInstruction(_, rv32i_instruction) if rv32i_instruction.is_atomic()
+        );
+        let is_vector = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Vector(_))
+        );
+        let is_custom = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Custom(_))
+        );
+        let is_indirect_jump = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jalr(_))
+                | Instruction::PseudoInstruction(_, PseudoInstruction::Ret)
+        );
+        let is_csr = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(
+                _,
+                Rv32iInstruction::Csrrw(_)
+                    | Rv32iInstruction::Csrrs(_)
+                    | Rv32iInstruction::Csrrc(_)
+                    | Rv32iInstruction::Csrrwi(_)
+                    | Rv32iInstruction::Csrrsi(_)
+                    | Rv32iInstruction::Csrrci(_)
+            )
+        );
+
+        // instructions above the hart's mode never run, they trap into the
+        // guest, see `csr.rs`
+        if !self.is_allowed(&instruction) {
+            self.trap_illegal_instruction(word);
+            return Ok(None);
+        }
+
+        for hooks in &mut self.hooks {
+            hooks.before_instruction(&self.vm_state, &instruction);
+        }
+
+        // loads and stores need the memory, everything else only the vm state
+        let memory_instruction = match &instruction {
+            Instruction::Rv32iInstruction(_, rv32i_instruction) if self.is_paging() => {
+                self.execute_paged(rv32i_instruction)
+            }
+            Instruction::Rv32iInstruction(_, rv32i_instruction) => {
+                match self.execute_mmio(rv32i_instruction) {
+                    Some(access) => Some(Ok(access)),
+                    None => rv32i_instruction
+                        .execute_memory_instruction(&mut self.vm_state, &mut self.memory),
+                }
+            }
+            Instruction::PseudoInstruction(_, _) => None,
+        };
+
+        let memory_access = match memory_instruction {
+            Some(result) => {
+                Some(result.map_err(|error| self.stack_overflow_of(error.at(pc, word)))?)
+            }
+            None if is_atomic => self
+                .execute_atomic(&instruction)
+                .map_err(|error| error.at(pc, word))?,
+            None if is_vector => self
+                .execute_vector(&instruction)
+                .map_err(|error| error.at(pc, word))?,
+            None if is_custom => {
+                self.execute_custom(word)
+                    .map_err(|error| error.at(pc, word))?;
+                None
+            }
+            None if is_ecall => {
+                self.ecall(pc, word)?;
+                None
+            }
+            None if self.execute_fence(&instruction) => None,
+            None if self.execute_sfence(&instruction) => None,
+            None if self.execute_csr(&instruction) => None,
+            // a CSR the vm does not have, or a write to a read-only one
+            None if is_csr => {
+                self.trap_illegal_instruction(word);
+                return Ok(None);
+            }
+            None => {
+                instruction
+                    .execute_instruction(&mut self.vm_state)
+                    .map_err(|error| error.at(pc, word))?;
+                None
+            }
+        };
+
+        if let Some(MemoryAccess::Write { address, size, .. }) = memory_access {
+            self.reservations.store(address, size);
+            // device registers are no heap
+            if self.memory.read_u8(address).is_ok() {
+                self.stats.record_write(address, size);
+            }
+        }
+        if let Some(access) = memory_access {
+            self.check_tohost(access);
+        }
+        if let (Some(cache), Some(MemoryAccess::Write { address, size, .. })) =
+            (&mut self.decode_cache, memory_access)
+        {
+            cache.invalidate(address, size);
+        }
+        if let (Some(blocks), Some(MemoryAccess::Write { address, size, .. })) =
+            (&mut self.block_cache, memory_access)
+        {
+            blocks.invalidate(address, size);
+        }
+        #[cfg(feature = "jit")]
+        if let (Some(jit), Some(MemoryAccess::Write { address, size, .. })) =
+            (&mut self.jit, memory_access)
+        {
+            jit.invalidate(address, size);
+        }
+
+        // the fetch is a memory access as well
+        if let Some(timing) = &mut self.timing {
+            timing.instruction(InstructionClass::of(&instruction));
+            timing.memory_access(pc);
+            match memory_access {
+                Some(MemoryAccess::Read { address, .. } | MemoryAccess::Write { address, .. }) => {
+                    timing.memory_access(address);
+                }
+                None => {}
+            }
+        }
+        if let Some(caches) = &mut self.caches {
+            caches.access(pc, memory_access);
+        }
+        if let Some(counters) = &mut self.performance_counters {
+            counters.record(&instruction, memory_access, self.vm_state.pc as u32);
+        }
+
+        for hooks in &mut self.hooks {
+            match memory_access {
+                Some(MemoryAccess::Read {
+                    address,
+                    size,
+                    value,
+                }) => hooks.on_memory_read(pc, address, size, value),
+                Some(MemoryAccess::Write {
+                    address,
+                    size,
+                    value,
+                }) => hooks.on_memory_write(pc, address, size, value),
+                None => {}
+            }
+            if let Some(register) = instruction.destination().filter(|rd| !rd.is_zero()) {
+                hooks.on_register_write(pc, register, self.vm_state.registers[register]);
+            }
+            hooks.after_instruction(&self.vm_state, &instruction);
+        }
+
+        if let Some(stacks) = &mut self.folded_stacks {
+            let cycles = self.timing.as_ref().map(|timing| timing.cycles);
+            stacks.record(pc, &self.call_stack, cycles);
+        }
+        self.track_call(pc, &instruction);
+        if is_indirect_jump {
+            self.stats.record_indirect_jump(pc, self.vm_state.pc as u32);
+        }
+        self.stats
+            .record_instruction(pc, sp, self.vm_state.sp() as u32);
+        self.tick_timers();
+        self.check_initialized(pc, memory_access);
+        self.check_stack(pc, word, sp, memory_access)?;
+        Ok(memory_access)
+    }
+
+    /// executes already decoded instructions. The pc decides which one runs
+    /// next (the one whose address is the pc), so branches work the same as
+    /// with `run()`. Returns `None` once the pc points to an address without
+    /// an instruction, or stops at a breakpoint or without gas like `run()`.
+    /// A loop that never leaves the instructions fails with
+    /// `ExecutionLimitExceeded` at the `execution_limit`, or after
+    /// `INSTRUCTION_BUDGET` instructions without one.
     pub fn execute_instructions(
         &mut self,
         instructions: Vec<Instruction>,
-    ) -> Result<(), Rv32iInstructionError> {
-        for instruction in instructions {
-            let _ = instruction.execute_instruction(&mut self.vm_state);
+    ) -> Result<Option<StopReason>, VmError> {
+        // the first instruction of an address wins, as it would with a scan
+        let mut by_address = HashMap::new();
+        for instruction in instructions.iter().rev() {
+            by_address.insert(instruction.address(), instruction);
         }
-        Ok(())
+        let limit = self.execution_limit.unwrap_or(INSTRUCTION_BUDGET);
+        let end = match self.execution_limit {
+            Some(limit) => limit,
+            None => self.stats.instructions_retired.saturating_add(limit),
+        };
+        let start = self.vm_state.pc;
+
+        while let Some(instruction) = by_address.get(&self.vm_state.pc) {
+            let pc = self.vm_state.pc as u32;
+            if self.vm_state.pc != start && self.breakpoints.is_breakpoint(pc) {
+                return Ok(Some(StopReason::Breakpoint { pc }));
+            }
+            if self.stats.instructions_retired >= end {
+                return Err(VmError::ExecutionLimitExceeded {
+                    pc,
+                    instruction: 0,
+                    limit,
+                });
+            }
+            if let Some(gas) = &mut self.gas {
+                if !gas.charge(instruction) {
+                    return Ok(Some(StopReason::OutOfGas { pc }));
+                }
+            }
+            let sp = self.vm_state.sp() as u32;
+            instruction.execute_instruction(&mut self.vm_state)?;
+            self.stats
+                .record_instruction(pc, sp, self.vm_state.sp() as u32);
+        }
+        Ok(None)
     }
 }
 
@@ -62,6 +884,81 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// the address in memory where the instruction lives
+    pub fn address(&self) -> i32 {
+        match self {
+            Self::PseudoInstruction(memory_address, _) => *memory_address,
+            Self::Rv32iInstruction(memory_address, _) => *memory_address,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::PseudoInstruction(_, PseudoInstruction::Ret) => "ret",
+            Self::PseudoInstruction(_, PseudoInstruction::Li(_)) => "li",
+            Self::Rv32iInstruction(_, instruction) => instruction.mnemonic(),
+        }
+    }
+
+    /// the register the instruction writes to, if any (x0 included)
+    pub fn destination(&self) -> Option<Register> {
+        match self {
+            Self::PseudoInstruction(_, PseudoInstruction::Li(DestinationImmediate { rd, .. })) => {
+                Some(*rd)
+            }
+            Self::PseudoInstruction(_, PseudoInstruction::Ret) => None,
+            Self::Rv32iInstruction(_, instruction) => match instruction {
+                Rv32iInstruction::Add(r)
+                | Rv32iInstruction::Sub(r)
+                | Rv32iInstruction::Xor(r)
+                | Rv32iInstruction::Or(r)
+                | Rv32iInstruction::And(r)
+                | Rv32iInstruction::Sll(r)
+                | Rv32iInstruction::Srl(r)
+                | Rv32iInstruction::Sra(r)
+                | Rv32iInstruction::Slt(r)
+                | Rv32iInstruction::Sltu(r)
+                | Rv32iInstruction::LrW(r)
+                | Rv32iInstruction::ScW(r)
+                | Rv32iInstruction::AmoswapW(r)
+                | Rv32iInstruction::AmoaddW(r)
+                | Rv32iInstruction::AmoxorW(r)
+                | Rv32iInstruction::AmoandW(r)
+                | Rv32iInstruction::AmoorW(r)
+                | Rv32iInstruction::AmominW(r)
+                | Rv32iInstruction::AmomaxW(r)
+                | Rv32iInstruction::AmominuW(r)
+                | Rv32iInstruction::AmomaxuW(r) => Some(r.rd),
+                Rv32iInstruction::Addi(i)
+                | Rv32iInstruction::Xori(i)
+                | Rv32iInstruction::Ori(i)
+                | Rv32iInstruction::Andi(i)
+                | Rv32iInstruction::Slli(i)
+                | Rv32iInstruction::Srli(i)
+                | Rv32iInstruction::Srai(i)
+                | Rv32iInstruction::Slti(i)
+                | Rv32iInstruction::Sltiu(i)
+                | Rv32iInstruction::Lb(i)
+                | Rv32iInstruction::Lh(i)
+                | Rv32iInstruction::Lw(i)
+                | Rv32iInstruction::Lbu(i)
+                | Rv32iInstruction::Lhu(i)
+                | Rv32iInstruction::Jalr(i)
+                | Rv32iInstruction::Csrrw(i)
+                | Rv32iInstruction::Csrrs(i)
+                | Rv32iInstruction::Csrrc(i)
+                | Rv32iInstruction::Csrrwi(i)
+                | Rv32iInstruction::Csrrsi(i)
+                | Rv32iInstruction::Csrrci(i) => Some(i.rd),
+                Rv32iInstruction::Lui(u)
+                | Rv32iInstruction::Auipc(u)
+                | Rv32iInstruction::Jal(u) => Some(u.rd),
+                Rv32iInstruction::Vector(vector) => vector.destination(),
+                _ => None,
+            },
+        }
+    }
+
     /// this executes the instruction. Well the execution is only possible if
     /// we have creates the extension and if in that extension we have
     /// implemented the instruction.
@@ -78,47 +975,40 @@ impl Instruction {
     ///
     /// So to put it simply the responsibility of this function is to
     /// map the right instruction execution function based on the instruction
-    /// variant and pass in the VM state.
-    pub fn execute_instruction(&self, vm_state: &mut VmState) -> Result<(), Rv32iInstructionError> {
-        let _ = match self {
-            // RV32I extension
-            Self::Rv32iInstruction(memory_address, rv32i_instruction) => match rv32i_instruction {
-                Rv32iInstruction::Add(destination_source1_source2) => {
-                    Rv32iInstruction::rv32i_instruction_add(destination_source1_source2, vm_state);
-                    Ok(())
-                }
-                Rv32iInstruction::Bltu(source1_source2_immediate) => {
-                    Rv32iInstruction::rv32i_instruction_bltu(source1_source2_immediate, vm_state);
-                    Ok(())
-                }
-                // if we end up here, it means that the instruction is not
-                // implemented yet
-                _ => {
-                    println!("not implemented yet: {self:?}");
-                    Err(Rv32iInstructionError::InstructionNotImplemented(
-                        "aaa".to_string(),
-                    ))
-                }
-            },
+    /// variant and pass in the VM state. For RV32I that is a lookup in a flat
+    /// table by opcode id, see `dispatch.rs`.
+    pub fn execute_instruction(&self, vm_state: &mut VmState) -> Result<(), VmError> {
+        match self {
+            // RV32I extension, through the handler table in `dispatch.rs`.
+            // If there is no handler, the instruction is not implemented yet.
+            // The raw instruction is not known here, the vm fills it in
+            Self::Rv32iInstruction(_, rv32i_instruction) => {
+                let dispatch = Dispatch::of(rv32i_instruction).ok_or(VmError::NotImplemented {
+                    pc: self.address() as u32,
+                    instruction: 0,
+                })?;
+                dispatch.execute(vm_state);
+            }
 
             // pseudo instructions extension
-            Self::PseudoInstruction(memory_address, pseudo_instruction) => match pseudo_instruction
-            {
+            Self::PseudoInstruction(_, pseudo_instruction) => match pseudo_instruction {
                 PseudoInstruction::Li(DestinationImmediate { rd, imm }) => {
-                    vm_state.registers[*rd as usize] = *imm as i32;
-                    Ok(())
+                    vm_state.registers.write(*rd, *imm);
+                    vm_state.pc = vm_state.pc.wrapping_add(4);
                 }
-                PseudoInstruction::Ret => Ok(()),
-                _ => {
-                    println!("not implemented yet: {self:?}");
-                    Err(Rv32iInstructionError::InstructionNotImplemented(
-                        "aaa".to_string(),
-                    ))
+                // ret is jalr zero, 0(ra)
+                PseudoInstruction::Ret => {
+                    Rv32iInstruction::rv32i_instruction_jalr(
+                        &DestinationSource1Immediate {
+                            rd: Register::ZERO,
+                            rs1: Register::RA,
+                            imm: 0,
+                        },
+                        vm_state,
+                    );
                 }
             },
-        };
-
-        vm_state.pc += 4;
+        }
 
         Ok(())
     }
@@ -130,11 +1020,18 @@ pub enum PseudoInstruction {
     Li(DestinationImmediate),
 }
 
+/// the method-style API of the first version of the emulator, kept for
+/// tests that poke single instructions. It is only a facade: the state is a
+/// `VmState` and every method runs the same executor as the vm, so the
+/// semantics of an instruction live in `rv32i.rs` only
 pub struct Emulator {
-    // index 1 to hold the return address for a call
-    // index 2 should be stack pointer
-    registers: [u32; 32],
-    pc: u32,
+    vm_state: VmState,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Emulator {
@@ -142,68 +1039,65 @@ impl Emulator {
     /// TODO: take as a parameter whether this is a 32bit or 64bits instruction set
     pub fn new() -> Self {
         Self {
-            registers: [0; 32],
-            pc: 0x1000,
+            vm_state: VmState {
+                pc: 0x1000,
+                ..VmState::default()
+            },
         }
     }
 
-    /// returns a value at the register index `register_index`
-    pub fn get_register_value(&self, register_index: usize) -> i32 {
-        self.registers[register_index] as i32
+    /// the registers and the pc underneath
+    pub fn vm_state(&self) -> &VmState {
+        &self.vm_state
     }
 
-    /// ADD - ADD
-    pub fn add(&mut self, rd: usize, rs1: usize, rs2: usize) {
-        if rd == 0 {
-            return;
-        }
-
-        let sum = self.registers[rs1].wrapping_add(self.registers[rs2]);
-        self.registers[rd] = sum;
+    /// returns the value of `register`
+    pub fn get_register_value(&self, register: Register) -> i32 {
+        self.vm_state.registers[register]
     }
 
-    /// ADDI - ADD Immediate
-    pub fn addi(&mut self, rd: usize, rs1: usize, imm: i32) {
-        if rd == 0 {
-            return;
-        }
+    /// ADD - ADD
+    pub fn add(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        Rv32iInstruction::rv32i_instruction_add(
+            &DestinationSource1Source2 { rd, rs1, rs2 },
+            &mut self.vm_state,
+        );
+    }
 
-        let sum = self.registers[rs1].wrapping_add(imm as u32);
-        self.registers[rd] = sum;
+    /// ADDI - ADD Immediate, `imm` is 12 bits
+    pub fn addi(&mut self, rd: Register, rs1: Register, imm: i32) {
+        Rv32iInstruction::rv32i_instruction_addi(
+            &DestinationSource1Immediate {
+                rd,
+                rs1,
+                imm: imm as i16,
+            },
+            &mut self.vm_state,
+        );
     }
 
     /// SUB - SUBtract
-    pub fn sub(&mut self, rd: usize, rs1: usize, rs2: usize) {
-        if rd == 0 {
-            return;
-        }
-
-        let sum = self.registers[rs1].wrapping_sub(self.registers[rs2]);
-        self.registers[rd] = sum;
+    pub fn sub(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        Rv32iInstruction::rv32i_instruction_sub(
+            &DestinationSource1Source2 { rd, rs1, rs2 },
+            &mut self.vm_state,
+        );
     }
 
     /// LUI -  Load Upper Imm
-    pub fn lui(&mut self, rd: usize, imm: i32) {
-        if rd == 0 {
-            return;
-        }
-
-        // shift the bits by 12 bits
-        let imm_as_bits = imm << 12;
-
-        self.registers[rd] = imm_as_bits as u32;
+    pub fn lui(&mut self, rd: Register, imm: i32) {
+        Rv32iInstruction::rv32i_instruction_lui(
+            &DestinationImmediate { rd, imm },
+            &mut self.vm_state,
+        );
     }
 
     /// AUIPC - Add Upper Imm to PC
-    pub fn auipc(&mut self, rd: usize, imm: i32) {
-        if rd == 0 {
-            return;
-        }
-
-        // shift the bits by 12 bits
-        let imm_as_bits = imm << 12;
-
-        self.registers[rd] = self.pc + imm_as_bits as u32;
+    pub fn auipc(&mut self, rd: Register, imm: i32) {
+        Rv32iInstruction::rv32i_instruction_auipc(
+            &DestinationImmediate { rd, imm },
+            &mut self.vm_state,
+        );
     }
 }
 
@@ -220,9 +1114,13 @@ mod test {
     use super::Emulator;
     use super::Instruction;
     use super::PseudoInstruction;
+    use super::Register;
+    use super::StopReason;
 
     use super::Rv32iInstruction;
+    use super::TimingModel;
     use super::Vm;
+    use super::VmError;
     use super::VmState;
 
     #[test]
@@ -256,41 +1154,52 @@ mod test {
         let instructions = vec![
             Instruction::PseudoInstruction(
                 0x1000,
-                PseudoInstruction::Li(DestinationImmediate { rd: 13, imm: 10 }),
+                PseudoInstruction::Li(DestinationImmediate {
+                    rd: Register::A3,
+                    imm: 10,
+                }),
             ),
             Instruction::PseudoInstruction(
                 0x1004,
-                PseudoInstruction::Li(DestinationImmediate { rd: 12, imm: 15 }),
+                PseudoInstruction::Li(DestinationImmediate {
+                    rd: Register::A2,
+                    imm: 15,
+                }),
             ),
             Instruction::Rv32iInstruction(
                 0x1008,
+                // in the real binary the li's are 2 bytes, here every
+                // instruction is 4 bytes so the offset is 8 to skip `li a2,25`
                 Rv32iInstruction::Bltu(Source1Source2Immediate {
-                    rs1: 10,
-                    rs2: 11,
-                    imm: 0x10,
+                    rs1: Register::A0,
+                    rs2: Register::A3,
+                    imm: 0x8,
                 }),
             ),
             Instruction::PseudoInstruction(
                 0x100c,
-                PseudoInstruction::Li(DestinationImmediate { rd: 12, imm: 25 }),
+                PseudoInstruction::Li(DestinationImmediate {
+                    rd: Register::A2,
+                    imm: 25,
+                }),
             ),
             Instruction::Rv32iInstruction(
                 0x1010,
                 Rv32iInstruction::Add(DestinationSource1Source2 {
-                    rd: 10,
-                    rs1: 10,
-                    rs2: 11,
+                    rd: Register::A0,
+                    rs1: Register::A0,
+                    rs2: Register::A1,
                 }),
             ),
             Instruction::Rv32iInstruction(
                 0x1014,
                 Rv32iInstruction::Add(DestinationSource1Source2 {
-                    rd: 10,
-                    rs1: 10,
-                    rs2: 12,
+                    rd: Register::A0,
+                    rs1: Register::A0,
+                    rs2: Register::A2,
                 }),
             ),
-            Instruction::PseudoInstruction(0x1000, PseudoInstruction::Ret),
+            Instruction::PseudoInstruction(0x1018, PseudoInstruction::Ret),
         ];
 
         // this is our new fresh registers
@@ -300,20 +1209,23 @@ mod test {
         initial_registers[10] = 50;
         initial_registers[11] = 55;
 
+        // where the caller wants us to return to
+        initial_registers[1] = 0x2000;
+
         //
         let vm_state = VmState {
-            registers: initial_registers,
+            registers: initial_registers.into(),
             pc: 0x1000,
-            sp: 0,
         };
 
-        let mut vm = Vm { vm_state };
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.vm_state = vm_state;
 
-        let _ = vm.execute_instructions(instructions);
+        assert_eq!(vm.execute_instructions(instructions), Ok(None));
 
         let expected_vm_registers_state = [
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 130, 55, 25, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0,
+            0, 0x2000, 0, 0, 0, 0, 0, 0, 0, 0, 130, 55, 25, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0,
         ];
 
         let return_value = vm.vm_state.registers[10];
@@ -322,8 +1234,229 @@ mod test {
         // assert register state
         assert_eq!(vm.vm_state.registers, expected_vm_registers_state);
 
-        // assert program counter
-        assert_eq!(vm.vm_state.pc, 4140);
+        // assert program counter, 50 < 10 is false so the branch was not
+        // taken, and ret went back to the caller
+        assert_eq!(vm.vm_state.pc, 0x2000);
+    }
+
+    #[test]
+    fn execute_instructions_should_stop_a_branch_to_itself() {
+        // 0x1000 beq zero, zero, 0
+        let branch = || {
+            vec![Instruction::Rv32iInstruction(
+                0x1000,
+                Rv32iInstruction::Beq(Source1Source2Immediate {
+                    rs1: Register::ZERO,
+                    rs2: Register::ZERO,
+                    imm: 0,
+                }),
+            )]
+        };
+
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.vm_state.pc = 0x1000;
+        vm.execution_limit = Some(10);
+        assert_eq!(
+            vm.execute_instructions(branch()),
+            Err(VmError::ExecutionLimitExceeded {
+                pc: 0x1000,
+                instruction: 0,
+                limit: 10
+            })
+        );
+        assert_eq!(vm.stats.instructions_retired, 10);
+
+        // without a limit the budget ends it
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.vm_state.pc = 0x1000;
+        assert!(matches!(
+            vm.execute_instructions(branch()),
+            Err(VmError::ExecutionLimitExceeded {
+                limit: super::INSTRUCTION_BUDGET,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn should_call_a_function_and_return_to_the_caller() {
+        // 0x1000 jal ra, 0x100c       call f
+        // 0x1004 addi a1, a0, 0       use the return value
+        // 0x1008 ebreak
+        // 0x100c addi a0, zero, 42    f: return 42
+        // 0x1010 ret
+        let program: Vec<u8> = [
+            0x00c0_00efu32,
+            0x0005_0593,
+            0x0010_0073,
+            0x02a0_0513,
+            0x0000_8067,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(vm.run().unwrap(), StopReason::Ebreak);
+
+        // ra points right after the call
+        assert_eq!(vm.vm_state.registers[1], 0x1004);
+        assert_eq!(vm.vm_state.registers[10], 42);
+        assert_eq!(vm.vm_state.registers[11], 42);
+        assert_eq!(vm.vm_state.pc, 0x1008);
+        assert_eq!(vm.stats.instructions_retired, 4);
+    }
+
+    #[test]
+    fn should_fetch_decode_and_execute_from_memory() {
+        // addi a3, zero, 10
+        // addi a2, zero, 15
+        // add a0, a0, a1
+        // lui a4, 0xfffff
+        let program: Vec<u8> = [0x00a0_0693u32, 0x00f0_0613, 0x00b5_0533, 0xffff_f737]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.registers.write(Register::A0, 50);
+        vm.vm_state.registers.write(Register::A1, 55);
+
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.vm_state.registers[13], 10);
+        assert_eq!(vm.vm_state.registers[12], 15);
+        assert_eq!(vm.vm_state.registers[10], 105);
+        assert_eq!(vm.vm_state.registers[14], 0xffff_f000u32 as i32);
+        assert_eq!(vm.vm_state.pc, 0x1010);
+
+        // running off the end of the memory is an error, not a panic
+        vm.vm_state.pc = 0x1100;
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn should_run_until_ebreak_and_summarize_the_run() {
+        // addi sp, sp, -16
+        // addi a0, zero, 3
+        // addi a0, a0, -1
+        // addi sp, sp, 16
+        // ebreak
+        let program: Vec<u8> = [
+            0xff01_0113u32,
+            0x0030_0513,
+            0xfff5_0513,
+            0x0101_0113,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.set_sp(0x1100);
+
+        assert_eq!(vm.run().unwrap(), StopReason::Ebreak);
+        assert_eq!(vm.vm_state.registers[10], 2);
+        assert_eq!(vm.stats.instructions_retired, 4);
+        assert_eq!(vm.stats.peak_stack(), 16);
+
+        let summary = vm.summary();
+        assert!(summary.contains("exit:                 Ebreak"));
+        assert!(summary.contains("instructions retired: 4"));
+        // a tick of mtime per cycle, at 10 MHz
+        assert!(summary.contains("guest time:           400ns"));
+        assert!(summary.contains("host time:"));
+        assert!(summary.contains("peak stack:           16 bytes"));
+        assert!(summary.contains("ebreak"));
+    }
+
+    #[test]
+    fn faults_should_carry_the_pc_and_the_instruction() {
+        // addi a0, zero, 1 then garbage
+        let mut vm = Vm::with_program_words(0x1000, &[0x0010_0513, 0xffff_ffff]);
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::IllegalInstruction {
+                pc: 0x1004,
+                instruction: 0xffff_ffff
+            })
+        );
+        assert_eq!(vm.stats.trap_counts["illegal instruction"], 1);
+
+        // ecall decodes fine but there is nothing to handle it yet, the
+        // error is not swallowed and the pc stays on it
+        let mut vm = Vm::with_program_words(0x1000, &[0x0000_0073]);
+        assert_eq!(
+            vm.step(),
+            Err(VmError::NotImplemented {
+                pc: 0x1000,
+                instruction: 0x0000_0073
+            })
+        );
+        assert_eq!(vm.vm_state.pc, 0x1000);
+    }
+
+    #[test]
+    fn jumping_to_a_misaligned_address_should_fault() {
+        // jal zero, +2
+        let mut vm = Vm::with_program_words(0x1000, &[0x0020_006f]);
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::MisalignedAccess {
+                pc: 0x1002,
+                instruction: 0,
+                address: 0x1002
+            })
+        );
+    }
+
+    #[test]
+    fn run_should_stop_at_the_execution_limit() {
+        // jal zero, 0 loops forever
+        let mut vm = Vm::with_program_words(0x1000, &[0x0000_006f]);
+        vm.execution_limit = Some(10);
+
+        let error = vm.run().unwrap_err();
+
+        assert_eq!(
+            error,
+            VmError::ExecutionLimitExceeded {
+                pc: 0x1000,
+                instruction: 0x6f,
+                limit: 10
+            }
+        );
+        assert_eq!(error.pc(), 0x1000);
+        assert_eq!(vm.stats.instructions_retired, 10);
+    }
+
+    #[test]
+    fn should_charge_fetches_to_their_memory_region() {
+        // addi a0, zero, 1 twice from "flash", then ebreak
+        let program: Vec<u8> = [0x0010_0513u32, 0x0010_0513, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100)
+            .with_timing(TimingModel::new().with_region("flash", 0x1000, 0x80, 3));
+        vm.load_program(0x1000, &program).unwrap();
+        vm.run().unwrap();
+
+        let timing = vm.timing.as_ref().unwrap();
+        assert_eq!(timing.cycles, 6);
+        assert!(vm
+            .summary()
+            .contains("flash                    2 accesses, 6 cycles"));
     }
 
     #[test]
@@ -331,17 +1464,37 @@ mod test {
         let vm_state = VmState::default();
 
         assert_eq!(vm_state.registers, [0; 32]);
-        assert_eq!(vm_state.sp, 0);
+        assert_eq!(vm_state.sp(), 0);
         assert_eq!(vm_state.pc, 1000);
     }
 
+    #[test]
+    fn sp_ra_and_gp_should_be_views_of_their_registers() {
+        // 0x1000 addi sp, sp, -16
+        // 0x1004 jal ra, 4
+        // 0x1008 lui gp, 0x2
+        let mut vm = Vm::with_program_words(0x1000, &[0xff01_0113, 0x0040_00ef, 0x0000_21b7]);
+        vm.vm_state.set_sp(0x1100);
+        assert_eq!(vm.vm_state.registers[2], 0x1100);
+
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.vm_state.sp(), 0x10f0);
+        assert_eq!(vm.vm_state.ra(), 0x1008);
+        assert_eq!(vm.vm_state.gp(), 0x2000);
+
+        vm.vm_state.set_ra(0);
+        assert_eq!(vm.vm_state.registers[Register::RA], 0);
+    }
+
     #[test]
     fn lui_should_work_correctly() {
         let mut emulator = Emulator::new();
 
         // 20 bits
         // 0000 0000 0000 0000 0001
-        let register_index = 1;
+        let register_index = Register::RA;
 
         // 32 bits
         // 0000 0000 0000 0000 0001 0000 0000 0000
@@ -356,4 +1509,35 @@ mod test {
             0b0001_0000_0000_0000
         );
     }
+
+    #[test]
+    fn the_emulator_facade_should_match_the_vm() {
+        let mut emulator = Emulator::new();
+        emulator.addi(Register::A0, Register::ZERO, -5);
+        emulator.addi(Register::A1, Register::ZERO, 7);
+        emulator.add(Register::A2, Register::A0, Register::A1);
+        emulator.sub(Register::A3, Register::A0, Register::A1);
+        emulator.auipc(Register::A4, 1);
+        emulator.addi(Register::ZERO, Register::A1, 1);
+
+        // 0x1000 addi a0, zero, -5
+        // 0x1004 addi a1, zero, 7
+        // 0x1008 add a2, a0, a1
+        // 0x100c sub a3, a0, a1
+        let mut vm = Vm::with_program_words(
+            0x1000,
+            &[0xffb0_0513, 0x0070_0593, 0x00b5_0633, 0x40b5_06b3],
+        );
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(
+            emulator.vm_state().registers.as_array()[..14],
+            vm.vm_state.registers.as_array()[..14]
+        );
+        // the facade never moves its pc, auipc sees 0x1000
+        assert_eq!(emulator.get_register_value(Register::A4), 0x2000);
+        assert_eq!(emulator.get_register_value(Register::ZERO), 0);
+    }
 }
@@ -0,0 +1,303 @@
+//! The way back from `Rv32iInstruction` to the instruction word, for tests
+//! and for tools that write guest code. `encode(decode(word))` gives `word`
+//! again, up to the bits the decoder ignores, see `canonical`.
+
+use super::instruction_signatures::{
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+    Source1Source2Immediate,
+};
+use super::rv32i::Rv32iInstruction;
+use super::vector::{VectorInstruction, VectorOperand, VectorOperation};
+
+/// the opcode of the A extension
+const OPCODE_AMO: u32 = 0x2f;
+/// aq and rl of the A extension
+const AMO_ORDERING: u32 = 0b11 << 25;
+const OPCODE_MISC_MEM: u32 = 0x0f;
+
+/// `word` with the bits the decoder ignores cleared, what `encode` gives
+/// back for it: the aq and rl bits of the A extension (every instruction is
+/// ordered in this vm) and everything but the opcode and funct3 of
+/// `fence.i` (reserved by the spec)
+pub fn canonical(word: u32) -> u32 {
+    match (word & 0x7f, word >> 12 & 0b111) {
+        (OPCODE_AMO, _) => word & !AMO_ORDERING,
+        (OPCODE_MISC_MEM, 0b001) => word & 0x707f,
+        _ => word,
+    }
+}
+
+fn r(opcode: u32, funct3: u32, funct7: u32, operands: &DestinationSource1Source2) -> u32 {
+    funct7 << 25
+        | (operands.rs2.index() as u32) << 20
+        | (operands.rs1.index() as u32) << 15
+        | funct3 << 12
+        | (operands.rd.index() as u32) << 7
+        | opcode
+}
+
+fn i(opcode: u32, funct3: u32, operands: &DestinationSource1Immediate) -> u32 {
+    (operands.imm as u32 & 0xfff) << 20
+        | (operands.rs1.index() as u32) << 15
+        | funct3 << 12
+        | (operands.rd.index() as u32) << 7
+        | opcode
+}
+
+/// slli, srli and srai: the shift amount and funct7 share the immediate
+fn shift(funct3: u32, funct7: u32, operands: &DestinationSource1Immediate) -> u32 {
+    i(
+        0x13,
+        funct3,
+        &DestinationSource1Immediate {
+            imm: (funct7 << 5 | (operands.imm as u32 & 0x1f)) as i16,
+            ..*operands
+        },
+    )
+}
+
+fn s(funct3: u32, operands: &Source1Source2Immediate) -> u32 {
+    let imm = operands.imm as u32;
+    (imm >> 5 & 0x7f) << 25
+        | (operands.rs2.index() as u32) << 20
+        | (operands.rs1.index() as u32) << 15
+        | funct3 << 12
+        | (imm & 0x1f) << 7
+        | 0x23
+}
+
+fn b(funct3: u32, operands: &Source1Source2Immediate) -> u32 {
+    let imm = operands.imm as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | (operands.rs2.index() as u32) << 20
+        | (operands.rs1.index() as u32) << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | 0x63
+}
+
+fn u(opcode: u32, operands: &DestinationImmediate) -> u32 {
+    (operands.imm as u32) << 12 | (operands.rd.index() as u32) << 7 | opcode
+}
+
+fn j(operands: &DestinationImmediate) -> u32 {
+    let imm = operands.imm as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | (operands.rd.index() as u32) << 7
+        | 0x6f
+}
+
+/// an A extension instruction with aq and rl 0
+fn amo(funct5: u32, operands: &DestinationSource1Source2) -> u32 {
+    r(OPCODE_AMO, 0b010, funct5 << 2, operands)
+}
+
+impl Rv32iInstruction {
+    /// the instruction word, see `canonical`
+    pub fn encode(&self) -> u32 {
+        match self {
+            Self::Add(o) => r(0x33, 0b000, 0, o),
+            Self::Sub(o) => r(0x33, 0b000, 0b010_0000, o),
+            Self::Sll(o) => r(0x33, 0b001, 0, o),
+            Self::Slt(o) => r(0x33, 0b010, 0, o),
+            Self::Sltu(o) => r(0x33, 0b011, 0, o),
+            Self::Xor(o) => r(0x33, 0b100, 0, o),
+            Self::Srl(o) => r(0x33, 0b101, 0, o),
+            Self::Sra(o) => r(0x33, 0b101, 0b010_0000, o),
+            Self::Or(o) => r(0x33, 0b110, 0, o),
+            Self::And(o) => r(0x33, 0b111, 0, o),
+
+            Self::Addi(o) => i(0x13, 0b000, o),
+            Self::Slti(o) => i(0x13, 0b010, o),
+            Self::Sltiu(o) => i(0x13, 0b011, o),
+            Self::Xori(o) => i(0x13, 0b100, o),
+            Self::Ori(o) => i(0x13, 0b110, o),
+            Self::Andi(o) => i(0x13, 0b111, o),
+            Self::Slli(o) => shift(0b001, 0, o),
+            Self::Srli(o) => shift(0b101, 0, o),
+            Self::Srai(o) => shift(0b101, 0b010_0000, o),
+
+            Self::Lb(o) => i(0x03, 0b000, o),
+            Self::Lh(o) => i(0x03, 0b001, o),
+            Self::Lw(o) => i(0x03, 0b010, o),
+            Self::Lbu(o) => i(0x03, 0b100, o),
+            Self::Lhu(o) => i(0x03, 0b101, o),
+            Self::Sb(o) => s(0b000, o),
+            Self::Sh(o) => s(0b001, o),
+            Self::Sw(o) => s(0b010, o),
+
+            Self::Beq(o) => b(0b000, o),
+            Self::Bne(o) => b(0b001, o),
+            Self::Blt(o) => b(0b100, o),
+            Self::Bge(o) => b(0b101, o),
+            Self::Bltu(o) => b(0b110, o),
+            Self::Bgeu(o) => b(0b111, o),
+            Self::Jal(o) => j(o),
+            Self::Jalr(o) => i(0x67, 0b000, o),
+            Self::Lui(o) => u(0x37, o),
+            Self::Auipc(o) => u(0x17, o),
+
+            Self::Ecall => 0x0000_0073,
+            Self::Ebreak => 0x0010_0073,
+            Self::Mret => 0x3020_0073,
+            Self::Sret => 0x1020_0073,
+            Self::Wfi => 0x1050_0073,
+            Self::Fence(o) => i(OPCODE_MISC_MEM, 0b000, o),
+            Self::FenceI => 0x0000_100f,
+            Self::SfenceVma(o) => r(0x73, 0b000, 0b000_1001, o),
+
+            Self::Csrrw(o) => i(0x73, 0b001, o),
+            Self::Csrrs(o) => i(0x73, 0b010, o),
+            Self::Csrrc(o) => i(0x73, 0b011, o),
+            Self::Csrrwi(o) => i(0x73, 0b101, o),
+            Self::Csrrsi(o) => i(0x73, 0b110, o),
+            Self::Csrrci(o) => i(0x73, 0b111, o),
+
+            Self::LrW(o) => amo(0b00010, o),
+            Self::ScW(o) => amo(0b00011, o),
+            Self::AmoswapW(o) => amo(0b00001, o),
+            Self::AmoaddW(o) => amo(0b00000, o),
+            Self::AmoxorW(o) => amo(0b00100, o),
+            Self::AmoandW(o) => amo(0b01100, o),
+            Self::AmoorW(o) => amo(0b01000, o),
+            Self::AmominW(o) => amo(0b10000, o),
+            Self::AmomaxW(o) => amo(0b10100, o),
+            Self::AmominuW(o) => amo(0b11000, o),
+            Self::AmomaxuW(o) => amo(0b11100, o),
+
+            Self::Vector(vector) => vector.encode(),
+            Self::Custom(word) => *word,
+        }
+    }
+}
+
+impl VectorInstruction {
+    /// the instruction word
+    pub fn encode(&self) -> u32 {
+        let vm = |masked: bool| u32::from(!masked) << 25;
+        match *self {
+            Self::Vsetvli { rd, rs1, vtype } => {
+                (vtype & 0x7ff) << 20
+                    | (rs1.index() as u32) << 15
+                    | 0b111 << 12
+                    | (rd.index() as u32) << 7
+                    | 0x57
+            }
+            Self::Vsetivli { rd, avl, vtype } => {
+                0b11 << 30
+                    | (vtype & 0x3ff) << 20
+                    | (avl & 0x1f) << 15
+                    | 0b111 << 12
+                    | (rd.index() as u32) << 7
+                    | 0x57
+            }
+            Self::Load {
+                vd: register,
+                rs1,
+                width,
+                masked,
+            }
+            | Self::Store {
+                vs3: register,
+                rs1,
+                width,
+                masked,
+            } => {
+                let opcode = match self {
+                    Self::Load { .. } => 0x07,
+                    _ => 0x27,
+                };
+                let funct3 = match width {
+                    8 => 0b000,
+                    16 => 0b101,
+                    32 => 0b110,
+                    _ => 0b111,
+                };
+                vm(masked)
+                    | (rs1.index() as u32) << 15
+                    | funct3 << 12
+                    | u32::from(register) << 7
+                    | opcode
+            }
+            Self::Arithmetic {
+                operation,
+                vd,
+                vs2,
+                operand,
+                masked,
+            } => {
+                let funct6 = match operation {
+                    VectorOperation::Add => 0b000000,
+                    VectorOperation::Sub => 0b000010,
+                    VectorOperation::Mul => 0b100101,
+                };
+                let multiply = operation == VectorOperation::Mul;
+                let (funct3, rs1) = match operand {
+                    VectorOperand::Vector(vs1) if multiply => (0b010, u32::from(vs1)),
+                    VectorOperand::Vector(vs1) => (0b000, u32::from(vs1)),
+                    VectorOperand::Scalar(rs1) if multiply => (0b110, rs1.index() as u32),
+                    VectorOperand::Scalar(rs1) => (0b100, rs1.index() as u32),
+                    VectorOperand::Immediate(imm) => (0b011, imm as u32 & 0x1f),
+                };
+                funct6 << 26
+                    | vm(masked)
+                    | u32::from(vs2) << 20
+                    | rs1 << 15
+                    | funct3 << 12
+                    | u32::from(vd) << 7
+                    | 0x57
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonical;
+    use crate::Rv32iInstruction;
+
+    #[test]
+    fn decoding_and_encoding_should_round_trip() {
+        // every opcode and funct3 with pseudo-random other bits (xorshift)
+        let mut state = 0x2545_f491u32;
+        let mut decoded = 0;
+        for opcode_and_funct3 in 0..(1 << 10) {
+            for _ in 0..64 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let low = opcode_and_funct3 & 0x7f | (opcode_and_funct3 >> 7) << 12;
+                let word = state & !0x707f | low;
+                if let Ok(instruction) =
+                    Rv32iInstruction::from_core_instruction_format(word.to_le_bytes())
+                {
+                    assert_eq!(
+                        instruction.encode(),
+                        canonical(word),
+                        "{word:#010x} decoded to {instruction:?}"
+                    );
+                    decoded += 1;
+                }
+            }
+        }
+        assert!(decoded > 5000, "only {decoded} words decoded");
+
+        // fields at their edges
+        for word in [
+            0xfff0_0513u32,
+            0x8000_0063,
+            0x7ff0_0fe3,
+            0x8000_006f,
+            0xffff_f037,
+        ] {
+            let instruction =
+                Rv32iInstruction::from_core_instruction_format(word.to_le_bytes()).unwrap();
+            assert_eq!(instruction.encode(), word);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use thiserror::Error;
+
+// thiserror takes a field of a type named `Backtrace` for a
+// `std::backtrace::Backtrace`
+use super::call_stack::Backtrace as GuestBacktrace;
+use super::instruction_formats::InstructionFormat;
+use super::limits::Limit;
+use super::prelude::*;
+
+/// what a faulting access was for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessKind {
+    /// the `mcause` of the access fault
+    pub fn cause(self) -> u32 {
+        match self {
+            Self::Fetch => 1,
+            Self::Load => 5,
+            Self::Store => 7,
+        }
+    }
+
+    /// the `mcause` of the page fault
+    pub fn page_fault_cause(self) -> u32 {
+        match self {
+            Self::Fetch => 12,
+            Self::Load => 13,
+            Self::Store => 15,
+        }
+    }
+}
+
+/// Everything that can go wrong while the vm runs the guest. Every fault
+/// carries the pc and the raw word of the instruction that caused it. Memory
+/// accesses done by the host (e.g. `vm.memory.write_u32`) are not tied to an
+/// instruction, their pc and instruction are 0.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum VmError {
+    #[error(
+        "illegal instruction {instruction:#010x} at pc {pc:#010x} ({})",
+        InstructionFormat::closest(*.instruction).name()
+    )]
+    IllegalInstruction { pc: u32, instruction: u32 },
+
+    #[error("instruction {instruction:#010x} at pc {pc:#010x} is not implemented yet")]
+    NotImplemented { pc: u32, instruction: u32 },
+
+    #[error(
+        "memory access at {address:#010x} is out of bounds (pc {pc:#010x}, instruction {instruction:#010x})"
+    )]
+    MemoryOutOfBounds {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+    },
+
+    /// an access the memory map does not allow: a guard region, or a store
+    /// to ROM
+    #[error(
+        "{kind:?} access fault at {address:#010x} (pc {pc:#010x}, instruction {instruction:#010x})"
+    )]
+    AccessFault {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+        kind: AccessKind,
+    },
+
+    #[error(
+        "misaligned access at {address:#010x} (pc {pc:#010x}, instruction {instruction:#010x})"
+    )]
+    MisalignedAccess {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+    },
+
+    /// a virtual address the page tables do not map, or not for `kind`, see
+    /// `mmu.rs`. The guest always handles these
+    #[error(
+        "{kind:?} page fault at {address:#010x} (pc {pc:#010x}, instruction {instruction:#010x})"
+    )]
+    PageFault {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+        kind: AccessKind,
+    },
+
+    /// an access to an MMIO region that has no device behind it
+    #[error(
+        "no device is mapped at {address:#010x} (pc {pc:#010x}, instruction {instruction:#010x})"
+    )]
+    UnmappedMmio {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+    },
+
+    #[error("execution limit of {limit} instructions exceeded at pc {pc:#010x}")]
+    ExecutionLimitExceeded {
+        pc: u32,
+        instruction: u32,
+        limit: u64,
+    },
+
+    /// a limit of `VmLimits` besides the instruction count, see `limits.rs`
+    #[error("{limit} exceeded at pc {pc:#010x}")]
+    LimitExceeded {
+        pc: u32,
+        instruction: u32,
+        limit: Limit,
+    },
+
+    /// sp went below the stack limit, or a store went into the guard under
+    /// it, see `stack_limit.rs`. `address` is sp or where the store went,
+    /// `calls` the backtrace (thiserror has its own idea of a field named
+    /// `backtrace`)
+    #[error(
+        "stack overflow at {address:#010x}, the stack ends at {limit:#010x} (pc {pc:#010x}, instruction {instruction:#010x})\n{}",
+        .calls.to_string().trim_end()
+    )]
+    StackOverflow {
+        pc: u32,
+        instruction: u32,
+        address: u32,
+        limit: u32,
+        calls: GuestBacktrace,
+    },
+}
+
+impl VmError {
+    /// the pc of the instruction that faulted
+    pub fn pc(&self) -> u32 {
+        match self {
+            Self::IllegalInstruction { pc, .. }
+            | Self::NotImplemented { pc, .. }
+            | Self::MemoryOutOfBounds { pc, .. }
+            | Self::AccessFault { pc, .. }
+            | Self::MisalignedAccess { pc, .. }
+            | Self::PageFault { pc, .. }
+            | Self::UnmappedMmio { pc, .. }
+            | Self::ExecutionLimitExceeded { pc, .. }
+            | Self::LimitExceeded { pc, .. }
+            | Self::StackOverflow { pc, .. } => *pc,
+        }
+    }
+
+    /// the raw word of the instruction that faulted
+    pub fn instruction(&self) -> u32 {
+        match self {
+            Self::IllegalInstruction { instruction, .. }
+            | Self::NotImplemented { instruction, .. }
+            | Self::MemoryOutOfBounds { instruction, .. }
+            | Self::AccessFault { instruction, .. }
+            | Self::MisalignedAccess { instruction, .. }
+            | Self::PageFault { instruction, .. }
+            | Self::UnmappedMmio { instruction, .. }
+            | Self::ExecutionLimitExceeded { instruction, .. }
+            | Self::LimitExceeded { instruction, .. }
+            | Self::StackOverflow { instruction, .. } => *instruction,
+        }
+    }
+
+    /// the format an illegal instruction is closest to, see
+    /// `InstructionFormat::closest`
+    pub fn closest_format(&self) -> Option<InstructionFormat> {
+        match self {
+            Self::IllegalInstruction { instruction, .. } => {
+                Some(InstructionFormat::closest(*instruction))
+            }
+            _ => None,
+        }
+    }
+
+    /// fills in where the fault happened. Memory and the decoder do not know
+    /// which instruction they are working for, the vm adds that on the way up
+    pub(crate) fn at(mut self, at_pc: u32, at_instruction: u32) -> Self {
+        match &mut self {
+            Self::IllegalInstruction { pc, instruction }
+            | Self::NotImplemented { pc, instruction }
+            | Self::MemoryOutOfBounds {
+                pc, instruction, ..
+            }
+            | Self::AccessFault {
+                pc, instruction, ..
+            }
+            | Self::MisalignedAccess {
+                pc, instruction, ..
+            }
+            | Self::PageFault {
+                pc, instruction, ..
+            }
+            | Self::UnmappedMmio {
+                pc, instruction, ..
+            }
+            | Self::ExecutionLimitExceeded {
+                pc, instruction, ..
+            }
+            | Self::LimitExceeded {
+                pc, instruction, ..
+            }
+            | Self::StackOverflow {
+                pc, instruction, ..
+            } => {
+                *pc = at_pc;
+                *instruction = at_instruction;
+            }
+        }
+        self
+    }
+
+    /// short name of the error, used to count traps in the run summary
+    pub(crate) fn trap_name(&self) -> &'static str {
+        match self {
+            Self::IllegalInstruction { .. } => "illegal instruction",
+            Self::NotImplemented { .. } => "not implemented",
+            Self::MemoryOutOfBounds { .. } => "memory out of bounds",
+            Self::AccessFault { .. } => "access fault",
+            Self::MisalignedAccess { .. } => "misaligned access",
+            Self::PageFault { .. } => "page fault",
+            Self::UnmappedMmio { .. } => "unmapped mmio",
+            Self::ExecutionLimitExceeded { .. } => "execution limit",
+            Self::LimitExceeded { .. } => "resource limit",
+            Self::StackOverflow { .. } => "stack overflow",
+        }
+    }
+}
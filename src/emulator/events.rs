@@ -0,0 +1,174 @@
+//! What the guest did, as a list of events a web frontend can replay without
+//! running the vm again. Every event is a JSON object with an `event` field
+//! for its kind; the schema only ever gains fields, `SCHEMA_VERSION` changes
+//! when one goes away or changes meaning:
+//!
+//! ```json
+//! {"event":"instruction","pc":4096,"text":"addi a0, zero, 5"}
+//! {"event":"register_write","pc":4096,"register":"a0","value":5}
+//! {"event":"memory_write","pc":4100,"address":4224,"size":4,"value":5}
+//! {"event":"trap","pc":4104,"cause":11,"value":0}
+//! ```
+//!
+//! An `instruction` comes before the events it caused. If the instruction
+//! faults, it is the last event.
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+
+use super::emulator::{Instruction, StopReason, Vm, VmState};
+use super::error::VmError;
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::register::Register;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum VmEvent {
+    /// the instruction at `pc` runs, `text` is its disassembly
+    Instruction { pc: u32, text: String },
+    /// the instruction at `pc` wrote `value` to the register with the ABI
+    /// name `register`
+    RegisterWrite {
+        pc: u32,
+        register: String,
+        value: i32,
+    },
+    /// the instruction at `pc` stored `size` bytes
+    MemoryWrite {
+        pc: u32,
+        address: u32,
+        size: u32,
+        value: u32,
+    },
+    /// the instruction at `pc` trapped into the guest's handler
+    Trap { pc: u32, cause: u32, value: u32 },
+}
+
+/// collects the events while the vm owns it
+struct EventRecorder(Rc<RefCell<Vec<VmEvent>>>);
+
+impl VmHooks for EventRecorder {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        self.0.borrow_mut().push(VmEvent::Instruction {
+            pc: vm_state.pc as u32,
+            text: instruction.to_string(),
+        });
+    }
+
+    fn on_memory_write(&mut self, pc: u32, address: u32, size: u32, value: u32) {
+        self.0.borrow_mut().push(VmEvent::MemoryWrite {
+            pc,
+            address,
+            size,
+            value,
+        });
+    }
+
+    fn on_register_write(&mut self, pc: u32, register: Register, value: i32) {
+        self.0.borrow_mut().push(VmEvent::RegisterWrite {
+            pc,
+            register: register.abi_name().to_string(),
+            value,
+        });
+    }
+
+    fn on_trap(&mut self, pc: u32, cause: u32, value: u32) {
+        self.0.borrow_mut().push(VmEvent::Trap { pc, cause, value });
+    }
+}
+
+impl Vm {
+    /// like `run()`, and returns everything the guest did on the way, also
+    /// when the run ends with an error
+    pub fn run_collect_events(&mut self) -> (Result<StopReason, VmError>, Vec<VmEvent>) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        self.add_hooks(Box::new(EventRecorder(events.clone())));
+        let result = self.run();
+        self.hooks.pop();
+        (result, events.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VmEvent;
+    use crate::ecall::EcallPolicy;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_collect_what_the_guest_did() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 0x14
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c sw t0, 0x80(t0)
+        // 0x1010 ecall
+        // 0x1014 ebreak                <- guest trap handler
+        let program: Vec<u8> = [
+            0x0000_0297u32,
+            0x0142_8293,
+            0x3052_9073,
+            0x0852_a023,
+            0x0000_0073,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_ecall_policy(EcallPolicy::bare_metal());
+        vm.load_program(0x1000, &program).unwrap();
+
+        let (result, events) = vm.run_collect_events();
+        assert_eq!(result, Ok(StopReason::Ebreak));
+        let instruction = |pc: u32, text: &str| VmEvent::Instruction {
+            pc,
+            text: text.to_string(),
+        };
+        let t0 = |pc, value| VmEvent::RegisterWrite {
+            pc,
+            register: "t0".to_string(),
+            value,
+        };
+        assert_eq!(
+            events,
+            vec![
+                instruction(0x1000, "auipc t0, 0x0"),
+                t0(0x1000, 0x1000),
+                instruction(0x1004, "addi t0, t0, 20"),
+                t0(0x1004, 0x1014),
+                instruction(0x1008, "csrrw zero, 0x305, t0"),
+                instruction(0x100c, "sw t0, 128(t0)"),
+                VmEvent::MemoryWrite {
+                    pc: 0x100c,
+                    address: 0x1094,
+                    size: 4,
+                    value: 0x1014,
+                },
+                instruction(0x1010, "ecall"),
+                VmEvent::Trap {
+                    pc: 0x1010,
+                    cause: 11,
+                    value: 0,
+                },
+            ]
+        );
+        // the hooks are gone again
+        assert!(vm.run_collect_events().1.is_empty());
+    }
+
+    #[test]
+    fn events_should_keep_their_json_schema() {
+        let event = VmEvent::RegisterWrite {
+            pc: 0x1000,
+            register: "a0".to_string(),
+            value: -1,
+        };
+        let json = r#"{"event":"register_write","pc":4096,"register":"a0","value":-1}"#;
+        assert_eq!(serde_json::to_string(&event).unwrap(), json);
+        assert_eq!(serde_json::from_str::<VmEvent>(json).unwrap(), event);
+    }
+}
@@ -0,0 +1,715 @@
+//! ext2 for `DiskImage`, the filesystem a Linux guest mounts without extra
+//! drivers. Like the FAT side of `disk_image.rs` it only works on the root
+//! directory: files go into it and come out of it, subdirectories are left
+//! alone.
+//!
+//! `format` writes revision 1 with 4 KiB blocks in a single block group,
+//! which is images from 64 KiB to 128 MiB, with the root directory and
+//! `lost+found` of `mke2fs`. Images from `mke2fs` work too as long as they
+//! have one block group and no incompatible feature but the file type in
+//! directory entries, e.g. `mkfs.ext2 -b 4096 image 64M`.
+
+use super::disk_image::{DiskImageError, FileEntry, Filesystem};
+use super::prelude::*;
+
+/// the superblock is 1024 bytes into the image, whatever the block size
+const SUPERBLOCK_OFFSET: usize = 1024;
+const MAGIC: u16 = 0xef53;
+
+/// the block size `format` uses
+const BLOCK_SIZE: usize = 4096;
+const INODE_SIZE: usize = 128;
+/// the images `format` accepts, in blocks. One block bitmap covers a group
+const MIN_BLOCKS: usize = 16;
+const MAX_BLOCKS: usize = BLOCK_SIZE * 8;
+
+const ROOT_INODE: u32 = 2;
+/// the first inode that is not reserved, `lost+found` on a new image
+const FIRST_INODE: u32 = 11;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+
+/// directory entries have the file type after the name length
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+/// the features that do not change how the root directory and its files
+/// are stored: sparse superblocks and large files
+const RO_COMPAT_SUPPORTED: u32 = 0x1 | 0x2;
+/// the directory has a hash tree, which a linear entry would not be in
+const INODE_INDEX_FLAG: u32 = 0x1000;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const FILE_TYPE_FILE: u8 = 1;
+const FILE_TYPE_DIRECTORY: u8 = 2;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// the size of a directory entry with a name of `name_length` bytes
+fn entry_size(name_length: usize) -> usize {
+    (8 + name_length).next_multiple_of(4)
+}
+
+/// a name for the root directory: not empty, no `/` or NUL, at most 255
+/// bytes, and not `.` or `..`
+fn check_name(name: &str) -> Result<(), DiskImageError> {
+    if name.is_empty()
+        || name.len() > 255
+        || name.contains(['/', '\0'])
+        || name == "."
+        || name == ".."
+    {
+        return Err(DiskImageError::InvalidName {
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// writes an empty filesystem over `data`, `label` is cut to 16 bytes
+pub(super) fn format(data: &mut [u8], label: &str) -> Result<(), DiskImageError> {
+    let blocks = data.len() / BLOCK_SIZE;
+    if !(MIN_BLOCKS..=MAX_BLOCKS).contains(&blocks) {
+        return Err(DiskImageError::UnsupportedSize {
+            size: data.len(),
+            filesystem: Filesystem::Ext2,
+        });
+    }
+    // an inode per 16 KiB, in whole blocks of the inode table
+    let inodes_per_block = BLOCK_SIZE / INODE_SIZE;
+    let inodes = (blocks / 4)
+        .next_multiple_of(inodes_per_block)
+        .max(inodes_per_block);
+    let inode_table_blocks = inodes / inodes_per_block;
+    // the superblock, the group descriptor, the bitmaps, the inode table,
+    // then the blocks of the root directory and lost+found
+    let (block_bitmap, inode_bitmap, inode_table) = (2, 3, 4);
+    let root_block = inode_table + inode_table_blocks;
+    let lost_and_found_block = root_block + 1;
+    let used_blocks = lost_and_found_block + 1;
+    data.fill(0);
+
+    let superblock = &mut data[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024];
+    put_u32(superblock, 0, inodes as u32);
+    put_u32(superblock, 4, blocks as u32);
+    put_u32(superblock, 12, (blocks - used_blocks) as u32);
+    put_u32(superblock, 16, inodes as u32 - FIRST_INODE);
+    // the first data block is 0 with blocks bigger than 1 KiB, 4096 is
+    // 1024 << 2
+    put_u32(superblock, 24, 2);
+    put_u32(superblock, 28, 2);
+    put_u32(superblock, 32, MAX_BLOCKS as u32);
+    put_u32(superblock, 36, MAX_BLOCKS as u32);
+    put_u32(superblock, 40, inodes as u32);
+    // no mount count checks
+    put_u16(superblock, 54, 0xffff);
+    put_u16(superblock, 56, MAGIC);
+    // clean, continue on errors
+    put_u16(superblock, 58, 1);
+    put_u16(superblock, 60, 1);
+    put_u32(superblock, 76, 1);
+    put_u32(superblock, 84, FIRST_INODE);
+    put_u16(superblock, 88, INODE_SIZE as u16);
+    put_u32(superblock, 96, FEATURE_INCOMPAT_FILETYPE);
+    superblock[104..120].copy_from_slice(b"web-riscv-vmRVVM");
+    for (byte, character) in superblock[120..136].iter_mut().zip(label.bytes()) {
+        *byte = character;
+    }
+
+    let descriptor = BLOCK_SIZE;
+    put_u32(data, descriptor, block_bitmap as u32);
+    put_u32(data, descriptor + 4, inode_bitmap as u32);
+    put_u32(data, descriptor + 8, inode_table as u32);
+    put_u16(data, descriptor + 12, (blocks - used_blocks) as u16);
+    put_u16(
+        data,
+        descriptor + 14,
+        (inodes - FIRST_INODE as usize) as u16,
+    );
+    put_u16(data, descriptor + 16, 2);
+
+    // the bits past the end of the group count as used
+    let bitmap = |data: &mut [u8], block: usize, used: usize, count: usize| {
+        let bits = &mut data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE];
+        for bit in (0..used).chain(count..BLOCK_SIZE * 8) {
+            bits[bit / 8] |= 1 << (bit % 8);
+        }
+    };
+    bitmap(data, block_bitmap, used_blocks, blocks);
+    bitmap(data, inode_bitmap, FIRST_INODE as usize, inodes);
+
+    let ext2 = Ext2::read(data)?;
+    let directory = |data: &mut [u8], inode: u32, mode: u16, links: u16, block: usize| {
+        let offset = ext2.inode_offset(inode);
+        put_u16(data, offset, MODE_DIRECTORY | mode);
+        put_u32(data, offset + 4, BLOCK_SIZE as u32);
+        put_u16(data, offset + 26, links);
+        put_u32(data, offset + 28, (BLOCK_SIZE / 512) as u32);
+        put_u32(data, offset + 40, block as u32);
+    };
+    directory(data, ROOT_INODE, 0o755, 3, root_block);
+    directory(data, FIRST_INODE, 0o700, 2, lost_and_found_block);
+    let entries = |data: &mut [u8], block: usize, names: &[(&str, u32)]| {
+        let mut offset = block * BLOCK_SIZE;
+        for (index, (name, inode)) in names.iter().enumerate() {
+            let size = if index + 1 == names.len() {
+                (block + 1) * BLOCK_SIZE - offset
+            } else {
+                entry_size(name.len())
+            };
+            ext2.write_entry(data, offset, *inode, size, name, FILE_TYPE_DIRECTORY);
+            offset += size;
+        }
+    };
+    entries(
+        data,
+        root_block,
+        &[
+            (".", ROOT_INODE),
+            ("..", ROOT_INODE),
+            ("lost+found", FIRST_INODE),
+        ],
+    );
+    entries(
+        data,
+        lost_and_found_block,
+        &[(".", FIRST_INODE), ("..", ROOT_INODE)],
+    );
+    Ok(())
+}
+
+/// whether `data` starts with an ext2 filesystem this module can read
+pub(super) fn is_ext2(data: &[u8]) -> bool {
+    Ext2::read(data).is_ok()
+}
+
+pub(super) fn list_files(data: &[u8]) -> Result<Vec<FileEntry>, DiskImageError> {
+    let ext2 = Ext2::read(data)?;
+    Ok(ext2
+        .root_entries(data)
+        .into_iter()
+        .filter(|entry| ext2.is_file(data, entry.inode))
+        .map(|entry| FileEntry {
+            size: u32_at(data, ext2.inode_offset(entry.inode) + 4),
+            name: entry.name,
+        })
+        .collect())
+}
+
+pub(super) fn extract_file(data: &[u8], name: &str) -> Result<Vec<u8>, DiskImageError> {
+    let ext2 = Ext2::read(data)?;
+    let entry = ext2.find_file(data, name)?;
+    let size = u32_at(data, ext2.inode_offset(entry.inode) + 4) as usize;
+    let (blocks, _) = ext2.blocks_of(data, entry.inode);
+    let mut contents = Vec::with_capacity(size);
+    for block in blocks {
+        // a hole reads as zeros
+        match block {
+            0 => contents.resize(contents.len() + ext2.block_size, 0),
+            block => contents.extend_from_slice(ext2.block(data, block)),
+        }
+    }
+    contents.truncate(size);
+    Ok(contents)
+}
+
+pub(super) fn inject_file(
+    data: &mut [u8],
+    name: &str,
+    contents: &[u8],
+) -> Result<(), DiskImageError> {
+    let ext2 = Ext2::read(data)?;
+    ext2.check_writable()?;
+    check_name(name)?;
+    if ext2.find_file(data, name).is_ok() {
+        remove_file(data, name)?;
+    }
+    if ext2
+        .root_entries(data)
+        .iter()
+        .any(|entry| entry.name == name)
+    {
+        // a directory of that name
+        return Err(DiskImageError::InvalidName {
+            name: name.to_string(),
+        });
+    }
+
+    let data_blocks = contents.len().div_ceil(ext2.block_size);
+    let disk_full = DiskImageError::DiskFull {
+        size: contents.len(),
+    };
+    let pointers = ext2.block_size / 4;
+    let mut index_blocks = 0;
+    if data_blocks > DIRECT_BLOCKS {
+        index_blocks += 1;
+    }
+    if data_blocks > DIRECT_BLOCKS + pointers {
+        let double = data_blocks - DIRECT_BLOCKS - pointers;
+        if double > pointers * pointers || u32::try_from(contents.len()).is_err() {
+            return Err(disk_full);
+        }
+        index_blocks += 1 + double.div_ceil(pointers);
+    }
+    let slot = ext2.directory_slot(data, name);
+    let directory_blocks = usize::from(slot.is_none());
+    let inode = ext2
+        .free_bits(data, ext2.inode_bitmap, ext2.inodes, 1)
+        .first()
+        .map(|bit| *bit as u32 + 1)
+        .ok_or(DiskImageError::NoFreeInode)?;
+    let needed = data_blocks + index_blocks + directory_blocks;
+    let free: Vec<usize> = ext2
+        .free_bits(
+            data,
+            ext2.block_bitmap,
+            ext2.blocks - ext2.first_data_block,
+            needed,
+        )
+        .into_iter()
+        .map(|bit| bit + ext2.first_data_block)
+        .collect();
+    if free.len() < needed {
+        return Err(disk_full);
+    }
+    for block in &free {
+        ext2.set_bit(data, ext2.block_bitmap, block - ext2.first_data_block, true);
+    }
+    ext2.set_bit(data, ext2.inode_bitmap, inode as usize - 1, true);
+    ext2.count_free(data, -(needed as i64), -1);
+
+    let (data_blocks, rest) = free.split_at(data_blocks);
+    let (index_blocks, directory_block) = rest.split_at(index_blocks);
+    for (block, chunk) in data_blocks.iter().zip(contents.chunks(ext2.block_size)) {
+        let bytes = ext2.block_mut(data, *block);
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        bytes[chunk.len()..].fill(0);
+    }
+
+    let offset = ext2.inode_offset(inode);
+    data[offset..offset + ext2.inode_size].fill(0);
+    put_u16(data, offset, MODE_FILE | 0o644);
+    put_u32(data, offset + 4, contents.len() as u32);
+    put_u16(data, offset + 26, 1);
+    let sectors = (data_blocks.len() + index_blocks.len()) * ext2.block_size / 512;
+    put_u32(data, offset + 28, sectors as u32);
+    for (index, block) in data_blocks.iter().take(DIRECT_BLOCKS).enumerate() {
+        put_u32(data, offset + 40 + index * 4, *block as u32);
+    }
+    // the index blocks were counted for exactly these data blocks
+    let mut index_blocks = index_blocks.iter().copied();
+    let mut rest = data_blocks.get(DIRECT_BLOCKS..).unwrap_or_default();
+    if let Some(indirect) = index_blocks.next() {
+        put_u32(data, offset + 40 + INDIRECT_BLOCK * 4, indirect as u32);
+        rest = ext2.write_pointers(data, indirect, rest);
+    }
+    if let Some(double) = index_blocks.next() {
+        put_u32(data, offset + 40 + DOUBLE_INDIRECT_BLOCK * 4, double as u32);
+        let indirect: Vec<usize> = index_blocks.collect();
+        ext2.write_pointers(data, double, &indirect);
+        for indirect in indirect {
+            rest = ext2.write_pointers(data, indirect, rest);
+        }
+    }
+
+    let offset = match slot {
+        Some(offset) => offset,
+        None => ext2.grow_root(data, directory_block[0])?,
+    };
+    ext2.insert_entry(data, offset, inode, name, FILE_TYPE_FILE);
+    Ok(())
+}
+
+pub(super) fn remove_file(data: &mut [u8], name: &str) -> Result<(), DiskImageError> {
+    let ext2 = Ext2::read(data)?;
+    ext2.check_writable()?;
+    let entry = ext2.find_file(data, name)?;
+    let (blocks, index_blocks) = ext2.blocks_of(data, entry.inode);
+    let mut freed = 0;
+    for block in blocks.into_iter().chain(index_blocks) {
+        if block != 0 {
+            ext2.set_bit(
+                data,
+                ext2.block_bitmap,
+                block - ext2.first_data_block,
+                false,
+            );
+            freed += 1;
+        }
+    }
+    ext2.set_bit(data, ext2.inode_bitmap, entry.inode as usize - 1, false);
+    ext2.count_free(data, freed, 1);
+    let offset = ext2.inode_offset(entry.inode);
+    data[offset..offset + ext2.inode_size].fill(0);
+
+    // the entry before it in the block takes its space, the first one of a
+    // block stays as an unused entry
+    match entry.previous {
+        Some(previous) => {
+            let size = u16_at(data, previous + 4) + u16_at(data, entry.offset + 4);
+            put_u16(data, previous + 4, size);
+        }
+        None => put_u32(data, entry.offset, 0),
+    }
+    Ok(())
+}
+
+/// an entry of the root directory
+struct Entry {
+    inode: u32,
+    name: String,
+    /// where it is in the image
+    offset: usize,
+    /// the entry before it in the same block
+    previous: Option<usize>,
+}
+
+/// where everything is in the image, from the superblock and the group
+/// descriptor
+struct Ext2 {
+    block_size: usize,
+    blocks: usize,
+    first_data_block: usize,
+    inodes: usize,
+    inode_size: usize,
+    block_bitmap: usize,
+    inode_bitmap: usize,
+    inode_table: usize,
+    file_types: bool,
+    ro_compat: u32,
+}
+
+impl Ext2 {
+    fn read(data: &[u8]) -> Result<Self, DiskImageError> {
+        let unknown = DiskImageError::UnknownFilesystem;
+        let superblock = data
+            .get(SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024)
+            .ok_or(unknown.clone())?;
+        if u16_at(superblock, 56) != MAGIC {
+            return Err(unknown);
+        }
+        let log_block_size = u32_at(superblock, 24);
+        if log_block_size > 2 {
+            return Err(unknown);
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks = u32_at(superblock, 4) as usize;
+        let first_data_block = u32_at(superblock, 20) as usize;
+        let blocks_per_group = u32_at(superblock, 32) as usize;
+        let inodes = u32_at(superblock, 0) as usize;
+        let revision = u32_at(superblock, 76);
+        let inode_size = match revision {
+            0 => INODE_SIZE,
+            _ => u16_at(superblock, 88) as usize,
+        };
+        let incompat = if revision == 0 {
+            0
+        } else {
+            u32_at(superblock, 96)
+        };
+        let ro_compat = if revision == 0 {
+            0
+        } else {
+            u32_at(superblock, 100)
+        };
+        if blocks * block_size > data.len()
+            || blocks <= first_data_block
+            || blocks - first_data_block > blocks_per_group.min(block_size * 8)
+            || inodes == 0
+            || inodes > block_size * 8
+            || u32_at(superblock, 40) as usize != inodes
+            || !inode_size.is_power_of_two()
+            || !(INODE_SIZE..=block_size).contains(&inode_size)
+            || incompat & !FEATURE_INCOMPAT_FILETYPE != 0
+        {
+            return Err(unknown);
+        }
+
+        let descriptor = (first_data_block + 1) * block_size;
+        let block_bitmap = u32_at(data, descriptor) as usize;
+        let inode_bitmap = u32_at(data, descriptor + 4) as usize;
+        let inode_table = u32_at(data, descriptor + 8) as usize;
+        let inode_table_end = inode_table + (inodes * inode_size).div_ceil(block_size);
+        if block_bitmap >= blocks || inode_bitmap >= blocks || inode_table_end > blocks {
+            return Err(unknown);
+        }
+        Ok(Self {
+            block_size,
+            blocks,
+            first_data_block,
+            inodes,
+            inode_size,
+            block_bitmap,
+            inode_bitmap,
+            inode_table,
+            file_types: incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            ro_compat,
+        })
+    }
+
+    /// features this module would break by writing
+    fn check_writable(&self) -> Result<(), DiskImageError> {
+        if self.ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+            return Err(DiskImageError::UnknownFilesystem);
+        }
+        Ok(())
+    }
+
+    fn block_offset(&self, block: usize) -> usize {
+        block * self.block_size
+    }
+
+    fn block<'a>(&self, data: &'a [u8], block: usize) -> &'a [u8] {
+        let offset = self.block_offset(block);
+        &data[offset..offset + self.block_size]
+    }
+
+    fn block_mut<'a>(&self, data: &'a mut [u8], block: usize) -> &'a mut [u8] {
+        let offset = self.block_offset(block);
+        &mut data[offset..offset + self.block_size]
+    }
+
+    fn inode_offset(&self, inode: u32) -> usize {
+        self.inode_table * self.block_size + (inode as usize - 1) * self.inode_size
+    }
+
+    fn is_file(&self, data: &[u8], inode: u32) -> bool {
+        (1..=self.inodes as u32).contains(&inode)
+            && u16_at(data, self.inode_offset(inode)) & MODE_TYPE == MODE_FILE
+    }
+
+    /// the data blocks of `inode` in order (0 for a hole), and the blocks
+    /// with their numbers. Numbers outside the image end the file, like a
+    /// broken FAT chain does
+    fn blocks_of(&self, data: &[u8], inode: u32) -> (Vec<usize>, Vec<usize>) {
+        let offset = self.inode_offset(inode);
+        let mut count = (u32_at(data, offset + 4) as usize).div_ceil(self.block_size);
+        let pointer = |index: usize| u32_at(data, offset + 40 + index * 4) as usize;
+        let (mut blocks, mut index_blocks) = (Vec::new(), Vec::new());
+
+        let mut take = |numbers: &mut dyn Iterator<Item = usize>, count: &mut usize| {
+            for block in numbers.take(*count) {
+                if block >= self.blocks {
+                    *count = 0;
+                    return;
+                }
+                blocks.push(block);
+                *count -= 1;
+            }
+        };
+        take(&mut (0..DIRECT_BLOCKS).map(pointer), &mut count);
+        let pointers = |block: usize| {
+            let bytes = &data[block * self.block_size..(block + 1) * self.block_size];
+            bytes
+                .chunks_exact(4)
+                .map(|number| u32::from_le_bytes(number.try_into().unwrap()) as usize)
+                .collect::<Vec<_>>()
+        };
+        let indirect = pointer(INDIRECT_BLOCK);
+        if count > 0 && (1..self.blocks).contains(&indirect) {
+            index_blocks.push(indirect);
+            take(&mut pointers(indirect).into_iter(), &mut count);
+        }
+        let double = pointer(DOUBLE_INDIRECT_BLOCK);
+        if count > 0 && (1..self.blocks).contains(&double) {
+            index_blocks.push(double);
+            for indirect in pointers(double) {
+                if count == 0 || !(1..self.blocks).contains(&indirect) {
+                    break;
+                }
+                index_blocks.push(indirect);
+                take(&mut pointers(indirect).into_iter(), &mut count);
+            }
+        }
+        (blocks, index_blocks)
+    }
+
+    /// fills the indirect block `block` with the first of `blocks`, returns
+    /// the ones that did not fit
+    fn write_pointers<'a>(
+        &self,
+        data: &mut [u8],
+        block: usize,
+        blocks: &'a [usize],
+    ) -> &'a [usize] {
+        let (now, rest) = blocks.split_at(blocks.len().min(self.block_size / 4));
+        let bytes = self.block_mut(data, block);
+        bytes.fill(0);
+        for (index, number) in now.iter().enumerate() {
+            put_u32(bytes, index * 4, *number as u32);
+        }
+        rest
+    }
+
+    /// the entries of the root directory but `.` and `..`
+    fn root_entries(&self, data: &[u8]) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        for block in self.blocks_of(data, ROOT_INODE).0 {
+            if block == 0 {
+                continue;
+            }
+            let start = block * self.block_size;
+            let (mut offset, mut previous) = (start, None);
+            while offset + 8 <= start + self.block_size {
+                let size = u16_at(data, offset + 4) as usize;
+                let name_length = data[offset + 6] as usize;
+                if size < 8 || offset + size > start + self.block_size || name_length + 8 > size {
+                    break;
+                }
+                let inode = u32_at(data, offset);
+                let name = String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_length]);
+                if inode != 0 && name != "." && name != ".." {
+                    entries.push(Entry {
+                        inode,
+                        name: name.into_owned(),
+                        offset,
+                        previous,
+                    });
+                }
+                previous = Some(offset);
+                offset += size;
+            }
+        }
+        entries
+    }
+
+    fn find_file(&self, data: &[u8], name: &str) -> Result<Entry, DiskImageError> {
+        self.root_entries(data)
+            .into_iter()
+            .find(|entry| entry.name == name && self.is_file(data, entry.inode))
+            .ok_or_else(|| DiskImageError::FileNotFound {
+                name: name.to_string(),
+            })
+    }
+
+    /// where an entry for `name` fits into the blocks the root directory
+    /// has: an unused entry or the room after the last name of one
+    fn directory_slot(&self, data: &[u8], name: &str) -> Option<usize> {
+        let needed = entry_size(name.len());
+        for block in self.blocks_of(data, ROOT_INODE).0 {
+            if block == 0 {
+                continue;
+            }
+            let start = block * self.block_size;
+            let mut offset = start;
+            while offset + 8 <= start + self.block_size {
+                let size = u16_at(data, offset + 4) as usize;
+                if size < 8 || offset + size > start + self.block_size {
+                    break;
+                }
+                let used = match u32_at(data, offset) {
+                    0 => 0,
+                    _ => entry_size(data[offset + 6] as usize),
+                };
+                if size >= used + needed {
+                    return Some(offset);
+                }
+                offset += size;
+            }
+        }
+        None
+    }
+
+    /// adds `block` to the root directory as one unused entry, the root
+    /// directory only grows into its direct blocks
+    fn grow_root(&self, data: &mut [u8], block: usize) -> Result<usize, DiskImageError> {
+        let offset = self.inode_offset(ROOT_INODE);
+        let size = u32_at(data, offset + 4) as usize;
+        let index = size / self.block_size;
+        if !size.is_multiple_of(self.block_size) || index >= DIRECT_BLOCKS {
+            return Err(DiskImageError::RootDirectoryFull);
+        }
+        put_u32(data, offset + 4, (size + self.block_size) as u32);
+        let sectors = u32_at(data, offset + 28) + (self.block_size / 512) as u32;
+        put_u32(data, offset + 28, sectors);
+        put_u32(data, offset + 40 + index * 4, block as u32);
+        let entry = self.block_offset(block);
+        self.write_entry(data, entry, 0, self.block_size, "", 0);
+        Ok(entry)
+    }
+
+    /// puts the entry into the one at `offset`, which `directory_slot` found
+    fn insert_entry(&self, data: &mut [u8], offset: usize, inode: u32, name: &str, kind: u8) {
+        // a hash tree would not know about the new entry, without the flag
+        // the directory is a plain list again
+        let root = self.inode_offset(ROOT_INODE);
+        let flags = u32_at(data, root + 32) & !INODE_INDEX_FLAG;
+        put_u32(data, root + 32, flags);
+
+        let size = u16_at(data, offset + 4) as usize;
+        if u32_at(data, offset) == 0 {
+            self.write_entry(data, offset, inode, size, name, kind);
+            return;
+        }
+        let used = entry_size(data[offset + 6] as usize);
+        put_u16(data, offset + 4, used as u16);
+        self.write_entry(data, offset + used, inode, size - used, name, kind);
+    }
+
+    fn write_entry(
+        &self,
+        data: &mut [u8],
+        offset: usize,
+        inode: u32,
+        size: usize,
+        name: &str,
+        kind: u8,
+    ) {
+        put_u32(data, offset, inode);
+        put_u16(data, offset + 4, size as u16);
+        data[offset + 6] = name.len() as u8;
+        data[offset + 7] = if self.file_types { kind } else { 0 };
+        data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// the first `count` clear bits of the bitmap in `block`, out of `bits`
+    fn free_bits(&self, data: &[u8], block: usize, bits: usize, count: usize) -> Vec<usize> {
+        let bitmap = &data[block * self.block_size..(block + 1) * self.block_size];
+        (0..bits)
+            .filter(|bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0)
+            .take(count)
+            .collect()
+    }
+
+    fn set_bit(&self, data: &mut [u8], block: usize, bit: usize, set: bool) {
+        let byte = &mut data[block * self.block_size + bit / 8];
+        if set {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+
+    /// adds to the free counts in the superblock and the group descriptor
+    fn count_free(&self, data: &mut [u8], blocks: i64, inodes: i64) {
+        let add32 = |data: &mut [u8], offset: usize, by: i64| {
+            let value = i64::from(u32_at(data, offset)) + by;
+            put_u32(data, offset, value as u32);
+        };
+        let add16 = |data: &mut [u8], offset: usize, by: i64| {
+            let value = i64::from(u16_at(data, offset)) + by;
+            put_u16(data, offset, value as u16);
+        };
+        add32(data, SUPERBLOCK_OFFSET + 12, blocks);
+        add32(data, SUPERBLOCK_OFFSET + 16, inodes);
+        let descriptor = (self.first_data_block + 1) * self.block_size;
+        add16(data, descriptor + 12, blocks);
+        add16(data, descriptor + 14, inodes);
+    }
+}
@@ -0,0 +1,297 @@
+//! Which ISA extensions a vm has, for compliance tests of narrow profiles
+//! (plain rv32i, rv32i_zicsr, ...). An instruction of an extension that is
+//! off is an illegal instruction, and `misa` reports the set.
+//!
+//! Only what the vm implements can be on: I, A, Zicsr and Zifencei by
+//! default, V with `Vm::with_vector` and X with `Vm::add_instruction_plugin`.
+//! M, C, F and D are not implemented, so `parse` refuses them and `misa`
+//! never claims them.
+
+use core::fmt;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use super::emulator::Vm;
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+
+/// MXL = 1 in the top bits of `misa`: XLEN is 32
+const MISA_MXL_32: u32 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Extension {
+    I,
+    M,
+    A,
+    F,
+    D,
+    C,
+    V,
+    /// non-standard, the custom opcodes of `plugin.rs`
+    X,
+    Zicsr,
+    Zifencei,
+}
+
+impl Extension {
+    /// the single letter extensions, in canonical order
+    const LETTERS: [Self; 8] = [
+        Self::I,
+        Self::M,
+        Self::A,
+        Self::F,
+        Self::D,
+        Self::C,
+        Self::V,
+        Self::X,
+    ];
+    const MULTI_LETTER: [Self; 2] = [Self::Zicsr, Self::Zifencei];
+
+    /// the letter of a single letter extension
+    fn letter(self) -> Option<char> {
+        Some(match self {
+            Self::I => 'i',
+            Self::M => 'm',
+            Self::A => 'a',
+            Self::F => 'f',
+            Self::D => 'd',
+            Self::C => 'c',
+            Self::V => 'v',
+            Self::X => 'x',
+            Self::Zicsr | Self::Zifencei => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zicsr => "zicsr",
+            Self::Zifencei => "zifencei",
+            _ => "",
+        }
+    }
+
+    pub fn is_implemented(self) -> bool {
+        !matches!(self, Self::M | Self::F | Self::D | Self::C)
+    }
+
+    /// the bit of the set, the misa bit for the letters
+    fn bit(self) -> u32 {
+        match self.letter() {
+            Some(letter) => 1 << (letter as u8 - b'a'),
+            None if self == Self::Zicsr => 1 << 26,
+            None => 1 << 27,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ExtensionsError {
+    #[error("{0:?} does not start with rv32i or rv32g")]
+    NotRv32(String),
+
+    #[error("unknown extension {0:?}")]
+    Unknown(String),
+
+    #[error("the vm does not implement the {0} extension")]
+    NotImplemented(String),
+}
+
+/// a set of extensions, always with I
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions(u32);
+
+impl Default for Extensions {
+    /// everything that needs no extra setup: rv32ia_zicsr_zifencei
+    fn default() -> Self {
+        Self::rv32i()
+            .with(Extension::A)
+            .with(Extension::Zicsr)
+            .with(Extension::Zifencei)
+    }
+}
+
+impl Extensions {
+    /// the base ISA alone
+    pub fn rv32i() -> Self {
+        Self(Extension::I.bit())
+    }
+
+    /// adds `extension`, if the vm implements it
+    pub fn with(self, extension: Extension) -> Self {
+        if !extension.is_implemented() {
+            return self;
+        }
+        Self(self.0 | extension.bit())
+    }
+
+    /// I stays
+    pub fn without(self, extension: Extension) -> Self {
+        if extension == Extension::I {
+            return self;
+        }
+        Self(self.0 & !extension.bit())
+    }
+
+    pub fn contains(self, extension: Extension) -> bool {
+        self.0 & extension.bit() != 0
+    }
+
+    /// the value of the `misa` CSR
+    pub fn misa(self) -> u32 {
+        let letters = Extension::LETTERS
+            .iter()
+            .filter(|extension| self.contains(**extension))
+            .fold(0, |misa, extension| misa | extension.bit());
+        MISA_MXL_32 | letters
+    }
+
+    /// an ISA string like `rv32ia_zicsr_zifencei`, `g` is
+    /// `imafd_zicsr_zifencei`. Case does not matter
+    pub fn parse(isa: &str) -> Result<Self, ExtensionsError> {
+        let isa = isa.to_ascii_lowercase();
+        let mut parts = isa.split('_');
+        let letters = parts
+            .next()
+            .and_then(|base| base.strip_prefix("rv32"))
+            .filter(|letters| letters.starts_with(['i', 'g']))
+            .ok_or_else(|| ExtensionsError::NotRv32(isa.clone()))?;
+        let mut names: Vec<String> = letters
+            .chars()
+            .flat_map(|letter| match letter {
+                'g' => "imafd".chars().map(String::from).collect(),
+                letter => vec![letter.to_string()],
+            })
+            .collect();
+        if letters.starts_with('g') {
+            names.extend(["zicsr".to_string(), "zifencei".to_string()]);
+        }
+        names.extend(parts.map(str::to_string));
+
+        let mut extensions = Self::rv32i();
+        for name in names {
+            let extension = Extension::LETTERS
+                .into_iter()
+                .chain(Extension::MULTI_LETTER)
+                .find(|extension| match extension.letter() {
+                    Some(letter) => name == letter.to_string(),
+                    None => name == extension.name(),
+                })
+                .ok_or_else(|| ExtensionsError::Unknown(name.clone()))?;
+            if !extension.is_implemented() {
+                return Err(ExtensionsError::NotImplemented(name));
+            }
+            extensions = extensions.with(extension);
+        }
+        Ok(extensions)
+    }
+}
+
+impl fmt::Display for Extensions {
+    /// the ISA string `parse` takes
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv32")?;
+        for extension in Extension::LETTERS {
+            if self.contains(extension) {
+                write!(f, "{}", extension.letter().unwrap_or_default())?;
+            }
+        }
+        for extension in Extension::MULTI_LETTER {
+            if self.contains(extension) {
+                write!(f, "_{}", extension.name())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rv32iInstruction {
+    /// the extension the instruction belongs to
+    pub fn extension(&self) -> Extension {
+        match self {
+            _ if self.is_atomic() => Extension::A,
+            Self::Csrrw(_)
+            | Self::Csrrs(_)
+            | Self::Csrrc(_)
+            | Self::Csrrwi(_)
+            | Self::Csrrsi(_)
+            | Self::Csrrci(_) => Extension::Zicsr,
+            Self::FenceI => Extension::Zifencei,
+            Self::Vector(_) => Extension::V,
+            Self::Custom(_) => Extension::X,
+            _ => Extension::I,
+        }
+    }
+}
+
+impl Vm {
+    /// only the instructions of `extensions` run, the others are illegal
+    /// instructions. `with_vector` and `add_instruction_plugin` turn V and X
+    /// on when they come after it
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extension, Extensions, ExtensionsError};
+    use crate::{Vm, VmError};
+
+    #[test]
+    fn should_parse_and_print_isa_strings() {
+        let extensions = Extensions::parse("RV32IA_Zicsr").unwrap();
+        assert!(extensions.contains(Extension::A));
+        assert!(!extensions.contains(Extension::Zifencei));
+        assert_eq!(extensions.to_string(), "rv32ia_zicsr");
+        assert_eq!(extensions.misa(), 0x4000_0101);
+        assert_eq!(Extensions::default().to_string(), "rv32ia_zicsr_zifencei");
+
+        assert_eq!(
+            Extensions::parse("rv32imac"),
+            Err(ExtensionsError::NotImplemented("m".to_string()))
+        );
+        assert_eq!(
+            Extensions::parse("rv32i_zfoo"),
+            Err(ExtensionsError::Unknown("zfoo".to_string()))
+        );
+        assert!(Extensions::parse("rv64i").is_err());
+    }
+
+    #[test]
+    fn should_only_run_the_enabled_extensions() {
+        // 0x1000 csrrs a0, misa, zero
+        // 0x1004 lr.w a1, (a0)
+        let program: Vec<u8> = [0x3010_2573u32, 0x1005_25af]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm =
+            Vm::new(0x1000, 0x100).with_extensions(Extensions::parse("rv32i_zicsr").unwrap());
+        vm.load_program(0x1000, &program).unwrap();
+
+        vm.step().unwrap();
+        // plus S and U, the modes are always there
+        assert_eq!(vm.vm_state.registers[10], 0x4014_0100);
+        assert_eq!(
+            vm.step(),
+            Err(VmError::IllegalInstruction {
+                pc: 0x1004,
+                instruction: 0x1005_25af,
+            })
+        );
+
+        // without Zicsr, reading misa is illegal too
+        let mut vm = Vm::new(0x1000, 0x100).with_extensions(Extensions::rv32i());
+        vm.load_program(0x1000, &program).unwrap();
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::IllegalInstruction { pc: 0x1000, .. })
+        ));
+    }
+}
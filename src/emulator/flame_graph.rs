@@ -0,0 +1,156 @@
+//! Instructions and cycles per call stack in the folded format flame graph
+//! tools read (`flamegraph.pl`, inferno, speedscope): one line per stack,
+//! the functions from the outermost in, separated by `;`, then the count.
+//!
+//! ```text
+//! _start;main 1200
+//! _start;main;compute 84000
+//! ```
+//!
+//! The stacks are the shadow call stack (see `call_stack.rs`) named with the
+//! symbol table, the cycles those of the timing model (one per instruction
+//! without one). The code that runs before the first call is named after
+//! the function the first instruction is in.
+
+use super::call_stack::Frame;
+use super::emulator::Vm;
+use super::prelude::*;
+use super::profile::FunctionRange;
+
+/// what the counts of the folded stacks are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    Instructions,
+    Cycles,
+}
+
+/// the instructions and cycles per call stack so far
+#[derive(Debug, Clone, Default)]
+pub struct FoldedStacks {
+    /// instructions and cycles per stack, a stack is the targets of its calls
+    stacks: HashMap<Vec<u32>, (u64, u64)>,
+    /// the first pc, it names the bottom of every stack
+    root: Option<u32>,
+    /// the cycles of the timing model at the last instruction
+    last_cycles: u64,
+}
+
+impl FoldedStacks {
+    /// counts one instruction at `pc` with the calls in `frames`, `cycles`
+    /// is the cycle count of the timing model after it, if there is one
+    pub(super) fn record(&mut self, pc: u32, frames: &[Frame], cycles: Option<u64>) {
+        self.root.get_or_insert(pc);
+        let cycles = match cycles {
+            Some(cycles) => cycles - core::mem::replace(&mut self.last_cycles, cycles),
+            None => 1,
+        };
+        let targets: Vec<u32> = frames.iter().map(|frame| frame.target).collect();
+        let (instructions, total) = self.stacks.entry(targets).or_default();
+        *instructions += 1;
+        *total += cycles;
+    }
+
+    /// the folded stacks, `functions` name the frames. Addresses outside of
+    /// them are shown in hex
+    pub fn to_folded(&self, functions: &[FunctionRange], weight: Weight) -> String {
+        let name = |address: u32| {
+            functions
+                .iter()
+                .find(|function| (function.start..function.end).contains(&address))
+                .map_or_else(|| format!("{address:#x}"), |function| function.name.clone())
+        };
+        let root = self.root.map(name).unwrap_or_default();
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(targets, (instructions, cycles))| {
+                let count = match weight {
+                    Weight::Instructions => instructions,
+                    Weight::Cycles => cycles,
+                };
+                let stack: Vec<String> = core::iter::once(root.clone())
+                    .chain(targets.iter().map(|target| name(*target)))
+                    .collect();
+                format!("{} {count}\n", stack.join(";"))
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+impl Vm {
+    /// counts the instructions and cycles per call stack from now on
+    pub fn with_folded_stacks(mut self) -> Self {
+        self.folded_stacks = Some(FoldedStacks {
+            last_cycles: self.timing.as_ref().map_or(0, |timing| timing.cycles),
+            ..FoldedStacks::default()
+        });
+        self
+    }
+
+    /// the folded stacks so far named with the symbol table, empty without
+    /// `with_folded_stacks`
+    pub fn folded_stacks(&self, weight: Weight) -> String {
+        self.folded_stacks
+            .as_ref()
+            .map(|stacks| stacks.to_folded(&FunctionRange::from_symbols(&self.symbols), weight))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Weight;
+    use crate::elf::{Symbol, SymbolKind};
+    use crate::gas::InstructionClass;
+    use crate::timing::TimingModel;
+    use crate::Vm;
+
+    #[test]
+    fn should_fold_the_instructions_and_cycles_per_stack() {
+        // 0x1000 addi s0, zero, 3     <- main
+        // 0x1004 jal ra, 0x1014
+        // 0x1008 addi s0, s0, -1
+        // 0x100c bne s0, zero, 0x1004
+        // 0x1010 ebreak
+        // 0x1014 addi a0, a0, 1       <- increment
+        // 0x1018 ret
+        let program: Vec<u8> = [
+            0x0030_0413u32,
+            0x0100_00ef,
+            0xfff4_0413,
+            0xfe04_1ce3,
+            0x0010_0073,
+            0x0015_0513,
+            0x0000_8067,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let timing = TimingModel::new().with_instruction_latency(InstructionClass::Jump, 2);
+        let mut vm = Vm::new(0x1000, 0x100)
+            .with_timing(timing)
+            .with_folded_stacks();
+        vm.load_program(0x1000, &program).unwrap();
+        for (name, address, size) in [("main", 0x1000, 0x14), ("increment", 0x1014, 0x8)] {
+            vm.symbols.push(Symbol {
+                name: name.to_string(),
+                address,
+                size,
+                kind: SymbolKind::Function,
+            });
+        }
+        vm.run().unwrap();
+
+        assert_eq!(
+            vm.folded_stacks(Weight::Instructions),
+            "main 10\nmain;increment 6\n"
+        );
+        // a cycle per fetch, the jal and the ret 2 more
+        assert_eq!(
+            vm.folded_stacks(Weight::Cycles),
+            "main 16\nmain;increment 12\n"
+        );
+    }
+}
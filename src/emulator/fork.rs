@@ -0,0 +1,87 @@
+//! Cheap copies of a running vm, for fuzzers and search drivers that try
+//! thousands of inputs from one checkpoint: `fork` shares the pages of big
+//! memories with the parent until one of them writes to a page (see
+//! `backing.rs`), so a child costs what it touches, not the whole RAM.
+//!
+//! ```text
+//! vm.run_until_symbol("main")?;
+//! for input in inputs {
+//!     let mut child = vm.fork();
+//!     child.memory.write_bytes(buffer, &input)?;
+//!     child.run()?;
+//! }
+//! ```
+//!
+//! The child gets the architectural state (registers, pc, CSRs, memory,
+//! gas and call stack) and the settings that are plain data. What belongs
+//! to the host is not copied: hooks, devices, ecall handlers, plugins,
+//! syscalls, semihosting and the strace log. Add them again if the child
+//! needs them.
+
+use super::emulator::Vm;
+
+impl Vm {
+    /// a copy of the vm that shares memory with it copy-on-write
+    pub fn fork(&mut self) -> Vm {
+        let mut child = Vm::new(0, 0);
+        child.vm_state = self.vm_state.clone();
+        child.memory = self.memory.fork();
+        child.csrs = self.csrs.clone();
+        child.call_stack = self.call_stack.clone();
+        child.clock = self.clock;
+        child.gas = self.gas.clone();
+        child.execution_limit = self.execution_limit;
+        child.limits = self.limits;
+        child.timing = self.timing.clone();
+        child.hypercall_policy = self.hypercall_policy.clone();
+        child.breakpoints = self.breakpoints.clone();
+        child.symbols = self.symbols.clone();
+        child.line_table = self.line_table.clone();
+        child.trap_illegal_instructions = self.trap_illegal_instructions;
+        child.vector = self.vector.clone();
+        child.extensions = self.extensions;
+        child.stack_limit = self.stack_limit;
+        child.ecall_policy = self.ecall_policy.clone();
+        child.wfi_policy = self.wfi_policy;
+        child.mmu = self.mmu.clone();
+        child
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backing::FORK_THRESHOLD;
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_run_forks_without_touching_the_parent() {
+        // 0x1000 lw a0, 0x100(zero)
+        // 0x1004 addi a0, a0, 1
+        // 0x1008 sw a0, 0x100(zero)
+        // 0x100c ebreak
+        let program: Vec<u8> = [0x1000_2503u32, 0x0015_0513, 0x10a0_2023, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut parent = Vm::new(0, FORK_THRESHOLD);
+        parent.load_program(0x1000, &program).unwrap();
+        parent.memory.write_u32(0x100, 41).unwrap();
+        parent.vm_state.pc = 0x1000;
+
+        let mut children: Vec<Vm> = (0..3).map(|_| parent.fork()).collect();
+        for (index, child) in children.iter_mut().enumerate() {
+            child.memory.write_u32(0x100, index as u32 * 10).unwrap();
+            assert_eq!(child.run(), Ok(StopReason::Ebreak));
+            assert_eq!(
+                child.vm_state.registers[Register::A0],
+                index as i32 * 10 + 1
+            );
+        }
+        assert_eq!(parent.vm_state.pc, 0x1000);
+        assert_eq!(parent.memory.read_u32(0x100), Ok(41));
+
+        assert_eq!(parent.run(), Ok(StopReason::Ebreak));
+        assert_eq!(parent.memory.read_u32(0x100), Ok(42));
+        assert_eq!(children[2].memory.read_u32(0x100), Ok(21));
+    }
+}
@@ -0,0 +1,176 @@
+//! A linear framebuffer, so guests can draw into a browser canvas. The
+//! device has a page of registers and the pixels after it, one RGBA8888
+//! pixel (red in the lowest byte) per 4 bytes, row after row. That is the
+//! byte order of `ImageData`, so a frontend can blit the buffer as it is.
+//!
+//! The guest writes `PRESENT` when a frame is complete; the host can look at
+//! `frames` to redraw only then, or at `take_dirty` to redraw whenever a
+//! pixel changed.
+
+use super::memory::Memory;
+use super::mmio::Device;
+use super::prelude::*;
+
+/// no device of QEMU's `virt` machine sits there (it is its PCI window)
+pub const FRAMEBUFFER_BASE: u32 = 0x4000_0000;
+
+const REG_WIDTH: u32 = 0x00;
+const REG_HEIGHT: u32 = 0x04;
+/// bytes per row
+const REG_STRIDE: u32 = 0x08;
+/// write anything when a frame is complete
+const REG_PRESENT: u32 = 0x10;
+/// where the pixels start
+pub const PIXELS_OFFSET: u32 = 0x1000;
+
+const BYTES_PER_PIXEL: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    /// a pixel changed since the last `take_dirty`
+    dirty: bool,
+    /// how many frames the guest presented
+    pub frames: u64,
+}
+
+impl Framebuffer {
+    /// a black, transparent framebuffer of `width` x `height` pixels
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height * BYTES_PER_PIXEL) as usize],
+            dirty: false,
+            frames: 0,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// the bytes the device needs on the bus, for `Vm::add_device`
+    pub fn size(&self) -> u32 {
+        PIXELS_OFFSET + self.pixels.len() as u32
+    }
+
+    /// the RGBA bytes of all pixels, row after row
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// the pixel at `x`, `y` as `[r, g, b, a]`
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let start = ((y * self.width + x) * BYTES_PER_PIXEL) as usize;
+        self.pixels[start..start + 4].try_into().ok()
+    }
+
+    /// whether a pixel changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        core::mem::take(&mut self.dirty)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Framebuffer {
+    /// the pixels for `new ImageData(array, width, height)`. A copy: a view
+    /// into the memory of the module would go stale when the memory grows
+    pub fn to_clamped_array(&self) -> js_sys::Uint8ClampedArray {
+        js_sys::Uint8ClampedArray::from(&self.pixels[..])
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn read(&mut self, offset: u32, size: u32, _memory: &mut Memory) -> u32 {
+        match offset {
+            REG_WIDTH => self.width,
+            REG_HEIGHT => self.height,
+            REG_STRIDE => self.width * BYTES_PER_PIXEL,
+            PIXELS_OFFSET.. => {
+                let start = (offset - PIXELS_OFFSET) as usize;
+                let Some(bytes) = self.pixels.get(start..start + size as usize) else {
+                    return 0;
+                };
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | u32::from(byte))
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32, _memory: &mut Memory) -> bool {
+        match offset {
+            REG_PRESENT => self.frames += 1,
+            PIXELS_OFFSET.. => {
+                let start = (offset - PIXELS_OFFSET) as usize;
+                if let Some(bytes) = self.pixels.get_mut(start..start + size as usize) {
+                    bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+                    self.dirty = true;
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framebuffer, FRAMEBUFFER_BASE};
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_show_the_pixels_the_guest_draws() {
+        // 0x1000 lui t0, 0x40000
+        // 0x1004 lw a0, 0(t0)          <- the width
+        // 0x1008 lui t1, 0xff000
+        // 0x100c addi t1, t1, 0xff     <- opaque red
+        // 0x1010 lui t2, 0x40001
+        // 0x1014 sw t1, 4(t2)          <- the pixel at 1, 0
+        // 0x1018 sw zero, 16(t0)       <- present the frame
+        // 0x101c ebreak
+        let program: Vec<u8> = [
+            0x4000_02b7u32,
+            0x0002_a503,
+            0xff00_0337,
+            0x0ff3_0313,
+            0x4000_13b7,
+            0x0063_a223,
+            0x0002_a823,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let framebuffer = Framebuffer::new(4, 3);
+        vm.add_device(FRAMEBUFFER_BASE, framebuffer.size(), Box::new(framebuffer));
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[Register::A0], 4);
+        let framebuffer = vm.device_mut::<Framebuffer>(FRAMEBUFFER_BASE).unwrap();
+        assert_eq!(framebuffer.pixel(1, 0), Some([0xff, 0, 0, 0xff]));
+        assert_eq!(framebuffer.pixel(0, 0), Some([0; 4]));
+        assert_eq!(framebuffer.pixels().len(), 4 * 3 * 4);
+        assert_eq!(framebuffer.frames, 1);
+        assert!(framebuffer.take_dirty());
+        assert!(!framebuffer.take_dirty());
+    }
+}
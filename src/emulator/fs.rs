@@ -0,0 +1,470 @@
+//! The files behind the file syscalls of `syscalls.rs`. A guest only sees
+//! what its `FileSystem` lets it see:
+//!
+//! - `HostFs` opens host files, but only below the directories on its
+//!   allow-list. Paths are resolved (`..`, symlinks) before they are
+//!   checked, anything else is `EACCES`. So is a symlink to nowhere, it
+//!   can't be checked.
+//! - `VirtFs` keeps the files in memory, for the wasm build where there is
+//!   no host filesystem, and for tests. `VirtFs::from_tar` loads a tar
+//!   archive, so the frontend can ship the same files a native run would
+//!   `--allow` as one download.
+//!
+//! Errors are Linux errno values, they go straight back to the guest.
+//! `HostFs` needs std, `VirtFs` does not.
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{self, ErrorKind, Read, Seek, Write};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use super::prelude::*;
+#[cfg(feature = "std")]
+use super::syscalls::{EACCES, EIO};
+use super::syscalls::{
+    EBADF, EEXIST, EINVAL, EISDIR, ENOENT, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_TRUNC,
+    O_WRONLY,
+};
+
+#[cfg(feature = "std")]
+pub use std::io::SeekFrom;
+
+/// where `FileHandle::seek` goes, like std's
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// where the guest's paths lead
+pub trait FileSystem {
+    /// opens `path` with the Linux `open` flags
+    fn open(&mut self, path: &str, flags: u32) -> Result<Box<dyn FileHandle>, i32>;
+}
+
+/// an open file
+pub trait FileHandle {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i32>;
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, i32>;
+    /// moves the position, returns the new one
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, i32>;
+    fn size(&mut self) -> Result<u64, i32>;
+}
+
+/// the errno for a host error
+#[cfg(feature = "std")]
+fn errno(error: &io::Error) -> i32 {
+    match error.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+/// host files below the allowed directories
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct HostFs {
+    allowed: Vec<PathBuf>,
+}
+
+#[cfg(feature = "std")]
+impl HostFs {
+    /// allows nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// lets the guest open everything below `directory`
+    pub fn allow(mut self, directory: impl AsRef<Path>) -> io::Result<Self> {
+        self.allowed.push(directory.as_ref().canonicalize()?);
+        Ok(self)
+    }
+
+    /// the host path for `path` if it is allowed. The file itself may not
+    /// exist yet (`O_CREAT`), its directory has to
+    fn resolve(&self, path: &str) -> Result<PathBuf, i32> {
+        let path = Path::new(path);
+        let resolved = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                let parent = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+                let resolved = parent.canonicalize().map_err(|error| errno(&error))?;
+                // a symlink may point out of the allowed directories
+                let resolved = resolved.join(name);
+                match resolved.canonicalize() {
+                    Ok(target) => target,
+                    // a dangling symlink would be followed by `O_CREAT`
+                    Err(_) if resolved.is_symlink() => return Err(EACCES),
+                    Err(_) => resolved,
+                }
+            }
+            _ => path.canonicalize().map_err(|error| errno(&error))?,
+        };
+        if self
+            .allowed
+            .iter()
+            .any(|directory| resolved.starts_with(directory))
+        {
+            Ok(resolved)
+        } else {
+            Err(EACCES)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl FileSystem for HostFs {
+    fn open(&mut self, path: &str, flags: u32) -> Result<Box<dyn FileHandle>, i32> {
+        let path = self.resolve(path)?;
+        if path.is_dir() {
+            return Err(EISDIR);
+        }
+        let access = flags & O_ACCMODE;
+        let file = OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(access != O_RDONLY)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL)
+            .open(path)
+            .map_err(|error| errno(&error))?;
+        Ok(Box::new(file))
+    }
+}
+
+#[cfg(feature = "std")]
+impl FileHandle for File {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i32> {
+        Read::read(self, buffer).map_err(|error| errno(&error))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, i32> {
+        Write::write(self, bytes).map_err(|error| errno(&error))
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, i32> {
+        Seek::seek(self, position).map_err(|error| errno(&error))
+    }
+
+    fn size(&mut self) -> Result<u64, i32> {
+        self.metadata()
+            .map(|metadata| metadata.len())
+            .map_err(|error| errno(&error))
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TarError {
+    #[error("the archive ends inside the entry at {offset:#x}")]
+    Truncated { offset: usize },
+
+    #[error("the header at {offset:#x} has a bad checksum")]
+    BadChecksum { offset: usize },
+
+    #[error("the header at {offset:#x} has a bad {field}")]
+    BadField { offset: usize, field: &'static str },
+}
+
+/// files in memory, by path. Open files share the contents with the file
+/// system, so what the guest writes can be read back from it
+#[derive(Debug, Clone, Default)]
+pub struct VirtFs {
+    files: HashMap<String, Rc<RefCell<Vec<u8>>>>,
+}
+
+impl VirtFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the regular files of a ustar (or old v7) tar archive, the other
+    /// entries (directories, links) are left out
+    pub fn from_tar(archive: &[u8]) -> Result<Self, TarError> {
+        let mut fs = Self::new();
+        let mut offset = 0;
+        while offset + TAR_BLOCK <= archive.len() {
+            let header = &archive[offset..offset + TAR_BLOCK];
+            // two zero blocks end the archive, one is enough for us
+            if header.iter().all(|&byte| byte == 0) {
+                return Ok(fs);
+            }
+            let field = |field| TarError::BadField { offset, field };
+            let checksum = octal(&header[148..156]).ok_or(field("checksum"))?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(index, &byte)| match index {
+                    148..156 => u64::from(b' '),
+                    _ => u64::from(byte),
+                })
+                .sum();
+            if sum != checksum {
+                return Err(TarError::BadChecksum { offset });
+            }
+
+            let size = octal(&header[124..136]).ok_or(field("size"))? as usize;
+            let contents = offset + TAR_BLOCK;
+            let data = archive
+                .get(contents..contents + size)
+                .ok_or(TarError::Truncated { offset })?;
+            if matches!(header[156], b'0' | 0) {
+                let name = text(&header[..100]).ok_or(field("name"))?;
+                // GNU tar has no prefix, its magic is "ustar  "
+                let path = match &header[257..263] {
+                    b"ustar\0" => {
+                        let prefix = text(&header[345..500]).ok_or(field("prefix"))?;
+                        format!("{prefix}/{name}")
+                    }
+                    _ => name.to_string(),
+                };
+                fs.insert(&path, data);
+            }
+            offset = contents + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        }
+        if offset == archive.len() {
+            Ok(fs)
+        } else {
+            Err(TarError::Truncated { offset })
+        }
+    }
+
+    /// adds or replaces the file at `path`
+    pub fn insert(&mut self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.files
+            .insert(normalize(path), Rc::new(RefCell::new(contents.into())));
+    }
+
+    /// the contents of the file at `path`
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        let file = self.files.get(&normalize(path))?;
+        Some(file.borrow().clone())
+    }
+}
+
+/// a NUL padded string field of a tar header
+fn text(field: &[u8]) -> Option<&str> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).ok()
+}
+
+/// a NUL or space padded octal number field of a tar header
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = text(field)?.trim_matches(' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// `path` without `.`, `..` and the leading `/`, the working directory is
+/// the root
+fn normalize(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+impl FileSystem for VirtFs {
+    fn open(&mut self, path: &str, flags: u32) -> Result<Box<dyn FileHandle>, i32> {
+        let path = normalize(path);
+        let directory = format!("{path}/");
+        if path.is_empty() || self.files.keys().any(|file| file.starts_with(&directory)) {
+            return Err(EISDIR);
+        }
+        let contents = match self.files.get(&path) {
+            Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(EEXIST),
+            Some(contents) => contents.clone(),
+            None if flags & O_CREAT != 0 => self.files.entry(path).or_default().clone(),
+            None => return Err(ENOENT),
+        };
+        let access = flags & O_ACCMODE;
+        if flags & O_TRUNC != 0 && access != O_RDONLY {
+            contents.borrow_mut().clear();
+        }
+        Ok(Box::new(VirtFile {
+            contents,
+            position: 0,
+            readable: access != O_WRONLY,
+            writable: access != O_RDONLY,
+            append: flags & O_APPEND != 0,
+        }))
+    }
+}
+
+struct VirtFile {
+    contents: Rc<RefCell<Vec<u8>>>,
+    position: u64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl FileHandle for VirtFile {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i32> {
+        if !self.readable {
+            return Err(EBADF);
+        }
+        let contents = self.contents.borrow();
+        let start = (self.position as usize).min(contents.len());
+        let length = buffer.len().min(contents.len() - start);
+        buffer[..length].copy_from_slice(&contents[start..start + length]);
+        self.position += length as u64;
+        Ok(length)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, i32> {
+        if !self.writable {
+            return Err(EBADF);
+        }
+        let mut contents = self.contents.borrow_mut();
+        if self.append {
+            self.position = contents.len() as u64;
+        }
+        let start = self.position as usize;
+        if contents.len() < start + bytes.len() {
+            contents.resize(start + bytes.len(), 0);
+        }
+        contents[start..start + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, i32> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => (self.contents.borrow().len() as u64, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or(EINVAL)?;
+        Ok(self.position)
+    }
+
+    fn size(&mut self) -> Result<u64, i32> {
+        Ok(self.contents.borrow().len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSystem, HostFs, TarError, VirtFs};
+    use crate::syscalls::{EACCES, EISDIR, O_APPEND, O_CREAT, O_RDONLY, O_WRONLY};
+
+    /// a ustar header for `path`, the prefix holding its directory
+    fn tar_header(path: &str, kind: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        let (prefix, name) = path.rsplit_once('/').unwrap_or(("", path));
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        header
+    }
+
+    #[test]
+    fn host_fs_should_only_open_allowed_directories() {
+        let directory = std::env::temp_dir().join("riscv-vm-host-fs");
+        let allowed = directory.join("allowed");
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::write(directory.join("secret"), "secret").unwrap();
+        std::fs::write(allowed.join("file"), "hello").unwrap();
+        let mut fs = HostFs::new().allow(&allowed).unwrap();
+
+        let mut file = fs
+            .open(allowed.join("file").to_str().unwrap(), O_RDONLY)
+            .unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!(file.read(&mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"hello");
+
+        for path in [directory.join("secret"), allowed.join("../secret")] {
+            assert_eq!(
+                fs.open(path.to_str().unwrap(), O_RDONLY).err(),
+                Some(EACCES)
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn host_fs_should_not_create_files_through_dangling_symlinks() {
+        let directory = std::env::temp_dir().join("riscv-vm-host-fs-symlink");
+        let allowed = directory.join("allowed");
+        std::fs::create_dir_all(&allowed).unwrap();
+        let outside = directory.join("outside");
+        let _ = std::fs::remove_file(&outside);
+        let link = allowed.join("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+        let mut fs = HostFs::new().allow(&allowed).unwrap();
+
+        assert_eq!(
+            fs.open(link.to_str().unwrap(), O_WRONLY | O_CREAT).err(),
+            Some(EACCES)
+        );
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn virt_fs_should_keep_what_the_guest_writes() {
+        let mut fs = VirtFs::new();
+        fs.insert("/log", "a");
+        let mut file = fs.open("./tmp/../log", O_WRONLY | O_APPEND).unwrap();
+        assert_eq!(file.write(b"bc"), Ok(2));
+        fs.open("new", O_WRONLY | O_CREAT).unwrap();
+
+        assert_eq!(fs.file("log").unwrap(), b"abc");
+        assert_eq!(fs.file("/new").unwrap(), b"");
+    }
+
+    #[test]
+    fn should_load_a_tar_archive() {
+        let mut archive = tar_header("data", b'5', 0);
+        archive.extend(tar_header("data/input.txt", b'0', 5));
+        archive.extend(b"hello");
+        archive.resize(3 * 512, 0);
+        archive.extend([0; 1024]);
+
+        let mut fs = VirtFs::from_tar(&archive).unwrap();
+        assert_eq!(fs.file("/data/input.txt").unwrap(), b"hello");
+        assert_eq!(fs.open("data", O_RDONLY).err(), Some(EISDIR));
+
+        archive[0] ^= 1;
+        assert_eq!(
+            VirtFs::from_tar(&archive).err(),
+            Some(TarError::BadChecksum { offset: 0 })
+        );
+        assert_eq!(
+            VirtFs::from_tar(&archive[512..1024]).err(),
+            Some(TarError::Truncated { offset: 0 })
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Gas metering for sandboxed execution: every instruction costs gas
+//! depending on its class and `run()` stops with `StopReason::OutOfGas`
+//! before an instruction the budget can not pay for. The host can add gas
+//! and call `run()` again to continue.
+
+use super::emulator::{Instruction, PseudoInstruction, Vm};
+use super::rv32i::Rv32iInstruction;
+
+/// the groups of instructions that can cost a different amount of gas (and
+/// cycles, see `TimingModel`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstructionClass {
+    /// arithmetic, logic, shifts, compares, lui, auipc and li
+    Alu,
+    /// the loads and `lr.w`
+    Load,
+    /// the stores, `sc.w` and the AMOs
+    Store,
+    /// conditional branches
+    Branch,
+    /// jal, jalr and ret
+    Jump,
+    /// ecall, ebreak and the CSR reads
+    System,
+}
+
+impl InstructionClass {
+    pub fn of(instruction: &Instruction) -> Self {
+        use Rv32iInstruction::*;
+
+        match instruction {
+            Instruction::Rv32iInstruction(_, rv32i_instruction) => match rv32i_instruction {
+                Lb(_) | Lh(_) | Lw(_) | Lbu(_) | Lhu(_) | LrW(_) => Self::Load,
+                Sb(_) | Sh(_) | Sw(_) | ScW(_) | AmoswapW(_) | AmoaddW(_) | AmoxorW(_)
+                | AmoandW(_) | AmoorW(_) | AmominW(_) | AmomaxW(_) | AmominuW(_) | AmomaxuW(_) => {
+                    Self::Store
+                }
+                Beq(_) | Bne(_) | Blt(_) | Bge(_) | Bltu(_) | Bgeu(_) => Self::Branch,
+                Jal(_) | Jalr(_) => Self::Jump,
+                Ecall | Ebreak | Mret | Sret | Wfi | Fence(_) | FenceI | SfenceVma(_)
+                | Csrrw(_) | Csrrs(_) | Csrrc(_) | Csrrwi(_) | Csrrsi(_) | Csrrci(_) => {
+                    Self::System
+                }
+                _ => Self::Alu,
+            },
+            Instruction::PseudoInstruction(_, PseudoInstruction::Ret) => Self::Jump,
+            Instruction::PseudoInstruction(_, PseudoInstruction::Li(_)) => Self::Alu,
+        }
+    }
+}
+
+/// how much gas one instruction of each class costs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasSchedule {
+    pub alu: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub system: u64,
+}
+
+/// memory accesses and ecalls cost more than plain arithmetic
+impl Default for GasSchedule {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            load: 3,
+            store: 3,
+            branch: 2,
+            jump: 2,
+            system: 10,
+        }
+    }
+}
+
+impl GasSchedule {
+    /// every instruction costs `cost`
+    pub fn uniform(cost: u64) -> Self {
+        Self {
+            alu: cost,
+            load: cost,
+            store: cost,
+            branch: cost,
+            jump: cost,
+            system: cost,
+        }
+    }
+
+    pub fn cost(&self, class: InstructionClass) -> u64 {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::Load => self.load,
+            InstructionClass::Store => self.store,
+            InstructionClass::Branch => self.branch,
+            InstructionClass::Jump => self.jump,
+            InstructionClass::System => self.system,
+        }
+    }
+}
+
+/// the gas left and what instructions cost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasMeter {
+    pub schedule: GasSchedule,
+    pub remaining: u64,
+}
+
+impl GasMeter {
+    pub fn new(schedule: GasSchedule, budget: u64) -> Self {
+        Self {
+            schedule,
+            remaining: budget,
+        }
+    }
+
+    /// takes the cost of `instruction` from the budget, returns false and
+    /// takes nothing if the budget is too small
+    pub fn charge(&mut self, instruction: &Instruction) -> bool {
+        let cost = self.schedule.cost(InstructionClass::of(instruction));
+        match self.remaining.checked_sub(cost) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Vm {
+    /// turns on gas metering
+    pub fn with_gas(mut self, gas: GasMeter) -> Self {
+        self.gas = Some(gas);
+        self
+    }
+
+    /// the gas left, `None` when metering is off
+    pub fn remaining_gas(&self) -> Option<u64> {
+        self.gas.as_ref().map(|gas| gas.remaining)
+    }
+
+    /// sets the gas left, does nothing when metering is off
+    pub fn set_remaining_gas(&mut self, remaining: u64) {
+        if let Some(gas) = &mut self.gas {
+            gas.remaining = remaining;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GasMeter, GasSchedule};
+    use crate::{StopReason, Vm};
+
+    // 0x1000 addi a0, zero, 42
+    // 0x1004 lui t0, 0x1
+    // 0x1008 sw a0, 0x80(t0)
+    // 0x100c ebreak
+    const PROGRAM: [u32; 4] = [0x02a0_0513, 0x0000_12b7, 0x08a2_a023, 0x0010_0073];
+
+    #[test]
+    fn run_should_stop_before_an_instruction_it_can_not_pay_for() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM)
+            .with_gas(GasMeter::new(GasSchedule::default(), 4));
+
+        // addi and lui cost 1 each, the store costs 3
+        assert_eq!(vm.run(), Ok(StopReason::OutOfGas { pc: 0x1008 }));
+        assert_eq!(vm.remaining_gas(), Some(2));
+        assert_eq!(vm.memory.read_u32(0x1080).unwrap(), 0);
+
+        vm.set_remaining_gas(3);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.remaining_gas(), Some(0));
+        assert_eq!(vm.memory.read_u32(0x1080).unwrap(), 42);
+    }
+
+    #[test]
+    fn restoring_a_snapshot_should_restore_the_budget() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM)
+            .with_gas(GasMeter::new(GasSchedule::uniform(1), 100));
+        let snapshot = vm.snapshot();
+
+        vm.run().unwrap();
+        assert_eq!(vm.remaining_gas(), Some(97));
+
+        vm.restore(&snapshot);
+        assert_eq!(vm.remaining_gas(), Some(100));
+        assert_eq!(vm.vm_state.pc, 0x1000);
+    }
+}
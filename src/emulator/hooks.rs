@@ -0,0 +1,135 @@
+use super::emulator::{Instruction, Vm, VmState};
+use super::prelude::*;
+use super::register::Register;
+
+/// Callbacks into the interpreter loop, for profilers, taint trackers,
+/// coverage tools, ... Every method does nothing by default so a hook only
+/// implements what it needs. Hooks are registered with `Vm::add_hooks` and
+/// are called in the order they were added.
+///
+/// The hooks only see what the guest does through `Vm::step()`/`Vm::run()`,
+/// not what the host does to the vm directly. An instruction that faults gets
+/// `before_instruction` but none of the others.
+pub trait VmHooks {
+    /// before `instruction` runs, the pc still points to it
+    fn before_instruction(&mut self, _vm_state: &VmState, _instruction: &Instruction) {}
+
+    /// after `instruction` ran, the pc already points to the next one
+    fn after_instruction(&mut self, _vm_state: &VmState, _instruction: &Instruction) {}
+
+    /// a load of `size` bytes, `value` is not sign extended
+    fn on_memory_read(&mut self, _pc: u32, _address: u32, _size: u32, _value: u32) {}
+
+    /// a store of `size` bytes
+    fn on_memory_write(&mut self, _pc: u32, _address: u32, _size: u32, _value: u32) {}
+
+    /// an instruction wrote `value` to `register`. Writes to x0 are thrown
+    /// away and are not reported
+    fn on_register_write(&mut self, _pc: u32, _register: Register, _value: i32) {}
+
+    /// the instruction at `pc` trapped into the guest's handler with
+    /// `cause`, `value` went to `mtval`
+    fn on_trap(&mut self, _pc: u32, _cause: u32, _value: u32) {}
+}
+
+impl Vm {
+    /// registers hooks that are called while the guest runs
+    pub fn add_hooks(&mut self, hooks: Box<dyn VmHooks>) {
+        self.hooks.push(hooks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::VmHooks;
+    use crate::{Instruction, Register, Vm, VmState};
+
+    /// writes every callback into a shared log
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl VmHooks for Recorder {
+        fn before_instruction(&mut self, vm_state: &VmState, _instruction: &Instruction) {
+            self.0
+                .borrow_mut()
+                .push(format!("before {:#x}", vm_state.pc));
+        }
+
+        fn after_instruction(&mut self, vm_state: &VmState, _instruction: &Instruction) {
+            self.0
+                .borrow_mut()
+                .push(format!("after {:#x}", vm_state.pc));
+        }
+
+        fn on_memory_read(&mut self, pc: u32, address: u32, size: u32, value: u32) {
+            self.0
+                .borrow_mut()
+                .push(format!("{pc:#x} read {size} {value:#x} at {address:#x}"));
+        }
+
+        fn on_memory_write(&mut self, pc: u32, address: u32, size: u32, value: u32) {
+            self.0
+                .borrow_mut()
+                .push(format!("{pc:#x} write {size} {value:#x} at {address:#x}"));
+        }
+
+        fn on_register_write(&mut self, pc: u32, register: Register, value: i32) {
+            self.0
+                .borrow_mut()
+                .push(format!("{pc:#x} {register} = {value}"));
+        }
+    }
+
+    #[test]
+    fn hooks_should_see_instructions_memory_and_registers() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 addi a0, zero, -2
+        // 0x1008 sh a0, 0x80(t0)
+        // 0x100c lh a1, 0x80(t0)
+        // 0x1010 lbu a2, 0x80(t0)
+        // 0x1014 ebreak
+        let program: Vec<u8> = [
+            0x0000_12b7u32,
+            0xffe0_0513,
+            0x08a2_9023,
+            0x0802_9583,
+            0x0802_c603,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        vm.add_hooks(Box::new(Recorder(log.clone())));
+        vm.run().unwrap();
+
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "before 0x1000",
+                "0x1000 t0 = 4096",
+                "after 0x1004",
+                "before 0x1004",
+                "0x1004 a0 = -2",
+                "after 0x1008",
+                "before 0x1008",
+                "0x1008 write 2 0xfffe at 0x1080",
+                "after 0x100c",
+                "before 0x100c",
+                "0x100c read 2 0xfffe at 0x1080",
+                "0x100c a1 = -2",
+                "after 0x1010",
+                "before 0x1010",
+                "0x1010 read 1 0xfe at 0x1080",
+                "0x1010 a2 = 254",
+                "after 0x1014",
+            ]
+        );
+        assert_eq!(vm.memory.read_u16(0x1080).unwrap(), 0xfffe);
+    }
+}
@@ -0,0 +1,252 @@
+//! Host functions the guest can call, for embedders that give guest
+//! programs services of their own (fetching a URL, crypto, drawing on a
+//! canvas). A hostcall is a closure that gets up to six arguments and the
+//! guest memory, and returns one word. The guest calls it either way:
+//!
+//! - with an `ecall`, the number in a7, the arguments in a0 to a5 and the
+//!   result in a0 (`Vm::add_hostcall`),
+//! - through a doorbell device, for guests that can not take the ecall
+//!   numbers: it stores the arguments to the words at 0x00 to 0x14, any
+//!   value to `DOORBELL_RING` and loads the result from `DOORBELL_RESULT`
+//!   (`Vm::add_hostcall_doorbell`).
+//!
+//! Buffers are passed as a pointer and a length, `HostcallArgs` has the
+//! helpers to read and write them.
+
+use super::emulator::Vm;
+use super::error::VmError;
+use super::memory::Memory;
+use super::mmio::Device;
+use super::prelude::*;
+use super::register::Register;
+
+/// storing to it calls the hostcall
+pub const DOORBELL_RING: u32 = 0x18;
+/// the result of the last call
+pub const DOORBELL_RESULT: u32 = 0x1c;
+pub const DOORBELL_SIZE: u32 = 0x20;
+/// the longest string `c_str` reads
+const MAX_C_STRING: usize = 4096;
+
+/// a host function, it returns the result for the guest
+pub type Hostcall = Box<dyn FnMut(&mut HostcallArgs<'_>) -> Result<u32, VmError>>;
+
+/// the arguments of one call and the guest memory
+pub struct HostcallArgs<'a> {
+    args: [u32; 6],
+    memory: &'a mut Memory,
+    /// whether a helper wrote to the memory
+    wrote: bool,
+}
+
+impl<'a> HostcallArgs<'a> {
+    pub fn new(args: [u32; 6], memory: &'a mut Memory) -> Self {
+        Self {
+            args,
+            memory,
+            wrote: false,
+        }
+    }
+
+    /// argument `index`, 0 to 5
+    pub fn arg(&self, index: usize) -> u32 {
+        self.args[index]
+    }
+
+    /// the whole guest memory, for what the helpers do not cover
+    pub fn memory(&mut self) -> &mut Memory {
+        self.wrote = true;
+        self.memory
+    }
+
+    /// the buffer whose pointer is argument `pointer` and whose length is
+    /// argument `length`
+    pub fn bytes(&self, pointer: usize, length: usize) -> Result<&[u8], VmError> {
+        self.memory
+            .read_bytes(self.args[pointer], self.args[length] as usize)
+    }
+
+    /// `bytes` as UTF-8, invalid sequences are replaced
+    pub fn str(&self, pointer: usize, length: usize) -> Result<String, VmError> {
+        Ok(String::from_utf8_lossy(self.bytes(pointer, length)?).into_owned())
+    }
+
+    /// the NUL terminated string argument `pointer` points at, `None`
+    /// when it is longer than `MAX_C_STRING`
+    pub fn c_str(&self, pointer: usize) -> Result<Option<String>, VmError> {
+        let string = self
+            .memory
+            .read_c_string(self.args[pointer], MAX_C_STRING)?;
+        Ok(string.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    /// copies as much of `data` as fits into the buffer of arguments
+    /// `pointer` and `length`, returns how much that was
+    pub fn write_bytes(
+        &mut self,
+        pointer: usize,
+        length: usize,
+        data: &[u8],
+    ) -> Result<u32, VmError> {
+        let count = data.len().min(self.args[length] as usize);
+        self.memory
+            .write_bytes(self.args[pointer], &data[..count])?;
+        self.wrote = true;
+        Ok(count as u32)
+    }
+}
+
+/// the arguments and the result of a doorbell, and the hostcall it rings
+struct Doorbell {
+    args: [u32; 6],
+    result: u32,
+    hostcall: Hostcall,
+}
+
+impl Device for Doorbell {
+    fn name(&self) -> &'static str {
+        "doorbell"
+    }
+
+    fn read(&mut self, offset: u32, _size: u32, _memory: &mut Memory) -> u32 {
+        match offset {
+            DOORBELL_RESULT => self.result,
+            offset if offset < DOORBELL_RING => self.args[offset as usize / 4],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32, memory: &mut Memory) -> bool {
+        match offset {
+            DOORBELL_RING => {
+                let mut args = HostcallArgs::new(self.args, memory);
+                // a device has no way to fail, the guest gets -1
+                self.result = (self.hostcall)(&mut args).unwrap_or(u32::MAX);
+                args.wrote
+            }
+            offset if offset < DOORBELL_RING => {
+                self.args[offset as usize / 4] = value;
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Vm {
+    /// `ecall` number `number` calls `hostcall`, the policy still decides
+    /// whether the host gets it
+    pub fn add_hostcall(&mut self, number: u32, mut hostcall: Hostcall) {
+        self.add_ecall_handler(
+            number,
+            Box::new(move |vm_state, memory| {
+                let registers = &vm_state.registers;
+                let args = [
+                    Register::A0,
+                    Register::A1,
+                    Register::A2,
+                    Register::A3,
+                    Register::A4,
+                    Register::A5,
+                ]
+                .map(|register| registers[register] as u32);
+                let result = hostcall(&mut HostcallArgs::new(args, memory))?;
+                vm_state.registers.write(Register::A0, result as i32);
+                Ok(())
+            }),
+        );
+    }
+
+    /// a doorbell at `base` that calls `hostcall`, `DOORBELL_SIZE` bytes
+    pub fn add_hostcall_doorbell(&mut self, base: u32, hostcall: Hostcall) {
+        let doorbell = Doorbell {
+            args: [0; 6],
+            result: 0,
+            hostcall,
+        };
+        self.add_device(base, DOORBELL_SIZE, Box::new(doorbell));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Register, StopReason, Vm};
+
+    /// upper cases the string of arguments 0 and 1 into the buffer of
+    /// arguments 2 and 3
+    fn upper_case() -> super::Hostcall {
+        Box::new(|args| {
+            let text = args.str(0, 1)?.to_uppercase();
+            args.write_bytes(2, 3, text.as_bytes())
+        })
+    }
+
+    #[test]
+    fn should_call_the_host_with_an_ecall_and_a_doorbell() {
+        // 0x1000 addi a0, zero, 0x80   <- "hi"
+        // 0x1004 addi a1, zero, 2
+        // 0x1008 addi a2, zero, 0x90
+        // 0x100c addi a3, zero, 1      <- only one byte fits
+        // 0x1010 addi a7, zero, 500
+        // 0x1014 ecall
+        // 0x1018 ebreak
+        let program: Vec<u8> = [
+            0x0800_0513u32,
+            0x0020_0593,
+            0x0900_0613,
+            0x0010_0693,
+            0x1f40_0893,
+            0x0000_0073,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0, 0x2000);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.load_program(0x80, b"hi").unwrap();
+        vm.add_hostcall(500, upper_case());
+        vm.vm_state.pc = 0x1000;
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[Register::A0], 1);
+        assert_eq!(vm.memory.read_bytes(0x90, 2).unwrap(), b"H\0");
+
+        // 0x1000 lui t0, 0x20000       <- the doorbell
+        // 0x1004 addi t1, zero, 0x80
+        // 0x1008 sw t1, 0(t0)
+        // 0x100c addi t1, zero, 2
+        // 0x1010 sw t1, 4(t0)
+        // 0x1014 addi t1, zero, 0xa0
+        // 0x1018 sw t1, 8(t0)
+        // 0x101c sw t1, 12(t0)
+        // 0x1020 sw zero, 24(t0)       <- DOORBELL_RING
+        // 0x1024 lw a0, 28(t0)         <- DOORBELL_RESULT
+        // 0x1028 ebreak
+        let program: Vec<u8> = [
+            0x2000_02b7u32,
+            0x0800_0313,
+            0x0062_a023,
+            0x0020_0313,
+            0x0062_a223,
+            0x0a00_0313,
+            0x0062_a423,
+            0x0062_a623,
+            0x0002_ac23,
+            0x01c2_a503,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0, 0x2000);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.load_program(0x80, b"hi").unwrap();
+        vm.add_hostcall_doorbell(0x2000_0000, upper_case());
+        vm.vm_state.pc = 0x1000;
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[Register::A0], 2);
+        assert_eq!(vm.memory.read_bytes(0xa0, 2).unwrap(), b"HI");
+    }
+}
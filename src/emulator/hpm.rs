@@ -0,0 +1,209 @@
+//! The hardware performance counters of Zihpm, so guest code that reads
+//! perf counters gets real numbers: `mhpmevent3` to `mhpmevent31` pick what
+//! `mhpmcounter3` to `mhpmcounter31` count, `hpmcounter3` to `hpmcounter31`
+//! (and the `h` CSRs for the upper halves) read them. The event numbers
+//! are `HpmEvent`, 0 or an unknown number counts nothing. Mispredictions
+//! need a predictor, see `branch_predictor.rs`.
+//!
+//! The counters only exist after `Vm::with_performance_counters`, `cycle`,
+//! `time` and `instret` of Zicntr always do (see `timing.rs`).
+
+use super::branch_predictor::BranchPredictor;
+use super::emulator::{Instruction, Vm};
+use super::memory::MemoryAccess;
+use super::prelude::*;
+use super::rv32i::Rv32iInstruction;
+use super::timing::CSR_HIGH_HALF;
+
+pub const CSR_MHPMEVENT3: u16 = 0x323;
+pub const CSR_MHPMCOUNTER3: u16 = 0xb03;
+pub const CSR_HPMCOUNTER3: u16 = 0xc03;
+/// counters 3 to 31
+const COUNTERS: usize = 29;
+
+/// what a counter counts, the number goes to its `mhpmevent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpmEvent {
+    Loads = 1,
+    Stores = 2,
+    /// conditional branches
+    Branches = 3,
+    TakenBranches = 4,
+    /// the conditional branches the predictor got wrong
+    Mispredictions = 5,
+}
+
+#[derive(Debug, Default)]
+pub struct PerformanceCounters {
+    events: [u32; COUNTERS],
+    counters: [u64; COUNTERS],
+    predictor: Option<Box<dyn BranchPredictor>>,
+}
+
+impl PerformanceCounters {
+    /// every counter at 0 and counting nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// predicts every branch with `predictor`, for `Mispredictions`
+    pub fn with_branch_predictor(mut self, predictor: Box<dyn BranchPredictor>) -> Self {
+        self.predictor = Some(predictor);
+        self
+    }
+
+    /// counter `index`, 3 to 31
+    pub fn counter(&self, index: usize) -> u64 {
+        self.counters[index - 3]
+    }
+
+    /// counts the events of `instruction`, which did `access` and went on
+    /// to `next_pc`
+    pub(super) fn record(
+        &mut self,
+        instruction: &Instruction,
+        access: Option<MemoryAccess>,
+        next_pc: u32,
+    ) {
+        let mut events = 0u32;
+        match access {
+            Some(MemoryAccess::Read { .. }) => events |= 1 << HpmEvent::Loads as u32,
+            Some(MemoryAccess::Write { .. }) => events |= 1 << HpmEvent::Stores as u32,
+            None => {}
+        }
+        if let Instruction::Rv32iInstruction(
+            _,
+            Rv32iInstruction::Beq(branch)
+            | Rv32iInstruction::Bne(branch)
+            | Rv32iInstruction::Blt(branch)
+            | Rv32iInstruction::Bge(branch)
+            | Rv32iInstruction::Bltu(branch)
+            | Rv32iInstruction::Bgeu(branch),
+        ) = instruction
+        {
+            let pc = instruction.address() as u32;
+            let target = pc.wrapping_add(i32::from(branch.imm) as u32);
+            let taken = next_pc == target;
+            events |= 1 << HpmEvent::Branches as u32;
+            if taken {
+                events |= 1 << HpmEvent::TakenBranches as u32;
+            }
+            if let Some(predictor) = &mut self.predictor {
+                if predictor.predict(pc, target) != taken {
+                    events |= 1 << HpmEvent::Mispredictions as u32;
+                }
+                predictor.update(pc, taken);
+            }
+        }
+        for (event, counter) in self.events.iter().zip(&mut self.counters) {
+            if *event < 32 && events & 1 << event != 0 {
+                *counter += 1;
+            }
+        }
+    }
+
+    /// the value of a counter or event CSR, `None` for the other CSRs
+    pub(super) fn read(&self, csr: u16) -> Option<u32> {
+        if let Some(index) = index(csr, CSR_MHPMEVENT3) {
+            return Some(self.events[index]);
+        }
+        let index = index(csr & !CSR_HIGH_HALF, CSR_MHPMCOUNTER3)
+            .or_else(|| index(csr & !CSR_HIGH_HALF, CSR_HPMCOUNTER3))?;
+        let counter = self.counters[index];
+        Some(if csr & CSR_HIGH_HALF != 0 {
+            (counter >> 32) as u32
+        } else {
+            counter as u32
+        })
+    }
+
+    /// writes an event or a machine counter CSR, false for the others
+    pub(super) fn write(&mut self, csr: u16, value: u32) -> bool {
+        if let Some(index) = index(csr, CSR_MHPMEVENT3) {
+            self.events[index] = value;
+        } else if let Some(index) = index(csr & !CSR_HIGH_HALF, CSR_MHPMCOUNTER3) {
+            let counter = &mut self.counters[index];
+            *counter = if csr & CSR_HIGH_HALF != 0 {
+                *counter & 0xffff_ffff | u64::from(value) << 32
+            } else {
+                *counter & !0xffff_ffff | u64::from(value)
+            };
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+/// which of the 29 CSRs from `first` `csr` is
+fn index(csr: u16, first: u16) -> Option<usize> {
+    let index = usize::from(csr.checked_sub(first)?);
+    (index < COUNTERS).then_some(index)
+}
+
+impl Vm {
+    /// the Zihpm counters from now on
+    pub fn with_performance_counters(mut self, counters: PerformanceCounters) -> Self {
+        self.performance_counters = Some(counters);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PerformanceCounters;
+    use crate::branch_predictor::StaticPredictor;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_count_the_events_the_guest_picks() {
+        // 0x1000 addi t0, zero, 1
+        // 0x1004 csrrw zero, mhpmevent3, t0      <- loads
+        // 0x1008 addi t0, zero, 3
+        // 0x100c csrrw zero, mhpmevent4, t0      <- branches
+        // 0x1010 addi t0, zero, 5
+        // 0x1014 csrrw zero, mhpmevent5, t0      <- mispredictions
+        // 0x1018 addi s0, zero, 3
+        // 0x101c lw t1, 0x80(zero)
+        // 0x1020 addi s0, s0, -1
+        // 0x1024 bne s0, zero, 0x101c
+        // 0x1028 csrrs a0, hpmcounter3, zero
+        // 0x102c csrrs a1, hpmcounter4, zero
+        // 0x1030 csrrs a2, hpmcounter5, zero
+        // 0x1034 ebreak
+        let program: Vec<u8> = [
+            0x0010_0293u32,
+            0x3232_9073,
+            0x0030_0293,
+            0x3242_9073,
+            0x0050_0293,
+            0x3252_9073,
+            0x0030_0413,
+            0x0800_2303,
+            0xfff4_0413,
+            0xfe04_1ce3,
+            0xc030_2573,
+            0xc040_25f3,
+            0xc050_2673,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let counters = PerformanceCounters::new().with_branch_predictor(Box::new(StaticPredictor));
+        let mut vm = Vm::new(0, 0x2000).with_performance_counters(counters);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.pc = 0x1000;
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        // the loop exit is the one the static predictor gets wrong
+        assert_eq!(vm.vm_state.registers.as_array()[10..13], [3, 3, 1]);
+
+        // the machine counters can be written, the user ones can not
+        assert!(vm.write_csr(0xb83, 1));
+        assert_eq!(vm.read_csr(0xc83), Some(1));
+        assert_eq!(vm.read_csr(0xb03), Some(3));
+        assert!(!vm.write_csr(0xc03, 0));
+        assert_eq!(Vm::new(0, 0).read_csr(0xc03), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! The host-target interface of spike, so bare-metal programs built for it
+//! (the riscv-tests, programs on riscv-pk's HTIF console) run unmodified.
+//! The guest writes a 64 bit command to `tohost`, the host clears it and
+//! answers in `fromhost`:
+//!
+//! ```text
+//! 63      56 55     48 47                  0
+//! | device  | command |       payload       |
+//! ```
+//!
+//! - device 0, command 0, payload `(code << 1) | 1`: exit with `code`, `run()`
+//!   stops with `HtifExit`. 1 means a riscv-test passed, anything else
+//!   carries the number of the check that failed
+//! - device 1, command 1: writes the low byte of the payload to the console
+//! - device 1, command 0: reads a byte, the answer is `0x100 | byte` (no
+//!   answer while there is no input)
+//!
+//! An RV32 guest writes the command as two words, low then high, so it is
+//! carried out once the high word is written. Other commands (the syscall
+//! proxy of riscv-pk) are cleared and ignored.
+
+use alloc::collections::VecDeque;
+
+use super::emulator::{StopReason, Vm};
+use super::memory::MemoryAccess;
+use super::prelude::*;
+
+pub const HTIF_DEVICE_SYSCALL: u8 = 0;
+pub const HTIF_DEVICE_CONSOLE: u8 = 1;
+pub const HTIF_CONSOLE_GETCHAR: u8 = 0;
+pub const HTIF_CONSOLE_PUTCHAR: u8 = 1;
+
+/// the addresses of `tohost` and `fromhost` and the console
+#[derive(Debug, Clone)]
+pub struct Htif {
+    pub tohost: u32,
+    /// where the answers go, `None` means the guest gets none
+    pub fromhost: Option<u32>,
+    /// what the guest wrote to the console
+    pub output: Vec<u8>,
+    /// what the guest reads from the console
+    pub input: VecDeque<u8>,
+}
+
+impl Htif {
+    pub fn new(tohost: u32) -> Self {
+        Self {
+            tohost,
+            fromhost: None,
+            output: Vec::new(),
+            input: VecDeque::new(),
+        }
+    }
+
+    pub fn with_fromhost(mut self, fromhost: u32) -> Self {
+        self.fromhost = Some(fromhost);
+        self
+    }
+}
+
+impl Vm {
+    /// turns on HTIF at fixed addresses, instead of the `tohost` and
+    /// `fromhost` symbols `load_elf` looks for
+    pub fn with_htif(mut self, htif: Htif) -> Self {
+        self.htif = Some(htif);
+        self
+    }
+
+    /// turns on HTIF at the `tohost` and `fromhost` symbols of the loaded
+    /// ELF file, if there is a `tohost` and HTIF is not on yet
+    pub(super) fn htif_from_symbols(&mut self) {
+        if self.htif.is_some() {
+            return;
+        }
+        let Some(tohost) = self.symbol("tohost").map(|symbol| symbol.address) else {
+            return;
+        };
+        let fromhost = self.symbol("fromhost").map(|symbol| symbol.address);
+        self.htif = Some(Htif {
+            fromhost,
+            ..Htif::new(tohost)
+        });
+    }
+
+    /// carries out the command in `tohost` once `access` wrote its high word
+    pub(super) fn check_tohost(&mut self, access: MemoryAccess) {
+        let Some(htif) = &mut self.htif else {
+            return;
+        };
+        let MemoryAccess::Write { address, size, .. } = access else {
+            return;
+        };
+        let high = htif.tohost.wrapping_add(4);
+        if address > high || address.wrapping_add(size) <= high {
+            return;
+        }
+        let (Ok(low), Ok(high)) = (
+            self.memory.read_u32(htif.tohost),
+            self.memory.read_u32(htif.tohost.wrapping_add(4)),
+        ) else {
+            return;
+        };
+        let command = u64::from(high) << 32 | u64::from(low);
+        if command == 0 {
+            return;
+        }
+        let device = (command >> 56) as u8;
+        let code = (command >> 48) as u8;
+        let payload = command & 0xffff_ffff_ffff;
+
+        let answer = match (device, code) {
+            (HTIF_DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                self.stop = Some(StopReason::HtifExit {
+                    code: (payload >> 1) as i32,
+                });
+                None
+            }
+            (HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR) => {
+                htif.output.push(payload as u8);
+                Some(0)
+            }
+            (HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR) => {
+                htif.input.pop_front().map(|byte| 0x100 | u64::from(byte))
+            }
+            _ => None,
+        };
+        let tohost = htif.tohost;
+        let answer = answer
+            .zip(htif.fromhost)
+            .map(|(payload, fromhost)| (fromhost, command & !0xffff_ffff_ffff | payload));
+
+        // a tohost outside the memory could not have been written
+        let _ = self.memory.write_bytes(tohost, &[0; 8]);
+        if let Some((fromhost, answer)) = answer {
+            let _ = self.memory.write_bytes(fromhost, &answer.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::test_elf;
+    use crate::{ExitReason, StopReason, Vm};
+
+    #[test]
+    fn should_exit_when_the_guest_writes_tohost() {
+        // 0x10000 lui t0, 0x10
+        // 0x10004 addi a0, zero, 7    exit code 3
+        // 0x10008 sw a0, 0x100(t0)    tohost
+        // 0x1000c sw zero, 0x104(t0)
+        // 0x10010 jal zero, 0
+        let code = [
+            0x0001_02b7,
+            0x0070_0513,
+            0x10a2_a023,
+            0x1002_a223,
+            0x0000_006f,
+        ];
+        let elf = test_elf::build(0x10000, &code, 0xf4, &[("tohost", 0x10100)]);
+        let mut vm = Vm::new(0x10000, 0x1000);
+        vm.load_elf(&elf).unwrap();
+        assert_eq!(vm.htif.as_ref().unwrap().tohost, 0x10100);
+
+        let stop_reason = vm.run().unwrap();
+        assert_eq!(stop_reason, StopReason::HtifExit { code: 3 });
+        assert_eq!(stop_reason.exit_reason(), Some(ExitReason::HtifExit(3)));
+        assert_eq!(vm.vm_state.pc, 0x10010);
+        assert_eq!(vm.memory.read_u32(0x10100), Ok(0));
+    }
+
+    #[test]
+    fn should_write_to_the_console_and_answer_in_fromhost() {
+        // 0x10000 lui t0, 0x10
+        // 0x10004 addi a0, zero, 0x68     'h'
+        // 0x10008 lui a1, 0x1010          device 1, putchar
+        // 0x1000c sw a0, 0x100(t0)        tohost
+        // 0x10010 sw a1, 0x104(t0)
+        // 0x10014 ebreak
+        let code = [
+            0x0001_02b7,
+            0x0680_0513,
+            0x0101_05b7,
+            0x10a2_a023,
+            0x10b2_a223,
+            0x0010_0073,
+        ];
+        let symbols = [("tohost", 0x10100), ("fromhost", 0x10108)];
+        let elf = test_elf::build(0x10000, &code, 0xf8, &symbols);
+        let mut vm = Vm::new(0x10000, 0x1000);
+        vm.load_elf(&elf).unwrap();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.htif.as_ref().unwrap().output, b"h");
+        assert_eq!(vm.memory.read_u32(0x10104), Ok(0));
+        assert_eq!(vm.memory.read_u32(0x10108), Ok(0));
+        assert_eq!(vm.memory.read_u32(0x1010c), Ok(0x0101_0000));
+    }
+}
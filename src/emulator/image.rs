@@ -0,0 +1,242 @@
+//! Program images besides ELF, the formats embedded toolchains produce:
+//! flat binaries that go to an address the host picks (`objcopy -O
+//! binary`), Intel HEX and Motorola S-records. All of them become an
+//! `Image`: blocks of bytes at their addresses and maybe an entry point.
+
+use thiserror::Error;
+
+use super::emulator::Vm;
+use super::error::VmError;
+use super::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Elf,
+    /// a flat binary, it has no addresses of its own
+    Bin,
+    IntelHex,
+    Srec,
+}
+
+impl ImageFormat {
+    /// `elf`, `bin`, `ihex` or `srec`, the names of the CLI's `--format`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "elf" => Some(Self::Elf),
+            "bin" => Some(Self::Bin),
+            "ihex" => Some(Self::IntelHex),
+            "srec" => Some(Self::Srec),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ImageError {
+    #[error("line {line}: {reason}")]
+    InvalidRecord { line: usize, reason: &'static str },
+
+    #[error("line {line}: the checksum is wrong")]
+    BadChecksum { line: usize },
+}
+
+/// bytes at addresses and where to start
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    /// the data of consecutive records is merged into one block
+    pub blocks: Vec<(u32, Vec<u8>)>,
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    /// a flat binary loaded at `address`, it starts there
+    pub fn from_bin(bytes: &[u8], address: u32) -> Self {
+        Self {
+            blocks: vec![(address, bytes.to_vec())],
+            entry: Some(address),
+        }
+    }
+
+    /// Intel HEX with 32 bit addresses (the extended linear address
+    /// records) or 20 bit ones (the extended segment address records)
+    pub fn parse_intel_hex(text: &str) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        let mut upper = 0u32;
+        for (index, record) in text.lines().enumerate() {
+            let line = index + 1;
+            let Some(record) = record.trim().strip_prefix(':') else {
+                continue;
+            };
+            let bytes = hex_bytes(record, line)?;
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(ImageError::BadChecksum { line });
+            }
+            let invalid = |reason| ImageError::InvalidRecord { line, reason };
+            let [length, high, low, kind, ..] = bytes[..] else {
+                return Err(invalid("the record is too short"));
+            };
+            let data = bytes
+                .get(4..4 + length as usize)
+                .filter(|_| bytes.len() == 5 + length as usize)
+                .ok_or(invalid("the length does not match the record"))?;
+            let value = data
+                .iter()
+                .fold(0u32, |value, byte| value << 8 | u32::from(*byte));
+            match (kind, length) {
+                (0x00, _) => image.push(
+                    upper.wrapping_add(u32::from_be_bytes([0, 0, high, low])),
+                    data,
+                ),
+                (0x01, _) => break,
+                (0x02, 2) => upper = value << 4,
+                (0x04, 2) => upper = value << 16,
+                (0x03, 4) => image.entry = Some((value >> 16 << 4).wrapping_add(value & 0xffff)),
+                (0x05, 4) => image.entry = Some(value),
+                (0x02..=0x05, _) => return Err(invalid("the length is wrong for the record type")),
+                _ => return Err(invalid("unknown record type")),
+            }
+        }
+        Ok(image)
+    }
+
+    /// Motorola S-records: S1 to S3 data with 16 to 32 bit addresses, S7 to
+    /// S9 the entry point, the header and count records are skipped
+    pub fn parse_srec(text: &str) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        for (index, record) in text.lines().enumerate() {
+            let line = index + 1;
+            let invalid = |reason| ImageError::InvalidRecord { line, reason };
+            let Some(record) = record.trim().strip_prefix('S') else {
+                continue;
+            };
+            let mut chars = record.chars();
+            let kind = chars.next().ok_or(invalid("the record is too short"))?;
+            let bytes = hex_bytes(chars.as_str(), line)?;
+            if bytes.first().map(|count| *count as usize + 1) != Some(bytes.len()) {
+                return Err(invalid("the count does not match the record"));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+                return Err(ImageError::BadChecksum { line });
+            }
+            let address_size = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(invalid("unknown record type")),
+            };
+            let body = &bytes[1..bytes.len() - 1];
+            if body.len() < address_size {
+                return Err(invalid("the record is too short"));
+            }
+            let (address, data) = body.split_at(address_size);
+            let address = address
+                .iter()
+                .fold(0u32, |value, byte| value << 8 | u32::from(*byte));
+            match kind {
+                '1'..='3' => image.push(address, data),
+                '7'..='9' => image.entry = Some(address),
+                _ => {}
+            }
+        }
+        Ok(image)
+    }
+
+    /// the lowest and the end of the highest address with data
+    pub fn span(&self) -> Option<(u32, u64)> {
+        let start = self.blocks.iter().map(|(address, _)| *address).min()?;
+        let end = self
+            .blocks
+            .iter()
+            .map(|(address, data)| u64::from(*address) + data.len() as u64)
+            .max()?;
+        Some((start, end))
+    }
+
+    fn push(&mut self, address: u32, data: &[u8]) {
+        match self.blocks.last_mut() {
+            Some((start, block))
+                if u64::from(*start) + block.len() as u64 == u64::from(address) =>
+            {
+                block.extend_from_slice(data)
+            }
+            _ => self.blocks.push((address, data.to_vec())),
+        }
+    }
+}
+
+/// the bytes of the hex digits in `text`
+fn hex_bytes(text: &str, line: usize) -> Result<Vec<u8>, ImageError> {
+    let invalid = ImageError::InvalidRecord {
+        line,
+        reason: "not an even number of hex digits",
+    };
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(invalid);
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).map_err(|_| invalid.clone()))
+        .collect()
+}
+
+impl Vm {
+    /// loads the blocks of `image` and moves the pc to its entry point, or to
+    /// its first block without one. Returns the pc
+    pub fn load_image(&mut self, image: &Image) -> Result<u32, VmError> {
+        self.invalidate_decode_cache();
+        for (address, data) in &image.blocks {
+            self.memory.load(*address, data)?;
+        }
+        let entry = image
+            .entry
+            .or_else(|| image.blocks.first().map(|(address, _)| *address))
+            .unwrap_or(self.vm_state.pc as u32);
+        self.vm_state.pc = entry as i32;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Image, ImageError};
+    use crate::Vm;
+
+    #[test]
+    fn should_read_intel_hex_and_s_records_into_the_same_image() {
+        // addi a0, zero, 42; ebreak at 0x8000_0000
+        let intel_hex = "\
+:0200000480007A
+:080000001305A00273001000BB
+:040000058000000077
+:00000001FF
+";
+        let srec = "\
+S00600004844521B
+S30D800000001305A0027300100035
+S705800000007A
+";
+        let expected = Image {
+            blocks: vec![(
+                0x8000_0000,
+                vec![0x13, 0x05, 0xa0, 0x02, 0x73, 0x00, 0x10, 0x00],
+            )],
+            entry: Some(0x8000_0000),
+        };
+        assert_eq!(Image::parse_intel_hex(intel_hex), Ok(expected.clone()));
+        assert_eq!(Image::parse_srec(srec), Ok(expected.clone()));
+        assert_eq!(
+            Image::from_bin(&expected.blocks[0].1, 0x8000_0000),
+            expected
+        );
+
+        let mut vm = Vm::new(0x8000_0000, 0x100);
+        assert_eq!(vm.load_image(&expected), Ok(0x8000_0000));
+        vm.run().unwrap();
+        assert_eq!(vm.vm_state.registers[10], 42);
+
+        assert_eq!(
+            Image::parse_intel_hex(":080000001305A00273001000BC\n"),
+            Err(ImageError::BadChecksum { line: 1 })
+        );
+    }
+}
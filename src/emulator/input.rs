@@ -0,0 +1,260 @@
+//! A keyboard (and whatever else sends input events), so interactive guests
+//! can get the keys the browser or the terminal forwards. The events are
+//! Linux evdev ones, a type, a code and a value, so a key is `EV_KEY` with
+//! the key code and 1 for a press, 0 for a release.
+//!
+//! The guest reads the number of queued events, then the oldest event, and
+//! writes `NEXT` to drop it. The interrupt line is up while interrupts are
+//! enabled and events wait; `Vm::add_device_with_irq` with `INPUT_IRQ`
+//! puts it on the PLIC.
+
+use alloc::collections::VecDeque;
+
+use super::memory::Memory;
+use super::mmio::Device;
+
+/// after the virtio devices of QEMU's `virt` machine
+pub const INPUT_BASE: u32 = 0x1002_0000;
+pub const INPUT_SIZE: u32 = 0x100;
+/// a PLIC line `virt` leaves free
+pub const INPUT_IRQ: u32 = 12;
+
+/// how many events are queued
+const REG_COUNT: u32 = 0x00;
+/// the oldest event, the type in the high half and the code in the low one
+const REG_EVENT: u32 = 0x04;
+const REG_VALUE: u32 = 0x08;
+/// write anything to drop the oldest event
+const REG_NEXT: u32 = 0x0c;
+const REG_INTERRUPT_ENABLE: u32 = 0x10;
+
+/// more events than this and the oldest get dropped, a guest that does not
+/// read them would make the queue grow forever
+const MAX_EVENTS: usize = 256;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+
+/// an evdev input event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Input {
+    /// what the guest reads next
+    pub events: VecDeque<InputEvent>,
+    interrupt_enable: bool,
+}
+
+impl Input {
+    pub fn push(&mut self, event: InputEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// a key press or release with its Linux key code, followed by the
+    /// `EV_SYN` that ends the report
+    pub fn key(&mut self, code: u16, pressed: bool) {
+        self.push(InputEvent {
+            kind: EV_KEY,
+            code,
+            value: pressed.into(),
+        });
+        self.push(InputEvent {
+            kind: EV_SYN,
+            code: 0,
+            value: 0,
+        });
+    }
+}
+
+impl Device for Input {
+    fn name(&self) -> &'static str {
+        "input"
+    }
+
+    fn read(&mut self, offset: u32, _size: u32, _memory: &mut Memory) -> u32 {
+        let event = self.events.front();
+        match offset {
+            REG_COUNT => self.events.len() as u32,
+            REG_EVENT => event.map_or(0, |event| {
+                u32::from(event.kind) << 16 | u32::from(event.code)
+            }),
+            REG_VALUE => event.map_or(0, |event| event.value as u32),
+            REG_INTERRUPT_ENABLE => self.interrupt_enable.into(),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32, _memory: &mut Memory) -> bool {
+        match offset {
+            REG_NEXT => {
+                self.events.pop_front();
+            }
+            REG_INTERRUPT_ENABLE => self.interrupt_enable = value & 1 != 0,
+            _ => {}
+        }
+        false
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_enable && !self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Input, InputEvent, EV_KEY, INPUT_BASE, INPUT_IRQ, INPUT_SIZE};
+    use crate::memory::Memory;
+    use crate::mmio::Device;
+    use crate::{Register, StopReason, Vm};
+
+    /// the key code of A
+    const KEY_A: u16 = 30;
+
+    #[test]
+    fn should_hand_key_presses_to_the_guest() {
+        // 0x1000 lui t0, 0x10020
+        // 0x1004 lw a0, 0(t0)          <- wait for an event
+        // 0x1008 beq a0, zero, 0x1004
+        // 0x100c lw a1, 4(t0)
+        // 0x1010 lw a2, 8(t0)
+        // 0x1014 sw zero, 12(t0)       <- next
+        // 0x1018 lw a0, 0(t0)
+        // 0x101c ebreak
+        let program: Vec<u8> = [
+            0x1002_02b7u32,
+            0x0002_a503,
+            0xfe05_0ee3,
+            0x0042_a583,
+            0x0082_a603,
+            0x0002_a623,
+            0x0002_a503,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let mut input = Input::default();
+        input.key(KEY_A, true);
+        vm.add_device(INPUT_BASE, INPUT_SIZE, Box::new(input));
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        let registers = &vm.vm_state.registers;
+        assert_eq!(
+            registers[Register::A1],
+            i32::from(EV_KEY) << 16 | i32::from(KEY_A)
+        );
+        assert_eq!(registers[Register::A2], 1);
+        // the EV_SYN is left
+        assert_eq!(registers[Register::A0], 1);
+    }
+
+    #[test]
+    fn should_raise_the_interrupt_while_events_wait() {
+        let mut input = Input::default();
+        let mut memory = Memory::new(0x1000, 0x100);
+        input.push(InputEvent {
+            kind: EV_KEY,
+            code: KEY_A,
+            value: 0,
+        });
+        assert!(!input.interrupt());
+        input.write(0x10, 4, 1, &mut memory);
+        assert!(input.interrupt());
+        input.write(0x0c, 4, 0, &mut memory);
+        assert!(!input.interrupt());
+    }
+
+    #[test]
+    fn a_key_press_should_run_the_trap_handler_with_the_line_claimed() {
+        let program: Vec<u8> = [
+            // 0x1000 auipc t0, 0
+            0x0000_0297u32,
+            // 0x1004 addi t0, t0, 0x50
+            0x0502_8293,
+            // 0x1008 csrrw zero, mtvec, t0
+            0x3052_9073,
+            // 0x100c lui t0, 0x0c000            the PLIC
+            0x0c00_02b7,
+            // 0x1010 addi t1, zero, 1
+            0x0010_0313,
+            // 0x1014 sw t1, 0x30(t0)           priority of line 12
+            0x0262_a823,
+            // 0x1018 lui t1, 0x1
+            0x0000_1337,
+            // 0x101c lui t2, 0x2
+            0x0000_23b7,
+            // 0x1020 add t2, t2, t0
+            0x0053_83b3,
+            // 0x1024 sw t1, 0(t2)              enable line 12 for context 0
+            0x0063_a023,
+            // 0x1028 lui t1, 0x1
+            0x0000_1337,
+            // 0x102c srli t1, t1, 1
+            0x0013_5313,
+            // 0x1030 csrrs zero, mie, t1       MEIE
+            0x3043_2073,
+            // 0x1034 lui t0, 0x10020           the input device
+            0x1002_02b7,
+            // 0x1038 addi t1, zero, 1
+            0x0010_0313,
+            // 0x103c sw t1, 0x10(t0)           its interrupt
+            0x0062_a823,
+            // 0x1040 csrrsi zero, mstatus, 8
+            0x3004_6073,
+            // 0x1044 jal zero, 0
+            0x0000_006f,
+            // 0x1048 nop
+            0x0000_0013,
+            // 0x104c nop
+            0x0000_0013,
+            // 0x1050 lui t0, 0x0c200           <- handler
+            0x0c20_02b7,
+            // 0x1054 lw a0, 4(t0)              claim
+            0x0042_a503,
+            // 0x1058 lui t1, 0x10020
+            0x1002_0337,
+            // 0x105c lw a1, 4(t1)              the event
+            0x0043_2583,
+            // 0x1060 csrrs a2, mcause, zero
+            0x3420_2673,
+            // 0x1064 ebreak
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_plic();
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device_with_irq(
+            INPUT_BASE,
+            INPUT_SIZE,
+            INPUT_IRQ,
+            Box::new(Input::default()),
+        );
+
+        // nothing to take while no key comes
+        assert_eq!(vm.run_for(100), Ok(StopReason::Preempted { pc: 0x1044 }));
+        vm.device_mut::<Input>(INPUT_BASE).unwrap().key(KEY_A, true);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+
+        let registers = &vm.vm_state.registers;
+        assert_eq!(vm.csrs.mepc, 0x1044);
+        assert_eq!(registers[Register::A0], INPUT_IRQ as i32);
+        assert_eq!(
+            registers[Register::A1],
+            i32::from(EV_KEY) << 16 | i32::from(KEY_A)
+        );
+        // a machine external interrupt
+        assert_eq!(registers[Register::A2] as u32, 0x8000_000b);
+    }
+}
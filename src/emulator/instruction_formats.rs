@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
 pub enum InstructionFormat {
     R,
     I,
@@ -5,6 +6,9 @@ pub enum InstructionFormat {
     B,
     U,
     J,
+    /// the formats of the V extension, `detect_format_from_opcode` leaves
+    /// them to `Unknown` and `vector.rs` decodes them
+    Vector,
     Unknown,
 }
 
@@ -12,10 +16,10 @@ impl InstructionFormat {
     pub fn detect_format_from_opcode(opcode: u8) -> Self {
         match opcode {
             // R-format opcodes
-            0x33 | 0x3B | 0x53 => InstructionFormat::R,
+            0x2F | 0x33 | 0x3B | 0x53 => InstructionFormat::R,
 
             // I-format opcodes
-            0x03 | 0x13 | 0x1B | 0x67 | 0x73 => InstructionFormat::I,
+            0x03 | 0x0F | 0x13 | 0x1B | 0x67 | 0x73 => InstructionFormat::I,
 
             // S-format opcodes
             0x23 => InstructionFormat::S,
@@ -34,9 +38,29 @@ impl InstructionFormat {
         }
     }
 
-    pub fn get_opcode_from_instruction(instruction: [u8; 4]) -> u8 {
-        
+    /// the format `word` is closest to, by its opcode: the vector opcodes
+    /// are `Vector`, the custom and reserved ones `Unknown`
+    pub fn closest(word: u32) -> Self {
+        match word & 0x7f {
+            0x07 | 0x27 | 0x57 => Self::Vector,
+            opcode => Self::detect_format_from_opcode(opcode as u8),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::R => "R-type",
+            Self::I => "I-type",
+            Self::S => "S-type",
+            Self::B => "B-type",
+            Self::U => "U-type",
+            Self::J => "J-type",
+            Self::Vector => "vector",
+            Self::Unknown => "unknown opcode",
+        }
+    }
 
+    pub fn get_opcode_from_instruction(instruction: [u8; 4]) -> u8 {
         instruction[0] & 0x7F
     }
 }
@@ -44,22 +68,22 @@ impl InstructionFormat {
 #[derive(Debug)]
 pub struct InstructionFormatR {
     /// bits 25 to 31
-    funct7: u8,
+    pub funct7: u8,
 
     /// bits 20 to 24
-    rs2: u8,
+    pub rs2: u8,
 
     /// bits 15 to 19
-    rs1: u8,
+    pub rs1: u8,
 
     /// bits 12 to 14
-    funct3: u8,
+    pub funct3: u8,
 
     /// bits 7 to 11
-    rd: u8,
+    pub rd: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatR {
@@ -77,7 +101,6 @@ impl InstructionFormatR {
     /// this is how the instructions are in elf file
     pub fn parse_instruction_from_bytes(bytes: &[u8]) -> Self {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-        
 
         Self::new(instruction)
     }
@@ -87,19 +110,19 @@ impl InstructionFormatR {
 #[derive(Debug)]
 pub struct InstructionFormatI {
     /// bits 20 to 31
-    imm: u8,
+    pub imm: u16,
 
     /// bits 15 to 19
-    rs1: u8,
+    pub rs1: u8,
 
     /// bits 12 to 14
-    funct3: u8,
+    pub funct3: u8,
 
     /// bits 7 to 11
-    rd: u8,
+    pub rd: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatI {
@@ -111,37 +134,41 @@ impl InstructionFormatI {
             rd: ((instruction >> 7) & 0b0001_1111) as u8,
             funct3: ((instruction >> 12) & 0b0111) as u8,
             rs1: ((instruction >> 15) & 0b0001_1111) as u8,
-            imm: ((instruction >> 20) & 0b1111_1111_1111) as u8,
+            imm: ((instruction >> 20) & 0b1111_1111_1111) as u16,
         }
     }
 
     pub fn parse_instruction_from_bytes(bytes: &[u8]) -> Self {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-        
 
         Self::new(instruction)
     }
+
+    /// the 12 bit immediate sign extended to 32 bits
+    pub fn immediate(&self) -> i32 {
+        sign_extend(self.imm as u32, 12)
+    }
 }
 
 #[derive(Debug)]
 pub struct InstructionFormatS {
     /// bits 25 to 31
-    imm_5_to_11: u8,
+    pub imm_5_to_11: u8,
 
     /// bits 20 to 24
-    rs2: u8,
+    pub rs2: u8,
 
     /// bits 15 to 19
-    rs1: u8,
+    pub rs1: u8,
 
     /// bits 12 to 14
-    func3: u8,
+    pub func3: u8,
 
     /// bits 7 to 11
-    imm_0_to_4: u8,
+    pub imm_0_to_4: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatS {
@@ -168,6 +195,14 @@ impl InstructionFormatS {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Self::new(instruction)
     }
+
+    /// imm[11:5] and imm[4:0] glued back together and sign extended
+    pub fn immediate(&self) -> i32 {
+        sign_extend(
+            ((self.imm_5_to_11 as u32) << 5) | self.imm_0_to_4 as u32,
+            12,
+        )
+    }
 }
 
 /// The only difference between the S and B formats is that the 12-bit immediate
@@ -179,28 +214,28 @@ impl InstructionFormatS {
 #[derive(Debug)]
 pub struct InstructionFormatB {
     /// bit 31
-    sign_imm_5_to_11: u8,
+    pub sign_imm_5_to_11: u8,
 
     /// bits 25 to 31
-    imm_5_to_11: u8,
+    pub imm_5_to_11: u8,
 
     /// bits 20 to 24
-    rs2: u8,
+    pub rs2: u8,
 
     /// bits 15 to 19
-    rs1: u8,
+    pub rs1: u8,
 
     /// bits 12 to 14
-    func3: u8,
+    pub func3: u8,
 
     /// bits 8 to 11
-    imm_0_to_4: u8,
+    pub imm_0_to_4: u8,
 
     /// bit 7
-    sign_imm_0_to_4: u8,
+    pub sign_imm_0_to_4: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatB {
@@ -231,18 +266,27 @@ impl InstructionFormatB {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Self::new(instruction)
     }
+
+    /// the branch offset in bytes, imm[0] is always 0
+    pub fn immediate(&self) -> i32 {
+        let imm = ((self.sign_imm_5_to_11 as u32) << 12)
+            | ((self.sign_imm_0_to_4 as u32) << 11)
+            | ((self.imm_5_to_11 as u32) << 5)
+            | ((self.imm_0_to_4 as u32) << 1);
+        sign_extend(imm, 13)
+    }
 }
 
 #[derive(Debug)]
 pub struct InstructionFormatU {
     /// bits 12 to 31
-    imm: u8,
+    pub imm: u32,
 
     /// bits 7 to 11
-    rd: u8,
+    pub rd: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatU {
@@ -250,7 +294,7 @@ impl InstructionFormatU {
         Self {
             // Bits 12 to 31: immediate (20 bits)
             // Using a binary mask for 20 bits: 0b1111_1111_1111_1111_1111
-            imm: ((instruction >> 12) & 0b1111_1111_1111_1111_1111) as u8,
+            imm: (instruction >> 12) & 0b1111_1111_1111_1111_1111,
             // Bits 7 to 11: rd (5 bits): mask 0b1_1111
             rd: ((instruction >> 7) & 0b0001_1111) as u8,
             // Bits 0 to 6: opcode (7 bits): mask 0b111_1111
@@ -262,6 +306,11 @@ impl InstructionFormatU {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Self::new(instruction)
     }
+
+    /// the upper 20 bits, not shifted yet (the instruction does the `<< 12`)
+    pub fn immediate(&self) -> i32 {
+        self.imm as i32
+    }
 }
 
 /// the only difference between the U and J formats is that the 20-bit immediate
@@ -271,29 +320,29 @@ impl InstructionFormatU {
 #[derive(Debug)]
 pub struct InstructionFormatJ {
     /// bit 31
-    sign_imm_21_30: u8,
+    pub sign_imm_21_30: u8,
 
     /// bits 21 to 30
-    imm_21_30: u8,
+    pub imm_21_30: u16,
 
     /// bit 20
-    sign_imm_12_19: u8,
+    pub sign_imm_12_19: u8,
 
     /// bits 12 to 19
-    imm_12_19: u8,
+    pub imm_12_19: u8,
 
     /// bits 7 to 11
-    rd: u8,
+    pub rd: u8,
 
     /// bits 0 to 6
-    opcode: u8,
+    pub opcode: u8,
 }
 
 impl InstructionFormatJ {
     pub fn new(instruction: u32) -> Self {
         Self {
             sign_imm_21_30: ((instruction >> 31) & 0b0001) as u8,
-            imm_21_30: ((instruction >> 21) & 0b0011_1111_1111) as u8,
+            imm_21_30: ((instruction >> 21) & 0b0011_1111_1111) as u16,
             sign_imm_12_19: ((instruction >> 20) & 0b0001) as u8,
             imm_12_19: ((instruction >> 12) & 0b1111_1111) as u8,
             rd: ((instruction >> 7) & 0b0001_1111) as u8,
@@ -305,6 +354,21 @@ impl InstructionFormatJ {
         let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Self::new(instruction)
     }
+
+    /// the jump offset in bytes, imm[0] is always 0
+    pub fn immediate(&self) -> i32 {
+        let imm = ((self.sign_imm_21_30 as u32) << 20)
+            | ((self.imm_12_19 as u32) << 12)
+            | ((self.sign_imm_12_19 as u32) << 11)
+            | ((self.imm_21_30 as u32) << 1);
+        sign_extend(imm, 21)
+    }
+}
+
+/// sign extends the lowest `bits` bits of `value` to a full i32
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
 }
 
 #[cfg(test)]
@@ -474,8 +538,6 @@ mod tests {
             InstructionFormatU::parse_instruction_from_bytes(&bits_as_little_endian);
 
         // Check that the fields are parsed correctly.
-        // Note: since we cast imm to u8, only the lowest 8 bits of the 20-bit field are stored.
-        // In our case the 20-bit immediate is 1, so its lower 8 bits are also 1.
         assert_eq!(instruction_format_u.imm, 0b00000001);
         assert_eq!(instruction_format_u.rd, 0b00001);
         assert_eq!(instruction_format_u.opcode, 0b0010111);
@@ -512,4 +574,20 @@ mod tests {
         assert_eq!(instruction_format_j.rd, 0b00101);
         assert_eq!(instruction_format_j.opcode, 0b1101111);
     }
+
+    #[test]
+    fn should_sign_extend_immediates() {
+        // addi a0, a0, -1
+        assert_eq!(InstructionFormatI::new(0xfff5_0513).immediate(), -1);
+        // sw s0, -4(sp)
+        assert_eq!(InstructionFormatS::new(0xfe81_2e23).immediate(), -4);
+        // bltu a0, a3, +6 (from the sum_2_number example)
+        assert_eq!(InstructionFormatB::new(0x00d5_6363).immediate(), 6);
+        // beq zero, zero, -4
+        assert_eq!(InstructionFormatB::new(0xfe00_0ee3).immediate(), -4);
+        // lui a0, 0xfffff, the upper bits are not shifted yet
+        assert_eq!(InstructionFormatU::new(0xffff_f537).immediate(), 0xfffff);
+        // jal zero, -8
+        assert_eq!(InstructionFormatJ::new(0xff9f_f06f).immediate(), -8);
+    }
 }
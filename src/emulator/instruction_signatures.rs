@@ -1,32 +1,28 @@
-#[derive(Debug)]
+use super::register::Register;
+
+#[derive(Debug, Clone, Copy)]
 pub struct DestinationSource1Source2 {
-    pub rd: u8,
-    pub rs1: u8,
-    pub rs2: u8,
+    pub rd: Register,
+    pub rs1: Register,
+    pub rs2: Register,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Source1Source2Immediate {
-    pub rs1: u8,
-    pub rs2: u8,
+    pub rs1: Register,
+    pub rs2: Register,
     pub imm: i16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct DestinationSource1Immediate {
-    pub rd: u8,
-    pub rs1: u8,
+    pub rd: Register,
+    pub rs1: Register,
     pub imm: i16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct DestinationImmediate {
-    pub rd: u8,
-    pub imm: i16,
-}
-
-#[derive(Debug)]
-pub struct PseudoLiSignature {
-    rd: u8,
-    imm: i32,
+    pub rd: Register,
+    pub imm: i32,
 }
@@ -0,0 +1,432 @@
+//! The instructions the vm implements, as a table: mnemonic, format,
+//! extension and operands of each, for an "instructions implemented" matrix
+//! in a UI. The table comes from the instruction enums: one sample per
+//! variant (and per vector mnemonic) and exhaustive matches for the format
+//! and the operands, so a new variant does not compile until it is here.
+//!
+//! The custom instructions of `plugin.rs` are not in it, their plugins know
+//! what they are, and neither are `vle64.v` and `vse64.v`: they decode (for
+//! the disassembly) but are illegal with the 32 bit elements of `vector.rs`.
+
+use serde::Serialize;
+
+use super::extensions::Extension;
+use super::instruction_formats::InstructionFormat;
+use super::instruction_signatures::{
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+    Source1Source2Immediate,
+};
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+use super::vector::{VectorInstruction, VectorOperand, VectorOperation};
+
+/// what an operand of an instruction is, in assembler order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OperandKind {
+    Rd,
+    Rs1,
+    Rs2,
+    /// a sign extended immediate (or offset)
+    Imm,
+    /// a zero extended immediate in a register field
+    Uimm,
+    Csr,
+    Vd,
+    Vs1,
+    Vs2,
+    Vs3,
+    Vtype,
+    /// the optional `v0.t`
+    Mask,
+}
+
+/// one row of the table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    pub format: InstructionFormat,
+    pub extension: Extension,
+    pub operands: &'static [OperandKind],
+}
+
+/// every instruction the vm implements
+pub fn instruction_set() -> Vec<InstructionInfo> {
+    Rv32iInstruction::samples()
+        .iter()
+        .map(|instruction| InstructionInfo {
+            mnemonic: instruction.mnemonic(),
+            format: instruction.format(),
+            extension: instruction.extension(),
+            operands: instruction.operand_kinds(),
+        })
+        .collect()
+}
+
+/// `instruction_set()` as a JSON array
+pub fn instruction_set_json() -> String {
+    serde_json::to_string(&instruction_set()).expect("the table only has plain fields")
+}
+
+impl Rv32iInstruction {
+    /// one instruction of every kind, all operands zero
+    fn samples() -> Vec<Self> {
+        let r = DestinationSource1Source2 {
+            rd: Register::ZERO,
+            rs1: Register::ZERO,
+            rs2: Register::ZERO,
+        };
+        let i = DestinationSource1Immediate {
+            rd: Register::ZERO,
+            rs1: Register::ZERO,
+            imm: 0,
+        };
+        let sb = Source1Source2Immediate {
+            rs1: Register::ZERO,
+            rs2: Register::ZERO,
+            imm: 0,
+        };
+        let uj = DestinationImmediate {
+            rd: Register::ZERO,
+            imm: 0,
+        };
+
+        let mut samples = vec![
+            Self::Add(r),
+            Self::Sub(r),
+            Self::Xor(r),
+            Self::Or(r),
+            Self::And(r),
+            Self::Sll(r),
+            Self::Srl(r),
+            Self::Sra(r),
+            Self::Slt(r),
+            Self::Sltu(r),
+            Self::Addi(i),
+            Self::Xori(i),
+            Self::Ori(i),
+            Self::Andi(i),
+            Self::Slli(i),
+            Self::Srli(i),
+            Self::Srai(i),
+            Self::Slti(i),
+            Self::Sltiu(i),
+            Self::Lb(i),
+            Self::Lh(i),
+            Self::Lw(i),
+            Self::Lbu(i),
+            Self::Lhu(i),
+            Self::Sb(sb),
+            Self::Sh(sb),
+            Self::Sw(sb),
+            Self::Beq(sb),
+            Self::Bne(sb),
+            Self::Blt(sb),
+            Self::Bge(sb),
+            Self::Bltu(sb),
+            Self::Bgeu(sb),
+            Self::Jal(uj),
+            Self::Jalr(i),
+            Self::Lui(uj),
+            Self::Auipc(uj),
+            Self::Ecall,
+            Self::Ebreak,
+            Self::Mret,
+            Self::Sret,
+            Self::Wfi,
+            Self::Fence(i),
+            Self::FenceI,
+            Self::SfenceVma(r),
+            Self::Csrrw(i),
+            Self::Csrrs(i),
+            Self::Csrrc(i),
+            Self::Csrrwi(i),
+            Self::Csrrsi(i),
+            Self::Csrrci(i),
+            Self::LrW(r),
+            Self::ScW(r),
+            Self::AmoswapW(r),
+            Self::AmoaddW(r),
+            Self::AmoxorW(r),
+            Self::AmoandW(r),
+            Self::AmoorW(r),
+            Self::AmominW(r),
+            Self::AmomaxW(r),
+            Self::AmominuW(r),
+            Self::AmomaxuW(r),
+            Self::Vector(VectorInstruction::Vsetvli {
+                rd: Register::ZERO,
+                rs1: Register::ZERO,
+                vtype: 0,
+            }),
+            Self::Vector(VectorInstruction::Vsetivli {
+                rd: Register::ZERO,
+                avl: 0,
+                vtype: 0,
+            }),
+        ];
+        for width in [8, 16, 32] {
+            let (rs1, masked) = (Register::ZERO, false);
+            samples.push(Self::Vector(VectorInstruction::Load {
+                vd: 0,
+                rs1,
+                width,
+                masked,
+            }));
+            samples.push(Self::Vector(VectorInstruction::Store {
+                vs3: 0,
+                rs1,
+                width,
+                masked,
+            }));
+        }
+        let operands = [
+            (VectorOperation::Add, VectorOperand::Vector(0)),
+            (VectorOperation::Add, VectorOperand::Scalar(Register::ZERO)),
+            (VectorOperation::Add, VectorOperand::Immediate(0)),
+            (VectorOperation::Sub, VectorOperand::Vector(0)),
+            (VectorOperation::Sub, VectorOperand::Scalar(Register::ZERO)),
+            (VectorOperation::Mul, VectorOperand::Vector(0)),
+            (VectorOperation::Mul, VectorOperand::Scalar(Register::ZERO)),
+        ];
+        samples.extend(operands.map(|(operation, operand)| {
+            Self::Vector(VectorInstruction::Arithmetic {
+                operation,
+                vd: 0,
+                vs2: 0,
+                operand,
+                masked: false,
+            })
+        }));
+        samples
+    }
+
+    /// the format of the encoding, `Vector` for the V extension
+    pub fn format(&self) -> InstructionFormat {
+        match self {
+            Self::Add(_)
+            | Self::Sub(_)
+            | Self::Xor(_)
+            | Self::Or(_)
+            | Self::And(_)
+            | Self::Sll(_)
+            | Self::Srl(_)
+            | Self::Sra(_)
+            | Self::Slt(_)
+            | Self::Sltu(_)
+            | Self::SfenceVma(_)
+            | Self::LrW(_)
+            | Self::ScW(_)
+            | Self::AmoswapW(_)
+            | Self::AmoaddW(_)
+            | Self::AmoxorW(_)
+            | Self::AmoandW(_)
+            | Self::AmoorW(_)
+            | Self::AmominW(_)
+            | Self::AmomaxW(_)
+            | Self::AmominuW(_)
+            | Self::AmomaxuW(_) => InstructionFormat::R,
+            Self::Addi(_)
+            | Self::Xori(_)
+            | Self::Ori(_)
+            | Self::Andi(_)
+            | Self::Slli(_)
+            | Self::Srli(_)
+            | Self::Srai(_)
+            | Self::Slti(_)
+            | Self::Sltiu(_)
+            | Self::Lb(_)
+            | Self::Lh(_)
+            | Self::Lw(_)
+            | Self::Lbu(_)
+            | Self::Lhu(_)
+            | Self::Jalr(_)
+            | Self::Ecall
+            | Self::Ebreak
+            | Self::Mret
+            | Self::Sret
+            | Self::Wfi
+            | Self::Fence(_)
+            | Self::FenceI
+            | Self::Csrrw(_)
+            | Self::Csrrs(_)
+            | Self::Csrrc(_)
+            | Self::Csrrwi(_)
+            | Self::Csrrsi(_)
+            | Self::Csrrci(_) => InstructionFormat::I,
+            Self::Sb(_) | Self::Sh(_) | Self::Sw(_) => InstructionFormat::S,
+            Self::Beq(_)
+            | Self::Bne(_)
+            | Self::Blt(_)
+            | Self::Bge(_)
+            | Self::Bltu(_)
+            | Self::Bgeu(_) => InstructionFormat::B,
+            Self::Lui(_) | Self::Auipc(_) => InstructionFormat::U,
+            Self::Jal(_) => InstructionFormat::J,
+            Self::Vector(_) => InstructionFormat::Vector,
+            Self::Custom(_) => InstructionFormat::Unknown,
+        }
+    }
+
+    /// the operands in assembler order
+    pub fn operand_kinds(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+
+        match self {
+            Self::Add(_)
+            | Self::Sub(_)
+            | Self::Xor(_)
+            | Self::Or(_)
+            | Self::And(_)
+            | Self::Sll(_)
+            | Self::Srl(_)
+            | Self::Sra(_)
+            | Self::Slt(_)
+            | Self::Sltu(_)
+            | Self::ScW(_)
+            | Self::AmoswapW(_)
+            | Self::AmoaddW(_)
+            | Self::AmoxorW(_)
+            | Self::AmoandW(_)
+            | Self::AmoorW(_)
+            | Self::AmominW(_)
+            | Self::AmomaxW(_)
+            | Self::AmominuW(_)
+            | Self::AmomaxuW(_) => &[Rd, Rs1, Rs2],
+            Self::LrW(_) => &[Rd, Rs1],
+            Self::SfenceVma(_) => &[Rs1, Rs2],
+            Self::Addi(_)
+            | Self::Xori(_)
+            | Self::Ori(_)
+            | Self::Andi(_)
+            | Self::Slti(_)
+            | Self::Sltiu(_)
+            | Self::Lb(_)
+            | Self::Lh(_)
+            | Self::Lw(_)
+            | Self::Lbu(_)
+            | Self::Lhu(_)
+            | Self::Jalr(_) => &[Rd, Rs1, Imm],
+            Self::Slli(_) | Self::Srli(_) | Self::Srai(_) => &[Rd, Rs1, Uimm],
+            Self::Sb(_) | Self::Sh(_) | Self::Sw(_) => &[Rs2, Rs1, Imm],
+            Self::Beq(_)
+            | Self::Bne(_)
+            | Self::Blt(_)
+            | Self::Bge(_)
+            | Self::Bltu(_)
+            | Self::Bgeu(_) => &[Rs1, Rs2, Imm],
+            Self::Jal(_) | Self::Lui(_) | Self::Auipc(_) => &[Rd, Imm],
+            Self::Ecall
+            | Self::Ebreak
+            | Self::Mret
+            | Self::Sret
+            | Self::Wfi
+            | Self::FenceI
+            | Self::Custom(_) => &[],
+            // pred and succ
+            Self::Fence(_) => &[Uimm, Uimm],
+            Self::Csrrw(_) | Self::Csrrs(_) | Self::Csrrc(_) => &[Rd, Csr, Rs1],
+            Self::Csrrwi(_) | Self::Csrrsi(_) | Self::Csrrci(_) => &[Rd, Csr, Uimm],
+            Self::Vector(vector) => match vector {
+                VectorInstruction::Vsetvli { .. } => &[Rd, Rs1, Vtype],
+                VectorInstruction::Vsetivli { .. } => &[Rd, Uimm, Vtype],
+                VectorInstruction::Load { .. } => &[Vd, Rs1, Mask],
+                VectorInstruction::Store { .. } => &[Vs3, Rs1, Mask],
+                VectorInstruction::Arithmetic { operand, .. } => match operand {
+                    VectorOperand::Vector(_) => &[Vd, Vs2, Vs1, Mask],
+                    VectorOperand::Scalar(_) => &[Vd, Vs2, Rs1, Mask],
+                    VectorOperand::Immediate(_) => &[Vd, Vs2, Imm, Mask],
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instruction_set, instruction_set_json, OperandKind};
+    use crate::extensions::Extension;
+    use crate::instruction_formats::InstructionFormat;
+    use crate::vector::VectorInstruction;
+    use crate::{Rv32iInstruction, Vm, VmError};
+
+    #[test]
+    fn should_list_every_instruction_once() {
+        let table = instruction_set();
+        let mnemonics: Vec<&str> = table.iter().map(|info| info.mnemonic).collect();
+        for (index, mnemonic) in mnemonics.iter().enumerate() {
+            assert!(
+                !mnemonics[..index].contains(mnemonic),
+                "{mnemonic} is in the table twice"
+            );
+        }
+
+        let sw = table.iter().find(|info| info.mnemonic == "sw").unwrap();
+        assert_eq!(sw.format, InstructionFormat::S);
+        assert_eq!(sw.extension, Extension::I);
+        assert_eq!(
+            sw.operands,
+            [OperandKind::Rs2, OperandKind::Rs1, OperandKind::Imm]
+        );
+        assert!(instruction_set_json().contains(
+            r#"{"mnemonic":"lr.w","format":"R","extension":"A","operands":["Rd","Rs1"]}"#
+        ));
+    }
+
+    #[test]
+    fn the_table_should_match_the_decoder_and_the_executor() {
+        let table = instruction_set();
+        for sample in Rv32iInstruction::samples() {
+            let word = sample.encode();
+
+            // the decoder gives the same instruction back
+            let decoded = Rv32iInstruction::from_core_instruction_format(word.to_le_bytes())
+                .unwrap_or_else(|error| panic!("{} does not decode: {error}", sample.mnemonic()));
+            assert_eq!(decoded.mnemonic(), sample.mnemonic());
+
+            // the vm executes it: it may fault on address 0, but it knows it
+            // 0x1000 vsetivli zero, 1, e32, m1, ta, ma
+            let program: Vec<u8> = [0xcd00_f057, word]
+                .iter()
+                .flat_map(|instruction| instruction.to_le_bytes())
+                .collect();
+            let mut vm = Vm::new(0x1000, 0x100).with_vector(128);
+            vm.load_program(0x1000, &program).unwrap();
+            vm.step().unwrap();
+            let result = vm.step();
+            assert!(
+                !matches!(result, Err(VmError::IllegalInstruction { .. })),
+                "the vm does not execute {}",
+                sample.mnemonic()
+            );
+        }
+
+        // every word the decoder knows is in the table (xorshift samples)
+        let mut state = 0x9e37_79b9u32;
+        for _ in 0..100_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let Ok(decoded) = Rv32iInstruction::from_core_instruction_format(state.to_le_bytes())
+            else {
+                continue;
+            };
+            let unsupported = matches!(
+                decoded,
+                Rv32iInstruction::Custom(_)
+                    | Rv32iInstruction::Vector(
+                        VectorInstruction::Load { width: 64, .. }
+                            | VectorInstruction::Store { width: 64, .. }
+                    )
+            );
+            if unsupported {
+                continue;
+            }
+            assert!(
+                table.iter().any(|info| info.mnemonic == decoded.mnemonic()),
+                "{:?} is not in the table",
+                decoded
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Translates hot basic blocks to native code with Cranelift. Only built
+//! with the `jit` feature.
+//!
+//! A block is the same as for the block cache: the straight run of
+//! register-only instructions from a pc, up to and including the first
+//! conditional branch. Loads, stores, jumps, ecalls and CSR instructions end
+//! the block before them and run in the interpreter, so the call stack,
+//! watchpoints and the memory stay exactly as without the jit.
+//!
+//! The guards:
+//! - a guest store drops the blocks it overlaps, and loading code or a
+//!   snapshot drops all of them, the same as the decode cache
+//! - a block runs at most 64 instructions and then returns
+//!   to the run loop, which checks the breakpoints and the execution limit
+//!   between blocks. Interrupts will be taken there too
+//! - the jit steps aside while hooks, timing, gas or breakpoints are on,
+//!   since those want to see every instruction
+
+use std::collections::HashMap;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use super::block_cache::decode_block;
+use super::dispatch::{Dispatch, Opcode};
+use super::emulator::Vm;
+use super::memory::Memory;
+
+/// the native code of a block, it gets the registers and the pc and leaves
+/// the pc at the next instruction
+type BlockFunction = unsafe extern "C" fn(*mut i32, *mut i32);
+
+#[derive(Clone, Copy)]
+struct Block {
+    function: BlockFunction,
+    /// the address after the last instruction
+    end: u32,
+    length: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Entry {
+    /// how often the interpreter started here so far
+    Counting(u32),
+    Compiled(Block),
+    /// the first instruction can not be translated
+    Interpreted,
+}
+
+pub struct Jit {
+    module: JITModule,
+    context: Context,
+    function_context: FunctionBuilderContext,
+    entries: HashMap<u32, Entry>,
+    /// a pc is translated once the interpreter got there this many times
+    pub threshold: u32,
+    pub compiled_blocks: u64,
+    /// how many instructions ran as native code
+    pub instructions: u64,
+}
+
+impl Jit {
+    /// `None` when Cranelift has no backend for the host
+    pub fn new() -> Option<Self> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        Some(Self {
+            context: module.make_context(),
+            module,
+            function_context: FunctionBuilderContext::new(),
+            entries: HashMap::new(),
+            threshold: 16,
+            compiled_blocks: 0,
+            instructions: 0,
+        })
+    }
+
+    /// the block at `pc` if it is translated, counts the visit otherwise and
+    /// translates it once it is hot
+    fn block(&mut self, pc: u32, memory: &Memory) -> Option<Block> {
+        let entry = self.entries.entry(pc).or_insert(Entry::Counting(0));
+        match entry {
+            Entry::Compiled(block) => return Some(*block),
+            Entry::Interpreted => return None,
+            Entry::Counting(count) if *count + 1 < self.threshold => {
+                *count += 1;
+                return None;
+            }
+            Entry::Counting(_) => {}
+        }
+
+        let entry = match self.compile(pc, memory) {
+            Some(block) => Entry::Compiled(block),
+            None => Entry::Interpreted,
+        };
+        self.entries.insert(pc, entry);
+        match entry {
+            Entry::Compiled(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    fn compile(&mut self, pc: u32, memory: &Memory) -> Option<Block> {
+        let instructions = decode_block(pc, memory);
+        let last = pc.wrapping_add(4 * instructions.len().checked_sub(1)? as u32);
+
+        let pointer = self.module.target_config().pointer_type();
+        self.module.clear_context(&mut self.context);
+        let signature = &mut self.context.func.signature;
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.function_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let registers_pointer = builder.block_params(entry)[0];
+        let pc_pointer = builder.block_params(entry)[1];
+
+        let mut registers = Registers {
+            pointer: registers_pointer,
+            values: [None; 32],
+            dirty: [false; 32],
+        };
+        let mut next_pc = None;
+        for (offset, dispatch) in instructions.iter().enumerate() {
+            let address = pc.wrapping_add(4 * offset as u32);
+            next_pc = translate(&mut builder, &mut registers, address, dispatch);
+        }
+        let next_pc = next_pc.unwrap_or_else(|| {
+            builder
+                .ins()
+                .iconst(types::I32, last.wrapping_add(4) as i32 as i64)
+        });
+
+        registers.store(&mut builder);
+        builder
+            .ins()
+            .store(MemFlags::trusted(), next_pc, pc_pointer, 0);
+        builder.ins().return_(&[]);
+        builder.finalize();
+
+        let id = self
+            .module
+            .declare_anonymous_function(&self.context.func.signature)
+            .ok()?;
+        self.module.define_function(id, &mut self.context).ok()?;
+        self.module.clear_context(&mut self.context);
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        self.compiled_blocks += 1;
+
+        Some(Block {
+            // SAFETY: the function was built with the signature of
+            // `BlockFunction` and the host's default calling convention
+            function: unsafe { std::mem::transmute::<*const u8, BlockFunction>(code) },
+            end: last.wrapping_add(4),
+            length: instructions.len() as u32,
+        })
+    }
+
+    /// drops the blocks with code in the `size` bytes from `address`. Their
+    /// native code stays allocated until the jit is dropped
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        let end = address.wrapping_add(size);
+        self.entries.retain(|pc, entry| match entry {
+            Entry::Compiled(block) => block.end <= address || end <= *pc,
+            _ => true,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// the guest registers as Cranelift values, loaded on first use and stored
+/// back at the end of the block if they were written
+struct Registers {
+    pointer: Value,
+    values: [Option<Value>; 32],
+    dirty: [bool; 32],
+}
+
+impl Registers {
+    fn get(&mut self, builder: &mut FunctionBuilder, index: usize) -> Value {
+        if index == 0 {
+            return builder.ins().iconst(types::I32, 0);
+        }
+        *self.values[index].get_or_insert_with(|| {
+            builder.ins().load(
+                types::I32,
+                MemFlags::trusted(),
+                self.pointer,
+                index as i32 * 4,
+            )
+        })
+    }
+
+    /// writes to x0 are dropped
+    fn set(&mut self, index: usize, value: Value) {
+        if index != 0 {
+            self.values[index] = Some(value);
+            self.dirty[index] = true;
+        }
+    }
+
+    fn store(&self, builder: &mut FunctionBuilder) {
+        for index in 1..32 {
+            if let (true, Some(value)) = (self.dirty[index], self.values[index]) {
+                builder
+                    .ins()
+                    .store(MemFlags::trusted(), value, self.pointer, index as i32 * 4);
+            }
+        }
+    }
+}
+
+/// emits one instruction, returns the next pc for the branch that ends the
+/// block
+fn translate(
+    builder: &mut FunctionBuilder,
+    registers: &mut Registers,
+    pc: u32,
+    dispatch: &Dispatch,
+) -> Option<Value> {
+    let operands = &dispatch.operands;
+    let rd = operands.rd.index();
+    let rs1 = registers.get(builder, operands.rs1.index());
+    let imm = operands.imm as i64;
+
+    let result = match dispatch.opcode {
+        Opcode::Add | Opcode::Sub | Opcode::Xor | Opcode::Or | Opcode::And => {
+            let rs2 = registers.get(builder, operands.rs2.index());
+            match dispatch.opcode {
+                Opcode::Add => builder.ins().iadd(rs1, rs2),
+                Opcode::Sub => builder.ins().isub(rs1, rs2),
+                Opcode::Xor => builder.ins().bxor(rs1, rs2),
+                Opcode::Or => builder.ins().bor(rs1, rs2),
+                _ => builder.ins().band(rs1, rs2),
+            }
+        }
+        // Cranelift masks the shift amount to 5 bits for 32 bit values, like
+        // RISC-V does
+        Opcode::Sll | Opcode::Srl | Opcode::Sra => {
+            let rs2 = registers.get(builder, operands.rs2.index());
+            match dispatch.opcode {
+                Opcode::Sll => builder.ins().ishl(rs1, rs2),
+                Opcode::Srl => builder.ins().ushr(rs1, rs2),
+                _ => builder.ins().sshr(rs1, rs2),
+            }
+        }
+        Opcode::Slt | Opcode::Sltu => {
+            let rs2 = registers.get(builder, operands.rs2.index());
+            let condition = match dispatch.opcode {
+                Opcode::Slt => IntCC::SignedLessThan,
+                _ => IntCC::UnsignedLessThan,
+            };
+            let is_less = builder.ins().icmp(condition, rs1, rs2);
+            builder.ins().uextend(types::I32, is_less)
+        }
+        Opcode::Addi => builder.ins().iadd_imm(rs1, imm),
+        Opcode::Xori => builder.ins().bxor_imm(rs1, imm),
+        Opcode::Ori => builder.ins().bor_imm(rs1, imm),
+        Opcode::Andi => builder.ins().band_imm(rs1, imm),
+        Opcode::Slli => builder.ins().ishl_imm(rs1, imm & 0x1f),
+        Opcode::Srli => builder.ins().ushr_imm(rs1, imm & 0x1f),
+        Opcode::Srai => builder.ins().sshr_imm(rs1, imm & 0x1f),
+        Opcode::Slti | Opcode::Sltiu => {
+            let condition = match dispatch.opcode {
+                Opcode::Slti => IntCC::SignedLessThan,
+                _ => IntCC::UnsignedLessThan,
+            };
+            let is_less = builder.ins().icmp_imm(condition, rs1, imm);
+            builder.ins().uextend(types::I32, is_less)
+        }
+        Opcode::Lui => builder.ins().iconst(types::I32, (imm << 12) as i32 as i64),
+        Opcode::Auipc => {
+            let address = pc.wrapping_add((imm << 12) as u32);
+            builder.ins().iconst(types::I32, address as i32 as i64)
+        }
+        Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge | Opcode::Bltu | Opcode::Bgeu => {
+            let rs2 = registers.get(builder, operands.rs2.index());
+            let condition = match dispatch.opcode {
+                Opcode::Beq => IntCC::Equal,
+                Opcode::Bne => IntCC::NotEqual,
+                Opcode::Blt => IntCC::SignedLessThan,
+                Opcode::Bge => IntCC::SignedGreaterThanOrEqual,
+                Opcode::Bltu => IntCC::UnsignedLessThan,
+                _ => IntCC::UnsignedGreaterThanOrEqual,
+            };
+            let taken = builder.ins().icmp(condition, rs1, rs2);
+            let target = pc.wrapping_add(imm as u32);
+            let target = builder.ins().iconst(types::I32, target as i32 as i64);
+            let next = builder
+                .ins()
+                .iconst(types::I32, pc.wrapping_add(4) as i32 as i64);
+            return Some(builder.ins().select(taken, target, next));
+        }
+        // blocks leave the jumps to the interpreter
+        Opcode::Jal | Opcode::Jalr => unreachable!("jumps end the block before them"),
+    };
+
+    registers.set(rd, result);
+    None
+}
+
+impl Vm {
+    /// translates hot code to native code, see `jit.rs`. Stays off when
+    /// Cranelift has no backend for the host. The run statistics count the
+    /// instructions of native blocks, but not per pc or for the stack
+    pub fn with_jit(mut self) -> Self {
+        self.jit = Jit::new();
+        self
+    }
+
+    /// runs the native block at `pc` if there is one, returns whether it did
+    pub(super) fn run_jitted(&mut self, pc: u32) -> bool {
+        let plain = self.is_plain();
+        let budget = self.budget(u64::MAX);
+        let Some(jit) = self.jit.as_mut().filter(|_| plain) else {
+            return false;
+        };
+        let Some(block) = jit.block(pc, &self.memory) else {
+            return false;
+        };
+        // the interpreter reports the instruction that crosses the limit
+        if block.length as u64 > budget {
+            return false;
+        }
+
+        // SAFETY: the block only reads and writes the 32 registers and the pc
+        unsafe {
+            (block.function)(self.vm_state.registers.as_mut_ptr(), &mut self.vm_state.pc);
+        }
+        jit.instructions += block.length as u64;
+        self.stats.instructions_retired += block.length as u64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_run_hot_loops_natively_with_the_same_result() {
+        // 0x1000 addi t0, zero, 100
+        // 0x1004 addi a0, a0, 3       <- loop
+        // 0x1008 slli a1, a0, 2
+        // 0x100c sltu a2, a1, a0
+        // 0x1010 xor a3, a3, a1
+        // 0x1014 addi t0, t0, -1
+        // 0x1018 bne t0, zero, 0x1004
+        // 0x101c ebreak
+        let program = [
+            0x0640_0293,
+            0x0035_0513,
+            0x0025_1593,
+            0x00a5_b633,
+            0x00b6_c6b3,
+            0xfff2_8293,
+            0xfe02_96e3,
+            0x0010_0073,
+        ];
+        let mut interpreted = Vm::new(0x1000, 0x100);
+        interpreted
+            .load_program(
+                0x1000,
+                &program
+                    .iter()
+                    .flat_map(|word: &u32| word.to_le_bytes())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        assert_eq!(interpreted.run(), Ok(StopReason::Ebreak));
+
+        let mut vm = Vm::with_program_words(0x1000, &program).with_jit();
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers, interpreted.vm_state.registers);
+        assert_eq!(vm.vm_state.pc, 0x101c);
+        assert_eq!(
+            vm.stats.instructions_retired,
+            interpreted.stats.instructions_retired
+        );
+        let jit = vm.jit.as_ref().unwrap();
+        assert!(jit.compiled_blocks > 0);
+        assert!(jit.instructions > 0);
+    }
+
+    #[test]
+    fn should_drop_blocks_the_guest_overwrites() {
+        // 0x1000 lui t1, 0x1
+        // 0x1004 lw t2, 0x40(t1)
+        // 0x1008 addi t0, zero, 20
+        // 0x100c addi a0, a0, 1       <- loop, becomes addi a0, a0, 100
+        // 0x1010 addi t0, t0, -1
+        // 0x1014 bne t0, zero, 0x100c
+        // 0x1018 bne a1, zero, 0x1028
+        // 0x101c addi a1, zero, 1
+        // 0x1020 sw t2, 0xc(t1)
+        // 0x1024 jal zero, 0x1008
+        // 0x1028 ebreak
+        // 0x1040 .word addi a0, a0, 100
+        let mut program = vec![
+            0x0000_1337,
+            0x0403_2383,
+            0x0140_0293,
+            0x0015_0513,
+            0xfff2_8293,
+            0xfe02_9ce3,
+            0x0005_9863,
+            0x0010_0593,
+            0x0073_2623,
+            0xfe5f_f06f,
+            0x0010_0073,
+        ];
+        program.resize(16, 0);
+        program.push(0x0645_0513);
+        let mut vm = Vm::with_program_words(0x1000, &program).with_jit();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], 20 + 20 * 100);
+    }
+}
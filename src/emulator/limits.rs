@@ -0,0 +1,201 @@
+//! Resource limits for running programs nobody checked, like uploads to the
+//! web playground. `VmLimits` holds them all, each one is optional:
+//!
+//! - the bytes of memory the memory map may have, checked before any of it
+//!   is allocated (`Vm::from_memory_map_with_limits`),
+//! - the instructions, `run()` fails with `ExecutionLimitExceeded`,
+//! - the host files open at the same time, `openat` fails with `EMFILE`
+//!   like on Linux,
+//! - the wall-clock time of all runs together, `run()` fails with
+//!   `LimitExceeded`. It is looked at every `TIME_CHECK_INTERVAL`
+//!   instructions, and without std there is no clock.
+
+use core::fmt;
+use core::time::Duration;
+
+use super::cooperative::Stopwatch;
+use super::emulator::Vm;
+use super::error::VmError;
+use super::memory::MemoryMap;
+
+/// instructions between two looks at the clock
+pub(super) const TIME_CHECK_INTERVAL: u64 = 10_000;
+
+/// which limit a run went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// the memory map wanted `size` bytes
+    Memory {
+        size: usize,
+        max: usize,
+    },
+    WallTime(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory { size, max } => {
+                write!(f, "memory limit of {max} bytes ({size} bytes asked for)")
+            }
+            Self::WallTime(max) => write!(f, "wall time limit of {max:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmLimits {
+    /// bytes of RAM and ROM
+    pub max_memory: Option<usize>,
+    pub max_instructions: Option<u64>,
+    pub max_open_files: Option<usize>,
+    pub max_wall_time: Option<Duration>,
+}
+
+impl VmLimits {
+    /// no limits, add them with the `with_` methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn with_max_instructions(mut self, instructions: u64) -> Self {
+        self.max_instructions = Some(instructions);
+        self
+    }
+
+    pub fn with_max_open_files(mut self, files: usize) -> Self {
+        self.max_open_files = Some(files);
+        self
+    }
+
+    pub fn with_max_wall_time(mut self, time: Duration) -> Self {
+        self.max_wall_time = Some(time);
+        self
+    }
+
+    /// fails if `map` has more bytes than the memory limit
+    pub fn check_memory_map(&self, map: &MemoryMap) -> Result<(), VmError> {
+        match self.max_memory {
+            Some(max) if map.size() > max => Err(VmError::LimitExceeded {
+                pc: 0,
+                instruction: 0,
+                limit: Limit::Memory {
+                    size: map.size(),
+                    max,
+                },
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Vm {
+    /// `from_memory_map` with `limits`, if the map is within them
+    pub fn from_memory_map_with_limits(map: &MemoryMap, limits: VmLimits) -> Result<Self, VmError> {
+        limits.check_memory_map(map)?;
+        Ok(Self::from_memory_map(map).with_limits(limits))
+    }
+
+    /// enforces `limits` from now on. The instruction limit is the
+    /// `execution_limit`, the memory is already there, see
+    /// `from_memory_map_with_limits`
+    pub fn with_limits(mut self, limits: VmLimits) -> Self {
+        self.execution_limit = limits.max_instructions;
+        self.limits = limits;
+        self
+    }
+
+    /// fails once all runs together took longer than the wall time limit,
+    /// `stopwatch` started with the current run
+    pub(super) fn check_wall_time(&self, stopwatch: &Stopwatch) -> Result<(), VmError> {
+        match self.limits.max_wall_time {
+            Some(max) if self.stats.run_time + stopwatch.elapsed() >= max => {
+                let pc = self.vm_state.pc as u32;
+                Err(VmError::LimitExceeded {
+                    pc,
+                    instruction: self.memory.read_u32(pc).unwrap_or(0),
+                    limit: Limit::WallTime(max),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::{Limit, VmLimits};
+    use crate::fs::VirtFs;
+    use crate::memory::MemoryMap;
+    use crate::register::Register;
+    use crate::syscalls::{EMFILE, SYS_OPENAT};
+    use crate::{Vm, VmError};
+
+    #[test]
+    fn should_enforce_every_limit() {
+        let limits = VmLimits::new()
+            .with_max_memory(0x2000)
+            .with_max_instructions(100)
+            .with_max_open_files(1);
+        let map = MemoryMap::new().ram(0x1000, 0x2000).rom(0x8000, 0x100);
+        assert_eq!(
+            Vm::from_memory_map_with_limits(&map, limits).err(),
+            Some(VmError::LimitExceeded {
+                pc: 0,
+                instruction: 0,
+                limit: Limit::Memory {
+                    size: 0x2100,
+                    max: 0x2000
+                },
+            })
+        );
+
+        // 0x1000 ecall                 <- openat("/a")
+        // 0x1004 jal zero, 0x1000
+        // 0x1008 "/a"
+        let program: Vec<u8> = [0x0000_0073u32, 0xffdf_f06f, 0x0000_612f]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let map = MemoryMap::new().ram(0x1000, 0x1000);
+        let mut fs = VirtFs::new();
+        fs.insert("/a", *b"a");
+        let mut vm = Vm::from_memory_map_with_limits(&map, limits)
+            .unwrap()
+            .with_file_system(fs);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.registers.write(Register::A7, SYS_OPENAT as i32);
+        vm.vm_state.registers.write(Register::A0, -100);
+        vm.vm_state.registers.write(Register::A1, 0x1008);
+
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], 3);
+        vm.vm_state.registers.write(Register::A0, -100);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], -EMFILE);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::ExecutionLimitExceeded { limit: 100, .. })
+        ));
+
+        // the time of the earlier runs counts
+        vm.execution_limit = None;
+        vm.limits.max_wall_time = Some(Duration::from_millis(1));
+        vm.stats.run_time = Duration::from_millis(1);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::LimitExceeded {
+                limit: Limit::WallTime(_),
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,544 @@
+use alloc::collections::BTreeSet;
+use core::iter;
+
+use super::backing::Backing;
+use super::error::{AccessKind, VmError};
+use super::prelude::*;
+
+/// `dirty_pages` and `diff` work in pages of `1 << PAGE_SHIFT` bytes
+pub const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+/// a load or store done by the guest, `size` is in bytes and `value` is what
+/// was read or written (not sign extended)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryAccess {
+    Read { address: u32, size: u32, value: u32 },
+    Write { address: u32, size: u32, value: u32 },
+}
+
+/// what the guest may do in a region of a `MemoryMap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Ram,
+    /// the guest can read and execute it, only the host can `load` into it
+    Rom,
+    /// every access faults, e.g. a page below the stack to catch overflows
+    Guard,
+}
+
+/// The address space of a vm, for `Vm::from_memory_map`. The first RAM
+/// region is the main memory (`Memory::base`/`Memory::size`), the other
+/// regions are checked before it, so a guard region can cut a hole into it.
+/// Addresses outside every region are out of bounds.
+///
+/// With `with_w_xor_x` no region is both writable and executable: the guest
+/// can only execute ROM, which it can not store to, and fetching from RAM
+/// faults. Code can then never change under a block the vm has compiled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    regions: Vec<(u32, usize, RegionKind)>,
+    w_xor_x: bool,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ram(mut self, base: u32, size: usize) -> Self {
+        self.regions.push((base, size, RegionKind::Ram));
+        self
+    }
+
+    pub fn rom(mut self, base: u32, size: usize) -> Self {
+        self.regions.push((base, size, RegionKind::Rom));
+        self
+    }
+
+    pub fn guard(mut self, base: u32, size: usize) -> Self {
+        self.regions.push((base, size, RegionKind::Guard));
+        self
+    }
+
+    /// write xor execute: fetches from RAM fault
+    pub fn with_w_xor_x(mut self) -> Self {
+        self.w_xor_x = true;
+        self
+    }
+
+    /// the bytes of all RAM and ROM regions, guards have none
+    pub fn size(&self) -> usize {
+        self.regions
+            .iter()
+            .filter(|(_, _, kind)| *kind != RegionKind::Guard)
+            .map(|(_, size, _)| size)
+            .sum()
+    }
+
+    /// where the pc starts: the first region that is no guard
+    pub(super) fn start(&self) -> u32 {
+        self.regions
+            .iter()
+            .find(|(_, _, kind)| *kind != RegionKind::Guard)
+            .map_or(0, |(base, _, _)| *base)
+    }
+}
+
+/// a region besides the main memory, a guard has no bytes
+#[derive(Debug, Clone)]
+struct Region {
+    base: u32,
+    size: usize,
+    kind: RegionKind,
+    bytes: Backing,
+    /// which bytes were written, see `track_initialization`
+    shadow: Option<Shadow>,
+}
+
+impl Region {
+    fn contains(&self, address: u32) -> bool {
+        address >= self.base && ((address - self.base) as usize) < self.size
+    }
+}
+
+/// one bit per byte, set once the byte is written
+#[derive(Debug, Clone)]
+struct Shadow(Vec<u64>);
+
+impl Shadow {
+    fn new(size: usize) -> Self {
+        Self(vec![0; size.div_ceil(64)])
+    }
+
+    fn set(&mut self, offset: usize, length: usize) {
+        for byte in offset..offset + length {
+            self.0[byte / 64] |= 1 << (byte % 64);
+        }
+    }
+
+    fn is_set(&self, offset: usize, length: usize) -> bool {
+        (offset..offset + length).all(|byte| self.0[byte / 64] & 1 << (byte % 64) != 0)
+    }
+}
+
+/// the pages of a memory that differ from an earlier copy of it, with their
+/// new bytes. The pages count from the start of each region, so the last one
+/// of a region can be short
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryDiff {
+    pub pages: Vec<(u32, Vec<u8>)>,
+}
+
+/// The guest memory. The main memory is a flat block of bytes that starts at
+/// `base`, so address `base` is `bytes[0]`; a `MemoryMap` can add ROM, more
+/// RAM and guard regions. RISC-V is little endian, so all the multi byte
+/// reads and writes are little endian too. Big memories are mapped lazily,
+/// see `backing.rs`.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    base: u32,
+    bytes: Backing,
+    regions: Vec<Region>,
+    /// the numbers of the pages written since `clear_dirty_pages`
+    dirty: BTreeSet<u32>,
+    /// which bytes of the main memory were written, see
+    /// `track_initialization`
+    shadow: Option<Shadow>,
+    /// only ROM is executable, see `MemoryMap::with_w_xor_x`
+    w_xor_x: bool,
+}
+
+impl Memory {
+    /// creates `size` bytes of zeroed memory starting at the address `base`
+    pub fn new(base: u32, size: usize) -> Self {
+        Self {
+            base,
+            bytes: Backing::zeroed(size),
+            regions: Vec::new(),
+            dirty: BTreeSet::new(),
+            shadow: None,
+            w_xor_x: false,
+        }
+    }
+
+    /// zeroed memory laid out like `map`
+    pub fn from_map(map: &MemoryMap) -> Self {
+        let main = map
+            .regions
+            .iter()
+            .position(|(_, _, kind)| *kind == RegionKind::Ram);
+        let (base, size) = main.map_or((0, 0), |index| {
+            let (base, size, _) = map.regions[index];
+            (base, size)
+        });
+        let regions = map
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != main)
+            .map(|(_, &(base, size, kind))| Region {
+                base,
+                size,
+                kind,
+                bytes: Backing::zeroed(if kind == RegionKind::Guard { 0 } else { size }),
+                shadow: None,
+            })
+            .collect();
+        Self {
+            regions,
+            w_xor_x: map.w_xor_x,
+            ..Self::new(base, size)
+        }
+    }
+
+    /// the first valid address
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// the number of bytes in the memory
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// the main memory, `bytes()[0]` is at `base`. It is allocated once and
+    /// only moves on `fork`, so the web frontend can keep a view into it
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// a copy that shares the pages of big memories with `self` until
+    /// either writes to them, see `backing.rs`
+    pub fn fork(&mut self) -> Self {
+        Self {
+            base: self.base,
+            bytes: self.bytes.fork(),
+            regions: self
+                .regions
+                .iter_mut()
+                .map(|region| Region {
+                    base: region.base,
+                    size: region.size,
+                    kind: region.kind,
+                    bytes: region.bytes.fork(),
+                    shadow: region.shadow.clone(),
+                })
+                .collect(),
+            dirty: self.dirty.clone(),
+            shadow: self.shadow.clone(),
+            w_xor_x: self.w_xor_x,
+        }
+    }
+
+    /// finds the region (`None` for the main memory) that has all the
+    /// `length` bytes from `address` and the index of the first one in it,
+    /// if `kind` of access is allowed there. `None` as the kind is the host
+    /// loading, which may write ROM
+    fn locate(
+        &self,
+        address: u32,
+        length: usize,
+        kind: Option<AccessKind>,
+    ) -> Result<(Option<usize>, usize), VmError> {
+        let out_of_bounds = VmError::MemoryOutOfBounds {
+            pc: 0,
+            instruction: 0,
+            address,
+        };
+        let fault = VmError::AccessFault {
+            pc: 0,
+            instruction: 0,
+            address,
+            kind: kind.unwrap_or(AccessKind::Store),
+        };
+        let fetch_from_ram = self.w_xor_x && kind == Some(AccessKind::Fetch);
+        if let Some(index) = self
+            .regions
+            .iter()
+            .position(|region| region.contains(address))
+        {
+            let region = &self.regions[index];
+            let offset = (address - region.base) as usize;
+            let denied = match region.kind {
+                RegionKind::Guard => true,
+                RegionKind::Rom => kind == Some(AccessKind::Store),
+                RegionKind::Ram => fetch_from_ram,
+            };
+            if denied {
+                return Err(fault);
+            }
+            if offset + length > region.size {
+                return Err(out_of_bounds);
+            }
+            return Ok((Some(index), offset));
+        }
+
+        let offset = address.wrapping_sub(self.base) as usize;
+        if address < self.base || offset + length > self.bytes.len() {
+            return Err(out_of_bounds);
+        }
+        if fetch_from_ram {
+            return Err(fault);
+        }
+        Ok((None, offset))
+    }
+
+    fn slice(&self, address: u32, length: usize, kind: AccessKind) -> Result<&[u8], VmError> {
+        let (region, offset) = self.locate(address, length, Some(kind))?;
+        let bytes = match region {
+            Some(index) => &self.regions[index].bytes,
+            None => &self.bytes,
+        };
+        Ok(&bytes[offset..offset + length])
+    }
+
+    fn slice_mut(
+        &mut self,
+        address: u32,
+        length: usize,
+        kind: Option<AccessKind>,
+    ) -> Result<&mut [u8], VmError> {
+        let (region, offset) = self.locate(address, length, kind)?;
+        self.mark_dirty(address, length);
+        let (bytes, shadow) = match region {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                (&mut region.bytes, &mut region.shadow)
+            }
+            None => (&mut self.bytes, &mut self.shadow),
+        };
+        if let Some(shadow) = shadow {
+            shadow.set(offset, length);
+        }
+        Ok(&mut bytes[offset..offset + length])
+    }
+
+    /// remembers which bytes of RAM get written from now on, the others
+    /// count as uninitialized. ROM always counts as initialized
+    pub fn track_initialization(&mut self) {
+        self.shadow = Some(Shadow::new(self.bytes.len()));
+        for region in &mut self.regions {
+            if region.kind == RegionKind::Ram {
+                region.shadow = Some(Shadow::new(region.size));
+            }
+        }
+    }
+
+    /// whether all the `length` bytes from `address` were written since
+    /// `track_initialization`, true if they are not tracked or not memory
+    pub fn is_initialized(&self, address: u32, length: usize) -> bool {
+        let Ok((region, offset)) = self.locate(address, length, None) else {
+            return true;
+        };
+        let shadow = match region {
+            Some(index) => &self.regions[index].shadow,
+            None => &self.shadow,
+        };
+        shadow
+            .as_ref()
+            .is_none_or(|shadow| shadow.is_set(offset, length))
+    }
+
+    pub(super) fn mark_dirty(&mut self, address: u32, length: usize) {
+        if length == 0 {
+            return;
+        }
+        let last = address.wrapping_add(length as u32 - 1);
+        self.dirty
+            .extend(address >> PAGE_SHIFT..=last >> PAGE_SHIFT);
+    }
+
+    /// the addresses of the pages the guest or the host wrote since the last
+    /// `clear_dirty_pages`, in order
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.dirty.iter().map(|page| page << PAGE_SHIFT)
+    }
+
+    pub fn clear_dirty_pages(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// the pages that differ from `old`, an earlier copy of this memory (from
+    /// a snapshot), so `old.apply_diff` makes it this one again
+    pub fn diff(&self, old: &Memory) -> MemoryDiff {
+        let blocks = iter::once((self.base, &self.bytes, &old.bytes)).chain(
+            self.regions
+                .iter()
+                .zip(&old.regions)
+                .map(|(new, old)| (new.base, &new.bytes, &old.bytes)),
+        );
+        let mut diff = MemoryDiff::default();
+        for (base, new, old) in blocks {
+            let pages = new.chunks(PAGE_SIZE).zip(old.chunks(PAGE_SIZE));
+            for (index, (new, old)) in pages.enumerate() {
+                if new != old {
+                    let address = base + (index * PAGE_SIZE) as u32;
+                    diff.pages.push((address, new.to_vec()));
+                }
+            }
+        }
+        diff
+    }
+
+    /// writes the pages of `diff` like `load`
+    pub fn apply_diff(&mut self, diff: &MemoryDiff) -> Result<(), VmError> {
+        for (address, bytes) in &diff.pages {
+            self.load(*address, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// copies `data` into memory starting at `address`, this is how programs
+    /// get into the vm. Unlike the stores it can write ROM
+    pub fn load(&mut self, address: u32, data: &[u8]) -> Result<(), VmError> {
+        self.slice_mut(address, data.len(), None)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    /// returns `length` bytes starting at `address`
+    pub fn read_bytes(&self, address: u32, length: usize) -> Result<&[u8], VmError> {
+        self.slice(address, length, AccessKind::Load)
+    }
+
+    /// writes `data` at `address` the way a store does, so not into ROM
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), VmError> {
+        self.slice_mut(address, data.len(), Some(AccessKind::Store))?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn read_u8(&self, address: u32) -> Result<u8, VmError> {
+        Ok(self.read_bytes(address, 1)?[0])
+    }
+
+    pub fn read_u16(&self, address: u32) -> Result<u16, VmError> {
+        let bytes = self.read_bytes(address, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&self, address: u32) -> Result<u32, VmError> {
+        let bytes = self.read_bytes(address, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// reads the instruction at `address`, which faults where the memory is
+    /// not executable
+    pub fn fetch_u32(&self, address: u32) -> Result<u32, VmError> {
+        let bytes = self.slice(address, 4, AccessKind::Fetch)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn write_u8(&mut self, address: u32, value: u8) -> Result<(), VmError> {
+        self.write_bytes(address, &[value])
+    }
+
+    pub fn write_u16(&mut self, address: u32, value: u16) -> Result<(), VmError> {
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, address: u32, value: u32) -> Result<(), VmError> {
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Memory, MemoryMap};
+    use crate::{AccessKind, Vm, VmError};
+
+    #[test]
+    fn should_read_back_little_endian_words() {
+        let mut memory = Memory::new(0x1000, 16);
+
+        memory.write_u32(0x1004, 0x00d5_6363).unwrap();
+
+        assert_eq!(memory.read_u8(0x1004).unwrap(), 0x63);
+        assert_eq!(memory.read_u16(0x1006).unwrap(), 0x00d5);
+        assert_eq!(memory.read_u32(0x1004).unwrap(), 0x00d5_6363);
+    }
+
+    #[test]
+    fn should_reject_accesses_outside_of_the_memory() {
+        let mut memory = Memory::new(0x1000, 16);
+
+        // below the base
+        assert!(memory.read_u8(0x0fff).is_err());
+        // the last 2 bytes are in bounds but the other 2 are not
+        assert!(memory.read_u32(0x100e).is_err());
+        assert!(memory.write_u32(0x100e, 1).is_err());
+        // right at the end
+        assert!(memory.read_u32(0x100c).is_ok());
+    }
+
+    #[test]
+    fn should_fault_on_guard_regions_and_stores_to_rom() {
+        let map = MemoryMap::new()
+            .rom(0x1000, 0x100)
+            .guard(0x2000, 0x1000)
+            .ram(0x3000, 0x1000);
+        let mut memory = Memory::from_map(&map);
+        assert_eq!((memory.base(), memory.size()), (0x3000, 0x1000));
+
+        // the host can load into ROM, the guest's stores can not
+        memory.load(0x1000, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(memory.read_u32(0x1000).unwrap(), 0x0403_0201);
+        let fault = |address, kind| VmError::AccessFault {
+            pc: 0,
+            instruction: 0,
+            address,
+            kind,
+        };
+        assert_eq!(
+            memory.write_u8(0x1000, 0),
+            Err(fault(0x1000, AccessKind::Store))
+        );
+        assert_eq!(memory.read_u8(0x2800), Err(fault(0x2800, AccessKind::Load)));
+        // between the regions
+        assert!(matches!(
+            memory.read_u8(0x1100),
+            Err(VmError::MemoryOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn guest_accesses_to_a_guard_region_should_fault() {
+        // 0x1000 lui t0, 0x3
+        // 0x1004 sw t0, 0(t0)
+        // 0x1008 sw t0, -4(t0)         <- the guard below the RAM
+        let program: Vec<u8> = [0x0000_32b7u32, 0x0052_a023, 0xfe52_ae23]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let map = MemoryMap::new()
+            .guard(0x2000, 0x1000)
+            .rom(0x1000, 0x100)
+            .ram(0x3000, 0x1000);
+        let mut vm = Vm::from_memory_map(&map);
+        assert_eq!(vm.vm_state.pc, 0x1000);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::AccessFault {
+                pc: 0x1008,
+                instruction: 0xfe52_ae23,
+                address: 0x2ffc,
+                kind: AccessKind::Store,
+            })
+        );
+        assert_eq!(vm.memory.read_u32(0x3000).unwrap(), 0x3000);
+        assert_eq!(AccessKind::Store.cause(), 7);
+
+        // running into the guard faults on the fetch
+        vm.vm_state.pc = 0x2000;
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::AccessFault {
+                kind: AccessKind::Fetch,
+                ..
+            })
+        ));
+    }
+}
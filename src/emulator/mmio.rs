@@ -0,0 +1,290 @@
+//! Memory-mapped devices. A device sits behind a range of addresses, and the
+//! guest's loads and stores in that range go to the device instead of the
+//! memory. The range does not have to be inside the memory, devices usually
+//! sit far below it (the UART of QEMU's `virt` machine is at 0x1000_0000).
+//!
+//! Only the plain loads and stores reach devices, the atomics and the host's
+//! `vm.memory` accesses do not.
+//!
+//! A device added with `add_device_with_irq` drives a line of the PLIC: the
+//! vm looks at its `interrupt()` before every instruction and raises or
+//! clears the line when the level changed.
+
+use core::any::Any;
+
+use super::emulator::Vm;
+use super::memory::{Memory, MemoryAccess};
+use super::prelude::*;
+use super::register::Register;
+use super::replay::ReplayEvent;
+use super::rv32i::Rv32iInstruction;
+
+/// a device register file. `offset` is from the start of the device's range
+/// and `size` is 1, 2 or 4 bytes; a read returns the value in the low bits.
+/// Devices that do DMA get to the guest memory through `memory`
+pub trait Device: Any {
+    fn read(&mut self, offset: u32, size: u32, memory: &mut Memory) -> u32;
+    /// returns whether the device wrote to the memory, then the vm drops
+    /// the code it decoded since that may have changed
+    fn write(&mut self, offset: u32, size: u32, value: u32, memory: &mut Memory) -> bool;
+    /// lets the device do what it does on its own, like taking in a
+    /// network packet; returns whether it wrote to the memory
+    fn poll(&mut self, _memory: &mut Memory) -> bool {
+        false
+    }
+    /// the level of the device's interrupt line
+    fn interrupt(&self) -> bool {
+        false
+    }
+    /// what `vm.summary()` calls it
+    fn name(&self) -> &'static str {
+        "device"
+    }
+}
+
+struct Mapping {
+    base: u32,
+    size: u32,
+    device: Box<dyn Device>,
+    /// the PLIC line of the device's interrupt
+    irq: Option<u32>,
+    /// the level the line has now
+    raised: bool,
+}
+
+/// the devices and the ranges they sit behind
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    /// the mapping with all `size` bytes from `address`
+    fn mapping(&mut self, address: u32, size: u32) -> Option<&mut Mapping> {
+        self.mappings.iter_mut().find(|mapping| {
+            address >= mapping.base && address - mapping.base + size <= mapping.size
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+}
+
+/// the base register, offset and size of a load or store, and the
+/// destination of a load or the source of a store. `None` for the other
+/// instructions
+pub(super) fn load_store(
+    instruction: &Rv32iInstruction,
+) -> Option<(Register, i16, u32, Option<Register>, Register)> {
+    Some(match instruction {
+        Rv32iInstruction::Lb(i) | Rv32iInstruction::Lbu(i) => {
+            (i.rs1, i.imm, 1, Some(i.rd), Register::ZERO)
+        }
+        Rv32iInstruction::Lh(i) | Rv32iInstruction::Lhu(i) => {
+            (i.rs1, i.imm, 2, Some(i.rd), Register::ZERO)
+        }
+        Rv32iInstruction::Lw(i) => (i.rs1, i.imm, 4, Some(i.rd), Register::ZERO),
+        Rv32iInstruction::Sb(s) => (s.rs1, s.imm, 1, None, s.rs2),
+        Rv32iInstruction::Sh(s) => (s.rs1, s.imm, 2, None, s.rs2),
+        Rv32iInstruction::Sw(s) => (s.rs1, s.imm, 4, None, s.rs2),
+        _ => return None,
+    })
+}
+
+/// the `value` a load read, extended the way the load does
+pub(super) fn extend_load(instruction: &Rv32iInstruction, value: u32) -> i32 {
+    match instruction {
+        Rv32iInstruction::Lb(_) => value as i8 as i32,
+        Rv32iInstruction::Lh(_) => value as i16 as i32,
+        Rv32iInstruction::Lbu(_) => value as u8 as i32,
+        Rv32iInstruction::Lhu(_) => value as u16 as i32,
+        _ => value as i32,
+    }
+}
+
+impl Vm {
+    /// puts `device` behind the `size` bytes from `base`
+    pub fn add_device(&mut self, base: u32, size: u32, device: Box<dyn Device>) {
+        self.bus.mappings.push(Mapping {
+            base,
+            size,
+            device,
+            irq: None,
+            raised: false,
+        });
+    }
+
+    /// like `add_device`, and the device's interrupt is the PLIC `line`
+    pub fn add_device_with_irq(
+        &mut self,
+        base: u32,
+        size: u32,
+        line: u32,
+        device: Box<dyn Device>,
+    ) {
+        self.bus.mappings.push(Mapping {
+            base,
+            size,
+            device,
+            irq: Some(line),
+            raised: false,
+        });
+    }
+
+    /// the device of type `T` at `base`
+    pub fn device<T: Device>(&self, base: u32) -> Option<&T> {
+        let mapping = self
+            .bus
+            .mappings
+            .iter()
+            .find(|mapping| mapping.base == base)?;
+        (&*mapping.device as &dyn Any).downcast_ref()
+    }
+
+    pub fn device_mut<T: Device>(&mut self, base: u32) -> Option<&mut T> {
+        let mapping = self
+            .bus
+            .mappings
+            .iter_mut()
+            .find(|mapping| mapping.base == base)?;
+        (&mut *mapping.device as &mut dyn Any).downcast_mut()
+    }
+
+    /// polls every device, call it between `run_for` slices so devices
+    /// that get input from the host can hand it to the guest
+    pub fn poll_devices(&mut self) {
+        let mut wrote = false;
+        for mapping in &mut self.bus.mappings {
+            wrote |= mapping.device.poll(&mut self.memory);
+        }
+        if wrote {
+            self.invalidate_decode_cache();
+        }
+    }
+
+    /// raises or clears the PLIC line of every device that has one, to the
+    /// level of its interrupt
+    pub(super) fn update_device_interrupts(&mut self) {
+        if self.plic.is_none() {
+            return;
+        }
+        for index in 0..self.bus.mappings.len() {
+            let mapping = &mut self.bus.mappings[index];
+            let Some(line) = mapping.irq else {
+                continue;
+            };
+            let level = mapping.device.interrupt();
+            if level != mapping.raised {
+                mapping.raised = level;
+                self.host_irq(line, level);
+            }
+        }
+    }
+
+    /// executes a load or store that hits a device and moves the pc past it,
+    /// `None` if `instruction` is no load or store or misses every device
+    pub(super) fn execute_mmio(&mut self, instruction: &Rv32iInstruction) -> Option<MemoryAccess> {
+        if self.bus.is_empty() && self.clint.is_none() && self.plic.is_none() {
+            return None;
+        }
+        let (rs1, imm, size, destination, source) = load_store(instruction)?;
+        let address = (self.vm_state.registers[rs1] as u32).wrapping_add(imm as i32 as u32);
+
+        let access = match destination {
+            Some(rd) => {
+                let value = self.read_device(address, size)?;
+                self.vm_state
+                    .registers
+                    .write(rd, extend_load(instruction, value));
+                MemoryAccess::Read {
+                    address,
+                    size,
+                    value,
+                }
+            }
+            None => {
+                let mask = u32::MAX >> (32 - 8 * size);
+                let value = self.vm_state.registers[source] as u32 & mask;
+                self.write_device(address, size, value)?;
+                MemoryAccess::Write {
+                    address,
+                    size,
+                    value,
+                }
+            }
+        };
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        Some(access)
+    }
+
+    /// reads from the CLINT, the PLIC or the device at `address`, `None` if
+    /// there is none
+    pub(super) fn read_device(&mut self, address: u32, size: u32) -> Option<u32> {
+        if let Some(value) = self.read_clint(address, size) {
+            self.record_builtin_device(address, false);
+            return Some(value);
+        }
+        if let Some(value) = self.read_plic(address, size) {
+            self.record_builtin_device(address, false);
+            return Some(value);
+        }
+        let at = self.stats.instructions_retired;
+        let mapping = self.bus.mapping(address, size)?;
+        let (base, name) = (mapping.base, mapping.device.name());
+        self.stats.record_device(base, name, false);
+        if self.is_replaying() {
+            let replayed = self.replayed(|event| match *event {
+                ReplayEvent::DeviceRead {
+                    at: read_at,
+                    address: read_address,
+                    value,
+                } if (read_at, read_address) == (at, address) => Some(value.into()),
+                _ => None,
+            });
+            if let Some(value) = replayed {
+                return Some(value as u32);
+            }
+        }
+        let mapping = self.bus.mapping(address, size)?;
+        let value = mapping
+            .device
+            .read(address - mapping.base, size, &mut self.memory);
+        self.record(ReplayEvent::DeviceRead { at, address, value });
+        Some(value)
+    }
+
+    /// writes to the CLINT, the PLIC or the device at `address`, `None` if
+    /// there is none
+    pub(super) fn write_device(&mut self, address: u32, size: u32, value: u32) -> Option<()> {
+        if self.write_clint(address, size, value) || self.write_plic(address, size, value) {
+            self.record_builtin_device(address, true);
+            return Some(());
+        }
+        let mapping = self.bus.mapping(address, size)?;
+        self.stats
+            .record_device(mapping.base, mapping.device.name(), true);
+        if mapping
+            .device
+            .write(address - mapping.base, size, value, &mut self.memory)
+        {
+            self.invalidate_decode_cache();
+        }
+        Some(())
+    }
+
+    /// counts an access to the CLINT or the PLIC for `vm.summary()`
+    fn record_builtin_device(&mut self, address: u32, write: bool) {
+        let clint = self.clint.as_ref().map(|clint| (clint.base, "clint"));
+        let plic = self.plic.as_ref().map(|plic| (plic.base, "plic"));
+        // the one of the two below `address` and closest to it
+        let device = [clint, plic]
+            .into_iter()
+            .flatten()
+            .filter(|(base, _)| *base <= address)
+            .max_by_key(|(base, _)| *base);
+        if let Some((base, name)) = device {
+            self.stats.record_device(base, name, write);
+        }
+    }
+}
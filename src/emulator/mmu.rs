@@ -0,0 +1,498 @@
+//! Sv32 virtual memory, the page tables of RV32 (Sv39 is the one of RV64,
+//! which this vm is not). With `MODE` set in `satp` every fetch, load and
+//! store below machine mode goes through a two-level walk from the root
+//! table at `satp.PPN`: a leaf on the first level maps a 4 MiB megapage, on
+//! the second a 4 KiB page. A leaf has to let the mode and the access in
+//! (`R`, `W`, `X`, `U`, and `SUM`/`MXR` in `mstatus`), everything else is a
+//! page fault, which always traps into the guest with the virtual address
+//! in `xtval`.
+//!
+//! A leaf that is used without its `A` bit, or written to without its `D`
+//! bit, gets them set (`AdPolicy::Update`), or faults so the guest's kernel
+//! sets them (`AdPolicy::Fault`). Walks are cached in the TLB of `tlb.rs`
+//! under the ASID in `satp`, so switching between address spaces keeps
+//! them. After editing a page table the guest drops the stale ones with
+//! `sfence.vma`: of one address (rs1), one ASID (rs2, global pages stay)
+//! or everything (x0 for both).
+//!
+//! While paging is on the decode and block caches and the jit are bypassed,
+//! and `MemoryAccess` has the physical address. An access that crosses a
+//! page is a misaligned access, the vector loads and stores are not
+//! implemented, and the host's accesses (`vm.memory`, `fetch_at`, the
+//! syscalls) are physical.
+
+use super::csr::{Privilege, MSTATUS_MXR, MSTATUS_SUM};
+use super::emulator::{Instruction, Vm};
+use super::error::{AccessKind, VmError};
+use super::memory::MemoryAccess;
+use super::mmio::{extend_load, load_store};
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+use super::tlb::{Tlb, TlbEntry};
+
+/// `satp.MODE`, translation is on
+pub const SATP_MODE_SV32: u32 = 1 << 31;
+/// the address space of the translations in `satp`
+const SATP_ASID_SHIFT: u32 = 22;
+/// ASIDLEN is 9, every ASID of Sv32
+const SATP_ASID_MASK: u32 = 0x1ff;
+/// the physical page number of the root table in `satp`
+const SATP_PPN: u32 = 0x3f_ffff;
+
+pub const PAGE_SIZE: u32 = 4096;
+pub const MEGAPAGE_SIZE: u32 = 4 << 20;
+
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+/// what happens to a leaf without the `A` or `D` bit the access needs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdPolicy {
+    /// the walk sets them, like Svadu
+    #[default]
+    Update,
+    /// the access page faults, like Svade
+    Fault,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Mmu {
+    pub ad_policy: AdPolicy,
+    pub tlb: Tlb,
+}
+
+impl Vm {
+    pub fn with_ad_policy(mut self, policy: AdPolicy) -> Self {
+        self.mmu.ad_policy = policy;
+        self
+    }
+
+    /// whether the accesses go through the page tables
+    pub fn is_paging(&self) -> bool {
+        self.csrs.satp & SATP_MODE_SV32 != 0 && self.csrs.privilege < Privilege::Machine
+    }
+
+    /// the ASID in `satp`
+    pub fn asid(&self) -> u16 {
+        (self.csrs.satp >> SATP_ASID_SHIFT & SATP_ASID_MASK) as u16
+    }
+
+    /// the physical address of `address`, `address` itself while paging is
+    /// off
+    pub fn translate(&mut self, address: u32, kind: AccessKind) -> Result<u32, VmError> {
+        if !self.is_paging() {
+            return Ok(address);
+        }
+        let mut entry = match self.mmu.tlb.lookup(address / PAGE_SIZE, self.asid()) {
+            Some(entry) => entry,
+            None => self.walk(address, kind)?,
+        };
+
+        let page_fault = VmError::PageFault {
+            pc: 0,
+            instruction: 0,
+            address,
+            kind,
+        };
+        if !self.permits(entry.pte, kind) {
+            return Err(page_fault);
+        }
+        let needed = PTE_A | if kind == AccessKind::Store { PTE_D } else { 0 };
+        if entry.pte & needed != needed {
+            if self.mmu.ad_policy == AdPolicy::Fault {
+                return Err(page_fault);
+            }
+            // the TLB may be older than the page tables: the bits go into
+            // the PTE that is there now, and if that is no longer the same
+            // leaf the access takes a fresh walk
+            let pte = self
+                .memory
+                .read_u32(entry.pte_address)
+                .map_err(|_| access_fault(address, kind))?;
+            if pte & PTE_V == 0 || (pte ^ entry.pte) & !(PTE_A | PTE_D) != 0 {
+                self.mmu.tlb.invalidate(Some(address), None);
+                entry = self.walk(address, kind)?;
+                if !self.permits(entry.pte, kind) {
+                    return Err(page_fault);
+                }
+            } else {
+                entry.pte = pte;
+            }
+            entry.pte |= needed;
+            self.memory
+                .write_u32(entry.pte_address, entry.pte)
+                .map_err(|_| access_fault(address, kind))?;
+        }
+        self.mmu.tlb.insert(entry);
+        Ok((entry.frame * PAGE_SIZE) | (address % PAGE_SIZE))
+    }
+
+    /// finds the leaf of `address` in the page tables
+    fn walk(&self, address: u32, kind: AccessKind) -> Result<TlbEntry, VmError> {
+        let page_fault = VmError::PageFault {
+            pc: 0,
+            instruction: 0,
+            address,
+            kind,
+        };
+        let mut table = u64::from(self.csrs.satp & SATP_PPN) * u64::from(PAGE_SIZE);
+        // a `G` on the way makes the rest global too
+        let mut global = false;
+        for level in [1, 0] {
+            let index = address >> (12 + 10 * level) & 0x3ff;
+            let pte_address = u32::try_from(table + u64::from(index) * 4)
+                .map_err(|_| access_fault(address, kind))?;
+            let pte = self
+                .memory
+                .read_u32(pte_address)
+                .map_err(|_| access_fault(address, kind))?;
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+                return Err(page_fault);
+            }
+            global |= pte & PTE_G != 0;
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = u64::from(ppn) * u64::from(PAGE_SIZE);
+                continue;
+            }
+            // a megapage is aligned, its frames are the ones of the page
+            let frame = if level == 1 {
+                if ppn & 0x3ff != 0 {
+                    return Err(page_fault);
+                }
+                ppn | ((address / PAGE_SIZE) & 0x3ff)
+            } else {
+                ppn
+            };
+            if u64::from(frame) * u64::from(PAGE_SIZE) > u64::from(u32::MAX) {
+                return Err(access_fault(address, kind));
+            }
+            return Ok(TlbEntry {
+                page: address / PAGE_SIZE,
+                frame,
+                pte,
+                pte_address,
+                asid: self.asid(),
+                global,
+                megapage: level == 1,
+            });
+        }
+        Err(page_fault)
+    }
+
+    /// whether the leaf `pte` lets the mode do `kind`
+    fn permits(&self, pte: u32, kind: AccessKind) -> bool {
+        let mstatus = self.csrs.mstatus;
+        let allowed = match kind {
+            AccessKind::Fetch => pte & PTE_X != 0,
+            AccessKind::Load => pte & PTE_R != 0 || mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0,
+            AccessKind::Store => pte & PTE_W != 0,
+        };
+        let mode = match self.csrs.privilege {
+            Privilege::User => pte & PTE_U != 0,
+            // supervisor mode never runs user code, and only touches user
+            // data with `SUM`
+            _ if pte & PTE_U == 0 => true,
+            _ => kind != AccessKind::Fetch && mstatus & MSTATUS_SUM != 0,
+        };
+        allowed && mode
+    }
+
+    /// fetches and decodes the instruction at the virtual `address`
+    pub(super) fn fetch_paged(&mut self, address: u32) -> Result<(u32, Instruction), VmError> {
+        let physical = self
+            .translate(address, AccessKind::Fetch)
+            .map_err(|error| error.at(address, 0))?;
+        let (word, instruction) = self.fetch_word_at(physical)?;
+        Ok(match instruction {
+            Instruction::Rv32iInstruction(_, instruction) => (
+                word,
+                Instruction::Rv32iInstruction(address as i32, instruction),
+            ),
+            pseudo => (word, pseudo),
+        })
+    }
+
+    /// executes a load or store at a virtual address, to the memory or a
+    /// device at the physical one. `None` for the other instructions
+    pub(super) fn execute_paged(
+        &mut self,
+        instruction: &Rv32iInstruction,
+    ) -> Option<Result<MemoryAccess, VmError>> {
+        load_store(instruction).map(|operands| self.access_paged(instruction, operands))
+    }
+
+    fn access_paged(
+        &mut self,
+        instruction: &Rv32iInstruction,
+        (rs1, imm, size, destination, source): (Register, i16, u32, Option<Register>, Register),
+    ) -> Result<MemoryAccess, VmError> {
+        let address = (self.vm_state.registers[rs1] as u32).wrapping_add(imm as i32 as u32);
+        let kind = match destination {
+            Some(_) => AccessKind::Load,
+            None => AccessKind::Store,
+        };
+        if address % PAGE_SIZE + size > PAGE_SIZE {
+            return Err(VmError::MisalignedAccess {
+                pc: 0,
+                instruction: 0,
+                address,
+            });
+        }
+        let address = self.translate(address, kind)?;
+
+        let access = match destination {
+            Some(rd) => {
+                let value = match self.read_device(address, size) {
+                    Some(value) => value,
+                    None => {
+                        let mut word = [0; 4];
+                        word[..size as usize]
+                            .copy_from_slice(self.memory.read_bytes(address, size as usize)?);
+                        u32::from_le_bytes(word)
+                    }
+                };
+                self.vm_state
+                    .registers
+                    .write(rd, extend_load(instruction, value));
+                MemoryAccess::Read {
+                    address,
+                    size,
+                    value,
+                }
+            }
+            None => {
+                let mask = u32::MAX >> (32 - 8 * size);
+                let value = self.vm_state.registers[source] as u32 & mask;
+                if self.write_device(address, size, value).is_none() {
+                    self.memory
+                        .write_bytes(address, &value.to_le_bytes()[..size as usize])?;
+                }
+                MemoryAccess::Write {
+                    address,
+                    size,
+                    value,
+                }
+            }
+        };
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        Ok(access)
+    }
+
+    /// executes `sfence.vma`, returns false for any other instruction. It
+    /// drops the translations of the address in rs1 and the ASID in rs2,
+    /// x0 stands for all of them
+    pub(super) fn execute_sfence(&mut self, instruction: &Instruction) -> bool {
+        let Instruction::Rv32iInstruction(_, Rv32iInstruction::SfenceVma(r)) = instruction else {
+            return false;
+        };
+        let registers = &self.vm_state.registers;
+        let address = (!r.rs1.is_zero()).then(|| registers[r.rs1] as u32);
+        let asid = (!r.rs2.is_zero()).then(|| (registers[r.rs2] as u32 & SATP_ASID_MASK) as u16);
+        self.mmu.tlb.invalidate(address, asid);
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        true
+    }
+}
+
+/// the fault of a walk or an `A`/`D` update that left the memory
+fn access_fault(address: u32, kind: AccessKind) -> VmError {
+    VmError::AccessFault {
+        pc: 0,
+        instruction: 0,
+        address,
+        kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdPolicy, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X, SATP_MODE_SV32};
+    use crate::csr::{Privilege, CSR_SATP};
+    use crate::{AccessKind, Register, StopReason, Vm};
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn user_mode_should_run_through_pages_and_megapages() {
+        // 0x1000 lui t0, 0x80000
+        // 0x1004 addi t0, t0, 0x100
+        // 0x1008 csrrw zero, satp, t0       <- Sv32, root table at 0x10_0000
+        // 0x100c lui t0, 0x40000
+        // 0x1010 csrrw zero, mepc, t0
+        // 0x1014 lui t0, 0x1
+        // 0x1018 addi t0, t0, 0x100
+        // 0x101c csrrw zero, mtvec, t0
+        // 0x1020 lui t0, 0x2
+        // 0x1024 addi t0, t0, -0x800
+        // 0x1028 csrrc zero, mstatus, t0    <- MPP = U
+        // 0x102c mret
+        let setup = words(&[
+            0x8000_02b7,
+            0x1002_8293,
+            0x1802_9073,
+            0x4000_02b7,
+            0x3412_9073,
+            0x0000_12b7,
+            0x1002_8293,
+            0x3052_9073,
+            0x0000_22b7,
+            0x8002_8293,
+            0x3002_b073,
+            0x3020_0073,
+        ]);
+        // 0x1100 csrrs a2, mcause, zero
+        // 0x1104 csrrs a3, mtval, zero
+        // 0x1108 ebreak
+        let handler = words(&[0x3420_2673, 0x3430_26f3, 0x0010_0073]);
+        // at 0x4000_0000, the page at 0x2000
+        // lui t0, 0x40001
+        // addi t1, zero, 42
+        // sw t1, 4(t0)             <- the page at 0x3000
+        // lui t2, 0x80000
+        // sw t1, 0x10(t2)          <- the megapage at 0x40_0000
+        // lw a0, 0x10(t2)
+        // lui t3, 0x40002
+        // lw a1, 0(t3)             <- not mapped
+        let user = words(&[
+            0x4000_12b7,
+            0x02a0_0313,
+            0x0062_a223,
+            0x8000_03b7,
+            0x0063_a823,
+            0x0103_a503,
+            0x4000_2e37,
+            0x000e_2583,
+        ]);
+        let new_vm = |policy| {
+            let mut vm = Vm::new(0, 0x80_0000).with_ad_policy(policy);
+            vm.load_program(0x1000, &setup).unwrap();
+            vm.load_program(0x1100, &handler).unwrap();
+            vm.load_program(0x2000, &user).unwrap();
+            vm.vm_state.pc = 0x1000;
+            // the root table points 0x4000_0000 to the table at 0x10_1000
+            // and maps 0x8000_0000 to a megapage
+            let memory = &mut vm.memory;
+            memory.write_u32(0x10_0400, 0x101 << 10 | PTE_V).unwrap();
+            let megapage = 0x400 << 10 | PTE_R | PTE_W | PTE_U | PTE_V | PTE_A | PTE_D;
+            memory.write_u32(0x10_0800, megapage).unwrap();
+            let code = 0x2 << 10 | PTE_R | PTE_X | PTE_U | PTE_V | PTE_A;
+            memory.write_u32(0x10_1000, code).unwrap();
+            memory
+                .write_u32(0x10_1004, 0x3 << 10 | PTE_R | PTE_W | PTE_U | PTE_V)
+                .unwrap();
+            vm
+        };
+        let registers = |vm: &Vm| -> Vec<u32> {
+            vm.vm_state.registers.as_array()[10..14]
+                .iter()
+                .map(|register| *register as u32)
+                .collect()
+        };
+
+        // the walk sets A and D on the data page, the load from the hole
+        // is a load page fault
+        let mut vm = new_vm(AdPolicy::Update);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(registers(&vm), [42, 0, 13, 0x4000_2000]);
+        assert_eq!(vm.memory.read_u32(0x3004), Ok(42));
+        assert_eq!(vm.memory.read_u32(0x40_0010), Ok(42));
+        let data = vm.memory.read_u32(0x10_1004).unwrap();
+        assert_eq!(data & (PTE_A | PTE_D), PTE_A | PTE_D);
+        // 8 fetches and 4 accesses, the megapage and the code page take
+        // turns in the same slot
+        let stats = vm.tlb().stats;
+        assert_eq!((stats.hits, stats.misses), (5, 7));
+        assert!(vm.summary().contains("tlb: 64 entries"));
+
+        // the guest sets them itself, the first store faults
+        let mut vm = new_vm(AdPolicy::Fault);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(registers(&vm), [0, 0, 15, 0x4000_1004]);
+        assert_eq!(vm.csrs.mepc, 0x4000_0008);
+    }
+
+    #[test]
+    fn sfence_vma_should_drop_the_translations_of_its_address_and_asid() {
+        // at 0x4000_2000, the page at 0x1000
+        // sfence.vma a0, zero
+        // sfence.vma zero, a1
+        let mut vm = Vm::new(0, 0x20_0000);
+        vm.load_program(0x1000, &words(&[0x1205_0073, 0x12b0_0073]))
+            .unwrap();
+        let data = PTE_R | PTE_W | PTE_V | PTE_A | PTE_D;
+        let memory = &mut vm.memory;
+        memory.write_u32(0x10_0400, 0x101 << 10 | PTE_V).unwrap();
+        memory.write_u32(0x10_1000, 0x2 << 10 | data).unwrap();
+        memory.write_u32(0x10_1004, 0x3 << 10 | data).unwrap();
+        let code = 0x1 << 10 | PTE_R | PTE_X | PTE_V | PTE_A;
+        memory.write_u32(0x10_1008, code).unwrap();
+        let satp = |asid: u32| SATP_MODE_SV32 | asid << 22 | 0x100;
+        vm.csrs.privilege = Privilege::Supervisor;
+        vm.write_csr(CSR_SATP, satp(1));
+        vm.vm_state.pc = 0x4000_2000;
+
+        let translate = |vm: &mut Vm| {
+            [0x4000_0000, 0x4000_1000]
+                .map(|address| vm.translate(address, AccessKind::Load).unwrap())
+        };
+        assert_eq!(translate(&mut vm), [0x2000, 0x3000]);
+        // the tables change, the TLB still has the old frames, also after
+        // a switch to another ASID and back
+        vm.memory.write_u32(0x10_1000, 0x5 << 10 | data).unwrap();
+        vm.memory.write_u32(0x10_1004, 0x6 << 10 | data).unwrap();
+        vm.write_csr(CSR_SATP, satp(2));
+        vm.write_csr(CSR_SATP, satp(1));
+        assert_eq!(translate(&mut vm), [0x2000, 0x3000]);
+
+        vm.vm_state.registers.write(Register::A0, 0x4000_0000);
+        vm.step().unwrap();
+        assert_eq!(translate(&mut vm), [0x5000, 0x3000]);
+        vm.vm_state.registers.write(Register::A1, 1);
+        vm.step().unwrap();
+        assert_eq!(translate(&mut vm), [0x5000, 0x6000]);
+        assert_eq!(vm.vm_state.pc, 0x4000_2008);
+    }
+
+    #[test]
+    fn the_d_update_should_go_into_the_pte_in_memory() {
+        let mut vm = Vm::new(0, 0x20_0000);
+        let data = PTE_R | PTE_W | PTE_V;
+        let memory = &mut vm.memory;
+        memory.write_u32(0x10_0400, 0x101 << 10 | PTE_V).unwrap();
+        memory.write_u32(0x10_1000, 0x2 << 10 | data).unwrap();
+        vm.csrs.privilege = Privilege::Supervisor;
+        vm.write_csr(CSR_SATP, SATP_MODE_SV32 | 0x100);
+
+        // a load caches the leaf with only A
+        assert_eq!(vm.translate(0x4000_0000, AccessKind::Load), Ok(0x2000));
+        assert_eq!(vm.memory.read_u32(0x10_1000), Ok(0x2 << 10 | data | PTE_A));
+        // the guest sets a bit of its own in the PTE, the store keeps it
+        let software = 1 << 8;
+        let pte = 0x2 << 10 | data | PTE_A | software;
+        vm.memory.write_u32(0x10_1000, pte).unwrap();
+        assert_eq!(vm.translate(0x4000_0004, AccessKind::Store), Ok(0x2004));
+        assert_eq!(vm.memory.read_u32(0x10_1000), Ok(pte | PTE_D));
+
+        // it maps another page elsewhere without an sfence.vma, a store
+        // walks again instead of writing the old leaf back
+        vm.memory.write_u32(0x10_1004, 0x3 << 10 | data).unwrap();
+        assert_eq!(vm.translate(0x4000_1000, AccessKind::Load), Ok(0x3000));
+        vm.memory.write_u32(0x10_1004, 0x5 << 10 | data).unwrap();
+        assert_eq!(vm.translate(0x4000_1000, AccessKind::Load), Ok(0x3000));
+        assert_eq!(vm.translate(0x4000_1000, AccessKind::Store), Ok(0x5000));
+        let pte = 0x5 << 10 | data | PTE_A | PTE_D;
+        assert_eq!(vm.memory.read_u32(0x10_1004), Ok(pte));
+    }
+}
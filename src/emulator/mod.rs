@@ -1,6 +1,103 @@
+pub mod atomic;
+pub mod backing;
+#[cfg(feature = "batch")]
+pub mod batch;
+pub mod block_cache;
+pub mod branch_predictor;
+pub mod breakpoints;
+pub mod cache;
+pub mod call_stack;
+pub mod clint;
+pub mod control_flow;
+pub mod cooperative;
+pub mod coverage;
+pub mod csr;
+pub mod debug_line;
+pub mod decode_cache;
+pub mod decompile;
+#[cfg(feature = "differential")]
+pub mod differential;
+pub mod disassemble;
+pub mod disk_image;
+pub mod dispatch;
+pub mod dtb;
+pub mod ecall;
+pub mod elf;
+#[allow(clippy::module_inception)]
 mod emulator;
+pub mod encode;
+mod error;
+pub mod events;
+mod ext2;
+pub mod extensions;
+pub mod flame_graph;
+pub mod fork;
+pub mod framebuffer;
+pub mod fs;
+pub mod gas;
+pub mod hooks;
+pub mod hostcall;
+pub mod hpm;
+pub mod htif;
+pub mod image;
+pub mod input;
 pub mod instruction_formats;
-mod instruction_signatures;
+pub mod instruction_signatures;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod limits;
+pub mod memory;
+pub mod mmio;
+pub mod mmu;
+pub mod monitor;
+pub mod net;
+pub mod payload;
+pub mod pipeline;
+pub mod plic;
+pub mod plugin;
+pub mod pod;
+mod prelude;
+pub mod process;
+pub mod profile;
+pub mod profiler;
+pub mod quiz;
+pub mod region;
+pub mod register;
+pub mod replay;
 mod rv32i;
+pub mod sbi;
+pub mod semihosting;
+pub mod smp;
+pub mod snapshot;
+pub mod stack_limit;
+pub mod strace;
+pub mod summary;
+#[cfg(feature = "symbolic")]
+pub mod symbolic;
+pub mod syscalls;
+pub mod taint;
+pub mod terminal;
+pub mod timing;
+pub mod tlb;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod uart;
+pub mod uninit;
+#[cfg(feature = "std")]
+mod user_net;
+pub mod vector;
+pub mod virtio;
+#[cfg(feature = "std")]
+pub mod virtio_blk;
+pub mod virtio_net;
+pub mod wfi;
+#[cfg(feature = "worker")]
+pub mod worker;
 
-pub use emulator::Emulator;
+pub use emulator::{Emulator, ExitReason, Instruction, PseudoInstruction, StopReason, Vm, VmState};
+pub use error::{AccessKind, VmError};
+pub use register::{Register, RegisterFile};
+pub use rv32i::Rv32iInstruction;
@@ -0,0 +1,288 @@
+//! A monitor console like QEMU's: one command per line to look at and change
+//! the registers and the memory, disassemble, set breakpoints and run.
+//!
+//! `Monitor::execute` takes a line and returns the reply, so any transport
+//! works: `serve` reads lines from stdin natively, under wasm the frontend
+//! posts each line as a message and posts the reply back. An empty line
+//! repeats the last command, like GDB, which makes stepping quick.
+//!
+//! Numbers are decimal or `0x` hex; anywhere an address goes a symbol name or
+//! `pc` works too.
+
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+
+use thiserror::Error;
+
+use super::emulator::Vm;
+use super::error::VmError;
+use super::prelude::*;
+use super::register::Register;
+
+const HELP: &str = "\
+info registers (r)        show the registers
+set <reg|pc> <value>      change a register
+x <address> [count]       show count words of memory
+w <address> <value>       write a word to memory
+dis [address] [count]     disassemble, from the pc by default
+break <address> (b)       stop before the instruction at address
+delete <address> (d)      remove a breakpoint
+info breakpoints          list the breakpoints
+step [count] (s)          execute count instructions
+continue (c)              run until the guest stops
+quit (q)                  leave the monitor
+";
+
+/// how many instructions `dis` shows without a count
+const DISASSEMBLE_COUNT: usize = 8;
+/// how many words `x` shows without a count
+const DUMP_COUNT: u32 = 4;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MonitorError {
+    #[error("unknown command {0:?}, try help")]
+    UnknownCommand(String),
+
+    #[error("missing argument, try help")]
+    MissingArgument,
+
+    #[error("{0:?} is no number, register or symbol")]
+    InvalidValue(String),
+
+    #[error("no breakpoint at {0:#010x}")]
+    NoBreakpoint(u32),
+
+    #[error(transparent)]
+    Vm(#[from] VmError),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Monitor {
+    /// the user asked to leave with `quit`
+    pub quit: bool,
+    /// what an empty line repeats
+    last: String,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// runs the command on `line`, returns what to show
+    pub fn execute(&mut self, vm: &mut Vm, line: &str) -> Result<String, MonitorError> {
+        let line = if line.trim().is_empty() {
+            self.last.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last.clone_from(&line);
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |index: usize| words.get(index).copied();
+        let value = |index: usize| match argument(index) {
+            Some(word) => parse_value(vm, word).map(Some),
+            None => Ok(None),
+        };
+        let required = |index: usize| value(index)?.ok_or(MonitorError::MissingArgument);
+
+        let Some(&command) = words.first() else {
+            return Ok(String::new());
+        };
+        match (command, argument(1)) {
+            ("help" | "h", _) => Ok(HELP.to_string()),
+            ("info", Some("registers")) | ("r", _) => Ok(registers(vm)),
+            ("info", Some("breakpoints")) => Ok(vm
+                .breakpoints
+                .breakpoints()
+                .map(|pc| format!("{pc:#010x}\n"))
+                .collect()),
+            ("set", Some(name)) => {
+                let value = required(2)?;
+                if name == "pc" {
+                    vm.vm_state.pc = value as i32;
+                } else {
+                    let register = Register::from_name(name)
+                        .ok_or_else(|| MonitorError::InvalidValue(name.to_string()))?;
+                    vm.vm_state.registers.write(register, value as i32);
+                }
+                Ok(String::new())
+            }
+            ("x", _) => {
+                let address = required(1)?;
+                let count = value(2)?.unwrap_or(DUMP_COUNT);
+                let mut lines = String::new();
+                for index in 0..count {
+                    let address = address.wrapping_add(index * 4);
+                    let word = vm.memory.read_u32(address)?;
+                    lines.push_str(&format!("{address:#010x}: {word:#010x}\n"));
+                }
+                Ok(lines)
+            }
+            ("w", _) => {
+                let (address, word) = (required(1)?, required(2)?);
+                vm.memory.write_u32(address, word)?;
+                vm.invalidate_decode_cache();
+                Ok(String::new())
+            }
+            ("dis", _) => {
+                let address = value(1)?.unwrap_or(vm.vm_state.pc as u32);
+                let count = value(2)?.map_or(DISASSEMBLE_COUNT, |count| count as usize);
+                Ok(vm.disassemble(address, count)?)
+            }
+            ("break" | "b", _) => {
+                let address = required(1)?;
+                vm.breakpoints.add_breakpoint(address);
+                Ok(format!("breakpoint at {address:#010x}\n"))
+            }
+            ("delete" | "d", _) => {
+                let address = required(1)?;
+                if !vm.breakpoints.remove_breakpoint(address) {
+                    return Err(MonitorError::NoBreakpoint(address));
+                }
+                Ok(String::new())
+            }
+            ("step" | "s", _) => {
+                for _ in 0..value(1)?.unwrap_or(1) {
+                    vm.step()?;
+                }
+                Ok(vm.disassemble(vm.vm_state.pc as u32, 1)?)
+            }
+            ("continue" | "c", _) => {
+                let reason = vm.run()?;
+                Ok(format!(
+                    "{reason:?}\n{}",
+                    vm.disassemble(vm.vm_state.pc as u32, 1)?
+                ))
+            }
+            ("quit" | "q", _) => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(MonitorError::UnknownCommand(line)),
+        }
+    }
+
+    /// reads commands from `input` and writes the replies to `output` until
+    /// `quit` or the end of the input, e.g. with stdin and stdout
+    #[cfg(feature = "std")]
+    pub fn serve(
+        &mut self,
+        vm: &mut Vm,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        write!(output, "(monitor) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.execute(vm, &line?) {
+                Ok(reply) => write!(output, "{reply}")?,
+                Err(error) => writeln!(output, "error: {error}")?,
+            }
+            if self.quit {
+                break;
+            }
+            write!(output, "(monitor) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// a number, a register, `pc` or a symbol
+fn parse_value(vm: &Vm, word: &str) -> Result<u32, MonitorError> {
+    let number = match word.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => word.parse::<u32>().ok(),
+    };
+    number
+        .or_else(|| (word == "pc").then_some(vm.vm_state.pc as u32))
+        .or_else(|| {
+            Register::from_name(word).map(|register| vm.vm_state.registers[register] as u32)
+        })
+        .or_else(|| vm.address_of(word))
+        .ok_or_else(|| MonitorError::InvalidValue(word.to_string()))
+}
+
+/// the pc and the registers, four to a line
+fn registers(vm: &Vm) -> String {
+    let mut lines = format!("pc   {:#010x}\n", vm.vm_state.pc as u32);
+    for register in Register::all() {
+        let value = vm.vm_state.registers[register] as u32;
+        lines.push_str(&format!("{:<4} {value:#010x}", register.abi_name()));
+        lines.push(if register.index() % 4 == 3 { '\n' } else { ' ' });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Monitor, MonitorError};
+    use crate::{Register, Vm};
+
+    #[test]
+    fn should_inspect_and_run_the_guest() {
+        // 0x1000 addi a0, zero, 5
+        // 0x1004 addi a0, a0, 1
+        // 0x1008 ebreak
+        let program: Vec<u8> = [0x0050_0513u32, 0x0015_0513, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let mut monitor = Monitor::new();
+
+        let reply = monitor.execute(&mut vm, "dis pc 2").unwrap();
+        assert_eq!(
+            reply,
+            "0x00001000: addi a0, zero, 5\n0x00001004: addi a0, a0, 1\n"
+        );
+        assert_eq!(
+            monitor.execute(&mut vm, "break 0x1004").unwrap(),
+            "breakpoint at 0x00001004\n"
+        );
+        let reply = monitor.execute(&mut vm, "c").unwrap();
+        assert!(reply.starts_with("Breakpoint { pc: 4100 }"));
+        assert_eq!(vm.vm_state.registers[Register::A0], 5);
+
+        monitor.execute(&mut vm, "set a0 0x10").unwrap();
+        monitor.execute(&mut vm, "s").unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], 0x11);
+        assert!(monitor
+            .execute(&mut vm, "r")
+            .unwrap()
+            .contains("a0   0x00000011"));
+
+        monitor.execute(&mut vm, "w 0x1080 0xcafe").unwrap();
+        assert_eq!(
+            monitor.execute(&mut vm, "x 0x1080 1").unwrap(),
+            "0x00001080: 0x0000cafe\n"
+        );
+        assert_eq!(
+            monitor.execute(&mut vm, "frobnicate"),
+            Err(MonitorError::UnknownCommand("frobnicate".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_serve_a_session_and_repeat_empty_lines() {
+        // 0x1000 addi a0, a0, 1
+        // 0x1004 addi a0, a0, 1
+        let program: Vec<u8> = [0x0015_0513u32, 0x0015_0513]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let mut output = Vec::new();
+
+        let input = "step\n\nx nowhere\nquit\nstep\n";
+        Monitor::new()
+            .serve(&mut vm, input.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(vm.vm_state.registers[Register::A0], 2);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("error: \"nowhere\" is no number, register or symbol"));
+    }
+}
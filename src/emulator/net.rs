@@ -0,0 +1,41 @@
+//! Network backends for `virtio_net.rs`: where the guest's Ethernet frames
+//! go and where the frames for it come from.
+//!
+//! `FrameQueue` just keeps the frames, for frontends that move them
+//! somewhere themselves (a WebSocket in the web build). `UserNet` of
+//! `user_net.rs` is a NAT to host sockets, it needs std.
+
+use core::any::Any;
+
+use alloc::collections::VecDeque;
+
+use super::prelude::*;
+#[cfg(feature = "std")]
+pub use super::user_net::{UserNet, GATEWAY_ADDRESS, GATEWAY_MAC, GUEST_ADDRESS};
+
+/// where the guest's frames go
+pub trait NetBackend: Any {
+    /// a frame from the guest
+    fn send(&mut self, frame: &[u8]);
+    /// the next frame for the guest, if there is one
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// keeps the frames for the frontend to move
+#[derive(Debug, Clone, Default)]
+pub struct FrameQueue {
+    /// what the guest sent
+    pub outgoing: VecDeque<Vec<u8>>,
+    /// what the guest receives next
+    pub incoming: VecDeque<Vec<u8>>,
+}
+
+impl NetBackend for FrameQueue {
+    fn send(&mut self, frame: &[u8]) {
+        self.outgoing.push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.incoming.pop_front()
+    }
+}
@@ -0,0 +1,240 @@
+//! Everything a machine boots with, loaded in one go: firmware, a kernel,
+//! an initial ramdisk and a device tree, each at its own address like a
+//! board's boot ROM would leave them.
+//!
+//! ```text
+//! let payload = Payload::new()
+//!     .with_elf(firmware)
+//!     .with_blob(0x8020_0000, kernel)
+//!     .with_initrd(0x8400_0000, initrd)
+//!     .with_device_tree(0x8700_0000, DeviceTree::virt(0x8000_0000, 0x0800_0000));
+//! vm.load_payload(&payload)?;
+//! ```
+//!
+//! The vm starts at the entry point of the first image unless `with_entry`
+//! says otherwise, with a0 and a1 set for the kernel (see `dtb.rs`). Images
+//! that overlap are an error, not a silent overwrite.
+
+use thiserror::Error;
+
+use super::dtb::DeviceTree;
+use super::elf::{Elf, ElfError};
+use super::emulator::Vm;
+use super::error::VmError;
+use super::image::Image;
+use super::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum PayloadError {
+    #[error("the {second} at {address:#x} overlaps the {first}")]
+    Overlap {
+        first: &'static str,
+        second: &'static str,
+        address: u32,
+    },
+
+    #[error(transparent)]
+    Elf(#[from] ElfError),
+
+    #[error(transparent)]
+    Memory(#[from] VmError),
+}
+
+/// one part of the payload
+#[derive(Debug, Clone)]
+enum Part {
+    Elf(Vec<u8>),
+    Image(Image),
+    Initrd(u32, Vec<u8>),
+    DeviceTree(u32, DeviceTree),
+}
+
+impl Part {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Elf(_) => "ELF file",
+            Self::Image(_) => "image",
+            Self::Initrd(..) => "initrd",
+            Self::DeviceTree(..) => "device tree",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Payload {
+    parts: Vec<Part>,
+    entry: Option<u32>,
+}
+
+impl Payload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// an ELF file, where it was linked for
+    pub fn with_elf(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.parts.push(Part::Elf(bytes.into()));
+        self
+    }
+
+    pub fn with_image(mut self, image: Image) -> Self {
+        self.parts.push(Part::Image(image));
+        self
+    }
+
+    /// a flat binary at `address`, like a kernel `Image`
+    pub fn with_blob(self, address: u32, bytes: &[u8]) -> Self {
+        self.with_image(Image::from_bin(bytes, address))
+    }
+
+    /// a ramdisk at `address`, the device tree tells the kernel where
+    pub fn with_initrd(mut self, address: u32, bytes: impl Into<Vec<u8>>) -> Self {
+        self.parts.push(Part::Initrd(address, bytes.into()));
+        self
+    }
+
+    /// `tree` at `address`, a1 points at it
+    pub fn with_device_tree(mut self, address: u32, tree: DeviceTree) -> Self {
+        self.parts.push(Part::DeviceTree(address, tree));
+        self
+    }
+
+    /// where the vm starts, instead of the entry point of the first image
+    pub fn with_entry(mut self, entry: u32) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+}
+
+impl Vm {
+    /// loads every part of `payload` and moves the pc to its entry point.
+    /// The symbols of all the ELF files are kept. Returns the entry point
+    pub fn load_payload(&mut self, payload: &Payload) -> Result<u32, PayloadError> {
+        let initrd = payload.parts.iter().find_map(|part| match part {
+            Part::Initrd(address, bytes) => {
+                Some((*address, address.wrapping_add(bytes.len() as u32)))
+            }
+            _ => None,
+        });
+
+        // nothing is loaded before every part is known to fit
+        let mut spans: Vec<(u64, u64, &'static str)> = Vec::new();
+        for part in &payload.parts {
+            let blocks: Vec<(u32, usize)> = match part {
+                Part::Elf(bytes) => Elf::parse(bytes)?
+                    .segments
+                    .iter()
+                    .map(|segment| (segment.address, segment.memory_size as usize))
+                    .collect(),
+                Part::Image(image) => image
+                    .blocks
+                    .iter()
+                    .map(|(address, data)| (*address, data.len()))
+                    .collect(),
+                Part::Initrd(address, bytes) => vec![(*address, bytes.len())],
+                Part::DeviceTree(address, tree) => vec![(*address, tree.to_bytes().len())],
+            };
+            for (address, size) in blocks {
+                let (start, end) = (u64::from(address), u64::from(address) + size as u64);
+                if let Some((_, _, first)) = spans
+                    .iter()
+                    .find(|(other_start, other_end, _)| start < *other_end && *other_start < end)
+                {
+                    return Err(PayloadError::Overlap {
+                        first,
+                        second: part.name(),
+                        address,
+                    });
+                }
+                spans.push((start, end, part.name()));
+            }
+        }
+
+        let mut symbols = Vec::new();
+        let mut entry = payload.entry;
+        for part in &payload.parts {
+            let part_entry = match part {
+                Part::Elf(bytes) => {
+                    let part_entry = self.load_elf(bytes)?;
+                    symbols.append(&mut self.symbols);
+                    Some(part_entry)
+                }
+                Part::Image(image) => Some(self.load_image(image)?),
+                Part::Initrd(address, bytes) => {
+                    self.memory.load(*address, bytes)?;
+                    None
+                }
+                Part::DeviceTree(address, tree) => {
+                    let tree = DeviceTree {
+                        initrd: tree.initrd.or(initrd),
+                        ..tree.clone()
+                    };
+                    self.load_device_tree_at(*address, &tree)?;
+                    None
+                }
+            };
+            entry = entry.or(part_entry);
+        }
+
+        let entry = entry.unwrap_or(self.vm_state.pc as u32);
+        self.vm_state.pc = entry as i32;
+        self.symbols = symbols;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Payload, PayloadError};
+    use crate::dtb::DeviceTree;
+    use crate::elf::test_elf;
+    use crate::memory::MemoryMap;
+    use crate::{Register, Vm};
+
+    #[test]
+    fn should_load_every_image_and_start_at_the_firmware() {
+        // 0x1000 lui t0, 0x80200      <- the firmware jumps to the kernel
+        // 0x1004 jalr zero, 0(t0)
+        let firmware = test_elf::build(
+            0x1000,
+            &[0x8020_02b7, 0x0002_8067],
+            0,
+            &[("_start", 0x1000)],
+        );
+        // 0x80200000 lw a0, 0(a1)      <- the kernel reads the tree's magic
+        // 0x80200004 ebreak
+        let kernel: Vec<u8> = [0x0005_a503u32, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let tree = DeviceTree::virt(0x8000_0000, 0x0100_0000);
+        let payload = Payload::new()
+            .with_elf(firmware)
+            .with_blob(0x8020_0000, &kernel)
+            .with_initrd(0x8040_0000, *b"initrd")
+            .with_device_tree(0x8080_0000, tree);
+
+        let map = MemoryMap::new()
+            .ram(0x8000_0000, 0x0100_0000)
+            .rom(0x1000, 0x100);
+        let mut vm = Vm::from_memory_map(&map);
+        assert_eq!(vm.load_payload(&payload), Ok(0x1000));
+        assert_eq!(vm.symbol("_start").unwrap().address, 0x1000);
+        assert_eq!(vm.vm_state.registers[Register::A1], 0x8080_0000u32 as i32);
+        assert_eq!(vm.memory.read_bytes(0x8040_0000, 6).unwrap(), b"initrd");
+
+        vm.run().unwrap();
+        assert_eq!(vm.vm_state.pc, 0x8020_0004u32 as i32);
+        assert_eq!(vm.vm_state.registers[Register::A0], 0xedfe_0dd0u32 as i32);
+
+        let payload = payload.with_initrd(0x8020_0004, *b"oops");
+        assert_eq!(
+            vm.load_payload(&payload),
+            Err(PayloadError::Overlap {
+                first: "image",
+                second: "initrd",
+                address: 0x8020_0004,
+            })
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! The classic 5-stage pipeline (IF, ID, EX, MEM, WB) for teaching: it
+//! takes a window of retired instructions and works out in which cycle each
+//! one is in which stage, with the stalls the hazards cost. The vm itself
+//! does not pipeline, this is a model of the instructions it ran.
+//!
+//! - A register an instruction reads while an older one is still computing
+//!   it is a data hazard. With forwarding the value goes from the end of EX
+//!   (or MEM for a load) straight to the EX of the reader, so only a load
+//!   followed by its use stalls. Without it the reader waits in ID until the
+//!   writer's WB (the register file is written in the first half of the
+//!   cycle and read in the second).
+//! - Branches are predicted not taken and resolved in EX. A taken branch or
+//!   a jump flushes the two instructions fetched after it, the next one is
+//!   fetched in the cycle after its EX.
+//!
+//! The diagram serializes to JSON for the web UI.
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt;
+
+use serde::Serialize;
+
+use super::emulator::{Instruction, PseudoInstruction, StopReason, Vm, VmState};
+use super::error::VmError;
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// an instruction as the pipeline sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetiredInstruction {
+    pub pc: u32,
+    pub text: String,
+    /// the registers it reads
+    pub sources: Vec<Register>,
+    pub destination: Option<Register>,
+    /// its result is only there after MEM
+    pub is_load: bool,
+    /// it was a taken branch, a jump or a trap, the next instruction is not
+    /// the one after it
+    pub redirect: bool,
+}
+
+impl RetiredInstruction {
+    /// `instruction` at `pc`, `next_pc` is where the vm went after it
+    pub fn new(instruction: &Instruction, next_pc: u32) -> Self {
+        let pc = instruction.address() as u32;
+        let (sources, is_load) = sources(instruction);
+        Self {
+            pc,
+            text: instruction.to_string(),
+            sources: sources.into_iter().filter(|rs| !rs.is_zero()).collect(),
+            destination: instruction.destination().filter(|rd| !rd.is_zero()),
+            is_load,
+            redirect: next_pc != pc.wrapping_add(4),
+        }
+    }
+}
+
+/// the registers `instruction` reads and whether it is a load
+fn sources(instruction: &Instruction) -> (Vec<Register>, bool) {
+    use Rv32iInstruction::*;
+
+    let instruction = match instruction {
+        Instruction::Rv32iInstruction(_, instruction) => instruction,
+        Instruction::PseudoInstruction(_, PseudoInstruction::Ret) => {
+            return (vec![Register::RA], false)
+        }
+        Instruction::PseudoInstruction(_, PseudoInstruction::Li(_)) => return (Vec::new(), false),
+    };
+    match instruction {
+        Add(r) | Sub(r) | Xor(r) | Or(r) | And(r) | Sll(r) | Srl(r) | Sra(r) | Slt(r) | Sltu(r)
+        | ScW(r) | AmoswapW(r) | AmoaddW(r) | AmoxorW(r) | AmoandW(r) | AmoorW(r) | AmominW(r)
+        | AmomaxW(r) | AmominuW(r) | AmomaxuW(r) => (vec![r.rs1, r.rs2], false),
+        LrW(r) => (vec![r.rs1], true),
+        Lb(i) | Lh(i) | Lw(i) | Lbu(i) | Lhu(i) => (vec![i.rs1], true),
+        Addi(i) | Xori(i) | Ori(i) | Andi(i) | Slli(i) | Srli(i) | Srai(i) | Slti(i) | Sltiu(i)
+        | Jalr(i) | Csrrw(i) | Csrrs(i) | Csrrc(i) => (vec![i.rs1], false),
+        Sb(s) | Sh(s) | Sw(s) | Beq(s) | Bne(s) | Blt(s) | Bge(s) | Bltu(s) | Bgeu(s) => {
+            (vec![s.rs1, s.rs2], false)
+        }
+        _ => (Vec::new(), false),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Stage {
+    #[serde(rename = "IF")]
+    Fetch,
+    #[serde(rename = "ID")]
+    Decode,
+    #[serde(rename = "EX")]
+    Execute,
+    #[serde(rename = "MEM")]
+    Memory,
+    #[serde(rename = "WB")]
+    WriteBack,
+    /// held in the stage before, a bubble goes down the pipeline instead
+    #[serde(rename = "stall")]
+    Stall,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fetch => "IF",
+            Self::Decode => "ID",
+            Self::Execute => "EX",
+            Self::Memory => "MEM",
+            Self::WriteBack => "WB",
+            Self::Stall => "--",
+        })
+    }
+}
+
+/// why an instruction waited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Hazard {
+    /// it reads `register` the instruction at `producer` writes
+    Data {
+        register: String,
+        producer: u32,
+        forwarded: bool,
+    },
+    /// the taken branch or jump at `branch` flushed what came after it
+    Control { branch: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PipelineRow {
+    pub pc: u32,
+    pub text: String,
+    /// the cycle of the IF, the first is 1
+    pub first_cycle: u64,
+    /// one stage per cycle from `first_cycle` on
+    pub stages: Vec<Stage>,
+    pub hazards: Vec<Hazard>,
+}
+
+/// the stage of every instruction in every cycle, rows in program order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PipelineDiagram {
+    pub forwarding: bool,
+    /// until the last WB
+    pub cycles: u64,
+    pub rows: Vec<PipelineRow>,
+}
+
+/// the cycles one instruction enters ID and EX in, the rest follow
+#[derive(Debug, Clone, Copy, Default)]
+struct Timing {
+    decode: u64,
+    execute: u64,
+}
+
+impl Timing {
+    fn memory(&self) -> u64 {
+        self.execute + 1
+    }
+
+    fn write_back(&self) -> u64 {
+        self.execute + 2
+    }
+}
+
+impl PipelineDiagram {
+    pub fn simulate(instructions: &[RetiredInstruction], forwarding: bool) -> Self {
+        let mut timings: Vec<Timing> = Vec::new();
+        let mut rows = Vec::new();
+        for (index, instruction) in instructions.iter().enumerate() {
+            let mut hazards = Vec::new();
+            let previous = timings.last().copied();
+            // IF frees up when the one before moves on to ID, ID when it
+            // moves on to EX
+            let mut fetch = previous.map_or(1, |previous| previous.decode);
+            if let Some(branch) = index
+                .checked_sub(1)
+                .map(|previous| &instructions[previous])
+                .filter(|previous| previous.redirect)
+            {
+                let resolved = previous.map_or(0, |previous| previous.execute);
+                fetch = fetch.max(resolved + 1);
+                hazards.push(Hazard::Control { branch: branch.pc });
+            }
+            let decode = (fetch + 1).max(previous.map_or(0, |previous| previous.execute));
+            let mut execute = decode + 1;
+
+            for register in &instruction.sources {
+                let Some(producer) = instructions[..index]
+                    .iter()
+                    .rposition(|older| older.destination == Some(*register))
+                else {
+                    continue;
+                };
+                let timing = timings[producer];
+                // from the register file only
+                let read = timing.write_back() + 1;
+                if read <= decode + 1 {
+                    continue;
+                }
+                let ready = match (forwarding, instructions[producer].is_load) {
+                    (true, true) => timing.memory() + 1,
+                    (true, false) => timing.execute + 1,
+                    (false, _) => read,
+                };
+                execute = execute.max(ready);
+                hazards.push(Hazard::Data {
+                    register: register.abi_name().to_string(),
+                    producer: instructions[producer].pc,
+                    forwarded: forwarding,
+                });
+            }
+
+            timings.push(Timing { decode, execute });
+
+            let mut stages = vec![Stage::Fetch];
+            stages.extend((fetch + 1..decode).map(|_| Stage::Stall));
+            stages.push(Stage::Decode);
+            stages.extend((decode + 1..execute).map(|_| Stage::Stall));
+            stages.extend([Stage::Execute, Stage::Memory, Stage::WriteBack]);
+            rows.push(PipelineRow {
+                pc: instruction.pc,
+                text: instruction.text.clone(),
+                first_cycle: fetch,
+                stages,
+                hazards,
+            });
+        }
+
+        Self {
+            forwarding,
+            cycles: timings.last().map_or(0, Timing::write_back),
+            rows,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("the diagram only has plain fields")
+    }
+}
+
+/// ```text
+/// cycle                          1   2   3   4   5   6
+/// 0x00001000 lui t0, 0x1         IF  ID  EX  MEM WB
+/// 0x00001004 lw a0, 64(t0)           IF  ID  EX  MEM WB
+/// ```
+impl fmt::Display for PipelineDiagram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut header = format!("{:<31}", "cycle");
+        for cycle in 1..=self.cycles {
+            header.push_str(&format!("{cycle:<4}"));
+        }
+        writeln!(f, "{}", header.trim_end())?;
+        for row in &self.rows {
+            let mut line = format!("{:#010x} {:<20}", row.pc, row.text);
+            line.push_str(&"    ".repeat(row.first_cycle as usize - 1));
+            for stage in &row.stages {
+                line.push_str(&format!("{:<4}", stage.to_string()));
+            }
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+struct PipelineRecorder(Rc<RefCell<Vec<RetiredInstruction>>>);
+
+impl VmHooks for PipelineRecorder {
+    fn after_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        self.0
+            .borrow_mut()
+            .push(RetiredInstruction::new(instruction, vm_state.pc as u32));
+    }
+}
+
+impl Vm {
+    /// runs up to `instructions` instructions like `run_for` and returns
+    /// the pipeline diagram of the ones that retired, also when the run
+    /// ends with an error
+    pub fn run_pipeline(
+        &mut self,
+        instructions: u64,
+        forwarding: bool,
+    ) -> (Result<StopReason, VmError>, PipelineDiagram) {
+        let retired = Rc::new(RefCell::new(Vec::new()));
+        self.add_hooks(Box::new(PipelineRecorder(retired.clone())));
+        let result = self.run_for(instructions);
+        self.hooks.pop();
+        let diagram = PipelineDiagram::simulate(&retired.take(), forwarding);
+        (result, diagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hazard, PipelineDiagram};
+    use crate::{StopReason, Vm};
+
+    /// the diagram of the first `instructions` of `program`
+    fn pipeline(program: &[u32], instructions: u64, forwarding: bool) -> PipelineDiagram {
+        let program: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let (result, diagram) = vm.run_pipeline(instructions, forwarding);
+        assert!(matches!(result, Ok(StopReason::Preempted { .. })));
+        diagram
+    }
+
+    /// the first cycle and the stages of every row
+    fn stages(diagram: &PipelineDiagram) -> Vec<(u64, String)> {
+        diagram
+            .rows
+            .iter()
+            .map(|row| {
+                let stages: Vec<String> = row.stages.iter().map(ToString::to_string).collect();
+                (row.first_cycle, stages.join(" "))
+            })
+            .collect()
+    }
+
+    /// an addi that reads the result of the addi right before it
+    const RAW: [u32; 2] = [
+        // 0x1000 addi a0, zero, 1
+        0x0010_0513,
+        // 0x1004 addi a1, a0, 2
+        0x0025_0593,
+    ];
+
+    #[test]
+    fn forwarding_should_hide_a_raw_hazard_on_an_alu_result() {
+        let diagram = pipeline(&RAW, 2, true);
+        assert_eq!(
+            stages(&diagram),
+            [
+                (1, "IF ID EX MEM WB".to_string()),
+                (2, "IF ID EX MEM WB".to_string()),
+            ]
+        );
+        assert_eq!(diagram.cycles, 6);
+        assert_eq!(
+            diagram.rows[1].hazards,
+            [Hazard::Data {
+                register: "a0".to_string(),
+                producer: 0x1000,
+                forwarded: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn without_forwarding_a_raw_hazard_should_wait_for_the_write_back() {
+        let diagram = pipeline(&RAW, 2, false);
+        // a0 is written in cycle 5 and read in the same cycle, EX in 6
+        assert_eq!(
+            stages(&diagram),
+            [
+                (1, "IF ID EX MEM WB".to_string()),
+                (2, "IF ID -- -- EX MEM WB".to_string()),
+            ]
+        );
+        assert_eq!(diagram.cycles, 8);
+        assert_eq!(
+            diagram.rows[1].hazards,
+            [Hazard::Data {
+                register: "a0".to_string(),
+                producer: 0x1000,
+                forwarded: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn a_store_of_a_loaded_value_should_wait_for_the_mem_of_the_load() {
+        let program = [
+            // 0x1000 lui t0, 0x1
+            0x0000_12b7,
+            // 0x1004 lw a0, 64(t0)
+            0x0402_a503,
+            // 0x1008 sw a0, 68(t0)
+            0x04a2_a223,
+        ];
+        let diagram = pipeline(&program, 3, true);
+        assert_eq!(
+            stages(&diagram),
+            [
+                (1, "IF ID EX MEM WB".to_string()),
+                (2, "IF ID EX MEM WB".to_string()),
+                (3, "IF ID -- EX MEM WB".to_string()),
+            ]
+        );
+        assert_eq!(diagram.cycles, 8);
+        assert!(diagram.rows[2].hazards.contains(&Hazard::Data {
+            register: "a0".to_string(),
+            producer: 0x1004,
+            forwarded: true,
+        }));
+    }
+
+    #[test]
+    fn a_branch_that_is_not_taken_should_not_flush() {
+        let program = [
+            // 0x1000 addi a0, zero, 1
+            0x0010_0513,
+            // 0x1004 beq a0, zero, 0x100c    not taken
+            0x0005_0463,
+            // 0x1008 addi a1, zero, 2
+            0x0020_0593,
+        ];
+        let diagram = pipeline(&program, 3, true);
+        assert_eq!(
+            stages(&diagram),
+            [
+                (1, "IF ID EX MEM WB".to_string()),
+                (2, "IF ID EX MEM WB".to_string()),
+                (3, "IF ID EX MEM WB".to_string()),
+            ]
+        );
+        assert_eq!(diagram.cycles, 7);
+        assert!(diagram.rows[2].hazards.is_empty());
+    }
+
+    #[test]
+    fn should_stall_for_a_load_use_and_flush_after_a_taken_branch() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 lw a0, 64(t0)
+        // 0x1008 addi a1, a0, 1        <- load-use
+        // 0x100c beq zero, zero, 0x1014
+        // 0x1010 addi a2, zero, 1      <- never retires
+        // 0x1014 addi a3, a1, 2
+        let program: Vec<u8> = [
+            0x0000_12b7u32,
+            0x0402_a503,
+            0x0015_0593,
+            0x0000_0463,
+            0x0010_0613,
+            0x0025_8693,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        let (result, diagram) = vm.run_pipeline(5, true);
+        assert_eq!(result, Ok(StopReason::Preempted { pc: 0x1018 }));
+        let stages: Vec<(u64, String)> = diagram
+            .rows
+            .iter()
+            .map(|row| {
+                let stages: Vec<String> = row.stages.iter().map(ToString::to_string).collect();
+                (row.first_cycle, stages.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                (1, "IF ID EX MEM WB".to_string()),
+                (2, "IF ID EX MEM WB".to_string()),
+                (3, "IF ID -- EX MEM WB".to_string()),
+                (4, "IF -- ID EX MEM WB".to_string()),
+                (8, "IF ID EX MEM WB".to_string()),
+            ]
+        );
+        assert_eq!(diagram.cycles, 12);
+        assert_eq!(
+            diagram.rows[2].hazards,
+            [Hazard::Data {
+                register: "a0".to_string(),
+                producer: 0x1004,
+                forwarded: true,
+            }]
+        );
+        assert_eq!(
+            diagram.rows[4].hazards,
+            [Hazard::Control { branch: 0x100c }]
+        );
+        assert!(diagram.to_string().starts_with("cycle "));
+        assert!(diagram
+            .to_json()
+            .contains("\"stages\":[\"IF\",\"ID\",\"stall\""));
+
+        // without forwarding every reader waits for the WB
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        let (_, diagram) = vm.run_pipeline(5, false);
+        assert_eq!(diagram.cycles, 15);
+    }
+}
@@ -0,0 +1,325 @@
+//! The PLIC of QEMU's `virt` machine, the interrupt controller between the
+//! devices and the harts, and `Vm::raise_irq`/`clear_irq` for the host to
+//! drive its lines; the devices on the bus drive theirs, see `mmio.rs`.
+//! Lines are level triggered: a raised line is pending until the guest
+//! claims it, and again after the guest completes it while it is still
+//! raised.
+//!
+//! Every hart has two contexts, machine (`2 * hart`) and supervisor
+//! (`2 * hart + 1`) mode, in the order of the device tree. A context sees
+//! the pending lines it enabled whose priority is above its threshold, the
+//! highest priority first (the lower line on a tie), and has MEIP or SEIP in
+//! `mip` while there is one. The vm takes the interrupt between two
+//! instructions, see `take_interrupt`.
+
+use super::csr::{MIP_MEIP, MIP_SEIP};
+use super::dtb::{PLIC_BASE, PLIC_SIZE};
+use super::emulator::Vm;
+use super::prelude::*;
+use super::replay::ReplayEvent;
+
+/// line 0 means no interrupt, the `riscv,ndev` of the device tree is 31
+pub const PLIC_LINES: u32 = 32;
+
+const PRIORITY: u32 = 0x00_0000;
+const PENDING: u32 = 0x00_1000;
+const ENABLE: u32 = 0x00_2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+const CLAIM: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Plic {
+    pub base: u32,
+    priority: [u32; PLIC_LINES as usize],
+    /// the raised lines, one bit each
+    raised: u32,
+    /// the lines a context claimed and did not complete yet
+    claimed: u32,
+    enable: Vec<u32>,
+    threshold: Vec<u32>,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self {
+            base: PLIC_BASE,
+            priority: [0; PLIC_LINES as usize],
+            raised: 0,
+            claimed: 0,
+            enable: Vec::new(),
+            threshold: Vec::new(),
+        }
+    }
+}
+
+impl Plic {
+    /// the lines waiting for a claim
+    pub fn pending(&self) -> u32 {
+        self.raised & !self.claimed
+    }
+
+    /// the line `context` gets from a claim, 0 for none
+    pub fn next(&self, context: usize) -> u32 {
+        let enabled = self.pending() & self.enable.get(context).copied().unwrap_or(0);
+        let threshold = self.threshold.get(context).copied().unwrap_or(0);
+        (1..PLIC_LINES)
+            .filter(|line| enabled & 1 << line != 0 && self.priority[*line as usize] > threshold)
+            .fold(0, |best, line| {
+                if best == 0 || self.priority[line as usize] > self.priority[best as usize] {
+                    line
+                } else {
+                    best
+                }
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let line = self.next(context);
+        self.claimed |= 1 << line & !1;
+        line
+    }
+
+    fn read(&self, offset: u32) -> u32 {
+        match offset {
+            PRIORITY..PENDING => self.priority[(offset / 4 % PLIC_LINES) as usize],
+            PENDING..ENABLE => self.pending() & !1,
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                self.enable.get(context).copied().unwrap_or(0)
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                match offset % CONTEXT_STRIDE {
+                    0 => self.threshold.get(context).copied().unwrap_or(0),
+                    // a claim changes the state, `read_plic` does it
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            PRIORITY..PENDING => self.priority[(offset / 4 % PLIC_LINES) as usize] = value & 0x7,
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                *slot(&mut self.enable, context) = value & !1;
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                match offset % CONTEXT_STRIDE {
+                    0 => *slot(&mut self.threshold, context) = value & 0x7,
+                    // completes the line
+                    CLAIM if value < PLIC_LINES => self.claimed &= !(1 << value),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// the entry of `context`, added when the guest first writes it
+fn slot(values: &mut Vec<u32>, context: usize) -> &mut u32 {
+    if values.len() <= context {
+        values.resize(context + 1, 0);
+    }
+    &mut values[context]
+}
+
+impl Vm {
+    /// puts a PLIC at `PLIC_BASE`, where the device tree says it is
+    pub fn with_plic(mut self) -> Self {
+        self.plic = Some(Plic::default());
+        self
+    }
+
+    /// raises the interrupt `line` of the PLIC, the guest takes it before
+    /// its next instruction if it is enabled. Returns false if there is no
+    /// PLIC or no such line
+    pub fn raise_irq(&mut self, line: u32) -> bool {
+        self.host_irq(line, true)
+    }
+
+    /// lowers the interrupt `line` again, a pending interrupt on it goes away
+    pub fn clear_irq(&mut self, line: u32) -> bool {
+        self.host_irq(line, false)
+    }
+
+    /// `set_irq` for the host and the devices, which is ignored while
+    /// replaying a log, the log has the lines then
+    pub(super) fn host_irq(&mut self, line: u32, raised: bool) -> bool {
+        if self.is_replaying() || !self.set_irq(line, raised) {
+            return false;
+        }
+        let at = self.stats.instructions_retired;
+        self.record(ReplayEvent::Irq { at, line, raised });
+        true
+    }
+
+    pub(super) fn set_irq(&mut self, line: u32, raised: bool) -> bool {
+        let Some(plic) = &mut self.plic else {
+            return false;
+        };
+        if line == 0 || line >= PLIC_LINES {
+            return false;
+        }
+        if raised {
+            plic.raised |= 1 << line;
+        } else {
+            plic.raised &= !(1 << line);
+        }
+        self.update_external_interrupts();
+        true
+    }
+
+    /// MEIP and SEIP in `mip` for the contexts of the current hart
+    pub(super) fn update_external_interrupts(&mut self) {
+        let Some(plic) = &self.plic else {
+            return;
+        };
+        let hart = self.csrs.mhartid as usize;
+        for (context, bit) in [(2 * hart, MIP_MEIP), (2 * hart + 1, MIP_SEIP)] {
+            if plic.next(context) != 0 {
+                self.csrs.mip |= bit;
+            } else {
+                self.csrs.mip &= !bit;
+            }
+        }
+    }
+
+    /// a load of the PLIC register at `address`, `None` if it is not in the
+    /// PLIC. A load of a claim register claims
+    pub(super) fn read_plic(&mut self, address: u32, size: u32) -> Option<u32> {
+        let plic = self.plic.as_mut()?;
+        let offset = address.checked_sub(plic.base)?;
+        if offset + size > PLIC_SIZE {
+            return None;
+        }
+        let offset = offset & !0b11;
+        if offset >= CONTEXT && offset % CONTEXT_STRIDE == CLAIM {
+            let line = plic.claim(((offset - CONTEXT) / CONTEXT_STRIDE) as usize);
+            self.update_external_interrupts();
+            return Some(line);
+        }
+        Some(plic.read(offset))
+    }
+
+    /// a store to the PLIC register at `address`, returns false if it is not
+    /// in the PLIC
+    pub(super) fn write_plic(&mut self, address: u32, size: u32, value: u32) -> bool {
+        let Some(plic) = &mut self.plic else {
+            return false;
+        };
+        let Some(offset) = address.checked_sub(plic.base) else {
+            return false;
+        };
+        if offset + size > PLIC_SIZE {
+            return false;
+        }
+        plic.write(offset & !0b11, value);
+        self.update_external_interrupts();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plic;
+    use crate::csr::MIP_MEIP;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_hand_out_the_highest_priority_line_first() {
+        let mut vm = Vm::new(0x1000, 0x100).with_plic();
+        let plic = vm.plic.as_mut().unwrap();
+        plic.priority[3] = 1;
+        plic.priority[10] = 2;
+        plic.priority[11] = 2;
+        plic.enable = vec![1 << 3 | 1 << 10 | 1 << 11];
+
+        assert!(vm.raise_irq(3));
+        assert!(vm.raise_irq(11));
+        assert!(vm.raise_irq(10));
+        assert!(!vm.raise_irq(32));
+        assert_ne!(vm.csrs.mip & MIP_MEIP, 0);
+
+        let plic: &mut Plic = vm.plic.as_mut().unwrap();
+        // 10 and 11 tie, the lower line wins
+        assert_eq!(plic.claim(0), 10);
+        assert_eq!(plic.claim(0), 11);
+        assert_eq!(plic.claim(0), 3);
+        assert_eq!(plic.claim(0), 0);
+
+        // completing a line that is still raised makes it pending again
+        plic.write(super::CONTEXT + super::CLAIM, 10);
+        assert_eq!(plic.next(0), 10);
+        vm.clear_irq(10);
+        assert_eq!(vm.plic.as_ref().unwrap().next(0), 0);
+        assert_eq!(vm.csrs.mip & MIP_MEIP, 0);
+    }
+
+    #[test]
+    fn the_guest_should_take_a_raised_irq_before_its_next_instruction() {
+        let program: Vec<u8> = [
+            // 0x1000 auipc t0, 0
+            0x0000_0297u32,
+            // 0x1004 addi t0, t0, 0x40
+            0x0402_8293,
+            // 0x1008 csrrw zero, mtvec, t0
+            0x3052_9073,
+            // 0x100c lui t0, 0x0c000            the PLIC
+            0x0c00_02b7,
+            // 0x1010 addi t1, zero, 1
+            0x0010_0313,
+            // 0x1014 sw t1, 0x28(t0)           priority of line 10
+            0x0262_a423,
+            // 0x1018 addi t1, zero, 0x400
+            0x4000_0313,
+            // 0x101c lui t2, 0x2
+            0x0000_23b7,
+            // 0x1020 add t2, t2, t0
+            0x0053_83b3,
+            // 0x1024 sw t1, 0(t2)              enable line 10 for context 0
+            0x0063_a023,
+            // 0x1028 lui t1, 0x1
+            0x0000_1337,
+            // 0x102c srli t1, t1, 1
+            0x0013_5313,
+            // 0x1030 csrrs zero, mie, t1       MEIE
+            0x3043_2073,
+            // 0x1034 csrrsi zero, mstatus, 8
+            0x3004_6073,
+            // 0x1038 jal zero, 0
+            0x0000_006f,
+            // 0x103c nop
+            0x0000_0013,
+            // 0x1040 lui t0, 0x0c200           <- handler
+            0x0c20_02b7,
+            // 0x1044 lw a0, 4(t0)              claim
+            0x0042_a503,
+            // 0x1048 sw a0, 4(t0)              complete
+            0x00a2_a223,
+            // 0x104c csrrs a1, mcause, zero
+            0x3420_25f3,
+            // 0x1050 ebreak
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_plic();
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(vm.run_for(100), Ok(StopReason::Preempted { pc: 0x1038 }));
+        vm.raise_irq(10);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.csrs.mepc, 0x1038);
+        assert_eq!(vm.vm_state.registers[10], 10);
+        assert_eq!(vm.vm_state.registers[11] as u32, 0x8000_000b);
+        // claimed and completed, but still raised
+        assert_eq!(vm.plic.as_ref().unwrap().pending(), 1 << 10);
+    }
+}
@@ -0,0 +1,178 @@
+//! Custom instructions for research ISAs on top of the vm. The four custom
+//! opcode spaces of the spec (custom-0 to custom-3) decode to
+//! `Rv32iInstruction::Custom` with the raw word, and the vm hands that word
+//! to the plugin registered for its opcode. Without a plugin they are
+//! illegal instructions, like any other unknown encoding.
+//!
+//! A plugin sees the registers and the memory like an ecall handler, and
+//! the vm moves the pc past the instruction afterwards. It decodes the rest
+//! of the word itself (funct3, funct7, ...) and returns
+//! `VmError::IllegalInstruction` for words it does not know.
+
+use super::emulator::{Vm, VmState};
+use super::error::VmError;
+use super::extensions::Extension;
+use super::memory::Memory;
+use super::prelude::*;
+
+/// the opcodes the spec leaves to custom extensions
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CustomOpcode {
+    Custom0 = 0x0b,
+    Custom1 = 0x2b,
+    Custom2 = 0x5b,
+    Custom3 = 0x7b,
+}
+
+impl CustomOpcode {
+    /// the custom opcode space of `word`, if it is in one
+    pub fn of(word: u32) -> Option<Self> {
+        Some(match word & 0x7f {
+            0x0b => Self::Custom0,
+            0x2b => Self::Custom1,
+            0x5b => Self::Custom2,
+            0x7b => Self::Custom3,
+            _ => return None,
+        })
+    }
+}
+
+/// the instructions of one custom opcode space
+pub trait InstructionPlugin {
+    /// executes `word`, the vm moves the pc past it afterwards
+    fn execute(
+        &mut self,
+        word: u32,
+        vm_state: &mut VmState,
+        memory: &mut Memory,
+    ) -> Result<(), VmError>;
+
+    /// the assembly of `word` for `Vm::disassemble`, `None` shows it as
+    /// `.insn`
+    fn disassemble(&self, _word: u32) -> Option<String> {
+        None
+    }
+}
+
+impl Vm {
+    /// lets `plugin` execute the instructions with `opcode`, instead of a
+    /// plugin registered for it before. Turns X on
+    pub fn add_instruction_plugin(
+        &mut self,
+        opcode: CustomOpcode,
+        plugin: Box<dyn InstructionPlugin>,
+    ) {
+        self.plugins.insert(opcode, plugin);
+        self.extensions = self.extensions.with(Extension::X);
+    }
+
+    /// executes the custom instruction `word` with its plugin and moves the
+    /// pc past it
+    pub(super) fn execute_custom(&mut self, word: u32) -> Result<(), VmError> {
+        let plugin = CustomOpcode::of(word).and_then(|opcode| self.plugins.get_mut(&opcode));
+        let Some(plugin) = plugin else {
+            return Err(VmError::IllegalInstruction {
+                pc: 0,
+                instruction: 0,
+            });
+        };
+        plugin.execute(word, &mut self.vm_state, &mut self.memory)?;
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        Ok(())
+    }
+
+    /// the assembly of the custom instruction `word` from its plugin
+    pub(super) fn disassemble_custom(&self, word: u32) -> Option<String> {
+        let plugin = self.plugins.get(&CustomOpcode::of(word)?)?;
+        plugin.disassemble(word)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CustomOpcode, InstructionPlugin};
+    use crate::memory::Memory;
+    use crate::{Register, StopReason, Vm, VmError, VmState};
+
+    /// `mac rd, rs1, rs2`: rd += rs1 * rs2, R-type with funct3 and funct7 0
+    struct Mac;
+
+    impl InstructionPlugin for Mac {
+        fn execute(
+            &mut self,
+            word: u32,
+            vm_state: &mut VmState,
+            _memory: &mut Memory,
+        ) -> Result<(), VmError> {
+            if word >> 25 != 0 || word >> 12 & 0b111 != 0 {
+                return Err(VmError::IllegalInstruction {
+                    pc: 0,
+                    instruction: 0,
+                });
+            }
+            let register = |shift: u32| Register::from_bits((word >> shift & 0x1f) as u8);
+            let (rd, rs1, rs2) = (register(7), register(15), register(20));
+            let product = vm_state.registers[rs1].wrapping_mul(vm_state.registers[rs2]);
+            vm_state
+                .registers
+                .write(rd, vm_state.registers[rd].wrapping_add(product));
+            Ok(())
+        }
+
+        fn disassemble(&self, word: u32) -> Option<String> {
+            let register = |shift: u32| Register::from_bits((word >> shift & 0x1f) as u8);
+            Some(format!(
+                "mac {}, {}, {}",
+                register(7),
+                register(15),
+                register(20)
+            ))
+        }
+    }
+
+    #[test]
+    fn should_hand_custom_instructions_to_their_plugin() {
+        // 0x1000 addi a0, zero, 2
+        // 0x1004 addi a1, zero, 3
+        // 0x1008 addi a2, zero, 4
+        // 0x100c mac a0, a1, a2        <- custom-0
+        // 0x1010 ebreak
+        // 0x1014 .insn custom-1
+        let program: Vec<u8> = [
+            0x0020_0513u32,
+            0x0030_0593,
+            0x0040_0613,
+            0x00c5_850b,
+            0x0010_0073,
+            0x0000_002b,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_instruction_plugin(CustomOpcode::Custom0, Box::new(Mac));
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[10], 14);
+        assert_eq!(
+            vm.disassemble(0x100c, 1).unwrap(),
+            "0x0000100c: mac a0, a1, a2\n"
+        );
+
+        // no plugin for custom-1
+        vm.vm_state.pc = 0x1014;
+        assert_eq!(
+            vm.run(),
+            Err(VmError::IllegalInstruction {
+                pc: 0x1014,
+                instruction: 0x0000_002b,
+            })
+        );
+        assert_eq!(
+            vm.disassemble(0x1014, 1).unwrap(),
+            "0x00001014: .insn 4, 0x0000002b\n"
+        );
+    }
+}
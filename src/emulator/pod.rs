@@ -0,0 +1,170 @@
+//! Typed reads and writes of guest memory for the code that handles
+//! syscalls and hostcalls: integers, arrays and C structs at guest
+//! addresses and C strings, all bounds checked, so nobody has to add up
+//! offsets by hand. Guest data is little endian; `Be` wraps the big endian
+//! fields of network headers and file formats. Every access faults like the
+//! guest's own loads and stores would.
+
+use super::error::VmError;
+use super::memory::Memory;
+use super::prelude::*;
+
+/// a value with a fixed layout in guest memory. Implement it for a C
+/// struct by reading and writing its fields at their offsets
+pub trait Pod: Sized {
+    /// in bytes
+    const SIZE: usize;
+
+    /// `bytes` has `SIZE` bytes
+    fn from_bytes(bytes: &[u8]) -> Self;
+
+    /// `bytes` has `SIZE` zeroed bytes
+    fn to_bytes(&self, bytes: &mut [u8]);
+}
+
+/// a big endian integer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Be<T>(pub T);
+
+macro_rules! integers {
+    ($($integer:ty),*) => {$(
+        impl Pod for $integer {
+            const SIZE: usize = core::mem::size_of::<$integer>();
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                <$integer>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn to_bytes(&self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Pod for Be<$integer> {
+            const SIZE: usize = core::mem::size_of::<$integer>();
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                Be(<$integer>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+
+            fn to_bytes(&self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.0.to_be_bytes());
+            }
+        }
+    )*};
+}
+
+integers!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: Pod, const N: usize> Pod for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        core::array::from_fn(|index| T::from_bytes(&bytes[index * T::SIZE..][..T::SIZE]))
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8]) {
+        for (value, bytes) in self.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+            value.to_bytes(bytes);
+        }
+    }
+}
+
+impl Memory {
+    /// the `T` at `address`
+    pub fn read_pod<T: Pod>(&self, address: u32) -> Result<T, VmError> {
+        Ok(T::from_bytes(self.read_bytes(address, T::SIZE)?))
+    }
+
+    /// stores `value` at `address` the way the guest would
+    pub fn write_pod<T: Pod>(&mut self, address: u32, value: &T) -> Result<(), VmError> {
+        let mut bytes = vec![0; T::SIZE];
+        value.to_bytes(&mut bytes);
+        self.write_bytes(address, &bytes)
+    }
+
+    /// the NUL terminated string at `address` without the NUL, `None` when
+    /// there is none in the first `max_length` bytes
+    pub fn read_c_string(
+        &self,
+        address: u32,
+        max_length: usize,
+    ) -> Result<Option<Vec<u8>>, VmError> {
+        let mut string = Vec::new();
+        for offset in 0..max_length as u32 {
+            match self.read_u8(address.wrapping_add(offset))? {
+                0 => return Ok(Some(string)),
+                byte => string.push(byte),
+            }
+        }
+        Ok(None)
+    }
+
+    /// stores `string` and a NUL at `address`
+    pub fn write_c_string(&mut self, address: u32, string: &[u8]) -> Result<(), VmError> {
+        let mut bytes = string.to_vec();
+        bytes.push(0);
+        self.write_bytes(address, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Be, Pod};
+    use crate::memory::Memory;
+    use crate::VmError;
+
+    /// a `struct timespec` of RV32 Linux
+    #[derive(Debug, PartialEq)]
+    struct Timespec {
+        seconds: i64,
+        nanoseconds: i32,
+    }
+
+    impl Pod for Timespec {
+        const SIZE: usize = 16;
+
+        fn from_bytes(bytes: &[u8]) -> Self {
+            Self {
+                seconds: i64::from_bytes(&bytes[0..8]),
+                nanoseconds: i32::from_bytes(&bytes[8..12]),
+            }
+        }
+
+        fn to_bytes(&self, bytes: &mut [u8]) {
+            self.seconds.to_bytes(&mut bytes[0..8]);
+            self.nanoseconds.to_bytes(&mut bytes[8..12]);
+        }
+    }
+
+    #[test]
+    fn should_read_back_what_was_written() {
+        let mut memory = Memory::new(0x1000, 0x40);
+        let time = Timespec {
+            seconds: -2,
+            nanoseconds: 500,
+        };
+        memory.write_pod(0x1000, &time).unwrap();
+        assert_eq!(memory.read_pod::<Timespec>(0x1000), Ok(time));
+        assert_eq!(
+            memory.read_pod::<[u32; 2]>(0x1000),
+            Ok([0xffff_fffe, 0xffff_ffff])
+        );
+
+        memory.write_pod(0x1010, &Be(0x1234u16)).unwrap();
+        assert_eq!(memory.read_u16(0x1010), Ok(0x3412));
+
+        memory.write_c_string(0x1020, b"abc").unwrap();
+        assert_eq!(memory.read_c_string(0x1020, 8), Ok(Some(b"abc".to_vec())));
+        assert_eq!(memory.read_c_string(0x1020, 3), Ok(None));
+
+        // a struct that runs past the end faults
+        assert!(matches!(
+            memory.read_pod::<Timespec>(0x1038),
+            Err(VmError::MemoryOutOfBounds {
+                address: 0x1038,
+                ..
+            })
+        ));
+    }
+}
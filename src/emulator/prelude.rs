@@ -0,0 +1,11 @@
+//! What the std prelude brings into scope, for the modules that also build
+//! without std: `use super::prelude::*;`. The HashMap is hashbrown's then.
+
+pub(crate) use alloc::boxed::Box;
+pub(crate) use alloc::string::{String, ToString};
+pub(crate) use alloc::vec::Vec;
+pub(crate) use alloc::{format, vec};
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::{HashMap, HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
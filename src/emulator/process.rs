@@ -0,0 +1,198 @@
+//! What a Linux user program finds when it starts: a stack with argc, the
+//! argv and envp pointers and the auxiliary vector, sp pointing at argc.
+//!
+//! ```text
+//! sp ->  argc
+//!        argv[0] .. argv[argc - 1], 0
+//!        envp[0] .. envp[n - 1], 0
+//!        auxv pairs (type, value), AT_NULL
+//!        ...
+//!        the AT_RANDOM bytes and the strings, up to the stack top
+//! ```
+//!
+//! `user_memory_map` lays out the memory for it: RAM over the segments of
+//! the ELF file, then the stack region with a guard page below it so an
+//! overflow faults instead of running into the program. `w_xor_x_memory_map`
+//! also makes the executable segments ROM and the rest non-executable.
+
+use super::elf::Elf;
+use super::emulator::Vm;
+use super::error::VmError;
+use super::image::Image;
+use super::memory::{Memory, MemoryMap};
+use super::prelude::*;
+
+/// where the stack ends unless the caller picks another place
+pub const DEFAULT_STACK_TOP: u32 = 0x8000_0000;
+pub const DEFAULT_STACK_SIZE: u32 = 1 << 20;
+/// the guard region under the stack of `user_memory_map`
+pub const STACK_GUARD_SIZE: u32 = 0x1000;
+
+const AT_NULL: u32 = 0;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_RANDOM: u32 = 25;
+
+/// the 16 bytes AT_RANDOM points to (libc seeds its stack canary with them),
+/// always the same so runs can be repeated
+const RANDOM_BYTES: [u8; 16] = *b"web-riscv-vm-rnd";
+
+/// the RAM for the segments of `elf`, from the page of the lowest to the end
+/// of the highest, and a stack of `stack_size` bytes that ends at
+/// `stack_top` with a guard page below it. The stack must not overlap the
+/// segments
+pub fn user_memory_map(elf: &Elf, stack_top: u32, stack_size: u32) -> MemoryMap {
+    let start = elf.segments.iter().map(|segment| segment.address).min();
+    let end = elf
+        .segments
+        .iter()
+        .map(|segment| u64::from(segment.address) + u64::from(segment.memory_size))
+        .max();
+    memory_map(start.zip(end), stack_top, stack_size)
+}
+
+/// `user_memory_map` for the blocks of an `Image`
+pub fn image_memory_map(image: &Image, stack_top: u32, stack_size: u32) -> MemoryMap {
+    memory_map(image.span(), stack_top, stack_size)
+}
+
+/// RAM over `span`, the lowest address and the end of the highest, then the
+/// stack with its guard
+fn memory_map(span: Option<(u32, u64)>, stack_top: u32, stack_size: u32) -> MemoryMap {
+    let (start, end) = span.unwrap_or((0, 0));
+    let start = start & !0xfff;
+    let stack = stack_top - stack_size;
+
+    MemoryMap::new()
+        .ram(start, (end - u64::from(start)) as usize)
+        .ram(stack, stack_size as usize)
+        .guard(stack - STACK_GUARD_SIZE, STACK_GUARD_SIZE as usize)
+}
+
+/// `user_memory_map` with write xor execute: the executable segments of
+/// `elf` are ROM and only they can be executed
+pub fn w_xor_x_memory_map(elf: &Elf, stack_top: u32, stack_size: u32) -> MemoryMap {
+    elf.segments
+        .iter()
+        .filter(|segment| segment.is_executable())
+        .fold(
+            user_memory_map(elf, stack_top, stack_size).with_w_xor_x(),
+            |map, segment| map.rom(segment.address, segment.memory_size as usize),
+        )
+}
+
+impl Vm {
+    /// puts `args` (the program name first) and `env` (`NAME=value`) on the
+    /// stack that ends at `stack_top` the way Linux does and points sp at
+    /// argc. `entry` goes into AT_ENTRY. Returns the new sp
+    pub fn push_arguments(
+        &mut self,
+        stack_top: u32,
+        args: &[&str],
+        env: &[&str],
+        entry: u32,
+    ) -> Result<u32, VmError> {
+        let mut sp = stack_top;
+        let mut push = |memory: &mut Memory, bytes: &[u8]| {
+            sp -= bytes.len() as u32;
+            memory.load(sp, bytes).map(|()| sp)
+        };
+
+        let mut strings = Vec::new();
+        for string in args.iter().chain(env) {
+            let bytes = [string.as_bytes(), &[0]].concat();
+            strings.push(push(&mut self.memory, &bytes)?);
+        }
+        let random = push(&mut self.memory, &RANDOM_BYTES)?;
+        let (argv, envp) = strings.split_at(args.len());
+
+        let mut words = vec![args.len() as u32];
+        words.extend(argv);
+        words.push(0);
+        words.extend(envp);
+        words.push(0);
+        words.extend([AT_PAGESZ, 0x1000, AT_ENTRY, entry, AT_RANDOM, random]);
+        words.extend([AT_NULL, 0]);
+
+        // the ABI wants sp 16 byte aligned
+        let sp = (random - 4 * words.len() as u32) & !0xf;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        self.memory.load(sp, &bytes)?;
+        self.vm_state.set_sp(sp as i32);
+        Ok(sp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::w_xor_x_memory_map;
+    use crate::elf::{test_elf, Elf};
+    use crate::memory::MemoryMap;
+    use crate::{AccessKind, Vm, VmError};
+
+    #[test]
+    fn should_lay_out_argc_argv_envp_and_auxv() {
+        let mut vm = Vm::from_memory_map(&MemoryMap::new().ram(0x1000, 0x1000));
+        let sp = vm
+            .push_arguments(0x2000, &["prog", "-v"], &["HOME=/"], 0x1000)
+            .unwrap();
+        assert_eq!(sp % 16, 0);
+        assert_eq!(vm.vm_state.sp(), sp as i32);
+
+        let word = |index: u32| vm.memory.read_u32(sp + 4 * index).unwrap();
+        let string = |address: u32| {
+            let bytes = vm
+                .memory
+                .read_bytes(address, 0x2000 - address as usize)
+                .unwrap();
+            let end = bytes.iter().position(|&byte| byte == 0).unwrap();
+            String::from_utf8(bytes[..end].to_vec()).unwrap()
+        };
+        assert_eq!(word(0), 2);
+        assert_eq!(string(word(1)), "prog");
+        assert_eq!(string(word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(string(word(4)), "HOME=/");
+        assert_eq!(word(5), 0);
+        // AT_PAGESZ, AT_ENTRY, AT_RANDOM and AT_NULL
+        assert_eq!((word(6), word(7)), (6, 0x1000));
+        assert_eq!((word(8), word(9)), (9, 0x1000));
+        assert_eq!(word(10), 25);
+        assert_eq!(
+            vm.memory.read_bytes(word(11), 16).unwrap(),
+            b"web-riscv-vm-rnd"
+        );
+        assert_eq!((word(12), word(13)), (0, 0));
+    }
+
+    #[test]
+    fn should_trap_stores_to_code_and_fetches_from_data_with_w_xor_x() {
+        // 0x1000 auipc t0, 0x0
+        // 0x1004 sw zero, 0(t0)
+        let bytes = test_elf::build(0x1000, &[0x0000_0297, 0x0002_a023], 0, &[]);
+        let map = w_xor_x_memory_map(&Elf::parse(&bytes).unwrap(), 0x10_0000, 0x1000);
+        let mut vm = Vm::from_memory_map(&map);
+        vm.load_elf(&bytes).unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(VmError::AccessFault {
+                pc: 0x1004,
+                instruction: 0x0002_a023,
+                address: 0x1000,
+                kind: AccessKind::Store,
+            })
+        );
+
+        // the stack is writable, so it is not executable
+        vm.vm_state.pc = 0xf_fff0;
+        assert_eq!(
+            vm.step(),
+            Err(VmError::AccessFault {
+                pc: 0xf_fff0,
+                instruction: 0,
+                address: 0xf_fff0,
+                kind: AccessKind::Fetch,
+            })
+        );
+    }
+}
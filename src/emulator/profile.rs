@@ -0,0 +1,257 @@
+//! Per-function instruction counts that are kept across runs, so someone
+//! optimizing guest code can compare two builds of it and spot regressions.
+//! The store is a JSON file keyed by a build id the user picks (a git hash,
+//! a version, ...).
+//!
+//! There are no symbols yet, the caller says where the functions are.
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::elf::{Symbol, SymbolKind};
+use super::prelude::*;
+use super::summary::RunStats;
+
+/// where a function is in the guest, `start..end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRange {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl FunctionRange {
+    pub fn new(name: &str, start: u32, end: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end,
+        }
+    }
+
+    /// the functions of an ELF symbol table, so profiles and backtraces can
+    /// use the names the loader found
+    pub fn from_symbols(symbols: &[Symbol]) -> Vec<Self> {
+        symbols
+            .iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Function && symbol.size > 0)
+            .map(|symbol| {
+                Self::new(
+                    &symbol.name,
+                    symbol.address,
+                    symbol.address.wrapping_add(symbol.size),
+                )
+            })
+            .collect()
+    }
+}
+
+/// how many instructions each function retired in one run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub functions: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    /// adds up `stats.pc_counts` per function. Functions that did not run
+    /// are in the profile with 0 instructions
+    pub fn from_stats(stats: &RunStats, functions: &[FunctionRange]) -> Self {
+        let mut profile = Self::default();
+        for function in functions {
+            let count: u64 = stats
+                .pc_counts
+                .iter()
+                .filter(|(pc, _)| (function.start..function.end).contains(*pc))
+                .map(|(_, count)| count)
+                .sum();
+            *profile.functions.entry(function.name.clone()).or_insert(0) += count;
+        }
+        profile
+    }
+}
+
+/// how one function changed between two builds, `None` if the function is
+/// not in that build's profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionChange {
+    pub name: String,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+impl FunctionChange {
+    /// the change in percent, rounded towards zero. `None` if there is
+    /// nothing to compare against
+    pub fn percent(&self) -> Option<i64> {
+        match (self.old, self.new) {
+            (Some(old), Some(new)) if old > 0 => Some((new as i64 - old as i64) * 100 / old as i64),
+            _ => None,
+        }
+    }
+}
+
+/// `sum_2_number: 7 → 5 instructions, −28%`
+impl fmt::Display for FunctionChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = &self.name;
+        match (self.old, self.new) {
+            (Some(old), Some(new)) => {
+                write!(f, "{name}: {old} → {new} instructions")?;
+                match self.percent() {
+                    Some(0) => write!(f, ", unchanged"),
+                    Some(percent) if percent < 0 => write!(f, ", −{}%", -percent),
+                    Some(percent) => write!(f, ", +{percent}%"),
+                    None => Ok(()),
+                }
+            }
+            (None, Some(new)) => write!(f, "{name}: new, {new} instructions"),
+            (Some(old), None) => write!(f, "{name}: removed, was {old} instructions"),
+            (None, None) => write!(f, "{name}: not profiled"),
+        }
+    }
+}
+
+/// The profiles of every build, saved as JSON at `path`. Each build keeps
+/// all its runs, comparisons use the latest one.
+/// needs std, it is a file
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct ProfileStore {
+    path: PathBuf,
+    builds: BTreeMap<String, Vec<Profile>>,
+}
+
+#[cfg(feature = "std")]
+impl ProfileStore {
+    /// loads the store at `path`, a missing file is an empty store
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let builds = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error),
+        };
+        Ok(Self { path, builds })
+    }
+
+    /// adds a run of `build_id` and writes the store back to disk
+    pub fn record(&mut self, build_id: &str, profile: Profile) -> io::Result<()> {
+        self.builds
+            .entry(build_id.to_string())
+            .or_default()
+            .push(profile);
+        self.save()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.builds)?)
+    }
+
+    pub fn build_ids(&self) -> impl Iterator<Item = &str> {
+        self.builds.keys().map(String::as_str)
+    }
+
+    /// every recorded run of `build_id`, oldest first
+    pub fn runs(&self, build_id: &str) -> &[Profile] {
+        self.builds.get(build_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn latest(&self, build_id: &str) -> Option<&Profile> {
+        self.runs(build_id).last()
+    }
+
+    /// every function of the latest runs of both builds, by name. `None` if
+    /// one of the builds was never recorded
+    pub fn compare(&self, old_build: &str, new_build: &str) -> Option<Vec<FunctionChange>> {
+        let old = self.latest(old_build)?;
+        let new = self.latest(new_build)?;
+
+        let mut names: Vec<&String> = old.functions.keys().collect();
+        names.extend(new.functions.keys());
+        names.sort();
+        names.dedup();
+
+        Some(
+            names
+                .into_iter()
+                .map(|name| FunctionChange {
+                    name: name.clone(),
+                    old: old.functions.get(name).copied(),
+                    new: new.functions.get(name).copied(),
+                })
+                .collect(),
+        )
+    }
+
+    /// `compare` as text, one function per line
+    pub fn report(&self, old_build: &str, new_build: &str) -> Option<String> {
+        let changes = self.compare(old_build, new_build)?;
+        Some(changes.iter().map(|change| format!("{change}\n")).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FunctionRange, Profile, ProfileStore};
+    use crate::summary::RunStats;
+
+    fn profile(counts: &[(u32, u64)]) -> Profile {
+        let mut stats = RunStats::default();
+        stats.pc_counts.extend(counts.iter().copied());
+        Profile::from_stats(
+            &stats,
+            &[
+                FunctionRange::new("main", 0x1000, 0x1010),
+                FunctionRange::new("sum_2_number", 0x1010, 0x1020),
+            ],
+        )
+    }
+
+    #[test]
+    fn profile_should_add_up_the_counts_per_function() {
+        let profile = profile(&[(0x1000, 1), (0x100c, 2), (0x1010, 3), (0x1020, 9)]);
+
+        assert_eq!(profile.functions["main"], 3);
+        assert_eq!(profile.functions["sum_2_number"], 3);
+        assert_eq!(profile.functions.len(), 2);
+    }
+
+    #[test]
+    fn store_should_survive_a_reload_and_compare_builds() {
+        let path = std::env::temp_dir().join(format!(
+            "riscv_emulator_profiles_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = ProfileStore::open(&path).unwrap();
+        store
+            .record("v1", profile(&[(0x1000, 4), (0x1010, 7)]))
+            .unwrap();
+        store
+            .record("v2", profile(&[(0x1000, 4), (0x1010, 9)]))
+            .unwrap();
+        // the latest run of a build is the one that is compared
+        store
+            .record("v2", profile(&[(0x1000, 5), (0x1010, 5)]))
+            .unwrap();
+
+        let store = ProfileStore::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(store.build_ids().collect::<Vec<_>>(), vec!["v1", "v2"]);
+        assert_eq!(store.runs("v2").len(), 2);
+        assert_eq!(
+            store.report("v1", "v2").unwrap(),
+            "main: 4 → 5 instructions, +25%\nsum_2_number: 7 → 5 instructions, −28%\n"
+        );
+        assert_eq!(store.report("v1", "v3"), None);
+    }
+}
@@ -0,0 +1,487 @@
+//! Instruction-frequency profiler: counts the executed instructions per
+//! mnemonic and per pc, how often each basic block is entered, how often
+//! each function is called, how often each branch is taken (and
+//! mispredicted, with a `BranchPredictor`) and the bytes loaded and stored.
+//! It is a `VmHooks`, so it costs nothing when it is not added.
+//! `riscv-vm stats` prints its report with `to_text`.
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use serde::Serialize;
+
+use super::branch_predictor::BranchPredictor;
+use super::emulator::{Instruction, Vm, VmState};
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::profile::FunctionRange;
+use super::rv32i::Rv32iInstruction;
+
+#[derive(Debug, Default)]
+struct Counts {
+    instructions: u64,
+    mnemonics: HashMap<&'static str, u64>,
+    pcs: HashMap<u32, u64>,
+    /// entries and executed instructions per block start
+    blocks: HashMap<u32, (u64, u64)>,
+    /// calls per target address
+    calls: HashMap<u32, u64>,
+    branches: HashMap<u32, BranchCount>,
+    memory: MemoryCount,
+    predictor: Option<Box<dyn BranchPredictor>>,
+    /// the block the last instruction belongs to, `None` when the next
+    /// instruction starts a new one
+    current_block: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MnemonicCount {
+    pub mnemonic: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PcCount {
+    pub pc: u32,
+    pub count: u64,
+}
+
+/// A basic block as it was executed: from where control entered it to the
+/// next branch or jump. A jump into the middle of a block counts as a block
+/// of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockCount {
+    pub start: u32,
+    pub entries: u64,
+    pub instructions: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CallCount {
+    pub target: u32,
+    /// the function the target is in, if the report was given one
+    pub function: Option<String>,
+    pub count: u64,
+}
+
+/// a conditional branch, `mispredicted` stays 0 without a predictor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BranchCount {
+    pub pc: u32,
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+/// the loads and stores and how many bytes they moved
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryCount {
+    pub loads: u64,
+    pub load_bytes: u64,
+    pub stores: u64,
+    pub store_bytes: u64,
+}
+
+/// everything the profiler counted, every list is sorted with the most
+/// executed first, the branches with the most mispredicted first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProfilerReport {
+    pub instructions: u64,
+    pub mnemonics: Vec<MnemonicCount>,
+    pub pcs: Vec<PcCount>,
+    pub blocks: Vec<BlockCount>,
+    pub calls: Vec<CallCount>,
+    pub branches: Vec<BranchCount>,
+    pub memory: MemoryCount,
+}
+
+impl ProfilerReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the report only has plain fields")
+    }
+
+    /// the totals and the mnemonics by rank, with their share and the
+    /// share of all the ranks up to theirs: a few mnemonics are most of
+    /// any program, Zipf's law for instructions
+    pub fn to_text(&self) -> String {
+        let percent = |part: u64, whole: u64| {
+            if whole == 0 {
+                0.0
+            } else {
+                part as f64 * 100.0 / whole as f64
+            }
+        };
+        let executed: u64 = self.branches.iter().map(|branch| branch.executed).sum();
+        let taken: u64 = self.branches.iter().map(|branch| branch.taken).sum();
+        let memory = &self.memory;
+
+        let mut text = String::new();
+        let _ = writeln!(text, "dynamic instructions {:>12}", self.instructions);
+        let _ = writeln!(text, "static instructions  {:>12}", self.pcs.len());
+        let _ = writeln!(
+            text,
+            "loads                {:>12} ({} bytes)",
+            memory.loads, memory.load_bytes
+        );
+        let _ = writeln!(
+            text,
+            "stores               {:>12} ({} bytes)",
+            memory.stores, memory.store_bytes
+        );
+        let _ = writeln!(
+            text,
+            "branches             {:>12} ({:.1}% taken)",
+            executed,
+            percent(taken, executed)
+        );
+        let _ = writeln!(text);
+        let _ = writeln!(text, "rank  mnemonic         count   share  cumul.");
+        let mut cumulative = 0;
+        for (index, mnemonic) in self.mnemonics.iter().enumerate() {
+            cumulative += mnemonic.count;
+            let share = percent(mnemonic.count, self.instructions);
+            let _ = writeln!(
+                text,
+                "{:>4}  {:<10} {:>11} {:>6.1}% {:>6.1}%  {}",
+                index + 1,
+                mnemonic.mnemonic,
+                mnemonic.count,
+                share,
+                percent(cumulative, self.instructions),
+                "#".repeat((share / 2.0 + 0.5) as usize)
+            );
+        }
+        text
+    }
+}
+
+/// The handle to read the counts from while the vm owns the hooks. Create it
+/// with `Vm::add_profiler`.
+#[derive(Debug, Clone)]
+pub struct Profiler(Rc<RefCell<Counts>>);
+
+impl Profiler {
+    /// the counts so far, calls are named after the `functions` they land in
+    pub fn report(&self, functions: &[FunctionRange]) -> ProfilerReport {
+        let counts = self.0.borrow();
+
+        let mut mnemonics: Vec<MnemonicCount> = counts
+            .mnemonics
+            .iter()
+            .map(|(mnemonic, count)| MnemonicCount {
+                mnemonic: mnemonic.to_string(),
+                count: *count,
+            })
+            .collect();
+        mnemonics.sort_by(|a, b| b.count.cmp(&a.count).then(a.mnemonic.cmp(&b.mnemonic)));
+
+        let mut pcs: Vec<PcCount> = counts
+            .pcs
+            .iter()
+            .map(|(pc, count)| PcCount {
+                pc: *pc,
+                count: *count,
+            })
+            .collect();
+        pcs.sort_by(|a, b| b.count.cmp(&a.count).then(a.pc.cmp(&b.pc)));
+
+        let mut blocks: Vec<BlockCount> = counts
+            .blocks
+            .iter()
+            .map(|(start, (entries, instructions))| BlockCount {
+                start: *start,
+                entries: *entries,
+                instructions: *instructions,
+            })
+            .collect();
+        blocks.sort_by(|a, b| {
+            b.instructions
+                .cmp(&a.instructions)
+                .then(a.start.cmp(&b.start))
+        });
+
+        let mut calls: Vec<CallCount> = counts
+            .calls
+            .iter()
+            .map(|(target, count)| CallCount {
+                target: *target,
+                function: functions
+                    .iter()
+                    .find(|function| (function.start..function.end).contains(target))
+                    .map(|function| function.name.clone()),
+                count: *count,
+            })
+            .collect();
+        calls.sort_by(|a, b| b.count.cmp(&a.count).then(a.target.cmp(&b.target)));
+
+        let mut branches: Vec<BranchCount> = counts.branches.values().copied().collect();
+        branches.sort_by(|a, b| {
+            b.mispredicted
+                .cmp(&a.mispredicted)
+                .then(b.executed.cmp(&a.executed))
+                .then(a.pc.cmp(&b.pc))
+        });
+
+        ProfilerReport {
+            instructions: counts.instructions,
+            mnemonics,
+            pcs,
+            blocks,
+            calls,
+            branches,
+            memory: counts.memory,
+        }
+    }
+
+    /// predicts every branch from now on with `predictor`, the report counts
+    /// its mispredictions
+    pub fn set_branch_predictor(&self, predictor: Box<dyn BranchPredictor>) {
+        self.0.borrow_mut().predictor = Some(predictor);
+    }
+
+    /// forgets everything counted so far, the predictor stays
+    pub fn reset(&self) {
+        let mut counts = self.0.borrow_mut();
+        let predictor = counts.predictor.take();
+        *counts = Counts {
+            predictor,
+            ..Counts::default()
+        };
+    }
+}
+
+impl VmHooks for Profiler {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        let mut counts = self.0.borrow_mut();
+        let pc = vm_state.pc as u32;
+
+        counts.instructions += 1;
+        *counts.mnemonics.entry(instruction.mnemonic()).or_insert(0) += 1;
+        *counts.pcs.entry(pc).or_insert(0) += 1;
+
+        let block = *counts.current_block.get_or_insert(pc);
+        let (entries, instructions) = counts.blocks.entry(block).or_insert((0, 0));
+        if block == pc {
+            *entries += 1;
+        }
+        *instructions += 1;
+    }
+
+    fn after_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        let mut counts = self.0.borrow_mut();
+        let pc = vm_state.pc as u32;
+
+        // a jal or jalr that links is a call
+        let links = matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jal(link))
+                if !link.rd.is_zero()
+        ) || matches!(
+            instruction,
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jalr(link))
+                if !link.rd.is_zero()
+        );
+        if links {
+            *counts.calls.entry(pc).or_insert(0) += 1;
+        }
+
+        if let Instruction::Rv32iInstruction(
+            _,
+            Rv32iInstruction::Beq(branch)
+            | Rv32iInstruction::Bne(branch)
+            | Rv32iInstruction::Blt(branch)
+            | Rv32iInstruction::Bge(branch)
+            | Rv32iInstruction::Bltu(branch)
+            | Rv32iInstruction::Bgeu(branch),
+        ) = instruction
+        {
+            let address = instruction.address() as u32;
+            let target = address.wrapping_add(i32::from(branch.imm) as u32);
+            let taken = pc == target;
+            let mispredicted = counts.predictor.as_mut().is_some_and(|predictor| {
+                let predicted = predictor.predict(address, target);
+                predictor.update(address, taken);
+                predicted != taken
+            });
+            let count = counts.branches.entry(address).or_insert(BranchCount {
+                pc: address,
+                ..BranchCount::default()
+            });
+            count.executed += 1;
+            count.taken += u64::from(taken);
+            count.mispredicted += u64::from(mispredicted);
+        }
+
+        let next = (instruction.address() as u32).wrapping_add(4);
+        if instruction.ends_basic_block() || pc != next {
+            counts.current_block = None;
+        }
+    }
+
+    fn on_memory_read(&mut self, _pc: u32, _address: u32, size: u32, _value: u32) {
+        let memory = &mut self.0.borrow_mut().memory;
+        memory.loads += 1;
+        memory.load_bytes += u64::from(size);
+    }
+
+    fn on_memory_write(&mut self, _pc: u32, _address: u32, size: u32, _value: u32) {
+        let memory = &mut self.0.borrow_mut().memory;
+        memory.stores += 1;
+        memory.store_bytes += u64::from(size);
+    }
+}
+
+impl Vm {
+    /// starts profiling, the returned handle gives the report
+    pub fn add_profiler(&mut self) -> Profiler {
+        let profiler = Profiler(Rc::new(RefCell::new(Counts::default())));
+        self.add_hooks(Box::new(profiler.clone()));
+        profiler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCount, BranchCount, CallCount, MemoryCount, MnemonicCount};
+    use crate::branch_predictor::StaticPredictor;
+    use crate::profile::FunctionRange;
+    use crate::Vm;
+
+    #[test]
+    fn should_count_mnemonics_blocks_and_calls() {
+        // 0x1000 addi s0, zero, 3
+        // 0x1004 jal ra, 0x1014       <- loop
+        // 0x1008 addi s0, s0, -1
+        // 0x100c bne s0, zero, 0x1004
+        // 0x1010 ebreak
+        // 0x1014 addi a0, a0, 1       <- increment
+        // 0x1018 ret
+        let program: Vec<u8> = [
+            0x0030_0413u32,
+            0x0100_00ef,
+            0xfff4_0413,
+            0xfe04_1ce3,
+            0x0010_0073,
+            0x0015_0513,
+            0x0000_8067,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+
+        let profiler = vm.add_profiler();
+        profiler.set_branch_predictor(Box::new(StaticPredictor));
+        vm.run().unwrap();
+        let report = profiler.report(&[FunctionRange::new("increment", 0x1014, 0x101c)]);
+
+        assert_eq!(report.instructions, 16);
+        assert_eq!(
+            report.mnemonics[0],
+            MnemonicCount {
+                mnemonic: "addi".to_string(),
+                count: 7
+            }
+        );
+        assert_eq!(report.pcs.len(), 6);
+        assert_eq!(
+            report.blocks,
+            vec![
+                BlockCount {
+                    start: 0x1008,
+                    entries: 3,
+                    instructions: 6
+                },
+                BlockCount {
+                    start: 0x1014,
+                    entries: 3,
+                    instructions: 6
+                },
+                BlockCount {
+                    start: 0x1000,
+                    entries: 1,
+                    instructions: 2
+                },
+                BlockCount {
+                    start: 0x1004,
+                    entries: 2,
+                    instructions: 2
+                },
+            ]
+        );
+        assert_eq!(
+            report.calls,
+            vec![CallCount {
+                target: 0x1014,
+                function: Some("increment".to_string()),
+                count: 3
+            }]
+        );
+        // backward taken mispredicts the loop exit
+        assert_eq!(
+            report.branches,
+            vec![BranchCount {
+                pc: 0x100c,
+                executed: 3,
+                taken: 2,
+                mispredicted: 1
+            }]
+        );
+        assert!(report.to_json().contains("\"mnemonic\": \"bne\""));
+    }
+
+    #[test]
+    fn should_count_memory_bytes_and_rank_the_mnemonics() {
+        // 0x1000 addi t0, zero, 2
+        // 0x1004 sw t0, 0x80(zero)
+        // 0x1008 lb t1, 0x80(zero)
+        // 0x100c addi t0, t0, -1
+        // 0x1010 bne t0, zero, 0x1004
+        // 0x1014 ebreak
+        let program: Vec<u8> = [
+            0x0020_0293u32,
+            0x0850_2023,
+            0x0800_0303,
+            0xfff2_8293,
+            0xfe02_9ae3,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0, 0x2000);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.pc = 0x1000;
+
+        let profiler = vm.add_profiler();
+        vm.run().unwrap();
+        let report = profiler.report(&[]);
+        assert_eq!(
+            report.memory,
+            MemoryCount {
+                loads: 2,
+                load_bytes: 2,
+                stores: 2,
+                store_bytes: 8,
+            }
+        );
+        assert_eq!(
+            report.to_text(),
+            "\
+dynamic instructions            9
+static instructions             5
+loads                           2 (2 bytes)
+stores                          2 (8 bytes)
+branches                        2 (50.0% taken)
+
+rank  mnemonic         count   share  cumul.
+   1  addi                 3   33.3%   33.3%  #################
+   2  bne                  2   22.2%   55.6%  ###########
+   3  lb                   2   22.2%   77.8%  ###########
+   4  sw                   2   22.2%  100.0%  ###########
+"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! "Quiz mode" for learning RISC-V: a frontend shows the next instruction,
+//! the student predicts what it does to the registers, memory and pc, then
+//! the vm runs it and says where the prediction was wrong.
+
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt;
+
+use super::decompile::decompile;
+use super::emulator::{Instruction, Vm};
+use super::error::VmError;
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::register::Register;
+
+/// the instruction the student has to predict
+#[derive(Debug)]
+pub struct QuizQuestion {
+    pub pc: u32,
+    pub instruction: Instruction,
+    /// the instruction as C-like pseudocode, as a hint
+    pub pseudocode: String,
+}
+
+/// What an instruction does: the registers it writes, the memory bytes it
+/// writes and where the pc goes. Used for the prediction and for what
+/// really happened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Effects {
+    pub registers: BTreeMap<Register, i32>,
+    pub memory: BTreeMap<u32, u8>,
+    /// `None` in a prediction means the student did not predict the pc
+    pub pc: Option<u32>,
+}
+
+impl Effects {
+    /// predicts that `register` is written with `value`
+    pub fn register(mut self, register: Register, value: i32) -> Self {
+        self.registers.insert(register, value);
+        self
+    }
+
+    /// predicts that the byte at `address` is written with `value`
+    pub fn byte(mut self, address: u32, value: u8) -> Self {
+        self.memory.insert(address, value);
+        self
+    }
+
+    /// predicts that the 4 bytes at `address` are written with `value`
+    /// (little endian, like the guest stores it)
+    pub fn word(mut self, address: u32, value: u32) -> Self {
+        for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.memory
+                .insert(address.wrapping_add(offset as u32), byte);
+        }
+        self
+    }
+
+    /// predicts where the pc goes
+    pub fn pc(mut self, pc: u32) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+}
+
+/// one difference between the prediction and reality, `None` means "not
+/// written" (or not predicted to be written)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mistake {
+    Register {
+        register: Register,
+        predicted: Option<i32>,
+        actual: Option<i32>,
+    },
+    Memory {
+        address: u32,
+        predicted: Option<u8>,
+        actual: Option<u8>,
+    },
+    Pc {
+        predicted: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for Mistake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Register {
+                register,
+                predicted,
+                actual,
+            } => match (predicted, actual) {
+                (Some(predicted), Some(actual)) => {
+                    write!(f, "{register} is {actual}, not {predicted}")
+                }
+                (Some(_), None) => write!(f, "{register} is not written"),
+                (None, Some(actual)) => write!(f, "{register} is written with {actual}"),
+                (None, None) => Ok(()),
+            },
+            Self::Memory {
+                address,
+                predicted,
+                actual,
+            } => match (predicted, actual) {
+                (Some(predicted), Some(actual)) => write!(
+                    f,
+                    "the byte at {address:#010x} is {actual:#04x}, not {predicted:#04x}"
+                ),
+                (Some(_), None) => write!(f, "the byte at {address:#010x} is not written"),
+                (None, Some(actual)) => {
+                    write!(
+                        f,
+                        "the byte at {address:#010x} is written with {actual:#04x}"
+                    )
+                }
+                (None, None) => Ok(()),
+            },
+            Self::Pc { predicted, actual } => {
+                write!(f, "the pc goes to {actual:#010x}, not {predicted:#010x}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuizResult {
+    /// what the instruction really did
+    pub actual: Effects,
+    /// empty when the prediction was right
+    pub mistakes: Vec<Mistake>,
+}
+
+impl QuizResult {
+    pub fn is_correct(&self) -> bool {
+        self.mistakes.is_empty()
+    }
+}
+
+/// collects the register and memory writes of one step
+struct EffectRecorder(Rc<RefCell<Effects>>);
+
+impl VmHooks for EffectRecorder {
+    fn on_memory_write(&mut self, _pc: u32, address: u32, size: u32, value: u32) {
+        let mut effects = self.0.borrow_mut();
+        for (offset, byte) in value
+            .to_le_bytes()
+            .into_iter()
+            .take(size as usize)
+            .enumerate()
+        {
+            effects
+                .memory
+                .insert(address.wrapping_add(offset as u32), byte);
+        }
+    }
+
+    fn on_register_write(&mut self, _pc: u32, register: Register, value: i32) {
+        self.0.borrow_mut().registers.insert(register, value);
+    }
+}
+
+impl Vm {
+    /// the next instruction, for the student to predict
+    pub fn quiz_question(&self) -> Result<QuizQuestion, VmError> {
+        let instruction = self.fetch()?;
+        let pseudocode = decompile(core::slice::from_ref(&instruction));
+        let pseudocode = pseudocode
+            .split_once(":  ")
+            .map_or(pseudocode.as_str(), |(_, statement)| statement)
+            .trim_end()
+            .to_string();
+
+        Ok(QuizQuestion {
+            pc: self.vm_state.pc as u32,
+            instruction,
+            pseudocode,
+        })
+    }
+
+    /// executes the next instruction and compares what it did with the
+    /// `prediction`
+    pub fn quiz_answer(&mut self, prediction: &Effects) -> Result<QuizResult, VmError> {
+        let effects = Rc::new(RefCell::new(Effects::default()));
+        self.add_hooks(Box::new(EffectRecorder(effects.clone())));
+        let result = self.step();
+        self.hooks.pop();
+        result?;
+
+        let mut actual = effects.take();
+        actual.pc = Some(self.vm_state.pc as u32);
+
+        let mut mistakes = Vec::new();
+        for register in Register::all() {
+            let predicted = prediction.registers.get(&register).copied();
+            let written = actual.registers.get(&register).copied();
+            if predicted != written {
+                mistakes.push(Mistake::Register {
+                    register,
+                    predicted,
+                    actual: written,
+                });
+            }
+        }
+
+        let mut addresses: Vec<u32> = prediction.memory.keys().copied().collect();
+        addresses.extend(actual.memory.keys());
+        addresses.sort();
+        addresses.dedup();
+        for address in addresses {
+            let predicted = prediction.memory.get(&address).copied();
+            let written = actual.memory.get(&address).copied();
+            if predicted != written {
+                mistakes.push(Mistake::Memory {
+                    address,
+                    predicted,
+                    actual: written,
+                });
+            }
+        }
+
+        if let (Some(predicted), Some(actual_pc)) = (prediction.pc, actual.pc) {
+            if predicted != actual_pc {
+                mistakes.push(Mistake::Pc {
+                    predicted,
+                    actual: actual_pc,
+                });
+            }
+        }
+
+        Ok(QuizResult { actual, mistakes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Effects, Mistake};
+    use crate::{Register, Vm};
+
+    // 0x1000 addi a0, zero, 42
+    // 0x1004 sw a0, 0x80(t0)
+    const PROGRAM: [u32; 2] = [0x02a0_0513, 0x08a2_a023];
+
+    #[test]
+    fn right_prediction_should_have_no_mistakes() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.vm_state.registers.write(Register::T0, 0x1000);
+
+        let question = vm.quiz_question().unwrap();
+        assert_eq!(question.pc, 0x1000);
+        assert_eq!(question.pseudocode, "a0 = 42;");
+
+        let result = vm
+            .quiz_answer(&Effects::default().register(Register::A0, 42).pc(0x1004))
+            .unwrap();
+        assert!(result.is_correct());
+
+        let result = vm
+            .quiz_answer(&Effects::default().word(0x1080, 42))
+            .unwrap();
+        assert!(result.is_correct());
+        assert_eq!(result.actual.pc, Some(0x1008));
+    }
+
+    #[test]
+    fn wrong_prediction_should_list_the_differences() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.vm_state.registers.write(Register::T0, 0x1000);
+        vm.step().unwrap();
+
+        // the student thinks sw writes a0 into t0 and only writes one byte
+        let prediction = Effects::default()
+            .register(Register::T0, 42)
+            .byte(0x1080, 42)
+            .pc(0x1010);
+        let result = vm.quiz_answer(&prediction).unwrap();
+
+        assert_eq!(
+            result.mistakes,
+            vec![
+                Mistake::Register {
+                    register: Register::T0,
+                    predicted: Some(42),
+                    actual: None
+                },
+                Mistake::Memory {
+                    address: 0x1081,
+                    predicted: None,
+                    actual: Some(0)
+                },
+                Mistake::Memory {
+                    address: 0x1082,
+                    predicted: None,
+                    actual: Some(0)
+                },
+                Mistake::Memory {
+                    address: 0x1083,
+                    predicted: None,
+                    actual: Some(0)
+                },
+                Mistake::Pc {
+                    predicted: 0x1010,
+                    actual: 0x1008
+                },
+            ]
+        );
+        assert_eq!(result.mistakes[0].to_string(), "t0 is not written");
+        assert_eq!(
+            result.mistakes[4].to_string(),
+            "the pc goes to 0x00001008, not 0x00001010"
+        );
+    }
+}
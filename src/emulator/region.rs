@@ -0,0 +1,106 @@
+//! Reading a labeled part of guest memory after a run, e.g. the signature of
+//! the RISC-V architectural tests (`begin_signature` to `end_signature`) or
+//! a result buffer an autograder checks.
+
+use core::fmt::Write;
+
+use super::elf::ElfError;
+use super::emulator::Vm;
+use super::error::VmError;
+use super::prelude::*;
+
+/// a copy of guest memory from `start`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl MemoryRegion {
+    /// one little endian word per line as 8 hex digits, the format of the
+    /// architectural test signatures. A last partial word is padded with
+    /// zeros
+    pub fn to_hex_words(&self) -> String {
+        let mut hex = String::new();
+        for chunk in self.bytes.chunks(4) {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let _ = writeln!(hex, "{:08x}", u32::from_le_bytes(word));
+        }
+        hex
+    }
+
+    /// the raw bytes, to write to a .bin file
+    pub fn to_bin(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Vm {
+    /// copies the guest memory `start..end`
+    pub fn read_region(&self, start: u32, end: u32) -> Result<MemoryRegion, VmError> {
+        let length = end.saturating_sub(start) as usize;
+        Ok(MemoryRegion {
+            start,
+            bytes: self.memory.read_bytes(start, length)?.to_vec(),
+        })
+    }
+
+    /// copies the guest memory from the symbol `begin` up to the symbol
+    /// `end` of the loaded ELF file
+    pub fn read_region_by_symbols(&self, begin: &str, end: &str) -> Result<MemoryRegion, ElfError> {
+        let address = |name: &str| {
+            self.symbol(name)
+                .map(|symbol| symbol.address)
+                .ok_or_else(|| ElfError::SymbolNotFound {
+                    name: name.to_string(),
+                })
+        };
+        Ok(self.read_region(address(begin)?, address(end)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::{test_elf, ElfError};
+    use crate::Vm;
+
+    #[test]
+    fn should_read_the_signature_between_its_symbols() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 addi a0, zero, 42
+        // 0x1008 sw a0, 0x20(t0)
+        // 0x100c sw t0, 0x24(t0)
+        // 0x1010 ebreak
+        let code = [
+            0x0000_12b7,
+            0x02a0_0513,
+            0x02a2_a023,
+            0x0252_a223,
+            0x0010_0073,
+        ];
+        let elf = test_elf::build(
+            0x1000,
+            &code,
+            0x10,
+            &[("begin_signature", 0x1020), ("end_signature", 0x1028)],
+        );
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_elf(&elf).unwrap();
+        vm.run().unwrap();
+
+        let signature = vm
+            .read_region_by_symbols("begin_signature", "end_signature")
+            .unwrap();
+        assert_eq!(signature.start, 0x1020);
+        assert_eq!(signature.to_hex_words(), "0000002a\n00001000\n");
+        assert_eq!(signature.to_bin(), [42, 0, 0, 0, 0, 0x10, 0, 0]);
+
+        assert_eq!(
+            vm.read_region_by_symbols("begin_signature", "missing"),
+            Err(ElfError::SymbolNotFound {
+                name: "missing".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
+/// the ABI names of the 32 integer registers, x8 is both s0 and fp and is
+/// called s0 here
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// One of the 32 integer registers x0-x31. It can only be built from a valid
+/// index, so indexing the register file with it can not go out of bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Register(u8);
+
+impl Register {
+    pub const ZERO: Self = Self(0);
+    pub const RA: Self = Self(1);
+    pub const SP: Self = Self(2);
+    pub const GP: Self = Self(3);
+    pub const TP: Self = Self(4);
+    pub const T0: Self = Self(5);
+    pub const T1: Self = Self(6);
+    pub const T2: Self = Self(7);
+    pub const S0: Self = Self(8);
+    pub const S1: Self = Self(9);
+    pub const A0: Self = Self(10);
+    pub const A1: Self = Self(11);
+    pub const A2: Self = Self(12);
+    pub const A3: Self = Self(13);
+    pub const A4: Self = Self(14);
+    pub const A5: Self = Self(15);
+    pub const A6: Self = Self(16);
+    pub const A7: Self = Self(17);
+    pub const S2: Self = Self(18);
+    pub const S3: Self = Self(19);
+    pub const S4: Self = Self(20);
+    pub const S5: Self = Self(21);
+    pub const S6: Self = Self(22);
+    pub const S7: Self = Self(23);
+    pub const S8: Self = Self(24);
+    pub const S9: Self = Self(25);
+    pub const S10: Self = Self(26);
+    pub const S11: Self = Self(27);
+    pub const T3: Self = Self(28);
+    pub const T4: Self = Self(29);
+    pub const T5: Self = Self(30);
+    pub const T6: Self = Self(31);
+
+    /// register x`index`, `None` if the index is not 0..=31
+    pub const fn new(index: u8) -> Option<Self> {
+        if index < 32 {
+            Some(Self(index))
+        } else {
+            None
+        }
+    }
+
+    /// the register in a 5 bit field of an instruction, the upper bits are
+    /// ignored
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits & 0b1_1111)
+    }
+
+    /// parses an ABI name (`a0`, `fp`, ...) or an `x<n>` name
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "fp" {
+            return Some(Self::S0);
+        }
+        if let Some(index) = name.strip_prefix('x') {
+            return index.parse().ok().and_then(Self::new);
+        }
+
+        ABI_NAMES
+            .iter()
+            .position(|abi_name| *abi_name == name)
+            .map(|index| Self(index as u8))
+    }
+
+    /// the index in the register file
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// x0, writes to it are thrown away
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn abi_name(self) -> &'static str {
+        ABI_NAMES[self.0 as usize]
+    }
+
+    /// a0-a7 as 0-7, `None` for the other registers
+    pub const fn argument_index(self) -> Option<u8> {
+        if self.0 >= 10 && self.0 <= 17 {
+            Some(self.0 - 10)
+        } else {
+            None
+        }
+    }
+
+    /// all 32 registers, x0 first
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..32).map(Self)
+    }
+}
+
+/// prints the ABI name, `{:#}` prints the `x<n>` name
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "x{}", self.0)
+        } else {
+            f.write_str(self.abi_name())
+        }
+    }
+}
+
+impl<T> Index<Register> for [T; 32] {
+    type Output = T;
+
+    fn index(&self, register: Register) -> &T {
+        &self[register.index()]
+    }
+}
+
+impl<T> IndexMut<Register> for [T; 32] {
+    fn index_mut(&mut self, register: Register) -> &mut T {
+        &mut self[register.index()]
+    }
+}
+
+/// the 32 integer registers of a hart. x0 is hardwired to zero here and
+/// nowhere else: `write` throws writes to it away and it always reads 0, so
+/// an instruction can not forget the rule. There is no `IndexMut`, writes
+/// go through `write`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterFile([i32; 32]);
+
+impl RegisterFile {
+    pub fn read(&self, register: Register) -> i32 {
+        self.0[register]
+    }
+
+    /// sets `register`, unless it is x0
+    pub fn write(&mut self, register: Register, value: i32) {
+        if !register.is_zero() {
+            self.0[register] = value;
+        }
+    }
+
+    /// x0 to x31
+    pub fn as_array(&self) -> &[i32; 32] {
+        &self.0
+    }
+
+    /// for native code that loads and stores the registers itself, it must
+    /// never store x0
+    #[cfg(feature = "jit")]
+    pub(super) fn as_mut_ptr(&mut self) -> *mut i32 {
+        self.0.as_mut_ptr()
+    }
+}
+
+/// x0 is set to 0, whatever `registers[0]` is
+impl From<[i32; 32]> for RegisterFile {
+    fn from(mut registers: [i32; 32]) -> Self {
+        registers[0] = 0;
+        Self(registers)
+    }
+}
+
+impl Index<Register> for RegisterFile {
+    type Output = i32;
+
+    fn index(&self, register: Register) -> &i32 {
+        &self.0[register]
+    }
+}
+
+/// `registers[10]` reads a0
+impl Index<usize> for RegisterFile {
+    type Output = i32;
+
+    fn index(&self, index: usize) -> &i32 {
+        &self.0[index]
+    }
+}
+
+impl PartialEq<[i32; 32]> for RegisterFile {
+    fn eq(&self, other: &[i32; 32]) -> bool {
+        self.0 == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Register, RegisterFile};
+
+    #[test]
+    fn should_only_accept_valid_indices() {
+        assert_eq!(Register::new(31), Some(Register::T6));
+        assert_eq!(Register::new(32), None);
+        assert_eq!(Register::from_bits(0b10_1010), Register::A0);
+    }
+
+    #[test]
+    fn should_convert_between_names_and_registers() {
+        assert_eq!(Register::from_name("a0"), Some(Register::A0));
+        assert_eq!(Register::from_name("fp"), Some(Register::S0));
+        assert_eq!(Register::from_name("x2"), Some(Register::SP));
+        assert_eq!(Register::from_name("x32"), None);
+        assert_eq!(Register::from_name("a8"), None);
+
+        assert_eq!(Register::A7.to_string(), "a7");
+        assert_eq!(format!("{:#}", Register::A7), "x17");
+
+        let mut registers = [0; 32];
+        registers[Register::SP] = 0x1000;
+        assert_eq!(registers[2], 0x1000);
+    }
+
+    #[test]
+    fn x0_should_always_read_zero() {
+        let mut registers = RegisterFile::from([7; 32]);
+        assert_eq!(registers.read(Register::ZERO), 0);
+
+        registers.write(Register::ZERO, 5);
+        registers.write(Register::A0, 5);
+        assert_eq!(registers[Register::ZERO], 0);
+        assert_eq!(registers[10], 5);
+    }
+}
@@ -0,0 +1,268 @@
+//! Record and replay of what comes into the vm from outside, to reproduce a
+//! run from a bug report bit for bit. While recording, the vm logs every
+//! input the guest could see differently next time, with the instruction
+//! count (`stats.instructions_retired`) it came at:
+//!
+//! - the PLIC lines the host raises and clears (`Vm::raise_irq`),
+//! - the values of loads from memory-mapped devices (UART RX, the input
+//!   device, ...), not the CLINT or the PLIC, those are part of the vm,
+//! - the guest's reads of the time (`rdtime`, `mtime`) with the host clock.
+//!
+//! Replaying feeds the log back instead: device loads return the logged
+//! values without asking the device (stores still go to it), the lines go
+//! up and down before the same instructions, and the host's own
+//! `raise_irq`/`clear_irq` calls are ignored.
+//!
+//! Everything else is deterministic already: the cycle clock (the default,
+//! the only one timer interrupts replay exactly with), stdin of the syscalls
+//! is always at its end, and `AT_RANDOM` points to fixed bytes. The input
+//! queues of SBI, semihosting and HTIF are not logged, fill them before the
+//! run when replaying.
+
+use serde::{Deserialize, Serialize};
+
+use super::emulator::Vm;
+use super::prelude::*;
+
+/// one input, `at` is the number of instructions retired before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReplayEvent {
+    /// the host raised or cleared the PLIC `line`
+    Irq { at: u64, line: u32, raised: bool },
+    /// a load from a device at `address` read `value`
+    DeviceRead { at: u64, address: u32, value: u32 },
+    /// the guest read the time of the host clock
+    Time { at: u64, value: u64 },
+}
+
+impl ReplayEvent {
+    pub fn at(&self) -> u64 {
+        match self {
+            Self::Irq { at, .. } | Self::DeviceRead { at, .. } | Self::Time { at, .. } => *at,
+        }
+    }
+}
+
+/// the inputs of a run in the order they came, JSON for bug reports
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayLog {
+    pub events: Vec<ReplayEvent>,
+}
+
+impl ReplayLog {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("the log is plain data")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// whether the vm records or replays
+#[derive(Debug, Clone)]
+pub enum Replay {
+    Recording(ReplayLog),
+    Replaying {
+        log: ReplayLog,
+        /// the next event
+        next: usize,
+        /// where the guest first asked for something else than the log has
+        diverged: Option<u64>,
+    },
+}
+
+impl Vm {
+    /// logs the inputs from now on, `finish_recording` hands them over
+    pub fn start_recording(&mut self) {
+        self.replay = Some(Replay::Recording(ReplayLog::default()));
+    }
+
+    /// stops recording and returns the log, `None` if the vm did not record
+    pub fn finish_recording(&mut self) -> Option<ReplayLog> {
+        match self.replay.take() {
+            Some(Replay::Recording(log)) => Some(log),
+            replay => {
+                self.replay = replay;
+                None
+            }
+        }
+    }
+
+    /// takes the inputs from `log` from now on. Start it on a vm set up like
+    /// the recorded one, with the same program and devices
+    pub fn start_replay(&mut self, log: ReplayLog) {
+        self.replay = Some(Replay::Replaying {
+            log,
+            next: 0,
+            diverged: None,
+        });
+    }
+
+    /// the instruction count where the guest stopped following the log, it
+    /// gets the live inputs from there on
+    pub fn replay_diverged(&self) -> Option<u64> {
+        match &self.replay {
+            Some(Replay::Replaying { diverged, .. }) => *diverged,
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_replaying(&self) -> bool {
+        matches!(self.replay, Some(Replay::Replaying { .. }))
+    }
+
+    pub(super) fn record(&mut self, event: ReplayEvent) {
+        if let Some(Replay::Recording(log)) = &mut self.replay {
+            log.events.push(event);
+        }
+    }
+
+    /// the value of the next event if `value` picks it, otherwise the
+    /// replay diverged here and this is `None`
+    pub(super) fn replayed(
+        &mut self,
+        value: impl FnOnce(&ReplayEvent) -> Option<u64>,
+    ) -> Option<u64> {
+        let at = self.stats.instructions_retired;
+        let Some(Replay::Replaying {
+            log,
+            next,
+            diverged,
+        }) = &mut self.replay
+        else {
+            return None;
+        };
+        let replayed = log.events.get(*next).and_then(value);
+        match replayed {
+            Some(_) => *next += 1,
+            None => *diverged = diverged.or(Some(at)),
+        }
+        replayed
+    }
+
+    /// raises and clears the lines the log has for this instruction
+    pub(super) fn replay_irqs(&mut self) {
+        let at = self.stats.instructions_retired;
+        while let Some(Replay::Replaying { log, next, .. }) = &mut self.replay {
+            let Some(&ReplayEvent::Irq {
+                at: irq_at,
+                line,
+                raised,
+            }) = log.events.get(*next)
+            else {
+                return;
+            };
+            if irq_at > at {
+                return;
+            }
+            *next += 1;
+            self.set_irq(line, raised);
+        }
+    }
+
+    /// when the next line goes up or down, so the run loop does not run
+    /// past it in one go
+    pub(super) fn next_replayed_irq(&self) -> Option<u64> {
+        let Some(Replay::Replaying { log, next, .. }) = &self.replay else {
+            return None;
+        };
+        match log.events.get(*next)? {
+            ReplayEvent::Irq { at, .. } => Some(*at),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayEvent, ReplayLog};
+    use crate::uart::{Uart, UART_BASE, UART_SIZE};
+    use crate::{StopReason, Vm};
+
+    /// a vm with a PLIC and a UART running `program`
+    fn uart_vm(program: &[u32]) -> Vm {
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_plic();
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device(UART_BASE, UART_SIZE, Box::new(Uart::default()));
+        vm
+    }
+
+    #[test]
+    fn should_replay_device_reads_and_interrupts() {
+        // 0x1000 auipc t0, 0
+        // 0x1004 addi t0, t0, 72        <- handler at 0x1048
+        // 0x1008 csrrw zero, mtvec, t0
+        // 0x100c lui t0, 0xc000
+        // 0x1010 addi t1, zero, 1
+        // 0x1014 sw t1, 4(t0)           <- priority of line 1
+        // 0x1018 lui t2, 0x2
+        // 0x101c add t2, t2, t0
+        // 0x1020 addi t1, zero, 2
+        // 0x1024 sw t1, 0(t2)           <- enable line 1 in context 0
+        // 0x1028 lui t1, 0x1
+        // 0x102c srli t1, t1, 1
+        // 0x1030 csrrs zero, mie, t1    <- MEIE
+        // 0x1034 csrrsi zero, mstatus, 8
+        // 0x1038 lui t0, 0x10000
+        // 0x103c lbu a0, 0(t0)          <- UART RX
+        // 0x1040 jal zero, 0
+        // 0x1044 addi zero, zero, 0
+        // 0x1048 lbu a1, 0(t0)
+        // 0x104c ebreak
+        let program = [
+            0x0000_0297,
+            0x0482_8293,
+            0x3052_9073,
+            0x0c00_02b7,
+            0x0010_0313,
+            0x0062_a223,
+            0x0000_23b7,
+            0x0053_83b3,
+            0x0020_0313,
+            0x0063_a023,
+            0x0000_1337,
+            0x0013_5313,
+            0x3043_2073,
+            0x3004_6073,
+            0x1000_02b7,
+            0x0002_c503,
+            0x0000_006f,
+            0x0000_0013,
+            0x0002_c583,
+            0x0010_0073,
+        ];
+        let mut vm = uart_vm(&program);
+        let uart = vm.device_mut::<Uart>(UART_BASE).unwrap();
+        uart.input.extend(*b"xy");
+        vm.start_recording();
+        assert_eq!(vm.run_for(100), Ok(StopReason::Preempted { pc: 0x1040 }));
+        assert!(vm.raise_irq(1));
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        let log = vm.finish_recording().unwrap();
+        assert_eq!(
+            log.events[1],
+            ReplayEvent::Irq {
+                at: 100,
+                line: 1,
+                raised: true,
+            }
+        );
+        let log = ReplayLog::from_json(&log.to_json()).unwrap();
+
+        // no input and no host: it all comes from the log
+        let mut replayed = uart_vm(&program);
+        replayed.start_replay(log);
+        assert_eq!(replayed.run(), Ok(StopReason::Ebreak));
+        assert_eq!(replayed.replay_diverged(), None);
+        assert_eq!(
+            replayed.vm_state.registers.as_array(),
+            vm.vm_state.registers.as_array()
+        );
+        assert_eq!(replayed.csrs.mepc, 0x1040);
+        assert_eq!(replayed.vm_state.registers[10], i32::from(b'x'));
+        assert_eq!(replayed.vm_state.registers[11], i32::from(b'y'));
+    }
+}
@@ -1,10 +1,19 @@
 use super::emulator::VmState;
-use super::instruction_formats::{InstructionFormat, InstructionFormatR};
+use super::error::VmError;
+use super::instruction_formats::{
+    InstructionFormat, InstructionFormatB, InstructionFormatI, InstructionFormatJ,
+    InstructionFormatR, InstructionFormatS, InstructionFormatU,
+};
 use super::instruction_signatures::{
-    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2, Source1Source2Immediate,
+    DestinationImmediate, DestinationSource1Immediate, DestinationSource1Source2,
+    Source1Source2Immediate,
 };
+use super::memory::{Memory, MemoryAccess};
+use super::plugin::CustomOpcode;
+use super::register::Register;
+use super::vector::VectorInstruction;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Rv32iInstruction {
     // regulars
     /// ADD
@@ -62,11 +71,11 @@ pub enum Rv32iInstruction {
 
     // storing
     /// Store Byte
-    Sb(DestinationSource1Immediate),
+    Sb(Source1Source2Immediate),
     /// Store Half Word
-    Sh(DestinationSource1Immediate),
+    Sh(Source1Source2Immediate),
     /// Store Word
-    Sw(DestinationSource1Immediate),
+    Sw(Source1Source2Immediate),
 
     // branching
     /// Branch Equal (==)
@@ -96,6 +105,57 @@ pub enum Rv32iInstruction {
     Ecall,
     /// Environment Break
     Ebreak,
+    /// Machine Return from a trap
+    Mret,
+    /// Supervisor Return from a trap
+    Sret,
+    /// Wait For Interrupt, what the vm does meanwhile is its `WfiPolicy`
+    Wfi,
+    /// Fence, `imm` is fm, pred and succ. Every access is in order in this vm
+    Fence(DestinationSource1Immediate),
+    /// Fence Instruction stream, makes the vm see code written by stores
+    FenceI,
+    /// Supervisor Fence of the Virtual Memory, drops the translations of the
+    /// address in rs1 and the ASID in rs2 (all of them for x0), see `mmu.rs`
+    SfenceVma(DestinationSource1Source2),
+
+    /// the part of the V extension in `vector.rs`
+    Vector(VectorInstruction),
+
+    /// the raw word of an instruction in a custom opcode space, a plugin
+    /// executes it, see `plugin.rs`
+    Custom(u32),
+
+    // Zicsr, `imm` is the CSR number. The vm executes these itself since the
+    // CSRs are not in the vm state, see `csr.rs`
+    /// CSR Read and Write
+    Csrrw(DestinationSource1Immediate),
+    /// CSR Read and Set
+    Csrrs(DestinationSource1Immediate),
+    /// CSR Read and Clear
+    Csrrc(DestinationSource1Immediate),
+    /// CSR Read and Write Immediate, the 5 bit immediate is in `rs1`
+    Csrrwi(DestinationSource1Immediate),
+    /// CSR Read and Set Immediate, the 5 bit immediate is in `rs1`
+    Csrrsi(DestinationSource1Immediate),
+    /// CSR Read and Clear Immediate, the 5 bit immediate is in `rs1`
+    Csrrci(DestinationSource1Immediate),
+
+    // the A extension, the address is in `rs1`. The aq and rl bits are
+    // ignored, every instruction is atomic and ordered in this vm anyway
+    /// Load Reserved Word, `rs2` is zero
+    LrW(DestinationSource1Source2),
+    /// Store Conditional Word, rd is 0 if the store happened
+    ScW(DestinationSource1Source2),
+    AmoswapW(DestinationSource1Source2),
+    AmoaddW(DestinationSource1Source2),
+    AmoxorW(DestinationSource1Source2),
+    AmoandW(DestinationSource1Source2),
+    AmoorW(DestinationSource1Source2),
+    AmominW(DestinationSource1Source2),
+    AmomaxW(DestinationSource1Source2),
+    AmominuW(DestinationSource1Source2),
+    AmomaxuW(DestinationSource1Source2),
 }
 
 /// the implementations of the instructions for RV32I are in this block
@@ -105,48 +165,650 @@ impl Rv32iInstruction {
         destination_source1_source2: &DestinationSource1Source2,
         vm_state: &mut VmState,
     ) {
-        if destination_source1_source2.rd == 0 {
-            return;
+        let sum = vm_state.registers[destination_source1_source2.rs1]
+            .wrapping_add(vm_state.registers[destination_source1_source2.rs2]);
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, sum);
+    }
+
+    /// Implements the sub instruction
+    pub fn rv32i_instruction_sub(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let difference = vm_state.registers[destination_source1_source2.rs1]
+            .wrapping_sub(vm_state.registers[destination_source1_source2.rs2]);
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, difference);
+    }
+
+    /// Implements the xor instruction
+    pub fn rv32i_instruction_xor(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_source2.rs1]
+            ^ vm_state.registers[destination_source1_source2.rs2];
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result);
+    }
+
+    /// Implements the or instruction
+    pub fn rv32i_instruction_or(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_source2.rs1]
+            | vm_state.registers[destination_source1_source2.rs2];
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result);
+    }
+
+    /// Implements the and instruction
+    pub fn rv32i_instruction_and(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_source2.rs1]
+            & vm_state.registers[destination_source1_source2.rs2];
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result);
+    }
+
+    /// Implements the sll instruction, only the lowest 5 bits of rs2 are the
+    /// shift amount
+    pub fn rv32i_instruction_sll(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = vm_state.registers[destination_source1_source2.rs2] as u32 & 0x1f;
+        let result = vm_state.registers[destination_source1_source2.rs1] << shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result);
+    }
+
+    /// Implements the srl instruction, zeros are shifted in
+    pub fn rv32i_instruction_srl(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = vm_state.registers[destination_source1_source2.rs2] as u32 & 0x1f;
+        let result = (vm_state.registers[destination_source1_source2.rs1] as u32) >> shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result as i32);
+    }
+
+    /// Implements the sra instruction, the sign bit is shifted in
+    pub fn rv32i_instruction_sra(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = vm_state.registers[destination_source1_source2.rs2] as u32 & 0x1f;
+        let result = vm_state.registers[destination_source1_source2.rs1] >> shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, result);
+    }
+
+    /// Implements the slt instruction (signed compare)
+    pub fn rv32i_instruction_slt(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let is_less = vm_state.registers[destination_source1_source2.rs1]
+            < vm_state.registers[destination_source1_source2.rs2];
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, is_less as i32);
+    }
+
+    /// Implements the sltu instruction (unsigned compare)
+    pub fn rv32i_instruction_sltu(
+        destination_source1_source2: &DestinationSource1Source2,
+        vm_state: &mut VmState,
+    ) {
+        let is_less = (vm_state.registers[destination_source1_source2.rs1] as u32)
+            < (vm_state.registers[destination_source1_source2.rs2] as u32);
+        vm_state
+            .registers
+            .write(destination_source1_source2.rd, is_less as i32);
+    }
+
+    /// Implements the addi instruction
+    pub fn rv32i_instruction_addi(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let sum = vm_state.registers[destination_source1_immediate.rs1]
+            .wrapping_add(destination_source1_immediate.imm as i32);
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, sum);
+    }
+
+    /// Implements the xori instruction
+    pub fn rv32i_instruction_xori(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_immediate.rs1]
+            ^ destination_source1_immediate.imm as i32;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result);
+    }
+
+    /// Implements the ori instruction
+    pub fn rv32i_instruction_ori(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_immediate.rs1]
+            | destination_source1_immediate.imm as i32;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result);
+    }
+
+    /// Implements the andi instruction
+    pub fn rv32i_instruction_andi(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let result = vm_state.registers[destination_source1_immediate.rs1]
+            & destination_source1_immediate.imm as i32;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result);
+    }
+
+    /// Implements the slli instruction, imm is the shift amount
+    pub fn rv32i_instruction_slli(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = destination_source1_immediate.imm as u32 & 0x1f;
+        let result = vm_state.registers[destination_source1_immediate.rs1] << shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result);
+    }
+
+    /// Implements the srli instruction, imm is the shift amount
+    pub fn rv32i_instruction_srli(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = destination_source1_immediate.imm as u32 & 0x1f;
+        let result = (vm_state.registers[destination_source1_immediate.rs1] as u32) >> shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result as i32);
+    }
+
+    /// Implements the srai instruction, imm is the shift amount
+    pub fn rv32i_instruction_srai(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let shift_amount = destination_source1_immediate.imm as u32 & 0x1f;
+        let result = vm_state.registers[destination_source1_immediate.rs1] >> shift_amount;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, result);
+    }
+
+    /// Implements the slti instruction (signed compare)
+    pub fn rv32i_instruction_slti(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let is_less = vm_state.registers[destination_source1_immediate.rs1]
+            < destination_source1_immediate.imm as i32;
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, is_less as i32);
+    }
+
+    /// Implements the sltiu instruction. The immediate is sign extended first
+    /// and then compared as unsigned, so `sltiu rd, rs1, -1` is true for
+    /// everything but 0xffff_ffff
+    pub fn rv32i_instruction_sltiu(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+    ) {
+        let is_less = (vm_state.registers[destination_source1_immediate.rs1] as u32)
+            < (destination_source1_immediate.imm as i32 as u32);
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, is_less as i32);
+    }
+
+    /// Implements the lui instruction, imm holds the upper 20 bits
+    pub fn rv32i_instruction_lui(
+        destination_immediate: &DestinationImmediate,
+        vm_state: &mut VmState,
+    ) {
+        vm_state
+            .registers
+            .write(destination_immediate.rd, destination_immediate.imm << 12);
+    }
+
+    /// Implements the auipc instruction, the pc is the address of the auipc
+    /// itself
+    pub fn rv32i_instruction_auipc(
+        destination_immediate: &DestinationImmediate,
+        vm_state: &mut VmState,
+    ) {
+        vm_state.registers.write(
+            destination_immediate.rd,
+            vm_state.pc.wrapping_add(destination_immediate.imm << 12),
+        );
+    }
+
+    /// Implements the lb instruction, the byte is sign extended
+    pub fn rv32i_instruction_lb(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::load(destination_source1_immediate, vm_state, memory, 1, true)
+    }
+
+    /// Implements the lh instruction, the half word is sign extended
+    pub fn rv32i_instruction_lh(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::load(destination_source1_immediate, vm_state, memory, 2, true)
+    }
+
+    /// Implements the lw instruction
+    pub fn rv32i_instruction_lw(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::load(destination_source1_immediate, vm_state, memory, 4, true)
+    }
+
+    /// Implements the lbu instruction, the byte is zero extended
+    pub fn rv32i_instruction_lbu(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::load(destination_source1_immediate, vm_state, memory, 1, false)
+    }
+
+    /// Implements the lhu instruction, the half word is zero extended
+    pub fn rv32i_instruction_lhu(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::load(destination_source1_immediate, vm_state, memory, 2, false)
+    }
+
+    /// Implements the sb instruction, the lowest byte of rs2 is stored
+    pub fn rv32i_instruction_sb(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &VmState,
+        memory: &mut Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::store(source1_source2_immediate, vm_state, memory, 1)
+    }
+
+    /// Implements the sh instruction, the lower half of rs2 is stored
+    pub fn rv32i_instruction_sh(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &VmState,
+        memory: &mut Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::store(source1_source2_immediate, vm_state, memory, 2)
+    }
+
+    /// Implements the sw instruction
+    pub fn rv32i_instruction_sw(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &VmState,
+        memory: &mut Memory,
+    ) -> Result<MemoryAccess, VmError> {
+        Self::store(source1_source2_immediate, vm_state, memory, 4)
+    }
+
+    /// reads `size` bytes at rs1 + imm into rd
+    fn load(
+        destination_source1_immediate: &DestinationSource1Immediate,
+        vm_state: &mut VmState,
+        memory: &Memory,
+        size: u32,
+        signed: bool,
+    ) -> Result<MemoryAccess, VmError> {
+        let address = vm_state.registers[destination_source1_immediate.rs1]
+            .wrapping_add(destination_source1_immediate.imm as i32) as u32;
+
+        let (value, extended) = match size {
+            1 => {
+                let value = memory.read_u8(address)?;
+                (
+                    value as u32,
+                    if signed {
+                        value as i8 as i32
+                    } else {
+                        value as i32
+                    },
+                )
+            }
+            2 => {
+                let value = memory.read_u16(address)?;
+                (
+                    value as u32,
+                    if signed {
+                        value as i16 as i32
+                    } else {
+                        value as i32
+                    },
+                )
+            }
+            _ => {
+                let value = memory.read_u32(address)?;
+                (value, value as i32)
+            }
+        };
+
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, extended);
+
+        Ok(MemoryAccess::Read {
+            address,
+            size,
+            value,
+        })
+    }
+
+    /// writes the lowest `size` bytes of rs2 to rs1 + imm
+    fn store(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &VmState,
+        memory: &mut Memory,
+        size: u32,
+    ) -> Result<MemoryAccess, VmError> {
+        let address = vm_state.registers[source1_source2_immediate.rs1]
+            .wrapping_add(source1_source2_immediate.imm as i32) as u32;
+        let value = vm_state.registers[source1_source2_immediate.rs2] as u32;
+
+        let value = match size {
+            1 => {
+                memory.write_u8(address, value as u8)?;
+                value & 0xff
+            }
+            2 => {
+                memory.write_u16(address, value as u16)?;
+                value & 0xffff
+            }
+            _ => {
+                memory.write_u32(address, value)?;
+                value
+            }
+        };
+
+        Ok(MemoryAccess::Write {
+            address,
+            size,
+            value,
+        })
+    }
+
+    /// the assembler name, e.g. `addi`
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Add(_) => "add",
+            Self::Sub(_) => "sub",
+            Self::Xor(_) => "xor",
+            Self::Or(_) => "or",
+            Self::And(_) => "and",
+            Self::Sll(_) => "sll",
+            Self::Srl(_) => "srl",
+            Self::Sra(_) => "sra",
+            Self::Slt(_) => "slt",
+            Self::Sltu(_) => "sltu",
+            Self::Addi(_) => "addi",
+            Self::Xori(_) => "xori",
+            Self::Ori(_) => "ori",
+            Self::Andi(_) => "andi",
+            Self::Slli(_) => "slli",
+            Self::Srli(_) => "srli",
+            Self::Srai(_) => "srai",
+            Self::Slti(_) => "slti",
+            Self::Sltiu(_) => "sltiu",
+            Self::Lb(_) => "lb",
+            Self::Lh(_) => "lh",
+            Self::Lw(_) => "lw",
+            Self::Lbu(_) => "lbu",
+            Self::Lhu(_) => "lhu",
+            Self::Sb(_) => "sb",
+            Self::Sh(_) => "sh",
+            Self::Sw(_) => "sw",
+            Self::Beq(_) => "beq",
+            Self::Bne(_) => "bne",
+            Self::Blt(_) => "blt",
+            Self::Bge(_) => "bge",
+            Self::Bltu(_) => "bltu",
+            Self::Bgeu(_) => "bgeu",
+            Self::Jal(_) => "jal",
+            Self::Jalr(_) => "jalr",
+            Self::Lui(_) => "lui",
+            Self::Auipc(_) => "auipc",
+            Self::Ecall => "ecall",
+            Self::Ebreak => "ebreak",
+            Self::Mret => "mret",
+            Self::Sret => "sret",
+            Self::Wfi => "wfi",
+            Self::Fence(_) => "fence",
+            Self::FenceI => "fence.i",
+            Self::SfenceVma(_) => "sfence.vma",
+            Self::Vector(vector) => vector.mnemonic(),
+            Self::Custom(_) => ".insn",
+            Self::Csrrw(_) => "csrrw",
+            Self::Csrrs(_) => "csrrs",
+            Self::Csrrc(_) => "csrrc",
+            Self::Csrrwi(_) => "csrrwi",
+            Self::Csrrsi(_) => "csrrsi",
+            Self::Csrrci(_) => "csrrci",
+            Self::LrW(_) => "lr.w",
+            Self::ScW(_) => "sc.w",
+            Self::AmoswapW(_) => "amoswap.w",
+            Self::AmoaddW(_) => "amoadd.w",
+            Self::AmoxorW(_) => "amoxor.w",
+            Self::AmoandW(_) => "amoand.w",
+            Self::AmoorW(_) => "amoor.w",
+            Self::AmominW(_) => "amomin.w",
+            Self::AmomaxW(_) => "amomax.w",
+            Self::AmominuW(_) => "amominu.w",
+            Self::AmomaxuW(_) => "amomaxu.w",
         }
+    }
 
-        let sum = vm_state.registers[destination_source1_source2.rs1 as usize]
-            .wrapping_add(vm_state.registers[destination_source1_source2.rs2 as usize]);
-        vm_state.registers[destination_source1_source2.rd as usize] = sum;
+    /// whether it is one of the A extension instructions, the vm executes
+    /// those since they need the reservations, see `atomic.rs`
+    pub fn is_atomic(&self) -> bool {
+        matches!(
+            self,
+            Self::LrW(_)
+                | Self::ScW(_)
+                | Self::AmoswapW(_)
+                | Self::AmoaddW(_)
+                | Self::AmoxorW(_)
+                | Self::AmoandW(_)
+                | Self::AmoorW(_)
+                | Self::AmominW(_)
+                | Self::AmomaxW(_)
+                | Self::AmominuW(_)
+                | Self::AmomaxuW(_)
+        )
     }
 
-    pub fn rv32i_instruction_bltu(
+    /// executes the loads and stores, they need the memory on top of the vm
+    /// state. Moves the pc to the next instruction when the access worked.
+    /// Returns `None` for every other instruction.
+    pub fn execute_memory_instruction(
+        &self,
+        vm_state: &mut VmState,
+        memory: &mut Memory,
+    ) -> Option<Result<MemoryAccess, VmError>> {
+        let result = match self {
+            Self::Lb(signature) => Self::rv32i_instruction_lb(signature, vm_state, memory),
+            Self::Lh(signature) => Self::rv32i_instruction_lh(signature, vm_state, memory),
+            Self::Lw(signature) => Self::rv32i_instruction_lw(signature, vm_state, memory),
+            Self::Lbu(signature) => Self::rv32i_instruction_lbu(signature, vm_state, memory),
+            Self::Lhu(signature) => Self::rv32i_instruction_lhu(signature, vm_state, memory),
+            Self::Sb(signature) => Self::rv32i_instruction_sb(signature, vm_state, memory),
+            Self::Sh(signature) => Self::rv32i_instruction_sh(signature, vm_state, memory),
+            Self::Sw(signature) => Self::rv32i_instruction_sw(signature, vm_state, memory),
+            _ => return None,
+        };
+
+        if result.is_ok() {
+            vm_state.pc = vm_state.pc.wrapping_add(4);
+        }
+
+        Some(result)
+    }
+
+    /// moves the pc to the branch target when `taken`. The offset is relative
+    /// to the branch instruction itself, which is where the pc still points.
+    /// Returns `taken` so the caller knows not to go to the next instruction.
+    fn take_branch(
         source1_source2_immediate: &Source1Source2Immediate,
         vm_state: &mut VmState,
-    ) {
-        if source1_source2_immediate.rs1 < source1_source2_immediate.rs2 {
-            vm_state.pc += source1_source2_immediate.imm as i32;
+        taken: bool,
+    ) -> bool {
+        if taken {
+            vm_state.pc = vm_state
+                .pc
+                .wrapping_add(source1_source2_immediate.imm as i32);
         }
+
+        taken
+    }
+
+    /// Implements the beq instruction
+    pub fn rv32i_instruction_beq(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1];
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2];
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 == rs2)
+    }
+
+    /// Implements the bne instruction
+    pub fn rv32i_instruction_bne(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1];
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2];
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 != rs2)
+    }
+
+    /// Implements the blt instruction (signed compare)
+    pub fn rv32i_instruction_blt(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1];
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2];
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 < rs2)
+    }
+
+    /// Implements the bge instruction (signed compare)
+    pub fn rv32i_instruction_bge(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1];
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2];
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 >= rs2)
+    }
+
+    /// Implements the bltu instruction (unsigned compare)
+    pub fn rv32i_instruction_bltu(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1] as u32;
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2] as u32;
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 < rs2)
+    }
+
+    /// Implements the bgeu instruction (unsigned compare)
+    pub fn rv32i_instruction_bgeu(
+        source1_source2_immediate: &Source1Source2Immediate,
+        vm_state: &mut VmState,
+    ) -> bool {
+        let rs1 = vm_state.registers[source1_source2_immediate.rs1] as u32;
+        let rs2 = vm_state.registers[source1_source2_immediate.rs2] as u32;
+
+        Self::take_branch(source1_source2_immediate, vm_state, rs1 >= rs2)
     }
 
+    /// Implements the jal instruction. The address of the next instruction
+    /// (the return address) goes into rd and the pc moves by the offset,
+    /// relative to the jal itself. `jal ra, f` is a call, `jal zero, l` a
+    /// plain jump.
     pub fn rv32i_instruction_jal(
         destination_immediate: &DestinationImmediate,
         vm_state: &mut VmState,
     ) {
-        // if source1_source2_immediate.rs1 < source1_source2_immediate.rs2 {
-        //     vm_state.pc += source1_source2_immediate.imm as i32;
-        // }
-        vm_state.pc = destination_immediate.imm as i32;
-        unimplemented!()
+        let return_address = vm_state.pc.wrapping_add(4);
+        vm_state.pc = vm_state.pc.wrapping_add(destination_immediate.imm);
+
+        vm_state
+            .registers
+            .write(destination_immediate.rd, return_address);
     }
 
+    /// Implements the jalr instruction. The target is rs1 + imm with the
+    /// lowest bit cleared, and the return address goes into rd. rs1 is read
+    /// before rd is written, so `jalr ra, 0(ra)` works. `ret` is
+    /// `jalr zero, 0(ra)`.
     pub fn rv32i_instruction_jalr(
         destination_source1_immediate: &DestinationSource1Immediate,
         vm_state: &mut VmState,
     ) {
-        // if source1_source2_immediate.rs1 < source1_source2_immediate.rs2 {
-        //     vm_state.pc += source1_source2_immediate.imm as i32;
-        // }
+        let return_address = vm_state.pc.wrapping_add(4);
+        let target = vm_state.registers[destination_source1_immediate.rs1]
+            .wrapping_add(destination_source1_immediate.imm as i32)
+            & !1;
+        vm_state.pc = target;
 
-        unimplemented!()
+        vm_state
+            .registers
+            .write(destination_source1_immediate.rd, return_address);
     }
 
     /// 4 * 8bits = 32bits
-    pub fn from_core_instruction_format(instruction: [u8; 4]) -> Self {
+    ///
+    /// decodes the raw instruction (little endian, as it is in memory) into
+    /// the RV32I instruction. Anything that is not RV32I is an
+    /// `IllegalInstruction`.
+    pub fn from_core_instruction_format(instruction: [u8; 4]) -> Result<Self, VmError> {
         // turn [u8; 4] to opcode
         let opcode = InstructionFormat::get_opcode_from_instruction(instruction);
 
@@ -155,13 +817,480 @@ impl Rv32iInstruction {
 
         // try to cast to that RV32I
         let instruction_as_u32 = u32::from_le_bytes(instruction);
+        // the decoder does not know the pc, the vm fills it in
+        let illegal_instruction = VmError::IllegalInstruction {
+            pc: 0,
+            instruction: instruction_as_u32,
+        };
+
+        let rv32i_instruction = match instruction_format {
+            InstructionFormat::R => {
+                let format_r = InstructionFormatR::new(instruction_as_u32);
+                let signature = DestinationSource1Source2 {
+                    rd: Register::from_bits(format_r.rd),
+                    rs1: Register::from_bits(format_r.rs1),
+                    rs2: Register::from_bits(format_r.rs2),
+                };
+
+                match (format_r.opcode, format_r.funct3, format_r.funct7) {
+                    (0x33, 0b000, 0b000_0000) => Self::Add(signature),
+                    (0x33, 0b000, 0b010_0000) => Self::Sub(signature),
+                    (0x33, 0b001, 0b000_0000) => Self::Sll(signature),
+                    (0x33, 0b010, 0b000_0000) => Self::Slt(signature),
+                    (0x33, 0b011, 0b000_0000) => Self::Sltu(signature),
+                    (0x33, 0b100, 0b000_0000) => Self::Xor(signature),
+                    (0x33, 0b101, 0b000_0000) => Self::Srl(signature),
+                    (0x33, 0b101, 0b010_0000) => Self::Sra(signature),
+                    (0x33, 0b110, 0b000_0000) => Self::Or(signature),
+                    (0x33, 0b111, 0b000_0000) => Self::And(signature),
+                    // funct7 is funct5 and the aq and rl bits
+                    (0x2f, 0b010, funct7) => match funct7 >> 2 {
+                        0b00010 if format_r.rs2 == 0 => Self::LrW(signature),
+                        0b00011 => Self::ScW(signature),
+                        0b00001 => Self::AmoswapW(signature),
+                        0b00000 => Self::AmoaddW(signature),
+                        0b00100 => Self::AmoxorW(signature),
+                        0b01100 => Self::AmoandW(signature),
+                        0b01000 => Self::AmoorW(signature),
+                        0b10000 => Self::AmominW(signature),
+                        0b10100 => Self::AmomaxW(signature),
+                        0b11000 => Self::AmominuW(signature),
+                        0b11100 => Self::AmomaxuW(signature),
+                        _ => return Err(illegal_instruction),
+                    },
+                    _ => return Err(illegal_instruction),
+                }
+            }
+            InstructionFormat::I => {
+                let format_i = InstructionFormatI::new(instruction_as_u32);
+                let imm = format_i.immediate();
+                // for the shifts the upper 7 bits of the immediate work like funct7
+                // and only the lower 5 bits are the shift amount
+                let shift_funct7 = format_i.imm >> 5;
+                let signature = DestinationSource1Immediate {
+                    rd: Register::from_bits(format_i.rd),
+                    rs1: Register::from_bits(format_i.rs1),
+                    imm: imm as i16,
+                };
+                let shift_signature = DestinationSource1Immediate {
+                    imm: imm as i16 & 0x1f,
+                    ..signature
+                };
+
+                match (format_i.opcode, format_i.funct3) {
+                    (0x13, 0b000) => Self::Addi(signature),
+                    (0x13, 0b010) => Self::Slti(signature),
+                    (0x13, 0b011) => Self::Sltiu(signature),
+                    (0x13, 0b100) => Self::Xori(signature),
+                    (0x13, 0b110) => Self::Ori(signature),
+                    (0x13, 0b111) => Self::Andi(signature),
+                    (0x13, 0b001) if shift_funct7 == 0b000_0000 => Self::Slli(shift_signature),
+                    (0x13, 0b101) if shift_funct7 == 0b000_0000 => Self::Srli(shift_signature),
+                    (0x13, 0b101) if shift_funct7 == 0b010_0000 => Self::Srai(shift_signature),
+                    (0x03, 0b000) => Self::Lb(signature),
+                    (0x03, 0b001) => Self::Lh(signature),
+                    (0x03, 0b010) => Self::Lw(signature),
+                    (0x03, 0b100) => Self::Lbu(signature),
+                    (0x03, 0b101) => Self::Lhu(signature),
+                    (0x67, 0b000) => Self::Jalr(signature),
+                    (0x0f, 0b000) => Self::Fence(DestinationSource1Immediate {
+                        imm: format_i.imm as i16,
+                        ..signature
+                    }),
+                    (0x0f, 0b001) => Self::FenceI,
+                    // funct7 is the upper bits of the immediate, rs2 the lower 5
+                    (0x73, 0b000) if format_i.rd == 0 && shift_funct7 == 0b000_1001 => {
+                        Self::SfenceVma(DestinationSource1Source2 {
+                            rd: Register::ZERO,
+                            rs1: signature.rs1,
+                            rs2: Register::from_bits((format_i.imm & 0x1f) as u8),
+                        })
+                    }
+                    (0x73, 0b000) if format_i.rd == 0 && format_i.rs1 == 0 => match format_i.imm {
+                        0 => Self::Ecall,
+                        1 => Self::Ebreak,
+                        0x302 => Self::Mret,
+                        0x102 => Self::Sret,
+                        0x105 => Self::Wfi,
+                        _ => return Err(illegal_instruction),
+                    },
+                    (0x73, funct3 @ (0b001..=0b011 | 0b101..=0b111)) => {
+                        let csr = DestinationSource1Immediate {
+                            imm: format_i.imm as i16,
+                            ..signature
+                        };
+                        match funct3 {
+                            0b001 => Self::Csrrw(csr),
+                            0b010 => Self::Csrrs(csr),
+                            0b011 => Self::Csrrc(csr),
+                            0b101 => Self::Csrrwi(csr),
+                            0b110 => Self::Csrrsi(csr),
+                            _ => Self::Csrrci(csr),
+                        }
+                    }
+                    _ => return Err(illegal_instruction),
+                }
+            }
+            InstructionFormat::S => {
+                let format_s = InstructionFormatS::new(instruction_as_u32);
+                let signature = Source1Source2Immediate {
+                    rs1: Register::from_bits(format_s.rs1),
+                    rs2: Register::from_bits(format_s.rs2),
+                    imm: format_s.immediate() as i16,
+                };
+
+                match format_s.func3 {
+                    0b000 => Self::Sb(signature),
+                    0b001 => Self::Sh(signature),
+                    0b010 => Self::Sw(signature),
+                    _ => return Err(illegal_instruction),
+                }
+            }
+            InstructionFormat::B => {
+                let format_b = InstructionFormatB::new(instruction_as_u32);
+                let signature = Source1Source2Immediate {
+                    rs1: Register::from_bits(format_b.rs1),
+                    rs2: Register::from_bits(format_b.rs2),
+                    imm: format_b.immediate() as i16,
+                };
+
+                match format_b.func3 {
+                    0b000 => Self::Beq(signature),
+                    0b001 => Self::Bne(signature),
+                    0b100 => Self::Blt(signature),
+                    0b101 => Self::Bge(signature),
+                    0b110 => Self::Bltu(signature),
+                    0b111 => Self::Bgeu(signature),
+                    _ => return Err(illegal_instruction),
+                }
+            }
+            InstructionFormat::U => {
+                let format_u = InstructionFormatU::new(instruction_as_u32);
+                let signature = DestinationImmediate {
+                    rd: Register::from_bits(format_u.rd),
+                    imm: format_u.immediate(),
+                };
+
+                match format_u.opcode {
+                    0x37 => Self::Lui(signature),
+                    0x17 => Self::Auipc(signature),
+                    _ => return Err(illegal_instruction),
+                }
+            }
+            InstructionFormat::J => {
+                let format_j = InstructionFormatJ::new(instruction_as_u32);
+
+                Self::Jal(DestinationImmediate {
+                    rd: Register::from_bits(format_j.rd),
+                    imm: format_j.immediate(),
+                })
+            }
+            InstructionFormat::Vector | InstructionFormat::Unknown => {
+                match VectorInstruction::decode(instruction_as_u32) {
+                    Some(vector) => Self::Vector(vector),
+                    None if CustomOpcode::of(instruction_as_u32).is_some() => {
+                        Self::Custom(instruction_as_u32)
+                    }
+                    None => return Err(illegal_instruction),
+                }
+            }
+        };
+
+        Ok(rv32i_instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::emulator::{Instruction, PseudoInstruction, VmState};
+    use super::super::instruction_signatures::{
+        DestinationImmediate, DestinationSource1Immediate, Source1Source2Immediate,
+    };
+    use super::super::register::Register;
+    use super::Rv32iInstruction;
+
+    type BranchVariant = fn(Source1Source2Immediate) -> Rv32iInstruction;
+
+    /// a branch at 0x1000 comparing x5 and x6
+    fn branch(variant: BranchVariant, imm: i16) -> Instruction {
+        Instruction::Rv32iInstruction(
+            0x1000,
+            variant(Source1Source2Immediate {
+                rs1: Register::T0,
+                rs2: Register::T1,
+                imm,
+            }),
+        )
+    }
+
+    #[test]
+    fn branches_should_compare_register_values() {
+        // (name, instruction, x5, x6, taken)
+        let cases: [(&str, BranchVariant, i32, i32, bool); 20] = [
+            ("beq equal", Rv32iInstruction::Beq, 7, 7, true),
+            ("beq not equal", Rv32iInstruction::Beq, 7, 8, false),
+            ("bne equal", Rv32iInstruction::Bne, 7, 7, false),
+            ("bne not equal", Rv32iInstruction::Bne, 7, 8, true),
+            ("blt less", Rv32iInstruction::Blt, 1, 2, true),
+            ("blt equal", Rv32iInstruction::Blt, 2, 2, false),
+            ("blt negative is less", Rv32iInstruction::Blt, -1, 1, true),
+            ("blt greater", Rv32iInstruction::Blt, 3, -3, false),
+            ("bge greater", Rv32iInstruction::Bge, 3, -3, true),
+            ("bge equal", Rv32iInstruction::Bge, -3, -3, true),
+            ("bge less", Rv32iInstruction::Bge, -4, -3, false),
+            ("bltu less", Rv32iInstruction::Bltu, 1, 2, true),
+            ("bltu equal", Rv32iInstruction::Bltu, 2, 2, false),
+            // -1 is 0xffff_ffff, the biggest unsigned number
+            ("bltu negative is big", Rv32iInstruction::Bltu, -1, 1, false),
+            (
+                "bltu positive below negative",
+                Rv32iInstruction::Bltu,
+                1,
+                -1,
+                true,
+            ),
+            ("bltu greater", Rv32iInstruction::Bltu, 10, 5, false),
+            ("bgeu greater", Rv32iInstruction::Bgeu, 10, 5, true),
+            ("bgeu equal", Rv32iInstruction::Bgeu, 5, 5, true),
+            ("bgeu negative is big", Rv32iInstruction::Bgeu, -1, 1, true),
+            ("bgeu less", Rv32iInstruction::Bgeu, 1, -1, false),
+        ];
+
+        for (name, variant, x5, x6, taken) in cases {
+            for imm in [16, -16] {
+                let mut vm_state = VmState {
+                    pc: 0x1000,
+                    ..VmState::default()
+                };
+                vm_state.registers.write(Register::T0, x5);
+                vm_state.registers.write(Register::T1, x6);
+
+                branch(variant, imm)
+                    .execute_instruction(&mut vm_state)
+                    .unwrap();
+
+                let expected_pc = if taken { 0x1000 + imm as i32 } else { 0x1004 };
+                assert_eq!(vm_state.pc, expected_pc, "{name} with offset {imm}");
+                // branches never write registers
+                assert_eq!(vm_state.registers[5], x5, "{name}");
+                assert_eq!(vm_state.registers[6], x6, "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn branch_to_itself_should_not_move_on() {
+        let mut vm_state = VmState {
+            pc: 0x1000,
+            ..VmState::default()
+        };
+
+        branch(Rv32iInstruction::Beq, 0)
+            .execute_instruction(&mut vm_state)
+            .unwrap();
+
+        assert_eq!(vm_state.pc, 0x1000);
+    }
+
+    #[test]
+    fn jal_should_link_and_jump_relative_to_its_own_pc() {
+        let mut vm_state = VmState {
+            pc: 0x1010,
+            ..VmState::default()
+        };
+
+        // jal ra, -16
+        Instruction::Rv32iInstruction(
+            0x1010,
+            Rv32iInstruction::Jal(DestinationImmediate {
+                rd: Register::RA,
+                imm: -16,
+            }),
+        )
+        .execute_instruction(&mut vm_state)
+        .unwrap();
+
+        assert_eq!(vm_state.pc, 0x1000);
+        assert_eq!(vm_state.registers[1], 0x1014);
 
-        let casted_instruction_maybe = match instruction_format {
-            InstructionFormat::R => Some(InstructionFormatR::new(instruction_as_u32)),
-            _ => None,
+        // j +8 does not link anything, x0 stays 0
+        Instruction::Rv32iInstruction(
+            0x1000,
+            Rv32iInstruction::Jal(DestinationImmediate {
+                rd: Register::ZERO,
+                imm: 8,
+            }),
+        )
+        .execute_instruction(&mut vm_state)
+        .unwrap();
+
+        assert_eq!(vm_state.pc, 0x1008);
+        assert_eq!(vm_state.registers[0], 0);
+    }
+
+    #[test]
+    fn jalr_should_clear_bit_0_and_read_rs1_before_writing_rd() {
+        let mut vm_state = VmState {
+            pc: 0x1000,
+            ..VmState::default()
+        };
+        vm_state.registers.write(Register::T0, 0x2001);
+
+        // jalr t0, 0x10(t0)
+        Instruction::Rv32iInstruction(
+            0x1000,
+            Rv32iInstruction::Jalr(DestinationSource1Immediate {
+                rd: Register::T0,
+                rs1: Register::T0,
+                imm: 0x10,
+            }),
+        )
+        .execute_instruction(&mut vm_state)
+        .unwrap();
+
+        // 0x2011 with bit 0 cleared
+        assert_eq!(vm_state.pc, 0x2010);
+        assert_eq!(vm_state.registers[5], 0x1004);
+    }
+
+    #[test]
+    fn ret_should_jump_to_ra() {
+        let mut vm_state = VmState {
+            pc: 0x1000,
+            ..VmState::default()
         };
+        vm_state.registers.write(Register::RA, 0x4000);
+
+        Instruction::PseudoInstruction(0x1000, PseudoInstruction::Ret)
+            .execute_instruction(&mut vm_state)
+            .unwrap();
+
+        assert_eq!(vm_state.pc, 0x4000);
+        assert_eq!(vm_state.registers[1], 0x4000);
+    }
+
+    #[test]
+    fn decoded_bltu_should_branch_relative_to_its_own_pc() {
+        // bltu a0, a3, +6 from the sum_2_number example, at 0x1004
+        let bltu =
+            Rv32iInstruction::from_core_instruction_format(0x00d5_6363u32.to_le_bytes()).unwrap();
+        let instruction = Instruction::Rv32iInstruction(0x1004, bltu);
+
+        // a0 = 5 < a3 = 10 -> taken
+        let mut vm_state = VmState {
+            pc: 0x1004,
+            ..VmState::default()
+        };
+        vm_state.registers.write(Register::A0, 5);
+        vm_state.registers.write(Register::A3, 10);
+        instruction.execute_instruction(&mut vm_state).unwrap();
+        assert_eq!(vm_state.pc, 0x100a);
+
+        // a0 = 50 >= a3 = 10 -> not taken
+        vm_state.pc = 0x1004;
+        vm_state.registers.write(Register::A0, 50);
+        instruction.execute_instruction(&mut vm_state).unwrap();
+        assert_eq!(vm_state.pc, 0x1008);
+    }
+}
+
+/// the arithmetic for random operands, beyond the hand-picked cases above
+#[cfg(test)]
+mod properties {
+    use proptest::prelude::*;
+
+    use super::super::emulator::{Instruction, VmState};
+    use super::super::instruction_signatures::{
+        DestinationSource1Immediate, DestinationSource1Source2,
+    };
+    use super::super::register::Register;
+    use super::Rv32iInstruction;
+
+    type RegisterVariant = fn(DestinationSource1Source2) -> Rv32iInstruction;
+    type ImmediateVariant = fn(DestinationSource1Immediate) -> Rv32iInstruction;
+
+    /// a0 after `variant a0, t0, t1` with t0 = `a` and t1 = `b`
+    fn register_op(variant: RegisterVariant, a: i32, b: i32) -> i32 {
+        let mut vm_state = VmState::default();
+        vm_state.registers.write(Register::T0, a);
+        vm_state.registers.write(Register::T1, b);
+        let instruction = variant(DestinationSource1Source2 {
+            rd: Register::A0,
+            rs1: Register::T0,
+            rs2: Register::T1,
+        });
+        Instruction::Rv32iInstruction(0, instruction)
+            .execute_instruction(&mut vm_state)
+            .unwrap();
+        vm_state.registers[Register::A0]
+    }
+
+    /// a0 after `variant a0, t0, imm` with t0 = `a`
+    fn immediate_op(variant: ImmediateVariant, a: i32, imm: i16) -> i32 {
+        let mut vm_state = VmState::default();
+        vm_state.registers.write(Register::T0, a);
+        let instruction = variant(DestinationSource1Immediate {
+            rd: Register::A0,
+            rs1: Register::T0,
+            imm,
+        });
+        Instruction::Rv32iInstruction(0, instruction)
+            .execute_instruction(&mut vm_state)
+            .unwrap();
+        vm_state.registers[Register::A0]
+    }
 
-        // Return
-        unimplemented!()
+    proptest! {
+        #[test]
+        fn add_and_sub_should_wrap(a: i32, b: i32) {
+            let sum = register_op(Rv32iInstruction::Add, a, b);
+            prop_assert_eq!(sum, a.wrapping_add(b));
+            prop_assert_eq!(sum, register_op(Rv32iInstruction::Add, b, a));
+            prop_assert_eq!(register_op(Rv32iInstruction::Sub, sum, b), a);
+            prop_assert_eq!(register_op(Rv32iInstruction::Sub, a, a), 0);
+        }
+
+        #[test]
+        fn slt_and_sltu_should_agree_unless_the_signs_differ(a: i32, b: i32) {
+            let slt = register_op(Rv32iInstruction::Slt, a, b);
+            let sltu = register_op(Rv32iInstruction::Sltu, a, b);
+            prop_assert_eq!(slt, (a < b) as i32);
+            prop_assert_eq!(sltu, ((a as u32) < (b as u32)) as i32);
+            if (a < 0) == (b < 0) {
+                prop_assert_eq!(slt, sltu);
+            } else {
+                prop_assert_ne!(slt, sltu);
+            }
+        }
+
+        #[test]
+        fn shifts_should_only_use_the_low_5_bits_of_the_amount(a: i32, amount: i32) {
+            let shamt = (amount & 0x1f) as u32;
+            let cases: [(RegisterVariant, ImmediateVariant, i32); 3] = [
+                (Rv32iInstruction::Sll, Rv32iInstruction::Slli, a << shamt),
+                (Rv32iInstruction::Srl, Rv32iInstruction::Srli, ((a as u32) >> shamt) as i32),
+                (Rv32iInstruction::Sra, Rv32iInstruction::Srai, a >> shamt),
+            ];
+            for (register_variant, immediate_variant, expected) in cases {
+                prop_assert_eq!(register_op(register_variant, a, amount), expected);
+                prop_assert_eq!(immediate_op(immediate_variant, a, shamt as i16), expected);
+            }
+        }
+
+        #[test]
+        fn addi_should_sign_extend_its_12_bit_immediate(a: i32, imm in 0u32..0x1000) {
+            // addi a0, t0, imm
+            let word = imm << 20 | 5 << 15 | 10 << 7 | 0x13;
+            let addi = Rv32iInstruction::from_core_instruction_format(word.to_le_bytes()).unwrap();
+            let mut vm_state = VmState::default();
+            vm_state.registers.write(Register::T0, a);
+            Instruction::Rv32iInstruction(0, addi)
+                .execute_instruction(&mut vm_state)
+                .unwrap();
+
+            let extended = ((imm << 20) as i32) >> 20;
+            prop_assert!((-2048..2048).contains(&extended));
+            prop_assert_eq!(vm_state.registers[Register::A0], a.wrapping_add(extended));
+        }
     }
 }
@@ -0,0 +1,283 @@
+//! A minimal SBI, the interface supervisor-mode kernels use to call the
+//! firmware, so payloads like xv6 or a small Linux run without OpenSBI. The
+//! call is an `ecall` with the extension id in a7 and the function id in a6;
+//! the error goes to a0 and the value to a1. The legacy extensions only
+//! return a0.
+//!
+//! Supported: the base extension, the legacy console, timer and shutdown
+//! calls, TIME, HSM (starting and stopping harts, see `smp.rs`) and SRST.
+//! Other extension ids go on to the host ecall handlers.
+
+use alloc::collections::VecDeque;
+
+use super::csr::MIP_STIP;
+use super::emulator::{StopReason, Vm};
+use super::prelude::*;
+use super::register::Register;
+
+pub const SBI_LEGACY_SET_TIMER: u32 = 0x00;
+pub const SBI_LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+pub const SBI_LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+pub const SBI_LEGACY_SHUTDOWN: u32 = 0x08;
+pub const SBI_EXT_BASE: u32 = 0x10;
+pub const SBI_EXT_TIME: u32 = 0x5449_4d45;
+pub const SBI_EXT_HSM: u32 = 0x0048_534d;
+pub const SBI_EXT_SRST: u32 = 0x5352_5354;
+
+pub const SBI_SUCCESS: i32 = 0;
+pub const SBI_ERR_NOT_SUPPORTED: i32 = -2;
+pub const SBI_ERR_INVALID_PARAM: i32 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i32 = -6;
+
+/// SBI 2.0
+const SPEC_VERSION: i32 = 2 << 24;
+/// not one of the registered implementation ids
+const IMPL_ID: i32 = 0x7276;
+
+/// the HSM state of a hart, the values are what `hart_get_status` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartStatus {
+    Started = 0,
+    Stopped = 1,
+}
+
+/// a `hart_start` the scheduler in `smp.rs` still has to carry out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HartStart {
+    pub hart: usize,
+    pub address: u32,
+    pub opaque: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sbi {
+    /// what the guest wrote to the console
+    pub output: Vec<u8>,
+    /// what `console_getchar` returns, -1 once it is empty
+    pub input: VecDeque<u8>,
+    /// when the supervisor timer interrupt becomes pending in `mip`
+    pub timer: Option<u64>,
+    pub(super) harts: Vec<HartStatus>,
+    pub(super) starts: Vec<HartStart>,
+}
+
+impl Default for Sbi {
+    fn default() -> Self {
+        Self {
+            output: Vec::new(),
+            input: VecDeque::new(),
+            timer: None,
+            harts: vec![HartStatus::Started],
+            starts: Vec::new(),
+        }
+    }
+}
+
+impl Sbi {
+    pub fn status(&self, hart: usize) -> Option<HartStatus> {
+        self.harts.get(hart).copied()
+    }
+}
+
+impl Vm {
+    /// answers SBI calls on the host, see `sbi.rs`
+    pub fn with_sbi(mut self) -> Self {
+        self.sbi = Some(Sbi::default());
+        self
+    }
+
+    /// handles the SBI call in a7 and moves past the ecall, returns false if
+    /// there is no SBI or it does not know the extension
+    pub(super) fn sbi_call(&mut self, extension: u32) -> bool {
+        let Some(sbi) = &mut self.sbi else {
+            return false;
+        };
+        let registers = &mut self.vm_state.registers;
+        let [a0, a1, a2] = [Register::A0, Register::A1, Register::A2].map(|r| registers[r] as u32);
+        let function = registers[Register::A6] as u32;
+        let hart = self.csrs.mhartid as usize;
+
+        // the legacy calls return only a0
+        let legacy = match extension {
+            SBI_LEGACY_SET_TIMER => {
+                sbi.timer = Some(u64::from(a1) << 32 | u64::from(a0));
+                self.csrs.mip &= !MIP_STIP;
+                Some(SBI_SUCCESS)
+            }
+            SBI_LEGACY_CONSOLE_PUTCHAR => {
+                sbi.output.push(a0 as u8);
+                Some(SBI_SUCCESS)
+            }
+            SBI_LEGACY_CONSOLE_GETCHAR => Some(sbi.input.pop_front().map_or(-1, i32::from)),
+            SBI_LEGACY_SHUTDOWN => {
+                self.stop = Some(StopReason::Shutdown);
+                Some(SBI_SUCCESS)
+            }
+            _ => None,
+        };
+        if let Some(result) = legacy {
+            registers.write(Register::A0, result);
+            self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+            return true;
+        }
+
+        let (error, value) = match (extension, function) {
+            (SBI_EXT_BASE, 0) => (SBI_SUCCESS, SPEC_VERSION),
+            (SBI_EXT_BASE, 1) => (SBI_SUCCESS, IMPL_ID),
+            (SBI_EXT_BASE, 2) => (SBI_SUCCESS, 1),
+            (SBI_EXT_BASE, 3) => {
+                let supported = matches!(
+                    a0,
+                    SBI_LEGACY_SET_TIMER
+                        | SBI_LEGACY_CONSOLE_PUTCHAR
+                        | SBI_LEGACY_CONSOLE_GETCHAR
+                        | SBI_LEGACY_SHUTDOWN
+                        | SBI_EXT_BASE
+                        | SBI_EXT_TIME
+                        | SBI_EXT_HSM
+                        | SBI_EXT_SRST
+                );
+                (SBI_SUCCESS, supported as i32)
+            }
+            // mvendorid, marchid and mimpid
+            (SBI_EXT_BASE, 4..=6) => (SBI_SUCCESS, 0),
+            (SBI_EXT_TIME, 0) => {
+                sbi.timer = Some(u64::from(a1) << 32 | u64::from(a0));
+                self.csrs.mip &= !MIP_STIP;
+                (SBI_SUCCESS, 0)
+            }
+            (SBI_EXT_HSM, 0) => match sbi.harts.get(a0 as usize) {
+                None => (SBI_ERR_INVALID_PARAM, 0),
+                Some(HartStatus::Started) => (SBI_ERR_ALREADY_AVAILABLE, 0),
+                Some(HartStatus::Stopped) => {
+                    sbi.harts[a0 as usize] = HartStatus::Started;
+                    sbi.starts.push(HartStart {
+                        hart: a0 as usize,
+                        address: a1,
+                        opaque: a2,
+                    });
+                    (SBI_SUCCESS, 0)
+                }
+            },
+            (SBI_EXT_HSM, 1) => {
+                if let Some(status) = sbi.harts.get_mut(hart) {
+                    *status = HartStatus::Stopped;
+                }
+                self.stop = Some(StopReason::HartStopped {
+                    pc: self.vm_state.pc as u32,
+                });
+                (SBI_SUCCESS, 0)
+            }
+            (SBI_EXT_HSM, 2) => match sbi.harts.get(a0 as usize) {
+                Some(status) => (SBI_SUCCESS, *status as i32),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            },
+            // shutdown, cold and warm reboot all end the run
+            (SBI_EXT_SRST, 0) if a0 <= 2 => {
+                self.stop = Some(StopReason::Shutdown);
+                (SBI_SUCCESS, 0)
+            }
+            (SBI_EXT_SRST, 0) => (SBI_ERR_INVALID_PARAM, 0),
+            (SBI_EXT_BASE | SBI_EXT_TIME | SBI_EXT_HSM | SBI_EXT_SRST, _) => {
+                (SBI_ERR_NOT_SUPPORTED, 0)
+            }
+            _ => return false,
+        };
+        registers.write(Register::A0, error);
+        registers.write(Register::A1, value);
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        true
+    }
+
+    /// makes the timer interrupt pending in `mip` once the time set with
+    /// `set_timer` has come
+    pub(super) fn tick_sbi_timer(&mut self) {
+        let now = self.time();
+        if let Some(sbi) = &mut self.sbi {
+            if sbi.timer.is_some_and(|timer| now >= timer) {
+                sbi.timer = None;
+                self.csrs.mip |= MIP_STIP;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SBI_SUCCESS;
+    use crate::csr::MIP_STIP;
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_print_read_and_shut_down() {
+        // 0x1000 addi a7, zero, 2       getchar
+        // 0x1004 ecall
+        // 0x1008 addi a7, zero, 1       putchar
+        // 0x100c ecall
+        // 0x1010 addi a7, zero, 8       shutdown
+        // 0x1014 ecall
+        // 0x1018 ebreak
+        let mut vm = Vm::with_program_words(
+            0x1000,
+            &[
+                0x0020_0893,
+                0x0000_0073,
+                0x0010_0893,
+                0x0000_0073,
+                0x0080_0893,
+                0x0000_0073,
+                0x0010_0073,
+            ],
+        )
+        .with_sbi();
+        vm.sbi.as_mut().unwrap().input.push_back(b'x');
+
+        assert_eq!(vm.run(), Ok(StopReason::Shutdown));
+        assert_eq!(vm.vm_state.pc, 0x1018);
+        assert_eq!(vm.sbi.unwrap().output, b"x");
+    }
+
+    #[test]
+    fn should_probe_extensions_and_fire_the_timer() {
+        // 0x1000 lui a7, 0x10          base
+        // 0x1004 srli a7, a7, 12
+        // 0x1008 addi a6, zero, 3      probe_extension
+        // 0x100c addi a0, zero, 1      the console
+        // 0x1010 ecall
+        // 0x1014 addi a7, zero, 0      legacy set_timer
+        // 0x1018 addi a0, zero, 20
+        // 0x101c addi a1, zero, 0
+        // 0x1020 ecall
+        // 0x1024 nop                   <- loop
+        // 0x1028 jal zero, 0x1024
+        let mut vm = Vm::with_program_words(
+            0x1000,
+            &[
+                0x0001_08b7,
+                0x00c8_d893,
+                0x0030_0813,
+                0x0010_0513,
+                0x0000_0073,
+                0x0000_0893,
+                0x0140_0513,
+                0x0000_0593,
+                0x0000_0073,
+                0x0000_0013,
+                0xffdf_f06f,
+            ],
+        )
+        .with_sbi();
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.vm_state.registers[Register::A0], SBI_SUCCESS);
+        assert_eq!(vm.vm_state.registers[Register::A1], 1);
+
+        vm.execution_limit = Some(19);
+        assert!(vm.run().is_err());
+        assert_eq!(vm.csrs.mip & MIP_STIP, 0);
+        vm.execution_limit = Some(20);
+        assert!(vm.run().is_err());
+        assert_ne!(vm.csrs.mip & MIP_STIP, 0);
+    }
+}
@@ -0,0 +1,285 @@
+//! RISC-V semihosting, the console and files of embedded firmware (the
+//! `semihosting` and `riscv-semihosting` crates, newlib's librdimon). A call
+//! is an `ebreak` between two marker instructions:
+//!
+//! ```text
+//! slli zero, zero, 0x1f
+//! ebreak
+//! srai zero, zero, 7
+//! ```
+//!
+//! The operation is in a0, a1 points to its parameter block (for
+//! `SYS_WRITEC` and `SYS_WRITE0` to the character or the string, for
+//! `SYS_EXIT` it is the reason itself) and the result goes to a0. An
+//! `ebreak` without the markers still stops `run()` with `Ebreak`.
+//!
+//! Supported: `SYS_OPEN` (`:tt` is the console), `SYS_CLOSE`, `SYS_WRITEC`,
+//! `SYS_WRITE0`, `SYS_WRITE`, `SYS_READ`, `SYS_ISTTY`, `SYS_SEEK`,
+//! `SYS_FLEN`, `SYS_EXIT` and `SYS_EXIT_EXTENDED`; the exits stop `run()`
+//! with `Exit`. The files come from a `FileSystem` like the Linux syscalls'.
+//! Other operations return -1.
+
+use alloc::collections::VecDeque;
+
+use super::emulator::{StopReason, Vm};
+use super::fs::{FileHandle, FileSystem, SeekFrom};
+use super::prelude::*;
+use super::register::Register;
+use super::strace::read_path;
+use super::syscalls::{O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0a;
+pub const SYS_FLEN: u32 = 0x0c;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// the reason of a normal `SYS_EXIT`, any other reason exits with 1
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+
+/// `slli zero, zero, 0x1f`
+const ENTRY_MARKER: u32 = 0x01f0_1013;
+/// `srai zero, zero, 7`
+const EXIT_MARKER: u32 = 0x4070_5013;
+
+/// what a handle stands for
+enum Handle {
+    Stdin,
+    Stdout,
+    File(Box<dyn FileHandle>),
+}
+
+#[derive(Default)]
+pub struct Semihosting {
+    /// what the guest wrote to the console
+    pub output: Vec<u8>,
+    /// what the guest reads from `:tt`
+    pub input: VecDeque<u8>,
+    /// where `SYS_OPEN` looks, `None` only opens `:tt`
+    pub fs: Option<Box<dyn FileSystem>>,
+    /// the open handles, from 1 up
+    handles: HashMap<u32, Handle>,
+}
+
+impl Semihosting {
+    pub fn with_file_system(mut self, fs: impl FileSystem + 'static) -> Self {
+        self.fs = Some(Box::new(fs));
+        self
+    }
+
+    fn open(&mut self, path: &[u8], mode: u32) -> Option<u32> {
+        let handle = match (path, mode / 4) {
+            (b":tt", 0) => Handle::Stdin,
+            (b":tt", _) => Handle::Stdout,
+            (path, kind) => {
+                // the fopen modes r, w and a, each also with b, + and +b
+                let plus = mode & 2 != 0;
+                let flags = match (kind, plus) {
+                    (0, false) => O_RDONLY,
+                    (0, true) => O_RDWR,
+                    (1, false) => O_WRONLY | O_CREAT | O_TRUNC,
+                    (1, true) => O_RDWR | O_CREAT | O_TRUNC,
+                    (2, false) => O_WRONLY | O_CREAT | O_APPEND,
+                    (2, true) => O_RDWR | O_CREAT | O_APPEND,
+                    _ => return None,
+                };
+                let path = core::str::from_utf8(path).ok()?;
+                Handle::File(self.fs.as_mut()?.open(path, flags).ok()?)
+            }
+        };
+        let number = (1..)
+            .find(|number| !self.handles.contains_key(number))
+            .expect("there are free handles");
+        self.handles.insert(number, handle);
+        Some(number)
+    }
+}
+
+impl Vm {
+    /// answers semihosting calls on the host, see `semihosting.rs`
+    pub fn with_semihosting(mut self, semihosting: Semihosting) -> Self {
+        self.semihosting = Some(semihosting);
+        self
+    }
+
+    /// handles the semihosting call if the `ebreak` at `pc` is one and moves
+    /// past it, false if it is a plain `ebreak`
+    pub(super) fn semihosting_call(&mut self, pc: u32) -> bool {
+        if self.semihosting.is_none()
+            || self.memory.read_u32(pc.wrapping_sub(4)) != Ok(ENTRY_MARKER)
+            || self.memory.read_u32(pc.wrapping_add(4)) != Ok(EXIT_MARKER)
+        {
+            return false;
+        }
+        let operation = self.vm_state.registers[Register::A0] as u32;
+        let parameter = self.vm_state.registers[Register::A1] as u32;
+        let result = self.semihosting_operation(operation, parameter);
+        self.vm_state
+            .registers
+            .write(Register::A0, result.unwrap_or(-1));
+        self.vm_state.pc = pc.wrapping_add(4) as i32;
+        true
+    }
+
+    fn semihosting_operation(&mut self, operation: u32, parameter: u32) -> Option<i32> {
+        let word = |index: u32| self.memory.read_u32(parameter.wrapping_add(4 * index)).ok();
+        let semihosting = self.semihosting.as_mut().expect("semihosting is on");
+        match operation {
+            SYS_OPEN => {
+                let (address, mode, length) = (word(0)?, word(1)?, word(2)?);
+                let path = self.memory.read_bytes(address, length as usize).ok()?;
+                semihosting.open(path, mode).map(|handle| handle as i32)
+            }
+            SYS_CLOSE => semihosting.handles.remove(&word(0)?).map(|_| 0),
+            SYS_WRITEC => {
+                semihosting
+                    .output
+                    .push(self.memory.read_u8(parameter).ok()?);
+                Some(0)
+            }
+            SYS_WRITE0 => {
+                semihosting
+                    .output
+                    .extend(read_path(&self.memory, parameter)?);
+                Some(0)
+            }
+            SYS_WRITE => {
+                let (handle, address, length) = (word(0)?, word(1)?, word(2)?);
+                let bytes = self.memory.read_bytes(address, length as usize).ok()?;
+                let written = match semihosting.handles.get_mut(&handle)? {
+                    Handle::Stdin => 0,
+                    Handle::Stdout => {
+                        semihosting.output.extend_from_slice(bytes);
+                        bytes.len()
+                    }
+                    Handle::File(file) => file.write(bytes).ok()?,
+                };
+                // the bytes that were not written
+                Some((length as usize - written) as i32)
+            }
+            SYS_READ => {
+                let (handle, address, length) = (word(0)?, word(1)?, word(2)?);
+                let mut buffer = vec![0; length as usize];
+                let read = match semihosting.handles.get_mut(&handle)? {
+                    Handle::Stdin => {
+                        let read = semihosting.input.len().min(buffer.len());
+                        for (byte, input) in buffer.iter_mut().zip(semihosting.input.drain(..read))
+                        {
+                            *byte = input;
+                        }
+                        read
+                    }
+                    Handle::Stdout => 0,
+                    Handle::File(file) => file.read(&mut buffer).ok()?,
+                };
+                self.memory.write_bytes(address, &buffer[..read]).ok()?;
+                Some((length as usize - read) as i32)
+            }
+            SYS_ISTTY => match semihosting.handles.get(&word(0)?)? {
+                Handle::File(_) => Some(0),
+                Handle::Stdin | Handle::Stdout => Some(1),
+            },
+            SYS_SEEK => match semihosting.handles.get_mut(&word(0)?)? {
+                Handle::File(file) => {
+                    file.seek(SeekFrom::Start(word(1)?.into())).ok()?;
+                    Some(0)
+                }
+                Handle::Stdin | Handle::Stdout => None,
+            },
+            SYS_FLEN => match semihosting.handles.get_mut(&word(0)?)? {
+                Handle::File(file) => i32::try_from(file.size().ok()?).ok(),
+                Handle::Stdin | Handle::Stdout => Some(0),
+            },
+            SYS_EXIT => {
+                let code = i32::from(parameter != ADP_STOPPED_APPLICATION_EXIT);
+                self.stop = Some(StopReason::Exit { code });
+                Some(0)
+            }
+            SYS_EXIT_EXTENDED => {
+                let code = match word(0)? {
+                    ADP_STOPPED_APPLICATION_EXIT => word(1)? as i32,
+                    _ => 1,
+                };
+                self.stop = Some(StopReason::Exit { code });
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Semihosting, ADP_STOPPED_APPLICATION_EXIT};
+    use crate::fs::VirtFs;
+    use crate::{StopReason, Vm};
+
+    /// a vm that makes the semihosting `calls` (operation, address of the
+    /// parameter, below 0x1800) one after the other, then `ebreak`s
+    fn vm_with_calls(calls: &[(u32, u32)], semihosting: Semihosting) -> Vm {
+        let mut program = Vec::new();
+        for (operation, parameter) in calls {
+            program.extend([
+                // addi a0, zero, operation
+                operation << 20 | 0x0000_0513,
+                // lui a1, 0x1
+                0x0000_15b7,
+                // addi a1, a1, parameter - 0x1000
+                (parameter - 0x1000) << 20 | 0x0005_8593,
+                // slli zero, zero, 0x1f / ebreak / srai zero, zero, 7
+                0x01f0_1013,
+                0x0010_0073,
+                0x4070_5013,
+            ]);
+        }
+        program.push(0x0010_0073);
+        let mut vm = Vm::new(0x1000, 0x400).with_semihosting(semihosting);
+        load_words(&mut vm, 0x1000, &program);
+        vm
+    }
+
+    fn load_words(vm: &mut Vm, address: u32, words: &[u32]) {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        vm.load_program(address, &bytes).unwrap();
+    }
+
+    #[test]
+    fn should_print_and_exit() {
+        // SYS_WRITE0, SYS_WRITEC, SYS_EXIT_EXTENDED
+        let calls = [(0x04, 0x1200), (0x03, 0x1200), (0x20, 0x1210)];
+        let mut vm = vm_with_calls(&calls, Semihosting::default());
+        vm.load_program(0x1200, b"hi\n\0").unwrap();
+        load_words(&mut vm, 0x1210, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+
+        assert_eq!(vm.run(), Ok(StopReason::Exit { code: 3 }));
+        assert_eq!(vm.semihosting.as_ref().unwrap().output, b"hi\nh");
+
+        // without the markers an ebreak stays an ebreak
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+    }
+
+    #[test]
+    fn should_open_and_read_files() {
+        let mut fs = VirtFs::new();
+        fs.insert("/data.txt", "hello");
+        // SYS_OPEN, SYS_READ
+        let calls = [(0x01, 0x1200), (0x06, 0x1210)];
+        let mut vm = vm_with_calls(&calls, Semihosting::default().with_file_system(fs));
+        // "/data.txt" at 0x1280, mode "rb"
+        load_words(&mut vm, 0x1200, &[0x1280, 1, 9]);
+        vm.load_program(0x1280, b"/data.txt").unwrap();
+        // the first handle, 8 bytes to 0x1300
+        load_words(&mut vm, 0x1210, &[1, 0x1300, 8]);
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        // 3 of the 8 bytes were not read
+        assert_eq!(vm.vm_state.registers[10], 3);
+        assert_eq!(vm.memory.read_bytes(0x1300, 5).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,310 @@
+//! Several harts on one vm. They share the memory, the caches and the
+//! statistics, and each has its own registers, pc and CSRs, with its id in
+//! `mhartid`. Only one hart runs at a time: it runs for a quantum of
+//! instructions, then the scheduler picks the next one. The `lr.w`
+//! reservations are per hart, see `atomic.rs`.
+//!
+//! A hart that executes `ebreak` is done. `run()` returns once every hart
+//! is done, or as soon as one of them stops for another reason. With the
+//! SBI (see `sbi.rs`) harts can also stop themselves and start each other.
+
+use super::csr::Csrs;
+use super::emulator::{StopReason, Vm, VmState};
+use super::error::VmError;
+use super::prelude::*;
+use super::register::Register;
+use super::sbi::HartStatus;
+
+/// the state of a hart while another one runs
+#[derive(Debug, Clone)]
+pub struct Hart {
+    pub vm_state: VmState,
+    pub csrs: Csrs,
+    /// the hart executed `ebreak` or was stopped through the SBI
+    pub halted: bool,
+}
+
+/// gets the harts and the id of the one that ran last, returns the next
+pub type PickHart = dyn FnMut(&[Hart], usize) -> usize;
+
+/// picks the hart that runs next
+pub enum Scheduler {
+    /// the next hart that is not halted, by id
+    RoundRobin,
+    /// picking a halted hart falls back to round robin
+    Custom(Box<PickHart>),
+}
+
+pub struct Smp {
+    pub vm: Vm,
+    /// the harts, the state of the current one is in `vm` while it runs
+    harts: Vec<Hart>,
+    current: usize,
+    /// how many instructions a hart runs before the next one gets a turn
+    pub quantum: u64,
+    scheduler: Scheduler,
+}
+
+impl Smp {
+    /// `count` harts that all start with the state of `vm`
+    pub fn new(mut vm: Vm, count: usize) -> Self {
+        assert!(count > 0, "there has to be at least one hart");
+        vm.csrs.mhartid = 0;
+        vm.reservations.hart = 0;
+        if let Some(sbi) = &mut vm.sbi {
+            sbi.harts = vec![HartStatus::Started; count];
+        }
+        let harts = (0..count)
+            .map(|id| Hart {
+                vm_state: vm.vm_state.clone(),
+                csrs: Csrs {
+                    mhartid: id as u32,
+                    ..vm.csrs.clone()
+                },
+                halted: false,
+            })
+            .collect();
+        Self {
+            vm,
+            harts,
+            current: 0,
+            quantum: 1000,
+            scheduler: Scheduler::RoundRobin,
+        }
+    }
+
+    pub fn with_quantum(mut self, quantum: u64) -> Self {
+        self.quantum = quantum;
+        self
+    }
+
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// the id of the hart that runs now
+    pub fn current_hart(&self) -> usize {
+        self.current
+    }
+
+    /// the state of every hart, with the current one stored first
+    pub fn harts(&mut self) -> &[Hart] {
+        self.store_current();
+        &self.harts
+    }
+
+    /// stops every hart but the first one, for kernels that start the
+    /// others with the SBI call `hart_start`
+    pub fn with_secondary_harts_stopped(mut self) -> Self {
+        for hart in &mut self.harts[1..] {
+            hart.halted = true;
+        }
+        if let Some(sbi) = &mut self.vm.sbi {
+            sbi.harts[1..].fill(HartStatus::Stopped);
+        }
+        self
+    }
+
+    /// runs the harts in turns until all of them executed `ebreak` or
+    /// stopped, or until one of them stops for another reason or fails. That
+    /// hart stays the current one
+    pub fn run(&mut self) -> Result<StopReason, VmError> {
+        loop {
+            let stop_reason = self.vm.run_for(self.quantum)?;
+            match stop_reason {
+                StopReason::Preempted { .. } | StopReason::Waiting { .. } => {}
+                StopReason::Ebreak | StopReason::HartStopped { .. } => {
+                    self.harts[self.current].halted = true;
+                }
+                stop_reason => return Ok(stop_reason),
+            }
+
+            self.store_current();
+            self.start_harts();
+            if self.harts.iter().all(|hart| hart.halted) {
+                return Ok(stop_reason);
+            }
+            self.switch();
+        }
+    }
+
+    /// carries out the `hart_start` calls: the hart starts at the address
+    /// with its id in a0 and the opaque value in a1
+    fn start_harts(&mut self) {
+        let Some(sbi) = &mut self.vm.sbi else {
+            return;
+        };
+        for start in sbi.starts.drain(..) {
+            let hart = &mut self.harts[start.hart];
+            hart.vm_state.pc = start.address as i32;
+            hart.vm_state
+                .registers
+                .write(Register::A0, start.hart as i32);
+            hart.vm_state
+                .registers
+                .write(Register::A1, start.opaque as i32);
+            hart.halted = false;
+        }
+    }
+
+    fn store_current(&mut self) {
+        let hart = &mut self.harts[self.current];
+        hart.vm_state = self.vm.vm_state.clone();
+        hart.csrs = self.vm.csrs.clone();
+    }
+
+    /// loads the hart the scheduler picks, the current one is stored
+    fn switch(&mut self) {
+        let round_robin = |harts: &[Hart], current: usize| {
+            (1..=harts.len())
+                .map(|offset| (current + offset) % harts.len())
+                .find(|&id| !harts[id].halted)
+                .unwrap_or(current)
+        };
+        let next = match &mut self.scheduler {
+            Scheduler::RoundRobin => round_robin(&self.harts, self.current),
+            Scheduler::Custom(pick) => match pick(&self.harts, self.current) {
+                id if self.harts.get(id).is_some_and(|hart| !hart.halted) => id,
+                _ => round_robin(&self.harts, self.current),
+            },
+        };
+
+        self.current = next;
+        self.vm.vm_state = self.harts[next].vm_state.clone();
+        self.vm.csrs = self.harts[next].csrs.clone();
+        self.vm.reservations.hart = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, Smp};
+    use crate::sbi::HartStatus;
+    use crate::{StopReason, Vm};
+
+    fn smp_with_program(program: &[u32], count: usize) -> Smp {
+        let program: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        Smp::new(vm, count)
+    }
+
+    #[test]
+    fn should_give_each_hart_its_id() {
+        // 0x1000 csrr a0, mhartid
+        // 0x1004 ebreak
+        let mut smp = smp_with_program(&[0xf140_2573, 0x0010_0073], 3);
+        assert_eq!(smp.run(), Ok(StopReason::Ebreak));
+
+        let ids: Vec<i32> = smp
+            .harts()
+            .iter()
+            .map(|hart| hart.vm_state.registers[10])
+            .collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert!(smp.harts().iter().all(|hart| hart.halted));
+    }
+
+    /// 0x1000 lui a1, 0x1
+    /// 0x1004 addi a1, a1, 0x80
+    /// 0x1008 addi a3, a1, 4
+    /// 0x100c addi t1, zero, 1
+    /// 0x1010 addi t0, zero, 100
+    /// 0x1014 lr.w a0, (a1)            <- loop
+    /// 0x1018 addi a0, a0, 1
+    /// 0x101c sc.w a2, a0, (a1)
+    /// 0x1020 bne a2, zero, 0x1014
+    /// 0x1024 amoadd.w zero, t1, (a3)
+    /// 0x1028 addi t0, t0, -1
+    /// 0x102c bne t0, zero, 0x1014
+    /// 0x1030 ebreak
+    const COUNTERS: [u32; 13] = [
+        0x0000_15b7,
+        0x0805_8593,
+        0x0045_8693,
+        0x0010_0313,
+        0x0640_0293,
+        0x1005_a52f,
+        0x0015_0513,
+        0x18a5_a62f,
+        0xfe06_1ae3,
+        0x0066_a02f,
+        0xfff2_8293,
+        0xfe02_94e3,
+        0x0010_0073,
+    ];
+
+    #[test]
+    fn should_not_lose_atomic_increments_of_interleaved_harts() {
+        let mut smp = smp_with_program(&COUNTERS, 2).with_quantum(1);
+        assert_eq!(smp.run(), Ok(StopReason::Ebreak));
+        assert_eq!(smp.vm.memory.read_u32(0x1080).unwrap(), 200);
+        assert_eq!(smp.vm.memory.read_u32(0x1084).unwrap(), 200);
+    }
+
+    #[test]
+    fn should_run_the_hart_the_scheduler_picks() {
+        // always hart 1 while it runs, then round robin
+        let mut smp = smp_with_program(&COUNTERS, 2)
+            .with_quantum(3)
+            .with_scheduler(Scheduler::Custom(Box::new(|_, _| 1)));
+        assert_eq!(smp.run(), Ok(StopReason::Ebreak));
+        assert_eq!(smp.vm.memory.read_u32(0x1084).unwrap(), 200);
+        // hart 0 ran its first quantum only, then hart 1 ran to its end
+        assert_eq!(smp.current_hart(), 0);
+    }
+
+    #[test]
+    fn should_start_and_stop_harts_through_the_sbi() {
+        // 0x1000 lui a7, 0x485
+        // 0x1004 addi a7, a7, 0x34d     HSM
+        // 0x1008 addi a0, zero, 1       hart 1
+        // 0x100c auipc a1, 0
+        // 0x1010 addi a1, a1, 0x14      starts at 0x1020
+        // 0x1014 addi a2, zero, 42      opaque
+        // 0x1018 ecall                  hart_start
+        // 0x101c ebreak
+        // 0x1020 lui t0, 0x1            <- hart 1
+        // 0x1024 sw a1, 0x80(t0)
+        // 0x1028 sw a0, 0x84(t0)
+        // 0x102c lui a7, 0x485
+        // 0x1030 addi a7, a7, 0x34d
+        // 0x1034 addi a6, zero, 1
+        // 0x1038 ecall                  hart_stop
+        // 0x103c ebreak
+        let program: Vec<u8> = [
+            0x0048_58b7u32,
+            0x34d8_8893,
+            0x0010_0513,
+            0x0000_0597,
+            0x0145_8593,
+            0x02a0_0613,
+            0x0000_0073,
+            0x0010_0073,
+            0x0000_12b7,
+            0x08b2_a023,
+            0x08a2_a223,
+            0x0048_58b7,
+            0x34d8_8893,
+            0x0010_0813,
+            0x0000_0073,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_sbi();
+        vm.load_program(0x1000, &program).unwrap();
+        let mut smp = Smp::new(vm, 2).with_secondary_harts_stopped();
+
+        assert_eq!(smp.run(), Ok(StopReason::HartStopped { pc: 0x1038 }));
+        assert_eq!(smp.vm.memory.read_u32(0x1080).unwrap(), 42);
+        assert_eq!(smp.vm.memory.read_u32(0x1084).unwrap(), 1);
+        let sbi = smp.vm.sbi.as_ref().unwrap();
+        assert_eq!(sbi.status(1), Some(HartStatus::Stopped));
+    }
+}
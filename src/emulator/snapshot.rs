@@ -0,0 +1,290 @@
+use super::call_stack::Frame;
+use super::csr::Csrs;
+use super::emulator::{Vm, VmState};
+use super::memory::{Memory, MemoryDiff};
+use super::prelude::*;
+use super::register::Register;
+
+/// ecall number (in a7) for "checkpoint now". Returns the checkpoint id in a0
+/// and 0 in a1.
+pub const HYPERCALL_CHECKPOINT: u32 = 0x0700_0001;
+
+/// ecall number (in a7) for "restore checkpoint a0". Execution continues right
+/// after the checkpoint ecall, with the id in a0 and 1 in a1 so the guest can
+/// tell it came back from a restore (like `setjmp`/`longjmp`).
+pub const HYPERCALL_RESTORE: u32 = 0x0700_0002;
+
+/// returned in a0 when the host does not allow the hypercall or the
+/// checkpoint id is not valid
+pub const HYPERCALL_DENIED: i32 = -1;
+
+/// The architectural state of the vm at one point in time: registers, pc,
+/// CSRs and the whole memory, plus the gas left and the shadow call stack. Stats and
+/// timing are not part of it, those keep counting across restores.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub vm_state: VmState,
+    pub memory: Memory,
+    pub csrs: Csrs,
+    /// `None` when gas metering was off
+    pub remaining_gas: Option<u64>,
+    /// the shadow call stack, so backtraces still work after a restore
+    pub call_stack: Vec<Frame>,
+}
+
+/// What the host allows the guest to do with the snapshot hypercalls. By
+/// default nothing is allowed, the host has to opt in.
+#[derive(Debug, Clone, Default)]
+pub struct HypercallPolicy {
+    pub allow_checkpoint: bool,
+    pub allow_restore: bool,
+    /// how many checkpoints the guest may keep, each one is a full copy of
+    /// memory
+    pub max_checkpoints: usize,
+}
+
+impl HypercallPolicy {
+    /// checkpoint and restore allowed, up to `max_checkpoints` checkpoints
+    pub fn allow_all(max_checkpoints: usize) -> Self {
+        Self {
+            allow_checkpoint: true,
+            allow_restore: true,
+            max_checkpoints,
+        }
+    }
+}
+
+impl Vm {
+    /// takes a snapshot of the current state
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            vm_state: self.vm_state.clone(),
+            memory: self.memory.clone(),
+            csrs: self.csrs.clone(),
+            remaining_gas: self.remaining_gas(),
+            call_stack: self.call_stack.clone(),
+        }
+    }
+
+    /// puts the vm back into the state of the snapshot
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.vm_state = snapshot.vm_state.clone();
+        // the pages the restore changes are dirty too, and those written
+        // before it still are
+        let changed = snapshot.memory.diff(&self.memory);
+        let dirty: Vec<u32> = self.memory.dirty_pages().collect();
+        self.memory = snapshot.memory.clone();
+        self.memory.clear_dirty_pages();
+        for page in dirty {
+            self.memory.mark_dirty(page, 1);
+        }
+        for (address, bytes) in &changed.pages {
+            self.memory.mark_dirty(*address, bytes.len());
+        }
+        self.invalidate_decode_cache();
+        // the translations are the ones of the page tables before it
+        self.mmu.tlb.flush();
+        self.csrs = snapshot.csrs.clone();
+        self.call_stack = snapshot.call_stack.clone();
+        if let Some(remaining_gas) = snapshot.remaining_gas {
+            self.set_remaining_gas(remaining_gas);
+        }
+    }
+
+    /// the addresses of the memory pages written since the last
+    /// `take_dirty_pages`
+    pub fn dirty_pages(&self) -> Vec<u32> {
+        self.memory.dirty_pages().collect()
+    }
+
+    /// `dirty_pages` and starts over, so a UI only redraws what changed
+    /// since its last frame
+    pub fn take_dirty_pages(&mut self) -> Vec<u32> {
+        let pages = self.memory.dirty_pages().collect();
+        self.memory.clear_dirty_pages();
+        pages
+    }
+
+    /// the memory pages that changed since `snapshot`, with their bytes now.
+    /// With them the snapshot can stand in for a full one of now, see
+    /// `Memory::apply_diff`
+    pub fn memory_diff(&self, snapshot: &Snapshot) -> MemoryDiff {
+        self.memory.diff(&snapshot.memory)
+    }
+
+    /// the checkpoints the guest took so far, the index is the id
+    pub fn checkpoints(&self) -> &[Snapshot] {
+        &self.checkpoints
+    }
+
+    /// handles the snapshot hypercalls, returns false if the ecall number is
+    /// not one of them
+    pub(super) fn snapshot_hypercall(&mut self, number: u32) -> bool {
+        match number {
+            HYPERCALL_CHECKPOINT => {
+                self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+
+                if self.hypercall_policy.allow_checkpoint
+                    && self.checkpoints.len() < self.hypercall_policy.max_checkpoints
+                {
+                    let id = self.checkpoints.len() as i32;
+                    self.vm_state.registers.write(Register::A0, id);
+                    self.vm_state.registers.write(Register::A1, 0);
+                    self.checkpoints.push(self.snapshot());
+                } else {
+                    self.vm_state
+                        .registers
+                        .write(Register::A0, HYPERCALL_DENIED);
+                }
+            }
+            HYPERCALL_RESTORE => {
+                let id = self.vm_state.registers[10];
+                let checkpoint = usize::try_from(id)
+                    .ok()
+                    .and_then(|index| self.checkpoints.get(index))
+                    .cloned();
+
+                match checkpoint {
+                    Some(checkpoint) if self.hypercall_policy.allow_restore => {
+                        // the guest must not be able to get its gas back
+                        let remaining_gas = self.remaining_gas();
+                        self.restore(&checkpoint);
+                        if let Some(remaining_gas) = remaining_gas {
+                            self.set_remaining_gas(remaining_gas);
+                        }
+                        self.vm_state.registers.write(Register::A0, id);
+                        self.vm_state.registers.write(Register::A1, 1);
+                    }
+                    _ => {
+                        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+                        self.vm_state
+                            .registers
+                            .write(Register::A0, HYPERCALL_DENIED);
+                    }
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HypercallPolicy, HYPERCALL_DENIED};
+    use crate::csr::{Privilege, CSR_SATP};
+    use crate::mmu::{PTE_A, PTE_D, PTE_R, PTE_V, PTE_W, SATP_MODE_SV32};
+    use crate::{AccessKind, Register, StopReason, Vm};
+
+    // 0x1000 lui a7, 0x7000
+    // 0x1004 addi a7, a7, 1       checkpoint
+    // 0x1008 ecall                a0 = id, a1 = 0 (1 after the restore)
+    // 0x100c bne a1, zero, 0x1024
+    // 0x1010 addi s0, s0, 1       the "speculative" work
+    // 0x1014 lui a7, 0x7000
+    // 0x1018 addi a7, a7, 2       restore a0
+    // 0x101c ecall
+    // 0x1020 ebreak               only reached when the restore was denied
+    // 0x1024 ebreak
+    const PROGRAM: [u32; 10] = [
+        0x0700_08b7,
+        0x0018_8893,
+        0x0000_0073,
+        0x0005_9c63,
+        0x0014_0413,
+        0x0700_08b7,
+        0x0028_8893,
+        0x0000_0073,
+        0x0010_0073,
+        0x0010_0073,
+    ];
+
+    #[test]
+    fn guest_should_roll_back_to_its_checkpoint() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+        vm.hypercall_policy = HypercallPolicy::allow_all(4);
+
+        assert_eq!(vm.run().unwrap(), StopReason::Ebreak);
+
+        assert_eq!(vm.vm_state.pc, 0x1024);
+        // came back from the restore
+        assert_eq!(vm.vm_state.registers[10], 0);
+        assert_eq!(vm.vm_state.registers[11], 1);
+        // the increment happened after the checkpoint so it was rolled back
+        assert_eq!(vm.vm_state.registers[8], 0);
+        assert_eq!(vm.checkpoints().len(), 1);
+    }
+
+    #[test]
+    fn host_policy_should_deny_hypercalls_by_default() {
+        let mut vm = Vm::with_program_words(0x1000, &PROGRAM);
+
+        assert_eq!(vm.run().unwrap(), StopReason::Ebreak);
+
+        assert_eq!(vm.vm_state.pc, 0x1020);
+        assert_eq!(vm.vm_state.registers[10], HYPERCALL_DENIED);
+        assert_eq!(vm.vm_state.registers[8], 1);
+        assert!(vm.checkpoints().is_empty());
+    }
+
+    #[test]
+    fn host_should_be_able_to_snapshot_and_restore() {
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.memory.write_u32(0x1080, 42).unwrap();
+        let snapshot = vm.snapshot();
+
+        vm.memory.write_u32(0x1080, 7).unwrap();
+        vm.vm_state.registers.write(Register::T0, 7);
+        vm.restore(&snapshot);
+
+        assert_eq!(vm.memory.read_u32(0x1080).unwrap(), 42);
+        assert_eq!(vm.vm_state.registers[5], 0);
+    }
+
+    #[test]
+    fn restore_should_drop_the_translations_of_the_page_tables_after_the_snapshot() {
+        let mut vm = Vm::new(0, 0x20_0000);
+        let data = PTE_R | PTE_W | PTE_V | PTE_A | PTE_D;
+        vm.memory.write_u32(0x10_0400, 0x101 << 10 | PTE_V).unwrap();
+        vm.memory.write_u32(0x10_1000, 0x2 << 10 | data).unwrap();
+        vm.csrs.privilege = Privilege::Supervisor;
+        vm.write_csr(CSR_SATP, SATP_MODE_SV32 | 0x100);
+        let snapshot = vm.snapshot();
+
+        vm.memory.write_u32(0x10_1000, 0x5 << 10 | data).unwrap();
+        assert_eq!(vm.translate(0x4000_0000, AccessKind::Load), Ok(0x5000));
+        vm.restore(&snapshot);
+        assert_eq!(vm.translate(0x4000_0000, AccessKind::Load), Ok(0x2000));
+    }
+
+    #[test]
+    fn should_track_dirty_pages_and_diff_against_a_snapshot() {
+        let mut vm = Vm::new(0x1000, 0x3000);
+        vm.memory.write_u32(0x1080, 42).unwrap();
+        assert_eq!(vm.dirty_pages(), [0x1000]);
+        assert_eq!(vm.take_dirty_pages(), [0x1000]);
+        assert!(vm.take_dirty_pages().is_empty());
+        let snapshot = vm.snapshot();
+
+        // the last word of a page and the first one of the next
+        vm.memory.write_u32(0x2ffe, 0x0102_0304).unwrap();
+        assert_eq!(vm.take_dirty_pages(), [0x2000, 0x3000]);
+        let diff = vm.memory_diff(&snapshot);
+        assert_eq!(
+            diff.pages
+                .iter()
+                .map(|(address, _)| *address)
+                .collect::<Vec<_>>(),
+            [0x2000, 0x3000]
+        );
+        let mut memory = snapshot.memory.clone();
+        memory.apply_diff(&diff).unwrap();
+        assert_eq!(memory.bytes(), vm.memory.bytes());
+
+        // restoring changes those pages back
+        vm.restore(&snapshot);
+        assert_eq!(vm.take_dirty_pages(), [0x2000, 0x3000]);
+        assert!(vm.memory_diff(&snapshot).pages.is_empty());
+    }
+}
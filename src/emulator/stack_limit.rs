@@ -0,0 +1,154 @@
+//! Stack overflow detection, to show where a recursion went too deep. With a
+//! `StackLimit` the vm fails with `VmError::StackOverflow` and a backtrace
+//! when sp goes below the limit, or when a store lands in the guard bytes
+//! under it while sp is still fine (a big local array, a callee that has
+//! not moved sp yet).
+//!
+//! The check runs after the instruction, so its effect is there, the pc
+//! still points at it. Stores into a guard region of the memory map (see
+//! `user_memory_map`) never happen, their access fault becomes the stack
+//! overflow.
+
+use super::emulator::Vm;
+use super::error::{AccessKind, VmError};
+use super::memory::MemoryAccess;
+use super::profile::FunctionRange;
+
+/// where the stack ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLimit {
+    /// the lowest address sp may point to
+    pub limit: u32,
+    /// how many bytes under `limit` are a guard, stores there overflow too
+    pub guard_size: u32,
+}
+
+impl StackLimit {
+    pub fn new(limit: u32, guard_size: u32) -> Self {
+        Self { limit, guard_size }
+    }
+
+    fn is_guard(&self, address: u32) -> bool {
+        address < self.limit && self.limit - address <= self.guard_size
+    }
+}
+
+impl Vm {
+    /// fails the run with `StackOverflow` once the stack goes past `limit`
+    pub fn with_stack_limit(mut self, limit: StackLimit) -> Self {
+        self.stack_limit = Some(limit);
+        self
+    }
+
+    /// turns an access fault in the guard into the stack overflow
+    pub(super) fn stack_overflow_of(&self, error: VmError) -> VmError {
+        match (self.stack_limit, &error) {
+            (
+                Some(limit),
+                VmError::AccessFault {
+                    pc,
+                    instruction,
+                    address,
+                    kind: AccessKind::Store,
+                },
+            ) if limit.is_guard(*address) => self.stack_overflow(*pc, *instruction, *address),
+            _ => error,
+        }
+    }
+
+    /// checks the instruction at `pc` that moved sp from `sp` and did
+    /// `access`
+    pub(super) fn check_stack(
+        &self,
+        pc: u32,
+        word: u32,
+        sp: u32,
+        access: Option<MemoryAccess>,
+    ) -> Result<(), VmError> {
+        let Some(limit) = self.stack_limit else {
+            return Ok(());
+        };
+        let new_sp = self.vm_state.sp() as u32;
+        if new_sp != sp && new_sp < limit.limit {
+            return Err(self.stack_overflow(pc, word, new_sp));
+        }
+        match access {
+            Some(MemoryAccess::Write { address, .. }) if limit.is_guard(address) => {
+                Err(self.stack_overflow(pc, word, address))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn stack_overflow(&self, pc: u32, instruction: u32, address: u32) -> VmError {
+        let functions = FunctionRange::from_symbols(&self.symbols);
+        VmError::StackOverflow {
+            pc,
+            instruction,
+            address,
+            limit: self.stack_limit.map_or(0, |limit| limit.limit),
+            calls: self.backtrace_at(pc, &functions),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StackLimit;
+    use crate::memory::MemoryMap;
+    use crate::{Register, Vm, VmError};
+
+    #[test]
+    fn should_stop_a_runaway_recursion() {
+        // 0x1000 lui sp, 0x2
+        // 0x1004 jal ra, 0x100c
+        // 0x1008 ebreak
+        // 0x100c addi sp, sp, -16      <- recurse
+        // 0x1010 sw ra, 12(sp)
+        // 0x1014 jal ra, 0x100c
+        // 0x1018 sw zero, -512(sp)
+        let program: Vec<u8> = [
+            0x0000_2137u32,
+            0x0080_00ef,
+            0x0010_0073,
+            0xff01_0113,
+            0x0011_2623,
+            0xff9f_f0ef,
+            0xe001_2023,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let map = MemoryMap::new().ram(0x1000, 0x1000).guard(0x1b00, 0x100);
+        let mut vm = Vm::from_memory_map(&map).with_stack_limit(StackLimit::new(0x1c00, 0x100));
+        vm.load_program(0x1000, &program).unwrap();
+
+        // 64 frames fit, the 65th does not
+        let Err(VmError::StackOverflow {
+            pc,
+            address,
+            limit,
+            calls,
+            ..
+        }) = vm.run()
+        else {
+            panic!("no stack overflow");
+        };
+        assert_eq!((pc, address, limit), (0x100c, 0x1bf0, 0x1c00));
+        assert_eq!(calls.0.len(), 1 + 65);
+        assert_eq!(calls.0[1].pc, 0x1014);
+        assert_eq!(calls.0[65].pc, 0x1004);
+
+        // a store into the guard with sp still above the limit
+        vm.vm_state.pc = 0x1018;
+        vm.vm_state.registers.write(Register::SP, 0x1d00);
+        assert!(matches!(
+            vm.step(),
+            Err(VmError::StackOverflow {
+                pc: 0x1018,
+                address: 0x1b00,
+                ..
+            })
+        ));
+    }
+}
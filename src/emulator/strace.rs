@@ -0,0 +1,476 @@
+//! strace for the guest: every `ecall` is logged as a Linux syscall with its
+//! arguments decoded (paths read from guest memory, flag names, a preview of
+//! buffers) and its return value.
+//!
+//! With `Vm::with_syscalls` the vm answers the file, write and exit
+//! syscalls itself (see `syscalls.rs`), every other line ends with
+//! `= ? (not implemented)`. That is still useful to see what a ported
+//! program expects from the kernel.
+
+use core::fmt::Write;
+
+use super::memory::Memory;
+use super::prelude::*;
+use super::register::Register;
+
+/// how many bytes of a buffer are shown
+const BUFFER_PREVIEW: usize = 32;
+
+/// the longest path that is read from guest memory
+const MAX_PATH: usize = 4096;
+
+const AT_FDCWD: i32 = -100;
+
+/// how an argument register is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Argument {
+    Int,
+    Hex,
+    /// a file descriptor, `AT_FDCWD` by name
+    Fd,
+    /// a NUL terminated string in guest memory
+    Path,
+    /// a buffer in guest memory, its length is the argument with that index
+    Buffer(usize),
+    OpenFlags,
+    /// file permissions, in octal
+    Mode,
+    Prot,
+    MapFlags,
+    Whence,
+}
+
+/// how the return value is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Return {
+    Int,
+    /// an address (brk, mmap)
+    Pointer,
+    /// the syscall does not return (exit)
+    None,
+}
+
+struct Syscall {
+    number: u32,
+    name: &'static str,
+    arguments: &'static [Argument],
+    returns: Return,
+}
+
+/// the syscalls of the RISC-V Linux ABI (asm-generic numbers) a ported
+/// program usually needs
+const SYSCALLS: &[Syscall] = {
+    use Argument::*;
+
+    &[
+        Syscall {
+            number: 17,
+            name: "getcwd",
+            arguments: &[Hex, Int],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 23,
+            name: "dup",
+            arguments: &[Fd],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 29,
+            name: "ioctl",
+            arguments: &[Fd, Hex, Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 34,
+            name: "mkdirat",
+            arguments: &[Fd, Path, Mode],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 35,
+            name: "unlinkat",
+            arguments: &[Fd, Path, Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 49,
+            name: "chdir",
+            arguments: &[Path],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 56,
+            name: "openat",
+            arguments: &[Fd, Path, OpenFlags, Mode],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 57,
+            name: "close",
+            arguments: &[Fd],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 62,
+            name: "lseek",
+            arguments: &[Fd, Int, Whence],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 63,
+            name: "read",
+            arguments: &[Fd, Buffer(2), Int],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 64,
+            name: "write",
+            arguments: &[Fd, Buffer(2), Int],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 80,
+            name: "fstat",
+            arguments: &[Fd, Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 93,
+            name: "exit",
+            arguments: &[Int],
+            returns: Return::None,
+        },
+        Syscall {
+            number: 94,
+            name: "exit_group",
+            arguments: &[Int],
+            returns: Return::None,
+        },
+        Syscall {
+            number: 96,
+            name: "set_tid_address",
+            arguments: &[Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 113,
+            name: "clock_gettime",
+            arguments: &[Int, Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 160,
+            name: "uname",
+            arguments: &[Hex],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 172,
+            name: "getpid",
+            arguments: &[],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 214,
+            name: "brk",
+            arguments: &[Hex],
+            returns: Return::Pointer,
+        },
+        Syscall {
+            number: 215,
+            name: "munmap",
+            arguments: &[Hex, Int],
+            returns: Return::Int,
+        },
+        Syscall {
+            number: 222,
+            name: "mmap",
+            arguments: &[Hex, Int, Prot, MapFlags, Fd, Int],
+            returns: Return::Pointer,
+        },
+        Syscall {
+            number: 226,
+            name: "mprotect",
+            arguments: &[Hex, Int, Prot],
+            returns: Return::Int,
+        },
+    ]
+};
+
+const OPEN_FLAGS: &[(u32, &str)] = &[
+    (0o100, "O_CREAT"),
+    (0o200, "O_EXCL"),
+    (0o400, "O_NOCTTY"),
+    (0o1000, "O_TRUNC"),
+    (0o2000, "O_APPEND"),
+    (0o4000, "O_NONBLOCK"),
+    (0o200000, "O_DIRECTORY"),
+    (0o2000000, "O_CLOEXEC"),
+];
+
+const PROT_FLAGS: &[(u32, &str)] = &[(1, "PROT_READ"), (2, "PROT_WRITE"), (4, "PROT_EXEC")];
+
+const MAP_FLAGS: &[(u32, &str)] = &[
+    (0x01, "MAP_SHARED"),
+    (0x02, "MAP_PRIVATE"),
+    (0x10, "MAP_FIXED"),
+    (0x20, "MAP_ANONYMOUS"),
+];
+
+const ERRNO_NAMES: &[(i32, &str)] = &[
+    (1, "EPERM"),
+    (2, "ENOENT"),
+    (9, "EBADF"),
+    (11, "EAGAIN"),
+    (12, "ENOMEM"),
+    (13, "EACCES"),
+    (14, "EFAULT"),
+    (17, "EEXIST"),
+    (20, "ENOTDIR"),
+    (21, "EISDIR"),
+    (22, "EINVAL"),
+    (28, "ENOSPC"),
+    (29, "ESPIPE"),
+    (38, "ENOSYS"),
+];
+
+/// The strace log. Turn it on with `Vm::with_strace`, the vm adds a line
+/// for every ecall that is not a hypercall.
+#[derive(Debug, Clone, Default)]
+pub struct Strace {
+    /// also print every line to stderr, like strace does (needs std)
+    pub echo: bool,
+    lines: Vec<String>,
+}
+
+impl Strace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `openat(AT_FDCWD, "/etc/passwd", O_RDONLY) = 3`, one per syscall
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// logs one syscall. `registers` are the ones the ecall saw, `result` is
+    /// a0 after it, `None` when the syscall failed inside the vm
+    pub(crate) fn record(&mut self, registers: &[i32; 32], result: Option<i32>, memory: &Memory) {
+        let line = format_syscall(registers, result, memory);
+        #[cfg(feature = "std")]
+        if self.echo {
+            eprintln!("{line}");
+        }
+        self.lines.push(line);
+    }
+}
+
+fn format_syscall(registers: &[i32; 32], result: Option<i32>, memory: &Memory) -> String {
+    let number = registers[Register::A7] as u32;
+    let argument_registers = &registers[Register::A0.index()..=Register::A5.index()];
+
+    let Some(syscall) = SYSCALLS.iter().find(|syscall| syscall.number == number) else {
+        let arguments: Vec<String> = argument_registers
+            .iter()
+            .map(|value| format!("{:#x}", *value as u32))
+            .collect();
+        return format!(
+            "syscall_{number}({}){}",
+            arguments.join(", "),
+            format_return(Return::Int, result)
+        );
+    };
+
+    let arguments: Vec<String> = syscall
+        .arguments
+        .iter()
+        .zip(argument_registers)
+        .map(|(argument, value)| {
+            format_argument(*argument, *value, argument_registers, result, memory)
+        })
+        .collect();
+
+    format!(
+        "{}({}){}",
+        syscall.name,
+        arguments.join(", "),
+        format_return(syscall.returns, result)
+    )
+}
+
+fn format_argument(
+    argument: Argument,
+    value: i32,
+    argument_registers: &[i32],
+    result: Option<i32>,
+    memory: &Memory,
+) -> String {
+    let address = value as u32;
+    match argument {
+        Argument::Int => value.to_string(),
+        Argument::Hex => format!("{address:#x}"),
+        Argument::Fd if value == AT_FDCWD => "AT_FDCWD".to_string(),
+        Argument::Fd => value.to_string(),
+        Argument::Path => read_path(memory, address)
+            .map(|path| quote(&path))
+            .unwrap_or_else(|| format!("{address:#x}")),
+        Argument::Buffer(length_index) => {
+            // after a read only the bytes that were read are interesting
+            let mut length = argument_registers[length_index].max(0) as usize;
+            if let Some(result) = result.filter(|result| *result >= 0) {
+                length = length.min(result as usize);
+            }
+            let preview = memory
+                .read_bytes(address, length.min(BUFFER_PREVIEW))
+                .map(quote);
+            match preview {
+                Ok(preview) if length > BUFFER_PREVIEW => format!("{preview}..."),
+                Ok(preview) => preview,
+                Err(_) => format!("{address:#x}"),
+            }
+        }
+        Argument::OpenFlags => {
+            let access = match address & 0b11 {
+                0 => "O_RDONLY",
+                1 => "O_WRONLY",
+                2 => "O_RDWR",
+                _ => "O_ACCMODE",
+            };
+            let flags = format_flags(address & !0b11, OPEN_FLAGS);
+            if flags == "0" {
+                access.to_string()
+            } else {
+                format!("{access}|{flags}")
+            }
+        }
+        Argument::Mode => format!("{address:#o}"),
+        Argument::Prot if address == 0 => "PROT_NONE".to_string(),
+        Argument::Prot => format_flags(address, PROT_FLAGS),
+        Argument::MapFlags => format_flags(address, MAP_FLAGS),
+        Argument::Whence => match value {
+            0 => "SEEK_SET".to_string(),
+            1 => "SEEK_CUR".to_string(),
+            2 => "SEEK_END".to_string(),
+            _ => value.to_string(),
+        },
+    }
+}
+
+fn format_return(returns: Return, result: Option<i32>) -> String {
+    let Some(result) = result else {
+        return " = ? (not implemented)".to_string();
+    };
+
+    match returns {
+        Return::None => String::new(),
+        // -4095..-1 are errors, like the kernel does it
+        _ if (-4095..0).contains(&result) => {
+            let errno = -result;
+            match ERRNO_NAMES.iter().find(|(number, _)| *number == errno) {
+                Some((_, name)) => format!(" = -1 {name}"),
+                None => format!(" = -1 errno {errno}"),
+            }
+        }
+        Return::Pointer => format!(" = {:#x}", result as u32),
+        Return::Int => format!(" = {result}"),
+    }
+}
+
+/// the names of the set flags joined by `|`, bits without a name in hex
+fn format_flags(mut value: u32, names: &[(u32, &str)]) -> String {
+    let mut parts = Vec::new();
+    for (bit, name) in names {
+        if value & bit != 0 {
+            parts.push(name.to_string());
+            value &= !bit;
+        }
+    }
+    if value != 0 || parts.is_empty() {
+        parts.push(format!("{value:#x}"));
+    }
+    parts.join("|")
+}
+
+/// the NUL terminated string at `address`, `None` if it cannot be read or
+/// is longer than `MAX_PATH`
+pub(super) fn read_path(memory: &Memory, address: u32) -> Option<Vec<u8>> {
+    memory.read_c_string(address, MAX_PATH).ok().flatten()
+}
+
+/// the bytes as a C string literal
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\t' => quoted.push_str("\\t"),
+            0x20..=0x7e => quoted.push(*byte as char),
+            _ => {
+                let _ = write!(quoted, "\\x{byte:02x}");
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_syscall, Strace};
+    use crate::memory::Memory;
+    use crate::{Register, Vm, VmError};
+
+    #[test]
+    fn should_decode_paths_flags_and_buffers() {
+        let mut memory = Memory::new(0x1000, 0x100);
+        memory.load(0x1080, b"/etc/passwd\0").unwrap();
+        memory.load(0x10c0, b"hello\n").unwrap();
+
+        let mut registers = [0; 32];
+        registers[Register::A7] = 56;
+        registers[Register::A0] = -100;
+        registers[Register::A1] = 0x1080;
+        registers[Register::A2] = 0o1101;
+        registers[Register::A3] = 0o644;
+        assert_eq!(
+            format_syscall(&registers, Some(3), &memory),
+            "openat(AT_FDCWD, \"/etc/passwd\", O_WRONLY|O_CREAT|O_TRUNC, 0o644) = 3"
+        );
+        assert_eq!(
+            format_syscall(&registers, Some(-2), &memory),
+            "openat(AT_FDCWD, \"/etc/passwd\", O_WRONLY|O_CREAT|O_TRUNC, 0o644) = -1 ENOENT"
+        );
+
+        let mut registers = [0; 32];
+        registers[Register::A7] = 64;
+        registers[Register::A0] = 1;
+        registers[Register::A1] = 0x10c0;
+        registers[Register::A2] = 6;
+        assert_eq!(
+            format_syscall(&registers, Some(6), &memory),
+            "write(1, \"hello\\n\", 6) = 6"
+        );
+    }
+
+    #[test]
+    fn vm_should_log_every_ecall() {
+        // 0x1000 addi a7, zero, 93
+        // 0x1004 addi a0, zero, 1
+        // 0x1008 ecall
+        let program: Vec<u8> = [0x05d0_0893u32, 0x0010_0513, 0x0000_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_strace(Strace::new());
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert!(matches!(vm.run(), Err(VmError::NotImplemented { .. })));
+        assert_eq!(
+            vm.strace.as_ref().unwrap().lines(),
+            ["exit(1) = ? (not implemented)"]
+        );
+    }
+}
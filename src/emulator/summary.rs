@@ -0,0 +1,293 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt;
+use core::time::Duration;
+
+use super::disassemble::symbol_at;
+use super::elf::Symbol;
+use super::prelude::*;
+
+/// how many entries the "hottest code" part of the summary shows
+const HOTTEST_ENTRIES: usize = 10;
+
+/// the loads and stores that went to one device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceActivity {
+    pub name: &'static str,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// Everything the vm keeps track of while running, so that at the end of a
+/// run there is something to tell the user about. `Vm::summary()` prints it.
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    /// why the last `run()` stopped, `None` while it did not stop yet
+    pub exit: Option<String>,
+
+    /// number of instructions that were executed
+    pub instructions_retired: u64,
+
+    /// host time spent inside `run()`
+    pub run_time: Duration,
+
+    /// sp when the first instruction was executed
+    pub initial_sp: Option<u32>,
+
+    /// the lowest sp seen, the stack grows down so this is the peak
+    pub lowest_sp: Option<u32>,
+
+    /// where the heap starts, the end of the loaded program (`_end`)
+    pub heap_start: Option<u32>,
+
+    /// the end of the highest write between `heap_start` and the stack
+    pub heap_top: Option<u32>,
+
+    /// how many times the instruction at each pc was executed
+    pub pc_counts: HashMap<u32, u64>,
+
+    /// how many times each kind of trap (ebreak, illegal instruction, ...)
+    /// happened
+    pub trap_counts: BTreeMap<String, u64>,
+
+    /// where each jalr (ret included) went, the pc of the jalr is the key.
+    /// Static analysis can not know these targets
+    pub indirect_jumps: BTreeMap<u32, BTreeSet<u32>>,
+
+    /// the loads and stores of each device, by its base address
+    pub devices: BTreeMap<u32, DeviceActivity>,
+}
+
+impl RunStats {
+    /// called after every retired instruction with the sp before and after it
+    pub fn record_instruction(&mut self, pc: u32, sp_before: u32, sp_after: u32) {
+        self.instructions_retired += 1;
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+
+        self.initial_sp.get_or_insert(sp_before);
+        let lowest_sp = self.lowest_sp.unwrap_or(sp_before);
+        self.lowest_sp = Some(lowest_sp.min(sp_after));
+    }
+
+    pub fn record_trap(&mut self, trap: &str) {
+        *self.trap_counts.entry(trap.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn record_indirect_jump(&mut self, pc: u32, target: u32) {
+        self.indirect_jumps.entry(pc).or_default().insert(target);
+    }
+
+    /// called for every store to the memory, the ones above `heap_start`
+    /// and below the stack move `heap_top`
+    pub fn record_write(&mut self, address: u32, size: u32) {
+        let Some(heap_start) = self.heap_start else {
+            return;
+        };
+        let stack = self.lowest_sp.or(self.initial_sp).unwrap_or(u32::MAX);
+        if (heap_start..stack).contains(&address) {
+            let end = address.saturating_add(size);
+            self.heap_top = Some(self.heap_top.unwrap_or(heap_start).max(end));
+        }
+    }
+
+    /// called for every load from and store to the device `name` at `base`
+    pub fn record_device(&mut self, base: u32, name: &'static str, write: bool) {
+        let activity = self.devices.entry(base).or_insert(DeviceActivity {
+            name,
+            ..DeviceActivity::default()
+        });
+        if write {
+            activity.writes += 1;
+        } else {
+            activity.reads += 1;
+        }
+    }
+
+    /// how many bytes past `heap_start` the guest wrote at most
+    pub fn peak_heap(&self) -> u32 {
+        match (self.heap_start, self.heap_top) {
+            (Some(heap_start), Some(heap_top)) => heap_top - heap_start,
+            _ => 0,
+        }
+    }
+
+    /// how many bytes the stack grew at most
+    pub fn peak_stack(&self) -> u32 {
+        match (self.initial_sp, self.lowest_sp) {
+            (Some(initial_sp), Some(lowest_sp)) => initial_sp.saturating_sub(lowest_sp),
+            _ => 0,
+        }
+    }
+
+    /// the most executed pcs, most executed first
+    pub fn hottest(&self, count: usize) -> Vec<(u32, u64)> {
+        let mut pc_counts: Vec<(u32, u64)> = self
+            .pc_counts
+            .iter()
+            .map(|(pc, count)| (*pc, *count))
+            .collect();
+        // ties are broken by address so the report is stable
+        pc_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pc_counts.truncate(count);
+        pc_counts
+    }
+
+    /// the functions of `symbols` that ran the most instructions, most
+    /// first. Code outside every symbol counts by its address
+    pub fn hottest_functions(&self, symbols: &[Symbol], count: usize) -> Vec<(String, u64)> {
+        let mut functions: BTreeMap<String, u64> = BTreeMap::new();
+        for (pc, executed) in &self.pc_counts {
+            let name = match symbol_at(symbols, *pc) {
+                Some((symbol, _)) => symbol.name.clone(),
+                None => format!("{pc:#010x}"),
+            };
+            *functions.entry(name).or_insert(0) += executed;
+        }
+        let mut functions: Vec<(String, u64)> = functions.into_iter().collect();
+        // the sort is stable, ties stay in name order
+        functions.sort_by_key(|(_, executed)| core::cmp::Reverse(*executed));
+        functions.truncate(count);
+        functions
+    }
+
+    /// the report with the hottest code by function and the time the guest
+    /// saw, see `Vm::summary`
+    pub fn report<'a>(&'a self, symbols: &'a [Symbol], guest_time: Option<Duration>) -> Report<'a> {
+        Report {
+            stats: self,
+            symbols,
+            guest_time,
+        }
+    }
+}
+
+/// `RunStats` as text, `RunStats::report` makes one
+pub struct Report<'a> {
+    stats: &'a RunStats,
+    symbols: &'a [Symbol],
+    guest_time: Option<Duration>,
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.report(&[], None).fmt(f)
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+        let seconds = stats.run_time.as_secs_f64();
+        let mips = if seconds > 0.0 {
+            stats.instructions_retired as f64 / seconds / 1_000_000.0
+        } else {
+            0.0
+        };
+
+        writeln!(
+            f,
+            "exit:                 {}",
+            stats.exit.as_deref().unwrap_or("still running")
+        )?;
+        writeln!(f, "instructions retired: {}", stats.instructions_retired)?;
+        if let Some(guest_time) = self.guest_time {
+            writeln!(f, "guest time:           {guest_time:?}")?;
+        }
+        writeln!(
+            f,
+            "host time:            {:?} ({mips:.2} MIPS)",
+            stats.run_time
+        )?;
+        writeln!(f, "peak stack:           {} bytes", stats.peak_stack())?;
+        if stats.heap_start.is_some() {
+            writeln!(f, "peak heap:            {} bytes", stats.peak_heap())?;
+        }
+
+        writeln!(f, "traps:")?;
+        if stats.trap_counts.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for (trap, count) in &stats.trap_counts {
+            writeln!(f, "  {trap:<24} {count}")?;
+        }
+
+        writeln!(f, "hottest code:")?;
+        for (function, count) in stats.hottest_functions(self.symbols, HOTTEST_ENTRIES) {
+            writeln!(f, "  {function:<24} {count}")?;
+        }
+
+        if !stats.devices.is_empty() {
+            writeln!(f, "devices:")?;
+        }
+        for (base, activity) in &stats.devices {
+            let device = format!("{} at {base:#x}", activity.name);
+            writeln!(
+                f,
+                "  {device:<24} {} reads, {} writes",
+                activity.reads, activity.writes
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunStats;
+    use crate::elf::{Symbol, SymbolKind};
+
+    #[test]
+    fn should_track_the_peak_stack_and_hottest_code() {
+        let mut stats = RunStats::default();
+
+        stats.record_instruction(0x1000, 0x2000, 0x2000);
+        stats.record_instruction(0x1004, 0x2000, 0x1fe0);
+        stats.record_instruction(0x1004, 0x1fe0, 0x1ff0);
+        stats.record_instruction(0x1008, 0x1ff0, 0x2000);
+
+        assert_eq!(stats.instructions_retired, 4);
+        assert_eq!(stats.peak_stack(), 0x20);
+        assert_eq!(stats.hottest(2), vec![(0x1004, 2), (0x1000, 1)]);
+    }
+
+    #[test]
+    fn report_should_name_functions_and_show_the_heap_and_devices() {
+        let mut stats = RunStats {
+            heap_start: Some(0x3000),
+            ..RunStats::default()
+        };
+        for pc in [0x1000, 0x1004, 0x1008, 0x1100, 0x1100, 0x1200] {
+            stats.record_instruction(pc, 0x8000, 0x7f00);
+        }
+        // below the heap, in it, and on the stack
+        stats.record_write(0x2000, 4);
+        stats.record_write(0x3100, 4);
+        stats.record_write(0x7f10, 4);
+        stats.record_device(0x1000_0000, "uart", true);
+        stats.record_device(0x1000_0000, "uart", true);
+        stats.record_device(0x1000_0000, "uart", false);
+
+        let function = |name: &str, address, size| Symbol {
+            name: name.to_string(),
+            address,
+            size,
+            kind: SymbolKind::Function,
+        };
+        let symbols = [function("main", 0x1000, 0x100), function("loop", 0x1100, 4)];
+        assert_eq!(
+            stats.hottest_functions(&symbols, 10),
+            [
+                ("main".to_string(), 3),
+                ("loop".to_string(), 2),
+                ("0x00001200".to_string(), 1)
+            ]
+        );
+        assert_eq!(stats.peak_heap(), 0x104);
+
+        let report = stats.report(&symbols, None).to_string();
+        assert!(report.contains("peak heap:            260 bytes"));
+        assert!(report.contains("  main                     3"));
+        assert!(report.contains("  uart at 0x10000000       1 reads, 2 writes"));
+        assert!(!report.contains("guest time"));
+    }
+}
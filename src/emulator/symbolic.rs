@@ -0,0 +1,759 @@
+//! An experiment in concolic execution, behind the `symbolic` feature. The
+//! vm runs the guest concretely as always, and `Symbolic` (a `VmHooks`)
+//! follows along with an expression for every register and memory byte
+//! that depends on the input: the bytes loaded from the source ranges
+//! (`in0`, `in1`, ... in the order the guest reads them) or marked symbolic
+//! by the host. A branch on such a value adds a path constraint, the
+//! condition in the direction the run took.
+//!
+//! The constraints come out as SMT-LIB (`QF_BV`) for a solver like z3:
+//! `path_smt` is the path of the run, `query_to_reach(pc)` the path up to
+//! the first branch that went past `pc`, with that branch flipped; its
+//! model is an input that gets there. Atomics, vector instructions, CSRs
+//! and loads through symbolic pointers use the concrete values.
+
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+use super::emulator::{Instruction, Vm, VmState};
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Xor,
+    Or,
+    And,
+    Sll,
+    Srl,
+    Sra,
+    /// 1 if less than (signed), 0 otherwise
+    Slt,
+    Sltu,
+}
+
+/// a 32 bit value computed from the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Const(u32),
+    /// the input byte `n`, zero extended
+    Input(usize),
+    Binary(BinaryOp, Rc<Expr>, Rc<Expr>),
+    /// byte `n` of the value, zero extended
+    Byte(Rc<Expr>, u8),
+    /// the low `bits` of the value, sign extended
+    SignExtend(Rc<Expr>, u8),
+}
+
+/// ```text
+/// (bvadd ((_ zero_extend 24) in0) #x00000001)
+/// ```
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Const(value) => write!(f, "#x{value:08x}"),
+            Self::Input(n) => write!(f, "((_ zero_extend 24) in{n})"),
+            Self::Binary(op, a, b) => {
+                let name = match op {
+                    BinaryOp::Add => "bvadd",
+                    BinaryOp::Sub => "bvsub",
+                    BinaryOp::Xor => "bvxor",
+                    BinaryOp::Or => "bvor",
+                    BinaryOp::And => "bvand",
+                    BinaryOp::Sll => "bvshl",
+                    BinaryOp::Srl => "bvlshr",
+                    BinaryOp::Sra => "bvashr",
+                    BinaryOp::Slt => "bvslt",
+                    BinaryOp::Sltu => "bvult",
+                };
+                match op {
+                    // RISC-V only uses the low 5 bits of the shift amount
+                    BinaryOp::Sll | BinaryOp::Srl | BinaryOp::Sra => {
+                        write!(f, "({name} {a} (bvand {b} #x0000001f))")
+                    }
+                    BinaryOp::Slt | BinaryOp::Sltu => {
+                        write!(f, "(ite ({name} {a} {b}) #x00000001 #x00000000)")
+                    }
+                    _ => write!(f, "({name} {a} {b})"),
+                }
+            }
+            Self::Byte(value, n) => {
+                let (high, low) = (8 * n + 7, 8 * n);
+                write!(f, "((_ zero_extend 24) ((_ extract {high} {low}) {value}))")
+            }
+            Self::SignExtend(value, bits) => write!(
+                f,
+                "((_ sign_extend {}) ((_ extract {} 0) {value}))",
+                32 - bits,
+                bits - 1
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+impl Comparison {
+    fn negated(self) -> Self {
+        match self {
+            Self::Eq => Self::Ne,
+            Self::Ne => Self::Eq,
+            Self::Lt => Self::Ge,
+            Self::Ge => Self::Lt,
+            Self::Ltu => Self::Geu,
+            Self::Geu => Self::Ltu,
+        }
+    }
+}
+
+/// a branch on a symbolic value in the direction the run took
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathConstraint {
+    pub pc: u32,
+    /// what held at the branch, the branch condition or its negation
+    pub comparison: Comparison,
+    pub a: Rc<Expr>,
+    pub b: Rc<Expr>,
+    /// where the other direction goes
+    pub other: u32,
+}
+
+impl PathConstraint {
+    /// the assertion of it, `flipped` for the other direction
+    fn to_smt(&self, flipped: bool) -> String {
+        let comparison = if flipped {
+            self.comparison.negated()
+        } else {
+            self.comparison
+        };
+        let (a, b) = (&self.a, &self.b);
+        match comparison {
+            Comparison::Eq => format!("(= {a} {b})"),
+            Comparison::Ne => format!("(not (= {a} {b}))"),
+            Comparison::Lt => format!("(bvslt {a} {b})"),
+            Comparison::Ge => format!("(bvsge {a} {b})"),
+            Comparison::Ltu => format!("(bvult {a} {b})"),
+            Comparison::Geu => format!("(bvuge {a} {b})"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SymbolicState {
+    sources: Vec<Range<u32>>,
+    /// the concrete value of every input byte so far
+    inputs: Vec<u8>,
+    registers: BTreeMap<Register, Rc<Expr>>,
+    /// the symbolic bytes, each one zero extended
+    memory: BTreeMap<u32, Rc<Expr>>,
+    /// what the current instruction writes to its register
+    result: Option<Rc<Expr>>,
+    /// sign extends the current load from that many bits
+    signed_load: Option<u8>,
+    /// what the current store stores
+    stored: Option<Rc<Expr>>,
+    constraints: Vec<PathConstraint>,
+}
+
+impl SymbolicState {
+    /// the expression of `register`, its value if it is concrete
+    fn operand(&self, vm_state: &VmState, register: Register) -> Rc<Expr> {
+        match self.registers.get(&register) {
+            Some(expr) => expr.clone(),
+            None => Rc::new(Expr::Const(vm_state.registers[register] as u32)),
+        }
+    }
+
+    fn is_symbolic(&self, register: Register) -> bool {
+        self.registers.contains_key(&register)
+    }
+
+    fn new_input(&mut self, value: u8) -> Rc<Expr> {
+        self.inputs.push(value);
+        Rc::new(Expr::Input(self.inputs.len() - 1))
+    }
+}
+
+/// The handle to the concolic executor while the vm owns the hooks. Create
+/// it with `Vm::add_symbolic`
+#[derive(Debug, Clone, Default)]
+pub struct Symbolic(Rc<RefCell<SymbolicState>>);
+
+impl Symbolic {
+    /// loads from the `length` bytes from `address` read new input bytes,
+    /// for devices like the UART
+    pub fn add_source(&self, address: u32, length: u32) {
+        let end = address.saturating_add(length);
+        self.0.borrow_mut().sources.push(address..end);
+    }
+
+    /// makes the `bytes` at `address` new input bytes, e.g. an input buffer
+    /// the host filled
+    pub fn make_symbolic(&self, address: u32, bytes: &[u8]) {
+        let mut state = self.0.borrow_mut();
+        for (offset, byte) in bytes.iter().enumerate() {
+            let input = state.new_input(*byte);
+            state.memory.insert(address + offset as u32, input);
+        }
+    }
+
+    /// the input bytes of the run so far, `in0` first
+    pub fn inputs(&self) -> Vec<u8> {
+        self.0.borrow().inputs.clone()
+    }
+
+    pub fn register(&self, register: Register) -> Option<Rc<Expr>> {
+        self.0.borrow().registers.get(&register).cloned()
+    }
+
+    pub fn constraints(&self) -> Vec<PathConstraint> {
+        self.0.borrow().constraints.clone()
+    }
+
+    /// the path of the run as an SMT-LIB script
+    pub fn path_smt(&self) -> String {
+        let state = self.0.borrow();
+        let assertions: Vec<String> = state
+            .constraints
+            .iter()
+            .map(|constraint| constraint.to_smt(false))
+            .collect();
+        smt_script(state.inputs.len(), &assertions)
+    }
+
+    /// an SMT-LIB script whose model is an input that takes the first branch
+    /// that went past `pc` the other way, `None` if no symbolic branch did
+    pub fn query_to_reach(&self, pc: u32) -> Option<String> {
+        let state = self.0.borrow();
+        let index = state
+            .constraints
+            .iter()
+            .position(|constraint| constraint.other == pc)?;
+        let assertions: Vec<String> = state.constraints[..=index]
+            .iter()
+            .enumerate()
+            .map(|(position, constraint)| constraint.to_smt(position == index))
+            .collect();
+        Some(smt_script(state.inputs.len(), &assertions))
+    }
+}
+
+fn smt_script(inputs: usize, assertions: &[String]) -> String {
+    let mut script = String::from("(set-logic QF_BV)\n");
+    for n in 0..inputs {
+        let _ = writeln!(script, "(declare-const in{n} (_ BitVec 8))");
+    }
+    for assertion in assertions {
+        let _ = writeln!(script, "(assert {assertion})");
+    }
+    script.push_str("(check-sat)\n(get-model)\n");
+    script
+}
+
+impl VmHooks for Symbolic {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        let mut state = self.0.borrow_mut();
+        state.result = None;
+        state.stored = None;
+        state.signed_load = None;
+        let Instruction::Rv32iInstruction(_, instruction) = instruction else {
+            return;
+        };
+        let pc = vm_state.pc as u32;
+        let binary = |op, a: Rc<Expr>, b: Rc<Expr>| Some(Rc::new(Expr::Binary(op, a, b)));
+        match instruction {
+            Rv32iInstruction::Add(r)
+            | Rv32iInstruction::Sub(r)
+            | Rv32iInstruction::Xor(r)
+            | Rv32iInstruction::Or(r)
+            | Rv32iInstruction::And(r)
+            | Rv32iInstruction::Sll(r)
+            | Rv32iInstruction::Srl(r)
+            | Rv32iInstruction::Sra(r)
+            | Rv32iInstruction::Slt(r)
+            | Rv32iInstruction::Sltu(r) => {
+                if !state.is_symbolic(r.rs1) && !state.is_symbolic(r.rs2) {
+                    return;
+                }
+                let op = match instruction {
+                    Rv32iInstruction::Add(_) => BinaryOp::Add,
+                    Rv32iInstruction::Sub(_) => BinaryOp::Sub,
+                    Rv32iInstruction::Xor(_) => BinaryOp::Xor,
+                    Rv32iInstruction::Or(_) => BinaryOp::Or,
+                    Rv32iInstruction::And(_) => BinaryOp::And,
+                    Rv32iInstruction::Sll(_) => BinaryOp::Sll,
+                    Rv32iInstruction::Srl(_) => BinaryOp::Srl,
+                    Rv32iInstruction::Sra(_) => BinaryOp::Sra,
+                    Rv32iInstruction::Slt(_) => BinaryOp::Slt,
+                    _ => BinaryOp::Sltu,
+                };
+                let (a, b) = (
+                    state.operand(vm_state, r.rs1),
+                    state.operand(vm_state, r.rs2),
+                );
+                state.result = binary(op, a, b);
+            }
+            Rv32iInstruction::Addi(i)
+            | Rv32iInstruction::Xori(i)
+            | Rv32iInstruction::Ori(i)
+            | Rv32iInstruction::Andi(i)
+            | Rv32iInstruction::Slli(i)
+            | Rv32iInstruction::Srli(i)
+            | Rv32iInstruction::Srai(i)
+            | Rv32iInstruction::Slti(i)
+            | Rv32iInstruction::Sltiu(i) => {
+                let Some(a) = state.registers.get(&i.rs1).cloned() else {
+                    return;
+                };
+                let op = match instruction {
+                    Rv32iInstruction::Addi(_) => BinaryOp::Add,
+                    Rv32iInstruction::Xori(_) => BinaryOp::Xor,
+                    Rv32iInstruction::Ori(_) => BinaryOp::Or,
+                    Rv32iInstruction::Andi(_) => BinaryOp::And,
+                    Rv32iInstruction::Slli(_) => BinaryOp::Sll,
+                    Rv32iInstruction::Srli(_) => BinaryOp::Srl,
+                    Rv32iInstruction::Srai(_) => BinaryOp::Sra,
+                    Rv32iInstruction::Slti(_) => BinaryOp::Slt,
+                    _ => BinaryOp::Sltu,
+                };
+                let imm = Rc::new(Expr::Const(i32::from(i.imm) as u32));
+                state.result = binary(op, a, imm);
+            }
+            Rv32iInstruction::Lb(_) => state.signed_load = Some(8),
+            Rv32iInstruction::Lh(_) => state.signed_load = Some(16),
+            Rv32iInstruction::Sb(s) | Rv32iInstruction::Sh(s) | Rv32iInstruction::Sw(s) => {
+                state.stored = state.registers.get(&s.rs2).cloned();
+            }
+            Rv32iInstruction::Beq(b)
+            | Rv32iInstruction::Bne(b)
+            | Rv32iInstruction::Blt(b)
+            | Rv32iInstruction::Bge(b)
+            | Rv32iInstruction::Bltu(b)
+            | Rv32iInstruction::Bgeu(b) => {
+                if !state.is_symbolic(b.rs1) && !state.is_symbolic(b.rs2) {
+                    return;
+                }
+                let comparison = match instruction {
+                    Rv32iInstruction::Beq(_) => Comparison::Eq,
+                    Rv32iInstruction::Bne(_) => Comparison::Ne,
+                    Rv32iInstruction::Blt(_) => Comparison::Lt,
+                    Rv32iInstruction::Bge(_) => Comparison::Ge,
+                    Rv32iInstruction::Bltu(_) => Comparison::Ltu,
+                    _ => Comparison::Geu,
+                };
+                let (x, y) = (vm_state.registers[b.rs1], vm_state.registers[b.rs2]);
+                let taken = match comparison {
+                    Comparison::Eq => x == y,
+                    Comparison::Ne => x != y,
+                    Comparison::Lt => x < y,
+                    Comparison::Ge => x >= y,
+                    Comparison::Ltu => (x as u32) < y as u32,
+                    Comparison::Geu => x as u32 >= y as u32,
+                };
+                let target = pc.wrapping_add(i32::from(b.imm) as u32);
+                let (comparison, other) = if taken {
+                    (comparison, pc.wrapping_add(4))
+                } else {
+                    (comparison.negated(), target)
+                };
+                let constraint = PathConstraint {
+                    pc,
+                    comparison,
+                    a: state.operand(vm_state, b.rs1),
+                    b: state.operand(vm_state, b.rs2),
+                    other,
+                };
+                state.constraints.push(constraint);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_memory_read(&mut self, _pc: u32, address: u32, size: u32, value: u32) {
+        let mut state = self.0.borrow_mut();
+        let from_source = state.sources.iter().any(|range| range.contains(&address));
+        let mut bytes = Vec::new();
+        for n in 0..size {
+            let concrete = (value >> (8 * n)) as u8;
+            let byte = if from_source {
+                Some(state.new_input(concrete))
+            } else {
+                state.memory.get(&(address + n)).cloned()
+            };
+            bytes.push(byte.unwrap_or_else(|| Rc::new(Expr::Const(concrete.into()))));
+        }
+        if !from_source && (0..size).all(|n| !state.memory.contains_key(&(address + n))) {
+            return;
+        }
+        let mut loaded = bytes[0].clone();
+        for (n, byte) in bytes.into_iter().enumerate().skip(1) {
+            let shift = Rc::new(Expr::Const(8 * n as u32));
+            let byte = Rc::new(Expr::Binary(BinaryOp::Sll, byte, shift));
+            loaded = Rc::new(Expr::Binary(BinaryOp::Or, loaded, byte));
+        }
+        if let Some(bits) = state.signed_load {
+            loaded = Rc::new(Expr::SignExtend(loaded, bits));
+        }
+        state.result = Some(loaded);
+    }
+
+    fn on_memory_write(&mut self, _pc: u32, address: u32, size: u32, _value: u32) {
+        let mut state = self.0.borrow_mut();
+        for n in 0..size {
+            match state.stored.clone() {
+                Some(stored) => {
+                    let byte = Rc::new(Expr::Byte(stored, n as u8));
+                    state.memory.insert(address + n, byte);
+                }
+                None => {
+                    state.memory.remove(&(address + n));
+                }
+            }
+        }
+    }
+
+    fn on_register_write(&mut self, _pc: u32, register: Register, _value: i32) {
+        let mut state = self.0.borrow_mut();
+        match state.result.clone() {
+            Some(result) => state.registers.insert(register, result),
+            None => state.registers.remove(&register),
+        };
+    }
+}
+
+impl Vm {
+    /// starts following the input symbolically, the returned handle sets the
+    /// sources and gives the path constraints
+    pub fn add_symbolic(&mut self) -> Symbolic {
+        let symbolic = Symbolic::default();
+        self.add_hooks(Box::new(symbolic.clone()));
+        symbolic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+
+    use super::{BinaryOp, Expr, Symbolic};
+    use crate::uart::{Uart, UART_BASE, UART_SIZE};
+    use crate::{Register, StopReason, Vm};
+
+    /// where `run` puts the symbolic bytes
+    const INPUT: u32 = 0x1080;
+
+    /// runs `program` to its `ebreak` with `input` at `INPUT`, every byte
+    /// of it symbolic
+    fn run(program: &[u32], input: &[u8]) -> Symbolic {
+        let program: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.load_program(INPUT, input).unwrap();
+        let symbolic = vm.add_symbolic();
+        symbolic.make_symbolic(INPUT, input);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        symbolic
+    }
+
+    /// what `instruction` computes into a2 from the symbolic a0 and a1 = 3
+    fn alu(instruction: u32) -> Option<String> {
+        let symbolic = run(
+            &[
+                // 0x1000 lui t0, 1
+                0x0000_12b7,
+                // 0x1004 lbu a0, 0x80(t0)
+                0x0802_c503,
+                // 0x1008 addi a1, zero, 3
+                0x0030_0593,
+                // 0x100c the instruction
+                instruction,
+                // 0x1010 ebreak
+                0x0010_0073,
+            ],
+            &[7],
+        );
+        symbolic.register(Register::A2).map(|expr| expr.to_string())
+    }
+
+    #[test]
+    fn should_print_every_expression_as_smt_lib() {
+        let input = Rc::new(Expr::Input(2));
+        let three = Rc::new(Expr::Const(3));
+        let binary = |op| Expr::Binary(op, input.clone(), three.clone()).to_string();
+
+        let in2 = "((_ zero_extend 24) in2)";
+        assert_eq!(input.to_string(), in2);
+        assert_eq!(three.to_string(), "#x00000003");
+        assert_eq!(binary(BinaryOp::Add), format!("(bvadd {in2} #x00000003)"));
+        assert_eq!(binary(BinaryOp::Sub), format!("(bvsub {in2} #x00000003)"));
+        assert_eq!(binary(BinaryOp::Xor), format!("(bvxor {in2} #x00000003)"));
+        assert_eq!(binary(BinaryOp::Or), format!("(bvor {in2} #x00000003)"));
+        assert_eq!(binary(BinaryOp::And), format!("(bvand {in2} #x00000003)"));
+        // only the low 5 bits of the shift amount count
+        assert_eq!(
+            binary(BinaryOp::Sll),
+            format!("(bvshl {in2} (bvand #x00000003 #x0000001f))")
+        );
+        assert_eq!(
+            binary(BinaryOp::Srl),
+            format!("(bvlshr {in2} (bvand #x00000003 #x0000001f))")
+        );
+        assert_eq!(
+            binary(BinaryOp::Sra),
+            format!("(bvashr {in2} (bvand #x00000003 #x0000001f))")
+        );
+        // the comparisons are 0 or 1
+        assert_eq!(
+            binary(BinaryOp::Slt),
+            format!("(ite (bvslt {in2} #x00000003) #x00000001 #x00000000)")
+        );
+        assert_eq!(
+            binary(BinaryOp::Sltu),
+            format!("(ite (bvult {in2} #x00000003) #x00000001 #x00000000)")
+        );
+        assert_eq!(
+            Expr::Byte(input.clone(), 1).to_string(),
+            format!("((_ zero_extend 24) ((_ extract 15 8) {in2}))")
+        );
+        assert_eq!(
+            Expr::SignExtend(input, 8).to_string(),
+            format!("((_ sign_extend 24) ((_ extract 7 0) {in2}))")
+        );
+    }
+
+    #[test]
+    fn should_build_an_expression_for_every_alu_instruction() {
+        let in0 = "((_ zero_extend 24) in0)";
+        let shift = "(bvand #x00000003 #x0000001f)";
+        let cases = [
+            // add a2, a0, a1
+            (0x00b5_0633, format!("(bvadd {in0} #x00000003)")),
+            // sub a2, a0, a1
+            (0x40b5_0633, format!("(bvsub {in0} #x00000003)")),
+            // xor a2, a0, a1
+            (0x00b5_4633, format!("(bvxor {in0} #x00000003)")),
+            // or a2, a0, a1
+            (0x00b5_6633, format!("(bvor {in0} #x00000003)")),
+            // and a2, a0, a1
+            (0x00b5_7633, format!("(bvand {in0} #x00000003)")),
+            // sll a2, a0, a1
+            (0x00b5_1633, format!("(bvshl {in0} {shift})")),
+            // srl a2, a0, a1
+            (0x00b5_5633, format!("(bvlshr {in0} {shift})")),
+            // sra a2, a0, a1
+            (0x40b5_5633, format!("(bvashr {in0} {shift})")),
+            // slt a2, a0, a1
+            (
+                0x00b5_2633,
+                format!("(ite (bvslt {in0} #x00000003) #x00000001 #x00000000)"),
+            ),
+            // sltu a2, a0, a1
+            (
+                0x00b5_3633,
+                format!("(ite (bvult {in0} #x00000003) #x00000001 #x00000000)"),
+            ),
+            // addi a2, a0, -1, the immediate is sign extended
+            (0xfff5_0613, format!("(bvadd {in0} #xffffffff)")),
+        ];
+        for (instruction, expected) in cases {
+            assert_eq!(alu(instruction), Some(expected), "{instruction:#010x}");
+        }
+    }
+
+    #[test]
+    fn concrete_operands_should_stay_concrete() {
+        // add a2, a1, a1 does not touch the input
+        assert_eq!(alu(0x00b5_8633), None);
+    }
+
+    #[test]
+    fn should_follow_symbolic_bytes_through_stores_and_loads() {
+        let symbolic = run(
+            &[
+                // 0x1000 lui t0, 1
+                0x0000_12b7,
+                // 0x1004 lh a0, 0x80(t0)
+                0x0802_9503,
+                // 0x1008 sh a0, 0x90(t0)
+                0x08a2_9823,
+                // 0x100c lhu a1, 0x90(t0)
+                0x0902_d583,
+                // 0x1010 sb zero, 0x90(t0)
+                0x0802_8823,
+                // 0x1014 lbu a2, 0x90(t0)
+                0x0902_c603,
+                // 0x1018 lbu a3, 0x91(t0)
+                0x0912_c683,
+                // 0x101c ebreak
+                0x0010_0073,
+            ],
+            &[0x12, 0x80],
+        );
+        let expr = |register| symbolic.register(register).map(|expr| expr.to_string());
+
+        // the two input bytes, in1 shifted over in0, sign extended by lh
+        let a0 = "((_ sign_extend 16) ((_ extract 15 0) \
+                  (bvor ((_ zero_extend 24) in0) \
+                  (bvshl ((_ zero_extend 24) in1) (bvand #x00000008 #x0000001f)))))";
+        assert_eq!(expr(Register::A0).as_deref(), Some(a0));
+        // sh stored the two bytes of a0, lhu puts them together again
+        let byte = |n: u8| {
+            format!(
+                "((_ zero_extend 24) ((_ extract {} {}) {a0}))",
+                8 * n + 7,
+                8 * n
+            )
+        };
+        assert_eq!(
+            expr(Register::A1),
+            Some(format!(
+                "(bvor {} (bvshl {} (bvand #x00000008 #x0000001f)))",
+                byte(0),
+                byte(1)
+            ))
+        );
+        // sb zero made the low byte concrete, the high one is still a0's
+        assert_eq!(expr(Register::A2), None);
+        assert_eq!(expr(Register::A3), Some(byte(1)));
+        assert_eq!(symbolic.inputs(), [0x12, 0x80]);
+    }
+
+    #[test]
+    fn should_emit_the_path_and_flip_only_the_last_branch() {
+        let symbolic = run(
+            &[
+                // 0x1000 lui t0, 1
+                0x0000_12b7,
+                // 0x1004 lbu a0, 0x80(t0)      <- in0
+                0x0802_c503,
+                // 0x1008 lbu a1, 0x81(t0)      <- in1
+                0x0812_c583,
+                // 0x100c bltu a0, a1, 0x1014   taken
+                0x00b5_6463,
+                // 0x1010 ebreak
+                0x0010_0073,
+                // 0x1014 addi a2, zero, 0x61
+                0x0610_0613,
+                // 0x1018 blt a2, a0, 0x1020    not taken
+                0x00a6_4463,
+                // 0x101c ebreak
+                0x0010_0073,
+                // 0x1020 ebreak
+                0x0010_0073,
+            ],
+            b"ab",
+        );
+        assert_eq!(symbolic.constraints().len(), 2);
+
+        assert_eq!(
+            symbolic.path_smt(),
+            "(set-logic QF_BV)\n\
+             (declare-const in0 (_ BitVec 8))\n\
+             (declare-const in1 (_ BitVec 8))\n\
+             (assert (bvult ((_ zero_extend 24) in0) ((_ zero_extend 24) in1)))\n\
+             (assert (bvsge #x00000061 ((_ zero_extend 24) in0)))\n\
+             (check-sat)\n\
+             (get-model)\n"
+        );
+        assert_eq!(
+            symbolic.query_to_reach(0x1020).unwrap(),
+            "(set-logic QF_BV)\n\
+             (declare-const in0 (_ BitVec 8))\n\
+             (declare-const in1 (_ BitVec 8))\n\
+             (assert (bvult ((_ zero_extend 24) in0) ((_ zero_extend 24) in1)))\n\
+             (assert (bvslt #x00000061 ((_ zero_extend 24) in0)))\n\
+             (check-sat)\n\
+             (get-model)\n"
+        );
+        assert_eq!(
+            symbolic.query_to_reach(0x1010).unwrap(),
+            "(set-logic QF_BV)\n\
+             (declare-const in0 (_ BitVec 8))\n\
+             (declare-const in1 (_ BitVec 8))\n\
+             (assert (bvuge ((_ zero_extend 24) in0) ((_ zero_extend 24) in1)))\n\
+             (check-sat)\n\
+             (get-model)\n"
+        );
+    }
+
+    #[test]
+    fn should_collect_the_path_and_a_query_for_the_other_branch() {
+        // 0x1000 lui t0, 0x10000
+        // 0x1004 lbu a0, 0(t0)         <- in0
+        // 0x1008 addi a0, a0, 1
+        // 0x100c addi a1, zero, 98
+        // 0x1010 bne a0, a1, 0x101c
+        // 0x1014 addi a2, zero, 1      <- reached with 'a'
+        // 0x1018 ebreak
+        // 0x101c addi a2, zero, 2
+        // 0x1020 ebreak
+        let program: Vec<u8> = [
+            0x1000_02b7u32,
+            0x0002_c503,
+            0x0015_0513,
+            0x0620_0593,
+            0x00b5_1663,
+            0x0010_0613,
+            0x0010_0073,
+            0x0020_0613,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device(UART_BASE, UART_SIZE, Box::new(Uart::default()));
+        vm.device_mut::<Uart>(UART_BASE)
+            .unwrap()
+            .input
+            .push_back(b'x');
+
+        let symbolic = vm.add_symbolic();
+        symbolic.add_source(UART_BASE, 1);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.pc, 0x1020);
+        assert_eq!(symbolic.inputs(), b"x");
+        assert!(symbolic.register(Register::A0).is_some());
+        assert!(symbolic.register(Register::A2).is_none());
+
+        let a0 = "(bvadd ((_ zero_extend 24) in0) #x00000001)";
+        assert_eq!(
+            symbolic.path_smt(),
+            format!(
+                "(set-logic QF_BV)\n\
+                 (declare-const in0 (_ BitVec 8))\n\
+                 (assert (not (= {a0} #x00000062)))\n\
+                 (check-sat)\n(get-model)\n"
+            )
+        );
+        assert_eq!(
+            symbolic.query_to_reach(0x1014).unwrap(),
+            format!(
+                "(set-logic QF_BV)\n\
+                 (declare-const in0 (_ BitVec 8))\n\
+                 (assert (= {a0} #x00000062))\n\
+                 (check-sat)\n(get-model)\n"
+            )
+        );
+        assert_eq!(symbolic.query_to_reach(0x1018), None);
+    }
+}
@@ -0,0 +1,329 @@
+//! The Linux syscalls a user program needs for files, printing and exiting,
+//! answered on the host like a proxy kernel. The number is in a7, the
+//! arguments in a0-a5 and the result (or a negative errno) goes to a0.
+//! Numbers it does not know go on to the host ecall handlers.
+//!
+//! The files come from the `FileSystem` of `fs.rs`; without one `openat`
+//! fails with `EACCES` and only fds 0-2 exist. fd 0 is always at its end,
+//! what the guest writes to 1 and 2 is collected, the host takes it out
+//! between `run_for` slices (that is how `riscv-vm run` streams it).
+//!
+//! `lseek` is the 32 bit one newlib calls, offset in a1 and whence in a2.
+//! `fstat` fills the asm-generic `struct stat` with the type, size and
+//! block size, the rest is 0.
+
+use super::emulator::{StopReason, Vm};
+use super::fs::{FileHandle, FileSystem, SeekFrom};
+use super::pod::Pod;
+use super::prelude::*;
+use super::register::Register;
+use super::strace::read_path;
+
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EEXIST: i32 = 17;
+pub const EISDIR: i32 = 21;
+pub const EINVAL: i32 = 22;
+pub const EMFILE: i32 = 24;
+
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+const AT_FDCWD: i32 = -100;
+/// the first fd `openat` hands out
+const FIRST_FILE: i32 = 3;
+const MAX_FILES: usize = 1024;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFREG: u32 = 0o100000;
+const BLOCK_SIZE: u32 = 4096;
+
+/// the `struct stat` of RV32 Linux, the fields left out are 0
+struct Stat {
+    mode: u32,
+    size: u64,
+}
+
+impl Pod for Stat {
+    const SIZE: usize = 128;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            mode: u32::from_bytes(&bytes[16..20]),
+            size: u64::from_bytes(&bytes[48..56]),
+        }
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8]) {
+        self.mode.to_bytes(&mut bytes[16..20]);
+        // st_nlink
+        1u32.to_bytes(&mut bytes[20..24]);
+        self.size.to_bytes(&mut bytes[48..56]);
+        BLOCK_SIZE.to_bytes(&mut bytes[56..60]);
+        self.size.div_ceil(512).to_bytes(&mut bytes[64..72]);
+    }
+}
+
+#[derive(Default)]
+pub struct Syscalls {
+    /// what the guest wrote to fd 1
+    pub stdout: Vec<u8>,
+    /// what the guest wrote to fd 2
+    pub stderr: Vec<u8>,
+    /// where `openat` looks, `None` lets the guest open nothing
+    pub fs: Option<Box<dyn FileSystem>>,
+    /// the files the guest opened, by fd
+    files: HashMap<i32, Box<dyn FileHandle>>,
+}
+
+impl Vm {
+    /// answers the Linux syscalls on the host, see `syscalls.rs`
+    pub fn with_syscalls(mut self) -> Self {
+        self.syscalls = Some(Syscalls::default());
+        self
+    }
+
+    /// answers the Linux syscalls with the files of `fs`
+    pub fn with_file_system(mut self, fs: impl FileSystem + 'static) -> Self {
+        self.syscalls.get_or_insert_with(Syscalls::default).fs = Some(Box::new(fs));
+        self
+    }
+
+    /// handles syscall `number`, false if the syscalls are off or it does
+    /// not know the number. The caller moves past the ecall
+    pub(super) fn syscall(&mut self, number: u32) -> bool {
+        if self.syscalls.is_none() {
+            return false;
+        }
+        let registers = &self.vm_state.registers;
+        let [a0, a1, a2] = [Register::A0, Register::A1, Register::A2].map(|r| registers[r]);
+
+        let result = match number {
+            SYS_OPENAT => self.openat(a0, a1 as u32, a2 as u32),
+            SYS_CLOSE => self.close(a0),
+            SYS_LSEEK => self.lseek(a0, a1, a2),
+            SYS_READ => self.read(a0, a1 as u32, a2 as u32),
+            SYS_WRITE => self.write(a0, a1 as u32, a2 as u32),
+            SYS_FSTAT => self.fstat(a0, a1 as u32),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.stop = Some(StopReason::Exit { code: a0 });
+                Ok(0)
+            }
+            _ => return false,
+        };
+        self.vm_state
+            .registers
+            .write(Register::A0, result.unwrap_or_else(|errno| -errno));
+        true
+    }
+
+    fn syscalls(&mut self) -> &mut Syscalls {
+        self.syscalls.as_mut().expect("the syscalls are on")
+    }
+
+    fn openat(&mut self, directory: i32, path: u32, flags: u32) -> Result<i32, i32> {
+        let path = read_path(&self.memory, path).ok_or(EFAULT)?;
+        let path = String::from_utf8(path).map_err(|_| ENOENT)?;
+        if directory != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let max_files = self
+            .limits
+            .max_open_files
+            .map_or(MAX_FILES, |max| max.min(MAX_FILES));
+        let syscalls = self.syscalls();
+        if syscalls.files.len() >= max_files {
+            return Err(EMFILE);
+        }
+        let file = syscalls.fs.as_mut().ok_or(EACCES)?.open(&path, flags)?;
+        let fd = (FIRST_FILE..)
+            .find(|fd| !syscalls.files.contains_key(fd))
+            .expect("there are fewer than MAX_FILES");
+        syscalls.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: i32) -> Result<i32, i32> {
+        match fd {
+            0..FIRST_FILE => Ok(0),
+            _ => self.syscalls().files.remove(&fd).map(|_| 0).ok_or(EBADF),
+        }
+    }
+
+    fn file(&mut self, fd: i32) -> Result<&mut Box<dyn FileHandle>, i32> {
+        self.syscalls().files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    fn lseek(&mut self, fd: i32, offset: i32, whence: i32) -> Result<i32, i32> {
+        let position = match whence {
+            0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+            1 => SeekFrom::Current(offset.into()),
+            2 => SeekFrom::End(offset.into()),
+            _ => return Err(EINVAL),
+        };
+        let position = self.file(fd)?.seek(position)?;
+        i32::try_from(position).map_err(|_| EINVAL)
+    }
+
+    fn read(&mut self, fd: i32, address: u32, length: u32) -> Result<i32, i32> {
+        if fd == 0 {
+            return Ok(0);
+        }
+        let mut buffer = vec![0; length as usize];
+        let read = self.file(fd)?.read(&mut buffer)?;
+        self.memory
+            .write_bytes(address, &buffer[..read])
+            .map_err(|_| EFAULT)?;
+        Ok(read as i32)
+    }
+
+    fn write(&mut self, fd: i32, address: u32, length: u32) -> Result<i32, i32> {
+        let bytes = self
+            .memory
+            .read_bytes(address, length as usize)
+            .map_err(|_| EFAULT)?
+            .to_vec();
+        let syscalls = self.syscalls();
+        match fd {
+            1 => syscalls.stdout.extend_from_slice(&bytes),
+            2 => syscalls.stderr.extend_from_slice(&bytes),
+            _ => return self.file(fd)?.write(&bytes).map(|written| written as i32),
+        }
+        Ok(length as i32)
+    }
+
+    fn fstat(&mut self, fd: i32, address: u32) -> Result<i32, i32> {
+        let (mode, size) = match fd {
+            0..FIRST_FILE => (S_IFCHR | 0o620, 0),
+            _ => (S_IFREG | 0o644, self.file(fd)?.size()?),
+        };
+        self.memory
+            .write_pod(address, &Stat { mode, size })
+            .map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::VirtFs;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_print_and_exit() {
+        // 0x1000 addi a0, zero, 1
+        // 0x1004 lui a1, 0x1
+        // 0x1008 addi a1, a1, 0x20
+        // 0x100c addi a2, zero, 3
+        // 0x1010 addi a7, zero, 64     <- write(1, "hi\n", 3)
+        // 0x1014 ecall
+        // 0x1018 addi a7, zero, 93     <- exit(3)
+        // 0x101c ecall
+        // 0x1020 "hi\n"
+        let mut program: Vec<u8> = [
+            0x0010_0513u32,
+            0x0000_15b7,
+            0x0205_8593,
+            0x0030_0613,
+            0x0400_0893,
+            0x0000_0073,
+            0x05d0_0893,
+            0x0000_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        program.extend_from_slice(b"hi\n");
+        let mut vm = Vm::new(0x1000, 0x100).with_syscalls();
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(vm.run(), Ok(StopReason::Exit { code: 3 }));
+        assert_eq!(vm.syscalls.as_ref().unwrap().stdout, b"hi\n");
+        assert_eq!(vm.vm_state.pc, 0x1020);
+    }
+
+    #[test]
+    fn should_open_read_and_write_files() {
+        // 0x1000 addi a0, zero, -100
+        // 0x1004 lui a1, 0x1
+        // 0x1008 addi a1, a1, 0x80
+        // 0x100c addi a2, zero, 0x442
+        // 0x1010 addi a7, zero, 56     <- openat(AT_FDCWD, "f", O_RDWR | O_CREAT | O_APPEND)
+        // 0x1014 ecall
+        // 0x1018 addi s0, a0, 0
+        // 0x101c addi a1, a1, 2
+        // 0x1020 addi a2, zero, 2
+        // 0x1024 addi a7, zero, 64     <- write(fd, "ok", 2)
+        // 0x1028 ecall
+        // 0x102c addi a0, s0, 0
+        // 0x1030 addi a1, zero, 0
+        // 0x1034 addi a2, zero, 0
+        // 0x1038 addi a7, zero, 62     <- lseek(fd, 0, SEEK_SET)
+        // 0x103c ecall
+        // 0x1040 addi a0, s0, 0
+        // 0x1044 lui a1, 0x1
+        // 0x1048 addi a1, a1, 0x90
+        // 0x104c addi a2, zero, 16
+        // 0x1050 addi a7, zero, 63     <- read(fd, 0x1090, 16)
+        // 0x1054 ecall
+        // 0x1058 ebreak
+        // 0x1080 "f\0ok"
+        let mut program: Vec<u8> = [
+            0xf9c0_0513u32,
+            0x0000_15b7,
+            0x0805_8593,
+            0x4420_0613,
+            0x0380_0893,
+            0x0000_0073,
+            0x0005_0413,
+            0x0025_8593,
+            0x0020_0613,
+            0x0400_0893,
+            0x0000_0073,
+            0x0004_0513,
+            0x0000_0593,
+            0x0000_0613,
+            0x03e0_0893,
+            0x0000_0073,
+            0x0004_0513,
+            0x0000_15b7,
+            0x0905_8593,
+            0x0100_0613,
+            0x03f0_0893,
+            0x0000_0073,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        program.resize(0x80, 0);
+        program.extend_from_slice(b"f\0ok");
+        let mut fs = VirtFs::new();
+        fs.insert("f", "say ");
+        let mut vm = Vm::new(0x1000, 0x100).with_file_system(fs);
+        vm.load_program(0x1000, &program).unwrap();
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[8], 3);
+        assert_eq!(vm.vm_state.registers[10], 6);
+        assert_eq!(vm.memory.read_bytes(0x1090, 6).unwrap(), b"say ok");
+    }
+}
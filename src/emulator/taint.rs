@@ -0,0 +1,292 @@
+//! A taint tracker built on the hooks. Data from the sources (loads from
+//! address ranges like the UART's receive register, or bytes the host marks
+//! tainted) is tainted, and so is everything computed from it: the
+//! registers an instruction writes are tainted when one of its operands is,
+//! stores copy the taint of the stored register to the bytes, loads take
+//! the taint of the loaded bytes.
+//!
+//! `Taint` reports an indirect jump to a tainted target (the input controls
+//! the pc) and a tainted store into a sink range. Only data flows count, a
+//! load through a tainted pointer or a branch on tainted data taints
+//! nothing. Atomics and vector instructions only propagate the taint of
+//! their register operands, not of memory.
+
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::ops::Range;
+
+use super::emulator::{Instruction, PseudoInstruction, Vm, VmState};
+use super::hooks::VmHooks;
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+/// where tainted data went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaintReport {
+    /// the indirect jump at `pc` went to a tainted `target`
+    Pc { pc: u32, target: u32 },
+    /// the store at `pc` wrote tainted bytes into a sink
+    Sink { pc: u32, address: u32, size: u32 },
+}
+
+#[derive(Debug, Default)]
+struct TaintState {
+    sources: Vec<Range<u32>>,
+    sinks: Vec<Range<u32>>,
+    /// the tainted bytes
+    memory: BTreeSet<u32>,
+    /// one bit per register
+    registers: u32,
+    /// the taint of what the current instruction writes to its register
+    result: bool,
+    /// the taint of the register the current store stores
+    stored: bool,
+    reports: Vec<TaintReport>,
+}
+
+impl TaintState {
+    fn is_tainted(&self, register: Register) -> bool {
+        self.registers & 1 << register.index() != 0
+    }
+
+    fn overlaps(ranges: &[Range<u32>], address: u32, size: u32) -> bool {
+        let end = address.saturating_add(size);
+        ranges
+            .iter()
+            .any(|range| address < range.end && range.start < end)
+    }
+}
+
+/// The handle to the taint tracker while the vm owns the hooks. Create it
+/// with `Vm::add_taint`
+#[derive(Debug, Clone, Default)]
+pub struct Taint(Rc<RefCell<TaintState>>);
+
+impl Taint {
+    /// loads from the `length` bytes from `address` are tainted, for devices
+    /// like the UART
+    pub fn add_source(&self, address: u32, length: u32) {
+        let end = address.saturating_add(length);
+        self.0.borrow_mut().sources.push(address..end);
+    }
+
+    /// tainted stores into the `length` bytes from `address` are reported
+    pub fn add_sink(&self, address: u32, length: u32) {
+        let end = address.saturating_add(length);
+        self.0.borrow_mut().sinks.push(address..end);
+    }
+
+    /// marks the `length` bytes from `address` tainted, e.g. an input
+    /// buffer the host filled
+    pub fn taint_memory(&self, address: u32, length: u32) {
+        let end = address.saturating_add(length);
+        self.0.borrow_mut().memory.extend(address..end);
+    }
+
+    pub fn taint_register(&self, register: Register) {
+        self.0.borrow_mut().registers |= 1 << register.index();
+    }
+
+    pub fn is_memory_tainted(&self, address: u32) -> bool {
+        self.0.borrow().memory.contains(&address)
+    }
+
+    pub fn is_register_tainted(&self, register: Register) -> bool {
+        self.0.borrow().is_tainted(register)
+    }
+
+    /// what reached the pc or a sink so far, in order
+    pub fn reports(&self) -> Vec<TaintReport> {
+        self.0.borrow().reports.clone()
+    }
+}
+
+/// the registers the value an instruction writes to rd is computed from,
+/// and the register an indirect jump jumps through
+fn operands(instruction: &Instruction) -> (Vec<Register>, Option<Register>) {
+    let Instruction::Rv32iInstruction(_, instruction) = instruction else {
+        if let Instruction::PseudoInstruction(_, PseudoInstruction::Ret) = instruction {
+            return (Vec::new(), Some(Register::RA));
+        }
+        return (Vec::new(), None);
+    };
+    let operands = match instruction {
+        Rv32iInstruction::Add(r)
+        | Rv32iInstruction::Sub(r)
+        | Rv32iInstruction::Xor(r)
+        | Rv32iInstruction::Or(r)
+        | Rv32iInstruction::And(r)
+        | Rv32iInstruction::Sll(r)
+        | Rv32iInstruction::Srl(r)
+        | Rv32iInstruction::Sra(r)
+        | Rv32iInstruction::Slt(r)
+        | Rv32iInstruction::Sltu(r) => vec![r.rs1, r.rs2],
+        Rv32iInstruction::Addi(i)
+        | Rv32iInstruction::Xori(i)
+        | Rv32iInstruction::Ori(i)
+        | Rv32iInstruction::Andi(i)
+        | Rv32iInstruction::Slli(i)
+        | Rv32iInstruction::Srli(i)
+        | Rv32iInstruction::Srai(i)
+        | Rv32iInstruction::Slti(i)
+        | Rv32iInstruction::Sltiu(i) => vec![i.rs1],
+        Rv32iInstruction::Jalr(i) => return (Vec::new(), Some(i.rs1)),
+        Rv32iInstruction::LrW(r) => vec![r.rs1],
+        Rv32iInstruction::ScW(r)
+        | Rv32iInstruction::AmoswapW(r)
+        | Rv32iInstruction::AmoaddW(r)
+        | Rv32iInstruction::AmoxorW(r)
+        | Rv32iInstruction::AmoandW(r)
+        | Rv32iInstruction::AmoorW(r)
+        | Rv32iInstruction::AmominW(r)
+        | Rv32iInstruction::AmomaxW(r)
+        | Rv32iInstruction::AmominuW(r)
+        | Rv32iInstruction::AmomaxuW(r) => vec![r.rs1, r.rs2],
+        _ => Vec::new(),
+    };
+    (operands, None)
+}
+
+impl VmHooks for Taint {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        let mut state = self.0.borrow_mut();
+        let (operands, jump) = operands(instruction);
+        state.result = operands.iter().any(|register| state.is_tainted(*register));
+        state.stored = match instruction {
+            Instruction::Rv32iInstruction(
+                _,
+                Rv32iInstruction::Sb(s) | Rv32iInstruction::Sh(s) | Rv32iInstruction::Sw(s),
+            ) => state.is_tainted(s.rs2),
+            _ => false,
+        };
+        let Some(base) = jump.filter(|register| state.is_tainted(*register)) else {
+            return;
+        };
+        let offset = match instruction {
+            Instruction::Rv32iInstruction(_, Rv32iInstruction::Jalr(i)) => i32::from(i.imm),
+            _ => 0,
+        };
+        let target = vm_state.registers[base].wrapping_add(offset) as u32 & !1;
+        let pc = vm_state.pc as u32;
+        state.reports.push(TaintReport::Pc { pc, target });
+    }
+
+    fn on_memory_read(&mut self, _pc: u32, address: u32, size: u32, _value: u32) {
+        let mut state = self.0.borrow_mut();
+        let end = address.saturating_add(size);
+        state.result = TaintState::overlaps(&state.sources, address, size)
+            || state.memory.range(address..end).next().is_some();
+    }
+
+    fn on_memory_write(&mut self, pc: u32, address: u32, size: u32, _value: u32) {
+        let mut state = self.0.borrow_mut();
+        let end = address.saturating_add(size);
+        if !state.stored {
+            let tainted: Vec<u32> = state.memory.range(address..end).copied().collect();
+            for byte in tainted {
+                state.memory.remove(&byte);
+            }
+            return;
+        }
+        state.memory.extend(address..end);
+        if TaintState::overlaps(&state.sinks, address, size) {
+            state.reports.push(TaintReport::Sink { pc, address, size });
+        }
+    }
+
+    fn on_register_write(&mut self, _pc: u32, register: Register, _value: i32) {
+        let mut state = self.0.borrow_mut();
+        if state.result {
+            state.registers |= 1 << register.index();
+        } else {
+            state.registers &= !(1 << register.index());
+        }
+    }
+}
+
+impl Vm {
+    /// starts tracking taint, the returned handle sets the sources and
+    /// sinks and gives the reports
+    pub fn add_taint(&mut self) -> Taint {
+        let taint = Taint::default();
+        self.add_hooks(Box::new(taint.clone()));
+        taint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaintReport;
+    use crate::uart::{Uart, UART_BASE, UART_SIZE};
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_follow_uart_input_to_a_sink_and_the_pc() {
+        // 0x1000 lui t0, 0x10000
+        // 0x1004 lbu a0, 0(t0)         <- UART RX, the source
+        // 0x1008 addi a1, a0, 1
+        // 0x100c addi a2, zero, 5
+        // 0x1010 lui t1, 0x1
+        // 0x1014 sw a1, 128(t1)        <- into the sink
+        // 0x1018 sw a2, 132(t1)
+        // 0x101c lw a3, 128(t1)
+        // 0x1020 andi a5, a3, 0        <- still tainted
+        // 0x1024 add a5, a5, t1
+        // 0x1028 jalr zero, 48(a5)
+        // 0x102c ebreak
+        // 0x1030 ebreak
+        let program: Vec<u8> = [
+            0x1000_02b7u32,
+            0x0002_c503,
+            0x0015_0593,
+            0x0050_0613,
+            0x0000_1337,
+            0x08b3_2023,
+            0x08c3_2223,
+            0x0803_2683,
+            0x0006_f793,
+            0x0067_87b3,
+            0x0307_8067,
+            0x0010_0073,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device(UART_BASE, UART_SIZE, Box::new(Uart::default()));
+        vm.device_mut::<Uart>(UART_BASE)
+            .unwrap()
+            .input
+            .push_back(b'x');
+
+        let taint = vm.add_taint();
+        taint.add_source(UART_BASE, 1);
+        taint.add_sink(0x1080, 4);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.pc, 0x1030);
+
+        assert_eq!(
+            taint.reports(),
+            [
+                TaintReport::Sink {
+                    pc: 0x1014,
+                    address: 0x1080,
+                    size: 4,
+                },
+                TaintReport::Pc {
+                    pc: 0x1028,
+                    target: 0x1030,
+                },
+            ]
+        );
+        assert!(taint.is_register_tainted(Register::A1));
+        assert!(taint.is_register_tainted(Register::A3));
+        assert!(!taint.is_register_tainted(Register::A2));
+        assert!(taint.is_memory_tainted(0x1083));
+        assert!(!taint.is_memory_tainted(0x1084));
+    }
+}
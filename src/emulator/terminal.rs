@@ -0,0 +1,357 @@
+//! A small VT100/ANSI terminal for the console output of the guest. It keeps
+//! a grid of cells that a frontend can draw as it is, so full-screen guest
+//! programs (editors, menus) show up right without a terminal emulator on
+//! the web side.
+//!
+//! It understands the escape sequences such programs use: cursor movement,
+//! erasing, colors and bold/inverse text, scrolling and saving the cursor.
+//! Everything else is ignored.
+
+use super::prelude::*;
+
+/// the 8 ANSI colors plus their bright versions (8-15)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    Indexed(u8),
+}
+
+/// how a cell is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub foreground: Color,
+    pub background: Color,
+    pub bold: bool,
+    pub inverse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            foreground: Color::Default,
+            background: Color::Default,
+            bold: false,
+            inverse: false,
+        }
+    }
+}
+
+/// one character on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub character: char,
+    pub style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            character: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+/// where the parser is in an escape sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// after ESC
+    Escape,
+    /// after ESC [, with the parameters so far and whether it started with ?
+    Csi {
+        parameters: Vec<u16>,
+        private: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Terminal {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    /// column and row, the column can be `width` right after writing to the
+    /// last column, the next character wraps then
+    cursor: (usize, usize),
+    saved_cursor: (usize, usize),
+    pub cursor_visible: bool,
+    style: Style,
+    state: State,
+    /// the bytes of a UTF-8 character that is not complete yet
+    utf8: Vec<u8>,
+}
+
+impl Terminal {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+            cursor: (0, 0),
+            saved_cursor: (0, 0),
+            cursor_visible: true,
+            style: Style::default(),
+            state: State::Ground,
+            utf8: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// column and row of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor.0.min(self.width - 1), self.cursor.1)
+    }
+
+    pub fn cell(&self, column: usize, row: usize) -> Cell {
+        self.cells[row * self.width + column]
+    }
+
+    /// the screen row by row, for the frontend to draw
+    pub fn rows(&self) -> impl Iterator<Item = &[Cell]> {
+        self.cells.chunks(self.width)
+    }
+
+    /// the characters on the screen, one line per row without the trailing
+    /// spaces
+    pub fn text(&self) -> String {
+        self.rows()
+            .map(|row| {
+                let line: String = row.iter().map(|cell| cell.character).collect();
+                format!("{}\n", line.trim_end())
+            })
+            .collect()
+    }
+
+    /// processes what the guest wrote to the console
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.feed_byte(byte);
+        }
+    }
+
+    fn feed_byte(&mut self, byte: u8) {
+        match core::mem::replace(&mut self.state, State::Ground) {
+            State::Ground => self.ground(byte),
+            State::Escape => match byte {
+                b'[' => {
+                    self.state = State::Csi {
+                        parameters: Vec::new(),
+                        private: false,
+                    }
+                }
+                b'7' => self.saved_cursor = self.cursor,
+                b'8' => self.cursor = self.saved_cursor,
+                b'c' => *self = Self::new(self.width, self.height),
+                _ => {}
+            },
+            State::Csi {
+                mut parameters,
+                mut private,
+            } => match byte {
+                b'0'..=b'9' => {
+                    if parameters.is_empty() {
+                        parameters.push(0);
+                    }
+                    let last = parameters.last_mut().unwrap();
+                    *last = last.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    self.state = State::Csi {
+                        parameters,
+                        private,
+                    };
+                }
+                b';' => {
+                    if parameters.is_empty() {
+                        parameters.push(0);
+                    }
+                    parameters.push(0);
+                    self.state = State::Csi {
+                        parameters,
+                        private,
+                    };
+                }
+                b'?' => {
+                    private = true;
+                    self.state = State::Csi {
+                        parameters,
+                        private,
+                    };
+                }
+                0x40..=0x7e => self.csi(byte, &parameters, private),
+                // anything else breaks the sequence
+                _ => {}
+            },
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        match byte {
+            0x1b => {
+                self.utf8.clear();
+                self.state = State::Escape;
+            }
+            b'\n' => self.line_feed(),
+            b'\r' => self.cursor.0 = 0,
+            0x08 => self.cursor.0 = self.cursor().0.saturating_sub(1),
+            b'\t' => self.cursor.0 = ((self.cursor.0 / 8 + 1) * 8).min(self.width - 1),
+            0x00..=0x1f | 0x7f => {}
+            0x20..=0x7e => self.print(byte as char),
+            _ => {
+                self.utf8.push(byte);
+                match core::str::from_utf8(&self.utf8) {
+                    Ok(text) => {
+                        let character = text.chars().next().unwrap();
+                        self.utf8.clear();
+                        self.print(character);
+                    }
+                    Err(error) if error.error_len().is_some() => {
+                        self.utf8.clear();
+                        self.print(char::REPLACEMENT_CHARACTER);
+                    }
+                    // not complete yet
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    fn print(&mut self, character: char) {
+        if self.cursor.0 >= self.width {
+            self.cursor.0 = 0;
+            self.line_feed();
+        }
+        let (column, row) = self.cursor;
+        self.cells[row * self.width + column] = Cell {
+            character,
+            style: self.style,
+        };
+        self.cursor.0 += 1;
+    }
+
+    fn line_feed(&mut self) {
+        if self.cursor.1 + 1 < self.height {
+            self.cursor.1 += 1;
+        } else {
+            self.cells.drain(..self.width);
+            self.cells
+                .extend(core::iter::repeat_n(Cell::default(), self.width));
+        }
+    }
+
+    /// a CSI sequence, `parameters` missing or 0 mean the default
+    fn csi(&mut self, command: u8, parameters: &[u16], private: bool) {
+        let parameter = |index: usize, default: u16| match parameters.get(index) {
+            Some(0) | None => default as usize,
+            Some(value) => *value as usize,
+        };
+        let (column, row) = self.cursor();
+
+        match (command, private) {
+            (b'h' | b'l', true) if parameters.contains(&25) => {
+                self.cursor_visible = command == b'h';
+            }
+            (b'A', false) => self.cursor = (column, row.saturating_sub(parameter(0, 1))),
+            (b'B', false) => self.cursor = (column, (row + parameter(0, 1)).min(self.height - 1)),
+            (b'C', false) => self.cursor = ((column + parameter(0, 1)).min(self.width - 1), row),
+            (b'D', false) => self.cursor = (column.saturating_sub(parameter(0, 1)), row),
+            (b'H' | b'f', false) => {
+                self.cursor = (
+                    (parameter(1, 1) - 1).min(self.width - 1),
+                    (parameter(0, 1) - 1).min(self.height - 1),
+                )
+            }
+            (b'J', false) => {
+                let cursor = row * self.width + column;
+                let range = match parameters.first().copied().unwrap_or(0) {
+                    0 => cursor..self.cells.len(),
+                    1 => 0..cursor + 1,
+                    _ => 0..self.cells.len(),
+                };
+                self.erase(range);
+            }
+            (b'K', false) => {
+                let line = row * self.width;
+                let range = match parameters.first().copied().unwrap_or(0) {
+                    0 => line + column..line + self.width,
+                    1 => line..line + column + 1,
+                    _ => line..line + self.width,
+                };
+                self.erase(range);
+            }
+            (b'm', false) => self.select_graphic_rendition(parameters),
+            (b's', false) => self.saved_cursor = self.cursor,
+            (b'u', false) => self.cursor = self.saved_cursor,
+            _ => {}
+        }
+    }
+
+    /// erased cells keep the background color, like a real terminal
+    fn erase(&mut self, range: core::ops::Range<usize>) {
+        let blank = Cell {
+            character: ' ',
+            style: Style {
+                background: self.style.background,
+                ..Style::default()
+            },
+        };
+        self.cells[range].fill(blank);
+    }
+
+    fn select_graphic_rendition(&mut self, parameters: &[u16]) {
+        if parameters.is_empty() {
+            self.style = Style::default();
+        }
+        for parameter in parameters {
+            match parameter {
+                0 => self.style = Style::default(),
+                1 => self.style.bold = true,
+                7 => self.style.inverse = true,
+                22 => self.style.bold = false,
+                27 => self.style.inverse = false,
+                30..=37 => self.style.foreground = Color::Indexed((parameter - 30) as u8),
+                39 => self.style.foreground = Color::Default,
+                40..=47 => self.style.background = Color::Indexed((parameter - 40) as u8),
+                49 => self.style.background = Color::Default,
+                90..=97 => self.style.foreground = Color::Indexed((parameter - 90 + 8) as u8),
+                100..=107 => self.style.background = Color::Indexed((parameter - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Color, Terminal};
+
+    #[test]
+    fn should_wrap_and_scroll() {
+        let mut terminal = Terminal::new(4, 2);
+        terminal.feed(b"abcdef\r\nxy\xc3\xa9");
+
+        assert_eq!(terminal.text(), "ef\nxy\u{e9}\n");
+        assert_eq!(terminal.cursor(), (3, 1));
+    }
+
+    #[test]
+    fn should_handle_cursor_movement_erasing_and_colors() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.feed(b"hello\r\nworld");
+        // clear the screen, draw a red bold title at row 2, column 3
+        terminal.feed(b"\x1b[2J\x1b[2;3H\x1b[1;31mmenu\x1b[0m!");
+        terminal.feed(b"\x1b[3;1Hitem\x1b[2D\x1b[K\x1b[?25l");
+
+        assert_eq!(terminal.text(), "\n  menu!\nit\n");
+        assert_eq!(terminal.cell(2, 1).style.foreground, Color::Indexed(1));
+        assert!(terminal.cell(2, 1).style.bold);
+        assert_eq!(terminal.cell(6, 1).style.foreground, Color::Default);
+        assert_eq!(terminal.cursor(), (2, 2));
+        assert!(!terminal.cursor_visible);
+    }
+}
@@ -0,0 +1,250 @@
+use core::fmt;
+
+use super::emulator::Vm;
+use super::gas::InstructionClass;
+use super::prelude::*;
+
+/// the counter CSRs `rdcycle`, `rdtime` and `rdinstret` read, the upper
+/// halves are at `+ 0x80`
+pub const CSR_CYCLE: u16 = 0xc00;
+pub const CSR_TIME: u16 = 0xc01;
+pub const CSR_INSTRET: u16 = 0xc02;
+pub(super) const CSR_HIGH_HALF: u16 = 0x80;
+
+/// A named address range with how many cycles one access to it costs, e.g.
+/// fast SRAM at 1 cycle, flash at 4 cycles and MMIO with wait states.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRegionLatency {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub latency: u64,
+}
+
+impl MemoryRegionLatency {
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+}
+
+/// how often a region was accessed and how many cycles that cost
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RegionStats {
+    pub accesses: u64,
+    pub cycles: u64,
+}
+
+/// The cycle-approximate timing model. It is optional, a `Vm` without one
+/// just counts instructions.
+///
+/// Every instruction is charged the latency of its class on top of its
+/// memory accesses, so a load can be made slower than an add. Classes
+/// without a latency cost nothing extra. Every memory access (instruction
+/// fetches included) is charged the latency of the region the address falls
+/// in. Regions are checked in the order they
+/// were added, so a smaller region added first can carve out a part of a
+/// bigger one. Addresses outside of all regions cost `default_memory_latency`.
+#[derive(Debug, Clone)]
+pub struct TimingModel {
+    pub default_memory_latency: u64,
+    instruction_latencies: HashMap<InstructionClass, u64>,
+    regions: Vec<MemoryRegionLatency>,
+    /// one entry per region, in the same order
+    region_stats: Vec<RegionStats>,
+    /// accesses that did not fall into any region
+    default_region_stats: RegionStats,
+    /// cycles elapsed so far
+    pub cycles: u64,
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimingModel {
+    /// every access costs 1 cycle until regions are added, instructions cost
+    /// nothing on top until latencies are set
+    pub fn new() -> Self {
+        Self {
+            default_memory_latency: 1,
+            instruction_latencies: HashMap::new(),
+            regions: Vec::new(),
+            region_stats: Vec::new(),
+            default_region_stats: RegionStats::default(),
+            cycles: 0,
+        }
+    }
+
+    /// adds a region where every access costs `latency` cycles
+    pub fn with_region(mut self, name: &str, start: u32, size: u32, latency: u64) -> Self {
+        self.regions.push(MemoryRegionLatency {
+            name: name.to_string(),
+            start,
+            size,
+            latency,
+        });
+        self.region_stats.push(RegionStats::default());
+        self
+    }
+
+    /// every instruction of `class` costs `latency` cycles on top of its
+    /// memory accesses
+    pub fn with_instruction_latency(mut self, class: InstructionClass, latency: u64) -> Self {
+        self.instruction_latencies.insert(class, latency);
+        self
+    }
+
+    pub fn instruction_latency(&self, class: InstructionClass) -> u64 {
+        self.instruction_latencies.get(&class).copied().unwrap_or(0)
+    }
+
+    /// charges an instruction of `class` and returns how many cycles it
+    /// took, not counting its memory accesses
+    pub fn instruction(&mut self, class: InstructionClass) -> u64 {
+        let latency = self.instruction_latency(class);
+        self.cycles += latency;
+        latency
+    }
+
+    pub fn regions(&self) -> &[MemoryRegionLatency] {
+        &self.regions
+    }
+
+    /// charges one access to `address` and returns how many cycles it took
+    pub fn memory_access(&mut self, address: u32) -> u64 {
+        let (latency, stats) = match self
+            .regions
+            .iter()
+            .position(|region| region.contains(address))
+        {
+            Some(index) => (self.regions[index].latency, &mut self.region_stats[index]),
+            None => (self.default_memory_latency, &mut self.default_region_stats),
+        };
+
+        stats.accesses += 1;
+        stats.cycles += latency;
+        self.cycles += latency;
+
+        latency
+    }
+
+    /// the accesses and cycles per region, the accesses outside of every
+    /// region are the last entry (named "other")
+    pub fn region_breakdown(&self) -> Vec<(&str, RegionStats)> {
+        self.regions
+            .iter()
+            .zip(&self.region_stats)
+            .map(|(region, stats)| (region.name.as_str(), *stats))
+            .chain(core::iter::once(("other", self.default_region_stats)))
+            .collect()
+    }
+}
+
+impl fmt::Display for TimingModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cycles:               {}", self.cycles)?;
+        writeln!(f, "memory timing:")?;
+
+        for (name, stats) in self.region_breakdown() {
+            if stats.accesses == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "  {name:<24} {} accesses, {} cycles",
+                stats.accesses, stats.cycles
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// the cycle counter: the cycles of the timing model, or the retired
+    /// instructions when timing is off (one cycle per instruction)
+    pub fn cycles(&self) -> u64 {
+        self.timing
+            .as_ref()
+            .map_or(self.stats.instructions_retired, |timing| timing.cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimingModel;
+    use crate::gas::InstructionClass;
+    use crate::{Register, Vm};
+
+    #[test]
+    fn should_charge_the_latency_of_the_matching_region() {
+        let mut timing = TimingModel::new()
+            .with_region("sram", 0x2000_0000, 0x1000, 1)
+            .with_region("flash", 0x0800_0000, 0x10000, 4)
+            .with_region("uart", 0x1000_0000, 0x100, 10);
+
+        assert_eq!(timing.memory_access(0x0800_0000), 4);
+        assert_eq!(timing.memory_access(0x0800_fffc), 4);
+        assert_eq!(timing.memory_access(0x2000_0010), 1);
+        assert_eq!(timing.memory_access(0x1000_0000), 10);
+        // not in any region
+        assert_eq!(timing.memory_access(0x0001_0000), 1);
+
+        assert_eq!(timing.cycles, 20);
+        let breakdown: Vec<(&str, u64, u64)> = timing
+            .region_breakdown()
+            .into_iter()
+            .map(|(name, stats)| (name, stats.accesses, stats.cycles))
+            .collect();
+        assert_eq!(
+            breakdown,
+            vec![
+                ("sram", 1, 1),
+                ("flash", 2, 8),
+                ("uart", 1, 10),
+                ("other", 1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_prefer_the_region_added_first() {
+        let mut timing = TimingModel::new()
+            .with_region("tcm", 0x8000_0000, 0x100, 1)
+            .with_region("dram", 0x8000_0000, 0x0100_0000, 20);
+
+        assert_eq!(timing.memory_access(0x8000_0000), 1);
+        assert_eq!(timing.memory_access(0x8000_0100), 20);
+    }
+
+    #[test]
+    fn rdcycle_should_see_the_instruction_latencies() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 lw a0, 0x80(t0)
+        // 0x1008 rdcycle a1
+        // 0x100c rdinstret a2
+        // 0x1010 ebreak
+        let program: Vec<u8> = [
+            0x0000_12b7u32,
+            0x0802_a503,
+            0xc000_25f3,
+            0xc020_2673,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+
+        let mut vm = Vm::new(0x1000, 0x100)
+            .with_timing(TimingModel::new().with_instruction_latency(InstructionClass::Load, 2));
+        vm.load_program(0x1000, &program).unwrap();
+        vm.run().unwrap();
+
+        // lui is one fetch, lw a fetch, a read and 2 cycles of latency
+        assert_eq!(vm.vm_state.registers[Register::A1], 5);
+        assert_eq!(vm.vm_state.registers[Register::A2], 3);
+        assert_eq!(vm.cycles(), 7);
+    }
+}
@@ -0,0 +1,334 @@
+//! The TLB in front of the page table walks of `mmu.rs`. It is direct
+//! mapped: a virtual page has one slot, a walk for another page that
+//! shares it pushes the old translation out. A guest that jumps between
+//! many pages wants more entries, `Vm::with_tlb` sets how many, 0 walks on
+//! every access.
+//!
+//! Every translation is for a 4 KiB page, also where the leaf maps a
+//! megapage, and belongs to the ASID in `satp` at the time of its walk
+//! unless a `G` bit on the way made it global. `invalidate` drops the ones
+//! of an address, an ASID or both, like `sfence.vma` does:
+//!
+//! ```text
+//! tlb.invalidate(Some(0x4000_1000), None);    // one page, every ASID
+//! tlb.invalidate(None, Some(3));              // ASID 3, not the global ones
+//! tlb.invalidate(None, None);                 // everything
+//! ```
+//!
+//! The hits, misses and invalidated entries are in `stats`, and in
+//! `vm.summary()` once the guest turned paging on.
+
+use core::fmt;
+
+use super::emulator::Vm;
+use super::mmu::PAGE_SIZE;
+use super::prelude::*;
+
+/// the size of the TLB of a new vm
+pub const DEFAULT_TLB_ENTRIES: usize = 64;
+
+/// one walk, the virtual page `page` is the physical `frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct TlbEntry {
+    pub(super) page: u32,
+    pub(super) frame: u32,
+    pub(super) pte: u32,
+    /// where `pte` is, for the `A` and `D` updates
+    pub(super) pte_address: u32,
+    pub(super) asid: u16,
+    /// in every ASID
+    pub(super) global: bool,
+    /// the leaf maps the whole 4 MiB around `page`
+    pub(super) megapage: bool,
+}
+
+impl TlbEntry {
+    /// whether the translation is one of `address`
+    fn maps(&self, address: u32) -> bool {
+        let page = address / PAGE_SIZE;
+        if self.megapage {
+            self.page >> 10 == page >> 10
+        } else {
+            self.page == page
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// the entries `invalidate` dropped
+    pub invalidated: u64,
+}
+
+impl TlbStats {
+    /// misses per lookup, 0 without lookups
+    pub fn miss_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.misses as f64 / lookups as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    pub stats: TlbStats,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new(DEFAULT_TLB_ENTRIES)
+    }
+}
+
+impl Tlb {
+    /// an empty TLB of `entries` translations
+    pub fn new(entries: usize) -> Self {
+        Self {
+            entries: vec![None; entries],
+            stats: TlbStats::default(),
+        }
+    }
+
+    /// how many translations it keeps
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// how many translations it has now
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, page: u32) -> Option<usize> {
+        match self.entries.len() {
+            0 => None,
+            entries => Some(page as usize % entries),
+        }
+    }
+
+    /// the translation of the virtual `page` in `asid`, counted as a hit
+    /// or a miss
+    pub(super) fn lookup(&mut self, page: u32, asid: u16) -> Option<TlbEntry> {
+        let entry = self
+            .slot(page)
+            .and_then(|slot| self.entries[slot])
+            .filter(|entry| entry.page == page && (entry.global || entry.asid == asid));
+        match entry {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        entry
+    }
+
+    /// keeps `entry`, in place of the one in its slot
+    pub(super) fn insert(&mut self, entry: TlbEntry) {
+        if let Some(slot) = self.slot(entry.page) {
+            self.entries[slot] = Some(entry);
+        }
+    }
+
+    /// drops the translations of `address` and of `asid`, either `None`
+    /// is all of them. Global translations are in every ASID, so they only
+    /// go without an ASID
+    pub fn invalidate(&mut self, address: Option<u32>, asid: Option<u16>) {
+        for slot in &mut self.entries {
+            let Some(entry) = slot else {
+                continue;
+            };
+            let address_matches = address.is_none_or(|address| entry.maps(address));
+            let asid_matches = asid.is_none_or(|asid| !entry.global && entry.asid == asid);
+            if address_matches && asid_matches {
+                *slot = None;
+                self.stats.invalidated += 1;
+            }
+        }
+    }
+
+    /// drops every translation
+    pub fn flush(&mut self) {
+        self.invalidate(None, None);
+    }
+}
+
+impl fmt::Display for Tlb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "tlb: {} entries: {} hits, {} misses ({:.1}%), {} invalidated",
+            self.capacity(),
+            self.stats.hits,
+            self.stats.misses,
+            self.stats.miss_rate() * 100.0,
+            self.stats.invalidated
+        )
+    }
+}
+
+impl Vm {
+    /// translates through `tlb` from now on, see `Tlb::new` for its size
+    pub fn with_tlb(mut self, tlb: Tlb) -> Self {
+        self.mmu.tlb = tlb;
+        self
+    }
+
+    pub fn tlb(&self) -> &Tlb {
+        &self.mmu.tlb
+    }
+
+    pub fn tlb_mut(&mut self) -> &mut Tlb {
+        &mut self.mmu.tlb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tlb, TlbEntry, DEFAULT_TLB_ENTRIES};
+    use crate::csr::{Privilege, CSR_SATP};
+    use crate::mmu::{PTE_A, PTE_D, PTE_R, PTE_V, PTE_W, SATP_MODE_SV32};
+    use crate::{AccessKind, Vm};
+
+    fn entry(page: u32, asid: u16, global: bool, megapage: bool) -> TlbEntry {
+        TlbEntry {
+            page,
+            frame: page,
+            pte: 0,
+            pte_address: 0,
+            asid,
+            global,
+            megapage,
+        }
+    }
+
+    /// a supervisor-mode vm with paging on, 0x4000_0000 maps to 0x2000
+    fn paged_vm(tlb: Tlb) -> Vm {
+        let mut vm = Vm::new(0, 0x20_0000).with_tlb(tlb);
+        let data = PTE_R | PTE_W | PTE_V | PTE_A | PTE_D;
+        vm.memory.write_u32(0x10_0400, 0x101 << 10 | PTE_V).unwrap();
+        vm.memory.write_u32(0x10_1000, 0x2 << 10 | data).unwrap();
+        vm.csrs.privilege = Privilege::Supervisor;
+        vm.write_csr(CSR_SATP, SATP_MODE_SV32 | 0x100);
+        vm
+    }
+
+    #[test]
+    fn a_walk_should_push_out_the_translation_in_its_slot() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(entry(0x40_000, 1, false, false));
+        tlb.insert(entry(0x40_001, 1, false, false));
+        // the slot of 0x40_000 again
+        tlb.insert(entry(0x40_008, 1, false, false));
+
+        assert_eq!(tlb.len(), 2);
+        assert!(tlb.lookup(0x40_000, 1).is_none());
+        assert!(tlb.lookup(0x40_001, 1).is_some());
+        assert!(tlb.lookup(0x40_008, 1).is_some());
+    }
+
+    #[test]
+    fn should_count_hits_and_misses() {
+        let mut tlb = Tlb::new(8);
+        assert_eq!(tlb.stats.miss_rate(), 0.0);
+        tlb.insert(entry(0x40_001, 2, false, false));
+        tlb.insert(entry(0x80_002, 1, true, false));
+
+        // another ASID, another page
+        assert!(tlb.lookup(0x40_001, 1).is_none());
+        assert!(tlb.lookup(0x40_003, 2).is_none());
+        assert!(tlb.lookup(0x40_001, 2).is_some());
+        // a global translation is in every ASID
+        assert!(tlb.lookup(0x80_002, 7).is_some());
+
+        assert_eq!((tlb.stats.hits, tlb.stats.misses), (2, 2));
+        assert_eq!(tlb.stats.miss_rate(), 0.5);
+    }
+
+    #[test]
+    fn invalidating_an_asid_should_keep_the_global_translations() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(entry(0x40_000, 1, false, false));
+        tlb.insert(entry(0x40_001, 1, true, false));
+        tlb.insert(entry(0x40_002, 2, false, false));
+
+        tlb.invalidate(None, Some(1));
+        assert_eq!(tlb.len(), 2);
+        assert!(tlb.lookup(0x40_000, 1).is_none());
+        assert!(tlb.lookup(0x40_001, 1).is_some());
+        assert!(tlb.lookup(0x40_002, 2).is_some());
+        assert_eq!(tlb.stats.invalidated, 1);
+
+        // without an ASID the global one goes too
+        tlb.invalidate(None, None);
+        assert!(tlb.is_empty());
+        assert_eq!(tlb.stats.invalidated, 3);
+    }
+
+    #[test]
+    fn invalidating_an_address_should_drop_only_its_page_or_megapage() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(entry(0x80_002, 1, false, true));
+        tlb.insert(entry(0x40_001, 1, false, false));
+        tlb.insert(entry(0x40_003, 2, false, false));
+
+        // an address in the megapage, not the page the walk was for
+        tlb.invalidate(Some(0x8000_3000), None);
+        assert!(tlb.lookup(0x80_002, 1).is_none());
+        // the page in another ASID stays
+        tlb.invalidate(Some(0x4000_3000), Some(1));
+        assert!(tlb.lookup(0x40_003, 2).is_some());
+        tlb.invalidate(Some(0x4000_1000), Some(1));
+        assert!(tlb.lookup(0x40_001, 1).is_none());
+
+        assert_eq!(tlb.len(), 1);
+        assert_eq!(tlb.stats.invalidated, 2);
+    }
+
+    #[test]
+    fn a_tlb_without_entries_should_miss_every_lookup() {
+        let mut tlb = Tlb::new(0);
+        tlb.insert(entry(1, 0, false, false));
+        assert!(tlb.is_empty());
+        assert!(tlb.lookup(1, 0).is_none());
+        tlb.flush();
+        assert_eq!(tlb.stats.misses, 1);
+    }
+
+    #[test]
+    fn with_tlb_should_set_the_tlb_the_walks_go_through() {
+        let mut vm = paged_vm(Tlb::default());
+        assert_eq!(vm.tlb().capacity(), DEFAULT_TLB_ENTRIES);
+        for _ in 0..2 {
+            assert_eq!(vm.translate(0x4000_0000, AccessKind::Load), Ok(0x2000));
+        }
+        assert_eq!((vm.tlb().stats.hits, vm.tlb().stats.misses), (1, 1));
+
+        // without entries every access walks
+        let mut vm = paged_vm(Tlb::new(0));
+        assert_eq!(vm.tlb().capacity(), 0);
+        for _ in 0..2 {
+            assert_eq!(vm.translate(0x4000_0000, AccessKind::Load), Ok(0x2000));
+        }
+        assert_eq!((vm.tlb().stats.hits, vm.tlb().stats.misses), (0, 2));
+        assert!(vm.tlb().is_empty());
+    }
+
+    #[test]
+    fn summary_should_show_the_tlb_once_paging_is_on() {
+        let mut vm = paged_vm(Tlb::new(16));
+        assert!(!vm.summary().contains("tlb:"));
+
+        vm.translate(0x4000_0000, AccessKind::Load).unwrap();
+        vm.translate(0x4000_0004, AccessKind::Load).unwrap();
+        vm.tlb_mut().flush();
+        assert!(vm
+            .summary()
+            .contains("tlb: 16 entries: 1 hits, 1 misses (50.0%), 1 invalidated\n"));
+    }
+}
@@ -0,0 +1,629 @@
+//! A compact binary log of everything a run did, for analysis tools that
+//! work offline on big executions: a JSON event per instruction (see
+//! `events.rs`) is too slow and too big for that. `riscv-vm trace` writes
+//! one, `riscv-vm trace-dump` prints it.
+//!
+//! The file starts with `TRACE_MAGIC` and the format version as a little
+//! endian u32, then come the records, each a tag byte and its fields, all
+//! little endian:
+//!
+//! ```text
+//! 1 instruction    pc: u32, word: u32, rs1: u32, rs2: u32
+//! 2 register write register: u8, value: u32
+//! 3 memory read    address: u32, size: u8, value: u32
+//! 4 memory write   address: u32, size: u8, value: u32
+//! 5 trap           cause: u32, value: u32
+//! ```
+//!
+//! `rs1` and `rs2` are the values of the registers in those fields of the
+//! word before it ran, whatever its format is. The effects follow the
+//! instruction that caused them and belong to its pc.
+//!
+//! `TraceFormat::Qemu` writes the text of `qemu-riscv32 -one-insn-per-tb
+//! -d in_asm,cpu` instead, so the scripts that diff QEMU logs work on ours:
+//!
+//! ```text
+//! ----------------
+//! IN:
+//! 0x00001000:  00150513          addi                    a0,a0,1
+//!
+//!  pc       00001000
+//!  x0/zero  00000000 x1/ra    00000000 x2/sp    00000000 x3/gp    00000000
+//!  ...
+//! ```
+
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt;
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use super::emulator::{Instruction, StopReason, Vm, VmState};
+use super::error::VmError;
+use super::hooks::VmHooks;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+pub const TRACE_MAGIC: [u8; 8] = *b"RVTRACE\0";
+pub const TRACE_VERSION: u32 = 1;
+
+const INSTRUCTION: u8 = 1;
+const REGISTER_WRITE: u8 = 2;
+const MEMORY_READ: u8 = 3;
+const MEMORY_WRITE: u8 = 4;
+const TRAP: u8 = 5;
+
+/// what `Vm::run_traced` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// the records above
+    Binary,
+    /// QEMU's log: the disassembly of an instruction the first time it
+    /// runs (`in_asm`, QEMU translates it then) and the pc and the
+    /// registers before every instruction (`cpu`, without the CSRs)
+    Qemu { in_asm: bool, cpu: bool },
+}
+
+impl TraceFormat {
+    /// `binary`, or QEMU's `-d` items `in_asm` and `cpu`, both with a comma
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "binary" {
+            return Some(Self::Binary);
+        }
+        let (mut in_asm, mut cpu) = (false, false);
+        for item in name.split(',') {
+            match item {
+                "in_asm" => in_asm = true,
+                "cpu" => cpu = true,
+                _ => return None,
+            }
+        }
+        Some(Self::Qemu { in_asm, cpu })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+    #[error("not a trace file")]
+    NotATrace,
+
+    #[error("trace format version {version} is not supported, only {TRACE_VERSION}")]
+    UnsupportedVersion { version: u32 },
+
+    #[error("unknown record tag {tag}")]
+    UnknownRecord { tag: u8 },
+
+    #[error("register x{index} does not exist")]
+    BadRegister { index: u8 },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceRecord {
+    /// the instruction `word` at `pc` runs with `operands` in rs1 and rs2
+    Instruction {
+        pc: u32,
+        word: u32,
+        operands: [u32; 2],
+    },
+    RegisterWrite {
+        register: Register,
+        value: u32,
+    },
+    /// a load, `value` is not sign extended
+    MemoryRead {
+        address: u32,
+        size: u8,
+        value: u32,
+    },
+    MemoryWrite {
+        address: u32,
+        size: u8,
+        value: u32,
+    },
+    /// the instruction trapped into the guest's handler
+    Trap {
+        cause: u32,
+        value: u32,
+    },
+}
+
+impl fmt::Display for TraceRecord {
+    /// one line like `objdump`'s for instructions, the effects indented
+    /// under them
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Instruction { pc, word, operands } => {
+                let text = Rv32iInstruction::from_core_instruction_format(word.to_le_bytes())
+                    .map(|instruction| {
+                        Instruction::Rv32iInstruction(pc as i32, instruction).to_string()
+                    })
+                    .unwrap_or_else(|_| "unknown".to_string());
+                write!(
+                    f,
+                    "{pc:08x}: {word:08x}  {text:<32} rs1={:#x} rs2={:#x}",
+                    operands[0], operands[1]
+                )
+            }
+            Self::RegisterWrite { register, value } => {
+                write!(f, "          {} <- {value:#x}", register.abi_name())
+            }
+            Self::MemoryRead {
+                address,
+                size,
+                value,
+            } => write!(f, "          {value:#x} <- [{address:#x}; {size}]"),
+            Self::MemoryWrite {
+                address,
+                size,
+                value,
+            } => write!(f, "          [{address:#x}; {size}] <- {value:#x}"),
+            Self::Trap { cause, value } => {
+                write!(f, "          trap cause {cause} value {value:#x}")
+            }
+        }
+    }
+}
+
+/// writes records after the header
+pub struct TraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// writes the header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&TRACE_MAGIC)?;
+        writer.write_all(&TRACE_VERSION.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(17);
+        let word = |bytes: &mut Vec<u8>, value: u32| bytes.extend(value.to_le_bytes());
+        match *record {
+            TraceRecord::Instruction {
+                pc,
+                word: instruction,
+                operands,
+            } => {
+                bytes.push(INSTRUCTION);
+                for value in [pc, instruction, operands[0], operands[1]] {
+                    word(&mut bytes, value);
+                }
+            }
+            TraceRecord::RegisterWrite { register, value } => {
+                bytes.extend([REGISTER_WRITE, register.index() as u8]);
+                word(&mut bytes, value);
+            }
+            TraceRecord::MemoryRead {
+                address,
+                size,
+                value,
+            }
+            | TraceRecord::MemoryWrite {
+                address,
+                size,
+                value,
+            } => {
+                let tag = match record {
+                    TraceRecord::MemoryRead { .. } => MEMORY_READ,
+                    _ => MEMORY_WRITE,
+                };
+                bytes.push(tag);
+                word(&mut bytes, address);
+                bytes.push(size);
+                word(&mut bytes, value);
+            }
+            TraceRecord::Trap { cause, value } => {
+                bytes.push(TRAP);
+                word(&mut bytes, cause);
+                word(&mut bytes, value);
+            }
+        }
+        self.writer.write_all(&bytes)
+    }
+
+    /// flushes and hands the writer back
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// the records of a trace, in order
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// reads and checks the header
+    pub fn new(mut reader: R) -> Result<Self, TraceError> {
+        let mut header = [0; 12];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| TraceError::NotATrace)?;
+        if header[..8] != TRACE_MAGIC {
+            return Err(TraceError::NotATrace);
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != TRACE_VERSION {
+            return Err(TraceError::UnsupportedVersion { version });
+        }
+        Ok(Self { reader })
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// the next record, `None` at the end of the file
+    pub fn read(&mut self) -> Result<Option<TraceRecord>, TraceError> {
+        let mut tag = [0];
+        if self.reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let record = match tag[0] {
+            INSTRUCTION => TraceRecord::Instruction {
+                pc: self.u32()?,
+                word: self.u32()?,
+                operands: [self.u32()?, self.u32()?],
+            },
+            REGISTER_WRITE => {
+                let index = self.u8()?;
+                let register = Register::new(index).ok_or(TraceError::BadRegister { index })?;
+                TraceRecord::RegisterWrite {
+                    register,
+                    value: self.u32()?,
+                }
+            }
+            tag @ (MEMORY_READ | MEMORY_WRITE) => {
+                let (address, size, value) = (self.u32()?, self.u8()?, self.u32()?);
+                if tag == MEMORY_READ {
+                    TraceRecord::MemoryRead {
+                        address,
+                        size,
+                        value,
+                    }
+                } else {
+                    TraceRecord::MemoryWrite {
+                        address,
+                        size,
+                        value,
+                    }
+                }
+            }
+            TRAP => TraceRecord::Trap {
+                cause: self.u32()?,
+                value: self.u32()?,
+            },
+            tag => return Err(TraceError::UnknownRecord { tag }),
+        };
+        Ok(Some(record))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+/// where the trace goes
+enum Sink<W: Write> {
+    Binary(TraceWriter<W>),
+    Qemu {
+        writer: W,
+        in_asm: bool,
+        cpu: bool,
+        /// the pcs `in_asm` already printed
+        translated: BTreeSet<u32>,
+    },
+}
+
+impl<W: Write> Sink<W> {
+    fn new(writer: W, format: TraceFormat) -> io::Result<Self> {
+        Ok(match format {
+            TraceFormat::Binary => Self::Binary(TraceWriter::new(writer)?),
+            TraceFormat::Qemu { in_asm, cpu } => Self::Qemu {
+                writer,
+                in_asm,
+                cpu,
+                translated: BTreeSet::new(),
+            },
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Binary(writer) => writer.finish(),
+            Self::Qemu { mut writer, .. } => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+}
+
+/// QEMU's lines for the instruction `word` at `pc`, before it runs
+fn write_qemu(
+    writer: &mut impl Write,
+    vm_state: &VmState,
+    instruction: &Instruction,
+    word: u32,
+    in_asm: bool,
+    cpu: bool,
+) -> io::Result<()> {
+    let pc = vm_state.pc as u32;
+    if in_asm {
+        // QEMU's disassembler pads the mnemonic and has no spaces after
+        // the commas
+        let text = instruction.to_string();
+        let (mnemonic, operands) = text.split_once(' ').unwrap_or((&text, ""));
+        writeln!(writer, "----------------")?;
+        writeln!(writer, "IN: ")?;
+        writeln!(
+            writer,
+            "0x{pc:08x}:  {word:08x}          {mnemonic:<24}{}",
+            operands.replace(", ", ",")
+        )?;
+        writeln!(writer)?;
+    }
+    if cpu {
+        writeln!(writer, " {:<8} {pc:08x}", "pc")?;
+        for register in Register::all() {
+            let name = format!("x{}/{}", register.index(), register.abi_name());
+            let value = vm_state.registers[register] as u32;
+            write!(writer, " {name:<8} {value:08x}")?;
+            if register.index() % 4 == 3 {
+                writeln!(writer)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the hook that writes the trace, it keeps the first error
+struct Tracer<W: Write> {
+    sink: Rc<RefCell<Result<Sink<W>, io::Error>>>,
+}
+
+impl<W: Write> Tracer<W> {
+    /// the binary format's record, the text formats have no use for it
+    fn write(&mut self, record: TraceRecord) {
+        let mut sink = self.sink.borrow_mut();
+        if let Ok(Sink::Binary(trace)) = &mut *sink {
+            if let Err(error) = trace.write(&record) {
+                *sink = Err(error);
+            }
+        }
+    }
+}
+
+impl<W: Write> VmHooks for Tracer<W> {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        let word = match instruction {
+            Instruction::Rv32iInstruction(_, instruction) => instruction.encode(),
+            Instruction::PseudoInstruction(..) => 0,
+        };
+        let pc = vm_state.pc as u32;
+        let mut sink = self.sink.borrow_mut();
+        if let Ok(Sink::Qemu {
+            writer,
+            in_asm,
+            cpu,
+            translated,
+        }) = &mut *sink
+        {
+            let in_asm = *in_asm && translated.insert(pc);
+            if let Err(error) = write_qemu(writer, vm_state, instruction, word, in_asm, *cpu) {
+                *sink = Err(error);
+            }
+            return;
+        }
+        drop(sink);
+
+        let register =
+            |shift: u32| vm_state.registers[Register::from_bits((word >> shift) as u8)] as u32;
+        self.write(TraceRecord::Instruction {
+            pc,
+            word,
+            operands: [register(15), register(20)],
+        });
+    }
+
+    fn on_memory_read(&mut self, _pc: u32, address: u32, size: u32, value: u32) {
+        self.write(TraceRecord::MemoryRead {
+            address,
+            size: size as u8,
+            value,
+        });
+    }
+
+    fn on_memory_write(&mut self, _pc: u32, address: u32, size: u32, value: u32) {
+        self.write(TraceRecord::MemoryWrite {
+            address,
+            size: size as u8,
+            value,
+        });
+    }
+
+    fn on_register_write(&mut self, _pc: u32, register: Register, value: i32) {
+        self.write(TraceRecord::RegisterWrite {
+            register,
+            value: value as u32,
+        });
+    }
+
+    fn on_trap(&mut self, _pc: u32, cause: u32, value: u32) {
+        self.write(TraceRecord::Trap { cause, value });
+    }
+}
+
+impl Vm {
+    /// like `run()`, and writes the trace of the run in `format` to
+    /// `writer`, which it hands back. The run's own result is inside, the
+    /// trace is complete unless writing failed
+    pub fn run_traced<W: Write + 'static>(
+        &mut self,
+        writer: W,
+        format: TraceFormat,
+    ) -> io::Result<(Result<StopReason, VmError>, W)> {
+        let sink = Rc::new(RefCell::new(Sink::new(writer, format)));
+        self.add_hooks(Box::new(Tracer { sink: sink.clone() }));
+        let result = self.run();
+        self.hooks.pop();
+        let sink = Rc::try_unwrap(sink)
+            .ok()
+            .expect("the tracer is gone")
+            .into_inner()?;
+        Ok((result, sink.finish()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TraceError, TraceFormat, TraceReader, TraceRecord};
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_read_back_the_trace_of_a_run() {
+        // 0x1000 addi t0, zero, 5
+        // 0x1004 sw t0, 0x80(zero)
+        // 0x1008 lw t1, 0x80(zero)
+        // 0x100c ebreak
+        let program: Vec<u8> = [0x0050_0293u32, 0x0850_2023, 0x0800_2303, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0, 0x2000);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.pc = 0x1000;
+
+        let (result, bytes) = vm.run_traced(Vec::new(), TraceFormat::Binary).unwrap();
+        assert_eq!(result, Ok(StopReason::Ebreak));
+        // the ebreak that stops the run is not traced
+        assert_eq!(bytes.len(), 12 + 3 * 17 + 2 * 6 + 2 * 10);
+        let records: Vec<TraceRecord> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            [
+                TraceRecord::Instruction {
+                    pc: 0x1000,
+                    word: 0x0050_0293,
+                    operands: [0, 0],
+                },
+                TraceRecord::RegisterWrite {
+                    register: Register::T0,
+                    value: 5,
+                },
+                TraceRecord::Instruction {
+                    pc: 0x1004,
+                    word: 0x0850_2023,
+                    operands: [0, 5],
+                },
+                TraceRecord::MemoryWrite {
+                    address: 0x80,
+                    size: 4,
+                    value: 5,
+                },
+                TraceRecord::Instruction {
+                    pc: 0x1008,
+                    word: 0x0800_2303,
+                    operands: [0, 0],
+                },
+                TraceRecord::MemoryRead {
+                    address: 0x80,
+                    size: 4,
+                    value: 5,
+                },
+                TraceRecord::RegisterWrite {
+                    register: Register::T1,
+                    value: 5,
+                },
+            ]
+        );
+        assert_eq!(
+            records[2].to_string(),
+            "00001004: 08502023  sw t0, 128(zero)                 rs1=0x0 rs2=0x5"
+        );
+        assert!(matches!(
+            TraceReader::new(&b"RVTRACE\0\x02\0\0\0"[..]),
+            Err(TraceError::UnsupportedVersion { version: 2 })
+        ));
+    }
+
+    #[test]
+    fn should_log_like_qemu() {
+        // 0x1000 addi a0, a0, 1
+        // 0x1004 addi t0, zero, 2
+        // 0x1008 bne a0, t0, -8
+        // 0x100c ebreak
+        let program: Vec<u8> = [0x0015_0513u32, 0x0020_0293, 0xfe55_1ce3, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let run = |format| {
+            let mut vm = Vm::new(0x1000, 0x100);
+            vm.load_program(0x1000, &program).unwrap();
+            let (result, log) = vm.run_traced(Vec::new(), format).unwrap();
+            assert_eq!(result, Ok(StopReason::Ebreak));
+            String::from_utf8(log).unwrap()
+        };
+
+        // the loop runs twice, its instructions are translated once
+        let in_asm = TraceFormat::from_name("in_asm").unwrap();
+        assert_eq!(
+            run(in_asm),
+            "\
+----------------
+IN: 
+0x00001000:  00150513          addi                    a0,a0,1
+
+----------------
+IN: 
+0x00001004:  00200293          addi                    t0,zero,2
+
+----------------
+IN: 
+0x00001008:  fe551ce3          bne                     a0,t0,0x1000
+
+"
+        );
+
+        let cpu = run(TraceFormat::from_name("cpu").unwrap());
+        assert_eq!(
+            cpu.lines().filter(|line| line.starts_with(" pc")).count(),
+            6
+        );
+        let dump: Vec<&str> = cpu.lines().skip(9).take(2).collect();
+        assert_eq!(
+            dump,
+            [
+                " pc       00001004",
+                " x0/zero  00000000 x1/ra    00000000 x2/sp    00000000 x3/gp    00000000",
+            ]
+        );
+        assert_eq!(
+            cpu.lines().nth(12),
+            Some(" x8/s0    00000000 x9/s1    00000000 x10/a0   00000001 x11/a1   00000000")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! A terminal debugger on top of the monitor: the disassembly around the pc,
+//! the registers and the stack on screen, and a command bar that takes the
+//! commands of `monitor.rs` (`break`, `step`, `continue`, ...). F10 steps and
+//! F5 continues without typing, Ctrl-C or `quit` leaves.
+//!
+//! `riscv-vm debug program.elf` starts it, with the `tui` feature.
+
+use std::io;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use super::emulator::Vm;
+use super::monitor::Monitor;
+use super::register::Register;
+
+/// how many instructions before the pc the disassembly shows
+const INSTRUCTIONS_BEFORE: u32 = 4;
+/// how many lines of command output stay on screen
+const OUTPUT_LINES: usize = 6;
+
+pub struct Debugger {
+    pub vm: Vm,
+    monitor: Monitor,
+    /// what is typed into the command bar
+    command: String,
+    /// the replies to the last commands
+    output: Vec<String>,
+}
+
+impl Debugger {
+    pub fn new(vm: Vm) -> Self {
+        Self {
+            vm,
+            monitor: Monitor::new(),
+            command: String::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// draws until the user quits, then puts the terminal back
+    pub fn run(mut self) -> io::Result<Vm> {
+        let mut terminal = ratatui::init();
+        let result = self.event_loop(&mut terminal);
+        ratatui::restore();
+        result.map(|()| self.vm)
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.monitor.quit {
+            terminal.draw(|frame| self.render(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                KeyCode::Char(character) => self.command.push(character),
+                KeyCode::Backspace => {
+                    self.command.pop();
+                }
+                KeyCode::Enter => {
+                    let command = std::mem::take(&mut self.command);
+                    self.execute(&command);
+                }
+                KeyCode::F(10) => self.execute("step"),
+                KeyCode::F(5) => self.execute("continue"),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// runs a monitor command and keeps its reply for the output pane
+    pub fn execute(&mut self, command: &str) {
+        let reply = match self.monitor.execute(&mut self.vm, command) {
+            Ok(reply) => reply,
+            Err(error) => format!("error: {error}"),
+        };
+        self.output.extend(reply.lines().map(str::to_string));
+        let excess = self.output.len().saturating_sub(OUTPUT_LINES);
+        self.output.drain(..excess);
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let [main, output, command] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(OUTPUT_LINES as u16 + 2),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [code, side] =
+            Layout::horizontal([Constraint::Min(40), Constraint::Length(48)]).areas(main);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(19), Constraint::Min(3)]).areas(side);
+
+        self.render_code(frame, code);
+        frame.render_widget(
+            Paragraph::new(self.registers()).block(Block::bordered().title("registers")),
+            registers,
+        );
+        self.render_stack(frame, stack);
+        frame.render_widget(
+            Paragraph::new(self.output.join("\n")).block(Block::bordered().title("output")),
+            output,
+        );
+        frame.render_widget(
+            Paragraph::new(format!("> {}", self.command)).block(Block::bordered().title("command")),
+            command,
+        );
+    }
+
+    /// the instructions around the pc, the pc's line highlighted and the
+    /// breakpoints marked with `*`
+    fn render_code(&self, frame: &mut Frame, area: Rect) {
+        let pc = self.vm.vm_state.pc as u32;
+        let start = pc.wrapping_sub(INSTRUCTIONS_BEFORE * 4);
+        let lines: Vec<Line> = (0..area.height.saturating_sub(2) as u32)
+            .map(|index| {
+                let address = start.wrapping_add(index * 4);
+                let text = match self.vm.fetch_at(address) {
+                    Ok(instruction) => instruction.disassemble(&self.vm.symbols),
+                    Err(_) => "??".to_string(),
+                };
+                let marker = if self.vm.breakpoints.is_breakpoint(address) {
+                    '*'
+                } else {
+                    ' '
+                };
+                let line = Line::raw(format!("{marker} {address:#010x}: {text}"));
+                if address == pc {
+                    line.style(Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    line
+                }
+            })
+            .collect();
+        let title = match self.vm.symbol_at(pc) {
+            Some((symbol, offset)) => format!("<{}+{offset:#x}>", symbol.name),
+            None => "code".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    /// the pc and the registers, two to a line
+    fn registers(&self) -> Vec<Line<'static>> {
+        let registers = &self.vm.vm_state.registers;
+        let mut lines = vec![Line::raw(format!(
+            "pc   {:#010x}",
+            self.vm.vm_state.pc as u32
+        ))];
+        for index in (0..32).step_by(2) {
+            let (left, right) = (
+                Register::new(index).unwrap(),
+                Register::new(index + 1).unwrap(),
+            );
+            lines.push(Line::raw(format!(
+                "{:<4} {:#010x}    {:<4} {:#010x}",
+                left.abi_name(),
+                registers[left] as u32,
+                right.abi_name(),
+                registers[right] as u32
+            )));
+        }
+        lines
+    }
+
+    /// the words from the stack pointer up
+    fn render_stack(&self, frame: &mut Frame, area: Rect) {
+        let sp = self.vm.vm_state.sp() as u32;
+        let lines: Vec<Line> = (0..area.height.saturating_sub(2) as u32)
+            .map_while(|index| {
+                let address = sp.wrapping_add(index * 4);
+                let word = self.vm.memory.read_u32(address).ok()?;
+                Some(Line::raw(format!("{address:#010x}: {word:#010x}")))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("stack")),
+            area,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::Vm;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    /// the screen as text, one line per row
+    fn screen(debugger: &Debugger) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| debugger.render(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let width = buffer.area.width as usize;
+        let symbols: Vec<&str> = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        symbols
+            .chunks(width)
+            .map(|row| row.concat() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn should_show_the_code_registers_and_stack() {
+        // 0x1000 addi a0, zero, 5
+        // 0x1004 addi a0, a0, 1
+        // 0x1008 ebreak
+        let program: Vec<u8> = [0x0050_0513u32, 0x0015_0513, 0x0010_0073]
+            .iter()
+            .flat_map(|instruction| instruction.to_le_bytes())
+            .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.vm_state.set_sp(0x10f0);
+        let mut debugger = Debugger::new(vm);
+
+        debugger.execute("break 0x1008");
+        debugger.execute("step");
+        let screen = screen(&debugger);
+        assert!(screen.contains("  0x00001000: addi a0, zero, 5"));
+        assert!(screen.contains("* 0x00001008: ebreak"));
+        assert!(screen.contains("a0   0x00000005"));
+        assert!(screen.contains("0x000010f0: 0x00000000"));
+        assert!(screen.contains("breakpoint at 0x00001008"));
+    }
+}
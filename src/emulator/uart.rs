@@ -0,0 +1,282 @@
+//! A 16550 UART, the serial console of QEMU's `virt` machine that xv6 and
+//! Linux talk to. Transmitting is instant, so the transmitter is always
+//! empty; received bytes wait in `input` until the guest reads them. The
+//! baud rate, FIFO and modem settings are kept but do nothing.
+//!
+//! The interrupt is up while a received byte waits (with bit 0 of `IER`),
+//! or once the transmitter went empty (with bit 1) until the guest reads
+//! `IIR` or writes the next byte, which empties it again right away. Put it
+//! on the bus with `Vm::add_device_with_irq` and `UART_IRQ` to get it to the
+//! PLIC.
+
+use alloc::collections::VecDeque;
+
+use super::memory::Memory;
+use super::mmio::Device;
+use super::prelude::*;
+
+/// where QEMU's `virt` machine has its UART
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+/// the PLIC line of the UART on `virt`
+pub const UART_IRQ: u32 = 10;
+
+const RBR_THR: u32 = 0;
+const IER: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+/// the divisor latch replaces the first two registers while this LCR bit is set
+const LCR_DLAB: u8 = 1 << 7;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+/// the FIFOs are enabled
+const IIR_FIFOS: u8 = 0xc0;
+
+#[derive(Debug, Clone, Default)]
+pub struct Uart {
+    /// what the guest transmitted
+    pub output: Vec<u8>,
+    /// what the guest receives next
+    pub input: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    /// the transmitter went empty and the guest did not read `IIR` since
+    thr_empty_interrupt: bool,
+}
+
+impl Uart {
+    /// the interrupt `IIR` reports, the received byte first
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.input.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_interrupt {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn read(&mut self, offset: u32, _size: u32, _memory: &mut Memory) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => self.input.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_interrupt = false;
+                }
+                IIR_FIFOS | id
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.input.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => 0,
+        };
+        value.into()
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32, _memory: &mut Memory) -> bool {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = value as u8;
+        match offset {
+            RBR_THR if dlab => self.divisor = self.divisor & 0xff00 | u16::from(value),
+            RBR_THR => {
+                self.output.push(value);
+                self.thr_empty_interrupt = true;
+            }
+            IER if dlab => self.divisor = self.divisor & 0x00ff | u16::from(value) << 8,
+            IER => {
+                // enabling it with the transmitter empty interrupts at once
+                if value & !self.ier & IER_THR_EMPTY != 0 {
+                    self.thr_empty_interrupt = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+        false
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+    use crate::memory::Memory;
+    use crate::mmio::Device;
+    use crate::{Register, StopReason, Vm};
+
+    #[test]
+    fn should_echo_what_the_guest_receives() {
+        // 0x1000 lui t0, 0x10000
+        // 0x1004 lbu a0, 5(t0)         <- poll the line status
+        // 0x1008 andi a0, a0, 1
+        // 0x100c beq a0, zero, 0x1004
+        // 0x1010 lbu a0, 0(t0)
+        // 0x1014 sb a0, 0(t0)
+        // 0x1018 ebreak
+        let program: Vec<u8> = [
+            0x1000_02b7u32,
+            0x0052_c503,
+            0x0015_7513,
+            0xfe05_0ce3,
+            0x0002_c503,
+            0x00a2_8023,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device(UART_BASE, UART_SIZE, Box::new(Uart::default()));
+        vm.device_mut::<Uart>(UART_BASE)
+            .unwrap()
+            .input
+            .push_back(b'!');
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers[Register::A0], i32::from(b'!'));
+        let uart = vm.device::<Uart>(UART_BASE).unwrap();
+        assert_eq!(uart.output, b"!");
+        assert!(uart.input.is_empty());
+    }
+
+    #[test]
+    fn a_received_byte_should_interrupt_through_the_plic() {
+        let program: Vec<u8> = [
+            // 0x1000 auipc t0, 0
+            0x0000_0297u32,
+            // 0x1004 addi t0, t0, 0x50
+            0x0502_8293,
+            // 0x1008 csrrw zero, mtvec, t0
+            0x3052_9073,
+            // 0x100c lui t0, 0x0c000            the PLIC
+            0x0c00_02b7,
+            // 0x1010 addi t1, zero, 1
+            0x0010_0313,
+            // 0x1014 sw t1, 0x28(t0)           priority of line 10
+            0x0262_a423,
+            // 0x1018 addi t1, zero, 0x400
+            0x4000_0313,
+            // 0x101c lui t2, 0x2
+            0x0000_23b7,
+            // 0x1020 add t2, t2, t0
+            0x0053_83b3,
+            // 0x1024 sw t1, 0(t2)              enable line 10 for context 0
+            0x0063_a023,
+            // 0x1028 lui t1, 0x1
+            0x0000_1337,
+            // 0x102c srli t1, t1, 1
+            0x0013_5313,
+            // 0x1030 csrrs zero, mie, t1       MEIE
+            0x3043_2073,
+            // 0x1034 lui t0, 0x10000           the UART
+            0x1000_02b7,
+            // 0x1038 addi t1, zero, 1
+            0x0010_0313,
+            // 0x103c sb t1, 1(t0)              IER, a received byte
+            0x0062_80a3,
+            // 0x1040 csrrsi zero, mstatus, 8
+            0x3004_6073,
+            // 0x1044 jal zero, 0
+            0x0000_006f,
+            // 0x1048 nop
+            0x0000_0013,
+            // 0x104c nop
+            0x0000_0013,
+            // 0x1050 lui t0, 0x0c200           <- handler
+            0x0c20_02b7,
+            // 0x1054 lw a0, 4(t0)              claim
+            0x0042_a503,
+            // 0x1058 lui t1, 0x10000
+            0x1000_0337,
+            // 0x105c lbu a1, 0(t1)
+            0x0003_4583,
+            // 0x1060 sw a0, 4(t0)              complete
+            0x00a2_a223,
+            // 0x1064 ebreak
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_plic();
+        vm.load_program(0x1000, &program).unwrap();
+        vm.add_device_with_irq(UART_BASE, UART_SIZE, UART_IRQ, Box::new(Uart::default()));
+
+        assert_eq!(vm.run_for(100), Ok(StopReason::Preempted { pc: 0x1044 }));
+        vm.device_mut::<Uart>(UART_BASE)
+            .unwrap()
+            .input
+            .push_back(b'!');
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.csrs.mepc, 0x1044);
+        assert_eq!(vm.vm_state.registers[Register::A0], UART_IRQ as i32);
+        assert_eq!(vm.vm_state.registers[Register::A1], i32::from(b'!'));
+        // the byte was read, the line is down again
+        assert_eq!(vm.plic.as_ref().unwrap().pending(), 0);
+    }
+
+    #[test]
+    fn an_empty_transmitter_should_interrupt_until_iir_is_read() {
+        let mut uart = Uart::default();
+        let mut memory = Memory::new(0x1000, 0x100);
+        assert!(!uart.interrupt());
+        // enabling it while the transmitter is empty
+        uart.write(1, 1, 0b10, &mut memory);
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(2, 1, &mut memory), 0xc2);
+        assert!(!uart.interrupt());
+        assert_eq!(uart.read(2, 1, &mut memory), 0xc1);
+
+        // every byte empties it again
+        uart.write(0, 1, u32::from(b'x'), &mut memory);
+        assert!(uart.interrupt());
+        // a received byte goes first and stays until it is read
+        uart.write(1, 1, 0b11, &mut memory);
+        uart.input.push_back(b'y');
+        assert_eq!(uart.read(2, 1, &mut memory), 0xc4);
+        assert_eq!(uart.read(0, 1, &mut memory), u32::from(b'y'));
+        assert_eq!(uart.read(2, 1, &mut memory), 0xc2);
+        assert!(!uart.interrupt());
+    }
+}
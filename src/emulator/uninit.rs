@@ -0,0 +1,133 @@
+//! A detector for reads of uninitialized memory, like MemorySanitizer. The
+//! memory keeps a shadow bit per byte of RAM that says whether it was ever
+//! written, by the guest or the host, and every load of the guest from a
+//! byte that was not is reported with the pc, the address and the symbol
+//! it is in. The run goes on, the load reads the zeros that are there.
+//!
+//! Turn it on before loading the program: what the loader writes (the
+//! segments, .bss, the arguments on the stack) counts as initialized then,
+//! and so does ROM. Each load instruction is reported once.
+
+use core::fmt;
+
+use super::emulator::Vm;
+use super::memory::MemoryAccess;
+use super::prelude::*;
+
+/// a load of bytes nobody wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UninitializedRead {
+    pub pc: u32,
+    pub address: u32,
+    pub size: u32,
+    /// the symbol the address is in and how far into it, if it is known
+    pub symbol: Option<(String, u32)>,
+}
+
+/// `uninitialized read of 4 bytes at 0x00001080 <buffer> by pc 0x00001004`
+impl fmt::Display for UninitializedRead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uninitialized read of {} bytes at {:#010x}",
+            self.size, self.address
+        )?;
+        match &self.symbol {
+            Some((name, 0)) => write!(f, " <{name}>")?,
+            Some((name, offset)) => write!(f, " <{name}+{offset:#x}>")?,
+            None => {}
+        }
+        write!(f, " by pc {:#010x}", self.pc)
+    }
+}
+
+impl Vm {
+    /// reports the loads of uninitialized memory in `uninitialized_reads`,
+    /// the memory written before this call does not count as written
+    pub fn with_uninit_detection(mut self) -> Self {
+        self.memory.track_initialization();
+        self.uninitialized_reads = Some(Vec::new());
+        self
+    }
+
+    /// the uninitialized reads so far, empty without `with_uninit_detection`
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead] {
+        self.uninitialized_reads.as_deref().unwrap_or_default()
+    }
+
+    /// reports `access` by the instruction at `pc` if it read uninitialized
+    /// bytes
+    pub(super) fn check_initialized(&mut self, pc: u32, access: Option<MemoryAccess>) {
+        let Some(MemoryAccess::Read { address, size, .. }) = access else {
+            return;
+        };
+        let Some(reads) = &self.uninitialized_reads else {
+            return;
+        };
+        if self.memory.is_initialized(address, size as usize)
+            || reads.iter().any(|read| read.pc == pc)
+        {
+            return;
+        }
+        let symbol = self
+            .symbol_at(address)
+            .map(|(symbol, offset)| (symbol.name.clone(), offset));
+        if let Some(reads) = &mut self.uninitialized_reads {
+            reads.push(UninitializedRead {
+                pc,
+                address,
+                size,
+                symbol,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::{Symbol, SymbolKind};
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_report_loads_of_bytes_nobody_wrote() {
+        // 0x1000 lui t0, 0x1
+        // 0x1004 lw a0, 128(t0)        <- buffer, never written
+        // 0x1008 sw a0, 132(t0)
+        // 0x100c lw a1, 132(t0)
+        // 0x1010 lbu a2, 137(t0)       <- the byte after the store
+        // 0x1014 ebreak
+        let program: Vec<u8> = [
+            0x0000_12b7u32,
+            0x0802_a503,
+            0x08a2_a223,
+            0x0842_a583,
+            0x0892_c603,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let mut vm = Vm::new(0x1000, 0x100).with_uninit_detection();
+        vm.load_program(0x1000, &program).unwrap();
+        vm.symbols.push(Symbol {
+            name: "buffer".to_string(),
+            address: 0x1080,
+            size: 16,
+            kind: SymbolKind::Object,
+        });
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        let reads: Vec<String> = vm
+            .uninitialized_reads()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            reads,
+            [
+                "uninitialized read of 4 bytes at 0x00001080 <buffer> by pc 0x00001004",
+                "uninitialized read of 1 bytes at 0x00001089 <buffer+0x9> by pc 0x00001010",
+            ]
+        );
+    }
+}
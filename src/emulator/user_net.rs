@@ -0,0 +1,541 @@
+//! `UserNet`, the network backend that is a user-mode NAT like QEMU's slirp:
+//! the guest is 10.0.2.15 on 10.0.2.0/24 and the gateway 10.0.2.2 stands for
+//! the host's loopback. It answers ARP for the gateway, and turns UDP
+//! datagrams and TCP connections of the guest into host sockets, so no
+//! privileges or tap devices are needed. There is no DHCP, the guest has to
+//! use the static addresses. Retransmission is not needed since the link
+//! never drops anything.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use super::net::NetBackend;
+
+pub const GUEST_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+pub const GATEWAY_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// the most data in one TCP segment to the guest
+const MSS: usize = 1460;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn word(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn long(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// the internet checksum of `bytes`, starting from `sum`
+fn checksum(bytes: &[u8], mut sum: u32) -> u16 {
+    for chunk in bytes.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// the sum over the pseudo header of TCP and UDP
+fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, length: usize) -> u32 {
+    let [a, b, c, d] = source.octets();
+    let [e, f, g, h] = destination.octets();
+    [
+        [a, b],
+        [c, d],
+        [e, f],
+        [g, h],
+        [0, protocol],
+        (length as u16).to_be_bytes(),
+    ]
+    .iter()
+    .map(|pair| u32::from(u16::from_be_bytes(*pair)))
+    .sum()
+}
+
+/// the host address behind an address the guest uses
+fn host_address(address: Ipv4Addr) -> Ipv4Addr {
+    if address == GATEWAY_ADDRESS {
+        Ipv4Addr::LOCALHOST
+    } else {
+        address
+    }
+}
+
+/// the address the guest sees for a host address
+fn guest_visible_address(address: Ipv4Addr) -> Ipv4Addr {
+    if address.is_loopback() {
+        GATEWAY_ADDRESS
+    } else {
+        address
+    }
+}
+
+/// a TCP connection of the guest, as (guest port, remote address)
+type ConnectionKey = (u16, SocketAddrV4);
+
+struct TcpConnection {
+    stream: TcpStream,
+    /// the sequence number of the next byte to the guest
+    sequence: u32,
+    /// the sequence number of the next byte from the guest
+    acknowledged: u32,
+    guest_closed: bool,
+    host_closed: bool,
+}
+
+/// the user-mode NAT, see the module docs
+#[derive(Default)]
+pub struct UserNet {
+    guest_mac: [u8; 6],
+    /// the frames for the guest
+    incoming: VecDeque<Vec<u8>>,
+    /// a host socket per UDP port of the guest
+    udp: HashMap<u16, UdpSocket>,
+    tcp: HashMap<ConnectionKey, TcpConnection>,
+    /// the initial sequence number of the next connection
+    next_sequence: u32,
+}
+
+impl UserNet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// wraps an IPv4 packet from `source` to the guest in a frame
+    fn deliver(&mut self, source: Ipv4Addr, protocol: u8, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(34 + payload.len());
+        frame.extend_from_slice(&self.guest_mac);
+        frame.extend_from_slice(&GATEWAY_MAC);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+        let mut header = [0u8; 20];
+        header[0] = 0x45;
+        header[2..4].copy_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        // don't fragment
+        header[6] = 0x40;
+        header[8] = 64;
+        header[9] = protocol;
+        header[12..16].copy_from_slice(&source.octets());
+        header[16..20].copy_from_slice(&GUEST_ADDRESS.octets());
+        let sum = checksum(&header, 0);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(payload);
+        self.incoming.push_back(frame);
+    }
+
+    fn deliver_udp(&mut self, source: SocketAddrV4, guest_port: u16, data: &[u8]) {
+        let length = 8 + data.len();
+        let mut datagram = Vec::with_capacity(length);
+        datagram.extend_from_slice(&source.port().to_be_bytes());
+        datagram.extend_from_slice(&guest_port.to_be_bytes());
+        datagram.extend_from_slice(&(length as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let sum = pseudo_header_sum(*source.ip(), GUEST_ADDRESS, PROTOCOL_UDP, length);
+        let sum = checksum(&datagram, sum);
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        self.deliver(*source.ip(), PROTOCOL_UDP, &datagram);
+    }
+
+    fn deliver_tcp(&mut self, key: ConnectionKey, flags: u8, data: &[u8]) {
+        let (guest_port, remote) = key;
+        let (sequence, acknowledged) = match self.tcp.get(&key) {
+            Some(connection) => (connection.sequence, connection.acknowledged),
+            None => (0, 0),
+        };
+        self.deliver_segment(remote, guest_port, sequence, acknowledged, flags, data);
+    }
+
+    fn deliver_segment(
+        &mut self,
+        remote: SocketAddrV4,
+        guest_port: u16,
+        sequence: u32,
+        acknowledged: u32,
+        flags: u8,
+        data: &[u8],
+    ) {
+        let length = 20 + data.len();
+        let mut segment = Vec::with_capacity(length);
+        segment.extend_from_slice(&remote.port().to_be_bytes());
+        segment.extend_from_slice(&guest_port.to_be_bytes());
+        segment.extend_from_slice(&sequence.to_be_bytes());
+        segment.extend_from_slice(&acknowledged.to_be_bytes());
+        segment.extend_from_slice(&[5 << 4, flags]);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment.extend_from_slice(data);
+        let sum = pseudo_header_sum(*remote.ip(), GUEST_ADDRESS, PROTOCOL_TCP, length);
+        let sum = checksum(&segment, sum);
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+        self.deliver(*remote.ip(), PROTOCOL_TCP, &segment);
+    }
+
+    /// answers who-has for any address on the guest's network but its own
+    fn arp(&mut self, packet: &[u8]) {
+        if packet.len() < 28 || word(packet, 6) != 1 {
+            return;
+        }
+        let target = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+        if target == GUEST_ADDRESS || target.octets()[..3] != GUEST_ADDRESS.octets()[..3] {
+            return;
+        }
+        let mut frame = Vec::with_capacity(42);
+        frame.extend_from_slice(&packet[8..14]);
+        frame.extend_from_slice(&GATEWAY_MAC);
+        frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+        frame.extend_from_slice(&packet[..6]);
+        frame.extend_from_slice(&2u16.to_be_bytes());
+        frame.extend_from_slice(&GATEWAY_MAC);
+        frame.extend_from_slice(&packet[24..28]);
+        frame.extend_from_slice(&packet[8..18]);
+        self.incoming.push_back(frame);
+    }
+
+    fn udp(&mut self, destination: Ipv4Addr, datagram: &[u8]) {
+        if datagram.len() < 8 {
+            return;
+        }
+        let (guest_port, port) = (word(datagram, 0), word(datagram, 2));
+        let length = (word(datagram, 4) as usize).clamp(8, datagram.len());
+        let socket = match self.udp.get(&guest_port) {
+            Some(socket) => socket,
+            None => {
+                let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) else {
+                    return;
+                };
+                if socket.set_nonblocking(true).is_err() {
+                    return;
+                }
+                self.udp.entry(guest_port).or_insert(socket)
+            }
+        };
+        let target = SocketAddrV4::new(host_address(destination), port);
+        // like a real network, a datagram can get lost
+        let _ = socket.send_to(&datagram[8..length], target);
+    }
+
+    fn tcp(&mut self, destination: Ipv4Addr, segment: &[u8]) {
+        if segment.len() < 20 {
+            return;
+        }
+        let guest_port = word(segment, 0);
+        let remote = SocketAddrV4::new(destination, word(segment, 2));
+        let key = (guest_port, remote);
+        let sequence = long(segment, 4);
+        let flags = segment[13];
+        let data = &segment[(usize::from(segment[12] >> 4) * 4).min(segment.len())..];
+
+        if flags & TCP_RST != 0 {
+            self.tcp.remove(&key);
+            return;
+        }
+        if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+            self.connect(key, sequence);
+            return;
+        }
+        let Some(connection) = self.tcp.get_mut(&key) else {
+            // not ours (any more)
+            let acknowledged = sequence.wrapping_add(data.len() as u32);
+            let reset = TCP_RST | TCP_ACK;
+            self.deliver_segment(
+                remote,
+                guest_port,
+                long(segment, 8),
+                acknowledged,
+                reset,
+                &[],
+            );
+            return;
+        };
+
+        let mut acknowledge = false;
+        if !data.is_empty() && sequence == connection.acknowledged {
+            if connection.stream.write_all(data).is_err() {
+                self.reset(key);
+                return;
+            }
+            connection.acknowledged = connection.acknowledged.wrapping_add(data.len() as u32);
+            acknowledge = true;
+        }
+        if flags & TCP_FIN != 0 && !connection.guest_closed {
+            connection.guest_closed = true;
+            connection.acknowledged = connection.acknowledged.wrapping_add(1);
+            let _ = connection.stream.shutdown(Shutdown::Write);
+            acknowledge = true;
+        }
+        let finished = connection.guest_closed && connection.host_closed;
+        if acknowledge {
+            self.deliver_tcp(key, TCP_ACK, &[]);
+        }
+        if finished {
+            self.tcp.remove(&key);
+        }
+    }
+
+    /// opens the host connection for a SYN of the guest
+    fn connect(&mut self, key: ConnectionKey, sequence: u32) {
+        let (guest_port, remote) = key;
+        let host = SocketAddrV4::new(host_address(*remote.ip()), remote.port());
+        let stream = TcpStream::connect_timeout(&host.into(), CONNECT_TIMEOUT)
+            .and_then(|stream| stream.set_nonblocking(true).map(|()| stream));
+        let Ok(stream) = stream else {
+            let acknowledged = sequence.wrapping_add(1);
+            self.deliver_segment(remote, guest_port, 0, acknowledged, TCP_RST | TCP_ACK, &[]);
+            return;
+        };
+
+        let initial = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(0x0001_0000);
+        self.tcp.insert(
+            key,
+            TcpConnection {
+                stream,
+                sequence: initial,
+                acknowledged: sequence.wrapping_add(1),
+                guest_closed: false,
+                host_closed: false,
+            },
+        );
+        self.deliver_tcp(key, TCP_SYN | TCP_ACK, &[]);
+        if let Some(connection) = self.tcp.get_mut(&key) {
+            connection.sequence = initial.wrapping_add(1);
+        }
+    }
+
+    fn reset(&mut self, key: ConnectionKey) {
+        self.deliver_tcp(key, TCP_RST | TCP_ACK, &[]);
+        self.tcp.remove(&key);
+    }
+
+    /// turns what the host sockets received into frames for the guest
+    fn poll(&mut self) {
+        let mut buffer = [0; MSS];
+        let mut datagrams = Vec::new();
+        for (&guest_port, socket) in &self.udp {
+            while let Ok((length, std::net::SocketAddr::V4(source))) = socket.recv_from(&mut buffer)
+            {
+                let source = SocketAddrV4::new(guest_visible_address(*source.ip()), source.port());
+                datagrams.push((source, guest_port, buffer[..length].to_vec()));
+            }
+        }
+        for (source, guest_port, data) in datagrams {
+            self.deliver_udp(source, guest_port, &data);
+        }
+
+        let keys: Vec<ConnectionKey> = self.tcp.keys().copied().collect();
+        for key in keys {
+            let Some(connection) = self.tcp.get_mut(&key) else {
+                continue;
+            };
+            if connection.host_closed {
+                continue;
+            }
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    connection.host_closed = true;
+                    let finished = connection.guest_closed;
+                    self.deliver_tcp(key, TCP_FIN | TCP_ACK, &[]);
+                    if let Some(connection) = self.tcp.get_mut(&key) {
+                        connection.sequence = connection.sequence.wrapping_add(1);
+                    }
+                    if finished {
+                        self.tcp.remove(&key);
+                    }
+                }
+                Ok(length) => {
+                    let data = buffer[..length].to_vec();
+                    self.deliver_tcp(key, TCP_PSH | TCP_ACK, &data);
+                    if let Some(connection) = self.tcp.get_mut(&key) {
+                        connection.sequence = connection.sequence.wrapping_add(length as u32);
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => self.reset(key),
+            }
+        }
+    }
+}
+
+impl NetBackend for UserNet {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        self.guest_mac.copy_from_slice(&frame[6..12]);
+        let payload = &frame[14..];
+        match word(frame, 12) {
+            ETHERTYPE_ARP => self.arp(payload),
+            ETHERTYPE_IPV4 if payload.len() >= 20 => {
+                let header_length = usize::from(payload[0] & 0x0f) * 4;
+                let total_length = (word(payload, 2) as usize).min(payload.len());
+                if header_length < 20 || total_length < header_length {
+                    return;
+                }
+                let destination = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+                let body = &payload[header_length..total_length];
+                match payload[9] {
+                    PROTOCOL_UDP => self.udp(destination, body),
+                    PROTOCOL_TCP => self.tcp(destination, body),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.incoming.is_empty() {
+            self.poll();
+        }
+        self.incoming.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checksum, long, pseudo_header_sum, word, NetBackend, UserNet, GATEWAY_ADDRESS, GATEWAY_MAC,
+        GUEST_ADDRESS, PROTOCOL_TCP, PROTOCOL_UDP, TCP_ACK, TCP_SYN,
+    };
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    /// an IPv4 frame from the guest to the gateway
+    fn frame(protocol: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = GATEWAY_MAC.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0]);
+        frame.extend_from_slice(&((20 + body.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+        frame.extend_from_slice(&GUEST_ADDRESS.octets());
+        frame.extend_from_slice(&GATEWAY_ADDRESS.octets());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn tcp_segment(port: u16, sequence: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = 4000u16.to_be_bytes().to_vec();
+        segment.extend_from_slice(&port.to_be_bytes());
+        segment.extend_from_slice(&sequence.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0, 5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// waits for the next frame for the guest
+    fn receive(net: &mut UserNet) -> Vec<u8> {
+        let started = Instant::now();
+        loop {
+            if let Some(frame) = net.receive() {
+                return frame;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "no frame");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// checks the IPv4 header and returns the body
+    fn ipv4_body(frame: &[u8], protocol: u8) -> &[u8] {
+        assert_eq!(&frame[..6], &GUEST_MAC);
+        assert_eq!(checksum(&frame[14..34], 0), 0);
+        assert_eq!(frame[23], protocol);
+        let body = &frame[34..];
+        let sum = pseudo_header_sum(GATEWAY_ADDRESS, GUEST_ADDRESS, protocol, body.len());
+        assert_eq!(checksum(body, sum), 0);
+        body
+    }
+
+    #[test]
+    fn should_answer_arp_for_the_gateway() {
+        let mut net = UserNet::new();
+        let mut request = vec![0xff; 6];
+        request.extend_from_slice(&GUEST_MAC);
+        request.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 1]);
+        request.extend_from_slice(&GUEST_MAC);
+        request.extend_from_slice(&GUEST_ADDRESS.octets());
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&GATEWAY_ADDRESS.octets());
+        net.send(&request);
+
+        let reply = net.receive().unwrap();
+        assert_eq!(&reply[..6], &GUEST_MAC);
+        assert_eq!(word(&reply, 20), 2);
+        assert_eq!(&reply[22..28], &GATEWAY_MAC);
+        assert_eq!(&reply[28..32], &GATEWAY_ADDRESS.octets());
+    }
+
+    #[test]
+    fn should_forward_udp_to_the_host() {
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut net = UserNet::new();
+
+        let mut datagram = 5000u16.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&[0, 10, 0, 0, b'p', b'i']);
+        net.send(&frame(PROTOCOL_UDP, &datagram));
+
+        let mut buffer = [0; 16];
+        let (length, guest) = host.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"pi");
+        host.send_to(b"pong", guest).unwrap();
+
+        let reply = receive(&mut net);
+        let body = ipv4_body(&reply, PROTOCOL_UDP);
+        assert_eq!((word(body, 0), word(body, 2)), (port, 5000));
+        assert_eq!(&body[8..], b"pong");
+    }
+
+    #[test]
+    fn should_connect_the_guest_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut net = UserNet::new();
+
+        net.send(&frame(PROTOCOL_TCP, &tcp_segment(port, 100, TCP_SYN, &[])));
+        let syn_ack = receive(&mut net);
+        let body = ipv4_body(&syn_ack, PROTOCOL_TCP);
+        assert_eq!(body[13], TCP_SYN | TCP_ACK);
+        assert_eq!(long(body, 8), 101);
+        let initial = long(body, 4);
+        let (mut host, _) = listener.accept().unwrap();
+
+        net.send(&frame(
+            PROTOCOL_TCP,
+            &tcp_segment(port, 101, TCP_ACK, b"GET"),
+        ));
+        let ack = receive(&mut net);
+        assert_eq!(long(ipv4_body(&ack, PROTOCOL_TCP), 8), 104);
+        let mut request = [0; 3];
+        host.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"GET");
+
+        host.write_all(b"200 OK").unwrap();
+        let response = receive(&mut net);
+        let body = ipv4_body(&response, PROTOCOL_TCP);
+        assert_eq!(long(body, 4), initial.wrapping_add(1));
+        assert_eq!(&body[20..], b"200 OK");
+    }
+}
@@ -0,0 +1,600 @@
+//! A small part of the V extension, enough for simple RVV kernels: the
+//! vector registers with a configurable VLEN, `vsetvli` and `vsetivli`, the
+//! unit-stride loads and stores (`vle8/16/32.v`, `vse8/16/32.v`) and the
+//! integer `vadd`, `vsub` and `vmul` in their vector, scalar and immediate
+//! forms, optionally masked by v0.
+//!
+//! SEW goes up to 32 bits and LMUL is 1, 2, 4 or 8, any other `vtype` sets
+//! `vill`. The 32 registers are one flat array, so a register group is just
+//! the bytes of its registers in a row. Tail and masked-off elements are
+//! left as they are, which the agnostic policies allow as well. `vstart` is
+//! always 0.
+//!
+//! The vector instructions are illegal unless the vm has a vector unit, see
+//! `Vm::with_vector`.
+
+use core::fmt;
+
+use super::emulator::{Instruction, Vm};
+use super::error::VmError;
+use super::extensions::Extension;
+use super::memory::MemoryAccess;
+use super::prelude::*;
+use super::register::Register;
+use super::rv32i::Rv32iInstruction;
+
+pub const CSR_VL: u16 = 0xc20;
+pub const CSR_VTYPE: u16 = 0xc21;
+pub const CSR_VLENB: u16 = 0xc22;
+
+/// `vtype` after a setting the vm does not support
+const VTYPE_VILL: u32 = 1 << 31;
+
+const OPCODE_LOAD_FP: u32 = 0x07;
+const OPCODE_STORE_FP: u32 = 0x27;
+const OPCODE_OP_V: u32 = 0x57;
+
+const OPIVV: u32 = 0b000;
+const OPMVV: u32 = 0b010;
+const OPIVI: u32 = 0b011;
+const OPIVX: u32 = 0b100;
+const OPMVX: u32 = 0b110;
+const OPCFG: u32 = 0b111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorOperation {
+    Add,
+    Sub,
+    Mul,
+}
+
+/// the second source of an arithmetic instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorOperand {
+    /// `.vv`, a vector register
+    Vector(u8),
+    /// `.vx`, an x register
+    Scalar(Register),
+    /// `.vi`, a sign extended 5 bit immediate
+    Immediate(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorInstruction {
+    /// sets vl to the requested length in `rs1` (VLMAX for x0) and vtype
+    Vsetvli {
+        rd: Register,
+        rs1: Register,
+        vtype: u32,
+    },
+    /// like `vsetvli` with the length as a 5 bit immediate
+    Vsetivli { rd: Register, avl: u32, vtype: u32 },
+    /// unit-stride load of `width` bit elements from the address in `rs1`
+    Load {
+        vd: u8,
+        rs1: Register,
+        width: u32,
+        masked: bool,
+    },
+    /// unit-stride store of `width` bit elements to the address in `rs1`
+    Store {
+        vs3: u8,
+        rs1: Register,
+        width: u32,
+        masked: bool,
+    },
+    /// `vd[i] = vs2[i] op operand`
+    Arithmetic {
+        operation: VectorOperation,
+        vd: u8,
+        vs2: u8,
+        operand: VectorOperand,
+        masked: bool,
+    },
+}
+
+impl VectorInstruction {
+    /// decodes the vector instructions above, `None` for anything else
+    pub fn decode(word: u32) -> Option<Self> {
+        let opcode = word & 0x7f;
+        let vd = (word >> 7 & 0x1f) as u8;
+        let funct3 = word >> 12 & 0b111;
+        let rs1 = (word >> 15 & 0x1f) as u8;
+        let vs2 = (word >> 20 & 0x1f) as u8;
+        let masked = word >> 25 & 1 == 0;
+        let funct6 = word >> 26;
+
+        match opcode {
+            OPCODE_LOAD_FP | OPCODE_STORE_FP => {
+                let width = match funct3 {
+                    0b000 => 8,
+                    0b101 => 16,
+                    0b110 => 32,
+                    0b111 => 64,
+                    _ => return None,
+                };
+                // nf, mew and mop are 0 and lumop/sumop is 0: unit-stride,
+                // one field
+                if word >> 26 != 0 || vs2 != 0 {
+                    return None;
+                }
+                let rs1 = Register::from_bits(rs1);
+                Some(match opcode {
+                    OPCODE_LOAD_FP => Self::Load {
+                        vd,
+                        rs1,
+                        width,
+                        masked,
+                    },
+                    _ => Self::Store {
+                        vs3: vd,
+                        rs1,
+                        width,
+                        masked,
+                    },
+                })
+            }
+            OPCODE_OP_V if funct3 == OPCFG => match word >> 30 {
+                0b00 | 0b01 => Some(Self::Vsetvli {
+                    rd: Register::from_bits(vd),
+                    rs1: Register::from_bits(rs1),
+                    vtype: word >> 20 & 0x7ff,
+                }),
+                0b11 => Some(Self::Vsetivli {
+                    rd: Register::from_bits(vd),
+                    avl: rs1.into(),
+                    vtype: word >> 20 & 0x3ff,
+                }),
+                _ => None,
+            },
+            OPCODE_OP_V => {
+                let operation = match (funct6, funct3) {
+                    (0b000000, OPIVV | OPIVX | OPIVI) => VectorOperation::Add,
+                    (0b000010, OPIVV | OPIVX) => VectorOperation::Sub,
+                    (0b100101, OPMVV | OPMVX) => VectorOperation::Mul,
+                    _ => return None,
+                };
+                let operand = match funct3 {
+                    OPIVV | OPMVV => VectorOperand::Vector(rs1),
+                    OPIVX | OPMVX => VectorOperand::Scalar(Register::from_bits(rs1)),
+                    _ => VectorOperand::Immediate((i32::from(rs1) << 27) >> 27),
+                };
+                Some(Self::Arithmetic {
+                    operation,
+                    vd,
+                    vs2,
+                    operand,
+                    masked,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        use VectorOperand::{Immediate, Scalar, Vector};
+        use VectorOperation::{Add, Mul, Sub};
+
+        match self {
+            Self::Vsetvli { .. } => "vsetvli",
+            Self::Vsetivli { .. } => "vsetivli",
+            Self::Load { width: 8, .. } => "vle8.v",
+            Self::Load { width: 16, .. } => "vle16.v",
+            Self::Load { width: 32, .. } => "vle32.v",
+            Self::Load { .. } => "vle64.v",
+            Self::Store { width: 8, .. } => "vse8.v",
+            Self::Store { width: 16, .. } => "vse16.v",
+            Self::Store { width: 32, .. } => "vse32.v",
+            Self::Store { .. } => "vse64.v",
+            Self::Arithmetic {
+                operation, operand, ..
+            } => match (operation, operand) {
+                (Add, Vector(_)) => "vadd.vv",
+                (Add, Scalar(_)) => "vadd.vx",
+                (Add, Immediate(_)) => "vadd.vi",
+                (Sub, Vector(_)) => "vsub.vv",
+                (Sub, _) => "vsub.vx",
+                (Mul, Vector(_)) => "vmul.vv",
+                (Mul, _) => "vmul.vx",
+            },
+        }
+    }
+
+    /// the x register the instruction writes, the vector registers are not
+    /// in the vm state
+    pub fn destination(&self) -> Option<Register> {
+        match self {
+            Self::Vsetvli { rd, .. } | Self::Vsetivli { rd, .. } => Some(*rd),
+            _ => None,
+        }
+    }
+}
+
+/// `vtype` like the assembler writes it, `e32, m1, ta, ma`
+fn vtype_name(vtype: u32) -> String {
+    let sew = 8 << (vtype >> 3 & 0b111);
+    let lmul = match vtype & 0b111 {
+        lmul @ 0..=3 => format!("m{}", 1 << lmul),
+        lmul => format!("mf{}", 1 << (8 - lmul)),
+    };
+    let tail = if vtype & 1 << 6 != 0 { "ta" } else { "tu" };
+    let mask = if vtype & 1 << 7 != 0 { "ma" } else { "mu" };
+    format!("e{sew}, {lmul}, {tail}, {mask}")
+}
+
+impl fmt::Display for VectorInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = self.mnemonic();
+        let mask = |masked: bool| if masked { ", v0.t" } else { "" };
+        match self {
+            Self::Vsetvli { rd, rs1, vtype } => {
+                write!(f, "{mnemonic} {rd}, {rs1}, {}", vtype_name(*vtype))
+            }
+            Self::Vsetivli { rd, avl, vtype } => {
+                write!(f, "{mnemonic} {rd}, {avl}, {}", vtype_name(*vtype))
+            }
+            Self::Load {
+                vd: register,
+                rs1,
+                masked,
+                ..
+            }
+            | Self::Store {
+                vs3: register,
+                rs1,
+                masked,
+                ..
+            } => write!(f, "{mnemonic} v{register}, ({rs1}){}", mask(*masked)),
+            Self::Arithmetic {
+                vd,
+                vs2,
+                operand,
+                masked,
+                ..
+            } => {
+                let operand = match operand {
+                    VectorOperand::Vector(vs1) => format!("v{vs1}"),
+                    VectorOperand::Scalar(rs1) => rs1.to_string(),
+                    VectorOperand::Immediate(imm) => imm.to_string(),
+                };
+                write!(f, "{mnemonic} v{vd}, v{vs2}, {operand}{}", mask(*masked))
+            }
+        }
+    }
+}
+
+/// the vector registers and the vector CSRs
+#[derive(Debug, Clone)]
+pub struct VectorUnit {
+    /// the 32 registers of VLEN bits, one after the other
+    pub registers: Vec<u8>,
+    pub vl: u32,
+    pub vtype: u32,
+}
+
+impl VectorUnit {
+    /// `vlen` is in bits, a power of two and at least 32
+    pub fn new(vlen: usize) -> Self {
+        assert!(
+            vlen.is_power_of_two() && vlen >= 32,
+            "VLEN must be a power of two and at least 32, not {vlen}"
+        );
+        Self {
+            registers: vec![0; 32 * vlen / 8],
+            vl: 0,
+            vtype: VTYPE_VILL,
+        }
+    }
+
+    /// VLEN in bytes
+    pub fn vlenb(&self) -> usize {
+        self.registers.len() / 32
+    }
+
+    /// the bytes of register `register`
+    pub fn register(&self, register: u8) -> &[u8] {
+        let vlenb = self.vlenb();
+        &self.registers[register as usize * vlenb..][..vlenb]
+    }
+
+    /// SEW in bits and LMUL of `vtype`, `None` for what the vm does not
+    /// support
+    fn configuration(vtype: u32) -> Option<(usize, usize)> {
+        let sew = match vtype >> 3 & 0b111 {
+            vsew @ 0..=2 => 8 << vsew,
+            _ => return None,
+        };
+        let lmul = match vtype & 0b111 {
+            vlmul @ 0..=3 => 1 << vlmul,
+            _ => return None,
+        };
+        // the reserved bits above vma
+        (vtype >> 8 == 0).then_some((sew, lmul))
+    }
+
+    /// `vsetvli`: sets vtype and returns the new vl for a requested length
+    /// of `avl`, `None` keeps vl as it is (rs1 and rd both x0)
+    fn set(&mut self, avl: Option<u32>, vtype: u32) -> u32 {
+        let Some((sew, lmul)) = Self::configuration(vtype) else {
+            self.vtype = VTYPE_VILL;
+            self.vl = 0;
+            return 0;
+        };
+        let vlmax = (self.vlenb() * 8 * lmul / sew) as u32;
+        self.vtype = vtype;
+        self.vl = avl.unwrap_or(self.vl).min(vlmax);
+        self.vl
+    }
+
+    fn active(&self, index: usize, masked: bool) -> bool {
+        !masked || self.registers[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// whether vl elements of `bytes` bytes from `register` on are in the
+    /// registers
+    fn fits(&self, register: u8, bytes: usize) -> bool {
+        register as usize * self.vlenb() + self.vl as usize * bytes <= self.registers.len()
+    }
+
+    /// element `index` of `bytes` bytes in the group starting at `register`
+    fn read(&self, register: u8, index: usize, bytes: usize) -> u32 {
+        let start = register as usize * self.vlenb() + index * bytes;
+        let mut word = [0; 4];
+        word[..bytes].copy_from_slice(&self.registers[start..start + bytes]);
+        u32::from_le_bytes(word)
+    }
+
+    fn write(&mut self, register: u8, index: usize, bytes: usize, value: u32) {
+        let start = register as usize * self.vlenb() + index * bytes;
+        self.registers[start..start + bytes].copy_from_slice(&value.to_le_bytes()[..bytes]);
+    }
+}
+
+impl Vm {
+    /// gives the vm a vector unit with `vlen` bit registers and turns V on,
+    /// see `vector.rs`
+    pub fn with_vector(mut self, vlen: usize) -> Self {
+        self.vector = Some(VectorUnit::new(vlen));
+        self.extensions = self.extensions.with(Extension::V);
+        self
+    }
+
+    /// executes a vector instruction and moves the pc past it. A load or
+    /// store is one access over all of its elements, `value` is the first one
+    pub(super) fn execute_vector(
+        &mut self,
+        instruction: &Instruction,
+    ) -> Result<Option<MemoryAccess>, VmError> {
+        let Instruction::Rv32iInstruction(_, Rv32iInstruction::Vector(instruction)) = instruction
+        else {
+            return Ok(None);
+        };
+        let illegal = VmError::IllegalInstruction {
+            pc: 0,
+            instruction: 0,
+        };
+        // the elements would each need a translation, see `mmu.rs`
+        let paging = self.is_paging();
+        let registers = &mut self.vm_state.registers;
+        let Some(vector) = &mut self.vector else {
+            return Err(illegal);
+        };
+        let configuration = VectorUnit::configuration(vector.vtype);
+
+        let access = match *instruction {
+            VectorInstruction::Vsetvli { rd, rs1, vtype } => {
+                let avl = match (rs1.is_zero(), rd.is_zero()) {
+                    (false, _) => Some(registers[rs1] as u32),
+                    (true, false) => Some(u32::MAX),
+                    (true, true) => None,
+                };
+                registers.write(rd, vector.set(avl, vtype) as i32);
+                None
+            }
+            VectorInstruction::Vsetivli { rd, avl, vtype } => {
+                registers.write(rd, vector.set(Some(avl), vtype) as i32);
+                None
+            }
+            VectorInstruction::Load {
+                vd: register,
+                rs1,
+                width,
+                masked,
+            }
+            | VectorInstruction::Store {
+                vs3: register,
+                rs1,
+                width,
+                masked,
+            } => {
+                let bytes = width as usize / 8;
+                if configuration.is_none()
+                    || width > 32
+                    || !vector.fits(register, bytes)
+                    || (masked && register == 0)
+                {
+                    return Err(illegal);
+                }
+                if paging {
+                    return Err(VmError::NotImplemented {
+                        pc: 0,
+                        instruction: 0,
+                    });
+                }
+                let load = matches!(instruction, VectorInstruction::Load { .. });
+                let address = registers[rs1] as u32;
+                let mut first = None;
+                for index in 0..vector.vl as usize {
+                    if !vector.active(index, masked) {
+                        continue;
+                    }
+                    let element = address.wrapping_add((index * bytes) as u32);
+                    let value = if load {
+                        let bytes = self.memory.read_bytes(element, bytes)?;
+                        let mut word = [0; 4];
+                        word[..bytes.len()].copy_from_slice(bytes);
+                        let value = u32::from_le_bytes(word);
+                        vector.write(register, index, bytes.len(), value);
+                        value
+                    } else {
+                        let value = vector.read(register, index, bytes);
+                        self.memory
+                            .write_bytes(element, &value.to_le_bytes()[..bytes])?;
+                        value
+                    };
+                    first.get_or_insert(value);
+                }
+                let size = vector.vl * bytes as u32;
+                first.map(|value| {
+                    if load {
+                        MemoryAccess::Read {
+                            address,
+                            size,
+                            value,
+                        }
+                    } else {
+                        MemoryAccess::Write {
+                            address,
+                            size,
+                            value,
+                        }
+                    }
+                })
+            }
+            VectorInstruction::Arithmetic {
+                operation,
+                vd,
+                vs2,
+                operand,
+                masked,
+            } => {
+                let Some((sew, lmul)) = configuration else {
+                    return Err(illegal);
+                };
+                let groups = [
+                    Some(vd),
+                    Some(vs2),
+                    match operand {
+                        VectorOperand::Vector(vs1) => Some(vs1),
+                        _ => None,
+                    },
+                ];
+                let misaligned = groups.iter().flatten().any(|&group| {
+                    !(group as usize).is_multiple_of(lmul) || group as usize + lmul > 32
+                });
+                if misaligned || (masked && vd == 0) {
+                    return Err(illegal);
+                }
+                let bytes = sew / 8;
+                let mask = (u64::from(u32::MAX) >> (32 - sew)) as u32;
+                for index in 0..vector.vl as usize {
+                    if !vector.active(index, masked) {
+                        continue;
+                    }
+                    let left = vector.read(vs2, index, bytes);
+                    let right = match operand {
+                        VectorOperand::Vector(vs1) => vector.read(vs1, index, bytes),
+                        VectorOperand::Scalar(rs1) => registers[rs1] as u32,
+                        VectorOperand::Immediate(imm) => imm as u32,
+                    };
+                    let result = match operation {
+                        VectorOperation::Add => left.wrapping_add(right),
+                        VectorOperation::Sub => left.wrapping_sub(right),
+                        VectorOperation::Mul => left.wrapping_mul(right),
+                    };
+                    vector.write(vd, index, bytes, result & mask);
+                }
+                None
+            }
+        };
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        Ok(access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VectorInstruction;
+    use crate::{StopReason, Vm, VmError};
+
+    #[test]
+    fn should_add_two_arrays_in_strips() {
+        // 0x1000 lui a1, 0x1
+        // 0x1004 addi a1, a1, 0x80     <- a = 0x1080
+        // 0x1008 addi a2, a1, 0x20     <- b = 0x10a0
+        // 0x100c addi a3, a1, 0x40     <- c = 0x10c0
+        // 0x1010 addi a0, zero, 6      <- n
+        // 0x1014 vsetvli t0, a0, e32, m1, ta, ma    <- loop
+        // 0x1018 vle32.v v1, (a1)
+        // 0x101c vle32.v v2, (a2)
+        // 0x1020 vadd.vv v3, v1, v2
+        // 0x1024 vmul.vx v3, v3, a0
+        // 0x1028 vse32.v v3, (a3)
+        // 0x102c sub a0, a0, t0
+        // 0x1030 slli t0, t0, 2
+        // 0x1034 add a1, a1, t0
+        // 0x1038 add a2, a2, t0
+        // 0x103c add a3, a3, t0
+        // 0x1040 bne a0, zero, 0x1014
+        // 0x1044 ebreak
+        let mut program = vec![
+            0x0000_15b7,
+            0x0805_8593,
+            0x0205_8613,
+            0x0405_8693,
+            0x0060_0513,
+            0x0d05_72d7,
+            0x0205_e087,
+            0x0206_6107,
+            0x0211_01d7,
+            0x9635_61d7,
+            0x0206_e1a7,
+            0x4055_0533,
+            0x0022_9293,
+            0x0055_85b3,
+            0x0056_0633,
+            0x0056_86b3,
+            0xfc05_1ae3,
+            0x0010_0073,
+        ];
+        program.resize(0x20, 0);
+        program.extend([1, 2, 3, 4, 5, 6, 0, 0]);
+        program.extend([10, 20, 30, 40, 50, 60]);
+        let mut vm = Vm::with_program_words(0x1000, &program).with_vector(128);
+
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        let c: Vec<u32> = (0..6)
+            .map(|index| vm.memory.read_u32(0x10c0 + 4 * index).unwrap())
+            .collect();
+        // the first strip has 4 elements and n = 6, the second 2 and n = 2
+        assert_eq!(c, [66, 132, 198, 264, 110, 132]);
+        assert_eq!(vm.read_csr(super::CSR_VLENB), Some(16));
+    }
+
+    #[test]
+    fn should_decode_and_print_the_vector_instructions() {
+        let text = |word| VectorInstruction::decode(word).unwrap().to_string();
+        assert_eq!(text(0x0d05_72d7), "vsetvli t0, a0, e32, m1, ta, ma");
+        assert_eq!(text(0xcd02_72d7), "vsetivli t0, 4, e32, m1, ta, ma");
+        assert_eq!(text(0x0205_e087), "vle32.v v1, (a1)");
+        assert_eq!(text(0x0020_8157), "vadd.vv v2, v2, v1, v0.t");
+        assert_eq!(text(0x022f_b1d7), "vadd.vi v3, v2, -1");
+        assert_eq!(text(0x0a20_c1d7), "vsub.vx v3, v2, ra");
+        assert_eq!(VectorInstruction::decode(0x0000_2007), None);
+    }
+
+    #[test]
+    fn vector_instructions_should_be_illegal_without_a_vector_unit() {
+        // 0x1000 vsetivli t0, 4, e32, m1, ta, ma
+        let program = 0xcd02_72d7u32.to_le_bytes();
+        let mut vm = Vm::new(0x1000, 0x100);
+        vm.load_program(0x1000, &program).unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(VmError::IllegalInstruction {
+                pc: 0x1000,
+                instruction: 0xcd02_72d7,
+            })
+        );
+    }
+}
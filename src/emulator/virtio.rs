@@ -0,0 +1,305 @@
+//! The virtio MMIO transport (version 2) with split virtqueues. It keeps the
+//! registers every virtio device has and walks the queues; what a request
+//! means is up to the `VirtioDevice` behind it, see `virtio_blk.rs`.
+//!
+//! The queue addresses are 64 bit, the high halves are ignored since the
+//! memory is 32 bit. The interrupt is up while `InterruptStatus` has a bit
+//! the driver did not acknowledge, every used-ring update sets one; put the
+//! transport on the bus with `Vm::add_device_with_irq` to get it to the
+//! PLIC, or poll `InterruptStatus`. A queue the guest set up wrong marks the
+//! device as needing a reset. Devices that get input on their own, like a
+//! network card, get to fill buffers when the host calls `Vm::poll_devices`.
+
+use super::error::VmError;
+use super::memory::Memory;
+use super::mmio::Device;
+use super::prelude::*;
+
+/// the first virtio device on QEMU's `virt` machine, the next ones follow
+/// every `VIRTIO_SIZE` bytes
+pub const VIRTIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_SIZE: u32 = 0x1000;
+/// the PLIC line of the first virtio device on `virt`, the next ones have
+/// the lines after it
+pub const VIRTIO_IRQ: u32 = 1;
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// "web" in ASCII
+const VENDOR_ID: u32 = 0x0062_6577;
+/// how many descriptors a queue can have
+const QUEUE_NUM_MAX: u32 = 256;
+
+const REG_MAGIC: u32 = 0x000;
+const REG_VERSION: u32 = 0x004;
+const REG_DEVICE_ID: u32 = 0x008;
+const REG_VENDOR_ID: u32 = 0x00c;
+const REG_DEVICE_FEATURES: u32 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u32 = 0x014;
+const REG_DRIVER_FEATURES: u32 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u32 = 0x024;
+const REG_QUEUE_SEL: u32 = 0x030;
+const REG_QUEUE_NUM_MAX: u32 = 0x034;
+const REG_QUEUE_NUM: u32 = 0x038;
+const REG_QUEUE_READY: u32 = 0x044;
+const REG_QUEUE_NOTIFY: u32 = 0x050;
+const REG_INTERRUPT_STATUS: u32 = 0x060;
+const REG_INTERRUPT_ACK: u32 = 0x064;
+const REG_STATUS: u32 = 0x070;
+const REG_QUEUE_DESC: u32 = 0x080;
+const REG_QUEUE_DRIVER: u32 = 0x090;
+const REG_QUEUE_DEVICE: u32 = 0x0a0;
+const REG_CONFIG_GENERATION: u32 = 0x0fc;
+const REG_CONFIG: u32 = 0x100;
+
+/// the device follows the virtio 1.0 spec, not the legacy one
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_NEEDS_RESET: u32 = 0x40;
+/// the device used a buffer
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+const DESCRIPTOR_SIZE: u32 = 16;
+const DESCRIPTOR_F_NEXT: u16 = 1;
+const DESCRIPTOR_F_WRITE: u16 = 2;
+
+/// one buffer of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub address: u32,
+    pub length: u32,
+    /// the device writes it, otherwise it reads it
+    pub writable: bool,
+}
+
+/// what is behind the transport: a block device, a network card
+pub trait VirtioDevice {
+    /// 2 for a block device, 1 for a network card
+    fn device_id(&self) -> u32;
+    /// the device specific feature bits, `VIRTIO_F_VERSION_1` is added
+    fn features(&self) -> u64;
+    /// the byte at `offset` in the configuration space
+    fn config(&self, offset: u32) -> u8;
+    /// handles the request in the descriptor chain from `queue`, returns
+    /// how many bytes it wrote into the writable buffers
+    fn request(
+        &mut self,
+        queue: u32,
+        chain: &[Descriptor],
+        memory: &mut Memory,
+    ) -> Result<u32, VmError>;
+    /// whether the device has something for a buffer of `queue`, a network
+    /// card leaves its receive buffers until a packet comes in
+    fn wants_buffer(&mut self, _queue: u32) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    /// the descriptor table, the available ring and the used ring
+    descriptors: u32,
+    driver: u32,
+    device: u32,
+    /// the next entry of the available ring to handle
+    next_available: u16,
+}
+
+/// a virtio device behind the MMIO registers, put it on the bus with
+/// `Vm::add_device`
+#[derive(Debug, Clone)]
+pub struct Virtio<D> {
+    pub device: D,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+}
+
+impl<D: VirtioDevice> Virtio<D> {
+    /// the transport for `device` with `queues` virtqueues
+    pub fn new(device: D, queues: usize) -> Self {
+        Self {
+            device,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Queue::default(); queues],
+            interrupt_status: 0,
+        }
+    }
+
+    /// the features the driver accepted
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = 0;
+        self.interrupt_status = 0;
+        for queue in &mut self.queues {
+            *queue = Queue::default();
+        }
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// handles everything new in the available ring of `queue`, returns
+    /// whether it used a buffer
+    fn notify(&mut self, queue: u32, memory: &mut Memory) -> Result<bool, VmError> {
+        let Some(state) = self.queues.get(queue as usize).filter(|queue| queue.ready) else {
+            return Ok(false);
+        };
+        let state = state.clone();
+        let mut next_available = state.next_available;
+
+        while next_available != memory.read_u16(state.driver + 2)?
+            && self.device.wants_buffer(queue)
+        {
+            let slot = u32::from(next_available) % state.num;
+            let head = memory.read_u16(state.driver + 4 + 2 * slot)?;
+            let chain = descriptor_chain(&state, head, memory)?;
+            let written = self.device.request(queue, &chain, memory)?;
+
+            let used = memory.read_u16(state.device + 2)?;
+            let entry = state.device + 4 + 8 * (u32::from(used) % state.num);
+            memory.write_u32(entry, head.into())?;
+            memory.write_u32(entry + 4, written)?;
+            memory.write_u16(state.device + 2, used.wrapping_add(1))?;
+            next_available = next_available.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+
+        self.queues[queue as usize].next_available = next_available;
+        Ok(next_available != state.next_available)
+    }
+}
+
+/// the descriptors from `head` on, following the next links
+fn descriptor_chain(queue: &Queue, head: u16, memory: &Memory) -> Result<Vec<Descriptor>, VmError> {
+    let mut chain = Vec::new();
+    let mut index = u32::from(head);
+    loop {
+        // a loop in the links would never end
+        if index >= queue.num || chain.len() as u32 >= queue.num {
+            return Err(VmError::MemoryOutOfBounds {
+                pc: 0,
+                instruction: 0,
+                address: queue.descriptors + index * DESCRIPTOR_SIZE,
+            });
+        }
+        let address = queue.descriptors + index * DESCRIPTOR_SIZE;
+        let flags = memory.read_u16(address + 12)?;
+        chain.push(Descriptor {
+            address: memory.read_u32(address)?,
+            length: memory.read_u32(address + 8)?,
+            writable: flags & DESCRIPTOR_F_WRITE != 0,
+        });
+        if flags & DESCRIPTOR_F_NEXT == 0 {
+            return Ok(chain);
+        }
+        index = memory.read_u16(address + 14)?.into();
+    }
+}
+
+impl<D: VirtioDevice + 'static> Device for Virtio<D> {
+    fn name(&self) -> &'static str {
+        "virtio"
+    }
+
+    fn read(&mut self, offset: u32, _size: u32, _memory: &mut Memory) -> u32 {
+        let features = self.device.features() | VIRTIO_F_VERSION_1;
+        match offset {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => features as u32,
+                1 => (features >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => match self.queue() {
+                Some(_) => QUEUE_NUM_MAX,
+                None => 0,
+            },
+            REG_QUEUE_READY => self.queue().is_some_and(|queue| queue.ready).into(),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => 0,
+            // the configuration space, little endian
+            REG_CONFIG.. => (0..4).fold(0, |value, byte| {
+                value | u32::from(self.device.config(offset - REG_CONFIG + byte)) << (8 * byte)
+            }),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32, memory: &mut Memory) -> bool {
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES => {
+                let shift = 32 * self.driver_features_sel.min(1);
+                self.driver_features &= !(u64::from(u32::MAX) << shift);
+                self.driver_features |= u64::from(value) << shift;
+            }
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    queue.num = value.min(QUEUE_NUM_MAX);
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value & 1 != 0 && queue.num > 0;
+                }
+            }
+            REG_QUEUE_DESC | REG_QUEUE_DRIVER | REG_QUEUE_DEVICE => {
+                if let Some(queue) = self.queue() {
+                    match offset {
+                        REG_QUEUE_DESC => queue.descriptors = value,
+                        REG_QUEUE_DRIVER => queue.driver = value,
+                        _ => queue.device = value,
+                    }
+                }
+            }
+            REG_QUEUE_NOTIFY => {
+                if self.notify(value, memory).is_err() {
+                    self.status |= STATUS_NEEDS_RESET;
+                }
+                return true;
+            }
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS if value == 0 => self.reset(),
+            REG_STATUS => self.status = value,
+            _ => {}
+        }
+        false
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn poll(&mut self, memory: &mut Memory) -> bool {
+        let mut used = false;
+        for queue in 0..self.queues.len() as u32 {
+            match self.notify(queue, memory) {
+                Ok(used_buffer) => used |= used_buffer,
+                Err(_) => self.status |= STATUS_NEEDS_RESET,
+            }
+        }
+        used
+    }
+}
@@ -0,0 +1,313 @@
+//! A virtio block device, so a guest kernel can mount a disk image. The disk
+//! is a `BlockStorage`: a `DiskImage` in memory (what the web build uses,
+//! filled from an ArrayBuffer) or a file on the host.
+//!
+//! A request is a header (type, sector), the data buffers and a status byte
+//! the device writes. Reads, writes, flushes and the device id are
+//! supported.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::disk_image::{DiskImage, SECTOR_SIZE};
+use super::error::VmError;
+use super::memory::Memory;
+use super::virtio::{Descriptor, Virtio, VirtioDevice};
+
+const DEVICE_ID: u32 = 2;
+/// the disk can not be written
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// type, reserved and sector
+const HEADER_SIZE: u32 = 16;
+/// the id `VIRTIO_BLK_T_GET_ID` returns, at most 20 bytes
+const DEVICE_SERIAL: &[u8] = b"web-riscv-vm";
+
+/// where the bytes of the disk are
+pub trait BlockStorage {
+    /// the size in bytes
+    fn size(&self) -> u64;
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BlockStorage for DiskImage {
+    fn size(&self) -> u64 {
+        self.as_bytes().len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let bytes = self
+            .as_bytes()
+            .get(offset as usize..offset as usize + buffer.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let first = offset as usize / SECTOR_SIZE;
+        let end = offset as usize + data.len();
+        if end > self.as_bytes().len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // the image only hands out sectors
+        let mut written = 0;
+        for sector in first..end.div_ceil(SECTOR_SIZE) {
+            let bytes = self
+                .sector_mut(sector)
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            let start = (offset as usize + written) - sector * SECTOR_SIZE;
+            let length = (SECTOR_SIZE - start).min(data.len() - written);
+            bytes[start..start + length].copy_from_slice(&data[written..written + length]);
+            written += length;
+        }
+        Ok(())
+    }
+}
+
+impl BlockStorage for File {
+    fn size(&self) -> u64 {
+        self.metadata().map_or(0, |metadata| metadata.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buffer)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// the disk behind a virtio transport
+pub struct VirtioBlock {
+    pub storage: Box<dyn BlockStorage>,
+    pub read_only: bool,
+}
+
+impl Virtio<VirtioBlock> {
+    /// a block device on its transport, ready for `Vm::add_device`
+    pub fn block(storage: Box<dyn BlockStorage>) -> Self {
+        Virtio::new(
+            VirtioBlock {
+                storage,
+                read_only: false,
+            },
+            1,
+        )
+    }
+}
+
+impl VirtioBlock {
+    /// the size in sectors of 512 bytes
+    pub fn capacity(&self) -> u64 {
+        self.storage.size() / SECTOR_SIZE as u64
+    }
+
+    /// moves the data between the disk and the buffers, returns the status
+    /// and how many bytes went into the buffers
+    fn transfer(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        buffers: &[Descriptor],
+        memory: &mut Memory,
+    ) -> Result<(u8, u32), VmError> {
+        let mut offset = sector * SECTOR_SIZE as u64;
+        let mut written = 0;
+        for buffer in buffers {
+            let ok = match kind {
+                VIRTIO_BLK_T_IN if buffer.writable => {
+                    let mut data = vec![0; buffer.length as usize];
+                    let ok = self.storage.read_at(offset, &mut data).is_ok();
+                    memory.load(buffer.address, &data)?;
+                    written += buffer.length;
+                    ok
+                }
+                VIRTIO_BLK_T_OUT if !buffer.writable && !self.read_only => {
+                    let data = memory.read_bytes(buffer.address, buffer.length as usize)?;
+                    self.storage.write_at(offset, data).is_ok()
+                }
+                _ => false,
+            };
+            if !ok {
+                return Ok((VIRTIO_BLK_S_IOERR, written));
+            }
+            offset += u64::from(buffer.length);
+        }
+        Ok((VIRTIO_BLK_S_OK, written))
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        let read_only = if self.read_only { VIRTIO_BLK_F_RO } else { 0 };
+        VIRTIO_BLK_F_FLUSH | read_only
+    }
+
+    /// the capacity in sectors, the rest of the configuration is zero
+    fn config(&self, offset: u32) -> u8 {
+        match offset {
+            0..8 => self.capacity().to_le_bytes()[offset as usize],
+            _ => 0,
+        }
+    }
+
+    fn request(
+        &mut self,
+        _queue: u32,
+        chain: &[Descriptor],
+        memory: &mut Memory,
+    ) -> Result<u32, VmError> {
+        let (Some(header), Some(status)) = (chain.first(), chain.last()) else {
+            return Ok(0);
+        };
+        if chain.len() < 2 || header.length < HEADER_SIZE || !status.writable {
+            return Ok(0);
+        }
+        let kind = memory.read_u32(header.address)?;
+        let sector = u64::from(memory.read_u32(header.address + 8)?)
+            | u64::from(memory.read_u32(header.address + 12)?) << 32;
+        let buffers = &chain[1..chain.len() - 1];
+
+        let (result, written) = match kind {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => self.transfer(kind, sector, buffers, memory)?,
+            VIRTIO_BLK_T_FLUSH => match self.storage.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => match buffers.first() {
+                Some(buffer) if buffer.writable => {
+                    let length = DEVICE_SERIAL.len().min(buffer.length as usize);
+                    memory.load(buffer.address, &DEVICE_SERIAL[..length])?;
+                    (VIRTIO_BLK_S_OK, length as u32)
+                }
+                _ => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        memory.write_u8(status.address, result)?;
+        Ok(written + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DEVICE_ID;
+    use crate::disk_image::DiskImage;
+    use crate::memory::Memory;
+    use crate::mmio::Device;
+    use crate::virtio::Virtio;
+
+    const DESCRIPTORS: u32 = 0x1000;
+    const AVAILABLE: u32 = 0x1100;
+    const USED: u32 = 0x1200;
+    const HEADER: u32 = 0x1300;
+    const DATA: u32 = 0x1400;
+    const STATUS: u32 = 0x1800;
+
+    /// sets up queue 0 with 8 descriptors the way a driver does
+    fn set_up(device: &mut dyn Device, memory: &mut Memory) {
+        for (offset, value) in [
+            (0x070, 1 | 2 | 8),
+            (0x030, 0),
+            (0x038, 8),
+            (0x080, DESCRIPTORS),
+            (0x090, AVAILABLE),
+            (0x0a0, USED),
+            (0x044, 1),
+            (0x070, 1 | 2 | 8 | 4),
+        ] {
+            device.write(offset, 4, value, memory);
+        }
+    }
+
+    /// the header, a data buffer of `length` bytes and the status, as
+    /// request `index` in the available ring
+    fn submit(device: &mut dyn Device, memory: &mut Memory, kind: u32, sector: u32, index: u16) {
+        memory.write_u32(HEADER, kind).unwrap();
+        memory.write_u32(HEADER + 8, sector).unwrap();
+        let data_flags = if kind == 0 { 1 | 2 } else { 1 };
+        for (descriptor, (address, length, flags, next)) in [
+            (HEADER, 16, 1, 1),
+            (DATA, 512, data_flags, 2),
+            (STATUS, 1, 2, 0),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let entry = DESCRIPTORS + 16 * descriptor as u32;
+            memory.write_u32(entry, address).unwrap();
+            memory.write_u32(entry + 8, length).unwrap();
+            memory.write_u16(entry + 12, flags).unwrap();
+            memory.write_u16(entry + 14, next).unwrap();
+        }
+        memory
+            .write_u16(AVAILABLE + 4 + 2 * u32::from(index % 8), 0)
+            .unwrap();
+        memory.write_u16(AVAILABLE + 2, index + 1).unwrap();
+        assert!(device.write(0x050, 4, 0, memory));
+    }
+
+    #[test]
+    fn should_read_and_write_sectors_of_the_disk() {
+        let mut image = DiskImage::new(4 * 512).unwrap();
+        image.sector_mut(2).unwrap()[..5].copy_from_slice(b"hello");
+        let mut device = Virtio::block(Box::new(image));
+        let mut memory = Memory::new(0x1000, 0x1000);
+
+        assert_eq!(device.read(0x000, 4, &mut memory), 0x7472_6976);
+        assert_eq!(device.read(0x008, 4, &mut memory), DEVICE_ID);
+        // the capacity in sectors
+        assert_eq!(device.read(0x100, 4, &mut memory), 4);
+        set_up(&mut device, &mut memory);
+
+        submit(&mut device, &mut memory, 0, 2, 0);
+        assert_eq!(memory.read_bytes(DATA, 5).unwrap(), b"hello");
+        assert_eq!(memory.read_u8(STATUS).unwrap(), 0);
+        // the used ring has the head and the 513 bytes written
+        assert_eq!(memory.read_u16(USED + 2).unwrap(), 1);
+        assert_eq!(memory.read_u32(USED + 4).unwrap(), 0);
+        assert_eq!(memory.read_u32(USED + 8).unwrap(), 513);
+        assert_eq!(device.read(0x060, 4, &mut memory), 1);
+        // the line stays up until the driver acknowledges the used buffer
+        assert!(device.interrupt());
+        device.write(0x064, 4, 1, &mut memory);
+        assert!(!device.interrupt());
+
+        memory.load(DATA, b"world").unwrap();
+        submit(&mut device, &mut memory, 1, 3, 1);
+        assert_eq!(memory.read_u8(STATUS).unwrap(), 0);
+        let mut sector = [0; 5];
+        device.device.storage.read_at(3 * 512, &mut sector).unwrap();
+        assert_eq!(&sector, b"world");
+
+        // past the end of the disk
+        submit(&mut device, &mut memory, 0, 4, 2);
+        assert_eq!(memory.read_u8(STATUS).unwrap(), 1);
+    }
+}
@@ -0,0 +1,201 @@
+//! A virtio network card. Queue 0 receives and queue 1 transmits, every
+//! buffer starts with the 12 byte `virtio_net_hdr` before the Ethernet
+//! frame. The frames go to a `NetBackend`: the user-mode NAT of `net.rs`, or
+//! a `FrameQueue` the web build bridges to a WebSocket.
+//!
+//! Frames for the guest wait in the backend until it has put a receive
+//! buffer in the queue and the host calls `Vm::poll_devices`. No offloads
+//! are offered, so the header is all zeros except for `num_buffers`.
+
+use super::error::VmError;
+use super::memory::Memory;
+use super::net::NetBackend;
+use super::prelude::*;
+use super::virtio::{Descriptor, Virtio, VirtioDevice};
+
+const DEVICE_ID: u32 = 1;
+/// the configuration has the MAC address
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// flags, gso type, header length, gso size, checksum start, checksum
+/// offset and the number of buffers
+const HEADER_SIZE: usize = 12;
+/// the locally administered address QEMU gives its first card
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// the network card behind a virtio transport
+pub struct VirtioNet {
+    pub backend: Box<dyn NetBackend>,
+    pub mac: [u8; 6],
+    /// the frame the next receive buffer gets
+    pending: Option<Vec<u8>>,
+}
+
+impl Virtio<VirtioNet> {
+    /// a network card on its transport, ready for `Vm::add_device`
+    pub fn net(backend: Box<dyn NetBackend>) -> Self {
+        Virtio::new(
+            VirtioNet {
+                backend,
+                mac: DEFAULT_MAC,
+                pending: None,
+            },
+            2,
+        )
+    }
+}
+
+impl VirtioNet {
+    /// puts the header and `frame` into the writable buffers, as much as
+    /// fits, returns how many bytes went in
+    fn fill(chain: &[Descriptor], frame: &[u8], memory: &mut Memory) -> Result<u32, VmError> {
+        let mut header = [0; HEADER_SIZE];
+        // num_buffers
+        header[10] = 1;
+        let packet = [&header[..], frame].concat();
+
+        let mut written = 0;
+        for buffer in chain.iter().filter(|buffer| buffer.writable) {
+            let length = (buffer.length as usize).min(packet.len() - written);
+            memory.load(buffer.address, &packet[written..written + length])?;
+            written += length;
+        }
+        Ok(written as u32)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    /// the MAC address, the status and the rest are zero
+    fn config(&self, offset: u32) -> u8 {
+        match offset {
+            0..6 => self.mac[offset as usize],
+            _ => 0,
+        }
+    }
+
+    fn request(
+        &mut self,
+        queue: u32,
+        chain: &[Descriptor],
+        memory: &mut Memory,
+    ) -> Result<u32, VmError> {
+        match queue {
+            RECEIVE_QUEUE => match self.pending.take() {
+                Some(frame) => Self::fill(chain, &frame, memory),
+                None => Ok(0),
+            },
+            TRANSMIT_QUEUE => {
+                let mut packet = Vec::new();
+                for buffer in chain.iter().filter(|buffer| !buffer.writable) {
+                    let data = memory.read_bytes(buffer.address, buffer.length as usize)?;
+                    packet.extend_from_slice(data);
+                }
+                if packet.len() > HEADER_SIZE {
+                    self.backend.send(&packet[HEADER_SIZE..]);
+                }
+                Ok(0)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn wants_buffer(&mut self, queue: u32) -> bool {
+        if queue != RECEIVE_QUEUE {
+            return true;
+        }
+        if self.pending.is_none() {
+            self.pending = self.backend.receive();
+        }
+        self.pending.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VirtioNet, DEFAULT_MAC, DEVICE_ID, HEADER_SIZE};
+    use crate::memory::Memory;
+    use crate::mmio::Device;
+    use crate::net::FrameQueue;
+    use crate::virtio::Virtio;
+
+    const RECEIVE: u32 = 0x1000;
+    const TRANSMIT: u32 = 0x1400;
+    const BUFFER: u32 = 0x1800;
+
+    /// sets up both queues with 4 descriptors, each table, available ring
+    /// and used ring 0x100 bytes apart
+    fn set_up(device: &mut dyn Device, memory: &mut Memory) {
+        device.write(0x070, 4, 1 | 2 | 8, memory);
+        for (queue, base) in [(0, RECEIVE), (1, TRANSMIT)] {
+            for (offset, value) in [
+                (0x030, queue),
+                (0x038, 4),
+                (0x080, base),
+                (0x090, base + 0x100),
+                (0x0a0, base + 0x200),
+                (0x044, 1),
+            ] {
+                device.write(offset, 4, value, memory);
+            }
+        }
+        device.write(0x070, 4, 1 | 2 | 8 | 4, memory);
+    }
+
+    /// makes `address` the only buffer of request `index` in the queue at
+    /// `base`
+    fn offer(memory: &mut Memory, base: u32, address: u32, length: u32, flags: u16, index: u16) {
+        memory.write_u32(base, address).unwrap();
+        memory.write_u32(base + 8, length).unwrap();
+        memory.write_u16(base + 12, flags).unwrap();
+        memory
+            .write_u16(base + 0x104 + 2 * u32::from(index % 4), 0)
+            .unwrap();
+        memory.write_u16(base + 0x102, index + 1).unwrap();
+    }
+
+    fn backend(device: &mut Virtio<VirtioNet>) -> &mut FrameQueue {
+        let backend: &mut dyn std::any::Any = &mut *device.device.backend;
+        backend.downcast_mut().unwrap()
+    }
+
+    #[test]
+    fn should_move_frames_between_the_guest_and_the_backend() {
+        let mut device = Virtio::net(Box::new(FrameQueue::default()));
+        let mut memory = Memory::new(0x1000, 0x1000);
+        assert_eq!(device.read(0x008, 4, &mut memory), DEVICE_ID);
+        assert_eq!(device.read(0x100, 4, &mut memory), 0x1200_5452);
+        assert_eq!(device.device.mac, DEFAULT_MAC);
+        set_up(&mut device, &mut memory);
+
+        // transmit: the header, then the frame
+        memory.load(BUFFER + HEADER_SIZE as u32, b"frame").unwrap();
+        offer(&mut memory, TRANSMIT, BUFFER, 12 + 5, 0, 0);
+        device.write(0x050, 4, 1, &mut memory);
+        assert_eq!(backend(&mut device).outgoing.pop_front().unwrap(), b"frame");
+        assert_eq!(memory.read_u16(TRANSMIT + 0x202).unwrap(), 1);
+
+        // a receive buffer waits until there is a frame for it
+        offer(&mut memory, RECEIVE, BUFFER, 64, 2, 0);
+        device.write(0x050, 4, 0, &mut memory);
+        assert!(!device.poll(&mut memory));
+        assert_eq!(memory.read_u16(RECEIVE + 0x202).unwrap(), 0);
+
+        backend(&mut device).incoming.push_back(b"reply".to_vec());
+        assert!(device.poll(&mut memory));
+        assert_eq!(memory.read_u16(RECEIVE + 0x202).unwrap(), 1);
+        assert_eq!(memory.read_u32(RECEIVE + 0x208).unwrap(), 12 + 5);
+        assert_eq!(memory.read_u16(BUFFER + 10).unwrap(), 1);
+        assert_eq!(memory.read_bytes(BUFFER + 12, 5).unwrap(), b"reply");
+    }
+}
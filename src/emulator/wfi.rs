@@ -0,0 +1,140 @@
+//! `wfi` and what the vm does while the guest waits for an interrupt. An
+//! idle loop of an interrupt-driven guest is `wfi` in a loop; run as a nop
+//! it spins through millions of instructions until the timer fires. With
+//! the default `WfiPolicy::Sleep` a `wfi` with no interrupt pending (in
+//! `mip` and enabled in `mie`, whether `mstatus.MIE` is set or not) moves
+//! the time on instead:
+//!
+//! - with the cycles or the manual clock the time is the vm's own, so it
+//!   jumps to the next CLINT or SBI timer, see `fast_forward_to_timer`
+//! - with the host clock the thread sleeps until the timer is due, at most
+//!   `MAX_SLEEP` at a time so that interrupts raised from other threads
+//!   are not late for long
+//! - on wasm the thread can not sleep, the run stops with `Waiting` like
+//!   it does with `WfiPolicy::Yield`, and `run_async` awaits the host's
+//!   `yield_now` before it goes on
+//!
+//! The wait is over after one `wfi` either way: the spec allows a `wfi`
+//! to return early, the guest's loop checks and waits again.
+
+use core::time::Duration;
+
+use super::clint::ClockSource;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use super::clint::TIMEBASE_FREQUENCY;
+use super::emulator::{StopReason, Vm};
+
+/// the longest a single `wfi` sleeps on the host
+pub const MAX_SLEEP: Duration = Duration::from_millis(10);
+
+/// what a `wfi` does when no interrupt is pending
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WfiPolicy {
+    /// nothing, the guest spins
+    Spin,
+    /// fast-forwards the vm's clock or sleeps until the next timer
+    #[default]
+    Sleep,
+    /// fast-forwards the vm's clock, otherwise stops the run with
+    /// `Waiting` so the host can do something else
+    Yield,
+}
+
+impl Vm {
+    pub fn with_wfi_policy(mut self, policy: WfiPolicy) -> Self {
+        self.wfi_policy = policy;
+        self
+    }
+
+    /// executes `wfi`, the next instruction runs once the wait is over
+    pub(super) fn wfi(&mut self) {
+        self.vm_state.pc = self.vm_state.pc.wrapping_add(4);
+        if self.wfi_policy == WfiPolicy::Spin || self.pending_interrupts() != 0 {
+            return;
+        }
+        if self.clock.source != ClockSource::Host && self.fast_forward_to_timer() {
+            return;
+        }
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if self.wfi_policy == WfiPolicy::Sleep {
+            std::thread::sleep(self.time_to_timer().min(MAX_SLEEP));
+            self.tick_timers();
+            return;
+        }
+        let pc = self.vm_state.pc as u32;
+        self.stop = Some(StopReason::Waiting { pc });
+    }
+
+    /// the host time until the next timer of the current hart is due
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    fn time_to_timer(&self) -> Duration {
+        let hart = self.csrs.mhartid as usize;
+        let clint = self.clint.as_ref().map(|clint| clint.mtimecmp(hart));
+        let sbi = self.sbi.as_ref().and_then(|sbi| sbi.timer);
+        let next_timer = clint.into_iter().chain(sbi).min().unwrap_or(u64::MAX);
+        let ticks = next_timer.saturating_sub(self.time());
+        let nanoseconds = u128::from(ticks) * 1_000_000_000 / u128::from(TIMEBASE_FREQUENCY);
+        Duration::from_nanos(nanoseconds.try_into().unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WfiPolicy;
+    use crate::csr::MIP_MTIP;
+    use crate::{StopReason, Vm};
+
+    #[test]
+    fn should_wait_for_the_timer_instead_of_spinning() {
+        // 0x1000 lui t0, 0x2004        mtimecmp of hart 0
+        // 0x1004 lui t1, 0x1
+        // 0x1008 sw t1, 0(t0)          <- 4096
+        // 0x100c sw zero, 4(t0)
+        // 0x1010 addi t0, zero, 0x80
+        // 0x1014 csrrs zero, mie, t0   <- MTIE
+        // 0x1018 wfi
+        // 0x101c csrrs a0, mip, zero
+        // 0x1020 ebreak
+        let program: Vec<u8> = [
+            0x0200_42b7u32,
+            0x0000_1337,
+            0x0062_a023,
+            0x0002_a223,
+            0x0800_0293,
+            0x3042_a073,
+            0x1050_0073,
+            0x3440_2573,
+            0x0010_0073,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        let new_vm = || {
+            let mut vm = Vm::new(0x1000, 0x100).with_clint();
+            vm.load_program(0x1000, &program).unwrap();
+            vm
+        };
+
+        // the cycles clock jumps to the timer, with or without sleeping
+        for policy in [WfiPolicy::Sleep, WfiPolicy::Yield] {
+            let mut vm = new_vm().with_wfi_policy(policy);
+            assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+            assert_eq!(vm.stats.instructions_retired, 8);
+            assert!(vm.time() >= 4096);
+            assert_eq!(vm.vm_state.registers.as_array()[10] as u32, MIP_MTIP);
+        }
+
+        // a spinning wfi is a nop
+        let mut vm = new_vm().with_wfi_policy(WfiPolicy::Spin);
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+        assert_eq!(vm.vm_state.registers.as_array()[10], 0);
+
+        // without a timer there is nothing to fast-forward to, the host
+        // gets the run back
+        let mut vm = new_vm().with_wfi_policy(WfiPolicy::Yield);
+        vm.memory.write_u32(0x1008, 0x0000_0013).unwrap();
+        vm.memory.write_u32(0x100c, 0x0000_0013).unwrap();
+        assert_eq!(vm.run(), Ok(StopReason::Waiting { pc: 0x101c }));
+        assert_eq!(vm.run(), Ok(StopReason::Ebreak));
+    }
+}
@@ -0,0 +1,301 @@
+//! The messages between a page and a vm that runs in a Web Worker, so long
+//! runs never block the UI thread. The page posts `WorkerCommand`s, the
+//! worker answers each with a list of `WorkerEvent`s. Both are JSON objects
+//! with a `command`/`event` field for their kind, the byte arrays are arrays
+//! of numbers (`Array.from(bytes)`, not the `Uint8Array` itself):
+//!
+//! ```json
+//! {"command":"load","elf":[127,69,76,70,...],"args":["program"]}
+//! {"command":"run"}
+//! {"command":"memory","address":4096,"length":16}
+//! {"event":"stopped","pc":4104,"reason":"Ebreak"}
+//! {"event":"output","text":"hello\n"}
+//! ```
+//!
+//! `run` only starts the vm: the worker calls `tick` from a `setTimeout`
+//! while `is_running`, every tick is one wall-clock slice of
+//! `run_for_time`. In between it gets the next messages, so `pause` and
+//! the others are answered during a run too.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::elf::Elf;
+use super::emulator::{StopReason, Vm};
+use super::process::{user_memory_map, DEFAULT_STACK_SIZE, DEFAULT_STACK_TOP};
+
+/// how long one `tick` runs the vm
+const SLICE: Duration = Duration::from_millis(20);
+
+/// page to worker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum WorkerCommand {
+    /// a new vm with the user program `elf` and `args` on its stack, like
+    /// `riscv-vm run`
+    Load {
+        elf: Vec<u8>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// runs one instruction
+    Step,
+    /// runs until the guest stops or `pause`
+    Run,
+    Pause,
+    Registers,
+    Memory {
+        address: u32,
+        length: u32,
+    },
+    Break {
+        address: u32,
+    },
+    Delete {
+        address: u32,
+    },
+}
+
+/// worker to page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WorkerEvent {
+    Loaded {
+        entry: u32,
+    },
+    /// the vm stopped before the instruction at `pc`, `reason` is the
+    /// `StopReason` (or `Step`/`Paused`) in its Debug format
+    Stopped {
+        pc: u32,
+        reason: String,
+    },
+    /// x0 to x31
+    Registers {
+        pc: u32,
+        registers: Vec<i32>,
+    },
+    Memory {
+        address: u32,
+        bytes: Vec<u8>,
+    },
+    /// what the guest wrote to stdout and stderr since the last event
+    Output {
+        text: String,
+    },
+    /// the command failed, or the vm did
+    Error {
+        message: String,
+    },
+}
+
+/// the vm of a worker and whether it is running
+pub struct VmWorker {
+    vm: Vm,
+    running: bool,
+}
+
+impl VmWorker {
+    /// an empty vm, `load` puts a program into it
+    pub fn new() -> Self {
+        Self {
+            vm: Vm::new(0, 0),
+            running: false,
+        }
+    }
+
+    /// whether the worker should call `tick`
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn handle(&mut self, command: WorkerCommand) -> Vec<WorkerEvent> {
+        let pc = self.vm.vm_state.pc as u32;
+        let event = match command {
+            WorkerCommand::Load { elf, args } => match load(&elf, &args) {
+                Ok((vm, entry)) => {
+                    self.vm = vm;
+                    self.running = false;
+                    WorkerEvent::Loaded { entry }
+                }
+                Err(message) => WorkerEvent::Error { message },
+            },
+            WorkerCommand::Step => {
+                let result = self.vm.step();
+                let mut events = self.take_output();
+                events.push(match result {
+                    Ok(()) => self.stopped("Step".to_string()),
+                    Err(error) => error_event(error),
+                });
+                return events;
+            }
+            WorkerCommand::Run => {
+                self.running = true;
+                return self.tick();
+            }
+            WorkerCommand::Pause => {
+                self.running = false;
+                self.stopped("Paused".to_string())
+            }
+            WorkerCommand::Registers => WorkerEvent::Registers {
+                pc,
+                registers: self.vm.vm_state.registers.as_array().to_vec(),
+            },
+            WorkerCommand::Memory { address, length } => {
+                match self.vm.memory.read_bytes(address, length as usize) {
+                    Ok(bytes) => WorkerEvent::Memory {
+                        address,
+                        bytes: bytes.to_vec(),
+                    },
+                    Err(error) => error_event(error),
+                }
+            }
+            WorkerCommand::Break { address } => {
+                self.vm.breakpoints.add_breakpoint(address);
+                return Vec::new();
+            }
+            WorkerCommand::Delete { address } => {
+                self.vm.breakpoints.remove_breakpoint(address);
+                return Vec::new();
+            }
+        };
+        vec![event]
+    }
+
+    /// runs one slice while running, `stopped` once the guest stops
+    pub fn tick(&mut self) -> Vec<WorkerEvent> {
+        if !self.running {
+            return Vec::new();
+        }
+        let result = self.vm.run_for_time(SLICE);
+        let mut events = self.take_output();
+        match result {
+            Ok(StopReason::Preempted { .. } | StopReason::Waiting { .. }) => {}
+            Ok(stop_reason) => {
+                self.running = false;
+                events.push(self.stopped(format!("{stop_reason:?}")));
+            }
+            Err(error) => {
+                self.running = false;
+                events.push(error_event(error));
+            }
+        }
+        events
+    }
+
+    /// `handle` for a command in JSON, the events come back as a JSON array
+    pub fn handle_json(&mut self, message: &str) -> String {
+        let events = match serde_json::from_str(message) {
+            Ok(command) => self.handle(command),
+            Err(error) => vec![error_event(error)],
+        };
+        to_json(&events)
+    }
+
+    /// `tick` with the events as a JSON array
+    pub fn tick_json(&mut self) -> String {
+        to_json(&self.tick())
+    }
+
+    fn stopped(&self, reason: String) -> WorkerEvent {
+        WorkerEvent::Stopped {
+            pc: self.vm.vm_state.pc as u32,
+            reason,
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<WorkerEvent> {
+        let Some(syscalls) = &mut self.vm.syscalls else {
+            return Vec::new();
+        };
+        let mut output = std::mem::take(&mut syscalls.stdout);
+        output.append(&mut syscalls.stderr);
+        if output.is_empty() {
+            return Vec::new();
+        }
+        let text = String::from_utf8_lossy(&output).into_owned();
+        vec![WorkerEvent::Output { text }]
+    }
+}
+
+impl Default for VmWorker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// a vm with the user program and its entry
+fn load(elf: &[u8], args: &[String]) -> Result<(Vm, u32), String> {
+    let map = user_memory_map(
+        &Elf::parse(elf).map_err(|error| error.to_string())?,
+        DEFAULT_STACK_TOP,
+        DEFAULT_STACK_SIZE,
+    );
+    let mut vm = Vm::from_memory_map(&map).with_syscalls();
+    let entry = vm.load_elf(elf).map_err(|error| error.to_string())?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    vm.push_arguments(DEFAULT_STACK_TOP, &args, &[], entry)
+        .map_err(|error| error.to_string())?;
+    Ok((vm, entry))
+}
+
+fn error_event(error: impl ToString) -> WorkerEvent {
+    WorkerEvent::Error {
+        message: error.to_string(),
+    }
+}
+
+fn to_json(events: &[WorkerEvent]) -> String {
+    serde_json::to_string(events).expect("the events are plain data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VmWorker, WorkerCommand, WorkerEvent};
+    use crate::elf::test_elf;
+
+    #[test]
+    fn should_run_a_program_with_commands() {
+        // 0x10000 addi a0, zero, 7
+        // 0x10004 ebreak
+        let elf = test_elf::build(0x10000, &[0x0070_0513, 0x0010_0073], 0, &[]);
+        let mut worker = VmWorker::new();
+        let load = WorkerCommand::Load {
+            elf,
+            args: vec!["program".to_string()],
+        };
+        assert_eq!(
+            worker.handle(load),
+            vec![WorkerEvent::Loaded { entry: 0x10000 }]
+        );
+
+        worker.handle(WorkerCommand::Break { address: 0x10004 });
+        assert_eq!(
+            worker.handle(WorkerCommand::Run),
+            vec![WorkerEvent::Stopped {
+                pc: 0x10004,
+                reason: "Breakpoint { pc: 65540 }".to_string(),
+            }]
+        );
+        assert!(!worker.is_running());
+        assert!(worker.tick().is_empty());
+
+        let registers = worker.handle(WorkerCommand::Registers);
+        let [WorkerEvent::Registers { pc, registers }] = registers.as_slice() else {
+            panic!("{registers:?}");
+        };
+        assert_eq!((*pc, registers[10]), (0x10004, 7));
+    }
+
+    #[test]
+    fn should_speak_json() {
+        let mut worker = VmWorker::new();
+        assert_eq!(
+            worker.handle_json(r#"{"command":"pause"}"#),
+            r#"[{"event":"stopped","pc":0,"reason":"Paused"}]"#
+        );
+        assert!(worker
+            .handle_json(r#"{"command":"jump"}"#)
+            .starts_with(r#"[{"event":"error","message":"unknown variant"#));
+    }
+}
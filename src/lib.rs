@@ -1,3 +1,41 @@
+//! A RISC-V emulator for RV32 guests. The default `std` feature has all of
+//! it; without it the core (decode, execute, memory, devices) is `no_std` +
+//! `alloc`, to embed it in a kernel or even in another guest. What needs an
+//! operating system (host files and sockets, stdin/stdout, the clock, the
+//! CLI) is left out then.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 mod emulator;
 
-pub use emulator::instruction_formats;
+#[cfg(feature = "batch")]
+pub use emulator::batch;
+#[cfg(feature = "differential")]
+pub use emulator::differential;
+#[cfg(feature = "jit")]
+pub use emulator::jit;
+#[cfg(feature = "symbolic")]
+pub use emulator::symbolic;
+#[cfg(feature = "std")]
+pub use emulator::trace;
+#[cfg(feature = "tui")]
+pub use emulator::tui;
+#[cfg(feature = "std")]
+pub use emulator::virtio_blk;
+#[cfg(feature = "worker")]
+pub use emulator::worker;
+pub use emulator::{
+    atomic, backing, block_cache, branch_predictor, breakpoints, cache, call_stack, clint,
+    control_flow, cooperative, coverage, csr, debug_line, decode_cache, decompile, disassemble,
+    disk_image, dispatch, dtb, ecall, elf, encode, events, extensions, flame_graph, fork,
+    framebuffer, fs, gas, hooks, hostcall, hpm, htif, image, input, instruction_formats,
+    instruction_signatures, isa, limits, memory, mmio, mmu, monitor, net, payload, pipeline, plic,
+    plugin, pod, process, profile, profiler, quiz, region, register, replay, sbi, semihosting, smp,
+    snapshot, stack_limit, strace, summary, syscalls, taint, terminal, timing, tlb, uart, uninit,
+    vector, virtio, virtio_net, wfi,
+};
+pub use emulator::{
+    AccessKind, Emulator, ExitReason, Instruction, PseudoInstruction, Register, RegisterFile,
+    Rv32iInstruction, StopReason, Vm, VmError, VmState,
+};
@@ -1,7 +1,483 @@
-mod emulator;
+use std::io::Write;
+use std::process::ExitCode;
 
-use emulator::Emulator;
+use riscv_emulator::disk_image::{DiskImage, Filesystem};
+use riscv_emulator::elf::{Elf, DEFAULT_LOAD_BIAS};
+use riscv_emulator::flame_graph::Weight;
+use riscv_emulator::fs::HostFs;
+use riscv_emulator::image::{Image, ImageFormat};
+use riscv_emulator::process::{
+    image_memory_map, user_memory_map, DEFAULT_STACK_SIZE, DEFAULT_STACK_TOP, STACK_GUARD_SIZE,
+};
+use riscv_emulator::stack_limit::StackLimit;
+use riscv_emulator::trace::{TraceFormat, TraceReader};
+use riscv_emulator::virtio::{Virtio, VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
+use riscv_emulator::{StopReason, Vm};
 
-fn main() {
-    let emulator = Emulator::new();
+const USAGE: &str = "\
+usage: riscv-vm run [--allow <directory>]... [--profile-out <file>] [--disk <image>]
+                    [--format elf|bin|ihex|srec] [--load-addr <address>] <program> [-- args...]
+       riscv-vm batch [--threads <n>] [--max-instructions <n>] [--max-time-ms <n>]
+                      <program.elf>... [-- args...]
+       riscv-vm trace [--trace-format binary|in_asm,cpu] <program.elf> -o <file|-> [-- args...]
+       riscv-vm trace-dump <trace.bin>
+       riscv-vm stats <program.elf> [-- args...]
+       riscv-vm debug <program.elf>
+       riscv-vm disk create [--fs fat|ext2] [--label <label>] <image> <size>
+       riscv-vm disk resize <image> <size>
+       riscv-vm disk put <image> <file> [<name>]
+       riscv-vm disk get <image> <name> [<file>]";
+/// how many instructions run between two flushes of the guest's output
+const SLICE: u64 = 100_000;
+
+/// how to read the program file: `--format` and `--load-addr`, where a
+/// flat binary or a position independent ELF file goes
+#[derive(Clone, Copy)]
+struct Format {
+    format: ImageFormat,
+    load_address: Option<u32>,
+}
+
+const ELF: Format = Format {
+    format: ImageFormat::Elf,
+    load_address: None,
+};
+
+/// `0x80000000` or `2147483648`
+fn parse_address(text: &str) -> Result<u32, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("{text:?} is not an address"))
+}
+
+/// `65536`, `64K`, `16M` or `1G`, in bytes
+fn parse_size(text: &str) -> Result<usize, String> {
+    let (number, unit) = match text.char_indices().last() {
+        Some((index, 'K')) => (&text[..index], 1 << 10),
+        Some((index, 'M')) => (&text[..index], 1 << 20),
+        Some((index, 'G')) => (&text[..index], 1 << 30),
+        _ => (text, 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("{text:?} is not a size"))
+}
+
+/// the program file as an image, `None` for ELF files
+fn parse_image(path: &str, bytes: &[u8], format: Format) -> Result<Option<Image>, String> {
+    let text = || std::str::from_utf8(bytes).map_err(|_| format!("{path}: not a text file"));
+    let image = match format.format {
+        ImageFormat::Elf => return Ok(None),
+        ImageFormat::Bin => {
+            let address = format
+                .load_address
+                .ok_or("--format bin needs --load-addr")?;
+            Ok(Image::from_bin(bytes, address))
+        }
+        ImageFormat::IntelHex => Image::parse_intel_hex(text()?),
+        ImageFormat::Srec => Image::parse_srec(text()?),
+    };
+    Ok(Some(image.map_err(|error| format!("{path}: {error}"))?))
+}
+
+/// a vm with the program loaded and `args` on its stack, the program path
+/// as argv[0]. It may open the files in the `allowed` directories
+fn load(path: &str, args: &[String], allowed: &[&String], format: Format) -> Result<Vm, String> {
+    let bytes = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+    let image = parse_image(path, &bytes, format)?;
+    let load_bias = format.load_address.unwrap_or(DEFAULT_LOAD_BIAS);
+    let map = match &image {
+        Some(image) => image_memory_map(image, DEFAULT_STACK_TOP, DEFAULT_STACK_SIZE),
+        None => {
+            let elf =
+                Elf::parse_with_load_bias(&bytes, load_bias).map_err(|error| error.to_string())?;
+            user_memory_map(&elf, DEFAULT_STACK_TOP, DEFAULT_STACK_SIZE)
+        }
+    };
+
+    let mut fs = HostFs::new();
+    for directory in allowed {
+        fs = fs
+            .allow(directory)
+            .map_err(|error| format!("{directory}: {error}"))?;
+    }
+    let stack_limit = StackLimit::new(DEFAULT_STACK_TOP - DEFAULT_STACK_SIZE, STACK_GUARD_SIZE);
+    let mut vm = Vm::from_memory_map(&map)
+        .with_file_system(fs)
+        .with_stack_limit(stack_limit);
+    let entry = match &image {
+        Some(image) => vm.load_image(image).map_err(|error| error.to_string())?,
+        None => vm
+            .load_elf_with_load_bias(&bytes, load_bias)
+            .map_err(|error| error.to_string())?,
+    };
+    let args: Vec<&str> = std::iter::once(path)
+        .chain(args.iter().map(String::as_str))
+        .collect();
+    vm.push_arguments(DEFAULT_STACK_TOP, &args, &[], entry)
+        .map_err(|error| error.to_string())?;
+    Ok(vm)
+}
+
+/// the disk image at `path` as the virtio disk of the guest, at the first
+/// virtio slot. Its writes go straight to the file
+fn attach_disk(vm: &mut Vm, path: &str) -> Result<(), String> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|error| format!("{path}: {error}"))?;
+    vm.add_device_with_irq(
+        VIRTIO_BASE,
+        VIRTIO_SIZE,
+        VIRTIO_IRQ,
+        Box::new(Virtio::block(Box::new(file))),
+    );
+    Ok(())
+}
+
+/// runs the program, passing its output on as it comes
+fn run(vm: &mut Vm) -> Result<ExitCode, String> {
+    loop {
+        let stop_reason = vm.run_for(SLICE).map_err(|error| error.to_string());
+        if let Some(syscalls) = &mut vm.syscalls {
+            let _ = std::io::stdout().write_all(&std::mem::take(&mut syscalls.stdout));
+            let _ = std::io::stderr().write_all(&std::mem::take(&mut syscalls.stderr));
+        }
+        if let Some(htif) = &mut vm.htif {
+            let _ = std::io::stdout().write_all(&std::mem::take(&mut htif.output));
+        }
+        let stop_reason = stop_reason?;
+        if let StopReason::Preempted { .. } | StopReason::Waiting { .. } = stop_reason {
+            continue;
+        }
+        return match stop_reason.exit_reason() {
+            Some(exit_reason) => Ok(ExitCode::from(exit_reason.code() as u8)),
+            None => Err(format!("stopped: {stop_reason:?}")),
+        };
+    }
+}
+
+/// `run`, and with a `profile_out` it writes the cycles per call stack
+/// there at the end, in the folded format of flame graphs
+fn run_profiled(mut vm: Vm, profile_out: Option<&String>) -> Result<ExitCode, String> {
+    let Some(path) = profile_out else {
+        return run(&mut vm);
+    };
+    let mut vm = vm.with_folded_stacks();
+    let result = run(&mut vm);
+    std::fs::write(path, vm.folded_stacks(Weight::Cycles))
+        .map_err(|error| format!("{path}: {error}"))?;
+    result
+}
+
+#[cfg(feature = "tui")]
+fn debug(vm: Vm) -> Result<ExitCode, String> {
+    riscv_emulator::tui::Debugger::new(vm)
+        .run()
+        .map(|_| ExitCode::SUCCESS)
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(not(feature = "tui"))]
+fn debug(_vm: Vm) -> Result<ExitCode, String> {
+    Err("the debugger needs the tui feature: cargo run --features tui".to_string())
+}
+
+/// runs the program to the end and writes the trace of the run to `out`
+/// (`-` is stdout), then passes the program's output and exit code on
+fn trace(mut vm: Vm, out: &str, format: TraceFormat) -> Result<ExitCode, String> {
+    let writer: Box<dyn Write> = if out == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        let file = std::fs::File::create(out).map_err(|error| format!("{out}: {error}"))?;
+        Box::new(std::io::BufWriter::new(file))
+    };
+    let (stop_reason, _) = vm
+        .run_traced(writer, format)
+        .map_err(|error| format!("{out}: {error}"))?;
+    if let Some(syscalls) = &vm.syscalls {
+        let _ = std::io::stdout().write_all(&syscalls.stdout);
+        let _ = std::io::stderr().write_all(&syscalls.stderr);
+    }
+    let stop_reason = stop_reason.map_err(|error| error.to_string())?;
+    match stop_reason.exit_reason() {
+        Some(exit_reason) => Ok(ExitCode::from(exit_reason.code() as u8)),
+        None => Err(format!("stopped: {stop_reason:?}")),
+    }
+}
+
+/// `run` with the profiler, its report goes to stderr at the end like the
+/// one of `perf stat`
+fn stats(mut vm: Vm) -> Result<ExitCode, String> {
+    let profiler = vm.add_profiler();
+    let result = run(&mut vm);
+    eprint!("\n{}", profiler.report(&[]).to_text());
+    result
+}
+
+/// prints every record of the trace at `path`, one per line
+fn trace_dump(path: &str) -> Result<ExitCode, String> {
+    let file = std::fs::File::open(path).map_err(|error| format!("{path}: {error}"))?;
+    let reader = TraceReader::new(std::io::BufReader::new(file))
+        .map_err(|error| format!("{path}: {error}"))?;
+    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    for record in reader {
+        let record = record.map_err(|error| format!("{path}: {error}"))?;
+        if writeln!(stdout, "{record}").is_err() {
+            // the reader went away, e.g. `| head`
+            break;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// runs every program in parallel and prints how each one went, fails
+/// unless all of them exited with 0
+/// `riscv-vm disk`: creates, resizes and formats images and moves files
+/// between the host and their root directory
+fn disk(arguments: &[String]) -> Result<ExitCode, String> {
+    let read = |path: &str| {
+        let bytes = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+        DiskImage::from_bytes(bytes).map_err(|error| format!("{path}: {error}"))
+    };
+    let write = |path: &str, image: &DiskImage| {
+        std::fs::write(path, image.as_bytes()).map_err(|error| format!("{path}: {error}"))
+    };
+    match arguments {
+        [command, rest @ ..] if command == "create" => {
+            let mut rest = rest;
+            let mut filesystem = Some(Filesystem::Fat);
+            let mut label = "";
+            while let [option, value, tail @ ..] = rest {
+                match option.as_str() {
+                    "--fs" => filesystem = Filesystem::from_name(value),
+                    "--label" => label = value,
+                    _ => break,
+                }
+                rest = tail;
+            }
+            let [path, size] = rest else {
+                return Err(USAGE.to_string());
+            };
+            let filesystem = filesystem.ok_or("--fs is fat or ext2")?;
+            let mut image = DiskImage::new(parse_size(size)?).map_err(|error| error.to_string())?;
+            image
+                .format(filesystem, label)
+                .map_err(|error| error.to_string())?;
+            write(path, &image)?;
+        }
+        [command, path, size] if command == "resize" => {
+            let mut image = read(path)?;
+            image
+                .resize(parse_size(size)?)
+                .map_err(|error| error.to_string())?;
+            write(path, &image)?;
+        }
+        [command, path, file, name @ ..] if command == "put" && name.len() <= 1 => {
+            let contents = std::fs::read(file).map_err(|error| format!("{file}: {error}"))?;
+            // the host file's name by default
+            let name = match name {
+                [name] => name.clone(),
+                _ => std::path::Path::new(file)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .ok_or_else(|| format!("{file}: not a file"))?,
+            };
+            let mut image = read(path)?;
+            image
+                .inject_file(&name, &contents)
+                .map_err(|error| format!("{path}: {error}"))?;
+            write(path, &image)?;
+        }
+        [command, path, name, file @ ..] if command == "get" && file.len() <= 1 => {
+            let contents = read(path)?
+                .extract_file(name)
+                .map_err(|error| format!("{path}: {error}"))?;
+            let file = file.first().unwrap_or(name);
+            std::fs::write(file, contents).map_err(|error| format!("{file}: {error}"))?;
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(feature = "batch")]
+fn batch(
+    options: &[(&String, &String)],
+    paths: &[String],
+    args: &[String],
+) -> Result<ExitCode, String> {
+    use riscv_emulator::batch::{Batch, BatchJob};
+    use riscv_emulator::limits::VmLimits;
+
+    let number = |value: &String| {
+        value
+            .parse::<u64>()
+            .map_err(|_| format!("{value:?} is not a number"))
+    };
+    let mut batch = Batch::new();
+    let mut limits = VmLimits::new();
+    for (option, value) in options {
+        match option.as_str() {
+            "--threads" => batch = batch.with_threads(number(value)? as usize),
+            "--max-instructions" => limits = limits.with_max_instructions(number(value)?),
+            "--max-time-ms" => {
+                limits = limits.with_max_wall_time(std::time::Duration::from_millis(number(value)?))
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let jobs = paths
+        .iter()
+        .map(|path| {
+            let elf = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+            Ok(BatchJob::new(path, elf).with_args(&args))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut all_passed = true;
+    for result in batch.with_limits(limits).run(&jobs) {
+        let outcome = match (&result.outcome, result.exit_code()) {
+            (_, Some(code)) => format!("exit {code}"),
+            (Ok(stop_reason), None) => format!("stopped: {stop_reason:?}"),
+            (Err(error), None) => error.to_string(),
+        };
+        all_passed &= result.exit_code() == Some(0);
+        println!(
+            "{}: {outcome} ({} instructions, {:?})",
+            result.name, result.instructions, result.wall_time
+        );
+    }
+    Ok(if all_passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+#[cfg(not(feature = "batch"))]
+fn batch(
+    _options: &[(&String, &String)],
+    _paths: &[String],
+    _args: &[String],
+) -> Result<ExitCode, String> {
+    Err("batch needs the batch feature: cargo run --features batch".to_string())
+}
+
+fn main() -> ExitCode {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let result = match arguments.as_slice() {
+        [command, path] if command == "debug" => load(path, &[], &[], ELF).and_then(debug),
+        [command, rest @ ..] if command == "trace" => {
+            let (format, rest) = match rest {
+                [option, name, rest @ ..] if option == "--trace-format" => (
+                    TraceFormat::from_name(name)
+                        .ok_or_else(|| format!("unknown trace format {name:?}")),
+                    rest,
+                ),
+                _ => (Ok(TraceFormat::Binary), rest),
+            };
+            format.and_then(|format| match rest {
+                [path, output, out] if output == "-o" => {
+                    load(path, &[], &[], ELF).and_then(|vm| trace(vm, out, format))
+                }
+                [path, output, out, separator, args @ ..]
+                    if output == "-o" && separator == "--" =>
+                {
+                    load(path, args, &[], ELF).and_then(|vm| trace(vm, out, format))
+                }
+                _ => Err(USAGE.to_string()),
+            })
+        }
+        [command, path] if command == "trace-dump" => trace_dump(path),
+        [command, rest @ ..] if command == "disk" => disk(rest),
+        [command, path, rest @ ..] if command == "stats" => match rest {
+            [] => load(path, &[], &[], ELF),
+            [separator, args @ ..] if separator == "--" => load(path, args, &[], ELF),
+            _ => Err(USAGE.to_string()),
+        }
+        .and_then(stats),
+        [command, rest @ ..] if command == "batch" => {
+            let mut rest = rest;
+            let mut options = Vec::new();
+            while let [option, value, tail @ ..] = rest {
+                if !option.starts_with("--") || option == "--" {
+                    break;
+                }
+                options.push((option, value));
+                rest = tail;
+            }
+            let (paths, args) = match rest.iter().position(|argument| argument == "--") {
+                Some(separator) => (&rest[..separator], &rest[separator + 1..]),
+                None => (rest, &[][..]),
+            };
+            if paths.is_empty() {
+                Err(USAGE.to_string())
+            } else {
+                batch(&options, paths, args)
+            }
+        }
+        [command, rest @ ..] if command == "run" => {
+            let mut rest = rest;
+            let mut allowed = Vec::new();
+            let mut profile_out = None;
+            let mut disk = None;
+            let mut format = Ok(ELF);
+            while let [option, value, tail @ ..] = rest {
+                match option.as_str() {
+                    "--allow" => allowed.push(value),
+                    "--profile-out" => profile_out = Some(value),
+                    "--disk" => disk = Some(value),
+                    "--format" => {
+                        format = format.and_then(|format| {
+                            let kind = ImageFormat::from_name(value)
+                                .ok_or_else(|| format!("unknown format {value:?}"))?;
+                            Ok(Format {
+                                format: kind,
+                                ..format
+                            })
+                        })
+                    }
+                    "--load-addr" => {
+                        format = format.and_then(|format| {
+                            Ok(Format {
+                                load_address: Some(parse_address(value)?),
+                                ..format
+                            })
+                        })
+                    }
+                    _ => break,
+                }
+                rest = tail;
+            }
+            format
+                .and_then(|format| match rest {
+                    [path] => load(path, &[], &allowed, format),
+                    [path, separator, args @ ..] if separator == "--" => {
+                        load(path, args, &allowed, format)
+                    }
+                    _ => Err(USAGE.to_string()),
+                })
+                .and_then(|mut vm| {
+                    if let Some(path) = disk {
+                        attach_disk(&mut vm, path)?;
+                    }
+                    run_profiled(vm, profile_out)
+                })
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,68 @@
+//! The long-running milestone: boot xv6 to its shell prompt. The kernel is
+//! not in the repository, build it and point `XV6_KERNEL` at the `kernel`
+//! ELF file (and `XV6_FS` at `fs.img`), then run
+//! `cargo test --release --test xv6 -- --ignored`.
+//!
+//! xv6-riscv itself is RV64, so this needs one of its RV32 ports. What xv6
+//! needs is there: supervisor mode, Sv32 paging, the CLINT, the PLIC, the
+//! SBI, the device tree, and the UART and virtio disk with their interrupts
+//! on PLIC lines 10 and 1. No RV32 port has been booted to the prompt with
+//! it yet, so the test stays ignored until one has.
+
+use riscv_emulator::disk_image::DiskImage;
+use riscv_emulator::dtb::DeviceTree;
+use riscv_emulator::uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use riscv_emulator::virtio::{Virtio, VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
+use riscv_emulator::{StopReason, Vm};
+
+/// where QEMU's `virt` machine has its memory
+const MEMORY_BASE: u32 = 0x8000_0000;
+const MEMORY_SIZE: usize = 128 << 20;
+
+/// how many instructions xv6 gets to reach the prompt
+const BOOT_INSTRUCTIONS: u64 = 2_000_000_000;
+const SLICE: u64 = 1_000_000;
+
+#[test]
+#[ignore = "needs an xv6 kernel in XV6_KERNEL"]
+fn should_boot_xv6_to_the_shell_prompt() {
+    let path = std::env::var("XV6_KERNEL").expect("XV6_KERNEL points at the xv6 kernel");
+    let kernel = std::fs::read(path).unwrap();
+
+    let mut vm = Vm::new(MEMORY_BASE, MEMORY_SIZE)
+        .with_decode_cache()
+        .with_block_cache()
+        .with_clint()
+        .with_plic()
+        .with_sbi();
+    vm.add_device_with_irq(UART_BASE, UART_SIZE, UART_IRQ, Box::new(Uart::default()));
+    vm.vm_state.pc = vm.load_elf(&kernel).unwrap() as i32;
+    let mut tree = DeviceTree::virt(MEMORY_BASE, MEMORY_SIZE as u32);
+    // the file system image, `fs.img` of the xv6 build
+    if let Ok(path) = std::env::var("XV6_FS") {
+        let image = DiskImage::from_bytes(std::fs::read(path).unwrap()).unwrap();
+        vm.add_device_with_irq(
+            VIRTIO_BASE,
+            VIRTIO_SIZE,
+            VIRTIO_IRQ,
+            Box::new(Virtio::block(Box::new(image))),
+        );
+        tree.virtio.push((VIRTIO_BASE, VIRTIO_SIZE));
+    }
+    vm.load_device_tree(&tree).unwrap();
+
+    let mut retired = 0;
+    while retired < BOOT_INSTRUCTIONS {
+        match vm.run_for(SLICE) {
+            Ok(StopReason::Preempted { .. }) => {}
+            stop => panic!("xv6 stopped with {stop:?}"),
+        }
+        retired += SLICE;
+
+        let output = &vm.device::<Uart>(UART_BASE).unwrap().output;
+        if String::from_utf8_lossy(output).contains("\n$ ") {
+            return;
+        }
+    }
+    panic!("no shell prompt after {BOOT_INSTRUCTIONS} instructions");
+}
@@ -0,0 +1,316 @@
+//! The JavaScript API of the vm, built with `wasm-pack build web --target
+//! web`. It is small on purpose, the page does the rest:
+//!
+//! ```js
+//! import init, { WebVm } from "./pkg/riscv_vm_web.js";
+//!
+//! await init();
+//! const vm = new WebVm();
+//! vm.load(new Uint8Array(await (await fetch("program.elf")).arrayBuffer()));
+//! vm.onTrace((pc, text) => console.log(pc.toString(16), text));
+//! vm.step();
+//! console.log(vm.run(100000), vm.registers(), vm.memory(0x1000, 16));
+//! ```
+//!
+//! `memory` copies. Frontends that draw the memory every frame make their
+//! own view of it in the wasm memory instead, from `memoryPointer` and
+//! `memoryLength`:
+//!
+//! ```js
+//! const view = new Uint8Array(wasm.memory.buffer, vm.memoryPointer(), vm.memoryLength());
+//! ```
+//!
+//! The guest memory moves on `load`, so the view is made again after every
+//! call that may have loaded a program, and whenever the wasm memory grew.
+
+mod worker;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+use riscv_emulator::elf::Elf;
+use riscv_emulator::hooks::VmHooks;
+use riscv_emulator::limits::VmLimits;
+use riscv_emulator::process::{user_memory_map, DEFAULT_STACK_SIZE, DEFAULT_STACK_TOP};
+use riscv_emulator::replay::ReplayLog;
+use riscv_emulator::{Instruction, Vm, VmState};
+
+pub use worker::WorkerVm;
+
+/// what an uploaded program may use. The page stops long runs itself with
+/// `abort`, there is no instruction or time limit
+const LIMITS: VmLimits = VmLimits {
+    max_memory: Some(256 << 20),
+    max_instructions: None,
+    max_open_files: Some(64),
+    max_wall_time: None,
+};
+
+/// the instructions that ran since the last callback, pc and assembly
+type Trace = Rc<RefCell<Vec<(u32, String)>>>;
+
+struct TraceHook(Trace);
+
+impl VmHooks for TraceHook {
+    fn before_instruction(&mut self, vm_state: &VmState, instruction: &Instruction) {
+        self.0
+            .borrow_mut()
+            .push((vm_state.pc as u32, instruction.to_string()));
+    }
+}
+
+#[wasm_bindgen]
+pub struct WebVm {
+    vm: Vm,
+    trace: Trace,
+    on_trace: Option<Function>,
+}
+
+#[wasm_bindgen]
+impl WebVm {
+    /// an empty vm, `load` puts a program into it
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            vm: Vm::new(0, 0),
+            trace: Trace::default(),
+            on_trace: None,
+        }
+    }
+
+    /// replaces the vm with one running the ELF file `elf`, with the stack
+    /// and the syscalls of `riscv-vm run` and a PLIC for `raiseIrq`. The
+    /// page may get any file, so it runs under `LIMITS`
+    pub fn load(&mut self, elf: &[u8]) -> Result<(), JsError> {
+        let map = user_memory_map(&Elf::parse(elf)?, DEFAULT_STACK_TOP, DEFAULT_STACK_SIZE);
+        let mut vm = Vm::from_memory_map_with_limits(&map, LIMITS)?
+            .with_syscalls()
+            .with_plic();
+        let entry = vm.load_elf(elf)?;
+        vm.push_arguments(DEFAULT_STACK_TOP, &["program"], &[], entry)?;
+        if self.on_trace.is_some() {
+            vm.add_hooks(Box::new(TraceHook(self.trace.clone())));
+        }
+        self.vm = vm;
+        Ok(())
+    }
+
+    /// runs one instruction
+    pub fn step(&mut self) -> Result<(), JsError> {
+        let result = self.vm.step();
+        self.flush_trace();
+        Ok(result?)
+    }
+
+    /// runs up to `instructions` instructions and says why it stopped
+    pub fn run(&mut self, instructions: u32) -> Result<String, JsError> {
+        let result = self.vm.run_for(instructions.into());
+        self.flush_trace();
+        Ok(format!("{:?}", result?))
+    }
+
+    /// runs up to `instructions` instructions and returns the 5-stage
+    /// pipeline diagram of them as JSON, rows of `{pc, text, first_cycle,
+    /// stages, hazards}`
+    pub fn pipeline(&mut self, instructions: u32, forwarding: bool) -> Result<String, JsError> {
+        let (result, diagram) = self.vm.run_pipeline(instructions.into(), forwarding);
+        self.flush_trace();
+        result?;
+        Ok(diagram.to_json())
+    }
+
+    /// runs for about `milliseconds` of wall-clock time. Calling it again
+    /// from a `setTimeout` while it says `Preempted` keeps the page
+    /// responsive during long runs, `Waiting` means the guest sits in `wfi`
+    /// until an interrupt, e.g. from `raiseIrq`
+    #[wasm_bindgen(js_name = runFor)]
+    pub fn run_for(&mut self, milliseconds: f64) -> Result<String, JsError> {
+        let result = self
+            .vm
+            .run_for_time(Duration::from_secs_f64(milliseconds.max(0.0) / 1000.0));
+        self.flush_trace();
+        Ok(format!("{:?}", result?))
+    }
+
+    /// stops the current or the next `runFor` with `Aborted`
+    pub fn abort(&self) {
+        self.vm.abort_handle().abort();
+    }
+
+    /// raises the PLIC interrupt `line`, the guest takes it before its next
+    /// instruction if it enabled it
+    #[wasm_bindgen(js_name = raiseIrq)]
+    pub fn raise_irq(&mut self, line: u32) -> bool {
+        self.vm.raise_irq(line)
+    }
+
+    #[wasm_bindgen(js_name = clearIrq)]
+    pub fn clear_irq(&mut self, line: u32) -> bool {
+        self.vm.clear_irq(line)
+    }
+
+    /// logs the inputs of the guest from now on, for a bug report that
+    /// `replay` reproduces
+    #[wasm_bindgen(js_name = startRecording)]
+    pub fn start_recording(&mut self) {
+        self.vm.start_recording();
+    }
+
+    /// the inputs since `startRecording` as JSON, empty if it was not called
+    #[wasm_bindgen(js_name = finishRecording)]
+    pub fn finish_recording(&mut self) -> String {
+        self.vm
+            .finish_recording()
+            .map(|log| log.to_json())
+            .unwrap_or_default()
+    }
+
+    /// feeds the inputs of `finishRecording` to the guest instead of the
+    /// page's, on a vm that loaded the same program
+    pub fn replay(&mut self, log: &str) -> Result<(), JsError> {
+        self.vm.start_replay(ReplayLog::from_json(log)?);
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.vm.vm_state.pc as u32
+    }
+
+    /// x0 to x31
+    pub fn registers(&self) -> Vec<i32> {
+        self.vm.vm_state.registers.as_array().to_vec()
+    }
+
+    /// `length` bytes from `address`
+    pub fn memory(&self, address: u32, length: usize) -> Result<Vec<u8>, JsError> {
+        Ok(self.vm.memory.read_bytes(address, length)?.to_vec())
+    }
+
+    /// where the main memory of the guest (from `memoryBase`) is in the
+    /// wasm memory, for frontends that make their own views of
+    /// `memory.buffer`. It changes with `load`
+    #[wasm_bindgen(js_name = memoryPointer)]
+    pub fn memory_pointer(&self) -> u32 {
+        self.vm.memory.bytes().as_ptr() as u32
+    }
+
+    /// how many bytes the main memory at `memoryPointer` has
+    #[wasm_bindgen(js_name = memoryLength)]
+    pub fn memory_length(&self) -> u32 {
+        self.vm.memory.bytes().len() as u32
+    }
+
+    /// the addresses of the 4 KiB pages written since the last call, the
+    /// only ones a memory view has to redraw
+    #[wasm_bindgen(js_name = dirtyPages)]
+    pub fn dirty_pages(&mut self) -> Vec<u32> {
+        self.vm.take_dirty_pages()
+    }
+
+    /// the guest address of the byte at `memoryPointer`
+    #[wasm_bindgen(js_name = memoryBase)]
+    pub fn memory_base(&self) -> u32 {
+        self.vm.memory.base()
+    }
+
+    /// what the guest wrote to stdout and stderr since the last call
+    pub fn output(&mut self) -> String {
+        let Some(syscalls) = &mut self.vm.syscalls else {
+            return String::new();
+        };
+        let mut output = std::mem::take(&mut syscalls.stdout);
+        output.append(&mut syscalls.stderr);
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// calls `callback(pc, text)` for every instruction that runs, after
+    /// each `step` and `run`
+    #[wasm_bindgen(js_name = onTrace)]
+    pub fn on_trace(&mut self, callback: Function) {
+        if self.on_trace.is_none() {
+            self.vm.add_hooks(Box::new(TraceHook(self.trace.clone())));
+        }
+        self.on_trace = Some(callback);
+    }
+
+    fn flush_trace(&mut self) {
+        let trace = std::mem::take(&mut *self.trace.borrow_mut());
+        let Some(callback) = &self.on_trace else {
+            return;
+        };
+        for (pc, text) in trace {
+            let _ = callback.call2(&JsValue::NULL, &pc.into(), &text.into());
+        }
+    }
+}
+
+/// the instructions the vm implements as a JSON array of `{mnemonic, format,
+/// extension, operands}`, for an "instructions implemented" matrix
+#[wasm_bindgen(js_name = instructionSet)]
+pub fn instruction_set() -> String {
+    riscv_emulator::isa::instruction_set_json()
+}
+
+impl Default for WebVm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WebVm;
+
+    /// an ELF file with `code` in one segment at 0x10000, its entry
+    fn elf(code: &[u32]) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let size = (code.len() as u32).to_le_bytes();
+        let mut elf = b"\x7fELF\x01\x01\x01".to_vec();
+        elf.resize(16, 0);
+        // ET_EXEC, EM_RISCV, version, entry, phoff, shoff, flags
+        for half in [2u16, 243] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1u32, 0x10000, 52, 0, 0] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+        for half in [52u16, 32, 1, 40, 0, 0] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        // PT_LOAD at offset 84, r-x
+        for word in [1u32, 84, 0x10000, 0x10000] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf.extend_from_slice(&size);
+        elf.extend_from_slice(&size);
+        for word in [5u32, 4] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf.extend_from_slice(&code);
+        elf
+    }
+
+    #[test]
+    fn should_load_step_and_run_a_program() {
+        // 0x10000 addi a0, zero, 1
+        // 0x10004 addi a1, zero, 2
+        // 0x10008 add a0, a0, a1
+        // 0x1000c ebreak
+        let elf = elf(&[0x0010_0513, 0x0020_0593, 0x00b5_0533, 0x0010_0073]);
+        let mut vm = WebVm::new();
+        vm.load(&elf).unwrap();
+        assert_eq!(vm.pc(), 0x10000);
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 0x10004);
+        assert_eq!(vm.run_for(10.0).unwrap(), "Ebreak");
+        assert_eq!(vm.registers()[10], 3);
+        assert_eq!(vm.memory(0x10008, 4).unwrap(), [0x33, 0x05, 0xb5, 0x00]);
+        assert_eq!(vm.output(), "");
+    }
+}
@@ -0,0 +1,34 @@
+//! The vm side of `www/worker.js`: a vm in a Web Worker that takes the JSON
+//! commands of `riscv_emulator::worker` and answers with JSON events.
+
+use wasm_bindgen::prelude::*;
+
+use riscv_emulator::worker::VmWorker;
+
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct WorkerVm {
+    worker: VmWorker,
+}
+
+#[wasm_bindgen]
+impl WorkerVm {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// one command in, a JSON array of events out
+    pub fn handle(&mut self, message: &str) -> String {
+        self.worker.handle_json(message)
+    }
+
+    /// runs one slice, call it from a `setTimeout` while `running()`
+    pub fn tick(&mut self) -> String {
+        self.worker.tick_json()
+    }
+
+    pub fn running(&self) -> bool {
+        self.worker.is_running()
+    }
+}
@@ -0,0 +1,82 @@
+// The examples page: loads an example program into the vm and shows the
+// registers, the instructions that ran and the memory around sp.
+import init, { WebVm } from "../pkg/riscv_vm_web.js";
+
+const ABI_NAMES = [
+  "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+  "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+  "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+  "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+// how many trace lines stay on the page
+const TRACE_LINES = 200;
+const hex = (value) => "0x" + (value >>> 0).toString(16).padStart(8, "0");
+const element = (id) => document.getElementById(id);
+
+await init();
+const vm = new WebVm();
+const trace = [];
+vm.onTrace((pc, text) => {
+  trace.push(`${hex(pc)}: ${text}`);
+  trace.splice(0, Math.max(0, trace.length - TRACE_LINES));
+});
+
+function render(status) {
+  const registers = vm.registers();
+  element("registers").textContent =
+    `pc   ${hex(vm.pc())}\n` +
+    ABI_NAMES.map((name, index) => `${name.padEnd(4)} ${hex(registers[index])}`).join("\n");
+  element("trace").textContent = trace.join("\n");
+  const sp = registers[2] >>> 0;
+  try {
+    const bytes = vm.memory(sp, 64);
+    let lines = [];
+    for (let offset = 0; offset < bytes.length; offset += 16) {
+      const row = Array.from(bytes.slice(offset, offset + 16), (byte) =>
+        byte.toString(16).padStart(2, "0"));
+      lines.push(`${hex(sp + offset)}: ${row.join(" ")}`);
+    }
+    element("memory").textContent = lines.join("\n");
+  } catch (error) {
+    element("memory").textContent = String(error);
+  }
+  element("status").textContent = status + vm.output();
+}
+
+// runs the vm, stops at the first error (e.g. when _start returns)
+function guard(action) {
+  try {
+    render(action() ?? "");
+  } catch (error) {
+    render(`stopped: ${error}`);
+  }
+}
+
+function load(elf) {
+  trace.length = 0;
+  guard(() => { vm.load(new Uint8Array(elf)); return "loaded"; });
+}
+
+element("load").onclick = async () => {
+  const response = await fetch(element("example").value);
+  if (!response.ok) {
+    element("status").textContent = `${response.status}, build the example first (web/README.md)`;
+    return;
+  }
+  load(await response.arrayBuffer());
+};
+element("file").onchange = async (event) => load(await event.target.files[0].arrayBuffer());
+element("step").onclick = () => guard(() => vm.step());
+// runs in 20 ms slices and lets the browser draw in between, until the
+// guest stops or stop is clicked
+element("run").onclick = () => {
+  const slice = () => guard(() => {
+    const reason = vm.runFor(20);
+    if (reason.startsWith("Preempted")) {
+      setTimeout(slice);
+    }
+    return reason;
+  });
+  slice();
+};
+element("stop").onclick = () => vm.abort();
@@ -0,0 +1,39 @@
+// Runs the vm in a Web Worker, for pages that must not block on long runs:
+//
+//   const worker = new Worker("worker.js", { type: "module" });
+//   worker.onmessage = (message) => console.log(message.data);
+//   worker.postMessage({ command: "load", elf: Array.from(bytes) });
+//   worker.postMessage({ command: "run" });
+//
+// The commands and events are those of src/emulator/worker.rs, each event
+// is posted on its own.
+import init, { WorkerVm } from "../pkg/riscv_vm_web.js";
+
+// messages can come before the module is ready, they wait for it
+const ready = init().then(() => new WorkerVm());
+let ticking = false;
+
+function post(events) {
+  for (const event of JSON.parse(events)) {
+    postMessage(event);
+  }
+}
+
+// one slice per task, so the next commands get in between
+function schedule(vm) {
+  if (ticking || !vm.running()) {
+    return;
+  }
+  ticking = true;
+  setTimeout(() => {
+    ticking = false;
+    post(vm.tick());
+    schedule(vm);
+  });
+}
+
+onmessage = async (message) => {
+  const vm = await ready;
+  post(vm.handle(JSON.stringify(message.data)));
+  schedule(vm);
+};